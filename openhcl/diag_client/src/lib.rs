@@ -643,11 +643,15 @@ pub async fn crash(&self, pid: i32) -> anyhow::Result<()> {
     }
 
     /// Sets up network packet capture trace.
+    ///
+    /// `filter` is an optional expression, in a subset of the `pcap-filter`
+    /// syntax, selecting the packets to capture.
     pub async fn packet_capture(
         &self,
         op: PacketCaptureOperation,
         num_streams: u32,
         snaplen: u16,
+        filter: Option<&str>,
     ) -> anyhow::Result<(Vec<PolledSocket<socket2::Socket>>, u32)> {
         let mut sockets = Vec::new();
         let op_data = match op {
@@ -661,6 +665,7 @@ pub async fn packet_capture(
                 Some(OpData::StartData(diag_proto::StartPacketCaptureData {
                     snaplen: snaplen.into(),
                     conns,
+                    filter: filter.unwrap_or_default().to_owned(),
                 }))
             }
             _ => None,
@@ -607,6 +607,8 @@ async fn handle_packet_capture(
                         Some(OperationData::OpStartData(StartData {
                             writers,
                             snaplen: start_data.snaplen,
+                            filter: (!start_data.filter.is_empty())
+                                .then(|| start_data.filter.clone()),
                         }))
                     }
                 }
@@ -25,7 +25,6 @@
 use pal_async::task::Spawn;
 use pal_async::timer::PolledTimer;
 use std::convert::Infallible;
-use std::ffi::OsStr;
 use std::io::ErrorKind;
 use std::io::IsTerminal;
 use std::io::Write;
@@ -281,6 +280,32 @@ enum Command {
         /// Length of the packet to capture.
         #[clap(short('s'), long, default_value = "65535", value_parser = clap::value_parser!(u16).range(1..))]
         snaplen: u16,
+        /// Only capture packets matching this filter, e.g.
+        /// `tcp and port 443 and not host 10.0.0.1`.
+        ///
+        /// Supports the `ip`, `ip6`, `arp`, `tcp`, `udp`, `icmp` and `icmp6`
+        /// protocols, `[src|dst] host ADDR`, `[src|dst] net ADDR/LEN` and
+        /// `[src|dst] port PORT[-PORT]`, combined with `and`, `or`, `not` and
+        /// parentheses.
+        #[clap(short('f'), long)]
+        filter: Option<String>,
+        /// Switch to a new file for each NIC when the current one reaches this
+        /// many millions of bytes. Files are numbered, e.g. `nic-0_00000.pcap`.
+        #[clap(short('C'), long, value_parser = clap::value_parser!(u64).range(1..))]
+        file_size: Option<u64>,
+        /// With `-C`, keep at most this many files for each NIC, deleting the
+        /// oldest.
+        #[clap(short('W'), long, requires = "file_size", value_parser = clap::value_parser!(u32).range(1..))]
+        file_count: Option<u32>,
+        /// Stream the capture live to a FIFO or Unix socket instead of writing
+        /// files. The nic index is appended to the path.
+        ///
+        /// An existing FIFO is written to, e.g. for `wireshark -k -i <path>`.
+        /// Otherwise, a Unix socket is created and the capture waits for a
+        /// reader to connect, e.g. with
+        /// `socat UNIX-CONNECT:<path> - | wireshark -k -i -`.
+        #[clap(long, conflicts_with_all = ["output", "file_size"])]
+        stream: Option<PathBuf>,
     },
     /// Memory usage profile tracing.
     MemoryProfileTrace {
@@ -827,30 +852,56 @@ pub fn main() -> anyhow::Result<()> {
                 output,
                 seconds,
                 snaplen,
+                filter,
+                file_size,
+                file_count,
+                stream,
             } => {
                 let client = new_client(driver.clone(), &vm)?;
+                let output = match stream {
+                    Some(path) => pcapng_sink::Output::Live { path },
+                    None => pcapng_sink::Output::File {
+                        path: output,
+                        rotation: file_size.map(|size| pcapng_sink::Rotation {
+                            max_file_size: size * 1_000_000,
+                            max_files: file_count.unwrap_or(0),
+                        }),
+                    },
+                };
+                let (_, num_streams) = client
+                    .packet_capture(PacketCaptureOperation::Query, 0, 0, None)
+                    .await?;
+                // Open the outputs first, so that live readers get the whole
+                // capture.
+                let outputs = (0..num_streams as usize)
+                    .map(|i| {
+                        let path = output.path(i);
+                        if matches!(output, pcapng_sink::Output::Live { .. }) {
+                            println!("Waiting for a reader on {}", path.display());
+                        }
+                        output
+                            .open(i)
+                            .with_context(|| format!("failed to open {}", path.display()))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
                 println!(
                     "Starting network packet capture. Wait for timeout or Ctrl-C to quit anytime."
                 );
-                let (_, num_streams) = client
-                    .packet_capture(PacketCaptureOperation::Query, 0, 0)
-                    .await?;
-                let file_stem = &output.file_stem().unwrap().to_string_lossy();
-                let extension = &output.extension().unwrap_or(OsStr::new("pcap"));
-                let mut new_output = PathBuf::from(&output);
                 let streams = client
-                    .packet_capture(PacketCaptureOperation::Start, num_streams, snaplen)
+                    .packet_capture(
+                        PacketCaptureOperation::Start,
+                        num_streams,
+                        snaplen,
+                        filter.as_deref(),
+                    )
                     .await?
                     .0
                     .into_iter()
-                    .enumerate()
-                    .map(|(i, i_stream)| {
-                        new_output.set_file_name(format!("{}-{}", file_stem, i));
-                        new_output.set_extension(extension);
-                        let mut out = AllowStdIo::new(fs_err::File::create(&new_output)?);
-                        Ok(async move { futures::io::copy(i_stream, &mut out).await })
+                    .zip(outputs)
+                    .map(|(i_stream, out)| async move {
+                        futures::io::copy(i_stream, &mut AllowStdIo::new(out)).await
                     })
-                    .collect::<Result<Vec<_>, std::io::Error>>()?;
+                    .collect::<Vec<_>>();
                 capture_packets(client, streams, seconds).await;
             }
             Command::CoreDump {
@@ -1044,7 +1095,7 @@ async fn capture_packets(
 
     let mut stop_streams = std::pin::pin!(async {
         if let Err(err) = client
-            .packet_capture(PacketCaptureOperation::Stop, 0, 0)
+            .packet_capture(PacketCaptureOperation::Stop, 0, 0, None)
             .await
         {
             eprintln!("Failed stop: {err}");
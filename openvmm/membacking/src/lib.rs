@@ -96,6 +96,8 @@ pub enum RemoteProcess {}
 pub use memory_manager::MemoryBuildError;
 pub use memory_manager::PartitionAttachError;
 pub use memory_manager::RamBackingRequest;
+pub use memory_manager::RamDiscard;
+pub use memory_manager::RamDiscardError;
 pub use memory_manager::RamVisibility;
 pub use memory_manager::RamVisibilityControl;
 pub use memory_manager::SharedMemoryBacking;
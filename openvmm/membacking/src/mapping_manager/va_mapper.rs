@@ -566,7 +566,6 @@ pub fn process(&self) -> Option<&RemoteProcess> {
     ///
     /// The caller must ensure this is only called on ranges backed by
     /// private anonymous memory.
-    #[expect(dead_code)] // Will be used by ballooning / memory hot-remove.
     pub fn decommit(&self, offset: usize, len: usize) -> Result<(), std::io::Error> {
         assert!(
             self.private_ranges
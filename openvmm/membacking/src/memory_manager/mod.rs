@@ -37,7 +37,7 @@ pub struct GuestMemoryManager {
     /// Guest RAM allocations. One per backing request. Empty only when
     /// there are no backing requests (no RAM at all).
     #[inspect(skip)]
-    guest_ram: Vec<RamBacking>,
+    guest_ram: Arc<Vec<RamBacking>>,
 
     #[inspect(skip)]
     ram_regions: Arc<Vec<RamRegion>>,
@@ -655,7 +655,7 @@ pub async fn build(self, max_addr: u64) -> Result<GuestMemoryManager, MemoryBuil
         }
 
         let gm = GuestMemoryManager {
-            guest_ram: backings,
+            guest_ram: Arc::new(backings),
             _thread: thread,
             ram_regions: Arc::new(ram_regions),
             mapping_manager,
@@ -739,6 +739,15 @@ pub fn ram_visibility_control(&self) -> RamVisibilityControl {
         }
     }
 
+    /// Returns an object for releasing the host memory backing ranges of guest
+    /// RAM.
+    pub fn ram_discard(&self) -> RamDiscard {
+        RamDiscard {
+            backings: self.guest_ram.clone(),
+            va_mapper: self.va_mapper.clone(),
+        }
+    }
+
     /// Returns the shared memory resources that can be used to reconstruct the
     /// memory backing.
     ///
@@ -882,6 +891,62 @@ pub async fn set_ram_visibility(
     }
 }
 
+/// A client to the [`GuestMemoryManager`] used to release the host memory
+/// backing ranges of guest RAM, such as pages handed back by a balloon
+/// driver.
+#[derive(Clone)]
+pub struct RamDiscard {
+    backings: Arc<Vec<RamBacking>>,
+    va_mapper: Arc<VaMapper>,
+}
+
+/// An error returned by [`RamDiscard::discard`].
+#[derive(Debug, Error)]
+pub enum RamDiscardError {
+    /// The range is not contained in a single RAM range.
+    #[error("{0} is not a RAM range")]
+    InvalidRange(MemoryRange),
+    /// Failed to release the host memory.
+    #[error("failed to discard RAM range {range}")]
+    Discard {
+        /// The range that failed.
+        range: MemoryRange,
+        /// The error.
+        #[source]
+        error: io::Error,
+    },
+}
+
+impl RamDiscard {
+    /// Releases the host memory backing `range`.
+    ///
+    /// The contents of the range are lost; the next access by the guest or
+    /// the host sees zeroes. `range` must be page aligned and lie within a
+    /// single RAM range.
+    pub fn discard(&self, range: MemoryRange) -> Result<(), RamDiscardError> {
+        for backing in self.backings.iter() {
+            let mut file_offset = 0;
+            for ram_range in &backing.ranges {
+                if ram_range.contains(&range) {
+                    let result = match &backing.mappable {
+                        Some(mappable) => sparse_mmap::discard_shared_memory(
+                            mappable,
+                            file_offset + (range.start() - ram_range.start()),
+                            range.len(),
+                        ),
+                        None => self
+                            .va_mapper
+                            .decommit(range.start() as usize, range.len() as usize),
+                    };
+                    return result.map_err(|error| RamDiscardError::Discard { range, error });
+                }
+                file_offset += ram_range.len();
+            }
+        }
+        Err(RamDiscardError::InvalidRange(range))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1120,4 +1185,35 @@ fn test_two_backings_with_gap() {
             assert_eq!(buf, pattern_b);
         });
     }
+
+    #[cfg(target_os = "linux")]
+    #[async_test]
+    async fn test_ram_discard() {
+        let page = SparseMapping::page_size() as u64;
+        let r0 = MemoryRange::new(0..2 * page);
+        let r1 = MemoryRange::new(4 * page..6 * page);
+        let (mgr, gm) = build_and_get_memory(&[&[r0, r1]]).await;
+
+        let pattern = vec![0xCC; 2 * page as usize];
+        gm.write_at(0, &pattern).unwrap();
+        gm.write_at(4 * page, &pattern).unwrap();
+
+        let discard = mgr.ram_discard();
+        discard
+            .discard(MemoryRange::new(5 * page..6 * page))
+            .unwrap();
+
+        // Only the discarded page reads back as zeroes.
+        let mut buf = vec![0u8; 2 * page as usize];
+        gm.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, pattern);
+        gm.read_at(4 * page, &mut buf).unwrap();
+        assert_eq!(buf[..page as usize], pattern[..page as usize]);
+        assert!(buf[page as usize..].iter().all(|&b| b == 0));
+
+        assert!(matches!(
+            discard.discard(MemoryRange::new(2 * page..3 * page)),
+            Err(RamDiscardError::InvalidRange(_))
+        ));
+    }
 }
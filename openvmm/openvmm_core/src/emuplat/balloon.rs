@@ -0,0 +1,40 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use membacking::RamDiscard;
+use memory_range::MemoryRange;
+use std::convert::Infallible;
+use std::sync::Arc;
+use virtio_resources::balloon::DiscardRam;
+use virtio_resources::balloon::DiscardRamHandleKind;
+use virtio_resources::balloon::ResolvedDiscardRam;
+use vm_resource::PlatformResource;
+use vm_resource::ResolveResource;
+
+/// Implementation of [`DiscardRam`] used by the virtio-balloon device, which
+/// releases the host backing of guest RAM through the memory manager.
+struct DiscardGuestRam(RamDiscard);
+
+impl DiscardRam for DiscardGuestRam {
+    fn discard_ram(&self, range: MemoryRange) -> std::io::Result<()> {
+        self.0.discard(range).map_err(std::io::Error::other)
+    }
+}
+
+/// Platform resolver for [`DiscardRamHandleKind`] in OpenVMM.
+pub struct DiscardRamResolver(pub RamDiscard);
+
+impl ResolveResource<DiscardRamHandleKind, PlatformResource> for DiscardRamResolver {
+    type Output = ResolvedDiscardRam;
+    type Error = Infallible;
+
+    fn resolve(
+        &self,
+        _resource: PlatformResource,
+        _input: (),
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(ResolvedDiscardRam(Arc::new(DiscardGuestRam(
+            self.0.clone(),
+        ))))
+    }
+}
@@ -1,6 +1,7 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+pub mod balloon;
 pub mod firmware;
 pub mod i440bx_host_pci_bridge;
 pub mod uefi;
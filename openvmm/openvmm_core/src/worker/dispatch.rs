@@ -6,6 +6,7 @@
 mod ecam_config_access;
 mod intel_vtd_wiring;
 mod ioapic_iommu_wiring;
+mod migrate;
 mod pcie_topology;
 mod pcie_wiring;
 mod smmu_wiring;
@@ -35,6 +36,8 @@
 use debug_ptr::DebugPtr;
 use disk_backend::Disk;
 use disk_backend::resolve::ResolveDiskParameters;
+use e1000e::E1000eDevice;
+use e1000e_resources::E1000eDeviceHandle;
 use firmware_uefi_resources::LogLevel;
 use floppy_resources::FloppyDiskConfig;
 use futures::FutureExt;
@@ -63,6 +66,7 @@
 use mesh_worker::WorkerId;
 use mesh_worker::WorkerRpc;
 use missing_dev::MissingDevManifest;
+use net_backend::resolve::ResolveEndpointParams;
 use openvmm_defs::config::Aarch64TopologyConfig;
 use openvmm_defs::config::ArchTopologyConfig;
 use openvmm_defs::config::Config;
@@ -92,6 +96,7 @@
 use openvmm_pcat_locator::RomFileLocation;
 use pal_async::DefaultDriver;
 use pal_async::DefaultPool;
+use pal_async::driver::Driver;
 use pal_async::local::block_with_io;
 use pal_async::task::Spawn;
 use pal_async::task::Task;
@@ -206,6 +211,7 @@ fn from_config(config: Config) -> Self {
             vga_firmware: config.vga_firmware,
             vtl2_gfx: config.vtl2_gfx,
             virtio_devices: config.virtio_devices,
+            e1000e_pci_devices: config.e1000e_pci_devices,
             vmbus: config.vmbus,
             vtl2_vmbus: config.vtl2_vmbus,
             #[cfg(all(windows, feature = "virt_whp"))]
@@ -257,6 +263,7 @@ pub struct Manifest {
     vga_firmware: Option<RomFileLocation>,
     vtl2_gfx: bool,
     virtio_devices: Vec<(VirtioBus, Resource<VirtioDeviceHandle>)>,
+    e1000e_pci_devices: Vec<E1000eDeviceHandle>,
     vmbus: Option<VmbusConfig>,
     vtl2_vmbus: Option<VmbusConfig>,
     #[cfg(all(windows, feature = "virt_whp"))]
@@ -353,7 +360,21 @@ fn new(parameters: Self::Parameters) -> anyhow::Result<Self> {
             .transpose()
             .context("failed to decode saved state")?;
 
-        let vm = block_with_io(|_| vm.load(saved_state, parameters.notify))?;
+        let vm = block_with_io(async |driver| {
+            if let Some(socket) = parameters.incoming_migration {
+                anyhow::ensure!(
+                    saved_state.is_none(),
+                    "cannot restore saved state and receive a migration"
+                );
+                let mut incoming = migrate::IncomingMigration::new(&driver, socket)?;
+                let saved_state = incoming.receive(&vm.gm, &vm.mem_layout).await?;
+                let vm = vm.load(Some(saved_state), parameters.notify).await?;
+                incoming.complete().await?;
+                Ok(vm)
+            } else {
+                vm.load(saved_state, parameters.notify).await
+            }
+        })?;
 
         LOADED_VM.store(&vm);
 
@@ -1475,6 +1496,9 @@ async fn load(
         resolver.add_resolver(emuplat::i440bx_host_pci_bridge::AdjustGpaRangeResolver(
             memory_manager.ram_visibility_control(),
         ));
+        resolver.add_resolver(emuplat::balloon::DiscardRamResolver(
+            memory_manager.ram_discard(),
+        ));
 
         let mapper = memory_manager.device_memory_mapper();
 
@@ -2865,6 +2889,50 @@ fn make_ids(
             }
         }
 
+        // Construct e1000e NICs on the legacy PCI bus. NICs on PCIe ports use
+        // MSI-X and are resolved with the other PCIe devices.
+        for (index, nic) in cfg.e1000e_pci_devices.into_iter().enumerate() {
+            let endpoint = resolver
+                .resolve(
+                    nic.endpoint,
+                    ResolveEndpointParams {
+                        mac_address: nic.mac_address,
+                    },
+                )
+                .await?;
+
+            let pci_inta_line = pci_inta_line.context("missing PCI INT#A line")?;
+
+            let device_number = pci_device_number;
+            pci_device_number += 1;
+            pci_legacy_interrupts.push(((device_number, None), pci_inta_line));
+
+            let bus = if cfg.chipset.with_piix4_pci_bus {
+                pci_bus_id_piix4.clone()
+            } else {
+                pci_bus_id_generic.clone()
+            };
+
+            chipset_builder
+                .arc_mutex_device(format!("e1000e{index}"))
+                .with_pci_addr(0, device_number, 0)
+                .on_pci_bus(bus)
+                .add(|services| {
+                    E1000eDevice::new(
+                        &driver_source,
+                        gm.clone(),
+                        e1000e::InterruptModel::IntX(services.new_line(
+                            IRQ_LINE_SET,
+                            "interrupt",
+                            pci_inta_line,
+                        )),
+                        nic.mac_address,
+                        endpoint.0,
+                        &mut services.register_mmio(),
+                    )
+                })?;
+        }
+
         let (chipset, devices) = chipset_builder.build()?;
         let (fatal_error_send, _fatal_error_recv) = mesh::channel();
         let chipset = vmm_core::vmotherboard_adapter::AdaptedChipset::new(
@@ -3438,7 +3506,7 @@ async fn assign_pci_resources(&mut self) -> anyhow::Result<()> {
 
     pub async fn run(
         mut self,
-        driver: &impl Spawn,
+        driver: &(impl Spawn + Driver),
         mut rpc_recv: mesh::Receiver<VmRpc>,
         mut worker_rpc: mesh::Receiver<WorkerRpc<RestartState>>,
     ) {
@@ -3537,6 +3605,19 @@ enum Event {
                         rpc.handle_failable(async |()| self.save().await.map(ProtobufMessage::new))
                             .await
                     }
+                    VmRpc::Restore(rpc) => {
+                        rpc.handle_failable(async |state: ProtobufMessage| {
+                            anyhow::ensure!(!self.running, "the VM must be paused");
+                            anyhow::ensure!(
+                                self.inner.partition.supports_reset(),
+                                "the partition does not support reset"
+                            );
+                            let state = state.parse().context("failed to decode saved state")?;
+                            self.reset(false).await?;
+                            self.restore(state).await
+                        })
+                        .await
+                    }
                     VmRpc::Nmi(rpc) => rpc.handle_sync(|vpindex| {
                         if vpindex < self.inner.processor_topology.vp_count() {
                             // Send an NMI MSI to the processor. We could raise
@@ -3775,6 +3856,10 @@ enum Event {
                         rpc.handle_failable(async |file| self.dump_state(file).await)
                             .await
                     }
+                    VmRpc::MigrateOut(rpc) => {
+                        rpc.handle_failable(async |params| self.migrate_out(driver, params).await)
+                            .await
+                    }
                 },
                 Event::Halt(Err(_)) => break,
                 Event::Halt(Ok(reason)) => {
@@ -3927,13 +4012,14 @@ async fn serialize(
             #[cfg(windows)]
             kernel_vmnics: vec![], // TODO
             input,
-            framebuffer: None,      // TODO
-            vga_firmware: None,     // TODO
-            vtl2_gfx: false,        // TODO
-            virtio_devices: vec![], // TODO
+            framebuffer: None,          // TODO
+            vga_firmware: None,         // TODO
+            vtl2_gfx: false,            // TODO
+            virtio_devices: vec![],     // TODO
+            e1000e_pci_devices: vec![], // TODO
             #[cfg(all(windows, feature = "virt_whp"))]
             vpci_resources: vec![], // TODO
-            vmgs: None,             // TODO
+            vmgs: None,                 // TODO
             secure_boot_enabled: false, // TODO
             custom_uefi_vars: Default::default(), // TODO
             firmware_event_send: self.inner.firmware_event_send,
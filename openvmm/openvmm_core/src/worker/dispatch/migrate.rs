@@ -0,0 +1,770 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Live migration between VM workers.
+//!
+//! The source streams guest RAM to the destination while the VM keeps
+//! running, using the hypervisor's dirty page log to resend pages that the
+//! guest writes in the meantime. Once the remaining dirty memory can be sent
+//! within the downtime target (or after a maximum number of passes), the VM
+//! is stopped, the remaining pages and the device state are sent, and the
+//! destination restores the VM.
+//!
+//! Device emulators write guest memory through the host mapping, which the
+//! hypervisor does not log. These writes are logged by [`GuestMemory`]
+//! instead, and the source resends the union of both logs.
+//!
+//! The stream is a header describing the guest RAM ranges followed by a
+//! sequence of records, each of which is either a run of pages or (last) the
+//! device state. The destination acknowledges with a single byte once the VM
+//! has been restored. All integers are little endian.
+
+use super::LoadedVm;
+use super::SavedState;
+use anyhow::Context;
+use futures::AsyncReadExt;
+use futures::AsyncWriteExt;
+use guestmem::GuestMemory;
+use hvdef::HV_PAGE_SIZE;
+use hvdef::Vtl;
+use memory_range::MemoryRange;
+use mesh::payload::message::ProtobufMessage;
+use openvmm_defs::rpc::MigrateOutParams;
+use openvmm_defs::rpc::MigrationStats;
+use pal_async::driver::Driver;
+use pal_async::socket::PolledSocket;
+use std::time::Instant;
+use virt::PartitionMemoryMap;
+use vm_topology::memory::MemoryLayout;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+const MAGIC: [u8; 8] = *b"OVMMIGR\0";
+const VERSION: u32 = 1;
+
+const PAGE_SIZE: usize = HV_PAGE_SIZE as usize;
+
+/// The maximum number of pages in a single record.
+const MAX_RECORD_PAGES: usize = 64;
+
+/// The maximum size of the device state accepted by the destination.
+const MAX_STATE_SIZE: u64 = 1 << 30;
+
+/// Sent by the destination once the VM has been restored.
+const ACK: u8 = 1;
+
+const RECORD_PAGES: u32 = 1;
+const RECORD_STATE: u32 = 2;
+
+#[repr(C)]
+#[derive(IntoBytes, FromBytes, Immutable, KnownLayout)]
+struct StreamHeader {
+    magic: [u8; 8],
+    version: u32,
+    range_count: u32,
+}
+
+#[repr(C)]
+#[derive(IntoBytes, FromBytes, Immutable, KnownLayout, PartialEq, Eq)]
+struct RangeHeader {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(IntoBytes, FromBytes, Immutable, KnownLayout)]
+struct RecordHeader {
+    kind: u32,
+    page_count: u32,
+    /// The address of the first page for page records, or the length of the
+    /// encoded device state for the state record.
+    value: u64,
+}
+
+/// Returns the guest memory ranges transferred by migration.
+fn migrated_ranges(layout: &MemoryLayout) -> anyhow::Result<Vec<MemoryRange>> {
+    anyhow::ensure!(
+        layout.vtl2_range().is_none(),
+        "live migration is not supported with VTL2 memory"
+    );
+    Ok(layout.ram().iter().map(|r| r.range).collect())
+}
+
+/// A bitmap with one bit per 4KiB page, starting at GPA 0.
+#[derive(Clone)]
+struct PageBitmap(Vec<u64>);
+
+impl PageBitmap {
+    fn new(end: u64) -> Self {
+        Self(vec![0; (end / HV_PAGE_SIZE).div_ceil(64) as usize])
+    }
+
+    fn get(&self, gpn: u64) -> bool {
+        self.0[(gpn / 64) as usize] & (1 << (gpn % 64)) != 0
+    }
+
+    fn set(&mut self, gpn: u64) {
+        self.0[(gpn / 64) as usize] |= 1 << (gpn % 64);
+    }
+
+    fn set_range(&mut self, range: MemoryRange) {
+        for gpn in range.start_4k_gpn()..range.end_4k_gpn() {
+            self.set(gpn);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.0.fill(0);
+    }
+
+    fn intersect(&mut self, other: &Self) {
+        for (a, b) in self.0.iter_mut().zip(&other.0) {
+            *a &= b;
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.0.iter().map(|w| w.count_ones() as u64).sum()
+    }
+}
+
+/// The source VM, as driven by the migration stream.
+trait SourceVm {
+    /// Sets the bits in `bitmap` for the pages below `end` that the guest's
+    /// processors wrote since the last query.
+    fn query_dirty_pages(&self, end: u64, bitmap: &mut [u64]) -> anyhow::Result<()>;
+
+    /// Stops the VM, returning whether it was running.
+    async fn pause(&mut self) -> bool;
+
+    /// Starts the VM.
+    async fn resume(&mut self);
+
+    /// Saves the state of the stopped VM.
+    async fn save(&mut self) -> anyhow::Result<SavedState>;
+}
+
+/// Adds the guest RAM pages written since the last query, by the guest's
+/// processors or through `gm`, to `dirty`.
+fn query_dirty(
+    vm: &impl SourceVm,
+    gm: &GuestMemory,
+    end: u64,
+    ram: &PageBitmap,
+    dirty: &mut PageBitmap,
+) -> anyhow::Result<()> {
+    vm.query_dirty_pages(end, &mut dirty.0)
+        .context("failed to query dirty pages")?;
+    gm.query_dirty_log(0, end, &mut dirty.0);
+    dirty.intersect(ram);
+    Ok(())
+}
+
+struct LoadedVmSource<'a> {
+    vm: &'a mut LoadedVm,
+    mapper: &'a dyn PartitionMemoryMap,
+}
+
+impl SourceVm for LoadedVmSource<'_> {
+    fn query_dirty_pages(&self, end: u64, bitmap: &mut [u64]) -> anyhow::Result<()> {
+        self.mapper.query_dirty_pages(0, end, bitmap)
+    }
+
+    async fn pause(&mut self) -> bool {
+        self.vm.pause().await
+    }
+
+    async fn resume(&mut self) {
+        self.vm.resume().await;
+    }
+
+    async fn save(&mut self) -> anyhow::Result<SavedState> {
+        self.vm.save().await
+    }
+}
+
+/// The sending side of a migration stream.
+struct Source {
+    socket: PolledSocket<socket2::Socket>,
+    gm: GuestMemory,
+    ranges: Vec<MemoryRange>,
+    buf: Vec<u8>,
+    pages_sent: u64,
+}
+
+impl Source {
+    fn new(
+        socket: PolledSocket<socket2::Socket>,
+        gm: GuestMemory,
+        ranges: Vec<MemoryRange>,
+    ) -> Self {
+        Self {
+            socket,
+            gm,
+            ranges,
+            buf: Vec::new(),
+            pages_sent: 0,
+        }
+    }
+
+    /// Migrates the VM's memory and device state to the destination.
+    ///
+    /// On success the VM is left stopped. On failure, the VM resumes if it was
+    /// running.
+    async fn migrate(
+        &mut self,
+        vm: &mut impl SourceVm,
+        max_downtime_ms: u64,
+        max_passes: u32,
+    ) -> anyhow::Result<MigrationStats> {
+        self.gm.set_dirty_log(true);
+        let result = self.migrate_inner(vm, max_downtime_ms, max_passes).await;
+        self.gm.set_dirty_log(false);
+        result
+    }
+
+    async fn migrate_inner(
+        &mut self,
+        vm: &mut impl SourceVm,
+        max_downtime_ms: u64,
+        max_passes: u32,
+    ) -> anyhow::Result<MigrationStats> {
+        let start = Instant::now();
+        let end = self.ranges.iter().map(|r| r.end()).max().unwrap_or(0);
+        let mut ram = PageBitmap::new(end);
+        for &range in &self.ranges {
+            ram.set_range(range);
+        }
+
+        self.send_header().await?;
+
+        // Pre-copy. The first pass sends all of memory, skipping zero pages
+        // since the destination's memory starts zeroed. Later passes send the
+        // pages that changed during the previous pass.
+        let mut dirty = ram.clone();
+        let mut passes = 0;
+        loop {
+            let pass_start = Instant::now();
+            let examined = self.send_pages(&dirty, passes == 0).await?;
+            let send_time = pass_start.elapsed();
+            passes += 1;
+
+            dirty.clear();
+            query_dirty(&*vm, &self.gm, end, &ram, &mut dirty)?;
+
+            let remaining = dirty.count();
+            let expected_ms =
+                remaining as f64 * send_time.as_secs_f64() / examined.max(1) as f64 * 1000.0;
+            tracing::info!(
+                pass = passes,
+                examined,
+                remaining,
+                expected_ms,
+                "migration pre-copy pass complete"
+            );
+            if expected_ms <= max_downtime_ms as f64 || passes >= max_passes {
+                break;
+            }
+        }
+
+        // Stop-and-copy.
+        let stop_start = Instant::now();
+        let was_running = vm.pause().await;
+        let pages_sent_before_stop = self.pages_sent;
+        let result = async {
+            query_dirty(&*vm, &self.gm, end, &ram, &mut dirty)?;
+            self.send_pages(&dirty, false).await?;
+            let state = vm.save().await?;
+            self.send_state(state).await?;
+            self.wait_for_ack().await
+        }
+        .await;
+
+        if let Err(err) = result {
+            if was_running {
+                vm.resume().await;
+            }
+            return Err(err);
+        }
+
+        let stats = MigrationStats {
+            passes,
+            pages_sent: self.pages_sent,
+            stopped_pages_sent: self.pages_sent - pages_sent_before_stop,
+            downtime_ms: stop_start.elapsed().as_millis() as u64,
+            total_ms: start.elapsed().as_millis() as u64,
+        };
+        tracing::info!(?stats, "live migration complete");
+        Ok(stats)
+    }
+
+    async fn send_header(&mut self) -> anyhow::Result<()> {
+        let header = StreamHeader {
+            magic: MAGIC,
+            version: VERSION,
+            range_count: self.ranges.len() as u32,
+        };
+        self.socket.write_all(header.as_bytes()).await?;
+        for range in &self.ranges {
+            let range = RangeHeader {
+                start: range.start(),
+                len: range.len(),
+            };
+            self.socket.write_all(range.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the pages that are set in `pages`, returning the number of pages
+    /// examined.
+    async fn send_pages(&mut self, pages: &PageBitmap, skip_zero: bool) -> anyhow::Result<u64> {
+        let mut count = 0;
+        for range in self.ranges.clone() {
+            let end = range.end_4k_gpn();
+            let mut gpn = range.start_4k_gpn();
+            while gpn < end {
+                if !pages.get(gpn) {
+                    gpn += 1;
+                    continue;
+                }
+                let start = gpn;
+                while gpn < end && pages.get(gpn) && gpn - start < MAX_RECORD_PAGES as u64 {
+                    gpn += 1;
+                }
+                self.send_run(start, (gpn - start) as usize, skip_zero)
+                    .await?;
+                count += gpn - start;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Sends a run of consecutive pages, omitting zero pages if `skip_zero`.
+    async fn send_run(&mut self, gpn: u64, count: usize, skip_zero: bool) -> anyhow::Result<()> {
+        self.buf.resize(count * PAGE_SIZE, 0);
+        self.gm
+            .read_at(gpn * HV_PAGE_SIZE, &mut self.buf)
+            .context("failed to read guest memory")?;
+
+        let mut i = 0;
+        while i < count {
+            let start = i;
+            while i < count {
+                let page = &self.buf[i * PAGE_SIZE..][..PAGE_SIZE];
+                if skip_zero && page.iter().all(|&b| b == 0) {
+                    break;
+                }
+                i += 1;
+            }
+            if i > start {
+                let header = RecordHeader {
+                    kind: RECORD_PAGES,
+                    page_count: (i - start) as u32,
+                    value: (gpn + start as u64) * HV_PAGE_SIZE,
+                };
+                let data = &self.buf[start * PAGE_SIZE..i * PAGE_SIZE];
+                self.socket.write_all(header.as_bytes()).await?;
+                self.socket.write_all(data).await?;
+                self.pages_sent += (i - start) as u64;
+            }
+            // Skip the zero page that ended the run, if any.
+            if i < count && i == start {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_state(&mut self, state: SavedState) -> anyhow::Result<()> {
+        let state = mesh::payload::encode(ProtobufMessage::new(state));
+        let header = RecordHeader {
+            kind: RECORD_STATE,
+            page_count: 0,
+            value: state.len() as u64,
+        };
+        self.socket.write_all(header.as_bytes()).await?;
+        self.socket.write_all(&state).await?;
+        self.socket.flush().await?;
+        Ok(())
+    }
+
+    async fn wait_for_ack(&mut self) -> anyhow::Result<()> {
+        let mut ack = [0];
+        self.socket
+            .read_exact(&mut ack)
+            .await
+            .context("destination failed to restore the VM")?;
+        anyhow::ensure!(ack[0] == ACK, "invalid acknowledgement from destination");
+        Ok(())
+    }
+}
+
+impl LoadedVm {
+    /// Live migrates the VM to the destination connected to `params.socket`.
+    ///
+    /// On success the VM is left stopped. On failure, dirty page tracking is
+    /// disabled and the VM resumes if it was running.
+    pub(super) async fn migrate_out(
+        &mut self,
+        driver: &impl Driver,
+        params: MigrateOutParams,
+    ) -> anyhow::Result<MigrationStats> {
+        let ranges = migrated_ranges(&self.inner.mem_layout)?;
+        let socket = PolledSocket::new(driver, params.socket)
+            .context("failed to register migration socket")?;
+        let mut source = Source::new(socket, self.inner.gm.clone(), ranges);
+
+        let mapper = self.inner.partition.memory_mapper(Vtl::Vtl0);
+        mapper
+            .set_dirty_tracking(true)
+            .context("failed to enable dirty page tracking")?;
+
+        let result = source
+            .migrate(
+                &mut LoadedVmSource {
+                    vm: self,
+                    mapper: mapper.as_ref(),
+                },
+                params.max_downtime_ms,
+                params.max_passes,
+            )
+            .await;
+
+        if let Err(err) = mapper.set_dirty_tracking(false) {
+            tracing::warn!(
+                error = err.as_ref() as &dyn std::error::Error,
+                "failed to disable dirty page tracking"
+            );
+        }
+        result
+    }
+}
+
+/// The receiving side of a migration stream.
+pub(super) struct IncomingMigration {
+    socket: PolledSocket<socket2::Socket>,
+}
+
+impl IncomingMigration {
+    pub fn new(driver: &impl Driver, socket: socket2::Socket) -> anyhow::Result<Self> {
+        Ok(Self {
+            socket: PolledSocket::new(driver, socket)
+                .context("failed to register migration socket")?,
+        })
+    }
+
+    /// Receives guest memory into `gm` and returns the VM's saved state.
+    pub async fn receive(
+        &mut self,
+        gm: &GuestMemory,
+        layout: &MemoryLayout,
+    ) -> anyhow::Result<SavedState> {
+        let ranges = migrated_ranges(layout)?;
+
+        let mut header = StreamHeader::new_zeroed();
+        self.socket
+            .read_exact(header.as_mut_bytes())
+            .await
+            .context("failed to read migration stream header")?;
+        anyhow::ensure!(header.magic == MAGIC, "not a migration stream");
+        anyhow::ensure!(
+            header.version == VERSION,
+            "unsupported migration stream version {}",
+            header.version
+        );
+        let mut source_ranges = Vec::new();
+        for _ in 0..header.range_count.min(ranges.len() as u32 + 1) {
+            let mut range = RangeHeader::new_zeroed();
+            self.socket.read_exact(range.as_mut_bytes()).await?;
+            let end = range
+                .start
+                .checked_add(range.len)
+                .context("invalid memory range")?;
+            source_ranges.push(MemoryRange::try_new(range.start..end)?);
+        }
+        anyhow::ensure!(
+            source_ranges == ranges,
+            "source memory layout {source_ranges:?} does not match {ranges:?}"
+        );
+
+        let mut buf = Vec::new();
+        loop {
+            let mut record = RecordHeader::new_zeroed();
+            self.socket
+                .read_exact(record.as_mut_bytes())
+                .await
+                .context("failed to read migration record")?;
+            match record.kind {
+                RECORD_PAGES => {
+                    let count = record.page_count as usize;
+                    anyhow::ensure!(count <= MAX_RECORD_PAGES, "invalid page count {count}");
+                    let end = record
+                        .value
+                        .checked_add((count * PAGE_SIZE) as u64)
+                        .context("invalid page address")?;
+                    let pages = MemoryRange::try_new(record.value..end)?;
+                    anyhow::ensure!(
+                        ranges.iter().any(|r| r.contains(&pages)),
+                        "pages {pages} are outside guest memory"
+                    );
+                    buf.resize(count * PAGE_SIZE, 0);
+                    self.socket.read_exact(&mut buf).await?;
+                    gm.write_at(record.value, &buf)
+                        .context("failed to write guest memory")?;
+                }
+                RECORD_STATE => {
+                    anyhow::ensure!(record.value <= MAX_STATE_SIZE, "device state too large");
+                    buf.resize(record.value as usize, 0);
+                    self.socket.read_exact(&mut buf).await?;
+                    let state: ProtobufMessage = mesh::payload::decode(&buf)
+                        .context("failed to decode migrated device state")?;
+                    return state
+                        .parse()
+                        .context("failed to decode migrated device state");
+                }
+                kind => anyhow::bail!("unknown migration record type {kind}"),
+            }
+        }
+    }
+
+    /// Tells the source that the VM has been restored, completing the
+    /// migration.
+    pub async fn complete(mut self) -> anyhow::Result<()> {
+        self.socket.write_all(&[ACK]).await?;
+        self.socket.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use parking_lot::Mutex;
+    use std::collections::VecDeque;
+
+    const RAM_SIZE: u64 = 0x100000;
+
+    /// Guest RAM split around an MMIO gap, so that the stream has two ranges.
+    fn layout() -> MemoryLayout {
+        MemoryLayout::new(
+            RAM_SIZE,
+            &[MemoryRange::new(0x80000..0xc0000)],
+            &[],
+            &[],
+            None,
+        )
+        .unwrap()
+    }
+
+    fn memory(layout: &MemoryLayout) -> GuestMemory {
+        GuestMemory::allocate(layout.end_of_ram() as usize)
+    }
+
+    fn socket_pair() -> (socket2::Socket, socket2::Socket) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+        (a.into(), b.into())
+    }
+
+    struct FakeVm {
+        gm: GuestMemory,
+        /// The addresses the guest writes before each dirty page query.
+        guest_writes: Mutex<VecDeque<Vec<u64>>>,
+        /// An address a device writes through guest memory when the VM is
+        /// paused, which is not reported in the hypervisor's dirty page log.
+        device_write: Option<u64>,
+        counter: Mutex<u8>,
+        running: bool,
+    }
+
+    impl FakeVm {
+        fn new(gm: GuestMemory) -> Self {
+            Self {
+                gm,
+                guest_writes: Default::default(),
+                device_write: None,
+                counter: Mutex::new(0),
+                running: true,
+            }
+        }
+
+        fn write(&self, addr: u64) {
+            let mut counter = self.counter.lock();
+            *counter = counter.wrapping_add(1).max(1);
+            self.gm.write_at(addr, &[*counter; 16]).unwrap();
+        }
+    }
+
+    impl SourceVm for FakeVm {
+        fn query_dirty_pages(&self, _end: u64, bitmap: &mut [u64]) -> anyhow::Result<()> {
+            for addr in self.guest_writes.lock().pop_front().unwrap_or_default() {
+                self.write(addr);
+                let gpn = addr / HV_PAGE_SIZE;
+                bitmap[(gpn / 64) as usize] |= 1 << (gpn % 64);
+            }
+            Ok(())
+        }
+
+        async fn pause(&mut self) -> bool {
+            if let Some(addr) = self.device_write {
+                self.write(addr);
+            }
+            std::mem::replace(&mut self.running, false)
+        }
+
+        async fn resume(&mut self) {
+            self.running = true;
+        }
+
+        async fn save(&mut self) -> anyhow::Result<SavedState> {
+            assert!(!self.running);
+            Ok(SavedState { units: Vec::new() })
+        }
+    }
+
+    /// Migrates `vm` to a fresh destination with `dest_layout`, returning the
+    /// results of both sides and the destination's memory.
+    async fn migrate(
+        driver: &DefaultDriver,
+        vm: &mut FakeVm,
+        dest_layout: &MemoryLayout,
+        max_downtime_ms: u64,
+        max_passes: u32,
+    ) -> (
+        anyhow::Result<MigrationStats>,
+        anyhow::Result<()>,
+        GuestMemory,
+    ) {
+        let (src_socket, dest_socket) = socket_pair();
+        let mut source = Source::new(
+            PolledSocket::new(driver, src_socket).unwrap(),
+            vm.gm.clone(),
+            migrated_ranges(&layout()).unwrap(),
+        );
+        let dest_gm = memory(dest_layout);
+        let source_result = source.migrate(vm, max_downtime_ms, max_passes);
+        let dest_result = async {
+            let mut incoming = IncomingMigration::new(driver, dest_socket)?;
+            incoming.receive(&dest_gm, dest_layout).await?;
+            incoming.complete().await
+        };
+        let (source_result, dest_result) = futures::join!(source_result, dest_result);
+        (source_result, dest_result, dest_gm)
+    }
+
+    fn assert_memory_eq(layout: &MemoryLayout, a: &GuestMemory, b: &GuestMemory) {
+        for range in migrated_ranges(layout).unwrap() {
+            let mut a_buf = vec![0; range.len() as usize];
+            let mut b_buf = vec![0; range.len() as usize];
+            a.read_at(range.start(), &mut a_buf).unwrap();
+            b.read_at(range.start(), &mut b_buf).unwrap();
+            assert!(a_buf == b_buf, "memory in {range} differs");
+        }
+    }
+
+    #[async_test]
+    async fn skips_zero_pages(driver: DefaultDriver) {
+        let layout = layout();
+        let mut vm = FakeVm::new(memory(&layout));
+        vm.write(0x1000);
+        vm.write(0x7f000);
+        vm.write(0xc0000);
+
+        let (stats, dest, dest_gm) = migrate(&driver, &mut vm, &layout, u64::MAX, 10).await;
+        let stats = stats.unwrap();
+        dest.unwrap();
+        assert_eq!(stats.passes, 1);
+        assert_eq!(stats.pages_sent, 3);
+        assert_eq!(stats.stopped_pages_sent, 0);
+        assert!(!vm.running);
+        assert_memory_eq(&layout, &vm.gm, &dest_gm);
+    }
+
+    #[async_test]
+    async fn resends_dirty_pages(driver: DefaultDriver) {
+        let layout = layout();
+        let mut vm = FakeVm::new(memory(&layout));
+        vm.guest_writes = Mutex::new(
+            [
+                vec![0x2000, 0x3000, 0xd0000],
+                vec![0x2000, 0x13f000],
+                vec![0x4000, 0x5000],
+            ]
+            .into(),
+        );
+
+        // A zero downtime target forces every pass.
+        let (stats, dest, dest_gm) = migrate(&driver, &mut vm, &layout, 0, 3).await;
+        let stats = stats.unwrap();
+        dest.unwrap();
+        assert_eq!(stats.passes, 3);
+        assert_eq!(stats.pages_sent, 7);
+        assert_eq!(stats.stopped_pages_sent, 2);
+        assert_memory_eq(&layout, &vm.gm, &dest_gm);
+    }
+
+    #[async_test]
+    async fn resends_unlogged_writes(driver: DefaultDriver) {
+        let layout = layout();
+        let mut vm = FakeVm::new(memory(&layout));
+        vm.write(0x6000);
+        vm.device_write = Some(0x6000);
+
+        let (stats, dest, dest_gm) = migrate(&driver, &mut vm, &layout, u64::MAX, 10).await;
+        let stats = stats.unwrap();
+        dest.unwrap();
+        assert_eq!(stats.pages_sent, 2);
+        assert_eq!(stats.stopped_pages_sent, 1);
+        assert_memory_eq(&layout, &vm.gm, &dest_gm);
+    }
+
+    #[async_test]
+    async fn layout_mismatch(driver: DefaultDriver) {
+        let mut vm = FakeVm::new(memory(&layout()));
+        vm.write(0x1000);
+        let dest_layout = MemoryLayout::new(RAM_SIZE, &[], &[], &[], None).unwrap();
+
+        let (stats, dest, _) = migrate(&driver, &mut vm, &dest_layout, u64::MAX, 10).await;
+        let err = dest.unwrap_err();
+        assert!(format!("{err:#}").contains("does not match"), "{err:#}");
+        stats.unwrap_err();
+        // The source resumes the VM when the migration fails.
+        assert!(vm.running);
+    }
+
+    #[async_test]
+    async fn truncated_stream(driver: DefaultDriver) {
+        let layout = layout();
+        let gm = memory(&layout);
+        gm.write_at(0, &[1; PAGE_SIZE * 4]).unwrap();
+        let (src_socket, dest_socket) = socket_pair();
+        let mut source = Source::new(
+            PolledSocket::new(&driver, src_socket).unwrap(),
+            gm,
+            migrated_ranges(&layout).unwrap(),
+        );
+
+        // Send a record promising four pages, followed by only one.
+        source.send_header().await.unwrap();
+        let header = RecordHeader {
+            kind: RECORD_PAGES,
+            page_count: 4,
+            value: 0,
+        };
+        source.socket.write_all(header.as_bytes()).await.unwrap();
+        source.socket.write_all(&[1; PAGE_SIZE]).await.unwrap();
+        drop(source);
+
+        let mut incoming = IncomingMigration::new(&driver, dest_socket).unwrap();
+        incoming
+            .receive(&memory(&layout), &layout)
+            .await
+            .unwrap_err();
+    }
+}
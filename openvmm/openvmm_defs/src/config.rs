@@ -3,6 +3,7 @@
 
 //! Configuration for the VM worker.
 
+use e1000e_resources::E1000eDeviceHandle;
 use guid::Guid;
 use input_core::InputData;
 use memory_range::MemoryRange;
@@ -44,6 +45,9 @@ pub struct Config {
     pub vga_firmware: Option<RomFileLocation>,
     pub vtl2_gfx: bool,
     pub virtio_devices: Vec<(VirtioBus, Resource<VirtioDeviceHandle>)>,
+    /// e1000e NICs on the legacy PCI bus. NICs on PCIe ports are in
+    /// `pcie_devices`.
+    pub e1000e_pci_devices: Vec<E1000eDeviceHandle>,
     #[cfg(windows)]
     pub vpci_resources: Vec<virt_whp::device::DeviceHandle>,
     pub vmgs: Option<VmgsResource>,
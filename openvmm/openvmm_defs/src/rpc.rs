@@ -47,6 +47,46 @@ pub enum VmRpc {
     /// handle to write to (typically a temporary file that gets renamed
     /// into place on success).
     DumpState(FailableRpc<File, ()>),
+    /// Live migrate the VM to another OpenVMM process.
+    ///
+    /// On success, the VM is left stopped and the destination owns it. On
+    /// failure, the VM resumes (if it was running) as if nothing happened.
+    MigrateOut(FailableRpc<MigrateOutParams, MigrationStats>),
+    /// Reset the VM's devices and restore them from state previously returned
+    /// by [`VmRpc::Save`].
+    ///
+    /// The VM must be paused. Guest memory is not modified; the caller is
+    /// responsible for restoring its contents first.
+    Restore(FailableRpc<ProtobufMessage, ()>),
+}
+
+/// Parameters for [`VmRpc::MigrateOut`].
+#[derive(MeshPayload)]
+pub struct MigrateOutParams {
+    /// A stream socket connected to the destination, which must have been
+    /// started with the socket's peer as its incoming migration stream.
+    pub socket: socket2::Socket,
+    /// The downtime to aim for, in milliseconds. Pre-copy continues until the
+    /// remaining dirty memory can be sent in this long.
+    pub max_downtime_ms: u64,
+    /// The maximum number of pre-copy passes before stopping the VM
+    /// regardless of the expected downtime.
+    pub max_passes: u32,
+}
+
+/// Statistics from a completed live migration.
+#[derive(Debug, MeshPayload)]
+pub struct MigrationStats {
+    /// The number of pre-copy passes over guest memory.
+    pub passes: u32,
+    /// The total number of pages sent.
+    pub pages_sent: u64,
+    /// The number of pages sent while the VM was stopped.
+    pub stopped_pages_sent: u64,
+    /// The time the VM was stopped, in milliseconds.
+    pub downtime_ms: u64,
+    /// The total migration time, in milliseconds.
+    pub total_ms: u64,
 }
 
 #[derive(Debug, MeshPayload, thiserror::Error)]
@@ -83,6 +123,8 @@ fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             VmRpc::AddPcieDevice(_) => "AddPcieDevice",
             VmRpc::RemovePcieDevice(_) => "RemovePcieDevice",
             VmRpc::DumpState(_) => "DumpState",
+            VmRpc::MigrateOut(_) => "MigrateOut",
+            VmRpc::Restore(_) => "Restore",
         };
         f.pad(s)
     }
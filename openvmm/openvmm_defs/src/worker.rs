@@ -33,6 +33,11 @@ pub struct VmWorkerParameters {
     /// File-backed guest RAM handle. When set, guest memory uses this
     /// fd/handle instead of allocating anonymous memory.
     pub shared_memory: Option<SharedMemoryFd>,
+    /// A stream socket to receive the VM from a live migration source.
+    ///
+    /// When set, guest memory and device state are read from the socket
+    /// instead of `saved_state`, and the VM is created stopped.
+    pub incoming_migration: Option<socket2::Socket>,
     /// The VM RPC channel.
     pub rpc: mesh::Receiver<VmRpc>,
     /// The notification channel.
@@ -0,0 +1,858 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! openvmm_disktool: an offline tool for creating, inspecting, converting,
+//! and committing OpenVMM disk images and layer stacks.
+//!
+//! Images are specified with the OpenVMM `--disk` syntax, so the same stack
+//! that is passed to OpenVMM can be passed to this tool. Images are opened
+//! with the same resolvers that OpenVMM uses, but VHD, VHDX, and QCOW2 files
+//! are opened as native layers, with each parent or backing file as a separate
+//! layer, so that the allocation of each image can be reported.
+
+#![forbid(unsafe_code)]
+
+use anyhow::Context;
+use clap::Parser;
+use clap::Subcommand;
+use disk_backend::DiskIo;
+use disk_layered::LayerConfiguration;
+use disk_layered::LayeredDisk;
+use disk_layered::resolve::ResolveDiskLayerParameters;
+use guestmem::GuestMemory;
+use openvmm_helpers::disk_cli::DiskCliKind;
+use openvmm_helpers::disk_cli::open_disk_layers;
+use openvmm_helpers::disk_cli::parse_memory;
+use pal_async::DefaultPool;
+use scsi_buffers::OwnedRequestBuffers;
+use std::io::Write;
+use std::ops::Range;
+use vm_resource::ResourceResolver;
+use vmcore::vm_task::SingleDriverBackend;
+use vmcore::vm_task::VmTaskDriverSource;
+
+#[cfg(not(test))]
+crypto::ensure_single_backend!();
+
+// Register the resolvers for the supported image types.
+vm_resource::register_static_resolvers! {
+    disk_layered::resolver::LayeredDiskResolver,
+    #[cfg(feature = "disk_crypt")]
+    disk_crypt::resolver::DiskCryptResolver,
+    disk_file::FileDiskResolver,
+    #[cfg(target_os = "linux")]
+    disk_blockdevice::resolver::StaticBlockDeviceResolver,
+    disk_vhd1::Vhd1Resolver,
+    disk_vhd1::resolver::Vhd1DiskResolver,
+    disk_qcow2::resolver::Qcow2Resolver,
+    disk_vhdx::resolver::VhdxResolver,
+    #[cfg(windows)]
+    disk_vhdmp::VhdmpDiskResolver,
+    #[cfg(feature = "disk_blob")]
+    disk_blob::resolver::BlobDiskResolver,
+    disk_qcow2::resolver::Qcow2DiskLayerResolver,
+    disk_vhd1::resolver::Vhd1DiskLayerResolver,
+    disk_vhdx::resolver::VhdxDiskLayerResolver,
+    #[cfg(feature = "disklayer_sqlite")]
+    disklayer_sqlite::resolver::SqliteDiskLayerResolver,
+}
+
+/// Manage OpenVMM disk images and layer stacks.
+///
+/// IMAGE uses the OpenVMM `--disk` syntax, for example:
+///
+///   [file:]<path>                    raw, .vhd, .vhdx, or .qcow2 file
+///
+///   qcow2diff:<path>:<IMAGE>         qcow2 diff layer on top of IMAGE
+///
+///   sql:<path>                       sqlite disk
+///
+///   sqldiff:<path>:<IMAGE>           sqlite diff layer on top of IMAGE
+///
+///   blob:<flat|vhd1>:<url>           read-only HTTP blob
+///
+///   crypt:xts-aes-256:<key>:<IMAGE>  IMAGE encrypted with the key in <key>
+#[derive(Parser)]
+#[clap(name = "openvmm_disktool", verbatim_doc_comment)]
+struct Options {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a new image.
+    ///
+    /// Only the top image is created. A diff layer has the size of the image
+    /// below it.
+    Create {
+        image: DiskCliKind,
+        /// The size of the image, in bytes (or with a K, M, G, or T suffix).
+        /// Not used for a diff layer.
+        #[clap(long, value_parser = parse_memory)]
+        size: Option<u64>,
+    },
+    /// Report the size and layers of an image.
+    Info {
+        image: DiskCliKind,
+        /// Also report the number of bytes allocated in each layer. This reads
+        /// every layer in full.
+        #[clap(long)]
+        allocation: bool,
+    },
+    /// Copy the contents of an image into a new image.
+    ///
+    /// All the layers of the source are flattened into the destination.
+    Convert {
+        source: DiskCliKind,
+        destination: DiskCliKind,
+    },
+    /// Commit the top layer of an image into the layer below it.
+    ///
+    /// The top layer's file is left unchanged and can be deleted afterwards.
+    Commit { image: DiskCliKind },
+    /// Print which layer provides each range of the disk.
+    Map { image: DiskCliKind },
+}
+
+fn main() {
+    if let Err(err) = do_main() {
+        eprintln!("error: {err:#}");
+        std::process::exit(1);
+    }
+}
+
+fn do_main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("warn")),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+
+    let options = Options::parse();
+    DefaultPool::run_with(async |driver| {
+        let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver));
+        let resolver = ResourceResolver::new();
+        run(
+            &resolver,
+            &driver_source,
+            options.command,
+            &mut std::io::stdout(),
+        )
+        .await
+    })
+}
+
+async fn run(
+    resolver: &ResourceResolver,
+    driver_source: &VmTaskDriverSource,
+    command: Command,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    match command {
+        Command::Create { image, size } => {
+            let image = create_spec(image, size)?;
+            let disk = open(resolver, driver_source, &image, Some(0)).await?;
+            disk.sync_cache().await.context("failed to flush image")?;
+            writeln!(out, "created image of {} bytes", disk_size(&disk))?;
+        }
+        Command::Info { image, allocation } => {
+            let disk = open(resolver, driver_source, &image, None).await?;
+            info(&disk, allocation, out).await?;
+        }
+        Command::Convert {
+            source,
+            destination,
+        } => {
+            let source = open(resolver, driver_source, &source, None).await?;
+            let destination = create_spec(destination, Some(disk_size(&source)))?;
+            let destination = open(resolver, driver_source, &destination, Some(0)).await?;
+            convert(&source, &destination).await?;
+        }
+        Command::Commit { image } => {
+            // Only the layer being committed into is written to.
+            let disk = open(resolver, driver_source, &image, Some(1)).await?;
+            let control = disk.control();
+            let layers = control.layers();
+            control
+                .commit_top_layer()
+                .await
+                .context("failed to commit the top layer")?;
+            writeln!(
+                out,
+                "committed {} layer into {} layer",
+                layers[0].layer_type, layers[1].layer_type
+            )?;
+        }
+        Command::Map { image } => {
+            let disk = open(resolver, driver_source, &image, None).await?;
+            map(&disk, out).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Opens `image` as a layered disk, with only the layer at index
+/// `writable_layer`, if any, writable.
+///
+/// The disk itself is only writable if its top layer is.
+async fn open(
+    resolver: &ResourceResolver,
+    driver_source: &VmTaskDriverSource,
+    image: &DiskCliKind,
+    writable_layer: Option<usize>,
+) -> anyhow::Result<LayeredDisk> {
+    let mut layers = Vec::new();
+    for (i, desc) in open_disk_layers(image, writable_layer)
+        .await?
+        .into_iter()
+        .enumerate()
+    {
+        let layer = resolver
+            .resolve(
+                desc.layer,
+                ResolveDiskLayerParameters {
+                    read_only: writable_layer != Some(i),
+                    driver_source,
+                },
+            )
+            .await
+            .with_context(|| format!("failed to open layer {i}"))?;
+        layers.push(LayerConfiguration {
+            layer: layer.0,
+            write_through: desc.write_through,
+            read_cache: desc.read_cache,
+        });
+    }
+    LayeredDisk::new(writable_layer != Some(0), layers)
+        .await
+        .context("invalid layer stack")
+}
+
+/// Returns `image` with its top image marked to be created, with a size of
+/// `size` bytes unless the top image is a diff layer.
+fn create_spec(image: DiskCliKind, size: Option<u64>) -> anyhow::Result<DiskCliKind> {
+    let len = |create_with_len: Option<u64>| {
+        create_with_len
+            .or(size)
+            .context("a size is required to create this image")
+    };
+    let image = match image {
+        DiskCliKind::File {
+            path,
+            create_with_len,
+            direct,
+        } => DiskCliKind::File {
+            path,
+            create_with_len: Some(len(create_with_len)?),
+            direct,
+        },
+        DiskCliKind::Qcow2 {
+            path,
+            create_with_len,
+        } => DiskCliKind::Qcow2 {
+            path,
+            create_with_len: Some(len(create_with_len)?),
+        },
+        DiskCliKind::Vhdx {
+            path,
+            create_with_len,
+        } => DiskCliKind::Vhdx {
+            path,
+            create_with_len: Some(len(create_with_len)?),
+        },
+        DiskCliKind::Sqlite {
+            path,
+            create_with_len,
+        } => DiskCliKind::Sqlite {
+            path,
+            create_with_len: Some(len(create_with_len)?),
+        },
+        DiskCliKind::SqliteDiff { path, disk, .. } => DiskCliKind::SqliteDiff {
+            path,
+            create: true,
+            disk,
+        },
+        DiskCliKind::Qcow2Diff { path, disk, .. } => DiskCliKind::Qcow2Diff {
+            path,
+            create: true,
+            disk,
+        },
+        DiskCliKind::Crypt {
+            cipher,
+            key_file,
+            disk,
+        } => DiskCliKind::Crypt {
+            cipher,
+            key_file,
+            disk: Box::new(create_spec(*disk, size)?),
+        },
+        DiskCliKind::Blob { .. } => anyhow::bail!("blob images are read only"),
+        DiskCliKind::Memory(_)
+        | DiskCliKind::MemoryDiff(_)
+        | DiskCliKind::AutoCacheSqlite { .. }
+        | DiskCliKind::PersistentReservationsWrapper(_)
+        | DiskCliKind::DelayDiskWrapper { .. } => {
+            anyhow::bail!("only file, qcow2, vhdx, sqlite, and encrypted images can be created")
+        }
+    };
+    Ok(image)
+}
+
+fn disk_size(disk: &LayeredDisk) -> u64 {
+    disk.sector_count() * disk.sector_size() as u64
+}
+
+async fn info(disk: &LayeredDisk, allocation: bool, out: &mut impl Write) -> anyhow::Result<()> {
+    let sector_size = disk.sector_size() as u64;
+    writeln!(out, "size: {} bytes", disk_size(disk))?;
+    writeln!(out, "sector size: {sector_size}")?;
+    writeln!(out, "physical sector size: {}", disk.physical_sector_size())?;
+    if let Some(disk_id) = disk.disk_id() {
+        let disk_id = disk_id
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        writeln!(out, "disk id: {disk_id}")?;
+    }
+    writeln!(out, "layers:")?;
+    let control = disk.control();
+    for (i, layer) in control.layers().iter().enumerate() {
+        let mut line = format!(
+            "  {i}: {}, {} bytes",
+            layer.layer_type,
+            layer.sector_count * sector_size
+        );
+        if allocation {
+            let allocated = control
+                .layer_allocation(i)
+                .await
+                .with_context(|| format!("failed to read layer {i}"))?
+                .iter()
+                .map(|range| range.end - range.start)
+                .sum::<u64>();
+            line += &format!(", {} bytes allocated", allocated * sector_size);
+        }
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+/// The number of bytes copied at a time when converting an image.
+const CONVERT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Copies the contents of `source` to `destination`, skipping sectors whose
+/// contents already match so that sparse destinations stay sparse.
+async fn convert(source: &LayeredDisk, destination: &LayeredDisk) -> anyhow::Result<()> {
+    if source.sector_size() != destination.sector_size() {
+        anyhow::bail!(
+            "sector size mismatch: source has {}, destination has {}",
+            source.sector_size(),
+            destination.sector_size()
+        );
+    }
+    let sector_shift = source.sector_size().trailing_zeros();
+    let chunk_sectors = (CONVERT_CHUNK_SIZE >> sector_shift) as u64;
+    let mem = GuestMemory::allocate(CONVERT_CHUNK_SIZE * 2);
+    let source_buf = OwnedRequestBuffers::linear(0, CONVERT_CHUNK_SIZE, true);
+    let dest_buf = OwnedRequestBuffers::linear(CONVERT_CHUNK_SIZE as u64, CONVERT_CHUNK_SIZE, true);
+    let mut source_data = vec![0; CONVERT_CHUNK_SIZE];
+    let mut dest_data = vec![0; CONVERT_CHUNK_SIZE];
+    let sector_count = source.sector_count();
+    let mut sector = 0;
+    while sector < sector_count {
+        let count = (sector_count - sector).min(chunk_sectors);
+        let len = (count as usize) << sector_shift;
+        let source_buffers = source_buf.buffer(&mem).subrange(0, len);
+        let dest_buffers = dest_buf.buffer(&mem).subrange(0, len);
+        source
+            .read_vectored(&source_buffers, sector)
+            .await
+            .with_context(|| format!("failed to read source at sector {sector}"))?;
+        destination
+            .read_vectored(&dest_buffers, sector)
+            .await
+            .with_context(|| format!("failed to read destination at sector {sector}"))?;
+        mem.read_at(0, &mut source_data[..len])?;
+        mem.read_at(CONVERT_CHUNK_SIZE as u64, &mut dest_data[..len])?;
+        for run in changed_runs(&source_data[..len], &dest_data[..len], sector_shift) {
+            let start = sector + run.start;
+            destination
+                .write_vectored(
+                    &source_buffers.subrange(
+                        (run.start as usize) << sector_shift,
+                        ((run.end - run.start) as usize) << sector_shift,
+                    ),
+                    start,
+                    false,
+                )
+                .await
+                .with_context(|| format!("failed to write destination at sector {start}"))?;
+        }
+        sector += count;
+    }
+    destination
+        .sync_cache()
+        .await
+        .context("failed to flush destination")
+}
+
+/// Returns the runs of sectors, relative to the start of the buffers, whose
+/// contents differ between `a` and `b`.
+fn changed_runs(a: &[u8], b: &[u8], sector_shift: u32) -> Vec<Range<u64>> {
+    let mut runs: Vec<Range<u64>> = Vec::new();
+    let sector_size = 1 << sector_shift;
+    for (i, (a, b)) in a.chunks(sector_size).zip(b.chunks(sector_size)).enumerate() {
+        if a == b {
+            continue;
+        }
+        let i = i as u64;
+        match runs.last_mut() {
+            Some(last) if last.end == i => last.end = i + 1,
+            _ => runs.push(i..i + 1),
+        }
+    }
+    runs
+}
+
+/// The source of a range of a layered disk's contents.
+#[derive(Copy, Clone, PartialEq)]
+enum MapSource {
+    Layer(usize),
+    Zero,
+}
+
+/// Splits `ranges` into the parts inside and outside of `allocated`. Both
+/// must be sorted and non-overlapping.
+fn split_ranges(
+    ranges: &[Range<u64>],
+    allocated: &[Range<u64>],
+) -> (Vec<Range<u64>>, Vec<Range<u64>>) {
+    let mut inside = Vec::new();
+    let mut outside = Vec::new();
+    let mut allocated = allocated.iter().peekable();
+    for range in ranges {
+        let mut start = range.start;
+        while start < range.end {
+            while allocated.next_if(|a| a.end <= start).is_some() {}
+            match allocated.peek() {
+                Some(a) if a.start <= start => {
+                    let end = a.end.min(range.end);
+                    inside.push(start..end);
+                    start = end;
+                }
+                Some(a) if a.start < range.end => {
+                    outside.push(start..a.start);
+                    start = a.start;
+                }
+                _ => {
+                    outside.push(start..range.end);
+                    start = range.end;
+                }
+            }
+        }
+    }
+    (inside, outside)
+}
+
+/// Returns the source of each range of the disk, in order.
+async fn disk_map(disk: &LayeredDisk) -> anyhow::Result<Vec<(Range<u64>, MapSource)>> {
+    let control = disk.control();
+    let mut unresolved = vec![0..disk.sector_count()];
+    let mut map = Vec::new();
+    // Sectors of a layer beyond the size of any layer above it are not
+    // visible.
+    let mut visible_sector_count = u64::MAX;
+    for (i, layer) in control.layers().iter().enumerate() {
+        visible_sector_count = visible_sector_count.min(layer.sector_count);
+        let allocated = control
+            .layer_allocation(i)
+            .await
+            .with_context(|| format!("failed to read layer {i}"))?
+            .into_iter()
+            .map(|range| range.start..range.end.min(visible_sector_count))
+            .filter(|range| !range.is_empty())
+            .collect::<Vec<_>>();
+        let (inside, outside) = split_ranges(&unresolved, &allocated);
+        map.extend(inside.into_iter().map(|range| (range, MapSource::Layer(i))));
+        unresolved = outside;
+    }
+    map.extend(unresolved.into_iter().map(|range| (range, MapSource::Zero)));
+    map.sort_by_key(|(range, _)| range.start);
+    map.dedup_by(|(range, source), (prev_range, prev_source)| {
+        if prev_range.end == range.start && prev_source == source {
+            prev_range.end = range.end;
+            true
+        } else {
+            false
+        }
+    });
+    Ok(map)
+}
+
+async fn map(disk: &LayeredDisk, out: &mut impl Write) -> anyhow::Result<()> {
+    let sector_size = disk.sector_size() as u64;
+    let layers = disk.control().layers();
+    writeln!(out, "{:<18} {:<18} source", "offset", "length")?;
+    for (range, source) in disk_map(disk).await? {
+        let source = match source {
+            MapSource::Layer(i) => format!("layer {i} ({})", layers[i].layer_type),
+            MapSource::Zero => "zero".to_owned(),
+        };
+        writeln!(
+            out,
+            "{:#018x} {:#018x} {source}",
+            range.start * sector_size,
+            (range.end - range.start) * sector_size
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Options;
+    use super::open;
+    use super::run;
+    use super::split_ranges;
+    use clap::Parser;
+    use disk_backend::DiskIo;
+    use disk_backend::resolve::ResolveDiskParameters;
+    use guestmem::GuestMemory;
+    use openvmm_helpers::disk_cli::open_disk;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use scsi_buffers::OwnedRequestBuffers;
+    use std::fs::File;
+    use vm_resource::ResourceResolver;
+    use vmcore::vm_task::SingleDriverBackend;
+    use vmcore::vm_task::VmTaskDriverSource;
+
+    const MB: u64 = 1024 * 1024;
+
+    struct TestEnv {
+        resolver: ResourceResolver,
+        driver_source: VmTaskDriverSource,
+        dir: tempfile::TempDir,
+    }
+
+    impl TestEnv {
+        fn new(driver: DefaultDriver) -> Self {
+            Self {
+                resolver: ResourceResolver::new(),
+                driver_source: VmTaskDriverSource::new(SingleDriverBackend::new(driver)),
+                dir: tempfile::tempdir().unwrap(),
+            }
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.dir.path().join(name).display().to_string()
+        }
+
+        fn create_file(&self, name: &str) -> File {
+            File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(self.path(name))
+                .unwrap()
+        }
+
+        /// Runs the tool with `args`, returning its output.
+        async fn run(&self, args: &[&str]) -> String {
+            let options = Options::try_parse_from(
+                std::iter::once("openvmm_disktool").chain(args.iter().copied()),
+            )
+            .unwrap();
+            let mut out = Vec::new();
+            run(
+                &self.resolver,
+                &self.driver_source,
+                options.command,
+                &mut out,
+            )
+            .await
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        }
+
+        /// Writes `data` at `sector` of `image`, opened as OpenVMM opens it.
+        async fn write(&self, image: &str, sector: u64, data: &[u8]) {
+            let disk = self
+                .resolver
+                .resolve(
+                    open_disk(&image.parse().unwrap(), false).await.unwrap(),
+                    ResolveDiskParameters {
+                        read_only: false,
+                        driver_source: &self.driver_source,
+                    },
+                )
+                .await
+                .unwrap()
+                .0;
+            let mem = GuestMemory::allocate(data.len());
+            mem.write_at(0, data).unwrap();
+            disk.write_vectored(
+                &OwnedRequestBuffers::linear(0, data.len(), false).buffer(&mem),
+                sector,
+                false,
+            )
+            .await
+            .unwrap();
+            disk.sync_cache().await.unwrap();
+        }
+
+        /// Reads the full contents of `image`.
+        async fn read(&self, image: &str) -> Vec<u8> {
+            let disk = open(
+                &self.resolver,
+                &self.driver_source,
+                &image.parse().unwrap(),
+                None,
+            )
+            .await
+            .unwrap();
+            let len = super::disk_size(&disk) as usize;
+            let mem = GuestMemory::allocate(len);
+            disk.read_vectored(&OwnedRequestBuffers::linear(0, len, true).buffer(&mem), 0)
+                .await
+                .unwrap();
+            let mut data = vec![0; len];
+            mem.read_at(0, &mut data).unwrap();
+            data
+        }
+
+        /// Creates a 4MB QCOW2 image with a backing file, as `qemu-img`
+        /// would, with data in the first cluster of the backing file and in
+        /// the cluster at 1MB of the top image. Returns the top image's path.
+        async fn qcow2_chain(&self) -> String {
+            let base = self.path("base.qcow2");
+            assert_eq!(
+                self.run(&["create", &base, "--size", "4M"]).await,
+                "created image of 4194304 bytes\n"
+            );
+            self.write(&base, 0, &[1; 4096]).await;
+            disk_qcow2::format(
+                &self.create_file("top.qcow2"),
+                &disk_qcow2::FormatParams {
+                    backing_file: Some("base.qcow2"),
+                    backing_format: Some("qcow2"),
+                    ..disk_qcow2::FormatParams::new(4 * MB)
+                },
+            )
+            .unwrap();
+            let top = self.path("top.qcow2");
+            self.write(&top, MB / 512, &[2; 512]).await;
+            top
+        }
+
+        /// Creates a 4MB VHDX differencing disk with 1MB blocks, with data in
+        /// the same places as [`Self::qcow2_chain`]. Returns the child's path.
+        async fn vhdx_chain(&self) -> String {
+            let base = self.path("base.vhdx");
+            disk_vhdx::format(
+                &self.create_file("base.vhdx"),
+                &disk_vhdx::FormatParams {
+                    block_size: MB as u32,
+                    ..disk_vhdx::FormatParams::new(4 * MB)
+                },
+            )
+            .unwrap();
+            self.write(&base, 0, &[1; 4096]).await;
+
+            let data_write_guid = disk_vhdx::read_info(&File::open(&base).unwrap())
+                .unwrap()
+                .data_write_guid;
+            disk_vhdx::format(
+                &self.create_file("child.vhdx"),
+                &disk_vhdx::FormatParams {
+                    block_size: MB as u32,
+                    parent: Some(disk_vhdx::ParentParams {
+                        data_write_guid,
+                        relative_path: "base.vhdx",
+                        absolute_win32_path: None,
+                    }),
+                    ..disk_vhdx::FormatParams::new(4 * MB)
+                },
+            )
+            .unwrap();
+            let child = self.path("child.vhdx");
+            self.write(&child, MB / 512, &[2; 512]).await;
+            child
+        }
+    }
+
+    /// The contents of the two-image chains created by the tests, such as
+    /// [`TestEnv::qcow2_chain`].
+    fn chain_contents() -> Vec<u8> {
+        let mut data = vec![0; 4 * MB as usize];
+        data[..4096].fill(1);
+        data[MB as usize..MB as usize + 512].fill(2);
+        data
+    }
+
+    fn map_output(ranges: &[(u64, u64, &str)]) -> String {
+        let mut out = format!("{:<18} {:<18} source\n", "offset", "length");
+        for (offset, len, source) in ranges {
+            out += &format!("{offset:#018x} {len:#018x} {source}\n");
+        }
+        out
+    }
+
+    #[test]
+    fn test_split_ranges() {
+        let (inside, outside) = split_ranges(&[0..10, 20..30], &[2..4, 8..22, 25..26, 40..50]);
+        assert_eq!(inside, [2..4, 8..10, 20..22, 25..26]);
+        assert_eq!(outside, [0..2, 4..8, 22..25, 26..30]);
+    }
+
+    #[async_test]
+    async fn vhdx_chain(driver: DefaultDriver) {
+        let env = TestEnv::new(driver);
+        let child = env.vhdx_chain().await;
+
+        // The base image allocates whole blocks, while the child only holds
+        // the written sector.
+        assert_eq!(
+            env.run(&["map", &child]).await,
+            map_output(&[
+                (0, MB, "layer 1 (vhdx)"),
+                (MB, 0x200, "layer 0 (vhdx)"),
+                (MB + 0x200, 3 * MB - 0x200, "zero"),
+            ])
+        );
+        assert!(env.run(&["info", "--allocation", &child]).await.ends_with(
+            "layers:\n  \
+                     0: vhdx, 4194304 bytes, 512 bytes allocated\n  \
+                     1: vhdx, 4194304 bytes, 1048576 bytes allocated\n"
+        ));
+
+        assert!(env.read(&child).await == chain_contents());
+    }
+
+    #[async_test]
+    async fn vhd1_chain(driver: DefaultDriver) {
+        let env = TestEnv::new(driver);
+        let base = env.path("base.vhd");
+        disk_vhd1::format_dynamic(
+            &env.create_file("base.vhd"),
+            &disk_vhd1::FormatParams::new(4 * MB),
+        )
+        .unwrap();
+        env.write(&base, 0, &[1; 4096]).await;
+
+        let info = disk_vhd1::read_info(&File::open(&base).unwrap()).unwrap();
+        disk_vhd1::format_dynamic(
+            &env.create_file("child.vhd"),
+            &disk_vhd1::FormatParams {
+                parent: Some(disk_vhd1::ParentParams {
+                    unique_id: info.unique_id,
+                    time_stamp: info.time_stamp,
+                    relative_path: "base.vhd",
+                    absolute_path: None,
+                }),
+                ..disk_vhd1::FormatParams::new(4 * MB)
+            },
+        )
+        .unwrap();
+        let child = env.path("child.vhd");
+        env.write(&child, MB / 512, &[2; 512]).await;
+
+        // Reads of the child fall through to the parent, which is unchanged.
+        assert!(env.read(&child).await == chain_contents());
+        let mut base_contents = vec![0; 4 * MB as usize];
+        base_contents[..4096].fill(1);
+        assert!(env.read(&base).await == base_contents);
+    }
+
+    #[async_test]
+    async fn qcow2_chain(driver: DefaultDriver) {
+        let env = TestEnv::new(driver);
+        let top = env.qcow2_chain().await;
+        // The images allocate whole 64KB clusters.
+        assert_eq!(
+            env.run(&["map", &top]).await,
+            map_output(&[
+                (0, 0x10000, "layer 1 (qcow2)"),
+                (0x10000, MB - 0x10000, "zero"),
+                (MB, 0x10000, "layer 0 (qcow2)"),
+                (MB + 0x10000, 3 * MB - 0x10000, "zero"),
+            ])
+        );
+    }
+
+    #[async_test]
+    async fn convert_sparse(driver: DefaultDriver) {
+        let env = TestEnv::new(driver);
+        let top = env.qcow2_chain().await;
+        let dest = env.path("dest.qcow2");
+        assert_eq!(env.run(&["convert", &top, &dest]).await, "");
+
+        // The chain is flattened, and only the clusters with data are
+        // allocated.
+        assert_eq!(
+            env.run(&["map", &dest]).await,
+            map_output(&[
+                (0, 0x10000, "layer 0 (qcow2)"),
+                (0x10000, MB - 0x10000, "zero"),
+                (MB, 0x10000, "layer 0 (qcow2)"),
+                (MB + 0x10000, 3 * MB - 0x10000, "zero"),
+            ])
+        );
+        assert!(
+            env.run(&["info", "--allocation", &dest])
+                .await
+                .ends_with("layers:\n  0: qcow2, 4194304 bytes, 131072 bytes allocated\n")
+        );
+        assert!(env.read(&dest).await == env.read(&top).await);
+    }
+
+    #[async_test]
+    async fn commit_qcow2(driver: DefaultDriver) {
+        let env = TestEnv::new(driver);
+        let top = env.qcow2_chain().await;
+        // Only the backing file is written, so the 64KB clusters of the top
+        // image do not prevent committing it.
+        assert_eq!(
+            env.run(&["commit", &top]).await,
+            "committed qcow2 layer into qcow2 layer\n"
+        );
+        assert!(env.read(&env.path("base.qcow2")).await == chain_contents());
+    }
+
+    #[async_test]
+    async fn commit_vhdx(driver: DefaultDriver) {
+        let env = TestEnv::new(driver);
+        let child = env.vhdx_chain().await;
+        assert_eq!(
+            env.run(&["commit", &child]).await,
+            "committed vhdx layer into vhdx layer\n"
+        );
+        assert!(env.read(&env.path("base.vhdx")).await == chain_contents());
+    }
+
+    // The diff syntax does not allow a colon in the diff layer's path.
+    #[cfg(all(feature = "disklayer_sqlite", not(windows)))]
+    #[async_test]
+    async fn commit_sqlite_diff(driver: DefaultDriver) {
+        let env = TestEnv::new(driver);
+        let base = env.path("base.img");
+        env.run(&["create", &base, "--size", "4M"]).await;
+        env.write(&base, 0, &[1; 4096]).await;
+        let diff = format!("sqldiff:{}:{base}", env.path("diff.dbhd"));
+        assert_eq!(
+            env.run(&["create", &diff]).await,
+            "created image of 4194304 bytes\n"
+        );
+        env.write(&diff, MB / 512, &[2; 512]).await;
+        assert!(env.read(&diff).await == chain_contents());
+
+        assert_eq!(
+            env.run(&["commit", &diff]).await,
+            "committed sqlite layer into disk layer\n"
+        );
+        assert!(env.read(&base).await == chain_contents());
+    }
+}
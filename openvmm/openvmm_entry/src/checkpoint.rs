@@ -0,0 +1,328 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Checkpoints: a tree of snapshots that also capture disk contents
+//! (`--checkpoint-dir`).
+//!
+//! Each layered disk runs with a sqlite differencing layer on top, stored in
+//! the tree's pending directory. Taking a checkpoint writes a portable
+//! snapshot into the pending directory, freezing that layer as the disk's
+//! changes since the parent checkpoint, and starts a new pending directory
+//! with a new layer. Reverting rebuilds each disk's stack from the deltas of
+//! the target checkpoint and its ancestors.
+
+use crate::snapshot_layer;
+use crate::with_vm_paused;
+use anyhow::Context;
+use disk_backend_resources::DiskLayerDescription;
+use disk_backend_resources::LayeredDiskRequest;
+use disk_backend_resources::ReplaceTopLayers;
+use disk_backend_resources::layer::SqliteDiskLayerHandle;
+use mesh::payload::message::ProtobufMessage;
+use mesh::rpc::RpcSend;
+use openvmm_defs::rpc::VmRpc;
+use openvmm_helpers::snapshot::SnapshotManifest;
+use openvmm_helpers::snapshot_tree::SnapshotTree;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use vm_resource::IntoResource;
+
+/// A checkpoint, as listed by [`Checkpoints::list`].
+#[derive(mesh::MeshPayload)]
+pub struct CheckpointInfo {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    /// The parent checkpoint's ID, or empty for a root.
+    pub parent: String,
+    /// Creation time, in seconds since the Unix epoch.
+    pub created: i64,
+    /// Whether the VM was last checkpointed to or reverted to this checkpoint.
+    pub current: bool,
+}
+
+/// The checkpoint tree of a running VM.
+pub(crate) struct Checkpoints {
+    tree: SnapshotTree,
+    /// The most recent checkpoint taken or reverted to.
+    current: Option<String>,
+    /// The pending directory holding each disk's active layer.
+    pending: String,
+    disks: BTreeMap<String, mesh::Sender<LayeredDiskRequest>>,
+    /// The number of layers added on top of each disk's configured layers.
+    layers: BTreeMap<String, u32>,
+    memory_file: PathBuf,
+}
+
+impl Checkpoints {
+    /// Opens the checkpoint tree at `dir` and redirects writes to `disks`
+    /// into new layers in the tree. The VM must not be running yet.
+    pub async fn open(
+        dir: &Path,
+        disks: BTreeMap<String, mesh::Sender<LayeredDiskRequest>>,
+        memory_file: PathBuf,
+    ) -> anyhow::Result<Self> {
+        let tree = SnapshotTree::open(dir)
+            .with_context(|| format!("failed to open checkpoint directory {}", dir.display()))?;
+        let mut this = Self {
+            pending: tree.create_pending()?,
+            tree,
+            current: None,
+            disks,
+            layers: BTreeMap::new(),
+            memory_file,
+        };
+        this.add_active_layers().await?;
+        Ok(this)
+    }
+
+    /// Adds a new writable layer to each disk, in the pending directory.
+    async fn add_active_layers(&mut self) -> anyhow::Result<()> {
+        for (name, rpc) in &self.disks {
+            let layer = snapshot_layer(Some(&self.tree.disk_path(&self.pending, name)))?;
+            rpc.call_failable(LayeredDiskRequest::AddTopLayer, layer)
+                .await
+                .with_context(|| format!("failed to add checkpoint layer to disk {name}"))?;
+            *self.layers.entry(name.clone()).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    /// Takes a checkpoint, returning its ID.
+    ///
+    /// `manifest` describes the VM; its name and description must already be
+    /// set.
+    pub async fn checkpoint(
+        &mut self,
+        vm_rpc: &mesh::Sender<VmRpc>,
+        manifest: SnapshotManifest,
+    ) -> anyhow::Result<String> {
+        if !manifest.name.is_empty()
+            && self
+                .tree
+                .list()?
+                .iter()
+                .any(|e| e.manifest.name == manifest.name)
+        {
+            anyhow::bail!("a checkpoint named {} already exists", manifest.name);
+        }
+
+        with_vm_paused(vm_rpc, async {
+            let state = vm_rpc
+                .call_failable(VmRpc::Save, ())
+                .await
+                .context("failed to save state")?;
+
+            let id = self.pending.clone();
+            let manifest = SnapshotManifest {
+                parent: self.current.clone().unwrap_or_default(),
+                disks: self.disks.keys().cloned().collect(),
+                ..manifest
+            };
+            openvmm_helpers::snapshot::write_portable_snapshot(
+                &self.tree.path(&id),
+                &manifest,
+                &mesh::payload::encode(state),
+                &self.memory_file,
+            )?;
+
+            // The current layers are now part of the checkpoint. Stack new
+            // ones on top for the changes that follow.
+            self.pending = self.tree.create_pending()?;
+            self.current = Some(id.clone());
+            self.add_active_layers().await?;
+            anyhow::Ok(id)
+        })
+        .await
+    }
+
+    /// Reverts the VM's memory, device state, and disks to the checkpoint
+    /// `name_or_id`, discarding changes made since the last checkpoint.
+    pub async fn revert(
+        &mut self,
+        vm_rpc: &mesh::Sender<VmRpc>,
+        name_or_id: &str,
+        validate: impl FnOnce(&SnapshotManifest) -> anyhow::Result<()>,
+    ) -> anyhow::Result<String> {
+        let target = self.tree.resolve(name_or_id)?;
+        validate(&target.manifest)?;
+        anyhow::ensure!(
+            target.manifest.compressed_memory,
+            "checkpoint {} has no memory image",
+            target.id
+        );
+        let chain = self.tree.ancestors(&target.id)?;
+        let state_bytes = fs_err::read(self.tree.path(&target.id).join("state.bin"))?;
+        let state: ProtobufMessage =
+            mesh::payload::decode(&state_bytes).context("failed to decode saved state")?;
+
+        with_vm_paused(vm_rpc, async {
+            let pending = self.tree.create_pending()?;
+            for (name, rpc) in &self.disks {
+                // The new active layer, then the checkpoint deltas, newest
+                // first.
+                let mut layers = vec![snapshot_layer(Some(&self.tree.disk_path(&pending, name)))?];
+                for entry in chain.iter().rev() {
+                    if entry.manifest.disks.contains(name) {
+                        layers.push(DiskLayerDescription::from(
+                            SqliteDiskLayerHandle {
+                                dbhd_path: self
+                                    .tree
+                                    .disk_path(&entry.id, name)
+                                    .display()
+                                    .to_string(),
+                                format_dbhd: None,
+                            }
+                            .into_resource(),
+                        ));
+                    }
+                }
+                let count = layers.len() as u32;
+                rpc.call_failable(
+                    LayeredDiskRequest::ReplaceTopLayers,
+                    ReplaceTopLayers {
+                        remove: self.layers.get(name).copied().unwrap_or(0),
+                        layers,
+                    },
+                )
+                .await
+                .with_context(|| format!("failed to revert disk {name}"))?;
+                self.layers.insert(name.clone(), count);
+            }
+
+            let old_pending = std::mem::replace(&mut self.pending, pending);
+            if let Err(err) = self.tree.discard_pending(&old_pending) {
+                tracing::warn!(
+                    error = err.as_ref() as &dyn std::error::Error,
+                    "failed to remove pending checkpoint"
+                );
+            }
+
+            openvmm_helpers::snapshot::restore_memory(
+                &self.tree.path(&target.id),
+                &target.manifest,
+                &self.memory_file,
+            )
+            .context("failed to restore checkpoint memory")?;
+
+            vm_rpc
+                .call_failable(VmRpc::Restore, state)
+                .await
+                .context("failed to restore device state")?;
+            self.current = Some(target.id.clone());
+            anyhow::Ok(target.id)
+        })
+        .await
+    }
+
+    /// Deletes the checkpoint `name_or_id`.
+    pub fn delete(&self, name_or_id: &str) -> anyhow::Result<String> {
+        let entry = self.tree.resolve(name_or_id)?;
+        self.tree.delete(&entry.id, self.current.as_deref())?;
+        Ok(entry.id)
+    }
+
+    /// Lists the checkpoints, oldest first.
+    pub fn list(&self) -> anyhow::Result<Vec<CheckpointInfo>> {
+        Ok(self
+            .tree
+            .list()?
+            .into_iter()
+            .map(|e| CheckpointInfo {
+                current: self.current.as_deref() == Some(e.id.as_str()),
+                id: e.id,
+                name: e.manifest.name,
+                description: e.manifest.description,
+                parent: e.manifest.parent,
+                created: e.manifest.created_at.seconds,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use pal_async::DefaultPool;
+    use pal_async::task::Spawn;
+
+    /// Serves the requests a checkpoint makes of a paused VM with no devices.
+    async fn fake_vm(mut recv: mesh::Receiver<VmRpc>) {
+        while let Some(rpc) = recv.next().await {
+            match rpc {
+                VmRpc::Pause(rpc) | VmRpc::Resume(rpc) => rpc.complete(false),
+                VmRpc::Save(rpc) => rpc.complete(Ok(ProtobufMessage::new(()))),
+                VmRpc::Restore(rpc) => rpc.complete(Ok(())),
+                _ => panic!("unexpected vm rpc"),
+            }
+        }
+    }
+
+    fn test_manifest(name: &str) -> SnapshotManifest {
+        SnapshotManifest {
+            version: openvmm_helpers::snapshot::MANIFEST_VERSION,
+            created_at: std::time::SystemTime::now().into(),
+            openvmm_version: String::new(),
+            memory_size_bytes: 0,
+            vp_count: 1,
+            page_size: 4096,
+            architecture: crate::GUEST_ARCH.to_string(),
+            compressed_memory: false,
+            vm_args: Vec::new(),
+            name: name.to_string(),
+            description: String::new(),
+            parent: String::new(),
+            disks: Vec::new(),
+            configured_disks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn revert_clears_pages_dirtied_after_checkpoint() {
+        DefaultPool::run_with(async |driver| {
+            let dir = tempfile::tempdir().unwrap();
+            let memory_file = dir.path().join("memory.bin");
+
+            // Page 1 is zero at checkpoint time.
+            let mut mem = vec![0x11u8; 4 * 4096];
+            mem[4096..2 * 4096].fill(0);
+            std::fs::write(&memory_file, &mem).unwrap();
+
+            let (vm_rpc, recv) = mesh::channel();
+            let _vm = driver.spawn("fake-vm", fake_vm(recv));
+            let mut checkpoints = Checkpoints::open(
+                &dir.path().join("checkpoints"),
+                BTreeMap::new(),
+                memory_file.clone(),
+            )
+            .await
+            .unwrap();
+            let id = checkpoints
+                .checkpoint(&vm_rpc, test_manifest("base"))
+                .await
+                .unwrap();
+
+            // The guest writes to every page, including the zero one.
+            std::fs::write(&memory_file, vec![0xeeu8; mem.len()]).unwrap();
+
+            let reverted = checkpoints
+                .revert(&vm_rpc, "base", |_| Ok(()))
+                .await
+                .unwrap();
+            assert_eq!(reverted, id);
+            assert!(
+                std::fs::read(&memory_file).unwrap() == mem,
+                "stale memory after revert"
+            );
+            assert!(
+                checkpoints
+                    .list()
+                    .unwrap()
+                    .iter()
+                    .any(|c| c.current && c.id == id)
+            );
+        })
+    }
+}
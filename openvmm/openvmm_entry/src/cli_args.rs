@@ -19,10 +19,26 @@
 #![warn(missing_docs)]
 
 use anyhow::Context;
+use clap::ArgMatches;
+use clap::Command;
+use clap::CommandFactory;
+use clap::FromArgMatches;
 use clap::Parser;
 use clap::ValueEnum;
 use cxl_spec::spec::CfmwsWindowRestrictions;
 use guid::Guid;
+use net_backend_resources::consomme::DnsRecord;
+use net_backend_resources::consomme::DnsRecordData;
+use net_backend_resources::consomme::EgressAction;
+use net_backend_resources::consomme::EgressPolicy;
+use net_backend_resources::consomme::EgressProtocol;
+use net_backend_resources::consomme::EgressRule;
+use net_backend_resources::consomme::GuestForwardConfig;
+use net_backend_resources::consomme::GuestForwardTarget;
+use net_backend_resources::impair::ImpairmentConfig;
+use net_backend_resources::impair::LinkImpairment;
+#[cfg(target_os = "linux")]
+use net_backend_resources::mac_address::MacAddress;
 use openvmm_defs::config::DEFAULT_PCAT_BOOT_ORDER;
 use openvmm_defs::config::DeviceVtl;
 use openvmm_defs::config::PcatBootDevice;
@@ -34,35 +50,111 @@
 use std::str::FromStr;
 use thiserror::Error;
 
+pub use openvmm_helpers::disk_cli::DiskCliKind;
+pub(crate) use openvmm_helpers::disk_cli::parse_memory;
+
 /// Parse CLI options, using a thread with a larger stack on Windows to avoid
 /// stack overflow in debug builds due to clap's deep stack usage.
 /// See <https://github.com/clap-rs/clap/issues/5134>.
-pub(crate) fn parse_options() -> Options {
-    // In non-optimized builds, clap uses an embarrassing amount of stack space
-    // to construct the `Command` instance for `Options`, more than the Windows
-    // default of 1MB. This has been known since 2023:
-    // <https://github.com/clap-rs/clap/issues/5134>, but no one has stepped up
-    // to fix it.
-    //
-    // Work around this by running the code on a thread with lots of stack
-    // space. This is easier and more reliable than configuring the PE binary to
-    // have a larger stack.
-    fn on_big_stack<R: Send>(f: impl Send + FnOnce() -> R) -> R {
-        if cfg!(windows) {
-            std::thread::scope(|s| {
-                std::thread::Builder::new()
-                    .stack_size(0x400000)
-                    .spawn_scoped(s, f)
-                    .unwrap()
-                    .join()
-                    .unwrap()
-            })
-        } else {
-            f()
+///
+/// Options from the `--config` file, if any, are parsed as if they came
+/// before the command-line arguments. Options set on the command line replace
+/// the file's, except for options that can be repeated, such as `--disk`,
+/// whose values are added to the file's.
+///
+/// With `--restore-snapshot`, the VM configuration recorded in the snapshot is
+/// used, and options set on the command line replace the recorded ones.
+pub(crate) fn parse_options() -> anyhow::Result<Options> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    on_big_stack(|| {
+        let mut command = Options::command();
+        let mut matches = command.clone().get_matches_from(&args);
+        if let Some(path) = matches.get_one::<PathBuf>("config") {
+            let file_args = crate::config_file::ConfigFile::load(path)?
+                .to_args(&command, Some(&matches))
+                .with_context(|| format!("invalid configuration in {}", path.display()))?;
+            let args = args
+                .iter()
+                .take(1)
+                .cloned()
+                .chain(file_args.into_iter().map(OsString::from))
+                .chain(args.iter().skip(1).cloned());
+            matches = command.clone().get_matches_from(args);
         }
+        let snapshot_cli = apply_snapshot_config(&command, &mut matches)?;
+        let mut opt = Options::from_arg_matches(&matches)
+            .unwrap_or_else(|err| err.format(&mut command).exit());
+        opt.vm_args = crate::config_file::canonical_args(&command, &matches)?;
+        if let Some(cli) = snapshot_cli {
+            // Guest memory comes from the snapshot, not from the backing file
+            // the VM was originally started with.
+            if !crate::config_file::set_on_command_line(&cli, "memory") {
+                opt.memory.file = None;
+            }
+        }
+        Ok(opt)
+    })
+}
+
+/// Merges the VM configuration recorded in the snapshot given with
+/// `--restore-snapshot`, if any, into `matches`.
+///
+/// This allows a snapshot to be restored (possibly on another host) without
+/// repeating the options the VM was originally started with, while still
+/// letting the command line override them, for example to give the new paths
+/// of the disks. Returns the original matches if the configuration was
+/// merged.
+fn apply_snapshot_config(
+    command: &Command,
+    matches: &mut ArgMatches,
+) -> anyhow::Result<Option<ArgMatches>> {
+    let Some(dir) = matches.get_one::<PathBuf>("restore_snapshot") else {
+        return Ok(None);
+    };
+    let manifest = openvmm_helpers::snapshot::read_manifest(dir)?;
+    if manifest.vm_args.is_empty() {
+        return Ok(None);
     }
+    let recorded = command
+        .clone()
+        .try_get_matches_from(std::iter::once("openvmm".to_owned()).chain(manifest.vm_args))
+        .context("failed to parse the VM configuration recorded in the snapshot")?;
+    // The memory backing file and checkpoint tree belong to the original VM.
+    let args = crate::config_file::override_args(
+        command,
+        &recorded,
+        matches,
+        &["deprecated_memory_backing_file", "checkpoint_dir"],
+    )?;
+    let merged = command
+        .clone()
+        .try_get_matches_from(std::iter::once("openvmm".to_owned()).chain(args))
+        .context("the command line conflicts with the snapshot's VM configuration")?;
+    Ok(Some(std::mem::replace(matches, merged)))
+}
 
-    on_big_stack(Options::parse)
+// In non-optimized builds, clap uses an embarrassing amount of stack space
+// to construct the `Command` instance for `Options`, more than the Windows
+// default of 1MB. This has been known since 2023:
+// <https://github.com/clap-rs/clap/issues/5134>, but no one has stepped up
+// to fix it.
+//
+// Work around this by running the code on a thread with lots of stack
+// space. This is easier and more reliable than configuring the PE binary to
+// have a larger stack.
+fn on_big_stack<R: Send>(f: impl Send + FnOnce() -> R) -> R {
+    if cfg!(windows) {
+        std::thread::scope(|s| {
+            std::thread::Builder::new()
+                .stack_size(0x400000)
+                .spawn_scoped(s, f)
+                .unwrap()
+                .join()
+                .unwrap()
+        })
+    } else {
+        f()
+    }
 }
 
 const DEFAULT_MEMORY_SIZE: u64 = 1024 * 1024 * 1024;
@@ -213,7 +305,10 @@ pub struct Options {
     pub deprecated_memory_backing_file: Option<PathBuf>,
 
     /// Restore VM from a snapshot directory (implies file-backed memory from
-    /// the snapshot's memory.bin). Cannot be used with --memory-backing-file.
+    /// the snapshot's memory.bin, or from its compressed memory image for
+    /// portable snapshots). Cannot be used with --memory-backing-file. The VM
+    /// configuration recorded in the snapshot is used, with any options given
+    /// on the command line replacing the recorded ones.
     #[clap(
         long,
         value_name = "DIR",
@@ -221,6 +316,32 @@ pub struct Options {
     )]
     pub restore_snapshot: Option<PathBuf>,
 
+    /// The arguments that configured the VM, recorded in snapshots.
+    #[clap(skip)]
+    pub vm_args: Vec<String>,
+
+    /// Keep a tree of checkpoints in DIR, managed with the `checkpoint`,
+    /// `checkpoints`, `revert`, and `delete-checkpoint` interactive commands.
+    /// Disk writes go to differencing layers in DIR, so the configured disks
+    /// are not modified. Requires `--memory file=<path>`.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["restore_snapshot", "migrate_incoming"]
+    )]
+    pub checkpoint_dir: Option<PathBuf>,
+
+    /// Receive a live-migrated VM instead of booting. Listens on ADDR
+    /// (`unix:<path>` or `tcp:<host>:<port>`) for a single connection from
+    /// the source's `migrate` command. The VM must be configured identically
+    /// to the source.
+    #[clap(
+        long,
+        value_name = "ADDR",
+        conflicts_with_all = ["restore_snapshot"]
+    )]
+    pub migrate_incoming: Option<MigrationAddress>,
+
     /// use private anonymous memory for guest RAM
     #[clap(long = "private-memory", hide = true, conflicts_with_all = ["deprecated_memory_backing_file", "restore_snapshot", "numa"])]
     pub deprecated_private_memory: bool,
@@ -324,6 +445,9 @@ pub struct Options {
         `;direct`: bypass the OS page cache
     `sql:<path>[;create=<len>]`    SQLite-backed disk (dev/test)
     `sqldiff:<path>[;create]:<disk>` SQLite diff layer on a backing disk
+    `qcow2:<path>[;create=<len>]`  QCOW2 image, with its backing file chain
+    `qcow2diff:<path>[;create]:<disk>` QCOW2 diff layer on a backing disk
+    `vhdx:<path>[;create=<len>]`   VHDX image, with its differencing parent chain
     `autocache:<key>:<disk>`       auto-cached SQLite layer (use `autocache::<disk>` to omit key; needs OPENVMM_AUTO_CACHE_PATH)
     `blob:<type>:<url>`            HTTP blob (read-only)
         <type>: `flat` or `vhd1`
@@ -334,6 +458,7 @@ pub struct Options {
 flags:
     `ro`                           open disk as read-only
     `dvd`                          specifies that device is cd/dvd and it is read_only
+    `snapshot`                     support live snapshots of the disk (see `snapshot-disk`), incompatible with `dvd` and `prwrap:`
     `vtl2`                         assign this disk to VTL2
     `uh`                           relay this disk to VTL0 through SCSI-to-OpenHCL (show to VTL0 as SCSI)
     `uh-nvme`                      relay this disk to VTL0 through NVMe-to-OpenHCL (show to VTL0 as SCSI)
@@ -366,6 +491,9 @@ pub struct Options {
         `;direct`: bypass the OS page cache
     `sql:<path>[;create=<len>]`    SQLite-backed disk (dev/test)
     `sqldiff:<path>[;create]:<disk>` SQLite diff layer on a backing disk
+    `qcow2:<path>[;create=<len>]`  QCOW2 image, with its backing file chain
+    `qcow2diff:<path>[;create]:<disk>` QCOW2 diff layer on a backing disk
+    `vhdx:<path>[;create=<len>]`   VHDX image, with its differencing parent chain
     `autocache:<key>:<disk>`       auto-cached SQLite layer (use `autocache::<disk>` to omit key; needs OPENVMM_AUTO_CACHE_PATH)
     `blob:<type>:<url>`            HTTP blob (read-only)
         <type>: `flat` or `vhd1`
@@ -375,6 +503,7 @@ pub struct Options {
 
 flags:
     `ro`                           open disk as read-only
+    `snapshot`                     support live snapshots of the disk (see `snapshot-disk`), incompatible with `prwrap:`
     `vtl2`                         assign this disk to VTL2
     `uh`                           relay this disk to VTL0 through SCSI-to-OpenHCL (show to VTL0 as NVMe)
     `uh-nvme`                      relay this disk to VTL0 through NVMe-to-OpenHCL (show to VTL0 as NVMe)
@@ -471,6 +600,7 @@ pub struct Options {
 
 flags:
     `ro`                           open disk as read-only
+    `snapshot`                     support live snapshots of the disk (see `snapshot-disk`), incompatible with `prwrap:`
 
 options:
     `pcie_port=<name>`             present the disk using pcie under the specified port
@@ -483,11 +613,12 @@ pub struct Options {
     /// The first positional argument is the socket path. Options:
     ///
     /// ```text
-    ///   type=blk|fs                        — device type (shorthand)
+    ///   type=blk|fs|net                    — device type (shorthand)
     ///   device_id=N                        — numeric virtio device ID
     ///   tag=NAME                           — mount tag (required for type=fs)
-    ///   num_queues=N                       — queue count (type=blk/fs only)
-    ///   queue_size=N                       — per-queue size (type=blk/fs only)
+    ///   mac=XX-XX-XX-XX-XX-XX              — MAC address (type=net only; default random)
+    ///   num_queues=N                       — queue count, or queue pairs for type=net
+    ///   queue_size=N                       — per-queue size (type=blk/fs/net only)
     ///   queue_sizes=[N,N,N]                — per-queue sizes (device_id= only)
     ///   pcie_port=NAME                     — present on PCIe under the specified port
     /// ```
@@ -501,6 +632,7 @@ pub struct Options {
     ///   --vhost-user /tmp/vhost.sock,type=blk,pcie_port=port0
     ///   --vhost-user /tmp/virtiofsd.sock,type=fs,tag=myfs
     ///   --vhost-user /tmp/virtiofsd.sock,type=fs,tag=myfs,num_queues=2,queue_size=1024
+    ///   --vhost-user /tmp/vhost-net.sock,type=net,num_queues=4
     /// ```
     #[cfg(target_os = "linux")]
     #[clap(long = "vhost-user")]
@@ -514,20 +646,62 @@ pub struct Options {
     #[clap(long)]
     pub nic: bool,
 
-    /// expose a virtual NIC with the given backend (consomme | dio | tap | none)
+    /// expose a virtual NIC with the given backend (consomme | dio | tap |
+    /// stream | dgram | none)
     ///
     /// Prefix with `uh:` to add this NIC via Mana emulation through OpenHCL,
-    /// `vtl2:` to assign this NIC to VTL2, or `pcie_port=<port_name>:` to
-    /// expose the NIC over emulated PCIe at the specified port.
+    /// or `vtl2:` to assign this NIC to VTL2.
+    ///
+    /// Prefix with `e1000e:` to expose an emulated Intel 82574 NIC instead of
+    /// a netvsc NIC, for guests without paravirtualized network drivers. It
+    /// is placed on the PCI bus, or, with `pcie_port=<port_name>:`, on the
+    /// specified emulated PCIe port.
+    ///
+    /// Prefix with `impair=<settings>:` to add delay, jitter, loss,
+    /// duplication, reordering, or a rate limit to the NIC's traffic, e.g.
+    /// `impair=delay=50ms,jitter=10ms,loss=1%,rate=10mbit:consomme`. Prefix a
+    /// setting with `tx.` or `rx.` to apply it in one direction only.
+    ///
+    /// For tap, append `,vhost` (e.g. `tap:tap0,vhost`) to move frames through
+    /// the kernel's vhost-net driver instead of reading and writing them one at
+    /// a time. With `queues=<N>:`, N queues of a multiqueue TAP interface are
+    /// used.
     ///
     /// For consomme, forward host ports into the guest with `hostfwd=`:
     ///   --net consomme:hostfwd=tcp::3389-:3389
     ///   --net consomme:hostfwd=tcp:127.0.0.1:8080-:80
     ///   --net consomme:hostfwd=tcp:\[::1\]:8080-:80
     ///   --net consomme:10.0.0.0/24,hostfwd=tcp::22-:22,hostfwd=udp::5000-:5000
+    ///
+    /// Restrict the guest's access to the host network with `restrict` (deny
+    /// by default) and `allow=`/`deny=` rules, matched in order, of the form
+    /// `[tcp:|udp:|icmp:]addr[/prefix][:port[-port]]`. Expose a host service
+    /// at a virtual guest address with `guestfwd=`:
+    ///   --net consomme:restrict,allow=tcp:192.0.2.10:443
+    ///   --net consomme:deny=10.0.0.0/8,deny=192.168.0.0/16
+    ///   --net consomme:restrict,guestfwd=tcp:10.0.0.100:80-127.0.0.1:8080
+    ///   --net consomme:guestfwd=tcp:10.0.0.100:80-unix:/tmp/artifacts.sock
+    ///
+    /// Answer DNS queries for a name locally with `dns=<name>=<target>`, where
+    /// the target is an IPv4 address (A), an IPv6 address (AAAA), or another
+    /// name (CNAME). Log every guest DNS query and response with `dnslog`:
+    ///   --net consomme:dns=build.test=10.0.0.100,dns=cache.test=build.test
+    ///   --net consomme:dnslog
+    ///
+    /// To connect VMs without privileges, use `stream:<path>` to connect to a
+    /// Unix stream socket (such as one from `--net-switch`), or
+    /// `dgram:<local_path>:<remote_path>` to exchange datagrams between two
+    /// Unix socket paths.
     #[clap(long)]
     pub net: Vec<NicConfigCli>,
 
+    /// run a learning Ethernet switch listening on the given Unix socket
+    /// path, instead of a VM
+    ///
+    /// VMs attach to the switch with `--net stream:<path>`.
+    #[clap(long, value_name = "PATH")]
+    pub net_switch: Option<PathBuf>,
+
     /// expose a virtual NIC using the Windows kernel-mode vmswitch.
     ///
     /// Specify the switch ID or "default" for the default switch.
@@ -719,6 +893,41 @@ pub struct Options {
     #[clap(long, value_name = "PORT", requires("virtio_rng"))]
     pub virtio_rng_pcie_port: Option<String>,
 
+    /// add a virtio memory balloon device
+    ///
+    /// The balloon target can be changed at runtime with the `balloon`
+    /// interactive command.
+    #[clap(long)]
+    pub virtio_balloon: bool,
+
+    /// let the guest deflate the virtio balloon when it runs out of memory
+    #[clap(long, requires("virtio_balloon"))]
+    pub virtio_balloon_deflate_on_oom: bool,
+
+    /// add virtio-input keyboard and tablet devices
+    ///
+    /// These receive VNC input in guests without PS/2 or VMBus input
+    /// devices.
+    #[clap(long)]
+    pub virtio_input: bool,
+
+    /// add a virtio-gpu display device
+    ///
+    /// The guest's display is shown through the VNC server and in
+    /// screenshots, as with the other video devices.
+    #[clap(long, conflicts_with_all(["gfx", "pcat"]))]
+    pub virtio_gpu: bool,
+
+    /// the display resolution offered to the virtio-gpu guest driver
+    #[clap(
+        long,
+        value_name = "WIDTHxHEIGHT",
+        default_value = "1024x768",
+        value_parser = parse_resolution,
+        requires("virtio_gpu")
+    )]
+    pub virtio_gpu_resolution: (u32, u32),
+
     /// virtio console device backed by a serial backend (/dev/hvc0 in guest)
     ///
     /// Accepts serial config (console | stderr | listen=\<path\> |
@@ -740,10 +949,27 @@ pub struct Options {
     ///
     /// Prefix with `uh:` to add this NIC via Mana emulation through OpenHCL,
     /// `vtl2:` to assign this NIC to VTL2, or `pcie_port=<port_name>:` to
-    /// expose the NIC over emulated PCIe at the specified port.
+    /// expose the NIC over emulated PCIe at the specified port. Prefix with
+    /// `queues=<N>:` to limit the number of queue pairs, or `mtu=<N>:` to
+    /// report an MTU to the guest.
+    ///
+    /// For tap, append `,vhost` (e.g. `tap:tap0,vhost`) to move frames through
+    /// the kernel's vhost-net driver, using one queue of a multiqueue TAP
+    /// interface per queue pair.
     #[clap(long)]
     pub virtio_net: Vec<NicConfigCli>,
 
+    /// load VM options from a TOML (or, with a `.json` extension, JSON)
+    /// configuration file. Options on the command line replace the ones in
+    /// the file, or for repeatable options such as `--disk`, are added to them.
+    #[clap(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// print the effective VM configuration, in the `--config` file format,
+    /// and exit
+    #[clap(long)]
+    pub dump_config: bool,
+
     /// send log output from the worker process to a file instead of stderr. the file will be overwritten.
     #[clap(long, value_name = "PATH")]
     pub log_file: Option<PathBuf>,
@@ -940,6 +1166,9 @@ pub struct Options {
         <path>: path to file
     `sql:<path>[;create=<len>]`    SQLite-backed disk (dev/test)
     `sqldiff:<path>[;create]:<disk>` SQLite diff layer on a backing disk
+    `qcow2:<path>[;create=<len>]`  QCOW2 image, with its backing file chain
+    `qcow2diff:<path>[;create]:<disk>` QCOW2 diff layer on a backing disk
+    `vhdx:<path>[;create=<len>]`   VHDX image, with its differencing parent chain
     `blob:<type>:<url>`            HTTP blob (read-only)
         <type>: `flat` or `vhd1`
     `crypt:<cipher>:<key_file>:<disk>` encrypted disk wrapper
@@ -972,6 +1201,9 @@ pub struct Options {
         <path>: path to file
     `sql:<path>[;create=<len>]`    SQLite-backed disk (dev/test)
     `sqldiff:<path>[;create]:<disk>` SQLite diff layer on a backing disk
+    `qcow2:<path>[;create=<len>]`  QCOW2 image, with its backing file chain
+    `qcow2diff:<path>[;create]:<disk>` QCOW2 diff layer on a backing disk
+    `vhdx:<path>[;create=<len>]`   VHDX image, with its differencing parent chain
     `blob:<type>:<url>`            HTTP blob (read-only)
         <type>: `flat` or `vhd1`
     `crypt:<cipher>:<key_file>:<disk>` encrypted disk wrapper
@@ -1297,6 +1529,9 @@ pub fn validate_memory_options(&self) -> anyhow::Result<()> {
         if self.memory.shared == Some(true) && self.deprecated_private_memory {
             anyhow::bail!("--memory shared=on conflicts with --private-memory");
         }
+        if self.checkpoint_dir.is_some() && self.memory_backing_file().is_none() {
+            anyhow::bail!("--checkpoint-dir requires --memory file=...");
+        }
         Ok(())
     }
 }
@@ -1389,6 +1624,18 @@ fn parse_guest_power_action(s: &str) -> Result<GuestPowerAction, String> {
     }
 }
 
+/// Parse a display resolution of the form `<width>x<height>`.
+fn parse_resolution(s: &str) -> Result<(u32, u32), String> {
+    let (width, height) = s
+        .split_once('x')
+        .ok_or_else(|| format!("expected <width>x<height>, got '{s}'"))?;
+    let parse = |v: &str| match v.parse::<u32>() {
+        Ok(0) | Err(_) => Err(format!("invalid dimension '{v}'")),
+        Ok(v) => Ok(v),
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
 #[derive(Copy, Clone, clap::ValueEnum)]
 pub enum VirtioBusCli {
     Auto,
@@ -1439,35 +1686,6 @@ pub enum SecureBootTemplateCli {
     UefiCa,
 }
 
-fn parse_memory(s: &str) -> anyhow::Result<u64> {
-    if s == "VMGS_DEFAULT" {
-        Ok(vmgs_format::VMGS_DEFAULT_CAPACITY)
-    } else {
-        || -> Option<u64> {
-            let mut b = s.as_bytes();
-            if s.ends_with('B') {
-                b = &b[..b.len() - 1]
-            }
-            if b.is_empty() {
-                return None;
-            }
-            let multi = match b[b.len() - 1] as char {
-                'T' => Some(1024 * 1024 * 1024 * 1024),
-                'G' => Some(1024 * 1024 * 1024),
-                'M' => Some(1024 * 1024),
-                'K' => Some(1024),
-                _ => None,
-            };
-            if multi.is_some() {
-                b = &b[..b.len() - 1]
-            }
-            let n: u64 = std::str::from_utf8(b).ok()?.parse().ok()?;
-            n.checked_mul(multi.unwrap_or(1))
-        }()
-        .with_context(|| format!("invalid memory size '{0}'", s))
-    }
-}
-
 /// Parses an address, which must be a `0x`-prefixed hexadecimal value.
 fn parse_address(s: &str) -> anyhow::Result<u64> {
     let hex = s
@@ -1733,239 +1951,6 @@ fn parse_number(s: &str) -> Result<u64, std::num::ParseIntError> {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum DiskCliKind {
-    // mem:<len>
-    Memory(u64),
-    // memdiff:<kind>
-    MemoryDiff(Box<DiskCliKind>),
-    // sql:<path>[;create=<len>]
-    Sqlite {
-        path: PathBuf,
-        create_with_len: Option<u64>,
-    },
-    // sqldiff:<path>[;create]:<kind>
-    SqliteDiff {
-        path: PathBuf,
-        create: bool,
-        disk: Box<DiskCliKind>,
-    },
-    // autocache:[key]:<kind>
-    AutoCacheSqlite {
-        cache_path: String,
-        key: Option<String>,
-        disk: Box<DiskCliKind>,
-    },
-    // prwrap:<kind>
-    PersistentReservationsWrapper(Box<DiskCliKind>),
-    // file:<path>[;direct][;create=<len>]
-    File {
-        path: PathBuf,
-        create_with_len: Option<u64>,
-        direct: bool,
-    },
-    // blob:<type>:<url>
-    Blob {
-        kind: BlobKind,
-        url: String,
-    },
-    // crypt:<cipher>:<key_file>:<kind>
-    Crypt {
-        cipher: DiskCipher,
-        key_file: PathBuf,
-        disk: Box<DiskCliKind>,
-    },
-    // delay:<delay_ms>:<kind>
-    DelayDiskWrapper {
-        delay_ms: u64,
-        disk: Box<DiskCliKind>,
-    },
-}
-
-#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
-pub enum DiskCipher {
-    #[clap(name = "xts-aes-256")]
-    XtsAes256,
-}
-
-#[derive(Copy, Clone, Debug, PartialEq)]
-pub enum BlobKind {
-    Flat,
-    Vhd1,
-}
-
-struct FileOpts {
-    path: PathBuf,
-    create_with_len: Option<u64>,
-    direct: bool,
-}
-
-fn parse_file_opts(arg: &str) -> anyhow::Result<FileOpts> {
-    let mut path = arg;
-    let mut create_with_len = None;
-    let mut direct = false;
-
-    // Parse semicolon-delimited options after the path.
-    if let Some((p, rest)) = arg.split_once(';') {
-        path = p;
-        for opt in rest.split(';') {
-            if let Some(len) = opt.strip_prefix("create=") {
-                create_with_len = Some(parse_memory(len)?);
-            } else if opt == "direct" {
-                direct = true;
-            } else {
-                anyhow::bail!("invalid file option '{opt}', expected 'create=<len>' or 'direct'");
-            }
-        }
-    }
-
-    Ok(FileOpts {
-        path: path.into(),
-        create_with_len,
-        direct,
-    })
-}
-
-impl DiskCliKind {
-    /// Parse an `autocache:[key]:<kind>` disk spec, given the cache path
-    /// (normally read from `OPENVMM_AUTO_CACHE_PATH`).
-    fn parse_autocache(
-        arg: &str,
-        cache_path: Result<String, std::env::VarError>,
-    ) -> anyhow::Result<Self> {
-        let (key, kind) = arg.split_once(':').context("expected [key]:kind")?;
-        let cache_path = cache_path.context("must set cache path via OPENVMM_AUTO_CACHE_PATH")?;
-        Ok(DiskCliKind::AutoCacheSqlite {
-            cache_path,
-            key: (!key.is_empty()).then(|| key.to_string()),
-            disk: Box::new(kind.parse()?),
-        })
-    }
-}
-
-impl FromStr for DiskCliKind {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> anyhow::Result<Self> {
-        let disk = match s.split_once(':') {
-            // convenience support for passing bare paths as file disks
-            None => {
-                let FileOpts {
-                    path,
-                    create_with_len,
-                    direct,
-                } = parse_file_opts(s)?;
-                DiskCliKind::File {
-                    path,
-                    create_with_len,
-                    direct,
-                }
-            }
-            Some((kind, arg)) => match kind {
-                "mem" => DiskCliKind::Memory(parse_memory(arg)?),
-                "memdiff" => DiskCliKind::MemoryDiff(Box::new(arg.parse()?)),
-                "sql" => {
-                    let FileOpts {
-                        path,
-                        create_with_len,
-                        direct,
-                    } = parse_file_opts(arg)?;
-                    if direct {
-                        anyhow::bail!("'direct' is not supported for 'sql' disks");
-                    }
-                    DiskCliKind::Sqlite {
-                        path,
-                        create_with_len,
-                    }
-                }
-                "sqldiff" => {
-                    let (path_and_opts, kind) =
-                        arg.split_once(':').context("expected path[;opts]:kind")?;
-                    let disk = Box::new(kind.parse()?);
-                    match path_and_opts.split_once(';') {
-                        Some((path, create)) => {
-                            if create != "create" {
-                                anyhow::bail!("invalid syntax after ';', expected 'create'")
-                            }
-                            DiskCliKind::SqliteDiff {
-                                path: path.into(),
-                                create: true,
-                                disk,
-                            }
-                        }
-                        None => DiskCliKind::SqliteDiff {
-                            path: path_and_opts.into(),
-                            create: false,
-                            disk,
-                        },
-                    }
-                }
-                "autocache" => {
-                    Self::parse_autocache(arg, std::env::var("OPENVMM_AUTO_CACHE_PATH"))?
-                }
-                "prwrap" => DiskCliKind::PersistentReservationsWrapper(Box::new(arg.parse()?)),
-                "file" => {
-                    let FileOpts {
-                        path,
-                        create_with_len,
-                        direct,
-                    } = parse_file_opts(arg)?;
-                    DiskCliKind::File {
-                        path,
-                        create_with_len,
-                        direct,
-                    }
-                }
-                "blob" => {
-                    let (blob_kind, url) = arg.split_once(':').context("expected kind:url")?;
-                    let blob_kind = match blob_kind {
-                        "flat" => BlobKind::Flat,
-                        "vhd1" => BlobKind::Vhd1,
-                        _ => anyhow::bail!("unknown blob kind {blob_kind}"),
-                    };
-                    DiskCliKind::Blob {
-                        kind: blob_kind,
-                        url: url.to_string(),
-                    }
-                }
-                "crypt" => {
-                    let (cipher, (key, kind)) = arg
-                        .split_once(':')
-                        .and_then(|(cipher, arg)| Some((cipher, arg.split_once(':')?)))
-                        .context("expected cipher:key_file:kind")?;
-                    DiskCliKind::Crypt {
-                        cipher: ValueEnum::from_str(cipher, false)
-                            .map_err(|err| anyhow::anyhow!("invalid cipher: {err}"))?,
-                        key_file: PathBuf::from(key),
-                        disk: Box::new(kind.parse()?),
-                    }
-                }
-                kind => {
-                    // here's a fun edge case: what if the user passes `--disk d:\path\to\disk.img`?
-                    //
-                    // in this case, we actually want to treat that leading `d:` as part of the
-                    // path, rather than as a disk with `kind == 'd'`
-                    let FileOpts {
-                        path,
-                        create_with_len,
-                        direct,
-                    } = parse_file_opts(s)?;
-                    if path.has_root() {
-                        DiskCliKind::File {
-                            path,
-                            create_with_len,
-                            direct,
-                        }
-                    } else {
-                        anyhow::bail!("invalid disk kind {kind}");
-                    }
-                }
-            },
-        };
-        Ok(disk)
-    }
-}
-
 /// Wire transport selection for `--rpc`.
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub enum RpcTransportCli {
@@ -2026,6 +2011,34 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
     }
 }
 
+/// The transport address of a live migration stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MigrationAddress {
+    /// A Unix socket path.
+    Unix(PathBuf),
+    /// A TCP `host:port`.
+    Tcp(String),
+}
+
+impl FromStr for MigrationAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (kind, addr) = s
+            .split_once(':')
+            .context("expected unix:<path> or tcp:<host>:<port>")?;
+        anyhow::ensure!(!addr.is_empty(), "missing address");
+        match kind {
+            "unix" => Ok(MigrationAddress::Unix(PathBuf::from(addr))),
+            "tcp" => {
+                anyhow::ensure!(addr.contains(':'), "expected tcp:<host>:<port>");
+                Ok(MigrationAddress::Tcp(addr.to_owned()))
+            }
+            _ => anyhow::bail!("invalid migration transport '{kind}', expected unix or tcp"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct VmgsCli {
     pub kind: DiskCliKind,
@@ -2094,6 +2107,7 @@ pub struct DiskCli {
     pub kind: DiskCliKind,
     pub read_only: bool,
     pub is_dvd: bool,
+    pub snapshot: bool,
     pub underhill: Option<UnderhillDiskSource>,
     pub pcie_port: Option<String>,
     pub controller: Option<String>,
@@ -2117,6 +2131,7 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
 
         let mut read_only = false;
         let mut is_dvd = false;
+        let mut snapshot = false;
         let mut underhill = None;
         let mut vtl = DeviceVtl::Vtl0;
         let mut pcie_port = None;
@@ -2133,6 +2148,7 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
                     is_dvd = true;
                     read_only = true;
                 }
+                "snapshot" => snapshot = true,
                 "vtl2" => {
                     vtl = DeviceVtl::Vtl2;
                 }
@@ -2178,6 +2194,10 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
             }
         }
 
+        if snapshot && is_dvd {
+            anyhow::bail!("`snapshot` is incompatible with `dvd`");
+        }
+
         if underhill.is_some() && vtl != DeviceVtl::Vtl0 {
             anyhow::bail!("`uh` or `uh-nvme` is incompatible with `vtl2`");
         }
@@ -2225,6 +2245,7 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
             kind,
             read_only,
             is_dvd,
+            snapshot,
             underhill,
             pcie_port,
             controller,
@@ -2712,12 +2733,24 @@ pub enum EndpointConfigCli {
     Consomme {
         cidr: Option<String>,
         host_fwd: Vec<HostPortConfigCli>,
+        egress: EgressPolicy,
+        guest_fwd: Vec<GuestForwardConfig>,
+        dns_records: Vec<DnsRecord>,
+        dns_log: bool,
     },
     Dio {
         id: Option<String>,
     },
     Tap {
         name: String,
+        vhost: bool,
+    },
+    Stream {
+        path: PathBuf,
+    },
+    Dgram {
+        local: PathBuf,
+        remote: PathBuf,
     },
 }
 
@@ -2774,6 +2807,120 @@ fn parse_hostfwd(s: &str) -> Result<HostPortConfigCli, String> {
     })
 }
 
+/// Parses a consomme egress rule of the form
+/// `[tcp:|udp:|icmp:]addr[/prefix][:port[-port]]`, with IPv6 addresses in
+/// brackets.
+fn parse_egress_rule(action: EgressAction, s: &str) -> Result<EgressRule, String> {
+    let (protocol, rest) = match s.split_once(':') {
+        Some(("tcp", rest)) => (Some(EgressProtocol::Tcp), rest),
+        Some(("udp", rest)) => (Some(EgressProtocol::Udp), rest),
+        Some(("icmp", rest)) => (Some(EgressProtocol::Icmp), rest),
+        _ => (None, s),
+    };
+    let (addr, rest) = match rest.strip_prefix('[') {
+        Some(rest) => rest
+            .split_once(']')
+            .ok_or_else(|| format!("missing ']' in egress rule '{s}'"))?,
+        None => rest.split_at(rest.find([':', '/']).unwrap_or(rest.len())),
+    };
+    let address: std::net::IpAddr = addr
+        .parse()
+        .map_err(|e| format!("invalid address '{addr}': {e}"))?;
+    let (prefix, ports) = match rest.split_once(':') {
+        Some((prefix, ports)) => (prefix, Some(ports)),
+        None => (rest, None),
+    };
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix.strip_prefix('/') {
+        Some(prefix) => prefix
+            .parse()
+            .ok()
+            .filter(|&n| n <= max_prefix)
+            .ok_or_else(|| format!("invalid prefix length '{prefix}'"))?,
+        None if prefix.is_empty() => max_prefix,
+        None => return Err(format!("invalid egress rule '{s}'")),
+    };
+    let ports = ports
+        .map(|ports| {
+            let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+            let parse = |p: &str| p.parse::<u16>().map_err(|_| format!("invalid port '{p}'"));
+            let (first, last) = (parse(first)?, parse(last)?);
+            if first > last {
+                return Err(format!("invalid port range '{ports}'"));
+            }
+            Ok((first, last))
+        })
+        .transpose()?;
+    Ok(EgressRule {
+        action,
+        protocol,
+        address: address.into(),
+        prefix_len,
+        ports,
+    })
+}
+
+/// Parses a consomme guest forward of the form
+/// `tcp:guestaddr:guestport-hostaddr:hostport` or
+/// `tcp:guestaddr:guestport-unix:path`.
+fn parse_guestfwd(s: &str) -> Result<GuestForwardConfig, String> {
+    let rest = s
+        .strip_prefix("tcp:")
+        .ok_or_else(|| format!("invalid guestfwd '{s}', only 'tcp:' is supported"))?;
+    let (guest, target) = rest.split_once('-').ok_or_else(|| {
+        format!(
+            "invalid guestfwd format '{s}', \
+             expected 'tcp:guestaddr:guestport-hostaddr:hostport'"
+        )
+    })?;
+    let (guest_address, guest_port) = guest
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected 'guestaddr:guestport', got '{guest}'"))?;
+    let guest_address = guest_address
+        .parse()
+        .map_err(|e| format!("invalid guestfwd address '{guest_address}': {e}"))?;
+    let guest_port = guest_port
+        .parse()
+        .map_err(|_| format!("invalid port '{guest_port}'"))?;
+    let target = match target.strip_prefix("unix:") {
+        Some(path) => GuestForwardTarget::Unix(path.to_owned()),
+        None => {
+            match parse_addr_port(target).map_err(|e| format!("invalid guestfwd target: {e}"))? {
+                (Some(address), port) => GuestForwardTarget::Tcp {
+                    address: address.into(),
+                    port,
+                },
+                (None, _) => return Err(format!("guestfwd target '{target}' needs an address")),
+            }
+        }
+    };
+    Ok(GuestForwardConfig {
+        guest_address,
+        guest_port,
+        target,
+    })
+}
+
+/// Parses a consomme static DNS record of the form `name=target`, where the
+/// target is an IPv4 address, an IPv6 address, or a name for a CNAME record.
+fn parse_dns_record(s: &str) -> Result<DnsRecord, String> {
+    let (name, target) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid dns record '{s}', expected 'name=target'"))?;
+    if name.is_empty() || target.is_empty() {
+        return Err(format!("invalid dns record '{s}', expected 'name=target'"));
+    }
+    let data = match target.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(addr)) => DnsRecordData::A(addr),
+        Ok(std::net::IpAddr::V6(addr)) => DnsRecordData::Aaaa(addr),
+        Err(_) => DnsRecordData::Cname(target.to_owned()),
+    };
+    Ok(DnsRecord {
+        name: name.to_owned(),
+        data,
+    })
+}
+
 /// Parse an address-port pair in one of these forms:
 /// - `[ipv6addr]:port`
 /// - `addr:port`
@@ -2822,22 +2969,62 @@ fn from_str(s: &str) -> Result<Self, Self::Err> {
                 let remaining = rest.join(":");
                 let mut cidr = None;
                 let mut host_fwd = Vec::new();
+                let mut egress = EgressPolicy::default();
+                let mut guest_fwd = Vec::new();
+                let mut dns_records = Vec::new();
+                let mut dns_log = false;
                 for opt in remaining.split(',').filter(|s| !s.is_empty()) {
                     if let Some(fwd) = opt.strip_prefix("hostfwd=") {
                         host_fwd.push(parse_hostfwd(fwd)?);
+                    } else if let Some(fwd) = opt.strip_prefix("guestfwd=") {
+                        guest_fwd.push(parse_guestfwd(fwd)?);
+                    } else if opt == "restrict" {
+                        egress.default = EgressAction::Deny;
+                    } else if let Some(rule) = opt.strip_prefix("allow=") {
+                        egress
+                            .rules
+                            .push(parse_egress_rule(EgressAction::Allow, rule)?);
+                    } else if let Some(rule) = opt.strip_prefix("deny=") {
+                        egress
+                            .rules
+                            .push(parse_egress_rule(EgressAction::Deny, rule)?);
+                    } else if let Some(record) = opt.strip_prefix("dns=") {
+                        dns_records.push(parse_dns_record(record)?);
+                    } else if opt == "dnslog" {
+                        dns_log = true;
                     } else if cidr.is_none() {
                         cidr = Some(opt.to_owned());
                     } else {
                         return Err(format!("unexpected consomme option '{opt}'"));
                     }
                 }
-                EndpointConfigCli::Consomme { cidr, host_fwd }
+                EndpointConfigCli::Consomme {
+                    cidr,
+                    host_fwd,
+                    egress,
+                    guest_fwd,
+                    dns_records,
+                    dns_log,
+                }
             }
             ["dio", s @ ..] => EndpointConfigCli::Dio {
                 id: s.first().map(|s| (*s).to_owned()),
             },
-            ["tap", name] => EndpointConfigCli::Tap {
-                name: (*name).to_owned(),
+            ["tap", rest] => {
+                let (name, vhost) = match rest.split_once(',') {
+                    None => (*rest, false),
+                    Some((name, "vhost")) => (name, true),
+                    Some((_, opt)) => return Err(format!("unexpected tap option '{opt}'")),
+                };
+                EndpointConfigCli::Tap {
+                    name: name.to_owned(),
+                    vhost,
+                }
+            }
+            ["stream", path] => EndpointConfigCli::Stream { path: path.into() },
+            ["dgram", local, remote] => EndpointConfigCli::Dgram {
+                local: local.into(),
+                remote: remote.into(),
             },
             _ => return Err("invalid network backend".into()),
         };
@@ -2851,8 +3038,11 @@ pub struct NicConfigCli {
     pub vtl: DeviceVtl,
     pub endpoint: EndpointConfigCli,
     pub max_queues: Option<u16>,
+    pub mtu: Option<u16>,
     pub underhill: bool,
     pub pcie_port: Option<String>,
+    pub impair: Option<ImpairmentConfig>,
+    pub e1000e: bool,
 }
 
 impl FromStr for NicConfigCli {
@@ -2861,20 +3051,29 @@ impl FromStr for NicConfigCli {
     fn from_str(mut s: &str) -> Result<Self, Self::Err> {
         let mut vtl = DeviceVtl::Vtl0;
         let mut max_queues = None;
+        let mut mtu = None;
         let mut underhill = false;
         let mut pcie_port = None;
+        let mut impair = None;
+        let mut e1000e = false;
         while let Some((opt, rest)) = s.split_once(':') {
             if let Some((opt, val)) = opt.split_once('=') {
                 match opt {
                     "queues" => {
                         max_queues = Some(val.parse().map_err(|_| "failed to parse queue count")?);
                     }
+                    "mtu" => {
+                        mtu = Some(val.parse().map_err(|_| "failed to parse mtu")?);
+                    }
                     "pcie_port" => {
                         if val.is_empty() {
                             return Err("`pcie_port=` requires port name argument".into());
                         }
                         pcie_port = Some(val.to_string());
                     }
+                    "impair" => {
+                        impair = Some(parse_impairment(val)?);
+                    }
                     _ => break,
                 }
             } else {
@@ -2883,6 +3082,7 @@ fn from_str(mut s: &str) -> Result<Self, Self::Err> {
                         vtl = DeviceVtl::Vtl2;
                     }
                     "uh" => underhill = true,
+                    "e1000e" => e1000e = true,
                     _ => break,
                 }
             }
@@ -2897,17 +3097,120 @@ fn from_str(mut s: &str) -> Result<Self, Self::Err> {
             return Err("`pcie_port` is incompatible with `uh` and `vtl2`".into());
         }
 
+        if e1000e && (underhill || vtl != DeviceVtl::Vtl0) {
+            return Err("`e1000e` is incompatible with `uh` and `vtl2`".into());
+        }
+
         let endpoint = s.parse()?;
         Ok(NicConfigCli {
             vtl,
             endpoint,
             max_queues,
+            mtu,
             underhill,
             pcie_port,
+            impair,
+            e1000e,
         })
     }
 }
 
+/// Parses a network impairment spec: comma-separated `key=value` settings,
+/// such as `delay=50ms,jitter=5ms,loss=1%`, or `none` for no impairment.
+///
+/// Settings prefixed with `tx.` apply only to packets sent by the guest, and
+/// those prefixed with `rx.` only to packets received by the guest. Others
+/// apply to both directions.
+pub(crate) fn parse_impairment(s: &str) -> Result<ImpairmentConfig, String> {
+    let mut config = ImpairmentConfig::default();
+    if s == "none" {
+        return Ok(config);
+    }
+    for setting in s.split(',') {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("expected <key>=<value>, got '{setting}'"))?;
+        if let Some(key) = key.strip_prefix("tx.") {
+            parse_link_impairment(&mut config.tx, key, value)?;
+        } else if let Some(key) = key.strip_prefix("rx.") {
+            parse_link_impairment(&mut config.rx, key, value)?;
+        } else {
+            parse_link_impairment(&mut config.tx, key, value)?;
+            parse_link_impairment(&mut config.rx, key, value)?;
+        }
+    }
+    Ok(config)
+}
+
+fn parse_link_impairment(link: &mut LinkImpairment, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "delay" => link.delay = parse_impairment_duration(value)?,
+        "jitter" => link.jitter = parse_impairment_duration(value)?,
+        "loss" => link.loss = parse_probability(value)?,
+        "dup" => link.duplicate = parse_probability(value)?,
+        "reorder" => link.reorder = parse_probability(value)?,
+        "rate" => link.rate = parse_bit_rate(value)?,
+        "burst" => {
+            link.burst = parse_memory(value)
+                .ok()
+                .and_then(|n| n.try_into().ok())
+                .ok_or_else(|| format!("invalid burst size '{value}'"))?
+        }
+        _ => return Err(format!("unknown impairment '{key}'")),
+    }
+    Ok(())
+}
+
+/// Parses a duration with a `s`, `ms`, or `us` suffix.
+fn parse_impairment_duration(s: &str) -> Result<std::time::Duration, String> {
+    let (n, scale) = if let Some(n) = s.strip_suffix("us") {
+        (n, 1e-6)
+    } else if let Some(n) = s.strip_suffix("ms") {
+        (n, 1e-3)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1.0)
+    } else {
+        return Err(format!("expected a duration such as 10ms, got '{s}'"));
+    };
+    n.parse::<f64>()
+        .ok()
+        .and_then(|n| std::time::Duration::try_from_secs_f64(n * scale).ok())
+        .ok_or_else(|| format!("invalid duration '{s}'"))
+}
+
+/// Parses a probability, either as a percentage (`5%`) or a fraction
+/// (`0.05`).
+fn parse_probability(s: &str) -> Result<f64, String> {
+    let p = match s.strip_suffix('%') {
+        Some(n) => n.parse::<f64>().map(|n| n / 100.0),
+        None => s.parse::<f64>(),
+    };
+    match p {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(format!("invalid probability '{s}'")),
+    }
+}
+
+/// Parses a rate in bits per second with a `bit`, `kbit`, `mbit`, or `gbit`
+/// suffix.
+fn parse_bit_rate(s: &str) -> Result<u64, String> {
+    let (n, scale) = if let Some(n) = s.strip_suffix("gbit") {
+        (n, 1_000_000_000)
+    } else if let Some(n) = s.strip_suffix("mbit") {
+        (n, 1_000_000)
+    } else if let Some(n) = s.strip_suffix("kbit") {
+        (n, 1_000)
+    } else if let Some(n) = s.strip_suffix("bit") {
+        (n, 1)
+    } else {
+        return Err(format!("expected a rate such as 10mbit, got '{s}'"));
+    };
+    n.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .ok_or_else(|| format!("invalid rate '{s}'"))
+}
+
 #[derive(Debug, Error)]
 #[error("unknown VTL2 relocation type: {0}")]
 pub struct UnknownVtl2RelocationType(String);
@@ -3744,6 +4047,12 @@ pub enum VhostUserDeviceTypeCli {
         num_queues: Option<u16>,
         queue_size: Option<u16>,
     },
+    /// Network device — frontend-owned config and control queue.
+    Net {
+        mac_address: Option<MacAddress>,
+        queue_pairs: Option<u16>,
+        queue_size: Option<u16>,
+    },
     /// Filesystem device — frontend-owned config with mount tag.
     Fs {
         tag: String,
@@ -3807,6 +4116,7 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
 
         let mut device_id: Option<u16> = None;
         let mut tag: Option<String> = None;
+        let mut mac_address: Option<MacAddress> = None;
         let mut pcie_port: Option<String> = None;
         let mut type_name = None;
         let mut num_queues: Option<u16> = None;
@@ -3824,6 +4134,9 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
                 "tag" => {
                     tag = Some(val.to_string());
                 }
+                "mac" => {
+                    mac_address = Some(val.parse().context("invalid mac")?);
+                }
                 "pcie_port" => {
                     pcie_port = Some(val.to_string());
                 }
@@ -3868,6 +4181,11 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
                 num_queues: num_queues.take(),
                 queue_size: queue_size.take(),
             },
+            Some("net") => VhostUserDeviceTypeCli::Net {
+                mac_address: mac_address.take(),
+                queue_pairs: num_queues.take(),
+                queue_size: queue_size.take(),
+            },
             Some(ty) => anyhow::bail!("unknown vhost-user device type: '{ty}'"),
             None => {
                 let queue_sizes = queue_sizes
@@ -3883,6 +4201,9 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
         if tag.is_some() {
             anyhow::bail!("tag= is only valid for type=fs");
         }
+        if mac_address.is_some() {
+            anyhow::bail!("mac= is only valid for type=net");
+        }
         if queue_sizes.is_some() {
             anyhow::bail!("queue_sizes= is only valid for device_id=");
         }
@@ -3934,6 +4255,29 @@ fn test_parse_rpc() {
         assert!(RpcCli::from_str("path=/a,path=/b").is_err());
     }
 
+    #[test]
+    fn test_parse_migration_address() {
+        assert_eq!(
+            MigrationAddress::from_str("unix:/tmp/migrate.sock").unwrap(),
+            MigrationAddress::Unix(PathBuf::from("/tmp/migrate.sock"))
+        );
+        assert_eq!(
+            MigrationAddress::from_str("tcp:127.0.0.1:4444").unwrap(),
+            MigrationAddress::Tcp("127.0.0.1:4444".into())
+        );
+        assert_eq!(
+            MigrationAddress::from_str("tcp:[::1]:4444").unwrap(),
+            MigrationAddress::Tcp("[::1]:4444".into())
+        );
+
+        // errors
+        assert!(MigrationAddress::from_str("").is_err());
+        assert!(MigrationAddress::from_str("/tmp/migrate.sock").is_err());
+        assert!(MigrationAddress::from_str("unix:").is_err());
+        assert!(MigrationAddress::from_str("tcp:4444").is_err());
+        assert!(MigrationAddress::from_str("vsock:3:4444").is_err());
+    }
+
     #[test]
     fn test_parse_file_opts() {
         // file: prefix with create
@@ -4030,6 +4374,18 @@ fn test_parse_pcie_disk() {
         assert!(DiskCli::from_str("file:disk.vhd,pcie_port=p0,uh-nvme").is_err());
     }
 
+    #[test]
+    fn test_parse_snapshot_disk() {
+        assert!(!DiskCli::from_str("file:disk.img").unwrap().snapshot);
+        assert!(
+            DiskCli::from_str("file:disk.img,on=scsi0,snapshot")
+                .unwrap()
+                .snapshot
+        );
+
+        assert!(DiskCli::from_str("file:disk.iso,dvd,snapshot").is_err());
+    }
+
     #[test]
     fn test_parse_memory_diff_disk() {
         let s = "memdiff:file:base.img";
@@ -4127,6 +4483,65 @@ fn test_parse_sqlite_diff_disk() {
         }
     }
 
+    #[test]
+    fn test_parse_qcow2_disk() {
+        let disk = DiskCliKind::from_str("qcow2:disk.qcow2").unwrap();
+        assert!(matches!(
+            disk,
+            DiskCliKind::Qcow2 { path, create_with_len: None } if path == Path::new("disk.qcow2")
+        ));
+
+        let disk = DiskCliKind::from_str("qcow2:disk.qcow2;create=1G").unwrap();
+        assert!(matches!(
+            disk,
+            DiskCliKind::Qcow2 {
+                create_with_len: Some(len),
+                ..
+            } if len == 1024 * 1024 * 1024
+        ));
+
+        assert!(DiskCliKind::from_str("qcow2:disk.qcow2;direct").is_err());
+
+        let disk = DiskCliKind::from_str("qcow2diff:diff.qcow2;create:file:base.img").unwrap();
+        match disk {
+            DiskCliKind::Qcow2Diff { path, create, disk } => {
+                assert_eq!(path, PathBuf::from("diff.qcow2"));
+                assert!(create);
+                assert!(
+                    matches!(*disk, DiskCliKind::File { path, .. } if path == Path::new("base.img"))
+                );
+            }
+            _ => panic!("Expected Qcow2Diff variant"),
+        }
+
+        let disk = DiskCliKind::from_str("qcow2diff:diff.qcow2:qcow2:base.qcow2").unwrap();
+        assert!(matches!(
+            disk,
+            DiskCliKind::Qcow2Diff { create: false, disk, .. }
+                if matches!(*disk, DiskCliKind::Qcow2 { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_vhdx_disk() {
+        let disk = DiskCliKind::from_str("vhdx:disk.vhdx").unwrap();
+        assert!(matches!(
+            disk,
+            DiskCliKind::Vhdx { path, create_with_len: None } if path == Path::new("disk.vhdx")
+        ));
+
+        let disk = DiskCliKind::from_str("vhdx:disk.vhdx;create=4G").unwrap();
+        assert!(matches!(
+            disk,
+            DiskCliKind::Vhdx {
+                create_with_len: Some(len),
+                ..
+            } if len == 4 * 1024 * 1024 * 1024
+        ));
+
+        assert!(DiskCliKind::from_str("vhdx:disk.vhdx;direct").is_err());
+    }
+
     #[test]
     fn test_parse_autocache_sqlite_disk() {
         // Test with cache path provided
@@ -4318,6 +4733,7 @@ fn test_endpoint_config_from_str() {
             EndpointConfigCli::Consomme {
                 cidr: None,
                 host_fwd,
+                ..
             } => assert!(host_fwd.is_empty()),
             _ => panic!("Expected Consomme variant without cidr"),
         }
@@ -4327,6 +4743,7 @@ fn test_endpoint_config_from_str() {
             EndpointConfigCli::Consomme {
                 cidr: Some(cidr),
                 host_fwd,
+                ..
             } => {
                 assert_eq!(cidr, "192.168.0.0/24");
                 assert!(host_fwd.is_empty());
@@ -4336,7 +4753,7 @@ fn test_endpoint_config_from_str() {
 
         // Test consomme with hostfwd
         match EndpointConfigCli::from_str("consomme:hostfwd=udp:127.0.0.1:5000-:5000").unwrap() {
-            EndpointConfigCli::Consomme { cidr, host_fwd } => {
+            EndpointConfigCli::Consomme { cidr, host_fwd, .. } => {
                 assert!(cidr.is_none());
                 assert_eq!(host_fwd.len(), 1);
                 assert_eq!(host_fwd[0].protocol, HostPortProtocolCli::Udp);
@@ -4352,7 +4769,7 @@ fn test_endpoint_config_from_str() {
 
         // Test consomme with cidr and hostfwd
         match EndpointConfigCli::from_str("consomme:10.0.0.0/24,hostfwd=tcp::2222-:22").unwrap() {
-            EndpointConfigCli::Consomme { cidr, host_fwd } => {
+            EndpointConfigCli::Consomme { cidr, host_fwd, .. } => {
                 assert_eq!(cidr.as_deref(), Some("10.0.0.0/24"));
                 assert_eq!(host_fwd.len(), 1);
                 assert_eq!(host_fwd[0].protocol, HostPortProtocolCli::Tcp);
@@ -4366,7 +4783,7 @@ fn test_endpoint_config_from_str() {
         match EndpointConfigCli::from_str("consomme:hostfwd=tcp::2222-:22,hostfwd=tcp::3389-:3389")
             .unwrap()
         {
-            EndpointConfigCli::Consomme { cidr, host_fwd } => {
+            EndpointConfigCli::Consomme { cidr, host_fwd, .. } => {
                 assert!(cidr.is_none());
                 assert_eq!(host_fwd.len(), 2);
                 assert_eq!(host_fwd[0].host_port, 2222);
@@ -4379,7 +4796,7 @@ fn test_endpoint_config_from_str() {
 
         // Test consomme with different host and guest ports
         match EndpointConfigCli::from_str("consomme:hostfwd=tcp:127.0.0.1:8080-:80").unwrap() {
-            EndpointConfigCli::Consomme { cidr, host_fwd } => {
+            EndpointConfigCli::Consomme { cidr, host_fwd, .. } => {
                 assert!(cidr.is_none());
                 assert_eq!(host_fwd.len(), 1);
                 assert_eq!(host_fwd[0].protocol, HostPortProtocolCli::Tcp);
@@ -4395,7 +4812,7 @@ fn test_endpoint_config_from_str() {
 
         // Test consomme with guest address (accepted but ignored by backend)
         match EndpointConfigCli::from_str("consomme:hostfwd=tcp::8080-10.0.0.2:80").unwrap() {
-            EndpointConfigCli::Consomme { cidr, host_fwd } => {
+            EndpointConfigCli::Consomme { cidr, host_fwd, .. } => {
                 assert!(cidr.is_none());
                 assert_eq!(host_fwd[0].host_port, 8080);
                 assert_eq!(host_fwd[0].guest_port, 80);
@@ -4405,7 +4822,7 @@ fn test_endpoint_config_from_str() {
 
         // Test consomme with IPv6 host address (bracketed)
         match EndpointConfigCli::from_str("consomme:hostfwd=tcp:[::1]:8080-:80").unwrap() {
-            EndpointConfigCli::Consomme { cidr, host_fwd } => {
+            EndpointConfigCli::Consomme { cidr, host_fwd, .. } => {
                 assert!(cidr.is_none());
                 assert_eq!(host_fwd.len(), 1);
                 assert_eq!(host_fwd[0].protocol, HostPortProtocolCli::Tcp);
@@ -4421,7 +4838,7 @@ fn test_endpoint_config_from_str() {
 
         // Test consomme with IPv6 guest address (bracketed)
         match EndpointConfigCli::from_str("consomme:hostfwd=tcp::8080-[::1]:80").unwrap() {
-            EndpointConfigCli::Consomme { cidr, host_fwd } => {
+            EndpointConfigCli::Consomme { cidr, host_fwd, .. } => {
                 assert!(cidr.is_none());
                 assert_eq!(host_fwd[0].host_port, 8080);
                 assert_eq!(host_fwd[0].guest_port, 80);
@@ -4429,6 +4846,112 @@ fn test_endpoint_config_from_str() {
             _ => panic!("Expected Consomme variant with IPv6 guest address"),
         }
 
+        // Test consomme with an egress policy
+        match EndpointConfigCli::from_str(
+            "consomme:restrict,allow=tcp:10.1.2.3:443,deny=[fd00::]/8,allow=udp:0.0.0.0/0:5000-5010",
+        )
+        .unwrap()
+        {
+            EndpointConfigCli::Consomme { egress, .. } => {
+                assert_eq!(egress.default, EgressAction::Deny);
+                assert_eq!(
+                    egress.rules,
+                    [
+                        EgressRule {
+                            action: EgressAction::Allow,
+                            protocol: Some(EgressProtocol::Tcp),
+                            address: std::net::IpAddr::from([10, 1, 2, 3]).into(),
+                            prefix_len: 32,
+                            ports: Some((443, 443)),
+                        },
+                        EgressRule {
+                            action: EgressAction::Deny,
+                            protocol: None,
+                            address: "fd00::".parse::<std::net::IpAddr>().unwrap().into(),
+                            prefix_len: 8,
+                            ports: None,
+                        },
+                        EgressRule {
+                            action: EgressAction::Allow,
+                            protocol: Some(EgressProtocol::Udp),
+                            address: std::net::IpAddr::from([0, 0, 0, 0]).into(),
+                            prefix_len: 0,
+                            ports: Some((5000, 5010)),
+                        },
+                    ]
+                );
+            }
+            _ => panic!("Expected Consomme variant with egress policy"),
+        }
+        assert!(EndpointConfigCli::from_str("consomme:allow=10.0.0.0/33").is_err());
+        assert!(EndpointConfigCli::from_str("consomme:deny=10.0.0.1:90-80").is_err());
+
+        // Test consomme with guest forwards
+        match EndpointConfigCli::from_str(
+            "consomme:guestfwd=tcp:10.0.0.100:80-127.0.0.1:8080,guestfwd=tcp:10.0.0.101:80-unix:/tmp/a.sock",
+        )
+        .unwrap()
+        {
+            EndpointConfigCli::Consomme { guest_fwd, .. } => {
+                assert_eq!(
+                    guest_fwd,
+                    [
+                        GuestForwardConfig {
+                            guest_address: std::net::Ipv4Addr::new(10, 0, 0, 100),
+                            guest_port: 80,
+                            target: GuestForwardTarget::Tcp {
+                                address: std::net::IpAddr::from([127, 0, 0, 1]).into(),
+                                port: 8080,
+                            },
+                        },
+                        GuestForwardConfig {
+                            guest_address: std::net::Ipv4Addr::new(10, 0, 0, 101),
+                            guest_port: 80,
+                            target: GuestForwardTarget::Unix("/tmp/a.sock".into()),
+                        },
+                    ]
+                );
+            }
+            _ => panic!("Expected Consomme variant with guest forwards"),
+        }
+        assert!(EndpointConfigCli::from_str("consomme:guestfwd=udp:10.0.0.100:53-:53").is_err());
+        assert!(EndpointConfigCli::from_str("consomme:guestfwd=tcp:10.0.0.100:80-:8080").is_err());
+
+        // Test consomme with static DNS records and query logging
+        match EndpointConfigCli::from_str(
+            "consomme:dns=build.test=10.0.0.100,dns=v6.test=fd00::1,dns=alias.test=build.test,dnslog",
+        )
+        .unwrap()
+        {
+            EndpointConfigCli::Consomme {
+                dns_records,
+                dns_log,
+                ..
+            } => {
+                assert!(dns_log);
+                assert_eq!(
+                    dns_records,
+                    [
+                        DnsRecord {
+                            name: "build.test".into(),
+                            data: DnsRecordData::A(std::net::Ipv4Addr::new(10, 0, 0, 100)),
+                        },
+                        DnsRecord {
+                            name: "v6.test".into(),
+                            data: DnsRecordData::Aaaa("fd00::1".parse().unwrap()),
+                        },
+                        DnsRecord {
+                            name: "alias.test".into(),
+                            data: DnsRecordData::Cname("build.test".into()),
+                        },
+                    ]
+                );
+            }
+            _ => panic!("Expected Consomme variant with DNS records"),
+        }
+        assert!(EndpointConfigCli::from_str("consomme:dns=build.test").is_err());
+        assert!(EndpointConfigCli::from_str("consomme:dns==10.0.0.1").is_err());
+
         // Test dio without id
         match EndpointConfigCli::from_str("dio").unwrap() {
             EndpointConfigCli::Dio { id: None } => (),
@@ -4445,11 +4968,38 @@ fn test_endpoint_config_from_str() {
 
         // Test tap
         match EndpointConfigCli::from_str("tap:tap0").unwrap() {
-            EndpointConfigCli::Tap { name } => {
+            EndpointConfigCli::Tap { name, vhost } => {
                 assert_eq!(name, "tap0");
+                assert!(!vhost);
             }
             _ => panic!("Expected Tap variant"),
         }
+        assert_eq!(
+            EndpointConfigCli::from_str("tap:tap0,vhost").unwrap(),
+            EndpointConfigCli::Tap {
+                name: "tap0".into(),
+                vhost: true,
+            }
+        );
+        assert!(EndpointConfigCli::from_str("tap:tap0,zerocopy").is_err());
+
+        // Test stream
+        assert_eq!(
+            EndpointConfigCli::from_str("stream:/tmp/switch.sock").unwrap(),
+            EndpointConfigCli::Stream {
+                path: "/tmp/switch.sock".into()
+            }
+        );
+
+        // Test dgram
+        assert_eq!(
+            EndpointConfigCli::from_str("dgram:/tmp/a.sock:/tmp/b.sock").unwrap(),
+            EndpointConfigCli::Dgram {
+                local: "/tmp/a.sock".into(),
+                remote: "/tmp/b.sock".into(),
+            }
+        );
+        assert!(EndpointConfigCli::from_str("dgram:/tmp/a.sock").is_err());
 
         // Test error case
         assert!(EndpointConfigCli::from_str("invalid").is_err());
@@ -4479,6 +5029,12 @@ fn test_nic_config_from_str() {
         assert!(config.pcie_port.is_none());
         assert!(matches!(config.endpoint, EndpointConfigCli::None));
 
+        // Test with mtu
+        let config = NicConfigCli::from_str("queues=2:mtu=9000:none").unwrap();
+        assert_eq!(config.max_queues, Some(2));
+        assert_eq!(config.mtu, Some(9000));
+        assert!(matches!(config.endpoint, EndpointConfigCli::None));
+
         // Test with underhill
         let config = NicConfigCli::from_str("uh:none").unwrap();
         assert!(config.underhill);
@@ -4490,13 +5046,62 @@ fn test_nic_config_from_str() {
         assert_eq!(config.pcie_port.unwrap(), "rp0".to_string());
         assert!(matches!(config.endpoint, EndpointConfigCli::None));
 
+        // Test with e1000e, on PCI and on a PCIe port
+        let config = NicConfigCli::from_str("e1000e:none").unwrap();
+        assert!(config.e1000e);
+        assert!(config.pcie_port.is_none());
+        let config = NicConfigCli::from_str("e1000e:pcie_port=rp0:none").unwrap();
+        assert!(config.e1000e);
+        assert_eq!(config.pcie_port.unwrap(), "rp0".to_string());
+
         // Test error cases
         assert!(NicConfigCli::from_str("queues=invalid:none").is_err());
+        assert!(NicConfigCli::from_str("mtu=70000:none").is_err());
         assert!(NicConfigCli::from_str("uh:vtl2:none").is_err()); // uh incompatible with vtl2
         assert!(NicConfigCli::from_str("pcie_port=rp0:vtl2:none").is_err());
         assert!(NicConfigCli::from_str("uh:pcie_port=rp0:none").is_err());
         assert!(NicConfigCli::from_str("pcie_port=:none").is_err());
         assert!(NicConfigCli::from_str("pcie_port:none").is_err());
+        assert!(NicConfigCli::from_str("e1000e:uh:none").is_err());
+        assert!(NicConfigCli::from_str("vtl2:e1000e:none").is_err());
+
+        // Test with impairment
+        let config = NicConfigCli::from_str("impair=loss=1%:queues=2:none").unwrap();
+        assert_eq!(config.impair.unwrap().rx.loss, 0.01);
+        assert_eq!(config.max_queues, Some(2));
+        assert!(NicConfigCli::from_str("impair=loss=2:none").is_err());
+    }
+
+    #[test]
+    fn test_parse_impairment() {
+        use std::time::Duration;
+
+        let config =
+            parse_impairment("delay=50ms,jitter=500us,tx.rate=10mbit,rx.dup=0.5,burst=64K")
+                .unwrap();
+        for link in [&config.tx, &config.rx] {
+            assert_eq!(link.delay, Duration::from_millis(50));
+            assert_eq!(link.jitter, Duration::from_micros(500));
+            assert_eq!(link.burst, 65536);
+        }
+        assert_eq!(config.tx.rate, 10_000_000);
+        assert_eq!(config.rx.rate, 0);
+        assert_eq!(config.tx.duplicate, 0.0);
+        assert_eq!(config.rx.duplicate, 0.5);
+
+        let config = parse_impairment("reorder=25%,loss=0.1").unwrap();
+        assert_eq!(config.tx.reorder, 0.25);
+        assert_eq!(config.rx.loss, 0.1);
+
+        assert_eq!(
+            parse_impairment("none").unwrap(),
+            ImpairmentConfig::default()
+        );
+        assert!(parse_impairment("delay=50").is_err());
+        assert!(parse_impairment("loss=150%").is_err());
+        assert!(parse_impairment("rate=fast").is_err());
+        assert!(parse_impairment("mtu=1500").is_err());
+        assert!(parse_impairment("delay").is_err());
     }
 
     #[test]
@@ -5330,6 +5935,23 @@ fn test_memory_options_reject_conflicting_legacy_aliases() {
         assert!(opt.validate_memory_options().is_err());
     }
 
+    #[test]
+    fn test_checkpoint_dir_requires_memory_file() {
+        let opt = Options::try_parse_from(["openvmm", "--checkpoint-dir", "/tmp/cp"]).unwrap();
+        assert!(opt.validate_memory_options().is_err());
+
+        let opt = Options::try_parse_from([
+            "openvmm",
+            "--checkpoint-dir",
+            "/tmp/cp",
+            "--memory",
+            "file=/tmp/mem",
+        ])
+        .unwrap();
+        opt.validate_memory_options().unwrap();
+        assert_eq!(opt.checkpoint_dir, Some(PathBuf::from("/tmp/cp")));
+    }
+
     #[test]
     fn test_pidfile_option_parsed() {
         let opt = Options::try_parse_from(["openvmm", "--pidfile", "/tmp/test.pid"]).unwrap();
@@ -5744,6 +6366,53 @@ fn test_parse_numa_distance() {
         assert!(parse_numa_distance("0:1:20:extra").is_err());
     }
 
+    #[test]
+    fn test_parse_resolution() {
+        assert_eq!(parse_resolution("1024x768").unwrap(), (1024, 768));
+        assert_eq!(parse_resolution("1920x1080").unwrap(), (1920, 1080));
+        assert!(parse_resolution("1024").is_err());
+        assert!(parse_resolution("0x768").is_err());
+        assert!(parse_resolution("1024x").is_err());
+        assert!(parse_resolution("1024x768x32").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_vhost_user_cli_net() {
+        let cli = VhostUserCli::from_str("/tmp/net.sock,type=net").unwrap();
+        assert_eq!(cli.socket_path, "/tmp/net.sock");
+        assert!(matches!(
+            cli.device_type,
+            VhostUserDeviceTypeCli::Net {
+                mac_address: None,
+                queue_pairs: None,
+                queue_size: None,
+            }
+        ));
+
+        let cli = VhostUserCli::from_str(
+            "/tmp/net.sock,type=net,mac=00-15-5d-12-34-56,num_queues=4,queue_size=512",
+        )
+        .unwrap();
+        let VhostUserDeviceTypeCli::Net {
+            mac_address,
+            queue_pairs,
+            queue_size,
+        } = cli.device_type
+        else {
+            panic!("expected a net device");
+        };
+        assert_eq!(
+            mac_address,
+            Some([0x00, 0x15, 0x5d, 0x12, 0x34, 0x56].into())
+        );
+        assert_eq!(queue_pairs, Some(4));
+        assert_eq!(queue_size, Some(512));
+
+        assert!(VhostUserCli::from_str("/tmp/net.sock,type=net,mac=bad").is_err());
+        assert!(VhostUserCli::from_str("/tmp/blk.sock,type=blk,mac=00-15-5d-12-34-56").is_err());
+    }
+
     #[cfg(guest_arch = "aarch64")]
     #[test]
     fn test_smmu_cli_from_str() {
@@ -0,0 +1,745 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! VM configuration files (`--config` and `--dump-config`).
+//!
+//! A configuration file describes a VM in typed sections that follow the
+//! vmservice `VMConfig` message (`processor_config`, `memory_config`,
+//! `devices_config`, and so on). The schema is versioned and defined here,
+//! independently of the command line: each field maps to the ID of the
+//! [`Options`](crate::cli_args::Options) argument it sets, and a file is
+//! loaded by converting it into command-line arguments. Device descriptions,
+//! such as disks and NICs, use the same syntax as the corresponding
+//! command-line values.
+//!
+//! ```toml
+//! version = 1
+//!
+//! [processor_config]
+//! processor_count = 4
+//!
+//! [memory_config]
+//! memory = "4G"
+//!
+//! [devices_config]
+//! vmbus_scsi = ["id=scsi0"]
+//! disks = ["file:disk.vhdx,on=scsi0"]
+//! ```
+
+use anyhow::Context;
+use clap::Arg;
+use clap::ArgAction;
+use clap::ArgMatches;
+use clap::Command;
+use clap::parser::ValueSource;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The current configuration file version.
+pub const CONFIG_VERSION: i64 = 1;
+
+/// Arguments that control the OpenVMM process, or the configuration file
+/// itself, rather than describing the VM. These are not stored in
+/// configuration files.
+const PROCESS_OPTIONS: &[&str] = &[
+    "config",
+    "dump_config",
+    "help",
+    "version",
+    "paused",
+    "restore_snapshot",
+    "migrate_incoming",
+    "log_file",
+    "pidfile",
+    "ttrpc",
+    "grpc",
+    "rpc",
+    "single_process",
+    "internal_worker",
+    "write_saved_state_proto",
+    "relay_console_path",
+    "relay_console_title",
+    "gdb",
+    "openhcl_dump_path",
+];
+
+/// A configuration file field, converted to and from command-line values.
+trait FieldValue: Default {
+    /// Whether values from the command line are added to the ones in the
+    /// file, rather than replacing them.
+    const LIST: bool;
+
+    fn is_unset(&self) -> bool;
+    fn to_args(&self, flag: &str, args: &mut Vec<String>);
+    fn from_values(values: Vec<String>) -> anyhow::Result<Self>;
+}
+
+impl FieldValue for bool {
+    const LIST: bool = false;
+
+    fn is_unset(&self) -> bool {
+        !self
+    }
+
+    fn to_args(&self, flag: &str, args: &mut Vec<String>) {
+        if *self {
+            args.push(flag.to_owned());
+        }
+    }
+
+    fn from_values(_values: Vec<String>) -> anyhow::Result<Self> {
+        Ok(true)
+    }
+}
+
+impl<T> FieldValue for Option<T>
+where
+    T: FromStr + Display,
+    T::Err: 'static + std::error::Error + Send + Sync,
+{
+    const LIST: bool = false;
+
+    fn is_unset(&self) -> bool {
+        self.is_none()
+    }
+
+    fn to_args(&self, flag: &str, args: &mut Vec<String>) {
+        if let Some(value) = self {
+            args.push(format!("{flag}={value}"));
+        }
+    }
+
+    fn from_values(values: Vec<String>) -> anyhow::Result<Self> {
+        let [value] = <[String; 1]>::try_from(values)
+            .ok()
+            .context("expected a single value")?;
+        Ok(Some(value.parse()?))
+    }
+}
+
+impl FieldValue for Vec<String> {
+    const LIST: bool = true;
+
+    fn is_unset(&self) -> bool {
+        self.is_empty()
+    }
+
+    fn to_args(&self, flag: &str, args: &mut Vec<String>) {
+        args.extend(self.iter().map(|value| format!("{flag}={value}")));
+    }
+
+    fn from_values(values: Vec<String>) -> anyhow::Result<Self> {
+        Ok(values)
+    }
+}
+
+/// Converts configuration file fields to command-line arguments.
+struct ArgWriter<'a> {
+    command: &'a Command,
+    /// The command-line arguments, whose values replace the file's.
+    cli: Option<&'a ArgMatches>,
+    args: Vec<String>,
+}
+
+impl ArgWriter<'_> {
+    fn push<T: FieldValue>(&mut self, id: &str, value: &T) -> anyhow::Result<()> {
+        if value.is_unset()
+            || (!T::LIST && self.cli.is_some_and(|cli| set_on_command_line(cli, id)))
+        {
+            return Ok(());
+        }
+        let long = self
+            .command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id)
+            .and_then(|arg| arg.get_long())
+            .with_context(|| format!("unknown argument {id}"))?;
+        value.to_args(&format!("--{long}"), &mut self.args);
+        Ok(())
+    }
+}
+
+/// Returns whether the argument `id` was set on the command line in `matches`.
+pub fn set_on_command_line(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+fn raw_values(matches: &ArgMatches, id: &str) -> anyhow::Result<Vec<String>> {
+    matches
+        .try_get_raw(id)?
+        .into_iter()
+        .flatten()
+        .map(|v| {
+            v.to_str()
+                .map(str::to_owned)
+                .with_context(|| format!("{id} value is not valid UTF-8"))
+        })
+        .collect()
+}
+
+/// Reads a configuration file field from the arguments set on the command
+/// line.
+fn field_from_matches<T: FieldValue>(matches: &ArgMatches, id: &str) -> anyhow::Result<T> {
+    if !set_on_command_line(matches, id) {
+        return Ok(T::default());
+    }
+    T::from_values(raw_values(matches, id)?).with_context(|| format!("invalid value for {id}"))
+}
+
+macro_rules! config_sections {
+    ($(
+        $(#[$section_attr:meta])*
+        $section:ident: $section_ty:ident {
+            $(
+                $(#[$field_attr:meta])*
+                $field:ident: $field_ty:ty = $id:literal,
+            )*
+        }
+    )*) => {
+        /// A VM configuration file.
+        #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        pub struct ConfigFile {
+            /// The schema version.
+            pub version: i64,
+            $(
+                $(#[$section_attr])*
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                pub $section: Option<$section_ty>,
+            )*
+        }
+
+        impl ConfigFile {
+            /// The IDs of the arguments that configuration files set.
+            const IDS: &[&str] = &[$($($id,)*)*];
+
+            fn write_args(&self, args: &mut ArgWriter<'_>) -> anyhow::Result<()> {
+                $(
+                    if let Some(section) = &self.$section {
+                        $(args.push($id, &section.$field)?;)*
+                    }
+                )*
+                Ok(())
+            }
+
+            fn from_matches(matches: &ArgMatches) -> anyhow::Result<Self> {
+                Ok(Self {
+                    version: CONFIG_VERSION,
+                    $(
+                        $section: Some($section_ty {
+                            $($field: field_from_matches(matches, $id)?,)*
+                        })
+                        .filter(|section| *section != $section_ty::default()),
+                    )*
+                })
+            }
+        }
+
+        $(
+            $(#[$section_attr])*
+            #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+            #[serde(deny_unknown_fields)]
+            pub struct $section_ty {
+                $(
+                    $(#[$field_attr])*
+                    #[serde(default, skip_serializing_if = "FieldValue::is_unset")]
+                    pub $field: $field_ty,
+                )*
+            }
+        )*
+    };
+}
+
+config_sections! {
+    /// Processor topology (`-p`, `--smt`, ...).
+    processor_config: ProcessorConfig {
+        processor_count: Option<u32> = "processors",
+        vps_per_socket: Option<u32> = "vps_per_socket",
+        smt: Option<String> = "smt",
+        apic_id_offset: Option<u32> = "apic_id_offset",
+        x2apic: Option<String> = "x2apic",
+        gic_msi: Option<String> = "gic_msi",
+        nested_virt: bool = "nested_virt",
+    }
+
+    /// Guest memory, in `--memory` syntax.
+    memory_config: MemoryConfig {
+        memory: Option<String> = "memory",
+    }
+
+    /// Virtual NUMA nodes and distances, in `--numa` and `--numa-distance`
+    /// syntax.
+    numa_config: NumaConfig {
+        nodes: Vec<String> = "numa",
+        distances: Vec<String> = "numa_distance",
+    }
+
+    /// Firmware and boot loader.
+    boot_config: BootConfig {
+        kernel: Option<String> = "kernel",
+        initrd: Option<String> = "initrd",
+        cmdline: Vec<String> = "cmdline",
+        device_tree: bool = "device_tree",
+        igvm: Option<String> = "igvm",
+        igvm_vtl2_relocation_type: Option<String> = "igvm_vtl2_relocation_type",
+        uefi: bool = "uefi",
+        uefi_firmware: Option<String> = "uefi_firmware",
+        uefi_debug: bool = "uefi_debug",
+        uefi_enable_memory_protections: bool = "uefi_enable_memory_protections",
+        uefi_force_dma_bounce: bool = "uefi_force_dma_bounce",
+        uefi_console_mode: Option<String> = "uefi_console_mode",
+        secure_boot: bool = "secure_boot",
+        secure_boot_template: Option<String> = "secure_boot_template",
+        custom_uefi_json: Option<String> = "custom_uefi_json",
+        disable_frontpage: bool = "disable_frontpage",
+        default_boot_always_attempt: bool = "default_boot_always_attempt",
+        efi_diagnostics_log_level: Option<String> = "efi_diagnostics_log_level",
+        pcat: bool = "pcat",
+        pcat_firmware: Option<String> = "pcat_firmware",
+        pcat_boot_order: Option<String> = "pcat_boot_order",
+        vga_firmware: Option<String> = "vga_firmware",
+    }
+
+    /// Storage controllers, disks, NICs, and other devices.
+    devices_config: DevicesConfig {
+        vmbus_scsi: Vec<String> = "vmbus_scsi",
+        scsi_sub_channels: Option<u16> = "scsi_sub_channels",
+        nvme_pci: Vec<String> = "nvme_pci",
+        openhcl_controllers: Vec<String> = "openhcl_controller",
+        disks: Vec<String> = "disk",
+        nvme_disks: Vec<String> = "nvme",
+        virtio_blk_disks: Vec<String> = "virtio_blk",
+        ide_disks: Vec<String> = "ide",
+        floppy_disks: Vec<String> = "floppy",
+        vhost_user: Vec<String> = "vhost_user",
+        vmgs: Option<String> = "vmgs",
+        test_gsp_by_id: bool = "test_gsp_by_id",
+        nic: bool = "nic",
+        net: Vec<String> = "net",
+        net_switch: Option<String> = "net_switch",
+        kernel_vmnic: Vec<String> = "kernel_vmnic",
+        mana: Vec<String> = "mana",
+        gfx: bool = "gfx",
+        vtl2_gfx: bool = "vtl2_gfx",
+        vnc: bool = "vnc",
+        vnc_port: Option<u16> = "vnc_port",
+        vnc_listen: Option<String> = "vnc_listen",
+        vnc_max_clients: Option<usize> = "vnc_max_clients",
+        vnc_evict_oldest: bool = "vnc_evict_oldest",
+        tpm: bool = "tpm",
+        guest_watchdog: bool = "guest_watchdog",
+        battery: bool = "battery",
+        imc: Option<String> = "imc",
+        cxl_test: Vec<String> = "cxl_test",
+        vfio: Vec<String> = "vfio",
+        device: Vec<String> = "device",
+        virtio_9p: Vec<String> = "virtio_9p",
+        virtio_9p_debug: bool = "virtio_9p_debug",
+        virtio_fs: Vec<String> = "virtio_fs",
+        virtio_fs_shmem: Vec<String> = "virtio_fs_shmem",
+        virtio_fs_bus: Option<String> = "virtio_fs_bus",
+        virtio_pmem: Option<String> = "virtio_pmem",
+        virtio_rng: bool = "virtio_rng",
+        virtio_rng_bus: Option<String> = "virtio_rng_bus",
+        virtio_rng_pcie_port: Option<String> = "virtio_rng_pcie_port",
+        virtio_balloon: bool = "virtio_balloon",
+        virtio_balloon_deflate_on_oom: bool = "virtio_balloon_deflate_on_oom",
+        virtio_input: bool = "virtio_input",
+        virtio_gpu: bool = "virtio_gpu",
+        virtio_gpu_resolution: Option<String> = "virtio_gpu_resolution",
+        virtio_console: Option<String> = "virtio_console",
+        virtio_console_pcie_port: Option<String> = "virtio_console_pcie_port",
+        virtio_net: Vec<String> = "virtio_net",
+        virtio_vsock_path: Option<String> = "virtio_vsock_path",
+    }
+
+    /// Serial ports.
+    serial_config: SerialConfig {
+        com1: Option<String> = "com1",
+        com2: Option<String> = "com2",
+        com3: Option<String> = "com3",
+        com4: Option<String> = "com4",
+        vmbus_com1: Option<String> = "vmbus_com1_serial",
+        vmbus_com2: Option<String> = "vmbus_com2_serial",
+        debugcon: Option<String> = "debugcon",
+        tx_only: bool = "serial_tx_only",
+    }
+
+    /// PCIe topology and IOMMUs.
+    pcie: PcieConfig {
+        root_complexes: Vec<String> = "pcie_root_complex",
+        root_ports: Vec<String> = "pcie_root_port",
+        switches: Vec<String> = "pcie_switch",
+        generic_initiators: Vec<String> = "pcie_generic_initiator",
+        remotes: Vec<String> = "pcie_remote",
+        iommus: Vec<String> = "iommu",
+        amd_iommus: Vec<String> = "amd_iommu",
+        intel_vtds: Vec<String> = "intel_vtd",
+        smmus: Vec<String> = "smmu",
+    }
+
+    /// Hyper-V socket listeners.
+    hvsocket_config: HvSocketConfig {
+        path: Option<String> = "vmbus_vsock_path",
+        vtl2_path: Option<String> = "vmbus_vtl2_vsock_path",
+    }
+
+    /// Hypervisor, VTL2, and guest power options, which have no vmservice
+    /// equivalent.
+    options: VmOptions {
+        hv: bool = "hv",
+        hypervisor: Option<String> = "hypervisor",
+        vtl2: bool = "vtl2",
+        get: bool = "get",
+        no_get: bool = "no_get",
+        no_vmbus: bool = "no_vmbus",
+        no_alias_map: bool = "no_alias_map",
+        isolation: Option<String> = "isolation",
+        late_map_vtl0_policy: Option<String> = "late_map_vtl0_policy",
+        vmbus_redirect: bool = "vmbus_redirect",
+        vmbus_max_version: Option<String> = "vmbus_max_version",
+        checkpoint_dir: Option<String> = "checkpoint_dir",
+        guest_reset_action: Option<String> = "guest_reset_action",
+        guest_shutdown_action: Option<String> = "guest_shutdown_action",
+        guest_crash_action: Option<String> = "guest_crash_action",
+        guest_watchdog_action: Option<String> = "guest_watchdog_action",
+    }
+}
+
+impl ConfigFile {
+    /// Reads the options that were set on the command line in `matches`.
+    ///
+    /// Fails if an option that affects the VM has no place in the file, such
+    /// as a deprecated alias of another option.
+    pub fn from_command_line(command: &Command, matches: &ArgMatches) -> anyhow::Result<Self> {
+        let unsupported = command
+            .get_arguments()
+            .filter(|arg| {
+                let id = arg.get_id().as_str();
+                set_on_command_line(matches, id)
+                    && !Self::IDS.contains(&id)
+                    && !PROCESS_OPTIONS.contains(&id)
+            })
+            .map(|arg| format!("--{}", arg.get_long().unwrap_or(arg.get_id().as_str())))
+            .collect::<Vec<_>>();
+        if !unsupported.is_empty() {
+            anyhow::bail!(
+                "cannot store {} in a configuration file",
+                unsupported.join(", ")
+            );
+        }
+        Self::from_matches(matches)
+    }
+
+    /// Returns the command-line arguments equivalent to the file, leaving
+    /// out single-valued options that are set on the command line in `cli`.
+    pub fn to_args(
+        &self,
+        command: &Command,
+        cli: Option<&ArgMatches>,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut args = ArgWriter {
+            command,
+            cli,
+            args: Vec::new(),
+        };
+        self.write_args(&mut args)?;
+        Ok(args.args)
+    }
+
+    /// Returns the file as TOML.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml_edit::ser::to_string_pretty(self)?)
+    }
+
+    fn parse(text: &str, json: bool) -> anyhow::Result<Self> {
+        // Check the version before the contents, since a newer file may have
+        // sections this version does not know about.
+        #[derive(Deserialize)]
+        struct Version {
+            version: Option<i64>,
+        }
+
+        let version = if json {
+            serde_json::from_str::<Version>(text)?.version
+        } else {
+            toml_edit::de::from_str::<Version>(text)?.version
+        };
+        let version = version.context("missing version")?;
+        if !(1..=CONFIG_VERSION).contains(&version) {
+            anyhow::bail!(
+                "configuration version {version} is not supported (expected 1..={CONFIG_VERSION})"
+            );
+        }
+        Ok(if json {
+            serde_json::from_str(text)?
+        } else {
+            toml_edit::de::from_str(text)?
+        })
+    }
+
+    /// Loads the configuration file at `path` (TOML, or JSON if the extension
+    /// is `.json`).
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs_err::read_to_string(path)?;
+        Self::parse(&text, path.extension().is_some_and(|ext| ext == "json"))
+            .with_context(|| format!("failed to parse {}", path.display()))
+    }
+}
+
+/// Returns the options that were set on the command line in `matches` as
+/// arguments, using each option's canonical spelling and leaving out the ones
+/// that control the process rather than the VM.
+pub fn canonical_args(command: &Command, matches: &ArgMatches) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if !PROCESS_OPTIONS.contains(&id) && set_on_command_line(matches, id) {
+            push_arg(&mut args, arg, matches)?;
+        }
+    }
+    Ok(args)
+}
+
+/// Returns the options set on the command line in `cli` as arguments, merged
+/// with the VM options in `recorded`, such as those recorded in a snapshot.
+///
+/// Options set in `cli` replace the recorded ones, including options that can
+/// be repeated, such as `--disk`. Recorded options that control the process,
+/// or whose IDs are in `skip`, are left out.
+pub fn override_args(
+    command: &Command,
+    recorded: &ArgMatches,
+    cli: &ArgMatches,
+    skip: &[&str],
+) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if set_on_command_line(cli, id) {
+            push_arg(&mut args, arg, cli)?;
+        } else if !PROCESS_OPTIONS.contains(&id)
+            && !skip.contains(&id)
+            && set_on_command_line(recorded, id)
+        {
+            push_arg(&mut args, arg, recorded)?;
+        }
+    }
+    Ok(args)
+}
+
+/// Appends the values of `arg` in `matches` to `args`.
+fn push_arg(args: &mut Vec<String>, arg: &Arg, matches: &ArgMatches) -> anyhow::Result<()> {
+    let id = arg.get_id().as_str();
+    let flag = format!(
+        "--{}",
+        arg.get_long()
+            .with_context(|| format!("argument {id} has no long name"))?
+    );
+    match arg.get_action() {
+        ArgAction::SetTrue => args.push(flag),
+        ArgAction::Count => {
+            args.extend((0..matches.get_count(id)).map(|_| flag.clone()));
+        }
+        ArgAction::Set | ArgAction::Append => {
+            let values = raw_values(matches, id)?;
+            if values.is_empty() {
+                args.push(flag);
+            }
+            args.extend(values.iter().map(|v| format!("{flag}={v}")));
+        }
+        action => anyhow::bail!("{flag} has unsupported action {action:?}"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli_args::Options;
+    use clap::CommandFactory;
+
+    fn matches(args: &[&str]) -> ArgMatches {
+        Options::command()
+            .try_get_matches_from(std::iter::once("openvmm").chain(args.iter().copied()))
+            .unwrap()
+    }
+
+    #[test]
+    fn schema_ids() {
+        // Every field must map to a VM option, so that renaming an option
+        // fails here rather than silently dropping it from loaded files.
+        let command = Options::command();
+        for id in ConfigFile::IDS {
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_id() == *id)
+                .unwrap_or_else(|| panic!("unknown argument {id}"));
+            assert!(!PROCESS_OPTIONS.contains(id), "{id}");
+            assert!(arg.get_long().is_some(), "{id}");
+        }
+    }
+
+    #[test]
+    fn toml_roundtrip() {
+        let command = Options::command();
+        let matches = matches(&[
+            "-p",
+            "4",
+            "--memory",
+            "4G",
+            "--uefi",
+            "--vmbus-scsi",
+            "id=scsi0",
+            "--disk",
+            "file:a.vhdx,on=scsi0",
+            "--disk",
+            "file:b.vhdx,on=scsi0",
+            "--nic",
+            "--com1",
+            "console",
+            "--log-file",
+            "openvmm.log",
+        ]);
+        let config = ConfigFile::from_command_line(&command, &matches).unwrap();
+        let text = config.to_toml().unwrap();
+        assert!(text.starts_with("version = 1\n"), "{text}");
+        assert!(
+            text.contains("[processor_config]\nprocessor_count = 4\n"),
+            "{text}"
+        );
+        assert!(!text.contains("log"), "{text}");
+        assert_eq!(ConfigFile::parse(&text, false).unwrap(), config);
+
+        let args = config.to_args(&command, None).unwrap();
+        assert_eq!(
+            args,
+            [
+                "--processors=4",
+                "--memory=4G",
+                "--uefi",
+                "--vmbus-scsi=id=scsi0",
+                "--disk=file:a.vhdx,on=scsi0",
+                "--disk=file:b.vhdx,on=scsi0",
+                "--nic",
+                "--com1=console",
+            ]
+        );
+
+        // The arguments must parse to the same configuration.
+        let reparsed = Options::command()
+            .try_get_matches_from(std::iter::once("openvmm".to_owned()).chain(args))
+            .unwrap();
+        assert_eq!(
+            ConfigFile::from_command_line(&command, &reparsed).unwrap(),
+            config
+        );
+    }
+
+    #[test]
+    fn command_line_overrides() {
+        let command = Options::command();
+        let config = ConfigFile::parse(
+            r#"
+                version = 1
+                [processor_config]
+                processor_count = 4
+                [devices_config]
+                disks = ["mem:1G"]
+                gfx = true
+            "#,
+            false,
+        )
+        .unwrap();
+        let cli = matches(&["-p", "2", "--disk", "mem:2G"]);
+        let args = config.to_args(&command, Some(&cli)).unwrap();
+        assert_eq!(args, ["--disk=mem:1G", "--gfx"]);
+    }
+
+    #[test]
+    fn canonical_args_skip_process_options() {
+        let command = Options::command();
+        let cli = matches(&["-p", "2", "--paused", "--pidfile", "openvmm.pid", "--gfx"]);
+        let args = canonical_args(&command, &cli).unwrap();
+        assert_eq!(args, ["--processors=2", "--gfx"]);
+    }
+
+    #[test]
+    fn recorded_args_overrides() {
+        let command = Options::command();
+        let recorded = matches(&[
+            "-p",
+            "4",
+            "--disk",
+            "file:a.vhdx",
+            "--disk",
+            "file:b.vhdx",
+            "--gfx",
+            "--paused",
+            "--log-file",
+            "openvmm.log",
+            "--checkpoint-dir",
+            "checkpoints",
+        ]);
+        let cli = matches(&["--disk", "file:c.vhdx", "--ttrpc", "vm.sock"]);
+        let args = override_args(&command, &recorded, &cli, &["checkpoint_dir"]).unwrap();
+        assert_eq!(
+            args,
+            [
+                "--processors=4",
+                "--disk=file:c.vhdx",
+                "--gfx",
+                "--ttrpc=vm.sock"
+            ]
+        );
+    }
+
+    #[test]
+    fn json_config() {
+        let command = Options::command();
+        let config = ConfigFile::parse(
+            r#"{
+                "version": 1,
+                "processor_config": { "processor_count": 2 },
+                "devices_config": { "disks": ["mem:1G"], "gfx": true }
+            }"#,
+            true,
+        )
+        .unwrap();
+        let args = config.to_args(&command, None).unwrap();
+        assert_eq!(args, ["--processors=2", "--disk=mem:1G", "--gfx"]);
+    }
+
+    #[test]
+    fn deprecated_options() {
+        // Deprecated aliases are not part of the schema.
+        let command = Options::command();
+        let err = ConfigFile::from_command_line(&command, &matches(&["--prefetch"])).unwrap_err();
+        assert!(err.to_string().contains("--prefetch"), "{err}");
+        assert!(
+            ConfigFile::parse("version = 1\n[memory_config]\nprefetch = true\n", false).is_err()
+        );
+    }
+
+    #[test]
+    fn invalid_config() {
+        let parse = |text: &str| ConfigFile::parse(text, false);
+        assert!(parse("[processor_config]\nprocessor_count = 2\n").is_err());
+        assert!(parse("version = 2\n").is_err());
+        assert!(parse("version = 1\n[options]\nprocessor_count = 2\n").is_err());
+        assert!(parse("version = 1\n[processor_config]\nprocessors = 2\n").is_err());
+        assert!(parse("version = 1\n[processor_config]\nprocessor_count = \"2\"\n").is_err());
+        assert!(parse("version = 1\n[devices_config]\ngfx = \"yes\"\n").is_err());
+        assert!(parse("version = 1\n[log_file]\n").is_err());
+        assert_eq!(
+            parse("version = 1\n").unwrap(),
+            ConfigFile {
+                version: 1,
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -22,16 +22,20 @@
 
 /// Spawns a crash dump handling task and returns a resource to instantiate a
 /// guest crash device.
+///
+/// `on_dump` is called with the path of each dump file when the guest starts
+/// writing it.
 pub fn spawn_dump_handler(
     spawner: impl Spawn,
     dump_path: PathBuf,
     max_file_size: Option<u64>,
+    on_dump: impl 'static + Send + Fn(&Path),
 ) -> (Resource<VmbusDeviceHandleKind>, Task<()>) {
     const DEFAULT_MAX_DUMP_SIZE: u64 = 256 * 1024 * 1024;
 
     let (send, recv) = channel::<FailableRpc<_, _>>();
     let task = spawner.spawn("crash_dumps", async move {
-        handle_dump_requests(&dump_path, recv).await
+        handle_dump_requests(&dump_path, recv, on_dump).await
     });
     let config = GuestCrashDeviceHandle {
         request_dump: send,
@@ -41,12 +45,13 @@ pub fn spawn_dump_handler(
 }
 
 /// Handles dump requests from the crash dump device by opening files in the
-/// provided path.
+/// provided path, calling `on_dump` with the path of each one.
 pub async fn handle_dump_requests(
     dump_path: &Path,
     mut recv: mesh::Receiver<
         mesh::rpc::Rpc<OneshotReceiver<()>, Result<File, mesh::error::RemoteError>>,
     >,
+    on_dump: impl Fn(&Path),
 ) {
     let mut tasks = FuturesUnordered::new();
     while let Some(rpc) = ((&mut recv).map(Some), (&mut tasks).map(|()| None))
@@ -68,6 +73,7 @@ pub async fn handle_dump_requests(
                 .context("failed to clone file")?;
 
             tracing::info!(path = %tempfile.path().display(), "writing VTL2 crash dump");
+            on_dump(tempfile.path());
             tasks.push(wait_for_dump(done, tempfile));
             anyhow::Ok(file)
         })
@@ -7,10 +7,13 @@
 #![expect(missing_docs)]
 #![forbid(unsafe_code)]
 
+mod checkpoint;
 mod cli_args;
+mod config_file;
 mod crash_dump;
 mod kvp;
 mod meshworker;
+mod migration;
 mod pidfile;
 mod repl;
 mod serial_io;
@@ -28,7 +31,6 @@
 use anyhow::Context;
 use anyhow::bail;
 use chipset_resources::battery::HostBatteryUpdate;
-use cli_args::DiskCliKind;
 use cli_args::EfiDiagnosticsLogLevelCli;
 use cli_args::EndpointConfigCli;
 use cli_args::GuestPowerAction;
@@ -40,12 +42,10 @@
 use cli_args::VmgsCli;
 use crash_dump::spawn_dump_handler;
 use cxl_spec::test::CxlTestDeviceHandle;
-use disk_backend_resources::DelayDiskHandle;
 use disk_backend_resources::DiskLayerDescription;
-use disk_backend_resources::layer::DiskLayerHandle;
 use disk_backend_resources::layer::RamDiskLayerHandle;
-use disk_backend_resources::layer::SqliteAutoCacheDiskLayerHandle;
 use disk_backend_resources::layer::SqliteDiskLayerHandle;
+use e1000e_resources::E1000eDeviceHandle;
 use floppy_resources::FloppyDiskConfig;
 use framebuffer::FRAMEBUFFER_SIZE;
 use framebuffer::FramebufferAccess;
@@ -60,7 +60,6 @@
 use input_core::MultiplexedInputHandle;
 use inspect::InspectMut;
 use mesh::CancelContext;
-use mesh::CellUpdater;
 use mesh::rpc::RpcSend;
 use meshworker::VmmMesh;
 use net_backend_resources::mac_address::MacAddress;
@@ -92,9 +91,7 @@
 use openvmm_defs::rpc::VmRpc;
 use openvmm_defs::worker::VM_WORKER;
 use openvmm_defs::worker::VmWorkerParameters;
-use openvmm_helpers::disk::OpenDiskOptions;
-use openvmm_helpers::disk::create_disk_type;
-use openvmm_helpers::disk::open_disk_type;
+use openvmm_helpers::disk_cli::open_disk;
 use pal_async::DefaultDriver;
 use pal_async::DefaultPool;
 use pal_async::socket::PolledSocket;
@@ -129,9 +126,8 @@
 use vm_manifest_builder::VmChipsetResult;
 use vm_manifest_builder::VmManifestBuilder;
 use vm_resource::IntoResource;
+use vm_resource::PlatformResource;
 use vm_resource::Resource;
-use vm_resource::kind::DiskHandleKind;
-use vm_resource::kind::DiskLayerHandleKind;
 use vm_resource::kind::NetEndpointHandleKind;
 use vm_resource::kind::VirtioDeviceHandle;
 use vm_resource::kind::VmbusDeviceHandleKind;
@@ -187,9 +183,23 @@ struct VmResources {
     framebuffer_access: Option<FramebufferAccess>,
     shutdown_ic: Option<mesh::Sender<hyperv_ic_resources::shutdown::ShutdownRpc>>,
     kvp_ic: Option<mesh::Sender<hyperv_ic_resources::kvp::KvpConnectRpc>>,
+    heartbeat_ic: Option<mesh::Cell<hyperv_ic_resources::heartbeat::HeartbeatStatus>>,
+    vss_ic: Option<mesh::Sender<hyperv_ic_resources::vss::VssRpc>>,
+    fcopy_ic: Option<mesh::Sender<hyperv_ic_resources::fcopy::FcopyRpc>>,
     scsi_rpc: Option<mesh::Sender<ScsiControllerRequest>>,
     nvme_vtl2_rpc: Option<mesh::Sender<NvmeControllerRequest>>,
     consomme_rpc: Option<mesh::Sender<net_backend_resources::consomme::ConsommeRequest>>,
+    balloon_rpc: Option<mesh::Sender<virtio_resources::balloon::BalloonRequest>>,
+    /// Runtime request channels for NICs with network impairment, keyed by
+    /// NIC index.
+    net_impair: BTreeMap<usize, mesh::Sender<net_backend_resources::impair::ImpairRequest>>,
+    /// Runtime request channels for NICs that support packet capture, keyed
+    /// by NIC index.
+    packet_capture:
+        BTreeMap<usize, mesh::Sender<net_backend_resources::packet_capture::PacketCaptureRequest>>,
+    /// Runtime request channels for disks that support live snapshots, keyed
+    /// by disk name.
+    layered_disks: BTreeMap<String, mesh::Sender<disk_backend_resources::LayeredDiskRequest>>,
     ged_rpc: Option<mesh::Sender<get_resources::ged::GuestEmulationRequest>>,
     vtl2_settings: Option<vtl2_settings_proto::Vtl2Settings>,
     /// Receives dirty rectangles from the synthetic video device for the VNC worker.
@@ -465,7 +475,11 @@ async fn vm_config_from_command_line(
 
     let with_get = opt.get || (opt.vtl2 && !opt.no_get);
 
-    let mut storage = storage_builder::StorageBuilder::new(with_get.then_some(openhcl_vtl));
+    // Checkpoints redirect the writes of every disk into the checkpoint tree.
+    let mut storage = storage_builder::StorageBuilder::new(
+        with_get.then_some(openhcl_vtl),
+        opt.checkpoint_dir.is_some(),
+    );
 
     // Register named controllers first, so that --disk on=<name>
     // references can be resolved.
@@ -503,6 +517,7 @@ async fn vm_config_from_command_line(
         ref kind,
         read_only,
         is_dvd,
+        snapshot,
         underhill,
         ref pcie_port,
         ref controller,
@@ -555,6 +570,7 @@ async fn vm_config_from_command_line(
                 kind,
                 is_dvd,
                 read_only,
+                snapshot,
             )
             .await?;
     }
@@ -576,6 +592,7 @@ async fn vm_config_from_command_line(
                 kind,
                 is_dvd,
                 read_only,
+                false,
             )
             .await?;
     }
@@ -607,6 +624,7 @@ async fn vm_config_from_command_line(
         ref kind,
         read_only,
         is_dvd,
+        snapshot,
         underhill,
         ref pcie_port,
         controller: _,
@@ -625,7 +643,9 @@ async fn vm_config_from_command_line(
             storage_builder::DiskLocation::Nvme(None)
         };
         storage
-            .add(vtl, underhill, None, target, kind, is_dvd, read_only)
+            .add(
+                vtl, underhill, None, target, kind, is_dvd, read_only, snapshot,
+            )
             .await?;
     }
 
@@ -634,6 +654,7 @@ async fn vm_config_from_command_line(
         ref kind,
         read_only,
         is_dvd,
+        snapshot,
         ref underhill,
         ref pcie_port,
         controller: _,
@@ -654,6 +675,7 @@ async fn vm_config_from_command_line(
                 kind,
                 is_dvd,
                 read_only,
+                snapshot,
             )
             .await?;
     }
@@ -665,7 +687,7 @@ async fn vm_config_from_command_line(
             read_only,
         } = disk;
         floppy_disks.push(FloppyDiskConfig {
-            disk_type: disk_open(kind, read_only).await?,
+            disk_type: open_disk(kind, read_only).await?,
             read_only,
         });
     }
@@ -675,13 +697,28 @@ async fn vm_config_from_command_line(
     let mut underhill_nics = Vec::new();
     let mut vpci_devices = Vec::new();
 
+    let mut e1000e_pci_devices = Vec::new();
+    let mut pcie_e1000e_nics = Vec::new();
+
     let mut nic_index = 0;
     for cli_cfg in &opt.net {
-        if cli_cfg.pcie_port.is_some() {
-            anyhow::bail!("`--net` does not support PCIe");
+        if cli_cfg.pcie_port.is_some() && !cli_cfg.e1000e {
+            anyhow::bail!("`--net` only supports PCIe with `e1000e`");
+        }
+        if cli_cfg.mtu.is_some() {
+            anyhow::bail!("`--net` does not support `mtu=`");
         }
         let vport = parse_endpoint(cli_cfg, &mut nic_index, &mut resources)?;
-        if cli_cfg.underhill {
+        if cli_cfg.e1000e {
+            let handle = E1000eDeviceHandle {
+                mac_address: vport.mac_address,
+                endpoint: vport.endpoint,
+            };
+            match vport.pcie_port {
+                Some(pcie_port) => pcie_e1000e_nics.push((pcie_port, handle)),
+                None => e1000e_pci_devices.push(handle),
+            }
+        } else if cli_cfg.underhill {
             if !opt.no_alias_map {
                 anyhow::bail!("must specify --no-alias-map to offer NICs to VTL2");
             }
@@ -699,7 +736,11 @@ async fn vm_config_from_command_line(
                 endpoint: vport.endpoint,
             });
         } else {
-            vmbus_devices.push(vport.into_netvsp_handle());
+            vmbus_devices.push(
+                vport
+                    .with_packet_capture(&mut resources)
+                    .into_netvsp_handle(),
+            );
         }
     }
 
@@ -710,15 +751,26 @@ async fn vm_config_from_command_line(
                 endpoint: EndpointConfigCli::Consomme {
                     cidr: None,
                     host_fwd: Vec::new(),
+                    egress: Default::default(),
+                    guest_fwd: Vec::new(),
+                    dns_records: Vec::new(),
+                    dns_log: false,
                 },
                 max_queues: None,
+                mtu: None,
                 underhill: false,
                 pcie_port: None,
+                impair: None,
+                e1000e: false,
             },
             &mut nic_index,
             &mut resources,
         )?;
-        vmbus_devices.push(nic_config.into_netvsp_handle());
+        vmbus_devices.push(
+            nic_config
+                .with_packet_capture(&mut resources)
+                .into_netvsp_handle(),
+        );
     }
 
     // Build initial PCIe devices list from CLI options. Storage devices
@@ -782,6 +834,9 @@ async fn vm_config_from_command_line(
     }
 
     for vport in &opt.mana {
+        if vport.mtu.is_some() {
+            anyhow::bail!("`--mana` does not support `mtu=`");
+        }
         let vport = parse_endpoint(vport, &mut nic_index, &mut resources)?;
         let vport_array = match (vport.vtl as usize, vport.pcie_port) {
             (vtl, None) => {
@@ -834,6 +889,15 @@ async fn vm_config_from_command_line(
             }),
     );
 
+    pcie_devices.extend(
+        pcie_e1000e_nics
+            .into_iter()
+            .map(|(pcie_port, handle)| PcieDeviceConfig {
+                port_name: pcie_port,
+                resource: handle.into_resource(),
+            }),
+    );
+
     for cxl_test in &opt.cxl_test {
         pcie_devices.push(PcieDeviceConfig {
             port_name: cxl_test.pcie_port.clone(),
@@ -1118,7 +1182,7 @@ async fn vm_config_from_command_line(
         None
     };
 
-    let framebuffer = if opt.gfx || opt.vtl2_gfx || opt.vnc.vnc || opt.pcat {
+    let framebuffer = if opt.gfx || opt.vtl2_gfx || opt.vnc.vnc || opt.pcat || opt.virtio_gpu {
         let vram = alloc_shared_memory(FRAMEBUFFER_SIZE, "vram")?;
         let (fb, fba) =
             framebuffer::framebuffer(vram, FRAMEBUFFER_SIZE, 0).context("creating framebuffer")?;
@@ -1262,9 +1326,10 @@ async fn vm_config_from_command_line(
         .build()
         .context("failed to build chipset configuration")?;
 
-    if opt.restore_snapshot.is_some() {
-        // Snapshot restore: skip firmware loading entirely. Device state and
-        // memory come from the snapshot directory.
+    if opt.restore_snapshot.is_some() || opt.migrate_incoming.is_some() {
+        // Snapshot restore or incoming migration: skip firmware loading
+        // entirely. Device state and memory come from the snapshot directory
+        // or the migration source.
         load_mode = LoadMode::None;
         with_hv = true;
     } else if let Some(path) = &opt.igvm {
@@ -1379,7 +1444,7 @@ async fn vm_config_from_command_line(
 
     let mut vmgs = Some(if let Some(VmgsCli { kind, provision }) = &opt.vmgs {
         let disk = VmgsDisk {
-            disk: disk_open(kind, false)
+            disk: open_disk(kind, false)
                 .await
                 .context("failed to open vmgs disk")?,
             encryption_policy: if opt.test_gsp_by_id {
@@ -1606,7 +1671,7 @@ async fn vm_config_from_command_line(
     let vtl2_vsock_listener = vsock_listener(opt.vmbus_vtl2_vsock_path.as_deref())?;
 
     if let Some(path) = &opt.openhcl_dump_path {
-        let (resource, task) = spawn_dump_handler(&spawner, path.clone(), None);
+        let (resource, task) = spawn_dump_handler(&spawner, path.clone(), None, |_| {});
         task.detach();
         vmbus_devices.push((openhcl_vtl, resource));
     }
@@ -1656,6 +1721,13 @@ async fn vm_config_from_command_line(
         resources.shutdown_ic = Some(shutdown_send);
         let (kvp_send, kvp_recv) = mesh::channel();
         resources.kvp_ic = Some(kvp_send);
+        let (heartbeat_status, heartbeat_cell) =
+            mesh::cell(hyperv_ic_resources::heartbeat::HeartbeatStatus::NoContact);
+        resources.heartbeat_ic = Some(heartbeat_cell);
+        let (vss_send, vss_recv) = mesh::channel();
+        resources.vss_ic = Some(vss_send);
+        let (fcopy_send, fcopy_recv) = mesh::channel();
+        resources.fcopy_ic = Some(fcopy_send);
         vmbus_devices.extend(
             [
                 hyperv_ic_resources::shutdown::ShutdownIcHandle {
@@ -1664,6 +1736,12 @@ async fn vm_config_from_command_line(
                 .into_resource(),
                 hyperv_ic_resources::kvp::KvpIcHandle { recv: kvp_recv }.into_resource(),
                 hyperv_ic_resources::timesync::TimesyncIcHandle.into_resource(),
+                hyperv_ic_resources::heartbeat::HeartbeatIcHandle {
+                    status: heartbeat_status,
+                }
+                .into_resource(),
+                hyperv_ic_resources::vss::VssIcHandle { recv: vss_recv }.into_resource(),
+                hyperv_ic_resources::fcopy::FcopyIcHandle { recv: fcopy_recv }.into_resource(),
             ]
             .map(|r| (DeviceVtl::Vtl0, r)),
         );
@@ -1709,9 +1787,11 @@ async fn vm_config_from_command_line(
         if cli_cfg.underhill {
             anyhow::bail!("use --net uh:[...] to add underhill NICs")
         }
-        let vport = parse_endpoint(cli_cfg, &mut nic_index, &mut resources)?;
+        let vport = parse_endpoint(cli_cfg, &mut nic_index, &mut resources)?
+            .with_packet_capture(&mut resources);
         let resource = virtio_resources::net::VirtioNetHandle {
             max_queues: vport.max_queues,
+            mtu: cli_cfg.mtu,
             mac_address: vport.mac_address,
             endpoint: vport.endpoint,
         }
@@ -1808,6 +1888,60 @@ async fn vm_config_from_command_line(
         }
     }
 
+    if opt.virtio_balloon {
+        let (send, recv) = mesh::channel();
+        resources.balloon_rpc = Some(send);
+        add_virtio_device(
+            VirtioBusCli::Auto,
+            virtio_resources::balloon::VirtioBalloonHandle {
+                discard_ram: PlatformResource.into_resource(),
+                deflate_on_oom: opt.virtio_balloon_deflate_on_oom,
+                recv,
+            }
+            .into_resource(),
+        );
+    }
+
+    if opt.virtio_input {
+        use virtio_resources::input::VirtioInputDevice;
+        use virtio_resources::input::VirtioInputHandle;
+
+        // Above PS/2 (0) and the synthetic VMBus devices (1).
+        const ELEVATION: usize = 2;
+        for device in [
+            VirtioInputDevice::Keyboard(
+                MultiplexedInputHandle {
+                    elevation: ELEVATION,
+                }
+                .into_resource(),
+            ),
+            VirtioInputDevice::Tablet(
+                MultiplexedInputHandle {
+                    elevation: ELEVATION,
+                }
+                .into_resource(),
+            ),
+        ] {
+            add_virtio_device(
+                VirtioBusCli::Auto,
+                VirtioInputHandle { device }.into_resource(),
+            );
+        }
+    }
+
+    if opt.virtio_gpu {
+        let (width, height) = opt.virtio_gpu_resolution;
+        add_virtio_device(
+            VirtioBusCli::Auto,
+            virtio_resources::gpu::VirtioGpuHandle {
+                framebuffer: SharedFramebufferHandle.into_resource(),
+                width,
+                height,
+            }
+            .into_resource(),
+        );
+    }
+
     if let Some(backend) = virtio_console_backend {
         let resource: Resource<VirtioDeviceHandle> =
             virtio_resources::console::VirtioConsoleHandle { backend }.into_resource();
@@ -1854,6 +1988,22 @@ async fn vm_config_from_command_line(
                 queue_size,
             }
             .into_resource(),
+            VhostUserDeviceTypeCli::Net {
+                mac_address,
+                queue_pairs,
+                queue_size,
+            } => virtio_resources::vhost_user::VhostUserNetHandle {
+                socket: stream.into(),
+                mac_address: mac_address.unwrap_or_else(|| {
+                    // Pick a random MAC address.
+                    let mut mac_address = [0x00, 0x15, 0x5D, 0, 0, 0];
+                    getrandom::fill(&mut mac_address[3..]).expect("rng failure");
+                    mac_address.into()
+                }),
+                queue_pairs,
+                queue_size,
+            }
+            .into_resource(),
             VhostUserDeviceTypeCli::Other {
                 device_id,
                 ref queue_sizes,
@@ -1993,6 +2143,7 @@ async fn vm_config_from_command_line(
         vga_firmware,
         vtl2_gfx: opt.vtl2_gfx,
         virtio_devices,
+        e1000e_pci_devices,
         vmbus: (with_hv && !opt.no_vmbus).then_some(VmbusConfig {
             vsock_listener: vtl0_vsock_listener,
             vsock_path: opt.vmbus_vsock_path.clone(),
@@ -2092,7 +2243,14 @@ fn parse_endpoint(
 ) -> anyhow::Result<NicConfig> {
     let _ = resources;
     let endpoint = match &cli_cfg.endpoint {
-        EndpointConfigCli::Consomme { cidr, host_fwd } => {
+        EndpointConfigCli::Consomme {
+            cidr,
+            host_fwd,
+            egress,
+            guest_fwd,
+            dns_records,
+            dns_log,
+        } => {
             let ports = host_fwd
                 .iter()
                 .map(|fwd| {
@@ -2124,6 +2282,10 @@ fn parse_endpoint(
                 cidr: cidr.clone(),
                 ports,
                 recv,
+                egress: egress.clone(),
+                guest_forwards: guest_fwd.clone(),
+                dns_records: dns_records.clone(),
+                log_dns_queries: *dns_log,
             }
             .into_resource()
         }
@@ -2148,20 +2310,89 @@ fn parse_endpoint(
                 bail!("cannot use dio on non-windows platforms")
             }
         }
-        EndpointConfigCli::Tap { name } => {
+        EndpointConfigCli::Tap { name, vhost } => {
             #[cfg(target_os = "linux")]
             {
-                let fd = net_tap::tap::open_tap(name)
-                    .with_context(|| format!("failed to open TAP device '{name}'"))?;
-                net_backend_resources::tap::TapHandle { fd }.into_resource()
+                if *vhost {
+                    // Open one TAP queue per queue pair, each with its own
+                    // vhost-net instance.
+                    let count = cli_cfg.max_queues.unwrap_or(1).max(1).into();
+                    let queues = net_tap::tap::open_tap_queues(name, count)
+                        .with_context(|| format!("failed to open TAP device '{name}'"))?
+                        .into_iter()
+                        .map(|tap| {
+                            Ok(net_backend_resources::tap::TapVhostQueue {
+                                tap,
+                                vhost: net_tap::vhost::open_vhost_net()?,
+                            })
+                        })
+                        .collect::<anyhow::Result<_>>()?;
+                    net_backend_resources::tap::TapVhostHandle { queues }.into_resource()
+                } else {
+                    let fd = net_tap::tap::open_tap(name)
+                        .with_context(|| format!("failed to open TAP device '{name}'"))?;
+                    net_backend_resources::tap::TapHandle { fd }.into_resource()
+                }
             }
 
             #[cfg(not(target_os = "linux"))]
             {
-                let _ = name;
+                let _ = (name, vhost);
                 bail!("TAP backend is only supported on Linux")
             }
         }
+        EndpointConfigCli::Stream { path } => {
+            #[cfg(unix)]
+            {
+                let socket = std::os::unix::net::UnixStream::connect(path)
+                    .with_context(|| format!("failed to connect to {}", path.display()))?;
+                net_backend_resources::socket::SocketHandle {
+                    socket: socket.into(),
+                    framing: net_backend_resources::socket::SocketFraming::Stream,
+                }
+                .into_resource()
+            }
+
+            #[cfg(not(unix))]
+            {
+                let _ = path;
+                bail!("stream backend is only supported on Unix platforms")
+            }
+        }
+        EndpointConfigCli::Dgram { local, remote } => {
+            #[cfg(unix)]
+            {
+                let socket = std::os::unix::net::UnixDatagram::bind(local)
+                    .with_context(|| format!("failed to bind to {}", local.display()))?;
+                net_backend_resources::socket::SocketHandle {
+                    socket: socket.into(),
+                    framing: net_backend_resources::socket::SocketFraming::Datagram {
+                        remote: Some(remote.to_string_lossy().into_owned()),
+                    },
+                }
+                .into_resource()
+            }
+
+            #[cfg(not(unix))]
+            {
+                let _ = (local, remote);
+                bail!("dgram backend is only supported on Unix platforms")
+            }
+        }
+    };
+
+    let endpoint = if let Some(config) = &cli_cfg.impair {
+        let (send, recv) = mesh::channel();
+        resources.net_impair.insert(*index, send);
+        net_backend_resources::impair::ImpairHandle {
+            endpoint,
+            config: config.clone(),
+            seed: None,
+            recv: Some(recv),
+        }
+        .into_resource()
+    } else {
+        endpoint
     };
 
     // Pick a random MAC address.
@@ -2174,9 +2405,11 @@ fn parse_endpoint(
         data1: *index as u32,
         ..BASE_INSTANCE_ID
     };
+    let nic_index = *index;
     *index += 1;
 
     Ok(NicConfig {
+        index: nic_index,
         vtl: cli_cfg.vtl,
         instance_id,
         endpoint,
@@ -2188,6 +2421,7 @@ fn parse_endpoint(
 
 #[derive(Debug)]
 struct NicConfig {
+    index: usize,
     vtl: DeviceVtl,
     instance_id: Guid,
     mac_address: MacAddress,
@@ -2197,6 +2431,23 @@ struct NicConfig {
 }
 
 impl NicConfig {
+    /// Wraps the endpoint so that its traffic can be captured from the REPL.
+    ///
+    /// Captures start and stop by asking the frontend to restart its queues,
+    /// so only frontends that handle endpoint restart requests (netvsp and
+    /// virtio-net) can use this.
+    fn with_packet_capture(mut self, resources: &mut VmResources) -> Self {
+        let (send, recv) = mesh::channel();
+        resources.packet_capture.insert(self.index, send);
+        self.endpoint = net_backend_resources::packet_capture::PacketCaptureHandle {
+            name: format!("nic{}", self.index),
+            endpoint: self.endpoint,
+            recv,
+        }
+        .into_resource();
+        self
+    }
+
     fn into_netvsp_handle(self) -> (DeviceVtl, Resource<VmbusDeviceHandleKind>) {
         (
             self.vtl,
@@ -2211,200 +2462,73 @@ fn into_netvsp_handle(self) -> (DeviceVtl, Resource<VmbusDeviceHandleKind>) {
     }
 }
 
-enum LayerOrDisk {
-    Layer(DiskLayerDescription),
-    Disk(Resource<DiskHandleKind>),
-}
-
-async fn disk_open(
-    disk_cli: &DiskCliKind,
-    read_only: bool,
-) -> anyhow::Result<Resource<DiskHandleKind>> {
-    let mut layers = Vec::new();
-    disk_open_inner(disk_cli, read_only, &mut layers).await?;
-    if layers.len() == 1 && matches!(layers[0], LayerOrDisk::Disk(_)) {
-        let LayerOrDisk::Disk(disk) = layers.pop().unwrap() else {
-            unreachable!()
-        };
-        Ok(disk)
-    } else {
-        Ok(Resource::new(disk_backend_resources::LayeredDiskHandle {
-            layers: layers
-                .into_iter()
-                .map(|layer| match layer {
-                    LayerOrDisk::Layer(layer) => layer,
-                    LayerOrDisk::Disk(disk) => DiskLayerDescription {
-                        layer: DiskLayerHandle(disk).into_resource(),
-                        read_cache: false,
-                        write_through: false,
-                    },
-                })
-                .collect(),
-        }))
+/// Runs `f` with the VM paused, resuming the VM afterwards if it was running.
+async fn with_vm_paused<T>(
+    vm_rpc: &mesh::Sender<VmRpc>,
+    f: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let was_running = vm_rpc.call(VmRpc::Pause, ()).await?;
+    let r = f.await;
+    if was_running {
+        vm_rpc.call(VmRpc::Resume, ()).await?;
     }
+    r
 }
 
-fn disk_open_inner<'a>(
-    disk_cli: &'a DiskCliKind,
-    read_only: bool,
-    layers: &'a mut Vec<LayerOrDisk>,
-) -> futures::future::BoxFuture<'a, anyhow::Result<()>> {
-    Box::pin(async move {
-        fn layer<T: IntoResource<DiskLayerHandleKind>>(layer: T) -> LayerOrDisk {
-            LayerOrDisk::Layer(layer.into_resource().into())
+/// Returns the description of a new top layer for a live disk snapshot,
+/// stored in memory or, if `sqlite_path` is specified, in a new sqlite file.
+fn snapshot_layer(sqlite_path: Option<&Path>) -> anyhow::Result<DiskLayerDescription> {
+    let layer = if let Some(path) = sqlite_path {
+        if path.exists() {
+            anyhow::bail!(
+                "cannot create new sqlite disk at {} - file already exists",
+                path.display()
+            );
         }
-        fn disk<T: IntoResource<DiskHandleKind>>(disk: T) -> LayerOrDisk {
-            LayerOrDisk::Disk(disk.into_resource())
+        SqliteDiskLayerHandle {
+            dbhd_path: path.display().to_string(),
+            format_dbhd: Some(disk_backend_resources::layer::SqliteDiskLayerFormatParams {
+                logically_read_only: false,
+                len: None,
+            }),
         }
-        match disk_cli {
-            &DiskCliKind::Memory(len) => {
-                layers.push(layer(RamDiskLayerHandle {
-                    len: Some(len),
-                    sector_size: None,
-                }));
-            }
-            DiskCliKind::File {
-                path,
-                create_with_len,
-                direct,
-            } => layers.push(LayerOrDisk::Disk(if let Some(size) = create_with_len {
-                create_disk_type(
-                    path,
-                    *size,
-                    OpenDiskOptions {
-                        read_only: false,
-                        direct: *direct,
-                    },
-                )
-                .with_context(|| format!("failed to create {}", path.display()))?
-            } else {
-                open_disk_type(
-                    path,
-                    OpenDiskOptions {
-                        read_only,
-                        direct: *direct,
-                    },
-                )
-                .await
-                .with_context(|| format!("failed to open {}", path.display()))?
-            })),
-            DiskCliKind::Blob { kind, url } => {
-                layers.push(disk(disk_backend_resources::BlobDiskHandle {
-                    url: url.to_owned(),
-                    format: match kind {
-                        cli_args::BlobKind::Flat => disk_backend_resources::BlobDiskFormat::Flat,
-                        cli_args::BlobKind::Vhd1 => {
-                            disk_backend_resources::BlobDiskFormat::FixedVhd1
-                        }
-                    },
-                }))
-            }
-            DiskCliKind::MemoryDiff(inner) => {
-                layers.push(layer(RamDiskLayerHandle {
-                    len: None,
-                    sector_size: None,
-                }));
-                disk_open_inner(inner, true, layers).await?;
-            }
-            DiskCliKind::PersistentReservationsWrapper(inner) => {
-                layers.push(disk(disk_backend_resources::DiskWithReservationsHandle(
-                    disk_open(inner, read_only).await?,
-                )))
-            }
-            DiskCliKind::DelayDiskWrapper {
-                delay_ms,
-                disk: inner,
-            } => layers.push(disk(DelayDiskHandle {
-                delay: CellUpdater::new(Duration::from_millis(*delay_ms)).cell(),
-                disk: disk_open(inner, read_only).await?,
-            })),
-            DiskCliKind::Crypt {
-                disk: inner,
-                cipher,
-                key_file,
-            } => layers.push(disk(disk_crypt_resources::DiskCryptHandle {
-                disk: disk_open(inner, read_only).await?,
-                cipher: match cipher {
-                    cli_args::DiskCipher::XtsAes256 => disk_crypt_resources::Cipher::XtsAes256,
-                },
-                key: fs_err::read(key_file).context("failed to read key file")?,
-            })),
-            DiskCliKind::Sqlite {
-                path,
-                create_with_len,
-            } => {
-                // FUTURE: this code should be responsible for opening
-                // file-handle(s) itself, and passing them into sqlite via a custom
-                // vfs. For now though - simply check if the file exists or not, and
-                // perform early validation of filesystem-level create options.
-                match (create_with_len.is_some(), path.exists()) {
-                    (true, true) => anyhow::bail!(
-                        "cannot create new sqlite disk at {} - file already exists",
-                        path.display()
-                    ),
-                    (false, false) => anyhow::bail!(
-                        "cannot open sqlite disk at {} - file not found",
-                        path.display()
-                    ),
-                    _ => {}
-                }
-
-                layers.push(layer(SqliteDiskLayerHandle {
-                    dbhd_path: path.display().to_string(),
-                    format_dbhd: create_with_len.map(|len| {
-                        disk_backend_resources::layer::SqliteDiskLayerFormatParams {
-                            logically_read_only: false,
-                            len: Some(len),
-                        }
-                    }),
-                }));
-            }
-            DiskCliKind::SqliteDiff { path, create, disk } => {
-                // FUTURE: this code should be responsible for opening
-                // file-handle(s) itself, and passing them into sqlite via a custom
-                // vfs. For now though - simply check if the file exists or not, and
-                // perform early validation of filesystem-level create options.
-                match (create, path.exists()) {
-                    (true, true) => anyhow::bail!(
-                        "cannot create new sqlite disk at {} - file already exists",
-                        path.display()
-                    ),
-                    (false, false) => anyhow::bail!(
-                        "cannot open sqlite disk at {} - file not found",
-                        path.display()
-                    ),
-                    _ => {}
-                }
-
-                layers.push(layer(SqliteDiskLayerHandle {
-                    dbhd_path: path.display().to_string(),
-                    format_dbhd: create.then_some(
-                        disk_backend_resources::layer::SqliteDiskLayerFormatParams {
-                            logically_read_only: false,
-                            len: None,
-                        },
-                    ),
-                }));
-                disk_open_inner(disk, true, layers).await?;
-            }
-            DiskCliKind::AutoCacheSqlite {
-                cache_path,
-                key,
-                disk,
-            } => {
-                layers.push(LayerOrDisk::Layer(DiskLayerDescription {
-                    read_cache: true,
-                    write_through: false,
-                    layer: SqliteAutoCacheDiskLayerHandle {
-                        cache_path: cache_path.clone(),
-                        cache_key: key.clone(),
-                    }
-                    .into_resource(),
-                }));
-                disk_open_inner(disk, read_only, layers).await?;
-            }
+        .into_resource()
+    } else {
+        RamDiskLayerHandle {
+            len: None,
+            sector_size: None,
         }
-        Ok(())
+        .into_resource()
+    };
+    Ok(layer.into())
+}
+
+/// Returns the parameters for copying the host file at `path` into
+/// `guest_dir` via the guest services IC, named `file_name` or, by default,
+/// the host file name.
+fn copy_file_params(
+    path: &Path,
+    guest_dir: String,
+    file_name: Option<String>,
+    overwrite: bool,
+    create_path: bool,
+) -> anyhow::Result<hyperv_ic_resources::fcopy::CopyFileParams> {
+    let file_name = match file_name {
+        Some(name) => name,
+        None => path
+            .file_name()
+            .context("path has no file name")?
+            .to_str()
+            .context("file name is not valid UTF-8")?
+            .to_owned(),
+    };
+    let file = fs_err::File::open(path)?.into();
+    Ok(hyperv_ic_resources::fcopy::CopyFileParams {
+        file,
+        guest_dir,
+        file_name,
+        overwrite,
+        create_path,
     })
 }
 
@@ -2420,6 +2544,53 @@ pub(crate) fn system_page_size() -> u32 {
     "aarch64"
 };
 
+/// Returns the disks on the command line, named by option and index, for
+/// recording their layers in snapshot manifests.
+fn configured_disks(opt: &Options) -> Vec<(String, openvmm_helpers::disk_cli::DiskCliKind)> {
+    let mut disks = Vec::new();
+    for (option, list) in [
+        ("disk", &opt.disk),
+        ("nvme", &opt.nvme),
+        ("virtio-blk", &opt.virtio_blk),
+    ] {
+        disks.extend(
+            list.iter()
+                .enumerate()
+                .map(|(i, disk)| (format!("{option}[{i}]"), disk.kind.clone())),
+        );
+    }
+    disks.extend(
+        opt.ide
+            .iter()
+            .enumerate()
+            .map(|(i, disk)| (format!("ide[{i}]"), disk.kind.clone())),
+    );
+    disks.extend(
+        opt.floppy
+            .iter()
+            .enumerate()
+            .map(|(i, disk)| (format!("floppy[{i}]"), disk.kind.clone())),
+    );
+    disks
+}
+
+/// Describes the current layer stacks of `disks`.
+fn snapshot_disks(
+    disks: &[(String, openvmm_helpers::disk_cli::DiskCliKind)],
+) -> anyhow::Result<Vec<openvmm_helpers::snapshot::SnapshotDisk>> {
+    disks
+        .iter()
+        .map(|(name, kind)| {
+            Ok(openvmm_helpers::snapshot::SnapshotDisk {
+                name: name.clone(),
+                layers: kind
+                    .snapshot_layers()
+                    .with_context(|| format!("failed to describe {name}"))?,
+            })
+        })
+        .collect()
+}
+
 /// Open a snapshot directory and validate it against the current VM config.
 /// Returns the shared memory fd (from memory.bin) and the saved device state.
 fn prepare_snapshot_restore(
@@ -2439,12 +2610,23 @@ fn prepare_snapshot_restore(
         opt.processors,
         system_page_size(),
     )?;
-
-    // Open memory.bin (existing file, no create, no resize).
-    let memory_file = fs_err::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(snapshot_dir.join("memory.bin"))?;
+    openvmm_helpers::snapshot::validate_disks(&manifest, &snapshot_disks(&configured_disks(opt))?)?;
+
+    let memory_file = if manifest.compressed_memory {
+        // Decompress the memory image into an unnamed temporary file so that
+        // the snapshot itself is never modified by the restored VM.
+        let mut file = tempfile::tempfile().context("failed to create guest memory file")?;
+        openvmm_helpers::snapshot::read_memory_image(snapshot_dir, &mut file)
+            .context("failed to read snapshot memory image")?;
+        file
+    } else {
+        // Open memory.bin (existing file, no create, no resize).
+        fs_err::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(snapshot_dir.join("memory.bin"))?
+            .into()
+    };
 
     // Validate file size matches expected memory size.
     let file_size = memory_file.metadata()?.len();
@@ -2455,8 +2637,7 @@ fn prepare_snapshot_restore(
         );
     }
 
-    let shared_memory_fd =
-        openvmm_helpers::shared_memory::file_to_shared_memory_fd(memory_file.into())?;
+    let shared_memory_fd = openvmm_helpers::shared_memory::file_to_shared_memory_fd(memory_file)?;
 
     // Reconstruct ProtobufMessage from the saved state bytes.
     // The save side wrote mesh::payload::encode(ProtobufMessage), so we decode
@@ -2478,7 +2659,11 @@ fn do_main(pidfile_guard: &mut Option<pidfile::Pidfile>) -> anyhow::Result<i32>
     // not return). Any worker host setup errors are return and bubbled up.
     meshworker::run_vmm_mesh_host()?;
 
-    let opt = cli_args::parse_options();
+    let opt = cli_args::parse_options()?;
+    if opt.dump_config {
+        print!("{}", cli_args::dump_config(&opt.vm_args)?);
+        return Ok(0);
+    }
     if let Some(path) = &opt.write_saved_state_proto {
         mesh::payload::protofile::DescriptorWriter::new(vmcore::save_restore::saved_state_roots())
             .write_to_path(path)
@@ -2495,6 +2680,28 @@ fn do_main(pidfile_guard: &mut Option<pidfile::Pidfile>) -> anyhow::Result<i32>
         return console_relay::relay_console(&path, console_title.as_str()).map(|()| 0);
     }
 
+    if let Some(path) = &opt.net_switch {
+        #[cfg(unix)]
+        {
+            let _ = std::fs::remove_file(path);
+            let listener = std::os::unix::net::UnixListener::bind(path)
+                .with_context(|| format!("failed to bind to {}", path.display()))?;
+            tracing::info!(path = %path.display(), "network switch listening");
+            return DefaultPool::run_with(async |driver| {
+                let mut switch =
+                    net_socket::switch::Switch::new(driver, socket2::Socket::from(listener))?;
+                switch.run().await?;
+                anyhow::Ok(0)
+            });
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+            bail!("--net-switch is only supported on Unix platforms")
+        }
+    }
+
     #[cfg(any(feature = "grpc", feature = "ttrpc"))]
     {
         let rpc = opt
@@ -2710,6 +2917,12 @@ async fn run_control_inner(
             (shared_memory, None)
         };
 
+        let incoming_migration = opt
+            .migrate_incoming
+            .as_ref()
+            .map(migration::accept)
+            .transpose()?;
+
         let params = VmWorkerParameters {
             hypervisor: match &opt.hypervisor {
                 Some(name) => openvmm_helpers::hypervisor::hypervisor_resource(name)?,
@@ -2718,6 +2931,7 @@ async fn run_control_inner(
             cfg: vm_config,
             saved_state,
             shared_memory,
+            incoming_migration,
             rpc: rpc_recv,
             notify: notify_send,
         };
@@ -2731,6 +2945,26 @@ async fn run_control_inner(
         tracing::info!("restoring VM from snapshot");
     }
 
+    if opt.migrate_incoming.is_some() {
+        tracing::info!("received VM from migration source");
+    }
+
+    // Redirect disk writes into the checkpoint tree before the guest runs.
+    let checkpoints = if let Some(dir) = &opt.checkpoint_dir {
+        Some(
+            checkpoint::Checkpoints::open(
+                dir,
+                resources.layered_disks.clone(),
+                opt.memory_backing_file()
+                    .cloned()
+                    .context("--checkpoint-dir requires a memory backing file")?,
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+
     if !opt.paused {
         vm_rpc.call(VmRpc::Resume, ()).await?;
     }
@@ -2773,6 +3007,9 @@ async fn run_control_inner(
         paravisor_diag: Some(paravisor_diag),
         igvm_path: opt.igvm.clone(),
         memory_backing_file: opt.memory_backing_file().cloned(),
+        vm_args: opt.vm_args.clone(),
+        configured_disks: configured_disks(&opt),
+        checkpoints,
         memory: opt.memory_size(),
         processors: opt.processors,
         log_file: opt.log_file.clone(),
@@ -2800,8 +3037,15 @@ async fn run_control_inner(
             scsi_rpc: resources.scsi_rpc,
             nvme_vtl2_rpc: resources.nvme_vtl2_rpc,
             consomme_rpc: resources.consomme_rpc,
+            balloon_rpc: resources.balloon_rpc,
+            net_impair: resources.net_impair,
+            packet_capture: resources.packet_capture,
+            layered_disks: resources.layered_disks,
             shutdown_ic: resources.shutdown_ic,
             kvp_ic: resources.kvp_ic,
+            heartbeat_ic: resources.heartbeat_ic,
+            vss_ic: resources.vss_ic,
+            fcopy_ic: resources.fcopy_ic,
             console_in: resources.console_in,
             has_vtl2,
         },
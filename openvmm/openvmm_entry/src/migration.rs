@@ -0,0 +1,54 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Transport setup for live migration streams.
+
+use crate::cleanup_socket;
+use crate::cli_args::MigrationAddress;
+use anyhow::Context;
+
+/// Listens on `addr` and waits for the migration source to connect.
+pub fn accept(addr: &MigrationAddress) -> anyhow::Result<socket2::Socket> {
+    tracing::info!(?addr, "waiting for incoming migration");
+    let socket = match addr {
+        MigrationAddress::Unix(path) => {
+            cleanup_socket(path);
+            let listener = unix_socket::UnixListener::bind(path)
+                .with_context(|| format!("failed to bind to {}", path.display()))?;
+            let (stream, _) = listener
+                .accept()
+                .context("failed to accept migration connection")?;
+            // The listener is no longer needed once the source has connected.
+            drop(listener);
+            let _ = std::fs::remove_file(path);
+            socket2::Socket::from(stream)
+        }
+        MigrationAddress::Tcp(addr) => {
+            let listener = std::net::TcpListener::bind(addr)
+                .with_context(|| format!("failed to bind to {addr}"))?;
+            let (stream, _) = listener
+                .accept()
+                .context("failed to accept migration connection")?;
+            stream.set_nodelay(true)?;
+            socket2::Socket::from(stream)
+        }
+    };
+    Ok(socket)
+}
+
+/// Connects to a migration destination listening on `addr`.
+pub fn connect(addr: &MigrationAddress) -> anyhow::Result<socket2::Socket> {
+    let socket = match addr {
+        MigrationAddress::Unix(path) => socket2::Socket::from(
+            unix_socket::UnixStream::connect(path)
+                .with_context(|| format!("failed to connect to {}", path.display()))?,
+        ),
+        MigrationAddress::Tcp(addr) => {
+            let stream = std::net::TcpStream::connect(addr)
+                .with_context(|| format!("failed to connect to {addr}"))?;
+            stream.set_nodelay(true)?;
+            socket2::Socket::from(stream)
+        }
+    };
+    Ok(socket)
+}
@@ -8,12 +8,17 @@
 //! directly. Commands that need exclusive resources (worker handles,
 //! DiagInspector, vtl2_settings) are dispatched via `Sender<VmControllerRpc>`.
 
+use crate::checkpoint::CheckpointInfo;
+use crate::cli_args::MigrationAddress;
 use crate::kvp;
+use crate::migration;
 use crate::storage_builder;
 use crate::vm_controller::AddVtl0ScsiDiskParams;
+use crate::vm_controller::CheckpointParams;
 use crate::vm_controller::InspectTarget;
 use crate::vm_controller::RemoveVtl0ScsiDiskByNvmeNsidParams;
 use crate::vm_controller::RemoveVtl0ScsiDiskParams;
+use crate::vm_controller::SaveSnapshotParams;
 use crate::vm_controller::ServiceVtl2Params;
 use crate::vm_controller::VmControllerEvent;
 use crate::vm_controller::VmControllerRpc;
@@ -22,6 +27,7 @@
 use clap::FromArgMatches;
 use clap::Parser;
 use console_relay::ConsoleLaunchOptions;
+use disk_backend_resources::LayeredDiskRequest;
 use disk_backend_resources::layer::RamDiskLayerHandle;
 use futures::AsyncWrite;
 use futures::AsyncWriteExt;
@@ -29,6 +35,9 @@
 use futures::StreamExt;
 use futures::executor::block_on;
 use futures_concurrency::stream::Merge;
+use hyperv_ic_resources::fcopy::FcopyRpc;
+use hyperv_ic_resources::heartbeat::HeartbeatStatus;
+use hyperv_ic_resources::vss::VssRpc;
 use inspect::InspectionBuilder;
 use mesh::CancelContext;
 use mesh::error::RemoteError;
@@ -39,9 +48,14 @@
 use net_backend_resources::consomme::HostPort;
 use net_backend_resources::consomme::HostPortConfig;
 use net_backend_resources::consomme::HostPortProtocol;
+use net_backend_resources::impair::ImpairRequest;
+use net_backend_resources::impair::ImpairmentConfig;
+use net_backend_resources::packet_capture::PacketCaptureRequest;
+use net_backend_resources::packet_capture::StartCapture;
 use nvme_resources::NamespaceDefinition;
 use nvme_resources::NvmeControllerRequest;
 use openvmm_defs::config::DeviceVtl;
+use openvmm_defs::rpc::MigrateOutParams;
 use openvmm_defs::rpc::PulseSaveRestoreError;
 use openvmm_defs::rpc::VmRpc;
 use pal_async::DefaultDriver;
@@ -51,6 +65,7 @@
 use pal_async::timer::PolledTimer;
 use scsidisk_resources::SimpleScsiDiskHandle;
 use scsidisk_resources::SimpleScsiDvdHandle;
+use std::collections::BTreeMap;
 use std::future::pending;
 use std::io;
 #[cfg(unix)]
@@ -65,6 +80,7 @@
 use storvsp_resources::ScsiDeviceAndPath;
 use storvsp_resources::ScsiPath;
 use tracing_helpers::AnyhowValueExt;
+use virtio_resources::balloon::BalloonRequest;
 use vm_resource::IntoResource;
 use vm_resource::Resource;
 
@@ -82,6 +98,14 @@ fn maybe_with_radix_u64(s: &str) -> Result<u64, String> {
     u64::from_str_radix(&s[prefix_len..], radix).map_err(|e| format!("{e}"))
 }
 
+fn parse_memory_size(s: &str) -> Result<u64, String> {
+    crate::cli_args::parse_memory(s).map_err(|e| format!("{e:#}"))
+}
+
+fn parse_impairment(s: &str) -> Result<ImpairmentConfig, String> {
+    crate::cli_args::parse_impairment(s)
+}
+
 #[derive(Parser)]
 #[clap(
     name = "openvmm",
@@ -114,6 +138,24 @@ enum InteractiveCommand {
     SaveSnapshot {
         /// Directory to write the snapshot to.
         dir: PathBuf,
+        /// Write a self-contained snapshot with a compressed copy of guest
+        /// RAM, which can be restored on another host. The VM stays paused,
+        /// but can be resumed once its disks have been copied.
+        #[clap(long)]
+        portable: bool,
+    },
+
+    /// Live migrate the VM to a destination started with
+    /// `--migrate-incoming`, then exit.
+    Migrate {
+        /// Destination address (`unix:<path>` or `tcp:<host>:<port>`).
+        addr: MigrationAddress,
+        /// Target maximum time the VM is stopped, in milliseconds.
+        #[clap(long, default_value = "300")]
+        max_downtime_ms: u64,
+        /// Maximum number of pre-copy passes before stopping the VM.
+        #[clap(long, default_value = "30")]
+        max_passes: u32,
     },
 
     /// Dump VM state (VP registers + memory) to a .vmrs file for WinDbg.
@@ -123,6 +165,33 @@ enum InteractiveCommand {
         path: PathBuf,
     },
 
+    /// Take a checkpoint of the VM and its disks (requires --checkpoint-dir).
+    #[clap(visible_alias = "cp")]
+    Checkpoint {
+        /// Name of the checkpoint.
+        name: Option<String>,
+        /// Description of the checkpoint.
+        #[clap(long, short)]
+        description: Option<String>,
+    },
+
+    /// List the checkpoints as a tree. The current checkpoint is marked `*`.
+    Checkpoints,
+
+    /// Revert the VM and its disks to a checkpoint, discarding all changes
+    /// since the last checkpoint.
+    Revert {
+        /// Name or ID of the checkpoint.
+        checkpoint: String,
+    },
+
+    /// Delete a checkpoint. Checkpoints with children, and the current
+    /// checkpoint and its ancestors, cannot be deleted.
+    DeleteCheckpoint {
+        /// Name or ID of the checkpoint.
+        checkpoint: String,
+    },
+
     /// Do a pulsed save restore (pause, save, reset, restore, resume) to the VM.
     #[clap(visible_alias = "psr")]
     PulseSaveRestore,
@@ -320,6 +389,50 @@ enum InteractiveCommand {
     /// Use KVP to interact with the guest.
     Kvp(kvp::KvpCommand),
 
+    /// Show the guest's health as reported by the heartbeat IC.
+    Heartbeat,
+
+    /// Ask the guest to flush and freeze its filesystems.
+    ///
+    /// Use this before `save-snapshot` or `snapshot-disk` to take an
+    /// application-consistent snapshot, then run `thaw-fs`. Requires the VSS
+    /// IC in the guest (`hv_vss_daemon` on Linux).
+    FreezeFs {
+        /// The timeout in seconds.
+        #[clap(long, default_value = "60")]
+        timeout: u64,
+    },
+
+    /// Thaw guest filesystems frozen by `freeze-fs`.
+    ThawFs {
+        /// The timeout in seconds.
+        #[clap(long, default_value = "60")]
+        timeout: u64,
+    },
+
+    /// Copy a host file into the guest.
+    ///
+    /// Requires the guest services IC in the guest (`hv_fcopy_daemon` or
+    /// `hv_fcopy_uio_daemon` on Linux).
+    CopyFile {
+        /// The host file to copy.
+        path: PathBuf,
+        /// The guest directory to copy the file into.
+        guest_dir: String,
+        /// The file name in the guest. Defaults to the host file name.
+        #[clap(long)]
+        name: Option<String>,
+        /// Replace the file if it already exists in the guest.
+        #[clap(long, short = 'f')]
+        overwrite: bool,
+        /// Create the guest directory if it does not exist.
+        #[clap(long, short = 'p')]
+        create_path: bool,
+        /// The timeout in seconds.
+        #[clap(long, default_value = "300")]
+        timeout: u64,
+    },
+
     /// Bind a host port to forward traffic to the guest (consomme).
     BindPort {
         /// The protocol to forward (tcp or udp).
@@ -339,6 +452,96 @@ enum InteractiveCommand {
         /// The guest port to unbind.
         guest_port: u16,
     },
+
+    /// Take a live snapshot of a disk by adding a new writable top layer.
+    ///
+    /// The VM is paused while the layer is added. Subsequent writes go to the
+    /// new layer, leaving the existing layers as a point-in-time snapshot.
+    SnapshotDisk {
+        /// The disk to snapshot (e.g. `scsi/0`, `nvme/1`, or
+        /// `<controller>/<lun>`).
+        disk: String,
+        /// Store the new layer in a new sqlite file, rather than in memory.
+        #[clap(long)]
+        sqlite: Option<PathBuf>,
+    },
+
+    /// Commit a disk's top layer into the layer below it, then remove it.
+    ///
+    /// The VM is paused while the layer is committed.
+    CommitDisk {
+        /// The disk whose top layer to commit.
+        disk: String,
+    },
+
+    /// Set the virtio-balloon target.
+    ///
+    /// The guest is asked to give this much memory (e.g. `512M`) back to the
+    /// host. Use `0` to deflate the balloon completely.
+    Balloon {
+        /// The target balloon size.
+        #[clap(value_parser = parse_memory_size)]
+        size: u64,
+    },
+
+    /// Show or change the network impairment of a NIC.
+    ///
+    /// Only NICs added with an `impair=` option can be impaired. Settings use
+    /// the same syntax as that option, e.g. `delay=50ms,loss=1%`; `none`
+    /// removes all impairment.
+    NetImpair {
+        /// The NIC index. Defaults to the first impaired NIC.
+        #[clap(long)]
+        nic: Option<usize>,
+        /// The new settings, replacing the current ones. If omitted, the
+        /// current settings are shown.
+        #[clap(value_parser = parse_impairment)]
+        settings: Option<ImpairmentConfig>,
+    },
+
+    /// Capture NIC traffic to pcapng files, or stream it live.
+    ///
+    /// NICs on netvsp and virtio-net frontends support capture.
+    #[clap(subcommand, visible_alias = "pcap")]
+    PacketCapture(PacketCaptureCommand),
+}
+
+/// Subcommands for packet capture.
+#[derive(clap::Subcommand)]
+enum PacketCaptureCommand {
+    /// Start capturing, replacing any capture in progress.
+    Start {
+        /// The NIC index. Defaults to all NICs that support capture.
+        #[clap(long)]
+        nic: Option<usize>,
+        /// Destination file path. The NIC index is appended to the file name.
+        #[clap(short('w'), default_value = "nic")]
+        output: PathBuf,
+        /// Length of the packet to capture.
+        #[clap(short('s'), long, default_value = "65535", value_parser = clap::value_parser!(u32).range(1..))]
+        snaplen: u32,
+        /// Only capture packets matching this filter, e.g.
+        /// `"tcp and port 443 and not host 10.0.0.1"`.
+        #[clap(short('f'), long)]
+        filter: Option<String>,
+        /// Switch to a new file for each NIC when the current one reaches this
+        /// many millions of bytes.
+        #[clap(short('C'), long, value_parser = clap::value_parser!(u64).range(1..))]
+        file_size: Option<u64>,
+        /// With `-C`, keep at most this many files for each NIC.
+        #[clap(short('W'), long, requires = "file_size", value_parser = clap::value_parser!(u32).range(1..))]
+        file_count: Option<u32>,
+        /// Stream the capture live to a FIFO or Unix socket instead of writing
+        /// files. The NIC index is appended to the path.
+        #[clap(long, conflicts_with_all = ["output", "file_size"])]
+        stream: Option<PathBuf>,
+    },
+    /// Stop capturing.
+    Stop {
+        /// The NIC index. Defaults to all NICs that support capture.
+        #[clap(long)]
+        nic: Option<usize>,
+    },
 }
 
 /// Subcommands for managing VTL2 settings.
@@ -434,8 +637,15 @@ pub(crate) struct ReplResources {
     pub scsi_rpc: Option<mesh::Sender<ScsiControllerRequest>>,
     pub nvme_vtl2_rpc: Option<mesh::Sender<NvmeControllerRequest>>,
     pub consomme_rpc: Option<mesh::Sender<ConsommeRequest>>,
+    pub balloon_rpc: Option<mesh::Sender<BalloonRequest>>,
+    pub net_impair: BTreeMap<usize, mesh::Sender<ImpairRequest>>,
+    pub packet_capture: BTreeMap<usize, mesh::Sender<PacketCaptureRequest>>,
+    pub layered_disks: BTreeMap<String, mesh::Sender<LayeredDiskRequest>>,
     pub shutdown_ic: Option<mesh::Sender<hyperv_ic_resources::shutdown::ShutdownRpc>>,
     pub kvp_ic: Option<mesh::Sender<hyperv_ic_resources::kvp::KvpConnectRpc>>,
+    pub heartbeat_ic: Option<mesh::Cell<HeartbeatStatus>>,
+    pub vss_ic: Option<mesh::Sender<VssRpc>>,
+    pub fcopy_ic: Option<mesh::Sender<FcopyRpc>>,
     pub console_in: Option<Box<dyn AsyncWrite + Send + Unpin>>,
     pub has_vtl2: bool,
 }
@@ -452,8 +662,15 @@ pub(crate) async fn run_repl(
         mut scsi_rpc,
         mut nvme_vtl2_rpc,
         consomme_rpc,
+        balloon_rpc,
+        net_impair,
+        packet_capture,
+        layered_disks,
         shutdown_ic,
         kvp_ic,
+        heartbeat_ic,
+        vss_ic,
+        fcopy_ic,
         console_in,
         has_vtl2,
     } = resources;
@@ -791,6 +1008,10 @@ enum Event {
                     VmControllerEvent::GuestHalt(reason) => {
                         tracing::info!(reason = reason.as_str(), "guest halted");
                     }
+                    VmControllerEvent::GuestPowerEvent { .. }
+                    | VmControllerEvent::GuestCrash { .. } => {
+                        // Already logged by the controller or dump handler.
+                    }
                     VmControllerEvent::ExitRequested { code } => break code,
                 }
                 continue;
@@ -863,16 +1084,26 @@ fn state_change<U: 'static + Send>(
                     StateChange::Reset,
                 );
             }
-            InteractiveCommand::SaveSnapshot { dir } => {
+            InteractiveCommand::SaveSnapshot { dir, portable } => {
                 match vm_controller
                     .call(
                         VmControllerRpc::SaveSnapshot,
-                        dir.to_string_lossy().into_owned(),
+                        SaveSnapshotParams {
+                            dir: dir.to_string_lossy().into_owned(),
+                            portable,
+                        },
                     )
                     .await
                     .map_err(anyhow::Error::from)
                     .and_then(|r| Ok(r?))
                 {
+                    Ok(()) if portable => {
+                        tracing::info!(
+                            dir = %dir.display(),
+                            "portable snapshot saved; VM is paused. \
+                             Copy the disks before resuming."
+                        );
+                    }
                     Ok(()) => {
                         snapshot_saved = true;
                         tracing::info!(
@@ -887,6 +1118,41 @@ fn state_change<U: 'static + Send>(
                     }
                 }
             }
+            InteractiveCommand::Migrate {
+                addr,
+                max_downtime_ms,
+                max_passes,
+            } => {
+                let result = async {
+                    let socket = migration::connect(&addr)?;
+                    let stats = vm_rpc
+                        .call_failable(
+                            VmRpc::MigrateOut,
+                            MigrateOutParams {
+                                socket,
+                                max_downtime_ms,
+                                max_passes,
+                            },
+                        )
+                        .await?;
+                    anyhow::Ok(stats)
+                }
+                .await;
+                match result {
+                    Ok(stats) => {
+                        println!(
+                            "migrated in {} ms ({} passes, {} pages, {} ms downtime)",
+                            stats.total_ms, stats.passes, stats.pages_sent, stats.downtime_ms
+                        );
+                        drop(scsi_rpc.take());
+                        drop(nvme_vtl2_rpc.take());
+                        vm_controller.send(VmControllerRpc::Quit);
+                    }
+                    Err(err) => {
+                        eprintln!("error: migrate failed: {err:#}");
+                    }
+                }
+            }
             InteractiveCommand::DumpState { path } => {
                 match vm_controller
                     .call(
@@ -908,6 +1174,56 @@ fn state_change<U: 'static + Send>(
                     }
                 }
             }
+            InteractiveCommand::Checkpoint { name, description } => {
+                match vm_controller
+                    .call(
+                        VmControllerRpc::Checkpoint,
+                        CheckpointParams {
+                            name: name.unwrap_or_default(),
+                            description: description.unwrap_or_default(),
+                        },
+                    )
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|r| Ok(r?))
+                {
+                    Ok(id) => println!("checkpoint {id} created"),
+                    Err(err) => eprintln!("error: checkpoint failed: {err:#}"),
+                }
+            }
+            InteractiveCommand::Checkpoints => {
+                match vm_controller
+                    .call(VmControllerRpc::ListCheckpoints, ())
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|r| Ok(r?))
+                {
+                    Ok(list) => print_checkpoints(&list),
+                    Err(err) => eprintln!("error: failed to list checkpoints: {err:#}"),
+                }
+            }
+            InteractiveCommand::Revert { checkpoint } => {
+                match vm_controller
+                    .call(VmControllerRpc::RevertCheckpoint, checkpoint)
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|r| Ok(r?))
+                {
+                    Ok(id) => println!("reverted to checkpoint {id}"),
+                    Err(err) => eprintln!("error: revert failed: {err:#}"),
+                }
+            }
+            InteractiveCommand::DeleteCheckpoint { checkpoint } => {
+                match vm_controller
+                    .call(VmControllerRpc::DeleteCheckpoint, checkpoint)
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|r| Ok(r?))
+                {
+                    Ok(id) => println!("checkpoint {id} deleted"),
+                    Err(err) => eprintln!("error: delete-checkpoint failed: {err:#}"),
+                }
+            }
             InteractiveCommand::PulseSaveRestore => {
                 state_change(
                     driver,
@@ -1517,6 +1833,62 @@ fn state_change<U: 'static + Send>(
                     eprintln!("error: {err:#}");
                 }
             }
+            InteractiveCommand::Heartbeat => match &heartbeat_ic {
+                Some(status) => println!("{:?}", status.get()),
+                None => eprintln!("error: no heartbeat ic configured"),
+            },
+            InteractiveCommand::FreezeFs { timeout } | InteractiveCommand::ThawFs { timeout } => {
+                let freeze = matches!(cmd, InteractiveCommand::FreezeFs { .. });
+                let action = async {
+                    let vss = vss_ic.as_ref().context("no vss ic configured")?;
+                    let request = if freeze { VssRpc::Freeze } else { VssRpc::Thaw };
+                    CancelContext::new()
+                        .with_timeout(Duration::from_secs(timeout))
+                        .until_cancelled(vss.call_failable(request, ()))
+                        .await??;
+                    anyhow::Ok(())
+                };
+                match action.await {
+                    Ok(()) if freeze => tracing::info!("guest filesystems frozen"),
+                    Ok(()) => tracing::info!("guest filesystems thawed"),
+                    Err(error) => {
+                        tracing::error!(
+                            error = error.as_error(),
+                            "error {} guest filesystems",
+                            if freeze { "freezing" } else { "thawing" }
+                        );
+                    }
+                }
+            }
+            InteractiveCommand::CopyFile {
+                path,
+                guest_dir,
+                name,
+                overwrite,
+                create_path,
+                timeout,
+            } => {
+                let action = async {
+                    let fcopy = fcopy_ic
+                        .as_ref()
+                        .context("no guest services ic configured")?;
+                    let params =
+                        crate::copy_file_params(&path, guest_dir, name, overwrite, create_path)?;
+                    CancelContext::new()
+                        .with_timeout(Duration::from_secs(timeout))
+                        .until_cancelled(fcopy.call_failable(FcopyRpc::CopyFile, params))
+                        .await??;
+                    anyhow::Ok(())
+                };
+                match action.await {
+                    Ok(()) => {
+                        tracing::info!(path = %path.display(), "file copied to guest");
+                    }
+                    Err(error) => {
+                        tracing::error!(error = error.as_error(), "error copying file to guest");
+                    }
+                }
+            }
             InteractiveCommand::BindPort {
                 protocol,
                 host_port,
@@ -1566,6 +1938,154 @@ fn state_change<U: 'static + Send>(
                     }
                 }
             }
+            InteractiveCommand::SnapshotDisk { disk, sqlite } => {
+                match snapshot_disk(&vm_rpc, &layered_disks, &disk, sqlite.as_deref()).await {
+                    Ok(()) => {
+                        tracing::info!(disk, "disk snapshot taken");
+                    }
+                    Err(error) => {
+                        tracing::error!(error = error.as_error(), "error taking disk snapshot");
+                    }
+                }
+            }
+            InteractiveCommand::CommitDisk { disk } => {
+                match commit_disk(&vm_rpc, &layered_disks, &disk).await {
+                    Ok(()) => {
+                        tracing::info!(disk, "disk layer committed");
+                    }
+                    Err(error) => {
+                        tracing::error!(error = error.as_error(), "error committing disk layer");
+                    }
+                }
+            }
+            InteractiveCommand::Balloon { size } => {
+                let action = async {
+                    let rpc = balloon_rpc.as_ref().context("no virtio-balloon device")?;
+                    rpc.call(BalloonRequest::SetTarget, size).await?;
+                    anyhow::Ok(())
+                };
+                match action.await {
+                    Ok(()) => {
+                        tracing::info!(size, "balloon target set");
+                    }
+                    Err(error) => {
+                        tracing::error!(error = error.as_error(), "error setting balloon target");
+                    }
+                }
+            }
+            InteractiveCommand::NetImpair { nic, settings } => {
+                let action = async {
+                    let (nic, rpc) = match nic {
+                        Some(nic) => (
+                            nic,
+                            net_impair
+                                .get(&nic)
+                                .with_context(|| format!("NIC {nic} is not impaired"))?,
+                        ),
+                        None => net_impair
+                            .iter()
+                            .next()
+                            .map(|(&nic, rpc)| (nic, rpc))
+                            .context("no impaired NICs")?,
+                    };
+                    match settings {
+                        Some(config) => {
+                            rpc.call_failable(ImpairRequest::Set, config).await?;
+                            println!("NIC {nic}: impairment updated");
+                        }
+                        None => {
+                            let config = rpc.call(ImpairRequest::Get, ()).await?;
+                            println!("NIC {nic}: {config:#?}");
+                        }
+                    }
+                    anyhow::Ok(())
+                };
+                if let Err(error) = action.await {
+                    tracing::error!(error = error.as_error(), "network impairment failed");
+                }
+            }
+            InteractiveCommand::PacketCapture(command) => {
+                let action = async {
+                    let nics = |nic: Option<usize>| match nic {
+                        Some(nic) => packet_capture
+                            .get(&nic)
+                            .map(|rpc| vec![(nic, rpc)])
+                            .with_context(|| format!("NIC {nic} does not support packet capture")),
+                        None if packet_capture.is_empty() => {
+                            anyhow::bail!("no NICs support packet capture")
+                        }
+                        None => Ok(packet_capture
+                            .iter()
+                            .map(|(&nic, rpc)| (nic, rpc))
+                            .collect()),
+                    };
+                    match command {
+                        PacketCaptureCommand::Start {
+                            nic,
+                            output,
+                            snaplen,
+                            filter,
+                            file_size,
+                            file_count,
+                            stream,
+                        } => {
+                            let output = match stream {
+                                Some(path) => pcapng_sink::Output::Live { path },
+                                None => pcapng_sink::Output::File {
+                                    path: output,
+                                    rotation: file_size.map(|size| pcapng_sink::Rotation {
+                                        max_file_size: size * 1_000_000,
+                                        max_files: file_count.unwrap_or(0),
+                                    }),
+                                },
+                            };
+                            for (nic, rpc) in nics(nic)? {
+                                let writer = start_capture_output(&output, nic).await?;
+                                let start = rpc.call_failable(
+                                    PacketCaptureRequest::Start,
+                                    StartCapture {
+                                        snaplen,
+                                        filter: filter.clone(),
+                                        writer,
+                                    },
+                                );
+                                let path = output.path(nic);
+                                match CancelContext::new()
+                                    .with_timeout(Duration::from_secs(5))
+                                    .until_cancelled(start)
+                                    .await
+                                {
+                                    Ok(r) => {
+                                        r?;
+                                        println!("NIC {nic}: capturing to {}", path.display());
+                                    }
+                                    // The frontend handles the request once the
+                                    // guest enables the NIC.
+                                    Err(_) => println!(
+                                        "NIC {nic}: capture to {} starts when the NIC is enabled",
+                                        path.display()
+                                    ),
+                                }
+                            }
+                        }
+                        PacketCaptureCommand::Stop { nic } => {
+                            for (nic, rpc) in nics(nic)? {
+                                let stop = rpc.call_failable(PacketCaptureRequest::Stop, ());
+                                CancelContext::new()
+                                    .with_timeout(Duration::from_secs(5))
+                                    .until_cancelled(stop)
+                                    .await
+                                    .with_context(|| format!("NIC {nic} did not respond"))??;
+                                println!("NIC {nic}: capture stopped");
+                            }
+                        }
+                    }
+                    anyhow::Ok(())
+                };
+                if let Err(error) = action.await {
+                    tracing::error!(error = error.as_error(), "packet capture failed");
+                }
+            }
             InteractiveCommand::Input { .. } | InteractiveCommand::InputMode => unreachable!(),
         }
     };
@@ -1573,6 +2093,150 @@ fn state_change<U: 'static + Send>(
     Ok(exit_request)
 }
 
+/// Opens `output` for NIC `nic` and starts a thread copying a capture stream
+/// to it, returning the writer for the capture endpoint.
+///
+/// Live outputs wait for a reader to attach before this returns.
+async fn start_capture_output(
+    output: &pcapng_sink::Output,
+    nic: usize,
+) -> anyhow::Result<std::fs::File> {
+    let (mut reader, writer) = io::pipe()?;
+    let (opened_send, opened_recv) = futures::channel::oneshot::channel();
+    if let pcapng_sink::Output::Live { .. } = output {
+        println!(
+            "NIC {nic}: waiting for a reader on {}",
+            output.path(nic).display()
+        );
+    }
+    let thread_output = output.clone();
+    thread::Builder::new()
+        .name(format!("packet-capture-{nic}"))
+        .spawn(move || {
+            let mut sink = match thread_output.open(nic) {
+                Ok(sink) => sink,
+                Err(err) => {
+                    let _ = opened_send.send(Err(err));
+                    return;
+                }
+            };
+            let _ = opened_send.send(Ok(()));
+            // Runs until the capture stops and closes the pipe.
+            if let Err(err) = io::copy(&mut reader, &mut sink) {
+                tracing::error!(
+                    nic,
+                    error = &err as &dyn std::error::Error,
+                    "packet capture write failed"
+                );
+            }
+        })?;
+    opened_recv
+        .await?
+        .with_context(|| format!("failed to open {}", output.path(nic).display()))?;
+
+    #[cfg(unix)]
+    let writer = std::os::fd::OwnedFd::from(writer).into();
+    #[cfg(windows)]
+    let writer = std::os::windows::io::OwnedHandle::from(writer).into();
+    Ok(writer)
+}
+
+/// Looks up the request channel for a disk that supports live snapshots.
+fn layered_disk<'a>(
+    layered_disks: &'a BTreeMap<String, mesh::Sender<LayeredDiskRequest>>,
+    name: &str,
+) -> anyhow::Result<&'a mesh::Sender<LayeredDiskRequest>> {
+    layered_disks.get(name).with_context(|| {
+        let names = layered_disks.keys().map(String::as_str).collect::<Vec<_>>();
+        format!(
+            "unknown disk '{name}' (disks supporting snapshots: {})",
+            names.join(", ")
+        )
+    })
+}
+
+/// Takes a live snapshot of the named disk by adding a new top layer, stored
+/// in memory or in a new sqlite file, with the VM paused.
+async fn snapshot_disk(
+    vm_rpc: &mesh::Sender<VmRpc>,
+    layered_disks: &BTreeMap<String, mesh::Sender<LayeredDiskRequest>>,
+    name: &str,
+    sqlite: Option<&std::path::Path>,
+) -> anyhow::Result<()> {
+    let rpc = layered_disk(layered_disks, name)?;
+    let layer = crate::snapshot_layer(sqlite)?;
+    crate::with_vm_paused(vm_rpc, async {
+        rpc.call_failable(LayeredDiskRequest::AddTopLayer, layer)
+            .await?;
+        anyhow::Ok(())
+    })
+    .await
+}
+
+/// Commits the top layer of the named disk into the layer below it, with the
+/// VM paused.
+async fn commit_disk(
+    vm_rpc: &mesh::Sender<VmRpc>,
+    layered_disks: &BTreeMap<String, mesh::Sender<LayeredDiskRequest>>,
+    name: &str,
+) -> anyhow::Result<()> {
+    let rpc = layered_disk(layered_disks, name)?;
+    crate::with_vm_paused(vm_rpc, async {
+        rpc.call_failable(LayeredDiskRequest::CommitTopLayer, ())
+            .await?;
+        anyhow::Ok(())
+    })
+    .await
+}
+
+/// Prints checkpoints as a tree, children indented under their parents.
+fn print_checkpoints(list: &[CheckpointInfo]) {
+    fn print(list: &[CheckpointInfo], entry: &CheckpointInfo, depth: usize, now: i64) {
+        let mut line = format!(
+            "{} {}{}",
+            if entry.current { '*' } else { ' ' },
+            "  ".repeat(depth),
+            entry.id
+        );
+        if !entry.name.is_empty() {
+            line += &format!(" {}", entry.name);
+        }
+        line += &format!(" ({} ago)", format_age(now - entry.created));
+        if !entry.description.is_empty() {
+            line += &format!(" - {}", entry.description);
+        }
+        println!("{line}");
+        for child in list.iter().filter(|c| c.parent == entry.id) {
+            print(list, child, depth + 1, now);
+        }
+    }
+
+    if list.is_empty() {
+        println!("no checkpoints");
+        return;
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    for root in list
+        .iter()
+        .filter(|e| !list.iter().any(|p| p.id == e.parent))
+    {
+        print(list, root, 0, now);
+    }
+}
+
+/// Formats an age in seconds with a single unit, such as `5m`.
+fn format_age(secs: i64) -> String {
+    let secs = secs.max(0);
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
 // -- Rustyline helpers --
 
 use rustyline::Helper;
@@ -1720,3 +2384,122 @@ async fn complete(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pal_async::DefaultPool;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// Serves pause and resume requests, recording them in `log`.
+    async fn fake_vm(mut recv: mesh::Receiver<VmRpc>, log: Arc<Mutex<Vec<&'static str>>>) {
+        while let Some(rpc) = recv.next().await {
+            match rpc {
+                VmRpc::Pause(rpc) => {
+                    log.lock().push("pause");
+                    rpc.complete(true)
+                }
+                VmRpc::Resume(rpc) => {
+                    log.lock().push("resume");
+                    rpc.complete(true)
+                }
+                _ => panic!("unexpected vm rpc"),
+            }
+        }
+    }
+
+    /// Serves layer requests for a disk with a single layer, recording them
+    /// in `log`.
+    async fn fake_layered_disk(
+        mut recv: mesh::Receiver<LayeredDiskRequest>,
+        log: Arc<Mutex<Vec<&'static str>>>,
+    ) {
+        let mut layers = 1;
+        while let Some(req) = recv.next().await {
+            match req {
+                LayeredDiskRequest::AddTopLayer(rpc) => rpc.handle_failable_sync(|_| {
+                    log.lock().push("add");
+                    layers += 1;
+                    anyhow::Ok(())
+                }),
+                LayeredDiskRequest::CommitTopLayer(rpc) => rpc.handle_failable_sync(|()| {
+                    if layers == 1 {
+                        anyhow::bail!("no layer to commit");
+                    }
+                    log.lock().push("commit");
+                    layers -= 1;
+                    Ok(())
+                }),
+                LayeredDiskRequest::ReplaceTopLayers(_) => panic!("unexpected layer request"),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_disk_commands() {
+        let mut parser = CommandParser::new();
+        let InteractiveCommand::SnapshotDisk { disk, sqlite } =
+            parser.parse("snapshot-disk scsi/0").unwrap()
+        else {
+            panic!("expected snapshot-disk");
+        };
+        assert_eq!(disk, "scsi/0");
+        assert!(sqlite.is_none());
+
+        let InteractiveCommand::SnapshotDisk { disk, sqlite } = parser
+            .parse("snapshot-disk nvme0/1 --sqlite snap.dbhd")
+            .unwrap()
+        else {
+            panic!("expected snapshot-disk");
+        };
+        assert_eq!(disk, "nvme0/1");
+        assert_eq!(sqlite.as_deref(), Some(std::path::Path::new("snap.dbhd")));
+
+        let InteractiveCommand::CommitDisk { disk } = parser.parse("commit-disk scsi/0").unwrap()
+        else {
+            panic!("expected commit-disk");
+        };
+        assert_eq!(disk, "scsi/0");
+    }
+
+    #[test]
+    fn snapshot_and_commit_disk() {
+        DefaultPool::run_with(async |driver| {
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let (vm_rpc, recv) = mesh::channel();
+            let _vm = driver.spawn("fake-vm", fake_vm(recv, log.clone()));
+            let (disk_rpc, recv) = mesh::channel();
+            let _disk = driver.spawn("fake-disk", fake_layered_disk(recv, log.clone()));
+            let layered_disks = BTreeMap::from([("scsi/0".to_string(), disk_rpc)]);
+
+            snapshot_disk(&vm_rpc, &layered_disks, "scsi/0", None)
+                .await
+                .unwrap();
+            commit_disk(&vm_rpc, &layered_disks, "scsi/0")
+                .await
+                .unwrap();
+            // The layer requests are made with the VM paused.
+            assert_eq!(
+                *log.lock(),
+                ["pause", "add", "resume", "pause", "commit", "resume"]
+            );
+
+            // The VM is resumed even if the request fails.
+            log.lock().clear();
+            let err = commit_disk(&vm_rpc, &layered_disks, "scsi/0")
+                .await
+                .unwrap_err();
+            assert!(format!("{err:#}").contains("no layer to commit"));
+            assert_eq!(*log.lock(), ["pause", "resume"]);
+
+            // An unknown disk fails without pausing the VM.
+            log.lock().clear();
+            let err = snapshot_disk(&vm_rpc, &layered_disks, "scsi/1", None)
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("scsi/0"), "{err}");
+            assert!(log.lock().is_empty());
+        })
+    }
+}
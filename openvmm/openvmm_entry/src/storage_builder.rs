@@ -6,8 +6,8 @@
 use crate::VmResources;
 use crate::cli_args::DiskCliKind;
 use crate::cli_args::UnderhillDiskSource;
-use crate::disk_open;
 use anyhow::Context;
+use disk_backend_resources::LayeredDiskRequest;
 use guid::Guid;
 use ide_resources::GuestMedia;
 use ide_resources::IdeDeviceConfig;
@@ -20,6 +20,8 @@
 use openvmm_defs::config::LoadMode;
 use openvmm_defs::config::PcieDeviceConfig;
 use openvmm_defs::config::VpciDeviceConfig;
+use openvmm_helpers::disk_cli::open_disk;
+use openvmm_helpers::disk_cli::open_disk_with_layer_control;
 use scsidisk_resources::SimpleScsiDiskHandle;
 use scsidisk_resources::SimpleScsiDvdHandle;
 use std::collections::BTreeMap;
@@ -134,6 +136,10 @@ pub(super) struct StorageBuilder {
     underhill_nvme_luns: Vec<Lun>,
     vtl0_virtio_blk_disks: Vec<VirtioBlkDisk>,
     openhcl_vtl: Option<DeviceVtl>,
+    /// Runtime request channels for disks that support live snapshots.
+    layered_disks: BTreeMap<String, mesh::Sender<LayeredDiskRequest>>,
+    /// Whether all hard disks support live snapshots, as checkpoints require.
+    snapshot_all_disks: bool,
 }
 
 struct VirtioBlkDisk {
@@ -184,7 +190,7 @@ fn from(value: UnderhillDiskSource) -> Self {
 const VIRTIO_BLK_INSTANCE_ID_TEMPLATE: Guid = guid::guid!("00000000-a4e7-4b53-b702-1f42d938647e");
 
 impl StorageBuilder {
-    pub fn new(openhcl_vtl: Option<DeviceVtl>) -> Self {
+    pub fn new(openhcl_vtl: Option<DeviceVtl>, snapshot_all_disks: bool) -> Self {
         Self {
             vtl0_ide_disks: Vec::new(),
             storvsp_ide_handles: Vec::new(),
@@ -199,6 +205,8 @@ pub fn new(openhcl_vtl: Option<DeviceVtl>) -> Self {
             underhill_nvme_luns: Vec::new(),
             vtl0_virtio_blk_disks: Vec::new(),
             openhcl_vtl,
+            layered_disks: BTreeMap::new(),
+            snapshot_all_disks,
         }
     }
 
@@ -304,18 +312,20 @@ pub async fn add(
         kind: &DiskCliKind,
         is_dvd: bool,
         read_only: bool,
+        snapshot: bool,
     ) -> anyhow::Result<()> {
         if let Some(source) = underhill {
             if vtl != DeviceVtl::Vtl0 {
                 anyhow::bail!("OpenHCL relay can only offer devices to VTL0");
             }
-            self.add_underhill(source.into(), target, kind, is_dvd, read_only)
+            self.add_underhill(source.into(), target, kind, is_dvd, read_only, snapshot)
                 .await?;
         } else if let Some(relay) = relay {
-            self.add_relay(relay, target, kind, is_dvd, read_only)
+            self.add_relay(relay, target, kind, is_dvd, read_only, snapshot)
                 .await?;
         } else {
-            self.add_inner(vtl, target, kind, is_dvd, read_only).await?;
+            self.add_inner(vtl, target, kind, is_dvd, read_only, snapshot)
+                .await?;
         }
         Ok(())
     }
@@ -329,9 +339,28 @@ async fn add_inner(
         kind: &DiskCliKind,
         is_dvd: bool,
         read_only: bool,
+        snapshot: bool,
     ) -> anyhow::Result<Option<u32>> {
-        let disk = disk_open(kind, read_only || is_dvd).await?;
-        let location = match target {
+        // Hard disks support live snapshots if requested, if checkpoints
+        // require them, or if they are layered disks anyway. This is not
+        // supported on IDE, where the disk is opened a second time for the
+        // storvsp accelerator channel, or for persistent reservation disks,
+        // since a layered disk does not pass reservations through.
+        let snapshot_supported = !is_dvd
+            && !matches!(target, DiskLocation::Ide(..))
+            && !matches!(kind, DiskCliKind::PersistentReservationsWrapper(_));
+        if snapshot && !snapshot_supported {
+            anyhow::bail!(
+                "snapshots are not supported for IDE disks, DVDs, or persistent reservation disks"
+            );
+        }
+        let (disk, layer_control) = if snapshot_supported {
+            open_disk_with_layer_control(kind, read_only, snapshot || self.snapshot_all_disks)
+                .await?
+        } else {
+            (open_disk(kind, read_only || is_dvd).await?, None)
+        };
+        let location = match target.clone() {
             DiskLocation::Ide(channel, device) => {
                 let guest_media = if is_dvd {
                     GuestMedia::Dvd(
@@ -378,7 +407,7 @@ async fn add_inner(
                 // drive itself is built in openvmm_core's worker. OpenVMM has no CLI
                 // surface for per-disk SCSI parameters, so they are left as Default here.
                 if !is_dvd {
-                    let storvsp_disk = disk_open(kind, read_only).await?;
+                    let storvsp_disk = open_disk(kind, read_only).await?;
                     self.storvsp_ide_handles.push((
                         DeviceVtl::Vtl0,
                         StorvspIdeDeviceHandle {
@@ -522,6 +551,20 @@ async fn add_inner(
                 None
             }
         };
+        if let Some(send) = layer_control {
+            let vtl_prefix = if vtl == DeviceVtl::Vtl2 { "vtl2-" } else { "" };
+            let name = match (&target, location) {
+                (DiskLocation::Scsi(_), Some(lun)) => format!("{vtl_prefix}scsi/{lun}"),
+                (DiskLocation::Nvme(_), Some(nsid)) => format!("{vtl_prefix}nvme/{nsid}"),
+                (DiskLocation::Named { controller, .. }, Some(n)) => format!("{controller}/{n}"),
+                (DiskLocation::VirtioBlk(Some(port)), _) => format!("virtio-blk/{port}"),
+                (DiskLocation::VirtioBlk(None), _) => {
+                    format!("virtio-blk/{}", self.vtl0_virtio_blk_disks.len() - 1)
+                }
+                (DiskLocation::Ide(..), _) | (_, None) => unreachable!(),
+            };
+            self.layered_disks.insert(name, send);
+        }
         Ok(location)
     }
 
@@ -535,6 +578,7 @@ async fn add_relay(
         kind: &DiskCliKind,
         is_dvd: bool,
         read_only: bool,
+        snapshot: bool,
     ) -> anyhow::Result<()> {
         // Look up the source controller to determine VTL and instance ID.
         let (source_vtl, device_type, device_path) = match &target {
@@ -578,7 +622,7 @@ async fn add_relay(
         }
 
         let sub_device_path = self
-            .add_inner(source_vtl, target, kind, is_dvd, read_only)
+            .add_inner(source_vtl, target, kind, is_dvd, read_only, snapshot)
             .await?
             .context("source device not supported by relay")?;
 
@@ -629,10 +673,11 @@ async fn add_underhill(
         kind: &DiskCliKind,
         is_dvd: bool,
         read_only: bool,
+        snapshot: bool,
     ) -> anyhow::Result<()> {
         let vtl = self.openhcl_vtl.context("OpenHCL not configured")?;
         let sub_device_path = self
-            .add_inner(vtl, source.clone(), kind, is_dvd, read_only)
+            .add_inner(vtl, source.clone(), kind, is_dvd, read_only, snapshot)
             .await?
             .context("source device not supported by OpenHCL")?;
 
@@ -712,6 +757,7 @@ pub fn build_config(
         resources: &mut VmResources,
         scsi_sub_channels: u16,
     ) -> anyhow::Result<()> {
+        resources.layered_disks.append(&mut self.layered_disks);
         config.ide_disks.append(&mut self.vtl0_ide_disks);
         if !self.storvsp_ide_handles.is_empty() {
             anyhow::ensure!(
@@ -880,6 +926,15 @@ pub fn build_config(
             resources.nvme_vtl2_rpc = Some(send);
         }
 
+        // Give each virtio-blk device one request queue per VP.
+        let virtio_blk_queues = Some(
+            config
+                .processor_topology
+                .proc_count
+                .try_into()
+                .unwrap_or(u16::MAX),
+        );
+
         for (i, vblk) in std::mem::take(&mut self.vtl0_virtio_blk_disks)
             .into_iter()
             .enumerate()
@@ -893,6 +948,7 @@ pub fn build_config(
                     VirtioBlkHandle {
                         disk: vblk.disk,
                         read_only: vblk.read_only,
+                        num_queues: virtio_blk_queues,
                     }
                     .into_resource(),
                 )
@@ -908,6 +964,7 @@ pub fn build_config(
                     VirtioBlkHandle {
                         disk: vblk.disk,
                         read_only: vblk.read_only,
+                        num_queues: virtio_blk_queues,
                     }
                     .into_resource(),
                 )
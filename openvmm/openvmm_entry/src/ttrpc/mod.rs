@@ -19,20 +19,39 @@
 #[derive(Clone, Default)]
 struct FdRegistry {}
 
+use crate::checkpoint::Checkpoints;
 use crate::meshworker::VmmMesh;
 use crate::serial_io::bind_serial;
 use crate::serial_io::connect_serial;
+use crate::vm_controller::CheckpointParams;
 use crate::vm_controller::GuestPowerActions;
+use crate::vm_controller::GuestPowerEventKind;
 use crate::vm_controller::InspectTarget;
+use crate::vm_controller::SaveSnapshotParams;
 use crate::vm_controller::VmController;
 use crate::vm_controller::VmControllerEvent;
 use crate::vm_controller::VmControllerRpc;
 use anyhow::Context;
 use anyhow::anyhow;
 use anyhow::bail;
+use disk_backend_resources::DiskLayerDescription;
+use disk_backend_resources::LayeredDiskHandle;
+use disk_backend_resources::LayeredDiskRequest;
+use disk_backend_resources::layer::DiskLayerHandle;
 use futures::FutureExt;
 use futures::StreamExt;
 use guid::Guid;
+use hyperv_ic_resources::fcopy::FcopyIcHandle;
+use hyperv_ic_resources::fcopy::FcopyRpc;
+use hyperv_ic_resources::heartbeat::HeartbeatIcHandle;
+use hyperv_ic_resources::heartbeat::HeartbeatStatus;
+use hyperv_ic_resources::shutdown::ShutdownIcHandle;
+use hyperv_ic_resources::shutdown::ShutdownParams;
+use hyperv_ic_resources::shutdown::ShutdownResult;
+use hyperv_ic_resources::shutdown::ShutdownRpc;
+use hyperv_ic_resources::shutdown::ShutdownType;
+use hyperv_ic_resources::vss::VssIcHandle;
+use hyperv_ic_resources::vss::VssRpc;
 use inspect::InspectionBuilder;
 use inspect_proto::InspectResponse2;
 use inspect_proto::InspectService;
@@ -41,6 +60,8 @@ struct FdRegistry {}
 use mesh::CancelReason;
 use mesh::MeshPayload;
 use mesh::error::RemoteError;
+use mesh::rpc::FailableRpc;
+use mesh::rpc::PendingFailableRpc;
 use mesh::rpc::RpcSend;
 use mesh_rpc::service::Code;
 use mesh_rpc::service::Status;
@@ -81,9 +102,13 @@ struct FdRegistry {}
 use pal_async::DefaultPool;
 use pal_async::task::Spawn;
 use pal_async::task::Task;
+use parking_lot::Mutex;
 use scsidisk_resources::SimpleScsiDiskHandle;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
 use std::fs::File;
 use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use storvsp_resources::ScsiControllerHandle;
@@ -319,6 +344,7 @@ fn run(self, recv: mesh::Receiver<WorkerRpc<Self::State>>) -> anyhow::Result<()>
                 wait_vm_response: None,
                 lifecycle: VmLifecycle::Uninitialized,
                 rpc_tasks: Vec::new(),
+                events: EventFeed::default(),
                 transport: self.transport,
                 registry: FdRegistry::default(),
             };
@@ -495,6 +521,12 @@ struct Vm {
     worker_rpc: mesh::Sender<VmRpc>,
     scsi_rpc: Option<mesh::Sender<ScsiControllerRequest>>,
     consomme_rpc: Option<mesh::Sender<ConsommeRequest>>,
+    /// Runtime layer request channels for the SCSI disks, keyed by LUN.
+    layered_disks: Mutex<BTreeMap<u8, mesh::Sender<LayeredDiskRequest>>>,
+    heartbeat_ic: mesh::Cell<HeartbeatStatus>,
+    vss_ic: mesh::Sender<VssRpc>,
+    fcopy_ic: mesh::Sender<FcopyRpc>,
+    shutdown_ic: mesh::Sender<ShutdownRpc>,
 }
 
 enum VmLifecycle {
@@ -524,6 +556,7 @@ struct VmService {
     wait_vm_response: Option<(mesh::CancelContext, mesh::OneshotSender<Result<(), Status>>)>,
     lifecycle: VmLifecycle,
     rpc_tasks: Vec<Task<()>>,
+    events: EventFeed,
     transport: ResolvedTransport,
     /// Registry of file descriptors passed in over the fd-passing protocol,
     /// resolvable by name (e.g. for tap NIC backends).
@@ -553,6 +586,70 @@ fn map_grpc<T>(r: anyhow::Result<T>) -> Result<T, Status> {
     r.map_err(grpc_error)
 }
 
+/// The number of events kept for `WaitVMEvent`.
+const MAX_EVENT_HISTORY: usize = 256;
+
+/// The VM event feed served by `WaitVMEvent`.
+#[derive(Default)]
+struct EventFeed {
+    /// The most recent events, oldest first.
+    events: VecDeque<vmservice::VmEvent>,
+    /// The sequence number of the most recent event.
+    sequence: u64,
+    /// `WaitVMEvent` calls waiting for the next event.
+    waiters: Vec<mesh::OneshotSender<vmservice::VmEvent>>,
+}
+
+impl EventFeed {
+    /// Returns the oldest event after `after_sequence`, or the next event if
+    /// there is none yet.
+    fn wait(&mut self, after_sequence: u64) -> mesh::OneshotReceiver<vmservice::VmEvent> {
+        let (send, recv) = mesh::oneshot();
+        match self
+            .events
+            .iter()
+            .find(|event| event.sequence > after_sequence)
+        {
+            Some(event) => send.send(event.clone()),
+            None => {
+                // Drop the waiters of cancelled calls so that clients that
+                // repeatedly time out do not accumulate.
+                self.waiters.retain(|waiter| !waiter.is_closed());
+                self.waiters.push(send);
+            }
+        }
+        recv
+    }
+
+    /// Records an event, assigning it the next sequence number, and completes
+    /// the waiting calls.
+    fn push(
+        &mut self,
+        event_type: vmservice::VmEventType,
+        timestamp: i64,
+        vp: Option<u32>,
+        reset: bool,
+        detail: String,
+    ) {
+        self.sequence += 1;
+        let event = vmservice::VmEvent {
+            sequence: self.sequence,
+            r#type: event_type as i32,
+            timestamp,
+            vp,
+            reset,
+            detail,
+        };
+        for waiter in self.waiters.drain(..) {
+            waiter.send(event.clone());
+        }
+        if self.events.len() == MAX_EVENT_HISTORY {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
 enum HandleAction {
     None,
     Quit,
@@ -619,6 +716,113 @@ async fn handle(&mut self, ctx: mesh::CancelContext, request: vmservice::Vm) ->
                 let r = self.remove_pcie_device(request);
                 self.start_rpc(response, r);
             }
+            vmservice::Vm::SnapshotDisk(request, response) => {
+                let r = self.snapshot_disk(request);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::CommitDisk(request, response) => {
+                let r = self.commit_disk(request);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::GuestHeartbeat((), response) => {
+                response.send(map_grpc(self.guest_heartbeat()));
+            }
+            vmservice::Vm::FreezeGuestFilesystems((), response) => {
+                let r = self.vss_request(VssRpc::Freeze);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::ThawGuestFilesystems((), response) => {
+                let r = self.vss_request(VssRpc::Thaw);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::CopyFileToGuest(request, response) => {
+                let r = self.copy_file_to_guest(request);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::SaveSnapshot(request, response) => {
+                let vmservice::SaveSnapshotRequest { dir, portable } = request;
+                let r = self.controller_request(
+                    VmControllerRpc::SaveSnapshot,
+                    SaveSnapshotParams { dir, portable },
+                );
+                // The VM is left paused. Wait for the save so that the
+                // lifecycle reflects it.
+                let r = async { r?.await }.await;
+                if r.is_ok() && !matches!(self.lifecycle, VmLifecycle::Halted(_)) {
+                    self.lifecycle = VmLifecycle::Paused;
+                }
+                response.send(map_grpc(r));
+            }
+            vmservice::Vm::RestoreSnapshot(request, response) => {
+                let r = self.controller_request(VmControllerRpc::RestoreSnapshot, request.dir);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::DumpState(request, response) => {
+                let r = self.controller_request(VmControllerRpc::DumpState, request.path);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::InspectVm(request, response) => {
+                let r = self.inspect_vm(ctx, request);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::UpdateVm(request, response) => {
+                let r = self.update_vm(ctx, request);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::InjectNmi(request, response) => {
+                let r = self.inject_nmi(request);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::ShutdownGuest(request, response) => {
+                let r = self.shutdown_guest(request);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::WaitVmEvent(request, response) => {
+                let r = Ok(self.wait_vm_event(ctx, request));
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::CreateCheckpoint(request, response) => {
+                let vmservice::CreateCheckpointRequest { name, description } = request;
+                let r = self
+                    .controller_request(
+                        VmControllerRpc::Checkpoint,
+                        CheckpointParams { name, description },
+                    )
+                    .map(checkpoint_response);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::ListCheckpoints((), response) => {
+                let r = self
+                    .controller_request(VmControllerRpc::ListCheckpoints, ())
+                    .map(|recv| async move {
+                        let checkpoints = recv
+                            .await?
+                            .into_iter()
+                            .map(|c| vmservice::CheckpointInfo {
+                                id: c.id,
+                                name: c.name,
+                                description: c.description,
+                                parent: c.parent,
+                                created: c.created,
+                                current: c.current,
+                            })
+                            .collect();
+                        Ok(vmservice::ListCheckpointsResponse { checkpoints })
+                    });
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::RevertCheckpoint(request, response) => {
+                let r = self
+                    .controller_request(VmControllerRpc::RevertCheckpoint, request.checkpoint)
+                    .map(checkpoint_response);
+                self.start_rpc(response, r);
+            }
+            vmservice::Vm::DeleteCheckpoint(request, response) => {
+                let r = self
+                    .controller_request(VmControllerRpc::DeleteCheckpoint, request.checkpoint)
+                    .map(checkpoint_response);
+                self.start_rpc(response, r);
+            }
         }
         HandleAction::None
     }
@@ -681,6 +885,127 @@ fn update(
         }
     }
 
+    fn inspect_vm(
+        &self,
+        ctx: mesh::CancelContext,
+        request: vmservice::InspectVmRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<vmservice::InspectVmResponse>> + use<>>
+    {
+        let vmservice::InspectVmRequest { path, depth } = request;
+        let inspect = self.inspect(
+            ctx,
+            inspect_proto::InspectRequest {
+                path,
+                depth: if depth == 0 { u32::MAX } else { depth },
+            },
+        );
+        Ok(async move {
+            let response = inspect.await?;
+            Ok(vmservice::InspectVmResponse {
+                json: response.result.json().to_string(),
+            })
+        })
+    }
+
+    fn update_vm(
+        &self,
+        ctx: mesh::CancelContext,
+        request: vmservice::UpdateVmRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<vmservice::UpdateVmResponse>> + use<>>
+    {
+        let vmservice::UpdateVmRequest { path, value } = request;
+        let update = self.update(ctx, inspect_proto::UpdateRequest { path, value });
+        Ok(async move {
+            let response = update.await?;
+            Ok(vmservice::UpdateVmResponse {
+                new_value: response.new_value.to_string(),
+            })
+        })
+    }
+
+    /// Sends a request to the VM controller.
+    fn controller_request<I, R>(
+        &self,
+        f: fn(FailableRpc<I, R>) -> VmControllerRpc,
+        input: I,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<R>> + use<I, R>>
+    where
+        I: 'static + Send,
+        R: 'static + Send,
+    {
+        let controller = self.vm_controller.as_ref().context("VM not created yet")?;
+        let recv = controller.call_failable(f, input);
+        Ok(async move { recv.await.map_err(anyhow::Error::from) })
+    }
+
+    fn inject_nmi(
+        &self,
+        request: vmservice::InjectNmiRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<()>> + use<>> {
+        let vm = self.vm.as_ref().context("VM not created yet")?;
+        let recv = vm.worker_rpc.call(VmRpc::Nmi, request.vp);
+        Ok(async move { recv.await.context("failed to inject NMI") })
+    }
+
+    fn shutdown_guest(
+        &self,
+        request: vmservice::ShutdownGuestRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<()>> + use<>> {
+        let vm = self.vm.as_ref().context("VM not created yet")?;
+        let shutdown_type = match vmservice::GuestShutdownType::try_from(request.r#type)
+            .context("invalid shutdown type")?
+        {
+            vmservice::GuestShutdownType::PowerOff => ShutdownType::PowerOff,
+            vmservice::GuestShutdownType::Reboot => ShutdownType::Reboot,
+            vmservice::GuestShutdownType::Hibernate => ShutdownType::Hibernate,
+        };
+        let recv = vm.shutdown_ic.call(
+            ShutdownRpc::Shutdown,
+            ShutdownParams {
+                shutdown_type,
+                force: request.force,
+            },
+        );
+        Ok(async move {
+            match recv.await.context("shutdown ic unavailable")? {
+                ShutdownResult::Ok => Ok(()),
+                ShutdownResult::NotReady => Err(anyhow::Error::new(Code::FailedPrecondition))
+                    .context("the guest is not ready to shut down"),
+                ShutdownResult::AlreadyInProgress => {
+                    Err(anyhow::Error::new(Code::FailedPrecondition))
+                        .context("a shutdown is already in progress")
+                }
+                ShutdownResult::Failed(status) => {
+                    bail!("guest shutdown failed with status {status:#x}")
+                }
+            }
+        })
+    }
+
+    fn wait_vm_event(
+        &mut self,
+        ctx: mesh::CancelContext,
+        request: vmservice::WaitVmEventRequest,
+    ) -> impl Future<Output = anyhow::Result<vmservice::VmEvent>> + use<> {
+        let recv = self.events.wait(request.after_sequence);
+        let mut ctx = ctx;
+        async move { Ok(ctx.until_cancelled(recv).await??) }
+    }
+
+    /// Records a VM event and completes any waiting `WaitVMEvent` calls.
+    fn push_event(
+        &mut self,
+        event_type: vmservice::VmEventType,
+        vp: Option<u32>,
+        reset: bool,
+        detail: String,
+    ) {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        self.events.push(event_type, timestamp, vp, reset, detail);
+    }
+
     async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Result<()> {
         let mut req_config = request.config.context("missing configuration")?;
 
@@ -777,6 +1102,18 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
             (numa, mem_size)
         };
 
+        let memory_backing_file = req_config
+            .memory_config
+            .as_ref()
+            .filter(|c| !c.backing_file.is_empty())
+            .map(|c| PathBuf::from(&c.backing_file));
+
+        let checkpoint_dir = (!req_config.checkpoint_dir.is_empty())
+            .then(|| PathBuf::from(&req_config.checkpoint_dir));
+        if checkpoint_dir.is_some() && memory_backing_file.is_none() {
+            bail!("checkpoint_dir requires a memory backing file");
+        }
+
         let config_proc_count = req_config
             .processor_config
             .as_ref()
@@ -821,6 +1158,7 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
             vga_firmware: None,
             vtl2_gfx: false,
             virtio_devices: vec![],
+            e1000e_pci_devices: vec![],
             vmbus: Some(VmbusConfig::default()),
             vtl2_vmbus: None,
             vmbus_devices: vec![],
@@ -837,17 +1175,65 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
             chipset_capabilities: chipset.capabilities,
             layout: layout_config,
             rtc_delta_milliseconds: 0,
-            automatic_guest_reset: true,
+            // Guest resets go through the controller so that they are reported
+            // to `WaitVMEvent` callers.
+            automatic_guest_reset: false,
             efi_diagnostics_log_level: Default::default(),
         };
 
+        let (heartbeat_status, heartbeat_ic) = mesh::cell(HeartbeatStatus::NoContact);
+        let (vss_ic, vss_recv) = mesh::channel();
+        let (fcopy_ic, fcopy_recv) = mesh::channel();
+        let (shutdown_ic, shutdown_recv) = mesh::channel();
+        config.vmbus_devices.extend(
+            [
+                ShutdownIcHandle {
+                    recv: shutdown_recv,
+                }
+                .into_resource(),
+                HeartbeatIcHandle {
+                    status: heartbeat_status,
+                }
+                .into_resource(),
+                VssIcHandle { recv: vss_recv }.into_resource(),
+                FcopyIcHandle { recv: fcopy_recv }.into_resource(),
+            ]
+            .map(|r| (DeviceVtl::Vtl0, r)),
+        );
+
+        let (event_send, event_recv) = mesh::channel();
+        if !req_config.crash_dump_dir.is_empty() {
+            // Report guest crashes to `WaitVMEvent` callers alongside the
+            // controller's events.
+            let event_send = event_send.clone();
+            let (resource, task) = crate::crash_dump::spawn_dump_handler(
+                &self.driver,
+                PathBuf::from(&req_config.crash_dump_dir),
+                None,
+                move |path| {
+                    event_send.send(VmControllerEvent::GuestCrash {
+                        dump_path: path.display().to_string(),
+                    })
+                },
+            );
+            task.detach();
+            config.vmbus_devices.push((DeviceVtl::Vtl0, resource));
+        }
+
         let mut scsi_rpc = None;
         let mut consomme_rpc = None;
+        let mut layered_disks = BTreeMap::new();
         if let Some(devices_config) = req_config.devices_config {
             if !devices_config.scsi_disks.is_empty() {
                 let mut devices = Vec::new();
                 for disk in devices_config.scsi_disks {
-                    devices.push(make_disk_config(disk).await?);
+                    // Checkpoints snapshot every disk.
+                    let snapshot = disk.snapshot || checkpoint_dir.is_some();
+                    let (device, layer_rpc) = make_disk_config(disk, snapshot).await?;
+                    if let Some(layer_rpc) = layer_rpc {
+                        layered_disks.insert(device.path.lun, layer_rpc);
+                    }
+                    devices.push(device);
                 }
                 let (send, recv) = mesh::channel();
                 config.vmbus_devices.push((
@@ -959,19 +1345,44 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
                     hypervisor: openvmm_helpers::hypervisor::choose_hypervisor()?,
                     cfg: config,
                     saved_state: None,
-                    shared_memory: None,
+                    shared_memory: memory_backing_file
+                        .as_deref()
+                        .map(|path| {
+                            openvmm_helpers::shared_memory::open_memory_backing_file(
+                                path,
+                                config_mem_size,
+                            )
+                        })
+                        .transpose()?,
+                    incoming_migration: None,
                     rpc: recv,
                     notify: notify_send,
                 },
             )
             .await?;
 
+        // Redirect disk writes into the checkpoint tree before the guest runs.
+        let checkpoints = if let Some(dir) = &checkpoint_dir {
+            Some(
+                Checkpoints::open(
+                    dir,
+                    layered_disks
+                        .iter()
+                        .map(|(lun, rpc)| (format!("scsi/{lun}"), rpc.clone()))
+                        .collect(),
+                    memory_backing_file.clone().unwrap(),
+                )
+                .await?,
+            )
+        } else {
+            None
+        };
+
         let memory = config_mem_size;
         let processors = config_proc_count;
 
         // Create channels for VmController.
         let (vm_controller_send, vm_controller_recv) = mesh::channel();
-        let (event_send, event_recv) = mesh::channel();
 
         // Build VmController with no paravisor-specific fields.
         let controller = VmController {
@@ -985,7 +1396,10 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
             vm_rpc: send.clone(),
             paravisor_diag: None,
             igvm_path: None,
-            memory_backing_file: None,
+            memory_backing_file,
+            vm_args: Vec::new(),
+            configured_disks: Vec::new(),
+            checkpoints,
             memory,
             processors,
             log_file: None,
@@ -1008,6 +1422,11 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
             scsi_rpc,
             consomme_rpc,
             worker_rpc: send,
+            layered_disks: Mutex::new(layered_disks),
+            heartbeat_ic,
+            vss_ic,
+            fcopy_ic,
+            shutdown_ic,
         }));
         self.lifecycle = VmLifecycle::Paused;
         Ok(())
@@ -1113,12 +1532,41 @@ fn handle_controller_event(&mut self, event: VmControllerEvent) {
                 // log rather than exiting the server out from under its clients.
                 tracing::warn!(code, "unexpected exit request in server mode");
             }
+            VmControllerEvent::GuestPowerEvent {
+                kind,
+                detail,
+                reset,
+            } => {
+                let (event_type, vp) = match kind {
+                    GuestPowerEventKind::PowerOff => (vmservice::VmEventType::GuestPowerOff, None),
+                    GuestPowerEventKind::Reset => (vmservice::VmEventType::GuestReset, None),
+                    GuestPowerEventKind::Hibernate => {
+                        (vmservice::VmEventType::GuestHibernate, None)
+                    }
+                    GuestPowerEventKind::TripleFault { vp } => {
+                        (vmservice::VmEventType::GuestTripleFault, Some(vp))
+                    }
+                    GuestPowerEventKind::Watchdog => (vmservice::VmEventType::GuestWatchdog, None),
+                    GuestPowerEventKind::Other => (vmservice::VmEventType::GuestOtherHalt, None),
+                };
+                self.push_event(event_type, vp, reset, detail);
+            }
+            VmControllerEvent::GuestCrash { dump_path } => {
+                tracing::info!(%dump_path, "guest crashed");
+                self.push_event(vmservice::VmEventType::GuestCrash, None, false, dump_path);
+            }
             VmControllerEvent::WorkerStopped { error } => {
                 if let Some(err) = &error {
                     tracing::error!(error = %err, "VM worker stopped with error");
                 } else {
                     tracing::info!("VM worker stopped");
                 }
+                self.push_event(
+                    vmservice::VmEventType::WorkerStopped,
+                    None,
+                    false,
+                    error.clone().unwrap_or_default(),
+                );
                 if let Some((_, response)) = self.wait_vm_response.take() {
                     let status = if let Some(err) = &error {
                         grpc_error(anyhow!("VM worker stopped: {}", err))
@@ -1175,6 +1623,71 @@ fn remove_pcie_device(
         Ok(async move { recv.await.map_err(anyhow::Error::from) })
     }
 
+    fn snapshot_disk(
+        &self,
+        request: vmservice::SnapshotDiskRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<()>> + use<>> {
+        let vm = self.vm.as_ref().context("VM not created yet")?;
+        snapshot_disk(&vm.worker_rpc, &vm.layered_disks.lock(), request)
+    }
+
+    fn commit_disk(
+        &self,
+        request: vmservice::CommitDiskRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<()>> + use<>> {
+        let vm = self.vm.as_ref().context("VM not created yet")?;
+        commit_disk(&vm.worker_rpc, &vm.layered_disks.lock(), request)
+    }
+
+    fn guest_heartbeat(&self) -> anyhow::Result<vmservice::GuestHeartbeatResponse> {
+        let vm = self.vm.as_ref().context("VM not created yet")?;
+        let status = match vm.heartbeat_ic.get() {
+            HeartbeatStatus::NoContact => vmservice::GuestHeartbeatStatus::NoContact,
+            HeartbeatStatus::Ok => vmservice::GuestHeartbeatStatus::Ok,
+            HeartbeatStatus::ApplicationCritical => {
+                vmservice::GuestHeartbeatStatus::ApplicationCritical
+            }
+            HeartbeatStatus::LostCommunication => {
+                vmservice::GuestHeartbeatStatus::LostCommunication
+            }
+        };
+        Ok(vmservice::GuestHeartbeatResponse {
+            status: status as i32,
+        })
+    }
+
+    fn vss_request(
+        &self,
+        request: fn(FailableRpc<(), ()>) -> VssRpc,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<()>> + use<>> {
+        let vm = self.vm.as_ref().context("VM not created yet")?;
+        let recv = vm.vss_ic.call_failable(request, ());
+        Ok(async move { recv.await.map_err(anyhow::Error::from) })
+    }
+
+    fn copy_file_to_guest(
+        &self,
+        request: vmservice::CopyFileToGuestRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<()>> + use<>> {
+        let vm = self.vm.as_ref().context("VM not created yet")?;
+        let vmservice::CopyFileToGuestRequest {
+            host_path,
+            guest_directory,
+            file_name,
+            overwrite,
+            create_directory,
+        } = request;
+        let params = crate::copy_file_params(
+            std::path::Path::new(&host_path),
+            guest_directory,
+            (!file_name.is_empty()).then_some(file_name),
+            overwrite,
+            create_directory,
+        )?;
+        let recv = vm.fcopy_ic.call_failable(FcopyRpc::CopyFile, params);
+        Ok(async move { recv.await.map_err(anyhow::Error::from) })
+    }
+
     fn modify_resource(
         &self,
         request: vmservice::ModifyResourceRequest,
@@ -1194,12 +1707,17 @@ fn modify_resource(
                         anyhow::bail!("controller must be 0");
                     }
                     let scsi_rpc = vm.scsi_rpc.as_ref().context("no scsi controller")?.clone();
+                    let vm = vm.clone();
                     Ok(async move {
-                        let config = make_disk_config(disk).await?;
+                        let snapshot = disk.snapshot;
+                        let (config, layer_rpc) = make_disk_config(disk, snapshot).await?;
                         scsi_rpc
                             .call_failable(ScsiControllerRequest::AddDevice, config)
-                            .await
-                            .map_err(anyhow::Error::from)
+                            .await?;
+                        if let Some(layer_rpc) = layer_rpc {
+                            vm.layered_disks.lock().insert(scsi_path.lun, layer_rpc);
+                        }
+                        Ok(())
                     }
                     .boxed())
                 } else if request.r#type == vmservice::ModifyType::Remove as i32 {
@@ -1208,7 +1726,13 @@ fn modify_resource(
                         .as_ref()
                         .context("no scsi controller")?
                         .call_failable(ScsiControllerRequest::RemoveDevice, scsi_path);
-                    Ok(async move { recv.await.map_err(anyhow::Error::from) }.boxed())
+                    let vm = vm.clone();
+                    Ok(async move {
+                        recv.await?;
+                        vm.layered_disks.lock().remove(&scsi_path.lun);
+                        Ok(())
+                    }
+                    .boxed())
                 } else {
                     anyhow::bail!("unsupported request type {}", request.r#type);
                 }
@@ -1361,6 +1885,10 @@ fn parse_nic_config(
                 .map(parse_port_config)
                 .collect::<anyhow::Result<_>>()?,
             recv,
+            egress: Default::default(),
+            guest_forwards: Vec::new(),
+            dns_records: Vec::new(),
+            log_dns_queries: false,
         }
         .into_resource(),
         _ => anyhow::bail!("unsupported backend"),
@@ -1377,28 +1905,117 @@ fn parse_nic_config(
     Ok((DeviceVtl::Vtl0, cfg.into_resource()))
 }
 
-async fn make_disk_config(disk: vmservice::ScsiDisk) -> anyhow::Result<ScsiDeviceAndPath> {
-    Ok(ScsiDeviceAndPath {
+/// Maps the checkpoint ID returned by a controller request to an RPC response.
+async fn checkpoint_response(
+    recv: impl Future<Output = anyhow::Result<String>>,
+) -> anyhow::Result<vmservice::CheckpointResponse> {
+    Ok(vmservice::CheckpointResponse { id: recv.await? })
+}
+
+/// Adds a new top layer to a SCSI disk to take a live snapshot of it.
+fn snapshot_disk(
+    worker_rpc: &mesh::Sender<VmRpc>,
+    layered_disks: &BTreeMap<u8, mesh::Sender<LayeredDiskRequest>>,
+    request: vmservice::SnapshotDiskRequest,
+) -> anyhow::Result<impl Future<Output = anyhow::Result<()>> + use<>> {
+    let vmservice::SnapshotDiskRequest {
+        controller,
+        lun,
+        layer_path,
+    } = request;
+    let layer = crate::snapshot_layer(
+        (!layer_path.is_empty()).then_some(std::path::Path::new(&layer_path)),
+    )?;
+    modify_disk_layers(worker_rpc, layered_disks, controller, lun, |layer_rpc| {
+        layer_rpc.call_failable(LayeredDiskRequest::AddTopLayer, layer)
+    })
+}
+
+/// Commits the top layer of a SCSI disk into the layer below it.
+fn commit_disk(
+    worker_rpc: &mesh::Sender<VmRpc>,
+    layered_disks: &BTreeMap<u8, mesh::Sender<LayeredDiskRequest>>,
+    request: vmservice::CommitDiskRequest,
+) -> anyhow::Result<impl Future<Output = anyhow::Result<()>> + use<>> {
+    let vmservice::CommitDiskRequest { controller, lun } = request;
+    modify_disk_layers(worker_rpc, layered_disks, controller, lun, |layer_rpc| {
+        layer_rpc.call_failable(LayeredDiskRequest::CommitTopLayer, ())
+    })
+}
+
+/// Sends a layer request to the SCSI disk at `lun`, with the VM paused.
+fn modify_disk_layers<F>(
+    worker_rpc: &mesh::Sender<VmRpc>,
+    layered_disks: &BTreeMap<u8, mesh::Sender<LayeredDiskRequest>>,
+    controller: u32,
+    lun: u32,
+    f: F,
+) -> anyhow::Result<impl Future<Output = anyhow::Result<()>> + use<F>>
+where
+    F: 'static + Send + FnOnce(&mesh::Sender<LayeredDiskRequest>) -> PendingFailableRpc<()>,
+{
+    if controller != 0 {
+        anyhow::bail!("controller must be 0");
+    }
+    let lun = u8::try_from(lun).ok().context("lun value out of range")?;
+    let layer_rpc = layered_disks
+        .get(&lun)
+        .with_context(|| format!("no disk supporting snapshots at lun {lun}"))?
+        .clone();
+    let worker_rpc = worker_rpc.clone();
+    Ok(async move {
+        crate::with_vm_paused(&worker_rpc, async {
+            f(&layer_rpc).await?;
+            Ok(())
+        })
+        .await
+    })
+}
+
+/// Builds the configuration for a SCSI disk.
+///
+/// If `snapshot` is set, the disk is wrapped in a layered disk so that it can
+/// be snapshotted at runtime via the returned request channel.
+async fn make_disk_config(
+    disk: vmservice::ScsiDisk,
+    snapshot: bool,
+) -> anyhow::Result<(ScsiDeviceAndPath, Option<mesh::Sender<LayeredDiskRequest>>)> {
+    let backing = open_disk_type(
+        disk.host_path.as_ref(),
+        OpenDiskOptions {
+            read_only: disk.read_only,
+            direct: false,
+        },
+    )
+    .await
+    .with_context(|| format!("failed to open {}", disk.host_path))?;
+    let (backing, layer_rpc) = if snapshot {
+        let (send, recv) = mesh::channel();
+        let backing = LayeredDiskHandle {
+            layers: vec![DiskLayerDescription::from(
+                DiskLayerHandle(backing).into_resource(),
+            )],
+            requests: Some(recv),
+        }
+        .into_resource();
+        (backing, Some(send))
+    } else {
+        (backing, None)
+    };
+    let config = ScsiDeviceAndPath {
         path: storvsp_resources::ScsiPath {
             path: 0,
             target: 0,
             lun: disk.lun.try_into().ok().context("lun value out of range")?,
         },
         device: SimpleScsiDiskHandle {
-            disk: open_disk_type(
-                disk.host_path.as_ref(),
-                OpenDiskOptions {
-                    read_only: disk.read_only,
-                    direct: false,
-                },
-            )
-            .await
-            .with_context(|| format!("failed to open {}", disk.host_path))?,
+            disk: backing,
             read_only: disk.read_only,
             parameters: Default::default(),
         }
         .into_resource(),
-    })
+    };
+    Ok((config, layer_rpc))
 }
 
 /// Builds a [`NumaTopology`] from the proto `NumaConfig`, returning the
@@ -1726,7 +2343,12 @@ async fn build_virtio_device(
         Kind::Blk(vmservice::VirtioBlk { backend, read_only }) => {
             let disk =
                 build_disk_backend(backend.context("missing blk backend")?, read_only).await?;
-            virtio_resources::blk::VirtioBlkHandle { disk, read_only }.into_resource()
+            virtio_resources::blk::VirtioBlkHandle {
+                disk,
+                read_only,
+                num_queues: None,
+            }
+            .into_resource()
         }
         Kind::Net(vmservice::VirtioNet {
             max_queues,
@@ -1738,6 +2360,7 @@ async fn build_virtio_device(
                 max_queues: max_queues
                     .map(|q| q.try_into().context("max_queues out of range"))
                     .transpose()?,
+                mtu: None,
                 mac_address: mac_address
                     .parse::<MacAddress>()
                     .context("invalid mac address")?,
@@ -1801,6 +2424,10 @@ fn build_nic_backend(
                     .map(parse_port_config)
                     .collect::<anyhow::Result<_>>()?,
                 recv: None,
+                egress: Default::default(),
+                guest_forwards: Vec::new(),
+                dns_records: Vec::new(),
+                log_dns_queries: false,
             }
             .into_resource()
         }
@@ -1894,6 +2521,19 @@ fn build_vhost_user_device(
             queue_size: queue_size.map(to_u16).transpose()?,
         }
         .into_resource(),
+        Kind::Net(vmservice::VhostUserNet {
+            mac_address,
+            queue_pairs,
+            queue_size,
+        }) => virtio_resources::vhost_user::VhostUserNetHandle {
+            socket: stream.into(),
+            mac_address: mac_address
+                .parse::<MacAddress>()
+                .context("invalid mac address")?,
+            queue_pairs: queue_pairs.map(to_u16).transpose()?,
+            queue_size: queue_size.map(to_u16).transpose()?,
+        }
+        .into_resource(),
         Kind::Other(vmservice::VhostUserGeneric {
             device_id,
             queue_sizes,
@@ -1915,3 +2555,201 @@ fn build_vhost_user_device(
 ) -> anyhow::Result<Resource<VirtioDeviceHandle>> {
     anyhow::bail!("vhost-user is only supported on unix hosts")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::EventFeed;
+    use super::MAX_EVENT_HISTORY;
+    use super::commit_disk;
+    use super::make_disk_config;
+    use super::snapshot_disk;
+    use disk_backend_resources::LayeredDiskRequest;
+    use futures::FutureExt;
+    use futures::StreamExt;
+    use openvmm_defs::rpc::VmRpc;
+    use openvmm_ttrpc_vmservice as vmservice;
+    use pal_async::DefaultPool;
+    use pal_async::task::Spawn;
+    use parking_lot::Mutex;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    fn push(feed: &mut EventFeed, event_type: vmservice::VmEventType) {
+        feed.push(event_type, 0, None, false, String::new());
+    }
+
+    fn ready(recv: mesh::OneshotReceiver<vmservice::VmEvent>) -> vmservice::VmEvent {
+        recv.now_or_never().expect("event should be ready").unwrap()
+    }
+
+    #[test]
+    fn event_sequence() {
+        let mut feed = EventFeed::default();
+        push(&mut feed, vmservice::VmEventType::GuestReset);
+        push(&mut feed, vmservice::VmEventType::GuestPowerOff);
+
+        // Zero returns the oldest event; a sequence number returns the next.
+        let event = ready(feed.wait(0));
+        assert_eq!(event.sequence, 1);
+        assert_eq!(event.r#type, vmservice::VmEventType::GuestReset as i32);
+        let event = ready(feed.wait(1));
+        assert_eq!(event.sequence, 2);
+        assert_eq!(event.r#type, vmservice::VmEventType::GuestPowerOff as i32);
+
+        // A caller that is caught up waits for the next event.
+        let mut recv = feed.wait(2);
+        assert!((&mut recv).now_or_never().is_none());
+        push(&mut feed, vmservice::VmEventType::GuestCrash);
+        let event = ready(recv);
+        assert_eq!(event.sequence, 3);
+        assert_eq!(event.r#type, vmservice::VmEventType::GuestCrash as i32);
+    }
+
+    #[test]
+    fn event_history_cap() {
+        let mut feed = EventFeed::default();
+        for _ in 0..MAX_EVENT_HISTORY + 10 {
+            push(&mut feed, vmservice::VmEventType::GuestReset);
+        }
+        assert_eq!(feed.events.len(), MAX_EVENT_HISTORY);
+
+        // The oldest events are dropped, leaving a gap in the sequence.
+        assert_eq!(ready(feed.wait(0)).sequence, 11);
+        assert_eq!(ready(feed.wait(100)).sequence, 101);
+    }
+
+    #[test]
+    fn event_waiters() {
+        let mut feed = EventFeed::default();
+        let first = feed.wait(0);
+        let second = feed.wait(0);
+
+        // Cancelled calls drop their receivers, and are pruned on the next
+        // wait instead of accumulating.
+        for _ in 0..10 {
+            drop(feed.wait(0));
+        }
+        let third = feed.wait(0);
+        assert_eq!(feed.waiters.len(), 3);
+
+        // Every waiter gets the next event.
+        push(&mut feed, vmservice::VmEventType::WorkerStopped);
+        assert!(feed.waiters.is_empty());
+        for recv in [first, second, third] {
+            assert_eq!(ready(recv).sequence, 1);
+        }
+    }
+
+    /// Serves pause and resume requests, recording them in `log`.
+    async fn fake_vm(mut recv: mesh::Receiver<VmRpc>, log: Arc<Mutex<Vec<&'static str>>>) {
+        while let Some(rpc) = recv.next().await {
+            match rpc {
+                VmRpc::Pause(rpc) => {
+                    log.lock().push("pause");
+                    rpc.complete(true)
+                }
+                VmRpc::Resume(rpc) => {
+                    log.lock().push("resume");
+                    rpc.complete(true)
+                }
+                _ => panic!("unexpected vm rpc"),
+            }
+        }
+    }
+
+    /// Serves layer requests, recording them in `log`.
+    async fn fake_layered_disk(
+        mut recv: mesh::Receiver<LayeredDiskRequest>,
+        log: Arc<Mutex<Vec<&'static str>>>,
+    ) {
+        while let Some(req) = recv.next().await {
+            match req {
+                LayeredDiskRequest::AddTopLayer(rpc) => {
+                    log.lock().push("add");
+                    rpc.complete(Ok(()))
+                }
+                LayeredDiskRequest::CommitTopLayer(rpc) => {
+                    log.lock().push("commit");
+                    rpc.complete(Ok(()))
+                }
+                LayeredDiskRequest::ReplaceTopLayers(_) => panic!("unexpected layer request"),
+            }
+        }
+    }
+
+    #[test]
+    fn disk_config_snapshot() {
+        DefaultPool::run_with(async |_| {
+            let file = tempfile::NamedTempFile::new().unwrap();
+            file.as_file().set_len(0x100000).unwrap();
+            let disk = vmservice::ScsiDisk {
+                lun: 3,
+                host_path: file.path().display().to_string(),
+                ..Default::default()
+            };
+
+            // Disks only get a layer request channel when snapshots are
+            // requested.
+            let (config, layer_rpc) = make_disk_config(disk.clone(), false).await.unwrap();
+            assert_eq!(config.path.lun, 3);
+            assert!(layer_rpc.is_none());
+            let (_, layer_rpc) = make_disk_config(disk, true).await.unwrap();
+            assert!(layer_rpc.is_some());
+        })
+    }
+
+    #[test]
+    fn snapshot_and_commit_disk() {
+        DefaultPool::run_with(async |driver| {
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let (worker_rpc, recv) = mesh::channel();
+            let _vm = driver.spawn("fake-vm", fake_vm(recv, log.clone()));
+            let (disk_rpc, recv) = mesh::channel();
+            let _disk = driver.spawn("fake-disk", fake_layered_disk(recv, log.clone()));
+            let layered_disks = BTreeMap::from([(1, disk_rpc)]);
+
+            snapshot_disk(
+                &worker_rpc,
+                &layered_disks,
+                vmservice::SnapshotDiskRequest {
+                    controller: 0,
+                    lun: 1,
+                    layer_path: String::new(),
+                },
+            )
+            .unwrap()
+            .await
+            .unwrap();
+            commit_disk(
+                &worker_rpc,
+                &layered_disks,
+                vmservice::CommitDiskRequest {
+                    controller: 0,
+                    lun: 1,
+                },
+            )
+            .unwrap()
+            .await
+            .unwrap();
+            // The layer requests are made with the VM paused.
+            assert_eq!(
+                *log.lock(),
+                ["pause", "add", "resume", "pause", "commit", "resume"]
+            );
+
+            // Requests for other disks fail without pausing the VM.
+            log.lock().clear();
+            for (controller, lun) in [(1, 1), (0, 0), (0, 256)] {
+                assert!(
+                    commit_disk(
+                        &worker_rpc,
+                        &layered_disks,
+                        vmservice::CommitDiskRequest { controller, lun },
+                    )
+                    .is_err()
+                );
+            }
+            assert!(log.lock().is_empty());
+        })
+    }
+}
@@ -5,6 +5,8 @@
 //! DiagInspector, vtl2_settings) and exposes them to the REPL via mesh RPC.
 
 use crate::DiagInspector;
+use crate::checkpoint::CheckpointInfo;
+use crate::checkpoint::Checkpoints;
 use crate::cli_args::GuestPowerAction;
 use crate::meshworker::VmmMesh;
 use anyhow::Context;
@@ -56,9 +58,20 @@ pub enum VmControllerRpc {
         Rpc<RemoveVtl0ScsiDiskByNvmeNsidParams, Result<Option<u32>, mesh::error::RemoteError>>,
     ),
     /// Save a VM snapshot to a directory.
-    SaveSnapshot(Rpc<String, Result<(), mesh::error::RemoteError>>),
+    SaveSnapshot(Rpc<SaveSnapshotParams, Result<(), mesh::error::RemoteError>>),
+    /// Replace the VM's memory and device state with a snapshot from a
+    /// directory.
+    RestoreSnapshot(Rpc<String, Result<(), mesh::error::RemoteError>>),
     /// Dump VM state (VP registers + memory) to a `.vmrs` file.
     DumpState(Rpc<String, Result<(), mesh::error::RemoteError>>),
+    /// Take a checkpoint, returning its ID.
+    Checkpoint(Rpc<CheckpointParams, Result<String, mesh::error::RemoteError>>),
+    /// List the checkpoints, oldest first.
+    ListCheckpoints(Rpc<(), Result<Vec<CheckpointInfo>, mesh::error::RemoteError>>),
+    /// Revert to a checkpoint, by name or ID, returning its ID.
+    RevertCheckpoint(Rpc<String, Result<String, mesh::error::RemoteError>>),
+    /// Delete a checkpoint, by name or ID, returning its ID.
+    DeleteCheckpoint(Rpc<String, Result<String, mesh::error::RemoteError>>),
     /// Service (update) the VTL2 firmware.
     ServiceVtl2(Rpc<ServiceVtl2Params, Result<u64, mesh::error::RemoteError>>),
     /// Stop the VM and quit.
@@ -87,6 +100,19 @@ pub struct RemoveVtl0ScsiDiskByNvmeNsidParams {
     pub nsid: u32,
 }
 
+#[derive(mesh::MeshPayload)]
+pub struct SaveSnapshotParams {
+    pub dir: String,
+    /// Write a self-contained snapshot with a compressed copy of guest RAM.
+    pub portable: bool,
+}
+
+#[derive(mesh::MeshPayload)]
+pub struct CheckpointParams {
+    pub name: String,
+    pub description: String,
+}
+
 #[derive(mesh::MeshPayload)]
 pub struct ServiceVtl2Params {
     pub user_mode_only: bool,
@@ -104,11 +130,50 @@ pub enum VmControllerEvent {
     VncWorkerStopped { error: Option<String> },
     /// The guest halted.
     GuestHalt(String),
+    /// The guest drove a power event. Sent after the configured action is
+    /// taken, in addition to `GuestHalt` if the VM stays halted.
+    GuestPowerEvent {
+        kind: GuestPowerEventKind,
+        /// The halt reason, for logging.
+        detail: String,
+        /// Whether the VM was reset in place.
+        reset: bool,
+    },
+    /// The guest reported a crash to the guest crash device and is writing a
+    /// dump to `dump_path`.
+    GuestCrash { dump_path: String },
     /// The controller requests that the process exit with this code, because the
     /// guest drove a power event the user opted into exiting on.
     ExitRequested { code: i32 },
 }
 
+/// The kind of a [`VmControllerEvent::GuestPowerEvent`].
+#[derive(Debug, mesh::MeshPayload)]
+pub enum GuestPowerEventKind {
+    PowerOff,
+    Reset,
+    Hibernate,
+    TripleFault {
+        vp: u32,
+    },
+    Watchdog,
+    /// Any other halt, such as a debug break.
+    Other,
+}
+
+impl From<&HaltReason> for GuestPowerEventKind {
+    fn from(reason: &HaltReason) -> Self {
+        match reason {
+            HaltReason::PowerOff => Self::PowerOff,
+            HaltReason::Reset => Self::Reset,
+            HaltReason::Hibernate => Self::Hibernate,
+            HaltReason::TripleFault { vp, .. } => Self::TripleFault { vp: *vp },
+            HaltReason::Watchdog => Self::Watchdog,
+            _ => Self::Other,
+        }
+    }
+}
+
 /// Owns exclusive VM resources and services RPCs from the REPL.
 pub struct VmController {
     pub(crate) mesh: VmmMesh,
@@ -122,6 +187,9 @@ pub struct VmController {
     pub(crate) paravisor_diag: Option<Arc<diag_client::DiagClient>>,
     pub(crate) igvm_path: Option<PathBuf>,
     pub(crate) memory_backing_file: Option<PathBuf>,
+    pub(crate) vm_args: Vec<String>,
+    pub(crate) configured_disks: Vec<(String, openvmm_helpers::disk_cli::DiskCliKind)>,
+    pub(crate) checkpoints: Option<Checkpoints>,
     pub(crate) memory: u64,
     pub(crate) processors: u32,
     pub(crate) log_file: Option<PathBuf>,
@@ -276,12 +344,18 @@ enum Event {
                 Event::Halt(reason) => {
                     tracing::info!(?reason, "guest halted");
                     let action = action_for(&reason, &self.guest_power_actions);
+                    let power_event = |reset| VmControllerEvent::GuestPowerEvent {
+                        kind: GuestPowerEventKind::from(&reason),
+                        detail: format!("{reason:?}"),
+                        reset,
+                    };
                     match action {
                         GuestPowerAction::Exit(code) => {
                             // The VM worker's teardown deadlocks once the guest vCPUs
                             // are parked, so don't stop it here; signal the runner to
                             // exit instead.
                             tracing::info!(exit_code = code, "requesting exit on guest halt");
+                            event_send.send(power_event(false));
                             event_send.send(VmControllerEvent::ExitRequested {
                                 code: i32::from(code),
                             });
@@ -298,11 +372,15 @@ enum Event {
                                     error = &err as &dyn std::error::Error,
                                     "failed to reset VM on guest power event; keeping it stopped"
                                 );
+                                event_send.send(power_event(false));
                                 event_send
                                     .send(VmControllerEvent::GuestHalt(format!("{reason:?}")));
+                            } else {
+                                event_send.send(power_event(true));
                             }
                         }
                         GuestPowerAction::Halt => {
+                            event_send.send(power_event(false));
                             event_send.send(VmControllerEvent::GuestHalt(format!("{reason:?}")));
                         }
                     }
@@ -378,8 +456,15 @@ async fn handle_rpc(&mut self, rpc: VmControllerRpc, quit: &mut bool) {
                 req.complete(result.map_err(mesh::error::RemoteError::new));
             }
             VmControllerRpc::SaveSnapshot(req) => {
+                let (params, req) = req.split();
+                let result = self
+                    .handle_save_snapshot(Path::new(&params.dir), params.portable)
+                    .await;
+                req.complete(result.map_err(mesh::error::RemoteError::new));
+            }
+            VmControllerRpc::RestoreSnapshot(req) => {
                 let (dir, req) = req.split();
-                let result = self.handle_save_snapshot(Path::new(&dir)).await;
+                let result = self.handle_restore_snapshot(Path::new(&dir)).await;
                 req.complete(result.map_err(mesh::error::RemoteError::new));
             }
             VmControllerRpc::DumpState(req) => {
@@ -387,6 +472,25 @@ async fn handle_rpc(&mut self, rpc: VmControllerRpc, quit: &mut bool) {
                 let result = self.handle_dump_state(Path::new(&path)).await;
                 req.complete(result.map_err(mesh::error::RemoteError::new));
             }
+            VmControllerRpc::Checkpoint(req) => {
+                let (params, req) = req.split();
+                let result = self.handle_checkpoint(params).await;
+                req.complete(result.map_err(mesh::error::RemoteError::new));
+            }
+            VmControllerRpc::ListCheckpoints(req) => {
+                let result = self.checkpoints().and_then(Checkpoints::list);
+                req.complete(result.map_err(mesh::error::RemoteError::new));
+            }
+            VmControllerRpc::RevertCheckpoint(req) => {
+                let (name, req) = req.split();
+                let result = self.handle_revert_checkpoint(&name).await;
+                req.complete(result.map_err(mesh::error::RemoteError::new));
+            }
+            VmControllerRpc::DeleteCheckpoint(req) => {
+                let (name, req) = req.split();
+                let result = self.checkpoints().and_then(|c| c.delete(&name));
+                req.complete(result.map_err(mesh::error::RemoteError::new));
+            }
             VmControllerRpc::ServiceVtl2(req) => {
                 let (params, req) = req.split();
                 let result = self.handle_service_vtl2(params).await;
@@ -442,13 +546,74 @@ fn handle_inspect(&mut self, target: InspectTarget, deferred: inspect::Deferred)
         deferred.inspect(obj);
     }
 
-    async fn handle_save_snapshot(&self, dir: &Path) -> anyhow::Result<()> {
+    /// Returns a manifest describing the VM for a new snapshot.
+    fn snapshot_manifest(&self) -> anyhow::Result<openvmm_helpers::snapshot::SnapshotManifest> {
+        Ok(openvmm_helpers::snapshot::SnapshotManifest {
+            version: openvmm_helpers::snapshot::MANIFEST_VERSION,
+            created_at: std::time::SystemTime::now().into(),
+            openvmm_version: env!("CARGO_PKG_VERSION").to_string(),
+            memory_size_bytes: self.memory,
+            vp_count: self.processors,
+            page_size: crate::system_page_size(),
+            architecture: crate::GUEST_ARCH.to_string(),
+            compressed_memory: false,
+            vm_args: Vec::new(),
+            name: String::new(),
+            description: String::new(),
+            parent: String::new(),
+            disks: Vec::new(),
+            configured_disks: crate::snapshot_disks(&self.configured_disks)?,
+        })
+    }
+
+    fn checkpoints(&self) -> anyhow::Result<&Checkpoints> {
+        self.checkpoints
+            .as_ref()
+            .context("checkpoints require --checkpoint-dir")
+    }
+
+    async fn handle_checkpoint(&mut self, params: CheckpointParams) -> anyhow::Result<String> {
+        let manifest = openvmm_helpers::snapshot::SnapshotManifest {
+            name: params.name,
+            description: params.description,
+            ..self.snapshot_manifest()?
+        };
+        let checkpoints = self
+            .checkpoints
+            .as_mut()
+            .context("checkpoints require --checkpoint-dir")?;
+        checkpoints.checkpoint(&self.vm_rpc, manifest).await
+    }
+
+    async fn handle_revert_checkpoint(&mut self, name_or_id: &str) -> anyhow::Result<String> {
+        let (memory, processors) = (self.memory, self.processors);
+        let disks = crate::snapshot_disks(&self.configured_disks)?;
+        let checkpoints = self
+            .checkpoints
+            .as_mut()
+            .context("checkpoints require --checkpoint-dir")?;
+        checkpoints
+            .revert(&self.vm_rpc, name_or_id, |manifest| {
+                openvmm_helpers::snapshot::validate_manifest(
+                    manifest,
+                    crate::GUEST_ARCH,
+                    memory,
+                    processors,
+                    crate::system_page_size(),
+                )?;
+                openvmm_helpers::snapshot::validate_disks(manifest, &disks)
+            })
+            .await
+    }
+
+    async fn handle_save_snapshot(&self, dir: &Path, portable: bool) -> anyhow::Result<()> {
         let memory_file_path = self
             .memory_backing_file
             .as_ref()
             .context("save-snapshot requires --memory-backing-file")?;
 
-        // Pause the VM.
+        // Pause the VM. It stays paused after the save, so that the disks can
+        // be copied in the state the snapshot refers to before resuming.
         self.vm_rpc
             .call(VmRpc::Pause, ())
             .await
@@ -464,23 +629,35 @@ async fn handle_save_snapshot(&self, dir: &Path) -> anyhow::Result<()> {
         // Serialize the ProtobufMessage to bytes for writing to disk.
         let saved_state_bytes = mesh::payload::encode(saved_state_msg);
 
+        // Build manifest. The disks are recorded relative to the snapshot
+        // directory, so it must exist first.
+        fs_err::create_dir_all(dir)?;
+        let manifest = openvmm_helpers::snapshot::SnapshotManifest {
+            compressed_memory: portable,
+            vm_args: self.vm_args.clone(),
+            configured_disks: openvmm_helpers::snapshot::record_disks(
+                dir,
+                &crate::snapshot_disks(&self.configured_disks)?,
+            )?,
+            ..self.snapshot_manifest()?
+        };
+
+        if portable {
+            // Copy guest RAM into the snapshot.
+            return openvmm_helpers::snapshot::write_portable_snapshot(
+                dir,
+                &manifest,
+                &saved_state_bytes,
+                memory_file_path,
+            );
+        }
+
         // Fsync the memory backing file.
         let memory_file = fs_err::File::open(memory_file_path)?;
         memory_file
             .sync_all()
             .context("failed to fsync memory backing file")?;
 
-        // Build manifest.
-        let manifest = openvmm_helpers::snapshot::SnapshotManifest {
-            version: openvmm_helpers::snapshot::MANIFEST_VERSION,
-            created_at: std::time::SystemTime::now().into(),
-            openvmm_version: env!("CARGO_PKG_VERSION").to_string(),
-            memory_size_bytes: self.memory,
-            vp_count: self.processors,
-            page_size: crate::system_page_size(),
-            architecture: crate::GUEST_ARCH.to_string(),
-        };
-
         // Write snapshot directory.
         openvmm_helpers::snapshot::write_snapshot(
             dir,
@@ -493,6 +670,39 @@ async fn handle_save_snapshot(&self, dir: &Path) -> anyhow::Result<()> {
         Ok(())
     }
 
+    async fn handle_restore_snapshot(&self, dir: &Path) -> anyhow::Result<()> {
+        let memory_file_path = self
+            .memory_backing_file
+            .as_ref()
+            .context("restore-snapshot requires a memory backing file")?;
+
+        let (manifest, state_bytes) = openvmm_helpers::snapshot::read_snapshot(dir)?;
+        openvmm_helpers::snapshot::validate_manifest(
+            &manifest,
+            crate::GUEST_ARCH,
+            self.memory,
+            self.processors,
+            crate::system_page_size(),
+        )?;
+        openvmm_helpers::snapshot::validate_disks(
+            &manifest,
+            &crate::snapshot_disks(&self.configured_disks)?,
+        )?;
+        let state: mesh::payload::message::ProtobufMessage =
+            mesh::payload::decode(&state_bytes).context("failed to decode saved state")?;
+
+        crate::with_vm_paused(&self.vm_rpc, async {
+            openvmm_helpers::snapshot::restore_memory(dir, &manifest, memory_file_path)?;
+
+            self.vm_rpc
+                .call_failable(VmRpc::Restore, state)
+                .await
+                .context("failed to restore device state")?;
+            anyhow::Ok(())
+        })
+        .await
+    }
+
     async fn handle_dump_state(&self, path: &Path) -> anyhow::Result<()> {
         // Write to a temporary file in the same directory, then rename into
         // place so readers never see a partially-written dump.
@@ -4,9 +4,15 @@
 //! Guest disk helpers.
 
 use anyhow::Context;
+use disk_backend_resources::layer::DiskLayerHandle;
+use disk_backend_resources::layer::Qcow2DiskLayerHandle;
+use disk_backend_resources::layer::Vhd1DiskLayerHandle;
+use disk_backend_resources::layer::VhdxDiskLayerHandle;
 use std::path::Path;
+use std::path::PathBuf;
 use vm_resource::Resource;
 use vm_resource::kind::DiskHandleKind;
+use vm_resource::kind::DiskLayerHandleKind;
 
 fn disk_open_error(path: &Path, verb: &str) -> String {
     let mut msg = format!("{verb} '{}'", path.display());
@@ -34,8 +40,13 @@ pub struct OpenDiskOptions {
 /// Opens the resources needed for using a disk from a file at `path`.
 ///
 /// If the file ends with .vhd and is a fixed VHD1, it will be opened using
-/// the user-mode VHD parser. Otherwise, if the file ends with .vhd or
-/// .vhdx, the file will be opened using the kernel-mode VHD parser.
+/// the user-mode VHD parser. Otherwise, on Windows, if the file ends with .vhd
+/// or .vhdx, the file will be opened using the kernel-mode VHD parser. On
+/// other hosts, dynamic .vhd and all .vhdx files are opened with the
+/// user-mode VHD1 and VHDX parsers, along with their chain of differencing
+/// parents. If the file ends with .qcow2, it
+/// will be opened with the QCOW2 parser, along with its chain of backing
+/// files.
 pub async fn open_disk_type(
     path: &Path,
     options: OpenDiskOptions,
@@ -74,7 +85,10 @@ pub async fn open_disk_type(
                         ))
                     }
                     #[cfg(not(windows))]
-                    anyhow::bail!("non-fixed VHD not supported on Linux");
+                    {
+                        ensure_no_direct("dynamic .vhd")?;
+                        open_vhd1_disk(path, read_only)?
+                    }
                 }
                 Err(err) => return Err(err.into()),
             }
@@ -91,7 +105,14 @@ pub async fn open_disk_type(
                 ))
             }
             #[cfg(not(windows))]
-            anyhow::bail!("VHDX not supported on Linux");
+            {
+                ensure_no_direct(".vhdx")?;
+                open_vhdx_disk(path, read_only)?
+            }
+        }
+        Some("qcow2") => {
+            ensure_no_direct(".qcow2")?;
+            open_qcow2_disk(path, read_only)?
         }
         Some("iso") if !read_only => {
             anyhow::bail!("iso file cannot be opened as read/write")
@@ -136,7 +157,16 @@ pub fn create_disk_type(
             Resource::new(disk_backend_resources::FixedVhd1DiskHandle(file))
         }
         Some("vhdx") => {
-            anyhow::bail!("creating vhdx not supported")
+            if options.direct {
+                anyhow::bail!("direct I/O is not supported for VHDX files");
+            }
+            create_vhdx_disk(path, size)?
+        }
+        Some("qcow2") => {
+            if options.direct {
+                anyhow::bail!("direct I/O is not supported for QCOW2 files");
+            }
+            create_qcow2_disk(path, size)?
         }
         Some("iso") => {
             anyhow::bail!("creating iso not supported")
@@ -145,6 +175,482 @@ pub fn create_disk_type(
     })
 }
 
+/// The maximum length of a QCOW2 backing file chain.
+const MAX_QCOW2_CHAIN_DEPTH: usize = 64;
+
+/// Returns true if the file at `path` starts with the QCOW2 magic number.
+fn is_qcow2(path: &Path) -> anyhow::Result<bool> {
+    use std::io::Read;
+
+    let mut magic = [0; 4];
+    let mut file =
+        fs_err::File::open(path).with_context(|| disk_open_error(path, "failed to open"))?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(u32::from_be_bytes(magic) == disk_qcow2::format::MAGIC),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Opens a QCOW2 image at `path`, along with its chain of backing files.
+///
+/// Backing files are always opened read-only. Relative backing file paths are
+/// interpreted relative to the directory containing the image that refers to
+/// them.
+pub fn open_qcow2_disk(path: &Path, read_only: bool) -> anyhow::Result<Resource<DiskHandleKind>> {
+    open_qcow2_chain(path, read_only, 0)
+}
+
+fn open_qcow2_chain(
+    path: &Path,
+    read_only: bool,
+    depth: usize,
+) -> anyhow::Result<Resource<DiskHandleKind>> {
+    if depth >= MAX_QCOW2_CHAIN_DEPTH {
+        anyhow::bail!("qcow2 backing file chain is too long");
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(path)
+        .with_context(|| disk_open_error(path, "failed to open"))?;
+
+    let backing = qcow2_backing_file(path, &file)?
+        .map(|(backing_path, is_qcow2)| {
+            if is_qcow2 {
+                open_qcow2_chain(&backing_path, true, depth + 1)
+            } else {
+                open_raw_disk(
+                    &backing_path,
+                    OpenDiskOptions {
+                        read_only: true,
+                        direct: false,
+                    },
+                    None,
+                )
+            }
+        })
+        .transpose()?;
+
+    Ok(Resource::new(disk_backend_resources::Qcow2DiskHandle {
+        file,
+        backing,
+    }))
+}
+
+/// Returns the path of the backing file of the QCOW2 image `file` at `path`,
+/// and whether the backing file is itself a QCOW2 image (rather than a raw
+/// file).
+fn qcow2_backing_file(
+    path: &Path,
+    file: &std::fs::File,
+) -> anyhow::Result<Option<(PathBuf, bool)>> {
+    let Some(backing) = disk_qcow2::read_backing_file(file)
+        .with_context(|| format!("failed to read qcow2 header of '{}'", path.display()))?
+    else {
+        return Ok(None);
+    };
+    let backing_path = path.parent().unwrap_or(Path::new("")).join(&backing.path);
+    let is_qcow2 = match backing.format.as_deref() {
+        Some("qcow2") => true,
+        Some("raw") => false,
+        None => is_qcow2(&backing_path)?,
+        Some(format) => anyhow::bail!(
+            "unsupported backing file format '{format}' for '{}'",
+            path.display()
+        ),
+    };
+    Ok(Some((backing_path, is_qcow2)))
+}
+
+/// Creates a new, empty QCOW2 image at `path` with a virtual size of `size`
+/// bytes.
+pub fn create_qcow2_disk(path: &Path, size: u64) -> anyhow::Result<Resource<DiskHandleKind>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| disk_open_error(path, "failed to create"))?;
+
+    disk_qcow2::format(&file, &disk_qcow2::FormatParams::new(size))?;
+    Ok(Resource::new(disk_backend_resources::Qcow2DiskHandle {
+        file,
+        backing: None,
+    }))
+}
+
+/// The maximum length of a VHD1 differencing disk chain.
+const MAX_VHD1_CHAIN_DEPTH: usize = 64;
+
+/// Opens a VHD1 image of any type at `path`, along with its chain of
+/// differencing parents.
+///
+/// Parents are always opened read-only. Each parent is located using the
+/// child's parent locators: the relative path is tried first (relative to the
+/// directory containing the child), followed by the absolute path. A
+/// candidate is only used if its unique ID matches the one recorded in the
+/// child.
+pub fn open_vhd1_disk(path: &Path, read_only: bool) -> anyhow::Result<Resource<DiskHandleKind>> {
+    open_vhd1_chain(path, read_only, 0)
+}
+
+fn open_vhd1_chain(
+    path: &Path,
+    read_only: bool,
+    depth: usize,
+) -> anyhow::Result<Resource<DiskHandleKind>> {
+    if depth >= MAX_VHD1_CHAIN_DEPTH {
+        anyhow::bail!("vhd differencing disk chain is too long");
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(path)
+        .with_context(|| disk_open_error(path, "failed to open"))?;
+
+    let info = disk_vhd1::read_info(&file)
+        .with_context(|| format!("failed to read vhd metadata of '{}'", path.display()))?;
+    let parent = info
+        .parent_locator
+        .map(|locator| -> anyhow::Result<_> {
+            let parent_path = find_vhd1_parent(path, &locator)?;
+            open_vhd1_chain(&parent_path, true, depth + 1)
+        })
+        .transpose()?;
+
+    Ok(Resource::new(disk_backend_resources::Vhd1DiskHandle {
+        file,
+        parent,
+    }))
+}
+
+/// Finds the parent of the differencing disk at `path`.
+fn find_vhd1_parent(
+    path: &Path,
+    locator: &disk_vhd1::ParentLocator,
+) -> anyhow::Result<std::path::PathBuf> {
+    let relative = locator
+        .relative_path
+        .as_deref()
+        .map(|p| path.parent().unwrap_or(Path::new("")).join(to_host_path(p)));
+    let absolute = locator.absolute_path.as_deref().map(to_host_path);
+
+    // A stale copy of the parent may be found before the real one, so keep
+    // looking after a mismatch.
+    let mut mismatched = Vec::new();
+    for candidate in relative.into_iter().chain(absolute) {
+        let Ok(file) = fs_err::File::open(&candidate) else {
+            continue;
+        };
+        let info = disk_vhd1::read_info(file.file())
+            .with_context(|| format!("failed to read vhd metadata of '{}'", candidate.display()))?;
+        if info.unique_id != locator.unique_id {
+            tracing::debug!(
+                candidate = %candidate.display(),
+                "vhd parent candidate does not match the differencing disk"
+            );
+            mismatched.push(candidate);
+            continue;
+        }
+        return Ok(candidate);
+    }
+    if !mismatched.is_empty() {
+        anyhow::bail!(
+            "no parent of '{}' matches the differencing disk's parent ID (checked {})",
+            path.display(),
+            mismatched
+                .iter()
+                .map(|p| format!("'{}'", p.display()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    anyhow::bail!(
+        "could not find the parent of '{}' (relative path {:?}, absolute path {:?})",
+        path.display(),
+        locator.relative_path,
+        locator.absolute_path,
+    )
+}
+
+/// Converts a path from a parent locator, which uses Windows path separators,
+/// to a host path.
+fn to_host_path(p: &str) -> std::path::PathBuf {
+    if cfg!(windows) {
+        std::path::PathBuf::from(p)
+    } else {
+        std::path::PathBuf::from(p.replace('\\', "/"))
+    }
+}
+
+/// The maximum length of a VHDX differencing disk chain.
+const MAX_VHDX_CHAIN_DEPTH: usize = 64;
+
+/// Opens a VHDX image at `path`, along with its chain of differencing parents.
+///
+/// Parents are always opened read-only. Each parent is located using the
+/// child's parent locator: the relative path is tried first (relative to the
+/// directory containing the child), followed by the absolute paths. A
+/// candidate is only used if its data write GUID matches the child's parent
+/// linkage.
+pub fn open_vhdx_disk(path: &Path, read_only: bool) -> anyhow::Result<Resource<DiskHandleKind>> {
+    open_vhdx_chain(path, read_only, 0)
+}
+
+fn open_vhdx_chain(
+    path: &Path,
+    read_only: bool,
+    depth: usize,
+) -> anyhow::Result<Resource<DiskHandleKind>> {
+    if depth >= MAX_VHDX_CHAIN_DEPTH {
+        anyhow::bail!("vhdx differencing disk chain is too long");
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(path)
+        .with_context(|| disk_open_error(path, "failed to open"))?;
+
+    let info = disk_vhdx::read_info(&file)
+        .with_context(|| format!("failed to read vhdx metadata of '{}'", path.display()))?;
+    let parent = info
+        .parent_locator
+        .map(|locator| -> anyhow::Result<_> {
+            let parent_path = find_vhdx_parent(path, &locator)?;
+            open_vhdx_chain(&parent_path, true, depth + 1)
+        })
+        .transpose()?;
+
+    Ok(Resource::new(disk_backend_resources::VhdxDiskHandle {
+        file,
+        parent,
+    }))
+}
+
+/// Finds the parent of the differencing disk at `path`.
+fn find_vhdx_parent(
+    path: &Path,
+    locator: &disk_vhdx::ParentLocator,
+) -> anyhow::Result<std::path::PathBuf> {
+    let relative = locator
+        .relative_path
+        .as_deref()
+        .map(|p| path.parent().unwrap_or(Path::new("")).join(to_host_path(p)));
+    let absolute = [&locator.absolute_win32_path, &locator.volume_path]
+        .into_iter()
+        .filter_map(|p| p.as_deref().map(to_host_path));
+
+    // A stale copy of the parent may be found before the real one, so keep
+    // looking after a mismatch.
+    let mut modified = Vec::new();
+    for candidate in relative.into_iter().chain(absolute) {
+        let Ok(file) = fs_err::File::open(&candidate) else {
+            continue;
+        };
+        let info = disk_vhdx::read_info(file.file()).with_context(|| {
+            format!("failed to read vhdx metadata of '{}'", candidate.display())
+        })?;
+        if !locator.matches(&info.data_write_guid) {
+            tracing::debug!(
+                candidate = %candidate.display(),
+                "vhdx parent candidate does not match the differencing disk"
+            );
+            modified.push(candidate);
+            continue;
+        }
+        return Ok(candidate);
+    }
+    if !modified.is_empty() {
+        anyhow::bail!(
+            "parent of '{}' has been modified since the differencing disk was created (checked {})",
+            path.display(),
+            modified
+                .iter()
+                .map(|p| format!("'{}'", p.display()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    anyhow::bail!(
+        "could not find the parent of '{}' (relative path {:?}, absolute path {:?})",
+        path.display(),
+        locator.relative_path,
+        locator.absolute_win32_path,
+    )
+}
+
+/// Opens the disk file at `path` as a stack of disk layers, top first.
+///
+/// Unlike [`open_disk_type`], dynamic and differencing .vhd files, .vhdx
+/// files, and .qcow2 files are always opened with the user-mode parsers, as
+/// native disk layers. Each image in the chain of parents or backing files
+/// becomes a separate layer, so that the sectors present in each image can be
+/// determined. Other files are opened with [`open_disk_type`] as a single
+/// layer.
+///
+/// Only the layer at index `writable_layer` of the returned stack, if any, is
+/// opened for writing. The rest are opened read only, so that images that
+/// can only be written to as the top of a stack, such as QCOW2 images with
+/// clusters larger than a sector, can still be opened as lower layers.
+pub async fn open_disk_type_layers(
+    path: &Path,
+    writable_layer: Option<usize>,
+) -> anyhow::Result<Vec<Resource<DiskLayerHandleKind>>> {
+    match path.extension().and_then(|s| s.to_str()) {
+        Some("vhd") => open_vhd1_layers(path, writable_layer),
+        Some("vhdx") => open_vhdx_layers(path, writable_layer),
+        Some("qcow2") => open_qcow2_layers(path, writable_layer),
+        _ => {
+            let disk = open_disk_type(
+                path,
+                OpenDiskOptions {
+                    read_only: writable_layer != Some(0),
+                    direct: false,
+                },
+            )
+            .await?;
+            Ok(vec![Resource::new(DiskLayerHandle(disk))])
+        }
+    }
+}
+
+/// Opens a QCOW2 image at `path` and its chain of backing files as a stack of
+/// disk layers, top first. A raw backing file becomes the bottom layer.
+///
+/// Only the layer at index `writable_layer`, if any, is opened for writing.
+pub fn open_qcow2_layers(
+    path: &Path,
+    writable_layer: Option<usize>,
+) -> anyhow::Result<Vec<Resource<DiskLayerHandleKind>>> {
+    let mut layers = Vec::new();
+    let mut path = path.to_owned();
+    loop {
+        if layers.len() >= MAX_QCOW2_CHAIN_DEPTH {
+            anyhow::bail!("qcow2 backing file chain is too long");
+        }
+        let read_only = writable_layer != Some(layers.len());
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(&path)
+            .with_context(|| disk_open_error(&path, "failed to open"))?;
+        let backing = qcow2_backing_file(&path, &file)?;
+        layers.push(Resource::new(Qcow2DiskLayerHandle { file, format: None }));
+        match backing {
+            Some((backing_path, true)) => path = backing_path,
+            Some((backing_path, false)) => {
+                let disk = open_raw_disk(
+                    &backing_path,
+                    OpenDiskOptions {
+                        read_only: writable_layer != Some(layers.len()),
+                        direct: false,
+                    },
+                    None,
+                )?;
+                layers.push(Resource::new(DiskLayerHandle(disk)));
+                break;
+            }
+            None => break,
+        }
+    }
+    Ok(layers)
+}
+
+/// Opens a VHD1 image at `path` and its chain of differencing parents as a
+/// stack of disk layers, top first. A fixed VHD becomes the bottom layer.
+///
+/// Only the layer at index `writable_layer`, if any, is opened for writing.
+pub fn open_vhd1_layers(
+    path: &Path,
+    writable_layer: Option<usize>,
+) -> anyhow::Result<Vec<Resource<DiskLayerHandleKind>>> {
+    let mut layers = Vec::new();
+    let mut path = path.to_owned();
+    loop {
+        if layers.len() >= MAX_VHD1_CHAIN_DEPTH {
+            anyhow::bail!("vhd differencing disk chain is too long");
+        }
+        let read_only = writable_layer != Some(layers.len());
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(&path)
+            .with_context(|| disk_open_error(&path, "failed to open"))?;
+        let info = disk_vhd1::read_info(&file)
+            .with_context(|| format!("failed to read vhd metadata of '{}'", path.display()))?;
+        if info.disk_type == disk_vhd1::Vhd1Type::Fixed {
+            let disk = Resource::new(disk_backend_resources::FixedVhd1DiskHandle(file));
+            layers.push(Resource::new(DiskLayerHandle(disk)));
+            break;
+        }
+        let parent = info
+            .parent_locator
+            .map(|locator| find_vhd1_parent(&path, &locator))
+            .transpose()?;
+        layers.push(Resource::new(Vhd1DiskLayerHandle { file }));
+        match parent {
+            Some(parent) => path = parent,
+            None => break,
+        }
+    }
+    Ok(layers)
+}
+
+/// Opens a VHDX image at `path` and its chain of differencing parents as a
+/// stack of disk layers, top first.
+///
+/// Only the layer at index `writable_layer`, if any, is opened for writing.
+pub fn open_vhdx_layers(
+    path: &Path,
+    writable_layer: Option<usize>,
+) -> anyhow::Result<Vec<Resource<DiskLayerHandleKind>>> {
+    let mut layers = Vec::new();
+    let mut path = path.to_owned();
+    loop {
+        if layers.len() >= MAX_VHDX_CHAIN_DEPTH {
+            anyhow::bail!("vhdx differencing disk chain is too long");
+        }
+        let read_only = writable_layer != Some(layers.len());
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(&path)
+            .with_context(|| disk_open_error(&path, "failed to open"))?;
+        let info = disk_vhdx::read_info(&file)
+            .with_context(|| format!("failed to read vhdx metadata of '{}'", path.display()))?;
+        let parent = info
+            .parent_locator
+            .map(|locator| find_vhdx_parent(&path, &locator))
+            .transpose()?;
+        layers.push(Resource::new(VhdxDiskLayerHandle { file }));
+        match parent {
+            Some(parent) => path = parent,
+            None => break,
+        }
+    }
+    Ok(layers)
+}
+
+/// Creates a new, empty dynamic VHDX image at `path` with a virtual size of
+/// `size` bytes.
+pub fn create_vhdx_disk(path: &Path, size: u64) -> anyhow::Result<Resource<DiskHandleKind>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| disk_open_error(path, "failed to create"))?;
+
+    disk_vhdx::format(&file, &disk_vhdx::FormatParams::new(size))?;
+    Ok(Resource::new(disk_backend_resources::VhdxDiskHandle {
+        file,
+        parent: None,
+    }))
+}
+
 /// Open or create a raw file or block device, returning the appropriate
 /// disk resource for the current platform.
 fn open_raw_disk(
@@ -193,3 +699,80 @@ fn open_raw_disk(
         Ok(Resource::new(disk_backend_resources::FileDiskHandle(file)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_vhdx(path: &Path) -> disk_vhdx::VhdxInfo {
+        let file = fs_err::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .unwrap();
+        disk_vhdx::format(file.file(), &disk_vhdx::FormatParams::new(0x100000)).unwrap();
+        disk_vhdx::read_info(file.file()).unwrap()
+    }
+
+    fn create_vhd1(path: &Path) -> disk_vhd1::Vhd1Info {
+        let file = fs_err::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .unwrap();
+        disk_vhd1::format_dynamic(file.file(), &disk_vhd1::FormatParams::new(0x100000)).unwrap();
+        disk_vhd1::read_info(file.file()).unwrap()
+    }
+
+    #[test]
+    fn vhd1_parent_skips_mismatched_candidate() {
+        let dir = tempfile::tempdir().unwrap();
+        let child = dir.path().join("child.vhd");
+        let stale = dir.path().join("parent.vhd");
+        std::fs::create_dir(dir.path().join("real")).unwrap();
+        let real = dir.path().join("real/parent.vhd");
+        create_vhd1(&stale);
+        let info = create_vhd1(&real);
+
+        let mut locator = disk_vhd1::ParentLocator {
+            unique_id: info.unique_id,
+            time_stamp: info.time_stamp,
+            name: "parent.vhd".into(),
+            relative_path: Some("parent.vhd".into()),
+            absolute_path: Some(real.to_str().unwrap().into()),
+        };
+        assert_eq!(find_vhd1_parent(&child, &locator).unwrap(), real);
+
+        // Only the mismatched copy can be found.
+        locator.absolute_path = None;
+        let err = find_vhd1_parent(&child, &locator).unwrap_err();
+        assert!(err.to_string().contains("parent ID"), "{err:#}");
+    }
+
+    #[test]
+    fn vhdx_parent_skips_modified_candidate() {
+        let dir = tempfile::tempdir().unwrap();
+        let child = dir.path().join("child.vhdx");
+        let stale = dir.path().join("parent.vhdx");
+        std::fs::create_dir(dir.path().join("real")).unwrap();
+        let real = dir.path().join("real/parent.vhdx");
+        create_vhdx(&stale);
+        let parent_linkage = create_vhdx(&real).data_write_guid;
+
+        let mut locator = disk_vhdx::ParentLocator {
+            parent_linkage,
+            parent_linkage2: None,
+            relative_path: Some("parent.vhdx".into()),
+            volume_path: None,
+            absolute_win32_path: Some(real.to_str().unwrap().into()),
+        };
+        assert_eq!(find_vhdx_parent(&child, &locator).unwrap(), real);
+
+        // Only the modified copy can be found.
+        locator.absolute_win32_path = None;
+        let err = find_vhdx_parent(&child, &locator).unwrap_err();
+        assert!(err.to_string().contains("has been modified"), "{err:#}");
+    }
+}
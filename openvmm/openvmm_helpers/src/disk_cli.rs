@@ -0,0 +1,857 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Parsing and opening disks specified with the OpenVMM `--disk` syntax,
+//! shared by the OpenVMM entry points.
+
+use crate::disk::OpenDiskOptions;
+use crate::disk::create_disk_type;
+use crate::disk::create_qcow2_disk;
+use crate::disk::create_vhdx_disk;
+use crate::disk::open_disk_type;
+use crate::disk::open_disk_type_layers;
+use crate::disk::open_qcow2_disk;
+use crate::disk::open_qcow2_layers;
+use crate::disk::open_vhdx_disk;
+use crate::disk::open_vhdx_layers;
+use crate::snapshot::SnapshotDiskLayer;
+use anyhow::Context;
+use disk_backend_resources::BlobDiskFormat;
+use disk_backend_resources::BlobDiskHandle;
+use disk_backend_resources::DelayDiskHandle;
+use disk_backend_resources::DiskLayerDescription;
+use disk_backend_resources::DiskWithReservationsHandle;
+use disk_backend_resources::LayeredDiskHandle;
+use disk_backend_resources::LayeredDiskRequest;
+use disk_backend_resources::layer::DiskLayerHandle;
+use disk_backend_resources::layer::Qcow2DiskLayerFormatParams;
+use disk_backend_resources::layer::Qcow2DiskLayerHandle;
+use disk_backend_resources::layer::RamDiskLayerHandle;
+use disk_backend_resources::layer::SqliteAutoCacheDiskLayerHandle;
+use disk_backend_resources::layer::SqliteDiskLayerFormatParams;
+use disk_backend_resources::layer::SqliteDiskLayerHandle;
+use futures::future::BoxFuture;
+use mesh::CellUpdater;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use vm_resource::IntoResource;
+use vm_resource::Resource;
+use vm_resource::kind::DiskHandleKind;
+use vm_resource::kind::DiskLayerHandleKind;
+
+/// Parses a size such as `1G` or `512MB`, or `VMGS_DEFAULT` for the default
+/// VMGS file size.
+pub fn parse_memory(s: &str) -> anyhow::Result<u64> {
+    if s == "VMGS_DEFAULT" {
+        Ok(vmgs_format::VMGS_DEFAULT_CAPACITY)
+    } else {
+        || -> Option<u64> {
+            let mut b = s.as_bytes();
+            if s.ends_with('B') {
+                b = &b[..b.len() - 1]
+            }
+            if b.is_empty() {
+                return None;
+            }
+            let multi = match b[b.len() - 1] as char {
+                'T' => Some(1024 * 1024 * 1024 * 1024),
+                'G' => Some(1024 * 1024 * 1024),
+                'M' => Some(1024 * 1024),
+                'K' => Some(1024),
+                _ => None,
+            };
+            if multi.is_some() {
+                b = &b[..b.len() - 1]
+            }
+            let n: u64 = std::str::from_utf8(b).ok()?.parse().ok()?;
+            n.checked_mul(multi.unwrap_or(1))
+        }()
+        .with_context(|| format!("invalid memory size '{0}'", s))
+    }
+}
+
+/// A disk, as specified with the OpenVMM `--disk` syntax.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiskCliKind {
+    /// `mem:<len>`: a RAM-backed disk.
+    Memory(u64),
+    /// `memdiff:<kind>`: a RAM-backed differencing layer.
+    MemoryDiff(Box<DiskCliKind>),
+    /// `sql:<path>[;create=<len>]`: a sqlite disk.
+    Sqlite {
+        /// The path to the sqlite file.
+        path: PathBuf,
+        /// If set, create a new disk of this size.
+        create_with_len: Option<u64>,
+    },
+    /// `sqldiff:<path>[;create]:<kind>`: a sqlite differencing layer.
+    SqliteDiff {
+        /// The path to the sqlite file.
+        path: PathBuf,
+        /// Create a new layer.
+        create: bool,
+        /// The disk underneath the layer.
+        disk: Box<DiskCliKind>,
+    },
+    /// `autocache:[key]:<kind>`: a sqlite read cache in front of another
+    /// disk.
+    AutoCacheSqlite {
+        /// The directory holding the cache.
+        cache_path: String,
+        /// The cache key, if not derived from the disk.
+        key: Option<String>,
+        /// The cached disk.
+        disk: Box<DiskCliKind>,
+    },
+    /// `prwrap:<kind>`: emulated persistent reservations on another disk.
+    PersistentReservationsWrapper(Box<DiskCliKind>),
+    /// `file:<path>[;direct][;create=<len>]`: a raw, VHD, or VHDX file.
+    File {
+        /// The path to the file.
+        path: PathBuf,
+        /// If set, create a new disk of this size.
+        create_with_len: Option<u64>,
+        /// Bypass the OS page cache.
+        direct: bool,
+    },
+    /// `qcow2:<path>[;create=<len>]`: a QCOW2 file.
+    Qcow2 {
+        /// The path to the file.
+        path: PathBuf,
+        /// If set, create a new disk of this size.
+        create_with_len: Option<u64>,
+    },
+    /// `qcow2diff:<path>[;create]:<kind>`: a QCOW2 differencing layer.
+    ///
+    /// An existing layer must allocate in units of one sector, as layers
+    /// created with `create` do.
+    Qcow2Diff {
+        /// The path to the file.
+        path: PathBuf,
+        /// Create a new layer.
+        create: bool,
+        /// The disk underneath the layer.
+        disk: Box<DiskCliKind>,
+    },
+    /// `vhdx:<path>[;create=<len>]`: a VHDX file.
+    Vhdx {
+        /// The path to the file.
+        path: PathBuf,
+        /// If set, create a new disk of this size.
+        create_with_len: Option<u64>,
+    },
+    /// `blob:<type>:<url>`: a read-only disk served over HTTP.
+    Blob {
+        /// The blob format.
+        kind: BlobKind,
+        /// The blob URL.
+        url: String,
+    },
+    /// `crypt:<cipher>:<key_file>:<kind>`: an encrypted view of another
+    /// disk.
+    Crypt {
+        /// The cipher.
+        cipher: DiskCipher,
+        /// The file holding the key.
+        key_file: PathBuf,
+        /// The encrypted disk.
+        disk: Box<DiskCliKind>,
+    },
+    /// `delay:<delay_ms>:<kind>`: another disk with added I/O latency.
+    DelayDiskWrapper {
+        /// The delay, in milliseconds.
+        delay_ms: u64,
+        /// The delayed disk.
+        disk: Box<DiskCliKind>,
+    },
+}
+
+/// The cipher of a `crypt:` disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiskCipher {
+    /// `xts-aes-256`.
+    XtsAes256,
+}
+
+impl FromStr for DiskCipher {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "xts-aes-256" => Ok(DiskCipher::XtsAes256),
+            _ => anyhow::bail!("invalid cipher '{s}', expected 'xts-aes-256'"),
+        }
+    }
+}
+
+/// The format of a `blob:` disk.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BlobKind {
+    /// A flat image.
+    Flat,
+    /// A fixed or dynamic VHD1 image.
+    Vhd1,
+}
+
+struct FileOpts {
+    path: PathBuf,
+    create_with_len: Option<u64>,
+    direct: bool,
+}
+
+fn parse_file_opts(arg: &str) -> anyhow::Result<FileOpts> {
+    let mut path = arg;
+    let mut create_with_len = None;
+    let mut direct = false;
+
+    // Parse semicolon-delimited options after the path.
+    if let Some((p, rest)) = arg.split_once(';') {
+        path = p;
+        for opt in rest.split(';') {
+            if let Some(len) = opt.strip_prefix("create=") {
+                create_with_len = Some(parse_memory(len)?);
+            } else if opt == "direct" {
+                direct = true;
+            } else {
+                anyhow::bail!("invalid file option '{opt}', expected 'create=<len>' or 'direct'");
+            }
+        }
+    }
+
+    Ok(FileOpts {
+        path: path.into(),
+        create_with_len,
+        direct,
+    })
+}
+
+struct DiffOpts {
+    path: PathBuf,
+    create: bool,
+    disk: Box<DiskCliKind>,
+}
+
+/// Parses the `<path>[;create]:<kind>` arguments of a differencing disk.
+fn parse_diff_opts(arg: &str) -> anyhow::Result<DiffOpts> {
+    let (path_and_opts, kind) = arg.split_once(':').context("expected path[;opts]:kind")?;
+    let disk = Box::new(kind.parse()?);
+    match path_and_opts.split_once(';') {
+        Some((path, create)) => {
+            if create != "create" {
+                anyhow::bail!("invalid syntax after ';', expected 'create'")
+            }
+            Ok(DiffOpts {
+                path: path.into(),
+                create: true,
+                disk,
+            })
+        }
+        None => Ok(DiffOpts {
+            path: path_and_opts.into(),
+            create: false,
+            disk,
+        }),
+    }
+}
+
+impl DiskCliKind {
+    /// Parse an `autocache:[key]:<kind>` disk spec, given the cache path
+    /// (normally read from `OPENVMM_AUTO_CACHE_PATH`).
+    pub fn parse_autocache(
+        arg: &str,
+        cache_path: Result<String, std::env::VarError>,
+    ) -> anyhow::Result<Self> {
+        let (key, kind) = arg.split_once(':').context("expected [key]:kind")?;
+        let cache_path = cache_path.context("must set cache path via OPENVMM_AUTO_CACHE_PATH")?;
+        Ok(DiskCliKind::AutoCacheSqlite {
+            cache_path,
+            key: (!key.is_empty()).then(|| key.to_string()),
+            disk: Box::new(kind.parse()?),
+        })
+    }
+
+    /// Describes the layers of the disk, top first, for recording in a
+    /// snapshot manifest.
+    pub fn snapshot_layers(&self) -> anyhow::Result<Vec<SnapshotDiskLayer>> {
+        fn layer(kind: &str, path: Option<&std::path::Path>) -> anyhow::Result<SnapshotDiskLayer> {
+            let (path, file_size) = match path {
+                Some(path) => {
+                    let path = fs_err::canonicalize(path)?;
+                    let file_size = fs_err::metadata(&path)?.len();
+                    (path.display().to_string(), file_size)
+                }
+                None => (String::new(), 0),
+            };
+            Ok(SnapshotDiskLayer {
+                kind: kind.to_string(),
+                path,
+                file_size,
+                content_hash: String::new(),
+            })
+        }
+
+        let mut layers = Vec::new();
+        let mut disk = self;
+        loop {
+            let next = match disk {
+                DiskCliKind::Memory(_) => {
+                    layers.push(layer("mem", None)?);
+                    None
+                }
+                DiskCliKind::MemoryDiff(inner) => {
+                    layers.push(layer("memdiff", None)?);
+                    Some(inner)
+                }
+                DiskCliKind::Sqlite { path, .. } => {
+                    layers.push(layer("sql", Some(path))?);
+                    None
+                }
+                DiskCliKind::SqliteDiff { path, disk, .. } => {
+                    layers.push(layer("sqldiff", Some(path))?);
+                    Some(disk)
+                }
+                DiskCliKind::AutoCacheSqlite { disk, .. } => {
+                    // The cache only holds copies of the disk underneath.
+                    Some(disk)
+                }
+                DiskCliKind::PersistentReservationsWrapper(inner) => Some(inner),
+                DiskCliKind::File { path, .. } => {
+                    layers.push(layer("file", Some(path))?);
+                    None
+                }
+                DiskCliKind::Qcow2 { path, .. } => {
+                    layers.push(layer("qcow2", Some(path))?);
+                    None
+                }
+                DiskCliKind::Qcow2Diff { path, disk, .. } => {
+                    layers.push(layer("qcow2diff", Some(path))?);
+                    Some(disk)
+                }
+                DiskCliKind::Vhdx { path, .. } => {
+                    layers.push(layer("vhdx", Some(path))?);
+                    None
+                }
+                DiskCliKind::Blob { url, .. } => {
+                    layers.push(SnapshotDiskLayer {
+                        kind: "blob".to_string(),
+                        path: url.clone(),
+                        file_size: 0,
+                        content_hash: String::new(),
+                    });
+                    None
+                }
+                DiskCliKind::Crypt { disk, .. } => {
+                    layers.push(layer("crypt", None)?);
+                    Some(disk)
+                }
+                DiskCliKind::DelayDiskWrapper { disk, .. } => Some(disk),
+            };
+            match next {
+                Some(next) => disk = next,
+                None => break,
+            }
+        }
+        Ok(layers)
+    }
+}
+
+impl FromStr for DiskCliKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let disk = match s.split_once(':') {
+            // convenience support for passing bare paths as file disks
+            None => {
+                let FileOpts {
+                    path,
+                    create_with_len,
+                    direct,
+                } = parse_file_opts(s)?;
+                DiskCliKind::File {
+                    path,
+                    create_with_len,
+                    direct,
+                }
+            }
+            Some((kind, arg)) => match kind {
+                "mem" => DiskCliKind::Memory(parse_memory(arg)?),
+                "memdiff" => DiskCliKind::MemoryDiff(Box::new(arg.parse()?)),
+                "sql" => {
+                    let FileOpts {
+                        path,
+                        create_with_len,
+                        direct,
+                    } = parse_file_opts(arg)?;
+                    if direct {
+                        anyhow::bail!("'direct' is not supported for 'sql' disks");
+                    }
+                    DiskCliKind::Sqlite {
+                        path,
+                        create_with_len,
+                    }
+                }
+                "sqldiff" => {
+                    let DiffOpts { path, create, disk } = parse_diff_opts(arg)?;
+                    DiskCliKind::SqliteDiff { path, create, disk }
+                }
+                "autocache" => {
+                    Self::parse_autocache(arg, std::env::var("OPENVMM_AUTO_CACHE_PATH"))?
+                }
+                "prwrap" => DiskCliKind::PersistentReservationsWrapper(Box::new(arg.parse()?)),
+                "file" => {
+                    let FileOpts {
+                        path,
+                        create_with_len,
+                        direct,
+                    } = parse_file_opts(arg)?;
+                    DiskCliKind::File {
+                        path,
+                        create_with_len,
+                        direct,
+                    }
+                }
+                "qcow2" => {
+                    let FileOpts {
+                        path,
+                        create_with_len,
+                        direct,
+                    } = parse_file_opts(arg)?;
+                    if direct {
+                        anyhow::bail!("'direct' is not supported for 'qcow2' disks");
+                    }
+                    DiskCliKind::Qcow2 {
+                        path,
+                        create_with_len,
+                    }
+                }
+                "qcow2diff" => {
+                    let DiffOpts { path, create, disk } = parse_diff_opts(arg)?;
+                    DiskCliKind::Qcow2Diff { path, create, disk }
+                }
+                "vhdx" => {
+                    let FileOpts {
+                        path,
+                        create_with_len,
+                        direct,
+                    } = parse_file_opts(arg)?;
+                    if direct {
+                        anyhow::bail!("'direct' is not supported for 'vhdx' disks");
+                    }
+                    DiskCliKind::Vhdx {
+                        path,
+                        create_with_len,
+                    }
+                }
+                "blob" => {
+                    let (blob_kind, url) = arg.split_once(':').context("expected kind:url")?;
+                    let blob_kind = match blob_kind {
+                        "flat" => BlobKind::Flat,
+                        "vhd1" => BlobKind::Vhd1,
+                        _ => anyhow::bail!("unknown blob kind {blob_kind}"),
+                    };
+                    DiskCliKind::Blob {
+                        kind: blob_kind,
+                        url: url.to_string(),
+                    }
+                }
+                "crypt" => {
+                    let (cipher, (key, kind)) = arg
+                        .split_once(':')
+                        .and_then(|(cipher, arg)| Some((cipher, arg.split_once(':')?)))
+                        .context("expected cipher:key_file:kind")?;
+                    DiskCliKind::Crypt {
+                        cipher: cipher.parse()?,
+                        key_file: PathBuf::from(key),
+                        disk: Box::new(kind.parse()?),
+                    }
+                }
+                kind => {
+                    // here's a fun edge case: what if the user passes `--disk d:\path\to\disk.img`?
+                    //
+                    // in this case, we actually want to treat that leading `d:` as part of the
+                    // path, rather than as a disk with `kind == 'd'`
+                    let FileOpts {
+                        path,
+                        create_with_len,
+                        direct,
+                    } = parse_file_opts(s)?;
+                    if path.has_root() {
+                        DiskCliKind::File {
+                            path,
+                            create_with_len,
+                            direct,
+                        }
+                    } else {
+                        anyhow::bail!("invalid disk kind {kind}");
+                    }
+                }
+            },
+        };
+        Ok(disk)
+    }
+}
+
+enum LayerOrDisk {
+    Layer(DiskLayerDescription),
+    Disk(Resource<DiskHandleKind>),
+}
+
+/// Opens the disk specified by `disk_cli`.
+pub async fn open_disk(
+    disk_cli: &DiskCliKind,
+    read_only: bool,
+) -> anyhow::Result<Resource<DiskHandleKind>> {
+    let mut layers = Vec::new();
+    open_disk_inner(disk_cli, (!read_only).then_some(0), false, &mut layers).await?;
+    Ok(take_single_disk(&mut layers).unwrap_or_else(|| layered_disk(layers, None)))
+}
+
+/// Opens `disk_cli` as [`open_disk`] does, returning a request channel for
+/// snapshotting and committing the disk's layers at runtime.
+///
+/// The channel is only returned if `snapshot` is set, in which case a disk
+/// that would not otherwise be layered is wrapped in a layered disk, or if
+/// `disk_cli` is a layered disk anyway.
+pub async fn open_disk_with_layer_control(
+    disk_cli: &DiskCliKind,
+    read_only: bool,
+    snapshot: bool,
+) -> anyhow::Result<(
+    Resource<DiskHandleKind>,
+    Option<mesh::Sender<LayeredDiskRequest>>,
+)> {
+    let mut layers = Vec::new();
+    open_disk_inner(disk_cli, (!read_only).then_some(0), false, &mut layers).await?;
+    if !snapshot && let Some(disk) = take_single_disk(&mut layers) {
+        return Ok((disk, None));
+    }
+    let (send, recv) = mesh::channel();
+    Ok((layered_disk(layers, Some(recv)), Some(send)))
+}
+
+/// Takes the disk out of `layers` if it consists of a single disk, which can
+/// be used directly rather than as a layered disk.
+fn take_single_disk(layers: &mut Vec<LayerOrDisk>) -> Option<Resource<DiskHandleKind>> {
+    if !matches!(layers.as_slice(), [LayerOrDisk::Disk(_)]) {
+        return None;
+    }
+    let Some(LayerOrDisk::Disk(disk)) = layers.pop() else {
+        unreachable!()
+    };
+    Some(disk)
+}
+
+/// Opens `disk_cli` as a stack of disk layers, top first, for offline
+/// inspection and modification.
+///
+/// Unlike [`open_disk`], image files are opened as native layers, with each
+/// image in their chain of parents or backing files as a separate layer (see
+/// [`open_disk_type_layers`]), so that the sectors present in each image can
+/// be determined.
+///
+/// Only the layer at index `writable_layer`, if any, is opened for writing,
+/// so that, for example, the top layer can be committed into the one below
+/// it without requiring the other layers to be writable.
+pub async fn open_disk_layers(
+    disk_cli: &DiskCliKind,
+    writable_layer: Option<usize>,
+) -> anyhow::Result<Vec<DiskLayerDescription>> {
+    let mut layers = Vec::new();
+    open_disk_inner(disk_cli, writable_layer, true, &mut layers).await?;
+    Ok(layers.into_iter().map(LayerOrDisk::into_layer).collect())
+}
+
+impl LayerOrDisk {
+    fn into_layer(self) -> DiskLayerDescription {
+        match self {
+            LayerOrDisk::Layer(layer) => layer,
+            LayerOrDisk::Disk(disk) => DiskLayerDescription {
+                layer: DiskLayerHandle(disk).into_resource(),
+                read_cache: false,
+                write_through: false,
+            },
+        }
+    }
+}
+
+fn layered_disk(
+    layers: Vec<LayerOrDisk>,
+    requests: Option<mesh::Receiver<LayeredDiskRequest>>,
+) -> Resource<DiskHandleKind> {
+    Resource::new(LayeredDiskHandle {
+        layers: layers.into_iter().map(LayerOrDisk::into_layer).collect(),
+        requests,
+    })
+}
+
+/// Appends the layers of `disk_cli` to `layers`.
+///
+/// Only the layer at index `writable_layer` of the full stack, if any, is
+/// opened for writing. If `image_layers` is set, image files are opened as
+/// native layers, as described in [`open_disk_layers`].
+fn open_disk_inner<'a>(
+    disk_cli: &'a DiskCliKind,
+    writable_layer: Option<usize>,
+    image_layers: bool,
+    layers: &'a mut Vec<LayerOrDisk>,
+) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        fn layer<T: IntoResource<DiskLayerHandleKind>>(layer: T) -> LayerOrDisk {
+            LayerOrDisk::Layer(layer.into_resource().into())
+        }
+        fn disk<T: IntoResource<DiskHandleKind>>(disk: T) -> LayerOrDisk {
+            LayerOrDisk::Disk(disk.into_resource())
+        }
+        let index = layers.len();
+        let read_only = writable_layer != Some(index);
+        // The writable layer's index within the stack of an image's layers,
+        // which starts at `index`.
+        let writable_image_layer = writable_layer.and_then(|w| w.checked_sub(index));
+        match disk_cli {
+            &DiskCliKind::Memory(len) => {
+                layers.push(layer(RamDiskLayerHandle {
+                    len: Some(len),
+                    sector_size: None,
+                }));
+            }
+            DiskCliKind::File {
+                path,
+                create_with_len,
+                direct,
+            } => {
+                // Direct I/O is only supported for disks, not image layers.
+                let image_layers = image_layers && !*direct;
+                if let Some(size) = create_with_len {
+                    let disk = create_disk_type(
+                        path,
+                        *size,
+                        OpenDiskOptions {
+                            read_only: false,
+                            direct: *direct,
+                        },
+                    )
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                    if !image_layers {
+                        layers.push(LayerOrDisk::Disk(disk));
+                        return Ok(());
+                    }
+                } else if !image_layers {
+                    let disk = open_disk_type(
+                        path,
+                        OpenDiskOptions {
+                            read_only,
+                            direct: *direct,
+                        },
+                    )
+                    .await
+                    .with_context(|| format!("failed to open {}", path.display()))?;
+                    layers.push(LayerOrDisk::Disk(disk));
+                    return Ok(());
+                }
+                // Reopen a newly created image as layers.
+                let writable = if create_with_len.is_some() {
+                    Some(0)
+                } else {
+                    writable_image_layer
+                };
+                for image in open_disk_type_layers(path, writable)
+                    .await
+                    .with_context(|| format!("failed to open {}", path.display()))?
+                {
+                    layers.push(LayerOrDisk::Layer(image.into()));
+                }
+            }
+            DiskCliKind::Qcow2 {
+                path,
+                create_with_len,
+            } => {
+                if let Some(size) = create_with_len {
+                    let disk = create_qcow2_disk(path, *size)
+                        .with_context(|| format!("failed to create {}", path.display()))?;
+                    if !image_layers {
+                        layers.push(LayerOrDisk::Disk(disk));
+                        return Ok(());
+                    }
+                } else if !image_layers {
+                    let disk = open_qcow2_disk(path, read_only)
+                        .with_context(|| format!("failed to open {}", path.display()))?;
+                    layers.push(LayerOrDisk::Disk(disk));
+                    return Ok(());
+                }
+                let writable = if create_with_len.is_some() {
+                    Some(0)
+                } else {
+                    writable_image_layer
+                };
+                for image in open_qcow2_layers(path, writable)
+                    .with_context(|| format!("failed to open {}", path.display()))?
+                {
+                    layers.push(LayerOrDisk::Layer(image.into()));
+                }
+            }
+            DiskCliKind::Vhdx {
+                path,
+                create_with_len,
+            } => {
+                if let Some(size) = create_with_len {
+                    let disk = create_vhdx_disk(path, *size)
+                        .with_context(|| format!("failed to create {}", path.display()))?;
+                    if !image_layers {
+                        layers.push(LayerOrDisk::Disk(disk));
+                        return Ok(());
+                    }
+                } else if !image_layers {
+                    let disk = open_vhdx_disk(path, read_only)
+                        .with_context(|| format!("failed to open {}", path.display()))?;
+                    layers.push(LayerOrDisk::Disk(disk));
+                    return Ok(());
+                }
+                let writable = if create_with_len.is_some() {
+                    Some(0)
+                } else {
+                    writable_image_layer
+                };
+                for image in open_vhdx_layers(path, writable)
+                    .with_context(|| format!("failed to open {}", path.display()))?
+                {
+                    layers.push(LayerOrDisk::Layer(image.into()));
+                }
+            }
+            DiskCliKind::Qcow2Diff { path, create, disk } => {
+                let file = if *create {
+                    fs_err::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create_new(true)
+                        .open(path)
+                        .context("failed to create qcow2 diff disk")?
+                } else {
+                    fs_err::OpenOptions::new()
+                        .read(true)
+                        .write(!read_only)
+                        .open(path)
+                        .context("failed to open qcow2 diff disk")?
+                };
+                layers.push(layer(Qcow2DiskLayerHandle {
+                    file: file.into(),
+                    format: create.then_some(Qcow2DiskLayerFormatParams { len: None }),
+                }));
+                open_disk_inner(disk, writable_layer, image_layers, layers).await?;
+            }
+            DiskCliKind::Blob { kind, url } => layers.push(disk(BlobDiskHandle {
+                url: url.to_owned(),
+                format: match kind {
+                    BlobKind::Flat => BlobDiskFormat::Flat,
+                    BlobKind::Vhd1 => BlobDiskFormat::Vhd1,
+                },
+            })),
+            DiskCliKind::MemoryDiff(inner) => {
+                layers.push(layer(RamDiskLayerHandle {
+                    len: None,
+                    sector_size: None,
+                }));
+                open_disk_inner(inner, writable_layer, image_layers, layers).await?;
+            }
+            DiskCliKind::PersistentReservationsWrapper(inner) => layers.push(disk(
+                DiskWithReservationsHandle(open_disk(inner, read_only).await?),
+            )),
+            DiskCliKind::DelayDiskWrapper {
+                delay_ms,
+                disk: inner,
+            } => layers.push(disk(DelayDiskHandle {
+                delay: CellUpdater::new(Duration::from_millis(*delay_ms)).cell(),
+                disk: open_disk(inner, read_only).await?,
+            })),
+            DiskCliKind::Crypt {
+                disk: inner,
+                cipher,
+                key_file,
+            } => layers.push(disk(disk_crypt_resources::DiskCryptHandle {
+                disk: open_disk(inner, read_only).await?,
+                cipher: match cipher {
+                    DiskCipher::XtsAes256 => disk_crypt_resources::Cipher::XtsAes256,
+                },
+                key: fs_err::read(key_file).context("failed to read key file")?,
+            })),
+            DiskCliKind::Sqlite {
+                path,
+                create_with_len,
+            } => {
+                // FUTURE: this code should be responsible for opening
+                // file-handle(s) itself, and passing them into sqlite via a custom
+                // vfs. For now though - simply check if the file exists or not, and
+                // perform early validation of filesystem-level create options.
+                match (create_with_len.is_some(), path.exists()) {
+                    (true, true) => anyhow::bail!(
+                        "cannot create new sqlite disk at {} - file already exists",
+                        path.display()
+                    ),
+                    (false, false) => anyhow::bail!(
+                        "cannot open sqlite disk at {} - file not found",
+                        path.display()
+                    ),
+                    _ => {}
+                }
+
+                layers.push(layer(SqliteDiskLayerHandle {
+                    dbhd_path: path.display().to_string(),
+                    format_dbhd: create_with_len.map(|len| SqliteDiskLayerFormatParams {
+                        logically_read_only: false,
+                        len: Some(len),
+                    }),
+                }));
+            }
+            DiskCliKind::SqliteDiff { path, create, disk } => {
+                // FUTURE: this code should be responsible for opening
+                // file-handle(s) itself, and passing them into sqlite via a custom
+                // vfs. For now though - simply check if the file exists or not, and
+                // perform early validation of filesystem-level create options.
+                match (create, path.exists()) {
+                    (true, true) => anyhow::bail!(
+                        "cannot create new sqlite disk at {} - file already exists",
+                        path.display()
+                    ),
+                    (false, false) => anyhow::bail!(
+                        "cannot open sqlite disk at {} - file not found",
+                        path.display()
+                    ),
+                    _ => {}
+                }
+
+                layers.push(layer(SqliteDiskLayerHandle {
+                    dbhd_path: path.display().to_string(),
+                    format_dbhd: create.then_some(SqliteDiskLayerFormatParams {
+                        logically_read_only: false,
+                        len: None,
+                    }),
+                }));
+                open_disk_inner(disk, writable_layer, image_layers, layers).await?;
+            }
+            DiskCliKind::AutoCacheSqlite {
+                cache_path,
+                key,
+                disk,
+            } => {
+                layers.push(LayerOrDisk::Layer(DiskLayerDescription {
+                    read_cache: true,
+                    write_through: false,
+                    layer: SqliteAutoCacheDiskLayerHandle {
+                        cache_path: cache_path.clone(),
+                        cache_key: key.clone(),
+                    }
+                    .into_resource(),
+                }));
+                // Writes pass through the cache to the disk below it, so that
+                // disk is writable if the cache is.
+                let writable_layer = if read_only {
+                    writable_layer
+                } else {
+                    Some(index + 1)
+                };
+                open_disk_inner(disk, writable_layer, image_layers, layers).await?;
+            }
+        }
+        Ok(())
+    })
+}
@@ -6,7 +6,9 @@
 #![forbid(unsafe_code)]
 
 pub mod disk;
+pub mod disk_cli;
 pub mod hypervisor;
 pub mod shared_memory;
 pub mod snapshot;
+pub mod snapshot_tree;
 pub mod underhill;
@@ -6,10 +6,30 @@
 use anyhow::Context;
 use mesh::payload::Protobuf;
 use mesh::payload::Timestamp;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
 use std::path::Path;
+use std::path::PathBuf;
 
 /// Current manifest format version. Bump when making incompatible changes.
-pub const MANIFEST_VERSION: u32 = 1;
+pub const MANIFEST_VERSION: u32 = 3;
+
+/// Oldest manifest format version that can still be restored.
+const MIN_MANIFEST_VERSION: u32 = 1;
+
+/// File name of the compressed memory image in a portable snapshot.
+const MEMORY_IMAGE: &str = "memory.zst";
+
+/// Magic number at the start of the decompressed memory image.
+const MEMORY_IMAGE_MAGIC: [u8; 8] = *b"OVMMEM01";
+
+/// Granularity of zero-page elision in the memory image.
+const MEMORY_IMAGE_PAGE_SIZE: usize = 4096;
+
+/// Maximum length of a single run of data in the memory image.
+const MEMORY_IMAGE_MAX_RUN: usize = 1024 * 1024;
 
 /// Manifest describing a VM snapshot.
 #[derive(Clone, Protobuf)]
@@ -36,6 +56,75 @@ pub struct SnapshotManifest {
     /// Architecture string ("x86_64" or "aarch64").
     #[mesh(7)]
     pub architecture: String,
+    /// Guest RAM is stored as a compressed image (`memory.zst`) rather than
+    /// as a link to the memory backing file (`memory.bin`).
+    #[mesh(8)]
+    pub compressed_memory: bool,
+    /// Command-line arguments that configured the VM, including its disks,
+    /// or empty if not recorded.
+    #[mesh(9)]
+    pub vm_args: Vec<String>,
+    /// User-visible name of the snapshot, or empty.
+    #[mesh(10)]
+    pub name: String,
+    /// Free-form description of the snapshot, or empty.
+    #[mesh(11)]
+    pub description: String,
+    /// ID of the parent snapshot in a snapshot tree, or empty if this is a
+    /// root or standalone snapshot.
+    #[mesh(12)]
+    pub parent: String,
+    /// Names of the layered disks whose changes since the parent snapshot are
+    /// stored alongside this snapshot.
+    #[mesh(13)]
+    pub disks: Vec<String>,
+    /// The layer stack of every disk the VM was configured with, or empty if
+    /// not recorded.
+    #[mesh(14)]
+    pub configured_disks: Vec<SnapshotDisk>,
+}
+
+/// A disk attached to a snapshotted VM.
+#[derive(Clone, Debug, PartialEq, Eq, Protobuf)]
+#[mesh(package = "openvmm.snapshot")]
+pub struct SnapshotDisk {
+    /// Where the disk is attached, such as `disk[0]` or `ide[1]`.
+    #[mesh(1)]
+    pub name: String,
+    /// The disk's layers, top first.
+    #[mesh(2)]
+    pub layers: Vec<SnapshotDiskLayer>,
+}
+
+/// One layer of a [`SnapshotDisk`].
+#[derive(Clone, Debug, PartialEq, Eq, Protobuf)]
+#[mesh(package = "openvmm.snapshot")]
+pub struct SnapshotDiskLayer {
+    /// The layer kind, as named in the disk syntax (`file`, `memdiff`, ...).
+    #[mesh(1)]
+    pub kind: String,
+    /// Path or URL of the layer's storage, or empty if the layer has none.
+    ///
+    /// For the running VM, the path of a file is canonical. In a manifest
+    /// written by [`record_disks`], it is relative to the snapshot directory
+    /// where possible.
+    #[mesh(2)]
+    pub path: String,
+    /// Size of the layer's file when the snapshot was taken, or zero.
+    #[mesh(3)]
+    pub file_size: u64,
+    /// Hex-encoded SHA-256 hash of the layer's file when the snapshot was
+    /// taken, or empty if not recorded.
+    #[mesh(4)]
+    pub content_hash: String,
+}
+
+impl SnapshotDiskLayer {
+    /// Returns whether the layer, as described for the running VM, is stored
+    /// in a file, whose path is then absolute.
+    fn is_file(&self) -> bool {
+        Path::new(&self.path).is_absolute()
+    }
 }
 
 /// Write a snapshot to the given directory.
@@ -103,15 +192,226 @@ pub fn write_snapshot(
     Ok(())
 }
 
+/// Write a self-contained snapshot to the given directory.
+///
+/// Unlike [`write_snapshot`], guest RAM is copied out of the memory backing
+/// file into a zstd-compressed image with zero pages elided, so the snapshot
+/// is not affected by later use of the backing file and can be copied to
+/// another filesystem or host. The snapshot consists of:
+/// - `manifest.bin` — protobuf-encoded [`SnapshotManifest`], with
+///   `compressed_memory` set
+/// - `state.bin` — raw device saved-state bytes
+/// - `memory.zst` — compressed memory image
+pub fn write_portable_snapshot(
+    dir: &Path,
+    manifest: &SnapshotManifest,
+    saved_state_bytes: &[u8],
+    memory_file_path: &Path,
+) -> anyhow::Result<()> {
+    fs_err::create_dir_all(dir)?;
+
+    // Write the memory image first so that a failure does not leave behind a
+    // manifest describing an incomplete snapshot.
+    let mut memory_file = fs_err::File::open(memory_file_path)?;
+    let size = memory_file.metadata()?.len();
+    write_memory_image(&dir.join(MEMORY_IMAGE), &mut memory_file, size)?;
+
+    // A stale memory.bin would otherwise be ignored silently.
+    let _ = fs_err::remove_file(dir.join("memory.bin"));
+
+    fs_err::write(dir.join("state.bin"), saved_state_bytes)?;
+
+    let manifest = SnapshotManifest {
+        compressed_memory: true,
+        ..manifest.clone()
+    };
+    fs_err::write(dir.join("manifest.bin"), mesh::payload::encode(manifest))?;
+
+    Ok(())
+}
+
+/// Writes `size` bytes of guest RAM from `memory` as a compressed memory image.
+///
+/// The decompressed image is a header (magic and RAM size) followed by runs of
+/// non-zero data, each prefixed by its offset and length, and terminated by a
+/// zero-length run. All integers are little endian.
+fn write_memory_image(path: &Path, memory: &mut impl Read, size: u64) -> anyhow::Result<()> {
+    let file = fs_err::File::create(path)?;
+    let mut encoder = zstd::Encoder::new(std::io::BufWriter::new(file), 0)?;
+    encoder.write_all(&MEMORY_IMAGE_MAGIC)?;
+    encoder.write_all(&size.to_le_bytes())?;
+
+    let mut buf = vec![0; MEMORY_IMAGE_MAX_RUN];
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(buf.len() as u64) as usize;
+        let chunk = &mut buf[..len];
+        memory
+            .read_exact(chunk)
+            .context("failed to read guest memory")?;
+
+        // Write each run of non-zero pages in the chunk.
+        let mut pages = chunk
+            .chunks(MEMORY_IMAGE_PAGE_SIZE)
+            .map(|page| page.iter().any(|&b| b != 0))
+            .enumerate()
+            .peekable();
+        while let Some((start, nonzero)) = pages.next() {
+            if !nonzero {
+                continue;
+            }
+            let mut end = start + 1;
+            while pages.next_if(|&(_, nonzero)| nonzero).is_some() {
+                end += 1;
+            }
+            let data =
+                &chunk[start * MEMORY_IMAGE_PAGE_SIZE..(end * MEMORY_IMAGE_PAGE_SIZE).min(len)];
+            encoder.write_all(&(offset + (start * MEMORY_IMAGE_PAGE_SIZE) as u64).to_le_bytes())?;
+            encoder.write_all(&(data.len() as u64).to_le_bytes())?;
+            encoder.write_all(data)?;
+        }
+        offset += len as u64;
+    }
+
+    encoder.write_all(&size.to_le_bytes())?;
+    encoder.write_all(&0u64.to_le_bytes())?;
+    encoder
+        .finish()?
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()
+        .context("failed to flush memory image")?;
+    Ok(())
+}
+
+/// Read a snapshot's compressed memory image into `file`.
+///
+/// `file` is resized to the guest RAM size and every byte of it is replaced:
+/// pages that are elided from the image are zeroed wherever `file` had
+/// existing contents. Beyond the file's original length, only non-zero data is
+/// written, so zero pages remain sparse where the filesystem supports it.
+pub fn read_memory_image(dir: &Path, file: &mut std::fs::File) -> anyhow::Result<()> {
+    let image = fs_err::File::open(dir.join(MEMORY_IMAGE))?;
+    let mut decoder = zstd::Decoder::new(image).context("failed to open memory image")?;
+
+    fn read_u64(decoder: &mut impl Read) -> anyhow::Result<u64> {
+        let mut v = [0; 8];
+        decoder
+            .read_exact(&mut v)
+            .context("memory image is truncated")?;
+        Ok(u64::from_le_bytes(v))
+    }
+
+    let mut magic = [0; 8];
+    decoder
+        .read_exact(&mut magic)
+        .context("memory image is truncated")?;
+    anyhow::ensure!(magic == MEMORY_IMAGE_MAGIC, "invalid memory image");
+    let size = read_u64(&mut decoder)?;
+    // Everything past the old length reads as zero once the file is extended.
+    let old_len = file.metadata()?.len();
+    file.set_len(size)?;
+
+    // Zeroes the stale contents of `file` in `pos..end`.
+    let zero_gap = |file: &mut std::fs::File, mut pos: u64, end: u64| -> anyhow::Result<()> {
+        let end = end.min(old_len);
+        let zeroes = [0; MEMORY_IMAGE_PAGE_SIZE];
+        if pos < end {
+            file.seek(SeekFrom::Start(pos))?;
+        }
+        while pos < end {
+            let len = (end - pos).min(zeroes.len() as u64) as usize;
+            file.write_all(&zeroes[..len])?;
+            pos += len as u64;
+        }
+        anyhow::Ok(())
+    };
+
+    let mut buf = vec![0; MEMORY_IMAGE_MAX_RUN];
+    let mut pos = 0;
+    loop {
+        let offset = read_u64(&mut decoder)?;
+        let len = read_u64(&mut decoder)?;
+        if len == 0 {
+            break;
+        }
+        anyhow::ensure!(
+            len <= MEMORY_IMAGE_MAX_RUN as u64
+                && offset >= pos
+                && offset.checked_add(len).is_some_and(|end| end <= size),
+            "invalid run {offset:#x}+{len:#x} in memory image"
+        );
+        let data = &mut buf[..len as usize];
+        decoder
+            .read_exact(data)
+            .context("memory image is truncated")?;
+        zero_gap(file, pos, offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        pos = offset + len;
+    }
+    zero_gap(file, pos, size)?;
+    Ok(())
+}
+
+/// Load a snapshot's guest RAM into the memory backing file of a running VM.
+///
+/// The backing file ends up with exactly the snapshot's contents: a compressed
+/// image is read with [`read_memory_image`], and `memory.bin` is copied over
+/// the backing file unless the two are the same file.
+pub fn restore_memory(
+    dir: &Path,
+    manifest: &SnapshotManifest,
+    memory_file_path: &Path,
+) -> anyhow::Result<()> {
+    let mut memory_file = fs_err::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(memory_file_path)?;
+    if manifest.compressed_memory {
+        read_memory_image(dir, memory_file.file_mut())
+            .context("failed to read snapshot memory image")?;
+    } else {
+        let source = dir.join("memory.bin");
+        // A snapshot saved by this VM links to its own backing file.
+        if !same_file(&source, memory_file_path)? {
+            let len = std::io::copy(&mut fs_err::File::open(source)?, &mut memory_file)
+                .context("failed to copy snapshot memory")?;
+            memory_file.set_len(len)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `a` and `b` are the same file, including through hard
+/// links where the platform can tell.
+fn same_file(a: &Path, b: &Path) -> std::io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let (a, b) = (fs_err::metadata(a)?, fs_err::metadata(b)?);
+        Ok((a.dev(), a.ino()) == (b.dev(), b.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(fs_err::canonicalize(a)? == fs_err::canonicalize(b)?)
+    }
+}
+
+/// Read a snapshot's manifest from the given directory.
+pub fn read_manifest(dir: &Path) -> anyhow::Result<SnapshotManifest> {
+    let manifest_bytes =
+        fs_err::read(dir.join("manifest.bin")).context("failed to read manifest.bin")?;
+    mesh::payload::decode(&manifest_bytes).context("failed to decode snapshot manifest")
+}
+
 /// Read a snapshot from the given directory.
 ///
 /// Returns the decoded manifest and the raw saved-state bytes.
-/// The caller is responsible for opening `memory.bin` separately.
+/// The caller is responsible for opening `memory.bin` (or reading the memory
+/// image, for portable snapshots) separately.
 pub fn read_snapshot(dir: &Path) -> anyhow::Result<(SnapshotManifest, Vec<u8>)> {
-    let manifest_bytes =
-        fs_err::read(dir.join("manifest.bin")).context("failed to read manifest.bin")?;
-    let manifest: SnapshotManifest =
-        mesh::payload::decode(&manifest_bytes).context("failed to decode snapshot manifest")?;
+    let manifest = read_manifest(dir)?;
 
     let state_bytes = fs_err::read(dir.join("state.bin")).context("failed to read state.bin")?;
 
@@ -130,10 +430,11 @@ pub fn validate_manifest(
     expected_vp_count: u32,
     expected_page_size: u32,
 ) -> anyhow::Result<()> {
-    if manifest.version != MANIFEST_VERSION {
+    if !(MIN_MANIFEST_VERSION..=MANIFEST_VERSION).contains(&manifest.version) {
         anyhow::bail!(
-            "snapshot manifest version {} is not supported (expected {})",
+            "snapshot manifest version {} is not supported (expected {}..={})",
             manifest.version,
+            MIN_MANIFEST_VERSION,
             MANIFEST_VERSION,
         );
     }
@@ -173,6 +474,167 @@ pub fn validate_manifest(
     Ok(())
 }
 
+/// Returns the VM's disks in the form recorded in the manifest of the snapshot
+/// in `dir`, which must exist.
+///
+/// Each file-backed layer is recorded with the hash of its contents, and with
+/// its path relative to `dir` where possible. This lets the snapshot and its
+/// disks be moved together, while [`validate_disks`] still rejects a layer
+/// that was modified without changing its size.
+pub fn record_disks(dir: &Path, disks: &[SnapshotDisk]) -> anyhow::Result<Vec<SnapshotDisk>> {
+    let dir = fs_err::canonicalize(dir)?;
+    disks
+        .iter()
+        .map(|disk| {
+            let layers = disk
+                .layers
+                .iter()
+                .map(|layer| {
+                    let mut layer = layer.clone();
+                    if layer.is_file() {
+                        let path = Path::new(&layer.path);
+                        layer.content_hash = hash_file(path)
+                            .with_context(|| format!("failed to hash {}", path.display()))?;
+                        if let Some(relative) = relative_path(&dir, path) {
+                            layer.path = relative.display().to_string();
+                        }
+                    }
+                    anyhow::Ok(layer)
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Ok(SnapshotDisk {
+                name: disk.name.clone(),
+                layers,
+            })
+        })
+        .collect()
+}
+
+/// Returns the hex-encoded SHA-256 hash of the contents of the file at `path`.
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut file = fs_err::File::open(path)?;
+    let mut hasher = crypto::sha_256::Sha256::new();
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finish().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Returns canonical path `path` relative to canonical directory `dir`, or
+/// `None` if they do not share a root, such as on different Windows volumes.
+fn relative_path(dir: &Path, path: &Path) -> Option<PathBuf> {
+    let mut dir_components = dir.components().peekable();
+    let mut path_components = path.components().peekable();
+    let mut shared = false;
+    while let (Some(a), Some(b)) = (dir_components.peek(), path_components.peek()) {
+        if a != b {
+            break;
+        }
+        dir_components.next();
+        path_components.next();
+        shared = true;
+    }
+    if !shared {
+        return None;
+    }
+    let mut relative = PathBuf::new();
+    relative.extend(dir_components.map(|_| ".."));
+    relative.extend(path_components);
+    Some(relative)
+}
+
+/// Validate that the VM's disks match the ones recorded in a snapshot.
+///
+/// The layer stacks must be identical, since guest state in the snapshot
+/// refers to the disk contents at the time it was taken. A file-backed layer
+/// recorded by [`record_disks`] must have the same contents, wherever it is
+/// now; otherwise, it must have the same path and size. Snapshots that did not
+/// record their disks are accepted.
+pub fn validate_disks(manifest: &SnapshotManifest, disks: &[SnapshotDisk]) -> anyhow::Result<()> {
+    if manifest.configured_disks.is_empty() {
+        return Ok(());
+    }
+
+    for disk in disks {
+        if !manifest
+            .configured_disks
+            .iter()
+            .any(|d| d.name == disk.name)
+        {
+            anyhow::bail!("disk {} is not in the snapshot", disk.name);
+        }
+    }
+
+    for recorded in &manifest.configured_disks {
+        let disk = disks
+            .iter()
+            .find(|d| d.name == recorded.name)
+            .with_context(|| format!("snapshot disk {} is not attached", recorded.name))?;
+        if disk.layers.len() != recorded.layers.len() {
+            anyhow::bail!(
+                "disk {} has {} layers, but the snapshot has {}",
+                disk.name,
+                disk.layers.len(),
+                recorded.layers.len(),
+            );
+        }
+        for (i, (layer, expected)) in disk.layers.iter().zip(&recorded.layers).enumerate() {
+            if !expected.content_hash.is_empty() {
+                if layer.kind != expected.kind || !layer.is_file() {
+                    anyhow::bail!(
+                        "disk {} layer {i} is {}:{}, but the snapshot has {}:{}",
+                        disk.name,
+                        layer.kind,
+                        layer.path,
+                        expected.kind,
+                        expected.path,
+                    );
+                }
+                // The layer may have been moved along with the snapshot, so
+                // only its contents are compared.
+                if layer.file_size != expected.file_size
+                    || hash_file(Path::new(&layer.path))
+                        .with_context(|| format!("failed to hash {}", layer.path))?
+                        != expected.content_hash
+                {
+                    anyhow::bail!(
+                        "disk {} layer {i} ({}) has changed since the snapshot was taken",
+                        disk.name,
+                        layer.path,
+                    );
+                }
+                continue;
+            }
+            if layer.kind != expected.kind || layer.path != expected.path {
+                anyhow::bail!(
+                    "disk {} layer {i} is {}:{}, but the snapshot has {}:{}",
+                    disk.name,
+                    layer.kind,
+                    layer.path,
+                    expected.kind,
+                    expected.path,
+                );
+            }
+            if layer.file_size != expected.file_size {
+                anyhow::bail!(
+                    "disk {} layer {i} ({}) is {} bytes, but was {} bytes in the snapshot",
+                    disk.name,
+                    layer.path,
+                    layer.file_size,
+                    expected.file_size,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -190,6 +652,13 @@ fn test_manifest() -> SnapshotManifest {
             vp_count: 2,
             page_size: 4096,
             architecture: "x86_64".to_string(),
+            compressed_memory: false,
+            vm_args: Vec::new(),
+            name: String::new(),
+            description: String::new(),
+            parent: String::new(),
+            disks: Vec::new(),
+            configured_disks: Vec::new(),
         }
     }
 
@@ -251,6 +720,245 @@ fn write_snapshot_same_memory_path() {
         assert_eq!(std::fs::read(&mem_path).unwrap(), b"SAMEFILE");
     }
 
+    #[test]
+    fn portable_snapshot_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let snap_dir = dir.path().join("snap");
+
+        // Mix zero and non-zero pages, with a partial trailing page and a
+        // run longer than a single record.
+        let mut mem = vec![0u8; 3 * MEMORY_IMAGE_MAX_RUN + 100];
+        mem[5] = 1;
+        mem[MEMORY_IMAGE_PAGE_SIZE * 3..MEMORY_IMAGE_MAX_RUN * 2].fill(0xaa);
+        *mem.last_mut().unwrap() = 2;
+        let mem_path = dir.path().join("memory.bin");
+        std::fs::write(&mem_path, &mem).unwrap();
+
+        let manifest = SnapshotManifest {
+            vm_args: vec!["--uefi".into(), "--memory".into(), "4G".into()],
+            ..test_manifest()
+        };
+        write_portable_snapshot(&snap_dir, &manifest, b"state", &mem_path).unwrap();
+
+        // The snapshot must not depend on the backing file.
+        std::fs::remove_file(&mem_path).unwrap();
+        assert!(!snap_dir.join("memory.bin").exists());
+
+        let (read_manifest, read_state) = read_snapshot(&snap_dir).unwrap();
+        assert!(read_manifest.compressed_memory);
+        assert_eq!(read_manifest.vm_args, manifest.vm_args);
+        assert_eq!(read_state, b"state");
+
+        let mut file = tempfile::tempfile().unwrap();
+        read_memory_image(&snap_dir, &mut file).unwrap();
+        let mut restored = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut restored).unwrap();
+        assert!(restored == mem, "memory image roundtrip mismatch");
+
+        // Reading over existing contents must also clear the zero pages.
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&vec![0xffu8; mem.len() + 10]).unwrap();
+        read_memory_image(&snap_dir, &mut file).unwrap();
+        let mut restored = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut restored).unwrap();
+        assert!(restored == mem, "memory image overwrite mismatch");
+
+        // Zero pages are elided, so the image is much smaller than RAM.
+        let image_len = std::fs::metadata(snap_dir.join(MEMORY_IMAGE))
+            .unwrap()
+            .len();
+        assert!(
+            image_len < mem.len() as u64 / 10,
+            "image is {image_len} bytes"
+        );
+    }
+
+    #[test]
+    fn read_memory_image_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mem_path = dir.path().join("memory.bin");
+        std::fs::write(&mem_path, vec![0x55u8; 4 * MEMORY_IMAGE_PAGE_SIZE]).unwrap();
+        write_portable_snapshot(dir.path(), &test_manifest(), b"state", &mem_path).unwrap();
+
+        // Replace the image with a compressed prefix of its contents.
+        let image_path = dir.path().join(MEMORY_IMAGE);
+        let image = zstd::decode_all(std::fs::File::open(&image_path).unwrap()).unwrap();
+        std::fs::write(
+            &image_path,
+            zstd::encode_all(&image[..image.len() / 2], 0).unwrap(),
+        )
+        .unwrap();
+
+        let mut file = tempfile::tempfile().unwrap();
+        let err = read_memory_image(dir.path(), &mut file).unwrap_err();
+        assert!(
+            err.to_string().contains("truncated"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn restore_memory_clears_stale_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let snap_dir = dir.path().join("snap");
+
+        // Page 1 is zero when the snapshot is taken.
+        let mut mem = vec![0x11u8; 4 * MEMORY_IMAGE_PAGE_SIZE];
+        mem[MEMORY_IMAGE_PAGE_SIZE..2 * MEMORY_IMAGE_PAGE_SIZE].fill(0);
+        mem[3 * MEMORY_IMAGE_PAGE_SIZE..].fill(0);
+        let mem_path = dir.path().join("memory.bin");
+        std::fs::write(&mem_path, &mem).unwrap();
+        let manifest = test_manifest();
+        write_portable_snapshot(&snap_dir, &manifest, b"state", &mem_path).unwrap();
+        let manifest = read_manifest(&snap_dir).unwrap();
+
+        // The guest then dirties every page, and the file is a page short of
+        // the snapshot so that the last page is only extended.
+        std::fs::write(&mem_path, vec![0xeeu8; 3 * MEMORY_IMAGE_PAGE_SIZE]).unwrap();
+        restore_memory(&snap_dir, &manifest, &mem_path).unwrap();
+        assert!(
+            std::fs::read(&mem_path).unwrap() == mem,
+            "stale memory after restore"
+        );
+    }
+
+    #[test]
+    fn restore_memory_from_linked_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let snap_dir = dir.path().join("snap");
+        let mut mem = vec![0x22u8; 2 * MEMORY_IMAGE_PAGE_SIZE];
+        mem[..MEMORY_IMAGE_PAGE_SIZE].fill(0);
+        let snap_mem_path = dir.path().join("snap-memory.bin");
+        std::fs::write(&snap_mem_path, &mem).unwrap();
+        write_snapshot(&snap_dir, &test_manifest(), b"state", &snap_mem_path).unwrap();
+        let manifest = read_manifest(&snap_dir).unwrap();
+
+        // Restore into another VM's backing file, which has non-zero data
+        // where the snapshot is zero and is longer than the snapshot.
+        let mem_path = dir.path().join("memory.bin");
+        std::fs::write(&mem_path, vec![0xeeu8; 3 * MEMORY_IMAGE_PAGE_SIZE]).unwrap();
+        restore_memory(&snap_dir, &manifest, &mem_path).unwrap();
+        assert!(
+            std::fs::read(&mem_path).unwrap() == mem,
+            "stale memory after restore"
+        );
+
+        // Restoring a snapshot that references the backing file itself
+        // leaves it alone.
+        restore_memory(&snap_dir, &manifest, &snap_mem_path).unwrap();
+        assert!(std::fs::read(&snap_mem_path).unwrap() == mem);
+    }
+
+    #[test]
+    fn validate_disks_layers() {
+        let disk = |path: &str, file_size| SnapshotDisk {
+            name: "disk[0]".to_string(),
+            layers: vec![
+                SnapshotDiskLayer {
+                    kind: "memdiff".to_string(),
+                    path: String::new(),
+                    file_size: 0,
+                    content_hash: String::new(),
+                },
+                SnapshotDiskLayer {
+                    kind: "file".to_string(),
+                    path: path.to_string(),
+                    file_size,
+                    content_hash: String::new(),
+                },
+            ],
+        };
+
+        // Snapshots that predate disk recording are accepted.
+        validate_disks(&test_manifest(), &[disk("/a.img", 1)]).unwrap();
+
+        let manifest = SnapshotManifest {
+            configured_disks: vec![disk("/a.img", 1)],
+            ..test_manifest()
+        };
+        validate_disks(&manifest, &[disk("/a.img", 1)]).unwrap();
+
+        let err = validate_disks(&manifest, &[disk("/b.img", 1)]).unwrap_err();
+        assert!(
+            err.to_string().contains("/b.img"),
+            "unexpected error: {err}"
+        );
+        let err = validate_disks(&manifest, &[disk("/a.img", 2)]).unwrap_err();
+        assert!(err.to_string().contains("bytes"), "unexpected error: {err}");
+        let err = validate_disks(&manifest, &[]).unwrap_err();
+        assert!(
+            err.to_string().contains("not attached"),
+            "unexpected error: {err}"
+        );
+        let mut extra = disk("/a.img", 1);
+        extra.name = "nvme[0]".to_string();
+        let err = validate_disks(&manifest, &[disk("/a.img", 1), extra]).unwrap_err();
+        assert!(
+            err.to_string().contains("not in the snapshot"),
+            "unexpected error: {err}"
+        );
+        let mut short = disk("/a.img", 1);
+        short.layers.remove(0);
+        let err = validate_disks(&manifest, &[short]).unwrap_err();
+        assert!(
+            err.to_string().contains("layers"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn record_disks_by_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let snap_dir = dir.path().join("snap");
+        std::fs::create_dir(&snap_dir).unwrap();
+        std::fs::create_dir(dir.path().join("disks")).unwrap();
+        let disk_path = dir.path().join("disks/a.img");
+        std::fs::write(&disk_path, b"disk contents").unwrap();
+
+        let disk = |path: &Path| SnapshotDisk {
+            name: "disk[0]".to_string(),
+            layers: vec![
+                SnapshotDiskLayer {
+                    kind: "memdiff".to_string(),
+                    path: String::new(),
+                    file_size: 0,
+                    content_hash: String::new(),
+                },
+                SnapshotDiskLayer {
+                    kind: "file".to_string(),
+                    path: fs_err::canonicalize(path).unwrap().display().to_string(),
+                    file_size: fs_err::metadata(path).unwrap().len(),
+                    content_hash: String::new(),
+                },
+            ],
+        };
+
+        let manifest = SnapshotManifest {
+            configured_disks: record_disks(&snap_dir, &[disk(&disk_path)]).unwrap(),
+            ..test_manifest()
+        };
+        let recorded = &manifest.configured_disks[0].layers;
+        assert_eq!(recorded[0], disk(&disk_path).layers[0]);
+        assert_eq!(Path::new(&recorded[1].path), Path::new("../disks/a.img"),);
+        assert_eq!(recorded[1].content_hash.len(), 64);
+        validate_disks(&manifest, &[disk(&disk_path)]).unwrap();
+
+        // A copy elsewhere has the same contents.
+        let moved = dir.path().join("b.img");
+        std::fs::copy(&disk_path, &moved).unwrap();
+        validate_disks(&manifest, &[disk(&moved)]).unwrap();
+
+        // A change that keeps the size is still detected.
+        std::fs::write(&disk_path, b"DISK contents").unwrap();
+        let err = validate_disks(&manifest, &[disk(&disk_path)]).unwrap_err();
+        assert!(
+            err.to_string().contains("has changed"),
+            "unexpected error: {err}"
+        );
+    }
+
     #[test]
     fn read_snapshot_missing_file() {
         let dir = tempfile::tempdir().unwrap();
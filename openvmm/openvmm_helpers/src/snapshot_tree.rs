@@ -0,0 +1,282 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Snapshot trees: a directory of portable snapshots linked by parent IDs.
+//!
+//! Each snapshot in the tree lives in a subdirectory named by its ID. A
+//! subdirectory without a manifest is pending: it holds the disk changes
+//! accumulated since the most recent snapshot, and becomes a snapshot when
+//! the next one is taken.
+
+use crate::snapshot::SnapshotManifest;
+use crate::snapshot::read_manifest;
+use anyhow::Context;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// A snapshot in a [`SnapshotTree`].
+#[derive(Clone)]
+pub struct SnapshotEntry {
+    /// The snapshot's ID, which is also its directory name.
+    pub id: String,
+    /// The snapshot's manifest.
+    pub manifest: SnapshotManifest,
+}
+
+/// A directory holding a tree of snapshots.
+pub struct SnapshotTree {
+    root: PathBuf,
+}
+
+impl SnapshotTree {
+    /// Opens the snapshot tree at `root`, creating it if necessary.
+    ///
+    /// Pending directories left behind by a previous process are removed,
+    /// discarding the changes made after that process's last snapshot.
+    pub fn open(root: &Path) -> anyhow::Result<Self> {
+        fs_err::create_dir_all(root)?;
+        let tree = Self {
+            root: root.to_owned(),
+        };
+        for entry in fs_err::read_dir(root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() && !entry.path().join("manifest.bin").exists() {
+                tracing::info!(path = %entry.path().display(), "removing pending snapshot");
+                fs_err::remove_dir_all(entry.path())?;
+            }
+        }
+        Ok(tree)
+    }
+
+    /// Returns the directory of the snapshot (or pending snapshot) `id`.
+    pub fn path(&self, id: &str) -> PathBuf {
+        self.root.join(id)
+    }
+
+    /// Returns the path of the file holding the changes to the layered disk
+    /// `disk` in the snapshot (or pending snapshot) `id`.
+    pub fn disk_path(&self, id: &str, disk: &str) -> PathBuf {
+        self.path(id)
+            .join(format!("{}.dbhd", disk.replace('/', "-")))
+    }
+
+    /// Lists the snapshots in the tree, oldest first.
+    pub fn list(&self) -> anyhow::Result<Vec<SnapshotEntry>> {
+        let mut entries = Vec::new();
+        for entry in fs_err::read_dir(&self.root)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() || !entry.path().join("manifest.bin").exists() {
+                continue;
+            }
+            let id = entry
+                .file_name()
+                .into_string()
+                .map_err(|name| anyhow::anyhow!("invalid snapshot directory {name:?}"))?;
+            let manifest = read_manifest(&entry.path())
+                .with_context(|| format!("failed to read snapshot {id}"))?;
+            entries.push(SnapshotEntry { id, manifest });
+        }
+        entries.sort_by(|a, b| (a.manifest.created_at, &a.id).cmp(&(b.manifest.created_at, &b.id)));
+        Ok(entries)
+    }
+
+    /// Finds the snapshot with ID or name `name_or_id`.
+    pub fn resolve(&self, name_or_id: &str) -> anyhow::Result<SnapshotEntry> {
+        let entries = self.list()?;
+        if let Some(entry) = entries.iter().find(|e| e.id == name_or_id) {
+            return Ok(entry.clone());
+        }
+        let mut matches = entries
+            .into_iter()
+            .filter(|e| !e.manifest.name.is_empty() && e.manifest.name == name_or_id);
+        let entry = matches
+            .next()
+            .with_context(|| format!("snapshot {name_or_id} not found"))?;
+        if matches.next().is_some() {
+            anyhow::bail!("snapshot name {name_or_id} is ambiguous, use the snapshot ID");
+        }
+        Ok(entry)
+    }
+
+    /// Returns the chain of snapshots from the root of the tree to `id`,
+    /// inclusive.
+    pub fn ancestors(&self, id: &str) -> anyhow::Result<Vec<SnapshotEntry>> {
+        let mut chain = Vec::<SnapshotEntry>::new();
+        let mut next = id.to_owned();
+        loop {
+            if chain.iter().any(|e| e.id == next) {
+                anyhow::bail!("snapshot {next} is its own ancestor");
+            }
+            let manifest = read_manifest(&self.path(&next))
+                .with_context(|| format!("failed to read snapshot {next}"))?;
+            let parent = manifest.parent.clone();
+            chain.push(SnapshotEntry { id: next, manifest });
+            if parent.is_empty() {
+                break;
+            }
+            next = parent;
+        }
+        chain.reverse();
+        Ok(chain)
+    }
+
+    /// Creates a new, empty pending snapshot directory and returns its ID.
+    pub fn create_pending(&self) -> anyhow::Result<String> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        // IDs are derived from the time so that they sort in creation order.
+        for n in now.. {
+            let id = format!("{n:012x}");
+            match fs_err::create_dir(self.path(&id)) {
+                Ok(()) => return Ok(id),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        unreachable!()
+    }
+
+    /// Removes the pending snapshot `id`, discarding its contents.
+    pub fn discard_pending(&self, id: &str) -> anyhow::Result<()> {
+        let path = self.path(id);
+        anyhow::ensure!(
+            !path.join("manifest.bin").exists(),
+            "{id} is not a pending snapshot"
+        );
+        fs_err::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    /// Deletes the snapshot `id`.
+    ///
+    /// Fails if the snapshot has children, or if it is `current` or one of
+    /// its ancestors, since the running VM's disks depend on it.
+    pub fn delete(&self, id: &str, current: Option<&str>) -> anyhow::Result<()> {
+        if let Some(child) = self.list()?.iter().find(|e| e.manifest.parent == id) {
+            anyhow::bail!("snapshot {id} has child snapshot {}", child.id);
+        }
+        if let Some(current) = current {
+            if self.ancestors(current)?.iter().any(|e| e.id == id) {
+                anyhow::bail!("snapshot {id} is in use by the running VM");
+            }
+        }
+        // Remove the manifest first so that a partially deleted snapshot is
+        // treated as pending and cleaned up by the next open.
+        let path = self.path(id);
+        fs_err::remove_file(path.join("manifest.bin"))?;
+        fs_err::remove_dir_all(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::MANIFEST_VERSION;
+    use mesh::payload::Timestamp;
+
+    fn add(tree: &SnapshotTree, name: &str, parent: &str, seconds: i64) -> String {
+        let id = tree.create_pending().unwrap();
+        let manifest = SnapshotManifest {
+            version: MANIFEST_VERSION,
+            created_at: Timestamp { seconds, nanos: 0 },
+            openvmm_version: "test-0.1.0".to_string(),
+            memory_size_bytes: 1024,
+            vp_count: 2,
+            page_size: 4096,
+            architecture: "x86_64".to_string(),
+            compressed_memory: true,
+            vm_args: Vec::new(),
+            name: name.to_string(),
+            description: String::new(),
+            parent: parent.to_string(),
+            disks: Vec::new(),
+            configured_disks: Vec::new(),
+        };
+        std::fs::write(
+            tree.path(&id).join("manifest.bin"),
+            mesh::payload::encode(manifest),
+        )
+        .unwrap();
+        id
+    }
+
+    #[test]
+    fn list_and_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = SnapshotTree::open(dir.path()).unwrap();
+        let b = add(&tree, "b", "", 20);
+        let a = add(&tree, "a", "", 10);
+        let pending = tree.create_pending().unwrap();
+        assert_ne!(a, b);
+
+        let ids = tree
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [a.clone(), b.clone()]);
+        assert_eq!(tree.resolve("a").unwrap().id, a);
+        assert_eq!(tree.resolve(&b).unwrap().manifest.name, "b");
+        assert!(tree.resolve("c").is_err());
+        assert!(tree.resolve(&pending).is_err());
+
+        add(&tree, "a", "", 30);
+        assert!(tree.resolve("a").is_err());
+        assert_eq!(tree.resolve(&a).unwrap().id, a);
+    }
+
+    #[test]
+    fn open_removes_pending() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = SnapshotTree::open(dir.path()).unwrap();
+        let id = add(&tree, "a", "", 10);
+        let pending = tree.create_pending().unwrap();
+        std::fs::write(tree.disk_path(&pending, "scsi/0"), b"data").unwrap();
+
+        let tree = SnapshotTree::open(dir.path()).unwrap();
+        assert!(tree.path(&id).exists());
+        assert!(!tree.path(&pending).exists());
+    }
+
+    #[test]
+    fn ancestors_and_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = SnapshotTree::open(dir.path()).unwrap();
+        let root = add(&tree, "root", "", 10);
+        let left = add(&tree, "left", &root, 20);
+        let right = add(&tree, "right", &root, 30);
+
+        let chain = tree
+            .ancestors(&left)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        assert_eq!(chain, [root.clone(), left.clone()]);
+
+        // The root has children, and the current snapshot's chain is in use.
+        assert!(tree.delete(&root, None).is_err());
+        assert!(tree.delete(&left, Some(&left)).is_err());
+
+        tree.delete(&left, Some(&right)).unwrap();
+        assert!(!tree.path(&left).exists());
+        tree.delete(&right, None).unwrap();
+        tree.delete(&root, None).unwrap();
+        assert!(tree.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn discard_pending() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = SnapshotTree::open(dir.path()).unwrap();
+        let id = add(&tree, "a", "", 10);
+        assert!(tree.discard_pending(&id).is_err());
+        let pending = tree.create_pending().unwrap();
+        tree.discard_pending(&pending).unwrap();
+        assert!(!tree.path(&pending).exists());
+    }
+}
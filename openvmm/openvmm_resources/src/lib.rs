@@ -57,10 +57,14 @@
 
     // Network backends
     net_backend::null::NullResolver,
+    net_impair::resolver::ImpairResolver,
+    net_packet_capture::resolver::PacketCaptureResolver,
     #[cfg(feature = "net_consomme")]
     net_consomme::resolver::ConsommeResolver,
     #[cfg(all(feature = "net_tap", target_os = "linux"))]
     net_tap::resolver::TapResolver,
+    #[cfg(unix)]
+    net_socket::resolver::SocketResolver,
     #[cfg(windows)]
     net_dio::resolver::DioResolver,
 
@@ -74,6 +78,9 @@
     disk_prwrap::DiskWithReservationsResolver,
     disk_delay::resolver::DelayDiskResolver,
     disk_vhd1::Vhd1Resolver,
+    disk_vhd1::resolver::Vhd1DiskResolver,
+    disk_qcow2::resolver::Qcow2Resolver,
+    disk_vhdx::resolver::VhdxResolver,
     #[cfg(windows)]
     disk_vhdmp::VhdmpDiskResolver,
     #[cfg(feature = "disk_blob")]
@@ -81,11 +88,15 @@
 
     // Disk Layers
     disklayer_ram::resolver::RamDiskLayerResolver,
+    disk_qcow2::resolver::Qcow2DiskLayerResolver,
+    disk_vhd1::resolver::Vhd1DiskLayerResolver,
+    disk_vhdx::resolver::VhdxDiskLayerResolver,
     #[cfg(feature = "disklayer_sqlite")]
     disklayer_sqlite::resolver::SqliteDiskLayerResolver,
 
     // PCI devices
     cxl_spec::test::resolver::CxlTestDeviceResolver,
+    e1000e::resolver::E1000eDeviceResolver,
     gdma::resolver::GdmaDeviceResolver,
     nvme::resolver::NvmeControllerResolver,
     nvme_test::resolver::NvmeFaultControllerResolver,
@@ -95,12 +106,15 @@
     scsidisk::resolver::SimpleScsiResolver,
 
     // Virtio devices
+    virtio_balloon::resolver::VirtioBalloonResolver,
     virtio_blk::resolver::VirtioBlkResolver,
     virtio_console::resolver::VirtioConsoleResolver,
     #[cfg(any(windows, target_os = "linux"))]
     virtiofs::resolver::VirtioFsResolver,
     #[cfg(any(windows, target_os = "linux"))]
     virtio_p9::resolver::VirtioPlan9Resolver,
+    virtio_gpu::resolver::VirtioGpuResolver,
+    virtio_input::resolver::VirtioInputResolver,
     virtio_net::resolver::VirtioNetResolver,
     virtio_pmem::resolver::VirtioPmemResolver,
     virtio_rng::resolver::VirtioRngResolver,
@@ -112,9 +126,12 @@
     guest_crash_device::resolver::GuestCrashDeviceResolver,
     guest_emulation_device::resolver::GuestEmulationDeviceResolver,
     guest_emulation_log::resolver::GuestEmulationLogResolver,
+    hyperv_ic::resolver::FcopyIcResolver,
+    hyperv_ic::resolver::HeartbeatIcResolver,
     hyperv_ic::resolver::KvpIcResolver,
     hyperv_ic::resolver::ShutdownIcResolver,
     hyperv_ic::resolver::TimesyncIcResolver,
+    hyperv_ic::resolver::VssIcResolver,
     netvsp::resolver::NetvspResolver,
     storvsp::resolver::StorvspResolver,
     storvsp::resolver::StorvspIdeResolver,
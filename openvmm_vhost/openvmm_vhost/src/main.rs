@@ -32,21 +32,75 @@ mod linux {
     use anyhow::Context as _;
     use clap::Parser;
     use clap::Subcommand;
-    use disk_backend_resources::FileDiskHandle;
+    use net_backend_resources::consomme::ConsommeHandle;
+    use net_backend_resources::mac_address::MacAddress;
+    use net_backend_resources::tap::TapHandle;
+    use openvmm_helpers::disk_cli::DiskCliKind;
     use pal_async::DefaultPool;
+    use serial_socket::net::OpenSocketSerialConfig;
+    use std::path::Path;
     use std::path::PathBuf;
+    use std::str::FromStr;
+    use unix_socket::UnixListener;
     use vhost_user_backend::VhostUserDeviceServer;
     use virtio::resolve::VirtioResolveInput;
     use virtio_resources::blk::VirtioBlkHandle;
+    use virtio_resources::console::VirtioConsoleHandle;
+    use virtio_resources::fs::VirtioFsBackend;
+    use virtio_resources::fs::VirtioFsHandle;
+    use virtio_resources::net::VirtioNetHandle;
+    use virtio_resources::pmem::VirtioPmemHandle;
+    use virtio_resources::rng::VirtioRngHandle;
+    use virtio_resources::vsock::VirtioVsockHandle;
+    use vm_resource::IntoResource;
     use vm_resource::Resource;
     use vm_resource::ResourceResolver;
+    use vm_resource::kind::VirtioDeviceHandle;
     use vmcore::vm_task::SingleDriverBackend;
     use vmcore::vm_task::VmTaskDriverSource;
 
+    #[cfg(not(test))]
+    crypto::ensure_single_backend!();
+
     // Register the resolvers needed by this binary.
     vm_resource::register_static_resolvers! {
+        // Virtio devices
         virtio_blk::resolver::VirtioBlkResolver,
+        virtio_console::resolver::VirtioConsoleResolver,
+        virtiofs::resolver::VirtioFsResolver,
+        virtio_net::resolver::VirtioNetResolver,
+        virtio_pmem::resolver::VirtioPmemResolver,
+        virtio_rng::resolver::VirtioRngResolver,
+        virtio_vsock::resolver::VirtioVsockResolver,
+
+        // Serial backends
+        serial_socket::net::SocketSerialResolver,
+
+        // Network backends
+        net_consomme::resolver::ConsommeResolver,
+        net_tap::resolver::TapResolver,
+
+        // Disks
+        disk_layered::resolver::LayeredDiskResolver,
+        #[cfg(feature = "disk_crypt")]
+        disk_crypt::resolver::DiskCryptResolver,
         disk_file::FileDiskResolver,
+        disk_prwrap::DiskWithReservationsResolver,
+        disk_delay::resolver::DelayDiskResolver,
+        disk_vhd1::Vhd1Resolver,
+        disk_vhd1::resolver::Vhd1DiskResolver,
+        disk_qcow2::resolver::Qcow2Resolver,
+        disk_vhdx::resolver::VhdxResolver,
+        #[cfg(feature = "disk_blob")]
+        disk_blob::resolver::BlobDiskResolver,
+
+        // Disk layers
+        disklayer_ram::resolver::RamDiskLayerResolver,
+        disk_qcow2::resolver::Qcow2DiskLayerResolver,
+        disk_vhd1::resolver::Vhd1DiskLayerResolver,
+        disk_vhdx::resolver::VhdxDiskLayerResolver,
+        #[cfg(feature = "disklayer_sqlite")]
+        disklayer_sqlite::resolver::SqliteDiskLayerResolver,
     }
 
     /// openvmm_vhost: vhost-user backend for OpenVMM virtio devices.
@@ -66,14 +120,111 @@ struct Cli {
     enum DeviceCommand {
         /// Expose a virtio-blk device.
         Blk {
-            /// Path to the disk image file.
+            /// The disk, using the OpenVMM `--disk` syntax (for example
+            /// `file:<path>`, `sql:<path>`, `memdiff:<disk>`, or
+            /// `crypt:<cipher>:<key_file>:<disk>`). A bare path opens a file.
             #[arg(long)]
-            disk: PathBuf,
+            disk: DiskCliKind,
 
             /// Open the disk as read-only.
             #[arg(long, default_value_t = false)]
             read_only: bool,
         },
+        /// Expose a virtio-fs device serving a host directory.
+        Fs {
+            /// The host directory to share.
+            #[arg(long)]
+            path: String,
+
+            /// The tag the guest uses to mount the file system.
+            #[arg(long)]
+            tag: String,
+
+            /// Comma-separated mount options, such as `uid=1000`.
+            #[arg(long, default_value = "")]
+            options: String,
+        },
+        /// Expose a virtio-console device.
+        Console {
+            /// Path of a Unix socket to listen on for the console stream.
+            #[arg(long)]
+            listen: PathBuf,
+        },
+        /// Expose a virtio-rng device.
+        Rng,
+        /// Expose a virtio-vsock device.
+        ///
+        /// Guest connections to port N are relayed to the Unix socket at
+        /// `<path>_N`, and host connections to `<path>` are relayed to the
+        /// guest (hybrid vsock).
+        Vsock {
+            /// The base path of the Unix sockets.
+            #[arg(long)]
+            path: String,
+
+            /// The guest's context ID.
+            #[arg(long, default_value_t = 3)]
+            guest_cid: u64,
+        },
+        /// Expose a virtio-pmem device.
+        Pmem {
+            /// Path to the file backing the persistent memory.
+            #[arg(long)]
+            path: String,
+        },
+        /// Expose a virtio-net device.
+        ///
+        /// Only the receive and transmit queues are served; the vhost-user
+        /// frontend handles the config space and control queue.
+        Net {
+            /// The network backend: `consomme[:<cidr>]` or `tap:<name>`.
+            #[arg(long)]
+            backend: NetBackend,
+
+            /// The guest's MAC address. This must match the MAC address
+            /// configured on the frontend.
+            #[arg(long)]
+            mac: MacAddress,
+
+            /// The number of receive/transmit queue pairs to serve.
+            #[arg(long, default_value_t = 1)]
+            queue_pairs: u16,
+        },
+    }
+
+    /// A network backend for the `net` device.
+    #[derive(Clone)]
+    enum NetBackend {
+        /// User-mode NAT, optionally with the given network CIDR.
+        Consomme { cidr: Option<String> },
+        /// A host TAP interface.
+        Tap { name: String },
+    }
+
+    impl FromStr for NetBackend {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> anyhow::Result<Self> {
+            let (kind, arg) = match s.split_once(':') {
+                Some((kind, arg)) => (kind, Some(arg)),
+                None => (s, None),
+            };
+            match (kind, arg) {
+                ("consomme", cidr) => Ok(NetBackend::Consomme {
+                    cidr: cidr.map(str::to_owned),
+                }),
+                ("tap", Some(name)) if !name.is_empty() => Ok(NetBackend::Tap {
+                    name: name.to_owned(),
+                }),
+                _ => anyhow::bail!("expected consomme[:<cidr>] or tap:<name>"),
+            }
+        }
+    }
+
+    /// Binds a Unix socket listener at `path`, replacing any stale socket.
+    fn bind_listener(path: &Path) -> anyhow::Result<UnixListener> {
+        let _ = std::fs::remove_file(path);
+        UnixListener::bind(path).with_context(|| format!("failed to bind to {}", path.display()))
     }
 
     pub fn main() -> anyhow::Result<()> {
@@ -91,34 +242,93 @@ pub fn main() -> anyhow::Result<()> {
             let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
             let resolver = ResourceResolver::new();
 
-            let server = match &cli.device {
+            let (name, resource): (_, Resource<VirtioDeviceHandle>) = match &cli.device {
                 DeviceCommand::Blk { disk, read_only } => {
-                    let file = std::fs::OpenOptions::new()
-                        .read(true)
-                        .write(!read_only)
-                        .open(disk)
-                        .with_context(|| format!("failed to open disk: {}", disk.display()))?;
-
-                    let virtio_handle = VirtioBlkHandle {
-                        disk: Resource::new(FileDiskHandle(file)),
+                    let disk = openvmm_helpers::disk_cli::open_disk(disk, *read_only)
+                        .await
+                        .context("failed to open disk")?;
+
+                    let handle = VirtioBlkHandle {
+                        disk,
                         read_only: *read_only,
+                        num_queues: None,
+                    };
+                    ("virtio-blk", handle.into_resource())
+                }
+                DeviceCommand::Fs { path, tag, options } => {
+                    let handle = VirtioFsHandle {
+                        tag: tag.clone(),
+                        fs: VirtioFsBackend::HostFs {
+                            root_path: path.clone(),
+                            mount_options: options.clone(),
+                        },
+                    };
+                    ("virtio-fs", handle.into_resource())
+                }
+                DeviceCommand::Console { listen } => {
+                    let backend = OpenSocketSerialConfig::from(bind_listener(listen)?);
+                    let handle = VirtioConsoleHandle {
+                        backend: backend.into_resource(),
+                    };
+                    ("virtio-console", handle.into_resource())
+                }
+                DeviceCommand::Rng => ("virtio-rng", VirtioRngHandle.into_resource()),
+                DeviceCommand::Vsock { path, guest_cid } => {
+                    let handle = VirtioVsockHandle {
+                        guest_cid: *guest_cid,
+                        base_path: path.clone(),
+                        listener: bind_listener(path.as_ref())?,
+                    };
+                    ("virtio-vsock", handle.into_resource())
+                }
+                DeviceCommand::Pmem { path } => {
+                    let handle = VirtioPmemHandle { path: path.clone() };
+                    ("virtio-pmem", handle.into_resource())
+                }
+                DeviceCommand::Net {
+                    backend,
+                    mac,
+                    queue_pairs,
+                } => {
+                    let endpoint = match backend {
+                        NetBackend::Consomme { cidr } => ConsommeHandle {
+                            cidr: cidr.clone(),
+                            ports: Vec::new(),
+                            recv: None,
+                            egress: Default::default(),
+                            guest_forwards: Vec::new(),
+                            dns_records: Vec::new(),
+                            log_dns_queries: false,
+                        }
+                        .into_resource(),
+                        NetBackend::Tap { name } => {
+                            let fd = net_tap::tap::open_tap(name)
+                                .with_context(|| format!("failed to open TAP device '{name}'"))?;
+                            TapHandle { fd }.into_resource()
+                        }
                     };
 
-                    let resolved = resolver
-                        .resolve(
-                            Resource::new(virtio_handle),
-                            VirtioResolveInput {
-                                driver_source: &driver_source,
-                            },
-                        )
-                        .await
-                        .context("failed to resolve virtio-blk device")?;
-
-                    VhostUserDeviceServer::new(resolved.0)
+                    let handle = VirtioNetHandle {
+                        max_queues: Some(*queue_pairs),
+                        mtu: None,
+                        mac_address: *mac,
+                        endpoint,
+                    };
+                    ("virtio-net", handle.into_resource())
                 }
             };
 
-            server
+            let resolved = resolver
+                .resolve(
+                    resource,
+                    VirtioResolveInput {
+                        driver_source: &driver_source,
+                    },
+                )
+                .await
+                .with_context(|| format!("failed to resolve {name} device"))?;
+
+            VhostUserDeviceServer::new(resolved.0)
                 .run(&driver, &cli.socket)
                 .await
                 .context("vhost-user server failed")
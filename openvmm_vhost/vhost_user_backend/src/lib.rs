@@ -15,6 +15,7 @@
 
 pub mod memory;
 pub mod queue_setup;
+pub mod shmem;
 
 /// Re-export protocol types from the shared crate.
 pub use vhost_user_protocol::protocol;
@@ -25,15 +26,18 @@
 use crate::memory::build_guest_memory;
 use crate::protocol::*;
 use crate::queue_setup::QueueSetup;
+use crate::shmem::BackendShmemRegion;
 use crate::socket::SocketError;
 use crate::socket::VhostUserSocket;
 use anyhow::Context as _;
 use guestmem::GuestMemory;
+use guestmem::MappedMemoryRegion;
 use pal_async::driver::SpawnDriver;
 use pal_async::socket::PolledSocket;
 use pal_event::Event;
 use std::os::fd::OwnedFd;
 use std::path::Path;
+use std::sync::Arc;
 use unix_socket::ScmReceiver;
 use unix_socket::UnixListener;
 use vhost_user_protocol::VHOST_USER_MAX_FDS;
@@ -194,11 +198,17 @@ async fn dispatch_message(
             }
 
             VhostUserRequestCode::GET_PROTOCOL_FEATURES => {
+                // Shared memory mappings are requested on the backend
+                // channel, passing the fd to map.
+                let shmem = traits.shared_memory.size > 0;
                 let pf = VhostUserProtocolFeatures::new()
                     .with_mq(true)
                     .with_reply_ack(true)
                     .with_config(true)
-                    .with_reset_device(true);
+                    .with_reset_device(true)
+                    .with_backend_req(shmem)
+                    .with_backend_send_fd(shmem)
+                    .with_shmem(shmem);
                 let reply_payload = VhostUserU64Msg {
                     value: pf.into_bits(),
                 };
@@ -479,6 +489,36 @@ async fn dispatch_message(
                 maybe_ack(socket, hdr, state).await?;
             }
 
+            VhostUserRequestCode::GET_SHMEM_CONFIG => {
+                let mut config = VhostUserShmemConfig {
+                    nregions: 0,
+                    padding: 0,
+                    memory_sizes: [0; VHOST_USER_MAX_SHMEM_REGIONS],
+                };
+                if traits.shared_memory.size > 0 {
+                    let id = traits.shared_memory.id;
+                    config.nregions = id as u32 + 1;
+                    config.memory_sizes[id as usize] = traits.shared_memory.size;
+                }
+                send_reply(socket, hdr, config.as_bytes(), &[]).await?;
+            }
+
+            VhostUserRequestCode::SET_BACKEND_REQ_FD => {
+                let fd = fds
+                    .into_iter()
+                    .next()
+                    .context("SET_BACKEND_REQ_FD: missing fd")?;
+                // The channel is only used for shared memory mappings, so
+                // hand the device its region now. The frontend queues the
+                // requests until it has a region to map them into.
+                if traits.shared_memory.size > 0 {
+                    let region: Arc<dyn MappedMemoryRegion> =
+                        Arc::new(BackendShmemRegion::new(traits.shared_memory.id, fd));
+                    self.device.set_shared_memory_region(&region)?;
+                }
+                maybe_ack(socket, hdr, state).await?;
+            }
+
             VhostUserRequestCode::RESET_DEVICE => {
                 self.stop_all_queues().await;
                 self.device.reset().await;
@@ -0,0 +1,101 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Device shared memory regions, mapped by the frontend on the backend's
+//! behalf.
+//!
+//! The backend cannot map into the guest-visible shared memory region
+//! itself. Instead, each mapping the device makes is forwarded to the
+//! frontend as a `SHMEM_MAP` or `SHMEM_UNMAP` request on the backend channel
+//! established by `SET_BACKEND_REQ_FD`, and the frontend maps the passed fd
+//! into its copy of the region.
+
+use crate::protocol::*;
+use guestmem::MappedMemoryRegion;
+use sparse_mmap::AsMappableRef;
+use std::io;
+use std::io::IoSlice;
+use std::os::fd::AsFd;
+use std::os::fd::BorrowedFd;
+use std::os::fd::OwnedFd;
+use zerocopy::IntoBytes;
+
+/// A shared memory region whose mappings are forwarded to the frontend.
+pub struct BackendShmemRegion {
+    shmid: u8,
+    /// The backend channel. Locked so that concurrent requests are not
+    /// interleaved on the stream.
+    channel: parking_lot::Mutex<OwnedFd>,
+}
+
+impl BackendShmemRegion {
+    /// Creates a region with ID `shmid`, sending requests on `channel`.
+    pub fn new(shmid: u8, channel: OwnedFd) -> Self {
+        Self {
+            shmid,
+            channel: parking_lot::Mutex::new(channel),
+        }
+    }
+
+    fn send(
+        &self,
+        code: VhostUserBackendRequestCode,
+        msg: &VhostUserMMap,
+        fd: Option<BorrowedFd<'_>>,
+    ) -> io::Result<()> {
+        let hdr = VhostUserMsgHeader {
+            request: code.0,
+            flags: VHOST_USER_FLAG_VERSION,
+            size: size_of::<VhostUserMMap>() as u32,
+        };
+        let bufs = [IoSlice::new(hdr.as_bytes()), IoSlice::new(msg.as_bytes())];
+        let channel = self.channel.lock();
+        let n = unix_socket::send_with_fds(channel.as_fd(), &bufs, fd)?;
+        if n != size_of::<VhostUserMsgHeader>() + size_of::<VhostUserMMap>() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "short write on backend channel",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl MappedMemoryRegion for BackendShmemRegion {
+    fn map(
+        &self,
+        offset: usize,
+        section: &dyn AsMappableRef,
+        file_offset: u64,
+        len: usize,
+        writable: bool,
+    ) -> io::Result<()> {
+        tracing::trace!(offset, file_offset, len, writable, "SHMEM_MAP");
+        let msg = VhostUserMMap {
+            shmid: self.shmid,
+            padding: [0; 7],
+            fd_offset: file_offset,
+            shm_offset: offset as u64,
+            len: len as u64,
+            flags: if writable { VHOST_USER_FLAG_MAP_RW } else { 0 },
+        };
+        self.send(
+            VhostUserBackendRequestCode::SHMEM_MAP,
+            &msg,
+            Some(section.as_fd()),
+        )
+    }
+
+    fn unmap(&self, offset: usize, len: usize) -> io::Result<()> {
+        tracing::trace!(offset, len, "SHMEM_UNMAP");
+        let msg = VhostUserMMap {
+            shmid: self.shmid,
+            padding: [0; 7],
+            fd_offset: 0,
+            shm_offset: offset as u64,
+            len: len as u64,
+            flags: 0,
+        };
+        self.send(VhostUserBackendRequestCode::SHMEM_UNMAP, &msg, None)
+    }
+}
@@ -152,6 +152,11 @@ struct RunArgs {
     #[arg(long, default_value = "4")]
     data_disk_size_gib: u64,
 
+    /// Number of virtio-blk request queues for the disk_io test data disk.
+    /// Defaults to the device default of one queue.
+    #[arg(long)]
+    virtio_blk_queues: Option<u16>,
+
     /// Test file size in MiB for the virtio_fs test.
     #[arg(long, default_value = "512")]
     virtiofs_file_size_mib: u64,
@@ -343,6 +348,7 @@ fn cmd_run(args: RunArgs) -> anyhow::Result<()> {
                     backend: args.disk_backend,
                     data_disk: args.data_disk.clone(),
                     data_disk_size_gib: args.data_disk_size_gib,
+                    virtio_blk_queues: args.virtio_blk_queues,
                     perf_dir: args.perf_dir.clone(),
                 };
 
@@ -47,6 +47,9 @@ pub struct DiskIoTest {
     pub data_disk: Option<PathBuf>,
     /// Data disk size in GiB.
     pub data_disk_size_gib: u64,
+    /// Number of virtio-blk request queues for the data disk, or `None` for
+    /// the device default. Ignored for storvsc.
+    pub virtio_blk_queues: Option<u16>,
     /// If set, record per-phase perf traces in this directory.
     pub perf_dir: Option<PathBuf>,
 }
@@ -170,6 +173,7 @@ async fn setup(
         // Attach erofs + data disk and NIC. Only one modify_backend() call is
         // allowed, so combine all PCIe device setup in a single call.
         let data_disk_path = self.data_disk.clone();
+        let virtio_blk_queues = self.virtio_blk_queues;
         match self.backend {
             DiskBackend::VirtioBlk => {
                 // Build the disk resource before entering the closure (which
@@ -191,6 +195,7 @@ async fn setup(
                                     virtio_resources::blk::VirtioBlkHandle {
                                         disk: FileDiskHandle(erofs_file.into()).into_resource(),
                                         read_only: true,
+                                        num_queues: None,
                                     }
                                     .into_resource(),
                                 )
@@ -203,6 +208,7 @@ async fn setup(
                                     virtio_resources::blk::VirtioBlkHandle {
                                         disk,
                                         read_only: false,
+                                        num_queues: virtio_blk_queues,
                                     }
                                     .into_resource(),
                                 )
@@ -231,6 +237,7 @@ async fn setup(
                                         virtio_resources::blk::VirtioBlkHandle {
                                             disk: FileDiskHandle(erofs_file.into()).into_resource(),
                                             read_only: true,
+                                            num_queues: None,
                                         }
                                         .into_resource(),
                                     )
@@ -12,6 +12,8 @@
 //!   Uses linux_direct with a read-only erofs image carrying iperf3.
 //! - **TAP**: kernel networking via a TAP device in a network namespace
 //!   (Linux only)
+//! - **TAP with vhost-net**: as TAP, with frames moved by the kernel's
+//!   vhost-net driver (Linux only, requires access to `/dev/vhost-net`)
 //!
 //! Each backend can use either VMBus (NETVSP) or virtio-net (PCIe) as
 //! the NIC frontend, selected via the `--nic` flag.
@@ -46,6 +48,9 @@ pub enum NetBackend {
     Consomme,
     /// TAP device in a network namespace (Linux only).
     Tap,
+    /// TAP device accelerated by vhost-net (Linux only).
+    #[value(name = "tap-vhost")]
+    TapVhost,
 }
 
 /// Network throughput test via iperf3.
@@ -109,6 +114,8 @@ fn name(&self) -> &str {
             (NetBackend::Consomme, NicBackend::VirtioNet) => "network_virtio",
             (NetBackend::Tap, NicBackend::Vmbus) => "network_tap_vmbus",
             (NetBackend::Tap, NicBackend::VirtioNet) => "network_tap_virtio",
+            (NetBackend::TapVhost, NicBackend::Vmbus) => "network_tap_vhost_vmbus",
+            (NetBackend::TapVhost, NicBackend::VirtioNet) => "network_tap_vhost_virtio",
         }
     }
 
@@ -138,9 +145,11 @@ async fn setup(
         let helper_name = match self.backend {
             NetBackend::Consomme => "iperf-helper",
             #[cfg(target_os = "linux")]
-            NetBackend::Tap => "tap-ns-helper",
+            NetBackend::Tap | NetBackend::TapVhost => "tap-ns-helper",
             #[cfg(not(target_os = "linux"))]
-            NetBackend::Tap => anyhow::bail!("TAP backend is only supported on Linux"),
+            NetBackend::Tap | NetBackend::TapVhost => {
+                anyhow::bail!("TAP backend is only supported on Linux")
+            }
         };
 
         let helper_mesh =
@@ -164,7 +173,7 @@ async fn setup(
 
         // For TAP backend, ask the helper to create the TAP device.
         #[cfg(target_os = "linux")]
-        let tap_fd = if self.backend == NetBackend::Tap {
+        let tap_fd = if matches!(self.backend, NetBackend::Tap | NetBackend::TapVhost) {
             use mesh::rpc::RpcSend;
             let fd = ready
                 .requests
@@ -196,6 +205,7 @@ async fn setup(
         let test_name = match self.backend {
             NetBackend::Consomme => "network_consomme",
             NetBackend::Tap => "network_tap",
+            NetBackend::TapVhost => "network_tap_vhost",
         };
         let params = petri::PetriTestParams {
             test_name,
@@ -244,6 +254,7 @@ async fn setup(
                                     virtio_resources::blk::VirtioBlkHandle {
                                         disk: FileDiskHandle(erofs_file.into()).into_resource(),
                                         read_only: true,
+                                        num_queues: None,
                                     }
                                     .into_resource(),
                                 )
@@ -254,12 +265,13 @@ async fn setup(
                 });
             }
             #[cfg(target_os = "linux")]
-            NetBackend::Tap => {
-                let tap_fd = tap_fd.unwrap();
-                builder = tap::configure_builder(builder, tap_fd, self.nic, erofs_file);
+            NetBackend::Tap | NetBackend::TapVhost => {
+                let endpoint =
+                    tap::endpoint(tap_fd.unwrap(), self.backend == NetBackend::TapVhost)?;
+                builder = tap::configure_builder(builder, endpoint, self.nic, erofs_file);
             }
             #[cfg(not(target_os = "linux"))]
-            NetBackend::Tap => unreachable!(),
+            NetBackend::Tap | NetBackend::TapVhost => unreachable!(),
         }
 
         if !self.diag {
@@ -283,11 +295,11 @@ async fn setup(
                 tracing::info!(host_ip = %host_ip, "detected host IP");
             }
             #[cfg(target_os = "linux")]
-            NetBackend::Tap => {
+            NetBackend::Tap | NetBackend::TapVhost => {
                 host_ip = tap::setup_guest_networking(&agent).await?;
             }
             #[cfg(not(target_os = "linux"))]
-            NetBackend::Tap => unreachable!(),
+            NetBackend::Tap | NetBackend::TapVhost => unreachable!(),
         }
 
         // Mount the erofs image (iperf3 pre-installed) and prepare chroot.
@@ -483,13 +495,33 @@ mod tap {
     use openvmm_defs::config::PcieDeviceConfig;
     use petri::pipette::cmd;
     use vm_resource::IntoResource;
+    use vm_resource::Resource;
+    use vm_resource::kind::NetEndpointHandleKind;
 
     /// MAC address for the TAP NIC (one byte different from Consomme's).
     const TAP_MAC_ADDRESS: MacAddress = MacAddress::new([0x00, 0x15, 0x5D, 0x12, 0x12, 0x13]);
 
-    /// Add a VMBus synthnic backed by a TAP fd to the VM config.
-    fn add_tap_nic(config: &mut openvmm_defs::config::Config, tap_fd: std::os::fd::OwnedFd) {
-        let endpoint = net_backend_resources::tap::TapHandle { fd: tap_fd }.into_resource();
+    /// Build the endpoint for a TAP fd, optionally accelerated by vhost-net.
+    pub(super) fn endpoint(
+        tap_fd: std::os::fd::OwnedFd,
+        vhost: bool,
+    ) -> anyhow::Result<Resource<NetEndpointHandleKind>> {
+        Ok(if vhost {
+            let vhost = net_tap::vhost::open_vhost_net()?;
+            net_backend_resources::tap::TapVhostHandle {
+                queues: vec![net_backend_resources::tap::TapVhostQueue { tap: tap_fd, vhost }],
+            }
+            .into_resource()
+        } else {
+            net_backend_resources::tap::TapHandle { fd: tap_fd }.into_resource()
+        })
+    }
+
+    /// Add a VMBus synthnic backed by a TAP endpoint to the VM config.
+    fn add_tap_nic(
+        config: &mut openvmm_defs::config::Config,
+        endpoint: Resource<NetEndpointHandleKind>,
+    ) {
         const TAP_NETVSP_INSTANCE: guid::Guid = guid::guid!("a1b2c3d4-e5f6-7890-abcd-ef1234567890");
 
         config.vmbus_devices.push((
@@ -504,15 +536,17 @@ fn add_tap_nic(config: &mut openvmm_defs::config::Config, tap_fd: std::os::fd::O
         ));
     }
 
-    /// Add a virtio-net NIC backed by a TAP fd to the VM config (PCIe).
-    fn add_virtio_tap_nic(config: &mut openvmm_defs::config::Config, tap_fd: std::os::fd::OwnedFd) {
-        let endpoint = net_backend_resources::tap::TapHandle { fd: tap_fd }.into_resource();
-
+    /// Add a virtio-net NIC backed by a TAP endpoint to the VM config (PCIe).
+    fn add_virtio_tap_nic(
+        config: &mut openvmm_defs::config::Config,
+        endpoint: Resource<NetEndpointHandleKind>,
+    ) {
         config.pcie_devices.push(PcieDeviceConfig {
             port_name: "s0rc0rp1".into(),
             resource: virtio_resources::VirtioPciDeviceHandle(
                 virtio_resources::net::VirtioNetHandle {
                     max_queues: None,
+                    mtu: None,
                     mac_address: TAP_MAC_ADDRESS,
                     endpoint,
                 }
@@ -526,7 +560,7 @@ fn add_virtio_tap_nic(config: &mut openvmm_defs::config::Config, tap_fd: std::os
     /// plus a read-only virtio-blk device with the erofs image.
     pub(super) fn configure_builder(
         builder: petri::PetriVmBuilder<petri::openvmm::OpenVmmPetriBackend>,
-        tap_fd: std::os::fd::OwnedFd,
+        endpoint: Resource<NetEndpointHandleKind>,
         nic: super::NicBackend,
         erofs_file: fs_err::File,
     ) -> petri::PetriVmBuilder<petri::openvmm::OpenVmmPetriBackend> {
@@ -552,6 +586,7 @@ pub(super) fn configure_builder(
                         virtio_resources::blk::VirtioBlkHandle {
                             disk: FileDiskHandle(erofs_file.into()).into_resource(),
                             read_only: true,
+                            num_queues: None,
                         }
                         .into_resource(),
                     )
@@ -560,8 +595,8 @@ pub(super) fn configure_builder(
 
                 // Add TAP NIC.
                 match nic {
-                    super::NicBackend::Vmbus => add_tap_nic(config, tap_fd),
-                    super::NicBackend::VirtioNet => add_virtio_tap_nic(config, tap_fd),
+                    super::NicBackend::Vmbus => add_tap_nic(config, endpoint),
+                    super::NicBackend::VirtioNet => add_virtio_tap_nic(config, endpoint),
                 }
             })
         })
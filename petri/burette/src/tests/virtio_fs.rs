@@ -172,6 +172,7 @@ async fn setup(
                             virtio_resources::blk::VirtioBlkHandle {
                                 disk: FileDiskHandle(erofs_file.into()).into_resource(),
                                 read_only: true,
+                                num_queues: None,
                             }
                             .into_resource(),
                         )
@@ -334,31 +334,46 @@ pub async fn new(
         };
 
         // Add default VMBus devices (skipped in minimal mode and no-vmbus mode).
-        let (shutdown_ic_send, kvp_ic_send) = if !properties.minimal_mode && !properties.no_vmbus {
-            let (shutdown_ic_send, shutdown_ic_recv) = mesh::channel();
-            vmbus_devices.push((
-                DeviceVtl::Vtl0,
-                ShutdownIcHandle {
-                    recv: shutdown_ic_recv,
-                }
-                .into_resource(),
-            ));
+        let (shutdown_ic_send, kvp_ic_send, heartbeat_ic) =
+            if !properties.minimal_mode && !properties.no_vmbus {
+                let (shutdown_ic_send, shutdown_ic_recv) = mesh::channel();
+                vmbus_devices.push((
+                    DeviceVtl::Vtl0,
+                    ShutdownIcHandle {
+                        recv: shutdown_ic_recv,
+                    }
+                    .into_resource(),
+                ));
 
-            let (kvp_ic_send, kvp_ic_recv) = mesh::channel();
-            vmbus_devices.push((
-                DeviceVtl::Vtl0,
-                hyperv_ic_resources::kvp::KvpIcHandle { recv: kvp_ic_recv }.into_resource(),
-            ));
+                let (kvp_ic_send, kvp_ic_recv) = mesh::channel();
+                vmbus_devices.push((
+                    DeviceVtl::Vtl0,
+                    hyperv_ic_resources::kvp::KvpIcHandle { recv: kvp_ic_recv }.into_resource(),
+                ));
 
-            vmbus_devices.push((
-                DeviceVtl::Vtl0,
-                hyperv_ic_resources::timesync::TimesyncIcHandle.into_resource(),
-            ));
+                vmbus_devices.push((
+                    DeviceVtl::Vtl0,
+                    hyperv_ic_resources::timesync::TimesyncIcHandle.into_resource(),
+                ));
 
-            (Some(shutdown_ic_send), Some(kvp_ic_send))
-        } else {
-            (None, None)
-        };
+                let (heartbeat_ic_updater, heartbeat_ic) =
+                    mesh::cell(hyperv_ic_resources::heartbeat::HeartbeatStatus::NoContact);
+                vmbus_devices.push((
+                    DeviceVtl::Vtl0,
+                    hyperv_ic_resources::heartbeat::HeartbeatIcHandle {
+                        status: heartbeat_ic_updater,
+                    }
+                    .into_resource(),
+                ));
+
+                (
+                    Some(shutdown_ic_send),
+                    Some(kvp_ic_send),
+                    Some(heartbeat_ic),
+                )
+            } else {
+                (None, None, None)
+            };
 
         // Make a vmbus or virtio vsock path for pipette connections
         let (vsock_listener, vsock_path) = make_vsock_listener()?;
@@ -679,6 +694,7 @@ pub async fn new(
             input: mesh::Receiver::new(),
             vtl2_gfx: false,
             virtio_devices: vec![],
+            e1000e_pci_devices: vec![],
             #[cfg(windows)]
             vpci_resources: vec![],
             debugger_rpc: None,
@@ -731,6 +747,7 @@ pub async fn new(
                 firmware_event_recv,
                 shutdown_ic_send,
                 kvp_ic_send,
+                heartbeat_ic,
                 ged_send,
                 pipette_listener,
                 vtl2_pipette_listener,
@@ -755,6 +772,8 @@ pub async fn new(
             framebuffer_view,
 
             pending_iommu: Vec::new(),
+
+            incoming_migration: None,
         })
     }
 }
@@ -1507,6 +1526,7 @@ async fn vmbus_storage_controllers_to_openvmm(
                             VirtioBlkHandle {
                                 disk: petri_disk_to_openvmm(disk).await?,
                                 read_only: false,
+                                num_queues: None,
                             }
                             .into_resource(),
                         )
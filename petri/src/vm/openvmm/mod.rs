@@ -185,6 +185,9 @@ pub struct PetriVmConfigOpenVmm {
     // Deferred IOMMU configuration: (rc_name, iommu_config) pairs resolved
     // against pcie_root_complexes at VM start time.
     pending_iommu: Vec<(String, openvmm_defs::config::PcieIommuConfig)>,
+
+    // Migration stream to receive the VM from instead of booting it.
+    incoming_migration: Option<socket2::Socket>,
 }
 /// Various channels and resources used to interact with the VM while it is running.
 struct PetriVmResourcesOpenVmm {
@@ -192,6 +195,7 @@ struct PetriVmResourcesOpenVmm {
     firmware_event_recv: Receiver<FirmwareEvent>,
     shutdown_ic_send: Option<Sender<ShutdownRpc>>,
     kvp_ic_send: Option<Sender<hyperv_ic_resources::kvp::KvpConnectRpc>>,
+    heartbeat_ic: Option<mesh::Cell<hyperv_ic_resources::heartbeat::HeartbeatStatus>>,
     ged_send: Option<Sender<get_resources::ged::GuestEmulationRequest>>,
     pipette_listener: PolledSocket<UnixListener>,
     vtl2_pipette_listener: Option<PolledSocket<UnixListener>>,
@@ -291,6 +295,7 @@ async fn memdiff_disk(path: &Path) -> anyhow::Result<Resource<DiskHandleKind>> {
             .into(),
             DiskLayerHandle(disk).into_resource().into(),
         ],
+        requests: None,
     }
     .into_resource())
 }
@@ -299,7 +304,7 @@ fn memdiff_remote_disk(url: &str) -> anyhow::Result<Resource<DiskHandleKind>> {
     // Strip query parameters and fragments before checking the file extension.
     let url_path = url.split(['?', '#']).next().unwrap_or(url);
     let format = if url_path.ends_with(".vhd") || url_path.ends_with(".vmgs") {
-        disk_backend_resources::BlobDiskFormat::FixedVhd1
+        disk_backend_resources::BlobDiskFormat::Vhd1
     } else {
         disk_backend_resources::BlobDiskFormat::Flat
     };
@@ -312,7 +317,7 @@ fn memdiff_remote_disk(url: &str) -> anyhow::Result<Resource<DiskHandleKind>> {
     // even if the filename stays the same. For flat-format blobs (e.g. ISOs),
     // fall back to the URL filename since there's no embedded ID.
     let cache_key = match format {
-        disk_backend_resources::BlobDiskFormat::FixedVhd1 => None,
+        disk_backend_resources::BlobDiskFormat::Vhd1 => None,
         disk_backend_resources::BlobDiskFormat::Flat => {
             Some(url_path.rsplit('/').next().unwrap_or(url_path).to_owned())
         }
@@ -345,6 +350,7 @@ fn memdiff_remote_disk(url: &str) -> anyhow::Result<Resource<DiskHandleKind>> {
             .into_resource()
             .into(),
         ],
+        requests: None,
     }
     .into_resource())
 }
@@ -92,6 +92,10 @@ pub fn with_nic(mut self) -> Self {
             cidr: None,
             ports: Vec::new(),
             recv: None,
+            egress: Default::default(),
+            guest_forwards: Vec::new(),
+            dns_records: Vec::new(),
+            log_dns_queries: false,
         }
         .into_resource();
         if let Some(vtl2_settings) = self.runtime_config.vtl2_settings.as_mut() {
@@ -138,6 +142,10 @@ pub fn with_pcie_nic(mut self, port_name: &str, mac_address: MacAddress) -> Self
             cidr: None,
             ports: Vec::new(),
             recv: None,
+            egress: Default::default(),
+            guest_forwards: Vec::new(),
+            dns_records: Vec::new(),
+            log_dns_queries: false,
         }
         .into_resource();
         self.config.pcie_devices.push(PcieDeviceConfig {
@@ -188,6 +196,10 @@ pub fn with_virtio_nic(mut self, port_name: &str) -> Self {
             cidr: None,
             ports: Vec::new(),
             recv: None,
+            egress: Default::default(),
+            guest_forwards: Vec::new(),
+            dns_records: Vec::new(),
+            log_dns_queries: false,
         }
         .into_resource();
 
@@ -196,6 +208,7 @@ pub fn with_virtio_nic(mut self, port_name: &str) -> Self {
             resource: virtio_resources::VirtioPciDeviceHandle(
                 virtio_resources::net::VirtioNetHandle {
                     max_queues: None,
+                    mtu: None,
                     mac_address: NIC_MAC_ADDRESS,
                     endpoint,
                 }
@@ -227,6 +240,10 @@ pub fn with_tcp_pipette_nic(mut self, port_name: &str) -> Self {
                 guest_port: pipette_client::PIPETTE_PORT as u16,
             }],
             recv: None,
+            egress: Default::default(),
+            guest_forwards: Vec::new(),
+            dns_records: Vec::new(),
+            log_dns_queries: false,
         }
         .into_resource();
         self.config.pcie_devices.push(PcieDeviceConfig {
@@ -234,6 +251,7 @@ pub fn with_tcp_pipette_nic(mut self, port_name: &str) -> Self {
             resource: virtio_resources::VirtioPciDeviceHandle(
                 virtio_resources::net::VirtioNetHandle {
                     max_queues: None,
+                    mtu: None,
                     mac_address: NIC_MAC_ADDRESS,
                     endpoint,
                 }
@@ -336,6 +354,16 @@ pub fn with_vtl2_relocation_mode(mut self, mode: Vtl2BaseAddressType) -> Self {
         self
     }
 
+    /// Receive the VM from a live migration stream instead of booting it.
+    ///
+    /// The VM must be configured identically to the migration source, whose
+    /// [`PetriVmOpenVmm::migrate_out`](super::PetriVmOpenVmm::migrate_out)
+    /// must run concurrently with starting this VM.
+    pub fn with_incoming_migration(mut self, socket: socket2::Socket) -> Self {
+        self.incoming_migration = Some(socket);
+        self
+    }
+
     /// Use a file-backed memory region instead of anonymous RAM.
     ///
     /// The file at the given path will be created (or opened) and sized to
@@ -27,6 +27,7 @@
 use mesh::rpc::RpcError;
 use mesh::rpc::RpcSend;
 use mesh_process::Mesh;
+use openvmm_defs::rpc::MigrationStats;
 use openvmm_defs::rpc::PulseSaveRestoreError;
 use pal_async::socket::PolledSocket;
 use petri_artifacts_core::ResolvedArtifact;
@@ -273,6 +274,10 @@ pub async fn send_enlightened_shutdown(&mut self, kind: ShutdownKind) -> anyhow:
         /// to send requests to it.
         pub async fn wait_for_kvp(&mut self) -> anyhow::Result<mesh::Sender<hyperv_ic_resources::kvp::KvpRpc>>
     );
+    petri_vm_fn!(
+        /// Waits for the guest to report a healthy status via the heartbeat IC.
+        pub async fn wait_for_heartbeat(&mut self) -> anyhow::Result<()>
+    );
     petri_vm_fn!(
         /// Stages the new OpenHCL file and saves the existing state.
         pub async fn save_openhcl(
@@ -336,6 +341,17 @@ pub async fn save_state(&mut self) -> anyhow::Result<Vec<u8>>
         /// Resume a paused VM.
         pub async fn resume(&mut self) -> anyhow::Result<()>
     );
+    petri_vm_fn!(
+        /// Live migrate the VM over `socket` to a VM started with
+        /// [`PetriVmConfigOpenVmm::with_incoming_migration`](super::PetriVmConfigOpenVmm::with_incoming_migration).
+        /// On success the VM is left stopped, and should be torn down.
+        pub async fn migrate_out(
+            &mut self,
+            socket: socket2::Socket,
+            max_downtime_ms: u64,
+            max_passes: u32
+        ) -> anyhow::Result<MigrationStats>
+    );
     petri_vm_fn!(
         /// Perform a pulse save/restore cycle: pause the VM, save all state,
         /// reset, restore, and resume. Useful for verifying that device state
@@ -484,6 +500,19 @@ async fn wait_for_kvp(
         Ok(send)
     }
 
+    async fn wait_for_heartbeat(&mut self) -> anyhow::Result<()> {
+        tracing::info!("Waiting for heartbeat IC");
+        let cell = self
+            .resources
+            .heartbeat_ic
+            .as_mut()
+            .context("heartbeat IC not configured")?;
+        while cell.get() != hyperv_ic_resources::heartbeat::HeartbeatStatus::Ok {
+            cell.wait_next().await;
+        }
+        Ok(())
+    }
+
     async fn save_openhcl(
         &self,
         new_openhcl: &ResolvedArtifact,
@@ -701,6 +730,17 @@ async fn resume(&self) -> anyhow::Result<()> {
         Ok(())
     }
 
+    async fn migrate_out(
+        &self,
+        socket: socket2::Socket,
+        max_downtime_ms: u64,
+        max_passes: u32,
+    ) -> anyhow::Result<MigrationStats> {
+        self.worker
+            .migrate_out(socket, max_downtime_ms, max_passes)
+            .await
+    }
+
     async fn verify_save_restore(&self) -> anyhow::Result<()> {
         for i in 0..2 {
             let result = self.worker.pulse_save_restore().await;
@@ -46,6 +46,8 @@ async fn run_core(self) -> anyhow::Result<(PetriVmOpenVmm, PetriVmRuntimeConfig)
             framebuffer_view,
 
             pending_iommu,
+
+            incoming_migration,
         } = self;
 
         // Resolve deferred IOMMU assignments.
@@ -145,7 +147,8 @@ async fn run_core(self) -> anyhow::Result<(PetriVmOpenVmm, PetriVmRuntimeConfig)
             );
         }
 
-        let (worker, halt_notif) = Worker::launch(&host, config, shared_memory)
+        let is_incoming_migration = incoming_migration.is_some();
+        let (worker, halt_notif) = Worker::launch(&host, config, shared_memory, incoming_migration)
             .await
             .context("failed to launch vm worker")?;
 
@@ -181,8 +184,9 @@ async fn run_core(self) -> anyhow::Result<(PetriVmOpenVmm, PetriVmRuntimeConfig)
         tracing::info!("Resuming VM");
         vm.resume().await?;
 
-        // Run basic save/restore test if it is supported
-        if supports_save_restore && !is_minimal {
+        // Run basic save/restore test if it is supported. A migrated VM has
+        // already been restored.
+        if supports_save_restore && !is_minimal && !is_incoming_migration {
             tracing::info!("Testing save/restore");
             vm.verify_save_restore().await?;
         }
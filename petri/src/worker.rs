@@ -8,6 +8,8 @@
 use mesh_worker::WorkerHandle;
 use mesh_worker::WorkerHost;
 use openvmm_defs::config::Config;
+use openvmm_defs::rpc::MigrateOutParams;
+use openvmm_defs::rpc::MigrationStats;
 use openvmm_defs::rpc::PulseSaveRestoreError;
 use openvmm_defs::rpc::VmRpc;
 use openvmm_defs::worker::VM_WORKER;
@@ -24,6 +26,7 @@ pub(crate) async fn launch(
         host: &WorkerHost,
         cfg: Config,
         shared_memory: Option<openvmm_defs::worker::SharedMemoryFd>,
+        incoming_migration: Option<socket2::Socket>,
     ) -> anyhow::Result<(Self, mesh::Receiver<HaltReason>)> {
         let (vm_rpc, rpc_recv) = mesh::channel();
         let (notify_send, notify_recv) = mesh::channel();
@@ -33,6 +36,7 @@ pub(crate) async fn launch(
             cfg,
             saved_state: None,
             shared_memory,
+            incoming_migration,
             rpc: rpc_recv,
             notify: notify_send,
         };
@@ -60,6 +64,26 @@ pub(crate) async fn save(&self) -> anyhow::Result<mesh::payload::message::Protob
         Ok(msg)
     }
 
+    pub(crate) async fn migrate_out(
+        &self,
+        socket: socket2::Socket,
+        max_downtime_ms: u64,
+        max_passes: u32,
+    ) -> anyhow::Result<MigrationStats> {
+        let stats = self
+            .rpc
+            .call_failable(
+                VmRpc::MigrateOut,
+                MigrateOutParams {
+                    socket,
+                    max_downtime_ms,
+                    max_passes,
+                },
+            )
+            .await?;
+        Ok(stats)
+    }
+
     pub(crate) async fn reset(&self) -> anyhow::Result<()> {
         self.rpc.call(VmRpc::Reset, ()).await??;
         Ok(())
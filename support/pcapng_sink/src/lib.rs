@@ -0,0 +1,86 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Destinations for `pcapng` packet capture streams.
+//!
+//! A packet capture produces one continuous `pcapng` byte stream per network
+//! interface. Besides writing it to a single file, this crate can split the
+//! stream into a ring buffer of size-limited files, or forward it to a FIFO or
+//! Unix socket so that a viewer such as Wireshark can follow the capture live.
+
+#![forbid(unsafe_code)]
+
+mod live;
+mod rotate;
+
+pub use live::LiveStream;
+pub use rotate::RotatingWriter;
+pub use rotate::Rotation;
+
+use std::ffi::OsString;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Where to write capture streams.
+#[derive(Debug, Clone)]
+pub enum Output {
+    /// Write each stream to a file.
+    File {
+        /// The file path. The stream index is appended to the file stem.
+        path: PathBuf,
+        /// If set, the file is split into a ring buffer of files.
+        rotation: Option<Rotation>,
+    },
+    /// Stream live to a FIFO or Unix socket. See [`LiveStream::open`].
+    Live {
+        /// The FIFO or socket path. The stream index is appended to the file
+        /// name.
+        path: PathBuf,
+    },
+}
+
+impl Output {
+    /// Opens the destination for stream `index`.
+    ///
+    /// For live output, this blocks until a reader attaches.
+    pub fn open(&self, index: usize) -> io::Result<Box<dyn Write + Send>> {
+        let path = self.path(index);
+        Ok(match self {
+            Output::File {
+                rotation: Some(rotation),
+                ..
+            } => Box::new(RotatingWriter::new(path, *rotation)?),
+            Output::File { rotation: None, .. } => Box::new(fs_err::File::create(path)?),
+            Output::Live { .. } => Box::new(LiveStream::open(&path)?),
+        })
+    }
+
+    /// Returns the path of stream `index`. Files without an extension get a
+    /// `.pcap` extension.
+    pub fn path(&self, index: usize) -> PathBuf {
+        match self {
+            Output::File { path, .. } => {
+                let mut path = stream_path(path, index);
+                if path.extension().is_none() {
+                    path.set_extension("pcap");
+                }
+                path
+            }
+            Output::Live { path } => stream_path(path, index),
+        }
+    }
+}
+
+/// Returns the path for stream `index` of a capture written to `path`, with
+/// the index appended to the file stem (`nic.pcap` becomes `nic-0.pcap`).
+fn stream_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = OsString::from(path.file_stem().unwrap_or_default());
+    name.push(format!("-{index}"));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
@@ -0,0 +1,72 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Live capture streaming to a FIFO or Unix socket.
+
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// A live capture stream, attached to a reader such as Wireshark.
+pub enum LiveStream {
+    /// An existing FIFO.
+    #[cfg(unix)]
+    Fifo(fs_err::File),
+    /// A connection accepted on a Unix socket.
+    Socket(unix_socket::UnixStream),
+}
+
+impl LiveStream {
+    /// Opens a live stream at `path`, blocking until a reader attaches.
+    ///
+    /// If `path` is an existing FIFO (on Unix hosts), the stream is written to
+    /// it, e.g. for `wireshark -k -i <path>`. Otherwise, a Unix socket is
+    /// created at `path` and the first connection to it receives the stream,
+    /// e.g. `socat UNIX-CONNECT:<path> - | wireshark -k -i -`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+
+            match fs_err::metadata(path) {
+                Ok(metadata) if metadata.file_type().is_fifo() => {
+                    let fifo = fs_err::OpenOptions::new().write(true).open(path)?;
+                    return Ok(Self::Fifo(fifo));
+                }
+                // Replace the socket left behind by a previous capture.
+                Ok(metadata) if metadata.file_type().is_socket() => fs_err::remove_file(path)?,
+                _ => {}
+            }
+        }
+
+        let listener = unix_socket::UnixListener::bind(path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("failed to create socket {}: {err}", path.display()),
+            )
+        })?;
+        let (stream, _) = listener.accept()?;
+        drop(listener);
+        // Nobody else can connect, so remove the socket.
+        let _ = std::fs::remove_file(path);
+        Ok(Self::Socket(stream))
+    }
+}
+
+impl Write for LiveStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            Self::Fifo(fifo) => fifo.write(buf),
+            Self::Socket(socket) => socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Self::Fifo(fifo) => fifo.flush(),
+            Self::Socket(socket) => socket.flush(),
+        }
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Ring buffer of size-limited capture files.
+
+use std::ffi::OsString;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Size-based rotation of capture files.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rotation {
+    /// Start a new file before the current one would grow past this many
+    /// bytes. A file always holds at least one packet.
+    pub max_file_size: u64,
+    /// Keep at most this many files, deleting the oldest one when starting a
+    /// new one. Zero keeps all files.
+    pub max_files: u32,
+}
+
+/// The section header block type. It reads the same in either byte order.
+const SECTION_HEADER: u32 = 0x0a0d0d0a;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+/// Block type, total length, and (for section headers) byte order magic.
+const BLOCK_PREFIX_LEN: usize = 12;
+/// Block type, total length, and the trailing copy of the length.
+const MIN_BLOCK_LEN: usize = 12;
+
+/// A writer that splits a `pcapng` stream across a ring buffer of files.
+///
+/// The stream is split at block boundaries. Each file starts with the current
+/// section header and interface description blocks, so that every file can be
+/// opened on its own. Files are named by appending a sequence number to the
+/// file stem: `nic-0.pcap` is written as `nic-0_00000.pcap`,
+/// `nic-0_00001.pcap`, and so on.
+pub struct RotatingWriter {
+    path: PathBuf,
+    rotation: Rotation,
+    file: fs_err::File,
+    sequence: u64,
+    file_len: u64,
+    file_has_packets: bool,
+    /// The byte order of the current section, once its header is seen.
+    big_endian: Option<bool>,
+    /// The current section header and interface description blocks.
+    headers: Vec<u8>,
+    /// Bytes of an incomplete block.
+    pending: Vec<u8>,
+}
+
+impl RotatingWriter {
+    /// Creates the first file of the ring buffer.
+    pub fn new(path: impl Into<PathBuf>, rotation: Rotation) -> io::Result<Self> {
+        let path = path.into();
+        let file = fs_err::File::create(file_path(&path, 0))?;
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            sequence: 0,
+            file_len: 0,
+            file_has_packets: false,
+            big_endian: None,
+            headers: Vec::new(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Returns the type and length of the block at the start of `data`, or
+    /// `None` if the block is not complete yet.
+    fn next_block(&mut self, data: &[u8]) -> io::Result<Option<(u32, usize)>> {
+        if data.len() < BLOCK_PREFIX_LEN {
+            return Ok(None);
+        }
+        let read = |b: &[u8], big_endian| {
+            let b = b[..4].try_into().unwrap();
+            if big_endian {
+                u32::from_be_bytes(b)
+            } else {
+                u32::from_le_bytes(b)
+            }
+        };
+        if read(data, true) == SECTION_HEADER {
+            self.big_endian = match read(&data[8..], true) {
+                BYTE_ORDER_MAGIC => Some(true),
+                magic if magic.swap_bytes() == BYTE_ORDER_MAGIC => Some(false),
+                _ => return Err(invalid_data("invalid pcapng byte order magic")),
+            };
+        }
+        let Some(big_endian) = self.big_endian else {
+            return Err(invalid_data(
+                "pcapng stream does not start with a section header",
+            ));
+        };
+        let block_type = read(data, big_endian);
+        let len = read(&data[4..], big_endian) as usize;
+        if len < MIN_BLOCK_LEN || len % 4 != 0 {
+            return Err(invalid_data("invalid pcapng block length"));
+        }
+        Ok((data.len() >= len).then_some((block_type, len)))
+    }
+
+    fn write_block(&mut self, block_type: u32, block: &[u8]) -> io::Result<()> {
+        match block_type {
+            SECTION_HEADER => {
+                self.headers.clear();
+                self.headers.extend_from_slice(block);
+            }
+            INTERFACE_DESCRIPTION => self.headers.extend_from_slice(block),
+            _ => {
+                if self.file_has_packets
+                    && self.file_len + block.len() as u64 > self.rotation.max_file_size
+                {
+                    self.rotate()?;
+                }
+                self.file_has_packets = true;
+            }
+        }
+        self.file.write_all(block)?;
+        self.file_len += block.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.sequence += 1;
+        self.file = fs_err::File::create(file_path(&self.path, self.sequence))?;
+        self.file.write_all(&self.headers)?;
+        self.file_len = self.headers.len() as u64;
+        self.file_has_packets = false;
+        if self.rotation.max_files != 0
+            && let Some(oldest) = self.sequence.checked_sub(self.rotation.max_files.into())
+        {
+            match fs_err::remove_file(file_path(&self.path, oldest)) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend_from_slice(buf);
+        let mut offset = 0;
+        let result = loop {
+            match self.next_block(&pending[offset..]) {
+                Ok(Some((block_type, len))) => {
+                    if let Err(err) = self.write_block(block_type, &pending[offset..][..len]) {
+                        break Err(err);
+                    }
+                    offset += len;
+                }
+                Ok(None) => break Ok(buf.len()),
+                Err(err) => break Err(err),
+            }
+        };
+        pending.drain(..offset);
+        self.pending = pending;
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn file_path(path: &Path, sequence: u64) -> PathBuf {
+    let mut name = OsString::from(path.file_stem().unwrap_or_default());
+    name.push(format!("_{sequence:05}"));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let len = (12 + body.len()) as u32;
+        let mut block = Vec::new();
+        block.extend_from_slice(&block_type.to_be_bytes());
+        block.extend_from_slice(&len.to_be_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&len.to_be_bytes());
+        block
+    }
+
+    fn section_header() -> Vec<u8> {
+        let mut body = BYTE_ORDER_MAGIC.to_be_bytes().to_vec();
+        body.extend_from_slice(&[0, 1, 0, 0]);
+        body.extend_from_slice(&[0xff; 8]);
+        block(SECTION_HEADER, &body)
+    }
+
+    #[test]
+    fn rotates_at_block_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nic-0.pcap");
+        let mut writer = RotatingWriter::new(
+            &path,
+            Rotation {
+                max_file_size: 200,
+                max_files: 2,
+            },
+        )
+        .unwrap();
+
+        let shb = section_header();
+        let idb = block(INTERFACE_DESCRIPTION, &[0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        let mut stream = [shb.clone(), idb.clone()].concat();
+        for i in 0..5u8 {
+            stream.extend(block(6, &[i; 96]));
+        }
+        // Write in small pieces to exercise reassembly of blocks.
+        for chunk in stream.chunks(7) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+
+        // Each file holds the headers and one packet, and only the last two
+        // files are kept.
+        assert!(!dir.path().join("nic-0_00002.pcap").exists());
+        for (sequence, packet) in [(3, 3u8), (4, 4)] {
+            let data = std::fs::read(dir.path().join(format!("nic-0_{sequence:05}.pcap"))).unwrap();
+            assert_eq!(
+                data,
+                [shb.clone(), idb.clone(), block(6, &[packet; 96])].concat()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_stream_without_section_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = RotatingWriter::new(
+            dir.path().join("nic.pcap"),
+            Rotation {
+                max_file_size: 1000,
+                max_files: 0,
+            },
+        )
+        .unwrap();
+        let err = writer.write_all(&block(6, &[0; 16])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -18,6 +18,7 @@
 pub use sys::SparseMapping;
 pub use sys::alloc_shared_memory;
 pub use sys::alloc_shared_memory_hugetlb;
+pub use sys::discard_shared_memory;
 pub use sys::new_mappable_from_file;
 
 use std::mem::MaybeUninit;
@@ -566,6 +566,37 @@ pub fn alloc_shared_memory_hugetlb(
     ))
 }
 
+/// Releases the host memory backing a range of a shared memory object.
+///
+/// The object keeps its size. Later accesses to the range, through any
+/// mapping, see zeroes.
+#[cfg(target_os = "linux")]
+pub fn discard_shared_memory(fd: impl AsFd, offset: u64, len: u64) -> io::Result<()> {
+    let offset = libc::off_t::try_from(offset).map_err(|_| io::ErrorKind::InvalidInput)?;
+    let len = libc::off_t::try_from(len).map_err(|_| io::ErrorKind::InvalidInput)?;
+    // SAFETY: punching a hole in a file has no memory safety implications;
+    // existing mappings of the range just observe zeroes.
+    unsafe {
+        libc::fallocate(
+            fd.as_fd().as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        )
+        .syscall_result()?
+    };
+    Ok(())
+}
+
+/// Releases the host memory backing a range of a shared memory object.
+#[cfg(not(target_os = "linux"))]
+pub fn discard_shared_memory(_fd: impl AsFd, _offset: u64, _len: u64) -> io::Result<()> {
+    Err(Error::new(
+        io::ErrorKind::Unsupported,
+        "discarding shared memory is only supported on Linux",
+    ))
+}
+
 /// Calls `mbind(MPOL_BIND)` on an already-mapped virtual address range,
 /// binding it to a specific host NUMA node.
 ///
@@ -901,6 +901,16 @@ pub fn alloc_shared_memory(size: usize, _name: &str) -> io::Result<OwnedHandle>
     }
 }
 
+/// Releases the host memory backing a range of a shared memory object.
+///
+/// Not supported for section objects.
+pub fn discard_shared_memory(_handle: impl AsHandle, _offset: u64, _len: u64) -> io::Result<()> {
+    Err(Error::new(
+        io::ErrorKind::Unsupported,
+        "discarding shared memory is not supported on Windows",
+    ))
+}
+
 /// Allocates a hugetlb mappable shared memory object of `size` bytes.
 ///
 /// On Windows this creates a large-page section (`SEC_LARGE_PAGES`). Only the
@@ -488,6 +488,9 @@ async fn unmap(&mut self) {
     async fn set_format(&mut self, format: FramebufferFormat) {
         self.set_format(format);
     }
+    fn vram(&self) -> io::Result<(GuestMemory, usize)> {
+        Ok((self.memory()?, self.len()))
+    }
 }
 
 impl ResolveResource<FramebufferHandleKind, SharedFramebufferHandle> for FramebufferLocalControl {
@@ -18,6 +18,7 @@
 use vmbus_async::pipe::MessagePipe;
 use vmbus_channel::RawAsyncChannel;
 use vmbus_channel::gpadl_ring::GpadlRingMem;
+use vmbus_ring::RingMem;
 use zerocopy::FromBytes;
 use zerocopy::FromZeros;
 use zerocopy::IntoBytes;
@@ -26,9 +27,9 @@
 const FRAMEWORK_VERSIONS: &[Version] = &[FRAMEWORK_VERSION_1, FRAMEWORK_VERSION_3];
 
 #[derive(InspectMut)]
-pub(crate) struct IcPipe {
+pub(crate) struct IcPipe<T: RingMem = GpadlRingMem> {
     #[inspect(mut)]
-    pub pipe: MessagePipe<GpadlRingMem>,
+    pub pipe: MessagePipe<T>,
     #[inspect(skip)]
     buf: Vec<u8>,
 }
@@ -49,8 +50,8 @@ pub(crate) struct Versions {
     pub message_version: Version,
 }
 
-impl IcPipe {
-    pub fn new(raw: RawAsyncChannel<GpadlRingMem>) -> Result<Self, std::io::Error> {
+impl<T: RingMem> IcPipe<T> {
+    pub fn new(raw: RawAsyncChannel<T>) -> Result<Self, std::io::Error> {
         let pipe = MessagePipe::new(raw)?;
         let buf = vec![0; hyperv_ic_protocol::MAX_MESSAGE_SIZE];
         Ok(Self { pipe, buf })
@@ -164,3 +165,103 @@ pub async fn read_response(&mut self) -> anyhow::Result<(Status, &[u8])> {
         Ok((header.status, rest))
     }
 }
+
+/// A fake guest endpoint for testing IC state machines.
+#[cfg(test)]
+pub(crate) mod test_helpers {
+    use hyperv_ic_protocol::FRAMEWORK_VERSION_3;
+    use hyperv_ic_protocol::Header;
+    use hyperv_ic_protocol::HeaderFlags;
+    use hyperv_ic_protocol::MessageType;
+    use hyperv_ic_protocol::NegotiateMessage;
+    use hyperv_ic_protocol::Status;
+    use hyperv_ic_protocol::Version;
+    use std::io::IoSlice;
+    use vmbus_async::async_dgram::AsyncRecvExt;
+    use vmbus_async::async_dgram::AsyncSendExt;
+    use vmbus_async::pipe::MessagePipe;
+    use vmbus_channel::RawAsyncChannel;
+    use vmbus_ring::FlatRingMem;
+    use zerocopy::FromBytes;
+    use zerocopy::FromZeros;
+    use zerocopy::IntoBytes;
+
+    pub const RING_SIZE: usize = 0x10000;
+
+    pub struct FakeGuest {
+        pipe: MessagePipe<FlatRingMem>,
+        buf: Vec<u8>,
+    }
+
+    impl FakeGuest {
+        pub fn new(raw: RawAsyncChannel<FlatRingMem>) -> Self {
+            Self {
+                pipe: MessagePipe::new(raw).unwrap(),
+                buf: vec![0; hyperv_ic_protocol::MAX_MESSAGE_SIZE],
+            }
+        }
+
+        /// Reads the next request from the host, returning its header and
+        /// body.
+        pub async fn read_request(&mut self) -> (Header, Vec<u8>) {
+            let n = self.pipe.recv(&mut self.buf).await.unwrap();
+            let (header, rest) = Header::read_from_prefix(&self.buf[..n]).unwrap();
+            assert!(header.flags.transaction() && header.flags.request());
+            let body = rest[..header.message_size as usize].to_vec();
+            (header, body)
+        }
+
+        /// Responds to the request with header `request`.
+        pub async fn respond(&mut self, request: &Header, status: Status, body: &[u8]) {
+            let header = Header {
+                framework_version: request.framework_version,
+                message_type: request.message_type,
+                message_version: request.message_version,
+                message_size: body.len() as u16,
+                status,
+                transaction_id: request.transaction_id,
+                flags: HeaderFlags::new()
+                    .with_transaction(true)
+                    .with_response(true),
+                ..FromZeros::new_zeroed()
+            };
+            self.pipe
+                .send_vectored(&[IoSlice::new(header.as_bytes()), IoSlice::new(body)])
+                .await
+                .unwrap();
+        }
+
+        /// Accepts the host's version negotiation request, selecting
+        /// `message_version`.
+        pub async fn negotiate(&mut self, message_version: Version) {
+            let (header, body) = self.read_request().await;
+            assert_eq!(header.message_type, MessageType::VERSION_NEGOTIATION);
+            let (message, rest) = NegotiateMessage::read_from_prefix(&body).unwrap();
+            let (versions, _) = <[Version]>::ref_from_prefix_with_elems(
+                rest,
+                (message.framework_version_count + message.message_version_count) as usize,
+            )
+            .unwrap();
+            let (framework_versions, message_versions) =
+                versions.split_at(message.framework_version_count as usize);
+            assert!(framework_versions.contains(&FRAMEWORK_VERSION_3));
+            assert!(message_versions.contains(&message_version));
+
+            let response = NegotiateMessage {
+                framework_version_count: 1,
+                message_version_count: 1,
+                ..FromZeros::new_zeroed()
+            };
+            self.respond(
+                &header,
+                Status::SUCCESS,
+                &[
+                    response.as_bytes(),
+                    [FRAMEWORK_VERSION_3, message_version].as_bytes(),
+                ]
+                .concat(),
+            )
+            .await;
+        }
+    }
+}
@@ -0,0 +1,563 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The guest services IC, used to copy files from the host into the guest.
+//!
+//! Windows guests handle this in the Hyper-V Guest Service Interface service;
+//! Linux guests need `hv_fcopy_daemon` (or `hv_fcopy_uio_daemon`) running.
+
+use crate::common::IcPipe;
+use crate::common::NegotiateState;
+use crate::common::Versions;
+use anyhow::Context as _;
+use async_trait::async_trait;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures::stream::once;
+use futures_concurrency::stream::Merge;
+use guestmem::GuestMemory;
+use hyperv_ic_protocol::Status;
+use hyperv_ic_protocol::fcopy as proto;
+use hyperv_ic_resources::fcopy::CopyFileParams;
+use hyperv_ic_resources::fcopy::FcopyRpc;
+use inspect::Inspect;
+use inspect::InspectMut;
+use mesh::error::RemoteError;
+use mesh::rpc::Rpc;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::pin::pin;
+use task_control::Cancelled;
+use task_control::StopTask;
+use vmbus_channel::RawAsyncChannel;
+use vmbus_channel::bus::ChannelType;
+use vmbus_channel::bus::OfferParams;
+use vmbus_channel::channel::ChannelOpenError;
+use vmbus_channel::gpadl_ring::GpadlRingMem;
+use vmbus_channel::simple::SaveRestoreSimpleVmbusDevice;
+use vmbus_channel::simple::SimpleVmbusDevice;
+use vmbus_ring::RingMem;
+use vmcore::save_restore::NoSavedState;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+const FCOPY_VERSIONS: &[hyperv_ic_protocol::Version] = &[proto::FCOPY_VERSION_1_1];
+
+/// A guest services IC device.
+#[derive(InspectMut)]
+pub struct FcopyIc {
+    #[inspect(skip)]
+    recv: mesh::Receiver<FcopyRpc>,
+}
+
+#[doc(hidden)]
+#[derive(InspectMut)]
+pub struct FcopyChannel<T: RingMem = GpadlRingMem> {
+    #[inspect(mut)]
+    pipe: IcPipe<T>,
+    state: ChannelState,
+    transfer: Option<Transfer>,
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ChannelState {
+    Negotiate(#[inspect(flatten)] NegotiateState),
+    Ready {
+        versions: Versions,
+        state: ReadyState,
+    },
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ReadyState {
+    Ready,
+    SendRequest(#[inspect(debug)] proto::Operation),
+    WaitResponse(#[inspect(debug)] proto::Operation),
+}
+
+/// A file copy in progress.
+#[derive(Inspect)]
+struct Transfer {
+    #[inspect(skip)]
+    params: CopyFileParams,
+    guest_path: String,
+    file_size: u64,
+    offset: u64,
+    /// The reason the copy is being cancelled.
+    #[inspect(with = "Option::is_some")]
+    error: Option<anyhow::Error>,
+    #[inspect(skip)]
+    rpc: Rpc<(), Result<(), RemoteError>>,
+}
+
+impl FcopyIc {
+    /// Returns a new guest services IC, using `recv` to receive file copy
+    /// requests.
+    pub fn new(recv: mesh::Receiver<FcopyRpc>) -> Self {
+        Self { recv }
+    }
+}
+
+impl<T: RingMem> FcopyChannel<T> {
+    fn new(channel: RawAsyncChannel<T>) -> Result<Self, ChannelOpenError> {
+        let pipe = IcPipe::new(channel)?;
+        Ok(Self {
+            pipe,
+            state: ChannelState::Negotiate(NegotiateState::default()),
+            transfer: None,
+        })
+    }
+
+    async fn process(&mut self, ic: &mut FcopyIc) -> anyhow::Result<()> {
+        enum Event {
+            StateMachine(anyhow::Result<()>),
+            Request(FcopyRpc),
+        }
+
+        loop {
+            let event = pin!(
+                (
+                    once(self.process_state_machine().map(Event::StateMachine)),
+                    (&mut ic.recv).map(Event::Request),
+                )
+                    .merge()
+            )
+            .next()
+            .await
+            .unwrap();
+            match event {
+                Event::StateMachine(r) => {
+                    r?;
+                }
+                Event::Request(FcopyRpc::CopyFile(rpc)) => match &mut self.state {
+                    ChannelState::Negotiate(_) => {
+                        rpc.fail(anyhow::anyhow!("guest services ic not ready"));
+                    }
+                    ChannelState::Ready { state, .. } => match state {
+                        ReadyState::Ready => {
+                            let (params, rpc) = rpc.split();
+                            match params.file.metadata() {
+                                Ok(metadata) => {
+                                    self.transfer = Some(Transfer {
+                                        guest_path: format!(
+                                            "{}/{}",
+                                            params.guest_dir, params.file_name
+                                        ),
+                                        params,
+                                        file_size: metadata.len(),
+                                        offset: 0,
+                                        error: None,
+                                        rpc,
+                                    });
+                                    *state =
+                                        ReadyState::SendRequest(proto::Operation::START_FILE_COPY);
+                                }
+                                Err(err) => rpc.complete(Err(RemoteError::new(err))),
+                            }
+                        }
+                        ReadyState::SendRequest(_) | ReadyState::WaitResponse(_) => {
+                            rpc.fail(anyhow::anyhow!("file copy already in progress"));
+                        }
+                    },
+                },
+            }
+        }
+    }
+
+    async fn process_state_machine(&mut self) -> anyhow::Result<()> {
+        match self.state {
+            ChannelState::Negotiate(ref mut state) => {
+                if let Some(versions) = self.pipe.negotiate(state, FCOPY_VERSIONS).await? {
+                    self.state = ChannelState::Ready {
+                        versions,
+                        state: ReadyState::Ready,
+                    };
+                }
+            }
+            ChannelState::Ready {
+                ref mut state,
+                ref versions,
+            } => match *state {
+                ReadyState::Ready => std::future::pending().await,
+                ReadyState::SendRequest(op) => {
+                    let copy = self.transfer.as_mut().expect("copy in progress");
+                    let message = match build_message(copy, op) {
+                        Ok(message) => message,
+                        Err(err) => {
+                            if op == proto::Operation::START_FILE_COPY {
+                                // Nothing has been sent to the guest yet.
+                                let copy = self.transfer.take().unwrap();
+                                copy.rpc.complete(Err(RemoteError::new(err)));
+                                *state = ReadyState::Ready;
+                            } else {
+                                // The host file could not be read. Abandon
+                                // the partially written guest file.
+                                copy.error = Some(err);
+                                *state = ReadyState::SendRequest(proto::Operation::CANCEL_FCOPY);
+                            }
+                            return Ok(());
+                        }
+                    };
+                    self.pipe
+                        .write_message(
+                            versions,
+                            hyperv_ic_protocol::MessageType::GUEST_INTERFACE,
+                            hyperv_ic_protocol::HeaderFlags::new()
+                                .with_transaction(true)
+                                .with_request(true),
+                            &message,
+                        )
+                        .await?;
+                    *state = ReadyState::WaitResponse(op);
+                }
+                ReadyState::WaitResponse(op) => {
+                    let (status, _) = self.pipe.read_response().await?;
+                    let copy = self.transfer.as_mut().expect("copy in progress");
+                    let next = if status != Status::SUCCESS {
+                        let err = status_error(status, &copy.guest_path);
+                        match op {
+                            proto::Operation::WRITE_TO_FILE => {
+                                copy.error = Some(err);
+                                Some(proto::Operation::CANCEL_FCOPY)
+                            }
+                            _ => {
+                                copy.error.get_or_insert(err);
+                                None
+                            }
+                        }
+                    } else if op == proto::Operation::START_FILE_COPY
+                        || op == proto::Operation::WRITE_TO_FILE
+                    {
+                        if op == proto::Operation::WRITE_TO_FILE {
+                            copy.offset = (copy.offset + proto::DATA_FRAGMENT_SIZE as u64)
+                                .min(copy.file_size);
+                        }
+                        if copy.offset < copy.file_size {
+                            Some(proto::Operation::WRITE_TO_FILE)
+                        } else {
+                            Some(proto::Operation::COMPLETE_FCOPY)
+                        }
+                    } else {
+                        None
+                    };
+                    if let Some(next) = next {
+                        *state = ReadyState::SendRequest(next);
+                    } else {
+                        let copy = self.transfer.take().unwrap();
+                        let result = match copy.error {
+                            None => {
+                                tracing::info!(
+                                    path = copy.guest_path,
+                                    size = copy.file_size,
+                                    "copied file to guest"
+                                );
+                                Ok(())
+                            }
+                            Some(err) => Err(RemoteError::new(err)),
+                        };
+                        copy.rpc.complete(result);
+                        *state = ReadyState::Ready;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Builds the message for `op`, reading the next fragment of the host file
+/// for writes.
+fn build_message(copy: &mut Transfer, op: proto::Operation) -> anyhow::Result<Vec<u8>> {
+    let header = proto::FcopyHeader {
+        operation: op,
+        ..FromZeros::new_zeroed()
+    };
+    let message = match op {
+        proto::Operation::START_FILE_COPY => {
+            let mut message = proto::StartCopyMessage::new_box_zeroed().unwrap();
+            message.header = header;
+            encode_path(&mut message.file_name, &copy.params.file_name)
+                .context("file name too long")?;
+            encode_path(&mut message.path_name, &copy.params.guest_dir)
+                .context("guest directory too long")?;
+            message.copy_flags = proto::CopyFlags::new()
+                .with_overwrite(copy.params.overwrite)
+                .with_create_path(copy.params.create_path);
+            message.file_size = copy.file_size;
+            message.as_bytes().to_vec()
+        }
+        proto::Operation::WRITE_TO_FILE => {
+            let mut message = proto::WriteMessage::new_box_zeroed().unwrap();
+            let len = (copy.file_size - copy.offset).min(proto::DATA_FRAGMENT_SIZE as u64) as usize;
+            copy.params
+                .file
+                .seek(SeekFrom::Start(copy.offset))
+                .and_then(|_| copy.params.file.read_exact(&mut message.data[..len]))
+                .context("failed to read host file")?;
+            message.header = header;
+            message.offset = copy.offset;
+            message.size = len as u32;
+            message.as_bytes().to_vec()
+        }
+        _ => proto::FinishMessage { header }.as_bytes().to_vec(),
+    };
+    Ok(message)
+}
+
+/// Encodes `s` as a null-terminated UTF-16 string into `buf`.
+fn encode_path(buf: &mut [u16; proto::MAX_PATH], s: &str) -> Option<()> {
+    let mut len = 0;
+    for c in s.encode_utf16() {
+        *buf.get_mut(len)? = c;
+        len += 1;
+    }
+    // Leave room for the null terminator.
+    (len < buf.len()).then_some(())
+}
+
+fn status_error(status: Status, path: &str) -> anyhow::Error {
+    match status {
+        Status::ALREADY_EXISTS => anyhow::anyhow!("{path} already exists in the guest"),
+        Status::PATH_NOT_FOUND => anyhow::anyhow!("the guest directory for {path} does not exist"),
+        Status::DISK_FULL => anyhow::anyhow!("guest disk is full copying {path}"),
+        status => anyhow::anyhow!("guest failed to copy {path}: status {:#x}", status.0),
+    }
+}
+
+#[async_trait]
+impl SimpleVmbusDevice for FcopyIc {
+    type SavedState = NoSavedState;
+    type Runner = FcopyChannel;
+
+    fn offer(&self) -> OfferParams {
+        OfferParams {
+            interface_name: "fcopy_ic".to_owned(),
+            instance_id: proto::INSTANCE_ID,
+            interface_id: proto::INTERFACE_ID,
+            channel_type: ChannelType::Pipe { message_mode: true },
+            ..Default::default()
+        }
+    }
+
+    fn inspect(&mut self, req: inspect::Request<'_>, runner: Option<&mut Self::Runner>) {
+        req.respond().merge(self).merge(runner);
+    }
+
+    fn open(
+        &mut self,
+        channel: RawAsyncChannel<GpadlRingMem>,
+        _guest_memory: GuestMemory,
+    ) -> Result<Self::Runner, ChannelOpenError> {
+        FcopyChannel::new(channel)
+    }
+
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        runner: &mut Self::Runner,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(async {
+            match runner.process(self).await {
+                Ok(()) => {}
+                Err(err) => {
+                    tracing::error!(
+                        error = err.as_ref() as &dyn std::error::Error,
+                        "guest services ic error"
+                    )
+                }
+            }
+        })
+        .await
+    }
+
+    fn supports_save_restore(
+        &mut self,
+    ) -> Option<
+        &mut dyn SaveRestoreSimpleVmbusDevice<SavedState = Self::SavedState, Runner = Self::Runner>,
+    > {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::test_helpers::FakeGuest;
+    use crate::common::test_helpers::RING_SIZE;
+    use hyperv_ic_protocol::Header;
+    use mesh::rpc::RpcSend;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use pal_async::task::Spawn;
+    use pal_async::task::Task;
+    use std::io::Write;
+    use test_with_tracing::test;
+    use vmbus_channel::connected_async_channels;
+    use zerocopy::FromBytes;
+
+    /// Negotiates with a fake guest and then starts the host channel.
+    async fn start(
+        driver: &DefaultDriver,
+    ) -> (mesh::Sender<FcopyRpc>, FakeGuest, Task<anyhow::Result<()>>) {
+        let (host, guest) = connected_async_channels(RING_SIZE);
+        let mut channel = FcopyChannel::new(host).unwrap();
+        let mut guest = FakeGuest::new(guest);
+        futures::join!(
+            async {
+                while let ChannelState::Negotiate(_) = channel.state {
+                    channel.process_state_machine().await.unwrap();
+                }
+            },
+            guest.negotiate(proto::FCOPY_VERSION_1_1),
+        );
+        let (send, recv) = mesh::channel();
+        let mut ic = FcopyIc::new(recv);
+        let task = driver.spawn("fcopy", async move { channel.process(&mut ic).await });
+        (send, guest, task)
+    }
+
+    fn copy_params(data: &[u8]) -> CopyFileParams {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(data).unwrap();
+        CopyFileParams {
+            file,
+            guest_dir: "/tmp".into(),
+            file_name: "test.bin".into(),
+            overwrite: true,
+            create_path: false,
+        }
+    }
+
+    fn decode_path(buf: &[u16]) -> String {
+        let len = buf.iter().position(|&c| c == 0).unwrap();
+        String::from_utf16(&buf[..len]).unwrap()
+    }
+
+    async fn read_request(guest: &mut FakeGuest) -> (Header, proto::Operation, Vec<u8>) {
+        let (header, body) = guest.read_request().await;
+        assert_eq!(
+            header.message_type,
+            hyperv_ic_protocol::MessageType::GUEST_INTERFACE
+        );
+        let (fcopy_header, _) = proto::FcopyHeader::read_from_prefix(&body).unwrap();
+        (header, fcopy_header.operation, body)
+    }
+
+    /// Accepts the start of a copy, returning the file size.
+    async fn accept_start(guest: &mut FakeGuest) -> u64 {
+        let (header, op, body) = read_request(guest).await;
+        assert_eq!(op, proto::Operation::START_FILE_COPY);
+        let message = proto::StartCopyMessage::read_from_bytes(&body).unwrap();
+        assert_eq!(decode_path(&message.file_name), "test.bin");
+        assert_eq!(decode_path(&message.path_name), "/tmp");
+        assert!(message.copy_flags.overwrite());
+        assert!(!message.copy_flags.create_path());
+        guest.respond(&header, Status::SUCCESS, &[]).await;
+        message.file_size
+    }
+
+    #[async_test]
+    async fn copy_file(driver: DefaultDriver) {
+        let (send, mut guest, _task) = start(&driver).await;
+
+        let data = (0..proto::DATA_FRAGMENT_SIZE * 2 + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let copy = send.call_failable(FcopyRpc::CopyFile, copy_params(&data));
+
+        let file_size = accept_start(&mut guest).await;
+        assert_eq!(file_size, data.len() as u64);
+
+        let mut guest_file = vec![0; data.len()];
+        let mut writes = 0;
+        loop {
+            let (header, op, body) = read_request(&mut guest).await;
+            if op == proto::Operation::COMPLETE_FCOPY {
+                guest.respond(&header, Status::SUCCESS, &[]).await;
+                break;
+            }
+            assert_eq!(op, proto::Operation::WRITE_TO_FILE);
+            let message = proto::WriteMessage::read_from_bytes(&body).unwrap();
+            let offset = message.offset as usize;
+            let size = message.size as usize;
+            guest_file[offset..offset + size].copy_from_slice(&message.data[..size]);
+            writes += 1;
+            guest.respond(&header, Status::SUCCESS, &[]).await;
+        }
+
+        copy.await.unwrap();
+        assert_eq!(writes, 3);
+        assert_eq!(guest_file, data);
+    }
+
+    #[async_test]
+    async fn copy_empty_file(driver: DefaultDriver) {
+        let (send, mut guest, _task) = start(&driver).await;
+
+        let copy = send.call_failable(FcopyRpc::CopyFile, copy_params(&[]));
+        assert_eq!(accept_start(&mut guest).await, 0);
+
+        let (header, op, _) = read_request(&mut guest).await;
+        assert_eq!(op, proto::Operation::COMPLETE_FCOPY);
+        guest.respond(&header, Status::SUCCESS, &[]).await;
+        copy.await.unwrap();
+    }
+
+    #[async_test]
+    async fn write_failure_cancels(driver: DefaultDriver) {
+        let (send, mut guest, _task) = start(&driver).await;
+
+        let data = vec![0xcc; proto::DATA_FRAGMENT_SIZE * 3];
+        let copy = send.call_failable(FcopyRpc::CopyFile, copy_params(&data));
+        accept_start(&mut guest).await;
+
+        let (header, op, _) = read_request(&mut guest).await;
+        assert_eq!(op, proto::Operation::WRITE_TO_FILE);
+        guest.respond(&header, Status::SUCCESS, &[]).await;
+
+        let (header, op, _) = read_request(&mut guest).await;
+        assert_eq!(op, proto::Operation::WRITE_TO_FILE);
+        guest.respond(&header, Status::DISK_FULL, &[]).await;
+
+        let (header, op, _) = read_request(&mut guest).await;
+        assert_eq!(op, proto::Operation::CANCEL_FCOPY);
+        guest.respond(&header, Status::SUCCESS, &[]).await;
+
+        let err = copy.await.unwrap_err();
+        assert!(err.to_string().contains("disk is full"), "{err}");
+    }
+
+    #[async_test]
+    async fn start_failure(driver: DefaultDriver) {
+        let (send, mut guest, _task) = start(&driver).await;
+
+        let copy = send.call_failable(FcopyRpc::CopyFile, copy_params(b"data"));
+        let (header, op, _) = read_request(&mut guest).await;
+        assert_eq!(op, proto::Operation::START_FILE_COPY);
+
+        // Only one copy can be in progress.
+        let err = send
+            .call_failable(FcopyRpc::CopyFile, copy_params(b"data"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("already in progress"), "{err}");
+
+        guest.respond(&header, Status::ALREADY_EXISTS, &[]).await;
+        let err = copy.await.unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{err}");
+
+        // The channel is ready for the next copy.
+        let copy = send.call_failable(FcopyRpc::CopyFile, copy_params(b"data"));
+        accept_start(&mut guest).await;
+        let (header, op, _) = read_request(&mut guest).await;
+        assert_eq!(op, proto::Operation::WRITE_TO_FILE);
+        guest.respond(&header, Status::SUCCESS, &[]).await;
+        let (header, op, _) = read_request(&mut guest).await;
+        assert_eq!(op, proto::Operation::COMPLETE_FCOPY);
+        guest.respond(&header, Status::SUCCESS, &[]).await;
+        copy.await.unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The heartbeat IC.
+
+use crate::common::IcPipe;
+use crate::common::NegotiateState;
+use crate::common::Versions;
+use anyhow::Context as _;
+use async_trait::async_trait;
+use futures::FutureExt;
+use guestmem::GuestMemory;
+use hyperv_ic_protocol::Status;
+use hyperv_ic_protocol::heartbeat as proto;
+use hyperv_ic_resources::heartbeat::HeartbeatStatus;
+use inspect::Inspect;
+use inspect::InspectMut;
+use pal_async::driver::Driver;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use std::future::pending;
+use std::time::Duration;
+use task_control::Cancelled;
+use task_control::StopTask;
+use vmbus_channel::RawAsyncChannel;
+use vmbus_channel::bus::ChannelType;
+use vmbus_channel::bus::OfferParams;
+use vmbus_channel::channel::ChannelOpenError;
+use vmbus_channel::gpadl_ring::GpadlRingMem;
+use vmbus_channel::simple::SaveRestoreSimpleVmbusDevice;
+use vmbus_channel::simple::SimpleVmbusDevice;
+use vmcore::save_restore::NoSavedState;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+const HEARTBEAT_VERSIONS: &[hyperv_ic_protocol::Version] =
+    &[proto::HEARTBEAT_VERSION_1, proto::HEARTBEAT_VERSION_3];
+
+/// Send a heartbeat every second.
+const HEARTBEAT_PERIOD: Duration = Duration::from_secs(1);
+
+/// Report lost communication if the guest does not respond to a heartbeat
+/// within this long.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Heartbeat IC device.
+#[derive(InspectMut)]
+pub struct HeartbeatIc {
+    #[inspect(skip)]
+    timer: PolledTimer,
+    #[inspect(with = "|x| inspect::AsDebug(*x.get())")]
+    status: mesh::CellUpdater<HeartbeatStatus>,
+}
+
+#[doc(hidden)]
+#[derive(InspectMut)]
+pub struct HeartbeatChannel {
+    #[inspect(mut)]
+    pipe: IcPipe,
+    state: ChannelState,
+    sequence_number: u64,
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ChannelState {
+    Negotiate(#[inspect(rename = "state")] NegotiateState),
+    Ready {
+        versions: Versions,
+        state: ReadyState,
+    },
+    Failed,
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ReadyState {
+    SleepUntilNextHeartbeat {
+        #[inspect(skip)]
+        next_heartbeat: Instant,
+    },
+    SendHeartbeat,
+    WaitForResponse {
+        #[inspect(skip)]
+        deadline: Option<Instant>,
+    },
+}
+
+impl HeartbeatIc {
+    /// Create a new heartbeat IC, reporting the guest's health via `status`.
+    pub fn new(
+        driver: &(impl Driver + ?Sized),
+        status: mesh::CellUpdater<HeartbeatStatus>,
+    ) -> Self {
+        Self {
+            timer: PolledTimer::new(driver),
+            status,
+        }
+    }
+
+    async fn set_status(&mut self, status: HeartbeatStatus) {
+        if *self.status.get() != status {
+            tracelimit::info_ratelimited!(?status, "guest heartbeat status changed");
+            self.status.set(status).await;
+        }
+    }
+}
+
+#[async_trait]
+impl SimpleVmbusDevice for HeartbeatIc {
+    type SavedState = NoSavedState;
+    type Runner = HeartbeatChannel;
+
+    fn offer(&self) -> OfferParams {
+        OfferParams {
+            interface_name: "heartbeat_ic".to_owned(),
+            instance_id: proto::INSTANCE_ID,
+            interface_id: proto::INTERFACE_ID,
+            channel_type: ChannelType::Pipe { message_mode: true },
+            ..Default::default()
+        }
+    }
+
+    fn inspect(&mut self, req: inspect::Request<'_>, runner: Option<&mut Self::Runner>) {
+        req.respond().merge(self).merge(runner);
+    }
+
+    fn open(
+        &mut self,
+        channel: RawAsyncChannel<GpadlRingMem>,
+        _guest_memory: GuestMemory,
+    ) -> Result<Self::Runner, ChannelOpenError> {
+        HeartbeatChannel::new(channel)
+    }
+
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        runner: &mut Self::Runner,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(async { runner.process(self).await })
+            .await
+    }
+
+    async fn close(&mut self) {
+        self.set_status(HeartbeatStatus::NoContact).await;
+    }
+
+    fn supports_save_restore(
+        &mut self,
+    ) -> Option<
+        &mut dyn SaveRestoreSimpleVmbusDevice<SavedState = Self::SavedState, Runner = Self::Runner>,
+    > {
+        None
+    }
+}
+
+impl HeartbeatChannel {
+    fn new(channel: RawAsyncChannel<GpadlRingMem>) -> Result<Self, ChannelOpenError> {
+        let pipe = IcPipe::new(channel)?;
+        Ok(Self {
+            pipe,
+            state: ChannelState::Negotiate(NegotiateState::default()),
+            sequence_number: 0,
+        })
+    }
+
+    async fn process(&mut self, ic: &mut HeartbeatIc) -> ! {
+        loop {
+            if let Err(err) = self.process_state_machine(ic).await {
+                tracing::error!(
+                    error = err.as_ref() as &dyn std::error::Error,
+                    "heartbeat ic error"
+                );
+                self.state = ChannelState::Failed;
+                ic.set_status(HeartbeatStatus::LostCommunication).await;
+            }
+        }
+    }
+
+    async fn process_state_machine(&mut self, ic: &mut HeartbeatIc) -> anyhow::Result<()> {
+        match self.state {
+            ChannelState::Negotiate(ref mut state) => {
+                if let Some(versions) = self.pipe.negotiate(state, HEARTBEAT_VERSIONS).await? {
+                    tracelimit::info_ratelimited!(
+                        framework = %versions.framework_version,
+                        version = %versions.message_version,
+                        "heartbeat versions negotiated"
+                    );
+                    self.state = ChannelState::Ready {
+                        versions,
+                        state: ReadyState::SendHeartbeat,
+                    };
+                }
+            }
+            ChannelState::Ready {
+                ref versions,
+                ref mut state,
+            } => match *state {
+                ReadyState::SleepUntilNextHeartbeat { next_heartbeat } => {
+                    ic.timer.sleep_until(next_heartbeat).await;
+                    *state = ReadyState::SendHeartbeat;
+                }
+                ReadyState::SendHeartbeat => {
+                    let message = proto::HeartbeatMessage {
+                        sequence_number: self.sequence_number,
+                        ..FromZeros::new_zeroed()
+                    };
+                    self.pipe
+                        .write_message(
+                            versions,
+                            hyperv_ic_protocol::MessageType::HEARTBEAT,
+                            hyperv_ic_protocol::HeaderFlags::new()
+                                .with_request(true)
+                                .with_transaction(true),
+                            message.as_bytes(),
+                        )
+                        .await?;
+                    *state = ReadyState::WaitForResponse {
+                        deadline: Some(Instant::now() + RESPONSE_TIMEOUT),
+                    };
+                }
+                ReadyState::WaitForResponse { deadline } => {
+                    let timeout = async {
+                        match deadline {
+                            Some(deadline) => ic.timer.sleep_until(deadline).await,
+                            None => pending().await,
+                        }
+                    };
+                    let response = futures::select! { // merge semantics
+                        r = self.pipe.read_response().fuse() => Some(r?),
+                        _ = timeout.fuse() => None,
+                    };
+                    let Some((status, buf)) = response else {
+                        // Keep waiting; the guest may still respond.
+                        ic.set_status(HeartbeatStatus::LostCommunication).await;
+                        *state = ReadyState::WaitForResponse { deadline: None };
+                        return Ok(());
+                    };
+                    if status != Status::SUCCESS {
+                        anyhow::bail!("heartbeat failed with status {:#x}", status.0);
+                    }
+                    let (message, _) = proto::HeartbeatMessage::read_from_prefix(buf)
+                        .ok()
+                        .context("missing heartbeat message")?;
+                    self.sequence_number = message.sequence_number;
+                    let status = match message.application_state {
+                        proto::ApplicationState::CRITICAL => HeartbeatStatus::ApplicationCritical,
+                        proto::ApplicationState::STOPPED => HeartbeatStatus::NoContact,
+                        // Linux guests do not report an application state.
+                        _ => HeartbeatStatus::Ok,
+                    };
+                    ic.set_status(status).await;
+                    *state = ReadyState::SleepUntilNextHeartbeat {
+                        next_heartbeat: Instant::now() + HEARTBEAT_PERIOD,
+                    };
+                }
+            },
+            ChannelState::Failed => pending().await,
+        }
+        Ok(())
+    }
+}
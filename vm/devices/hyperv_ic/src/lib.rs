@@ -12,11 +12,16 @@
 //! * timesync IC for synchronizing time
 //! * heartbeat IC for reporting guest health
 //! * KVP IC for exchanging arbitrary key/value data between the host and guest
+//! * VSS IC for freezing guest filesystems during a backup
+//! * guest services IC for copying files into the guest
 
 #![forbid(unsafe_code)]
 
 mod common;
+pub mod fcopy;
+pub mod heartbeat;
 pub mod kvp;
 pub mod resolver;
 pub mod shutdown;
 pub mod timesync;
+pub mod vss;
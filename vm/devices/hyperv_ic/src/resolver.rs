@@ -3,14 +3,20 @@
 
 //! Resource resolvers for the ICs.
 
+use crate::fcopy::FcopyIc;
+use crate::heartbeat::HeartbeatIc;
 use crate::kvp::KvpIc;
 use crate::shutdown::ShutdownIc;
 use crate::timesync::TimesyncIc;
+use crate::vss::VssIc;
 use anyhow::Context as _;
 use async_trait::async_trait;
+use hyperv_ic_resources::fcopy::FcopyIcHandle;
+use hyperv_ic_resources::heartbeat::HeartbeatIcHandle;
 use hyperv_ic_resources::kvp::KvpIcHandle;
 use hyperv_ic_resources::shutdown::ShutdownIcHandle;
 use hyperv_ic_resources::timesync::TimesyncIcHandle;
+use hyperv_ic_resources::vss::VssIcHandle;
 use std::convert::Infallible;
 use vm_resource::AsyncResolveResource;
 use vm_resource::IntoResource;
@@ -104,3 +110,76 @@ async fn resolve(
         .into())
     }
 }
+
+/// Resource resolver for the heartbeat IC.
+pub struct HeartbeatIcResolver;
+
+declare_static_resolver! {
+    HeartbeatIcResolver,
+    (VmbusDeviceHandleKind, HeartbeatIcHandle),
+}
+
+impl ResolveResource<VmbusDeviceHandleKind, HeartbeatIcHandle> for HeartbeatIcResolver {
+    type Output = ResolvedVmbusDevice;
+    type Error = Infallible;
+
+    fn resolve(
+        &self,
+        resource: HeartbeatIcHandle,
+        input: ResolveVmbusDeviceHandleParams<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(SimpleDeviceWrapper::new(
+            input.driver_source.simple(),
+            HeartbeatIc::new(&input.driver_source.simple(), resource.status),
+        )
+        .into())
+    }
+}
+
+/// Resource resolver for the VSS IC.
+pub struct VssIcResolver;
+
+declare_static_resolver! {
+    VssIcResolver,
+    (VmbusDeviceHandleKind, VssIcHandle),
+}
+
+impl ResolveResource<VmbusDeviceHandleKind, VssIcHandle> for VssIcResolver {
+    type Output = ResolvedVmbusDevice;
+    type Error = Infallible;
+
+    fn resolve(
+        &self,
+        resource: VssIcHandle,
+        input: ResolveVmbusDeviceHandleParams<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(
+            SimpleDeviceWrapper::new(input.driver_source.simple(), VssIc::new(resource.recv))
+                .into(),
+        )
+    }
+}
+
+/// Resource resolver for the guest services (file copy) IC.
+pub struct FcopyIcResolver;
+
+declare_static_resolver! {
+    FcopyIcResolver,
+    (VmbusDeviceHandleKind, FcopyIcHandle),
+}
+
+impl ResolveResource<VmbusDeviceHandleKind, FcopyIcHandle> for FcopyIcResolver {
+    type Output = ResolvedVmbusDevice;
+    type Error = Infallible;
+
+    fn resolve(
+        &self,
+        resource: FcopyIcHandle,
+        input: ResolveVmbusDeviceHandleParams<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(
+            SimpleDeviceWrapper::new(input.driver_source.simple(), FcopyIc::new(resource.recv))
+                .into(),
+        )
+    }
+}
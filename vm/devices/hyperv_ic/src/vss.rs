@@ -0,0 +1,370 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The VSS (backup) IC.
+//!
+//! Only the freeze and thaw operations are implemented. These are enough to
+//! take application-consistent snapshots of the guest's disks: Windows guests
+//! freeze VSS-aware applications and flush their volumes, and Linux guests
+//! running `hv_vss_daemon` freeze all mounted filesystems.
+
+use crate::common::IcPipe;
+use crate::common::NegotiateState;
+use crate::common::Versions;
+use async_trait::async_trait;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures::stream::once;
+use futures_concurrency::stream::Merge;
+use guestmem::GuestMemory;
+use hyperv_ic_protocol::Status;
+use hyperv_ic_protocol::vss as proto;
+use hyperv_ic_resources::vss::VssRpc;
+use inspect::Inspect;
+use inspect::InspectMut;
+use mesh::error::RemoteError;
+use mesh::rpc::Rpc;
+use std::pin::pin;
+use task_control::Cancelled;
+use task_control::StopTask;
+use vmbus_channel::RawAsyncChannel;
+use vmbus_channel::bus::ChannelType;
+use vmbus_channel::bus::OfferParams;
+use vmbus_channel::channel::ChannelOpenError;
+use vmbus_channel::gpadl_ring::GpadlRingMem;
+use vmbus_channel::simple::SaveRestoreSimpleVmbusDevice;
+use vmbus_channel::simple::SimpleVmbusDevice;
+use vmbus_ring::RingMem;
+use vmcore::save_restore::NoSavedState;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+/// Freeze and thaw are only supported from version 5.0.
+const VSS_VERSIONS: &[hyperv_ic_protocol::Version] = &[
+    proto::VSS_VERSION_WINBLUE,
+    proto::VSS_VERSION_THRESHOLD,
+    proto::VSS_VERSION_THRESHOLD_UR1,
+];
+
+/// A VSS IC device.
+#[derive(InspectMut)]
+pub struct VssIc {
+    #[inspect(skip)]
+    recv: mesh::Receiver<VssRpc>,
+}
+
+#[doc(hidden)]
+#[derive(InspectMut)]
+pub struct VssChannel<T: RingMem = GpadlRingMem> {
+    #[inspect(mut)]
+    pipe: IcPipe<T>,
+    state: ChannelState,
+    frozen: bool,
+    #[inspect(with = "Option::is_some")]
+    pending: Option<Rpc<(), Result<(), RemoteError>>>,
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ChannelState {
+    Negotiate(#[inspect(flatten)] NegotiateState),
+    Ready {
+        versions: Versions,
+        state: ReadyState,
+    },
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ReadyState {
+    Ready,
+    SendRequest(#[inspect(debug)] Operation),
+    WaitResponse(#[inspect(debug)] Operation),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Operation {
+    Freeze,
+    Thaw,
+}
+
+impl VssIc {
+    /// Returns a new VSS IC, using `recv` to receive freeze and thaw
+    /// requests.
+    pub fn new(recv: mesh::Receiver<VssRpc>) -> Self {
+        Self { recv }
+    }
+}
+
+impl<T: RingMem> VssChannel<T> {
+    fn new(channel: RawAsyncChannel<T>) -> Result<Self, ChannelOpenError> {
+        let pipe = IcPipe::new(channel)?;
+        Ok(Self {
+            pipe,
+            state: ChannelState::Negotiate(NegotiateState::default()),
+            frozen: false,
+            pending: None,
+        })
+    }
+
+    async fn process(&mut self, ic: &mut VssIc) -> anyhow::Result<()> {
+        enum Event {
+            StateMachine(anyhow::Result<()>),
+            Request(VssRpc),
+        }
+
+        loop {
+            let event = pin!(
+                (
+                    once(self.process_state_machine().map(Event::StateMachine)),
+                    (&mut ic.recv).map(Event::Request),
+                )
+                    .merge()
+            )
+            .next()
+            .await
+            .unwrap();
+            match event {
+                Event::StateMachine(r) => {
+                    r?;
+                }
+                Event::Request(req) => {
+                    let (op, rpc) = match req {
+                        VssRpc::Freeze(rpc) => (Operation::Freeze, rpc),
+                        VssRpc::Thaw(rpc) => (Operation::Thaw, rpc),
+                    };
+                    match &mut self.state {
+                        ChannelState::Negotiate(_) => {
+                            rpc.fail(anyhow::anyhow!("vss ic not ready"));
+                        }
+                        ChannelState::Ready { state, .. } => match state {
+                            ReadyState::Ready => {
+                                let ((), rpc) = rpc.split();
+                                self.pending = Some(rpc);
+                                *state = ReadyState::SendRequest(op);
+                            }
+                            ReadyState::SendRequest(_) | ReadyState::WaitResponse(_) => {
+                                rpc.fail(anyhow::anyhow!("vss request already in progress"));
+                            }
+                        },
+                    }
+                }
+            }
+        }
+    }
+
+    async fn process_state_machine(&mut self) -> anyhow::Result<()> {
+        match self.state {
+            ChannelState::Negotiate(ref mut state) => {
+                if let Some(versions) = self.pipe.negotiate(state, VSS_VERSIONS).await? {
+                    self.state = ChannelState::Ready {
+                        versions,
+                        state: ReadyState::Ready,
+                    };
+                }
+            }
+            ChannelState::Ready {
+                ref mut state,
+                ref versions,
+            } => match *state {
+                ReadyState::Ready => std::future::pending().await,
+                ReadyState::SendRequest(op) => {
+                    let message = match op {
+                        Operation::Freeze => {
+                            let mut message = proto::Message2::new_box_zeroed().unwrap();
+                            message.header.operation = proto::Operation::FREEZE_APPLICATIONS;
+                            message.as_bytes().to_vec()
+                        }
+                        Operation::Thaw => [
+                            proto::VssHeader {
+                                operation: proto::Operation::THAW_APPLICATIONS,
+                                reserved: [0; 7],
+                            }
+                            .as_bytes(),
+                            proto::MessageThawApplications { flags: 0 }.as_bytes(),
+                        ]
+                        .concat(),
+                    };
+                    self.pipe
+                        .write_message(
+                            versions,
+                            hyperv_ic_protocol::MessageType::VSS,
+                            hyperv_ic_protocol::HeaderFlags::new()
+                                .with_transaction(true)
+                                .with_request(true),
+                            &message,
+                        )
+                        .await?;
+                    *state = ReadyState::WaitResponse(op);
+                }
+                ReadyState::WaitResponse(op) => {
+                    let (status, _) = self.pipe.read_response().await?;
+                    let result = if status == Status::SUCCESS {
+                        self.frozen = op == Operation::Freeze;
+                        tracing::info!(?op, "guest vss operation complete");
+                        Ok(())
+                    } else {
+                        Err(RemoteError::new(anyhow::anyhow!(
+                            "guest failed the {op:?} request with status {:#x}",
+                            status.0
+                        )))
+                    };
+                    if let Some(rpc) = self.pending.take() {
+                        rpc.complete(result);
+                    }
+                    *state = ReadyState::Ready;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SimpleVmbusDevice for VssIc {
+    type SavedState = NoSavedState;
+    type Runner = VssChannel;
+
+    fn offer(&self) -> OfferParams {
+        OfferParams {
+            interface_name: "vss_ic".to_owned(),
+            instance_id: proto::INSTANCE_ID,
+            interface_id: proto::INTERFACE_ID,
+            channel_type: ChannelType::Pipe { message_mode: true },
+            ..Default::default()
+        }
+    }
+
+    fn inspect(&mut self, req: inspect::Request<'_>, runner: Option<&mut Self::Runner>) {
+        req.respond().merge(self).merge(runner);
+    }
+
+    fn open(
+        &mut self,
+        channel: RawAsyncChannel<GpadlRingMem>,
+        _guest_memory: GuestMemory,
+    ) -> Result<Self::Runner, ChannelOpenError> {
+        VssChannel::new(channel)
+    }
+
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        runner: &mut Self::Runner,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(async {
+            match runner.process(self).await {
+                Ok(()) => {}
+                Err(err) => {
+                    tracing::error!(
+                        error = err.as_ref() as &dyn std::error::Error,
+                        "vss ic error"
+                    )
+                }
+            }
+        })
+        .await
+    }
+
+    fn supports_save_restore(
+        &mut self,
+    ) -> Option<
+        &mut dyn SaveRestoreSimpleVmbusDevice<SavedState = Self::SavedState, Runner = Self::Runner>,
+    > {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::test_helpers::FakeGuest;
+    use crate::common::test_helpers::RING_SIZE;
+    use hyperv_ic_protocol::Header;
+    use mesh::rpc::RpcSend;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use pal_async::task::Spawn;
+    use pal_async::task::Task;
+    use test_with_tracing::test;
+    use vmbus_channel::connected_async_channels;
+    use zerocopy::FromBytes;
+
+    /// Negotiates with a fake guest and then starts the host channel.
+    async fn start(
+        driver: &DefaultDriver,
+    ) -> (mesh::Sender<VssRpc>, FakeGuest, Task<anyhow::Result<()>>) {
+        let (host, guest) = connected_async_channels(RING_SIZE);
+        let mut channel = VssChannel::new(host).unwrap();
+        let mut guest = FakeGuest::new(guest);
+        futures::join!(
+            async {
+                while let ChannelState::Negotiate(_) = channel.state {
+                    channel.process_state_machine().await.unwrap();
+                }
+            },
+            guest.negotiate(proto::VSS_VERSION_THRESHOLD_UR1),
+        );
+        let (send, recv) = mesh::channel();
+        let mut ic = VssIc::new(recv);
+        let task = driver.spawn("vss", async move { channel.process(&mut ic).await });
+        (send, guest, task)
+    }
+
+    async fn expect_request(guest: &mut FakeGuest, operation: proto::Operation) -> Header {
+        let (header, body) = guest.read_request().await;
+        assert_eq!(header.message_type, hyperv_ic_protocol::MessageType::VSS);
+        let (vss_header, _) = proto::VssHeader::read_from_prefix(&body).unwrap();
+        assert_eq!(vss_header.operation, operation);
+        header
+    }
+
+    #[async_test]
+    async fn freeze_thaw(driver: DefaultDriver) {
+        let (send, mut guest, _task) = start(&driver).await;
+
+        let freeze = send.call_failable(VssRpc::Freeze, ());
+        let header = expect_request(&mut guest, proto::Operation::FREEZE_APPLICATIONS).await;
+        guest.respond(&header, Status::SUCCESS, &[]).await;
+        freeze.await.unwrap();
+
+        let thaw = send.call_failable(VssRpc::Thaw, ());
+        let header = expect_request(&mut guest, proto::Operation::THAW_APPLICATIONS).await;
+        guest.respond(&header, Status::SUCCESS, &[]).await;
+        thaw.await.unwrap();
+    }
+
+    #[async_test]
+    async fn guest_failure(driver: DefaultDriver) {
+        let (send, mut guest, _task) = start(&driver).await;
+
+        let freeze = send.call_failable(VssRpc::Freeze, ());
+        let header = expect_request(&mut guest, proto::Operation::FREEZE_APPLICATIONS).await;
+
+        // Only one request can be outstanding.
+        let err = send.call_failable(VssRpc::Thaw, ()).await.unwrap_err();
+        assert!(err.to_string().contains("already in progress"), "{err}");
+
+        guest.respond(&header, Status::FAIL, &[]).await;
+        let err = freeze.await.unwrap_err();
+        assert!(err.to_string().contains("Freeze"), "{err}");
+
+        // The channel is ready for the next request.
+        let thaw = send.call_failable(VssRpc::Thaw, ());
+        let header = expect_request(&mut guest, proto::Operation::THAW_APPLICATIONS).await;
+        guest.respond(&header, Status::SUCCESS, &[]).await;
+        thaw.await.unwrap();
+    }
+
+    #[async_test]
+    async fn not_negotiated(driver: DefaultDriver) {
+        let (host, _guest) = connected_async_channels(RING_SIZE);
+        let mut channel = VssChannel::new(host).unwrap();
+        let (send, recv) = mesh::channel();
+        let mut ic = VssIc::new(recv);
+        let _task = driver.spawn("vss", async move { channel.process(&mut ic).await });
+
+        let err = send.call_failable(VssRpc::Freeze, ()).await.unwrap_err();
+        assert!(err.to_string().contains("not ready"), "{err}");
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Protocol definitions for the guest services (file copy) IC.
+
+use crate::Version;
+use bitfield_struct::bitfield;
+use guid::Guid;
+use open_enum::open_enum;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+/// The unique vmbus interface ID of the guest services IC.
+pub const INTERFACE_ID: Guid = guid::guid!("34d14be3-dee4-41c8-9ae7-6b174977c192");
+/// The vmbus instance ID used for the guest services IC.
+pub const INSTANCE_ID: Guid = guid::guid!("2f2c8846-3d28-413a-814f-f1149efbda1a");
+
+/// Version 1.1.
+pub const FCOPY_VERSION_1_1: Version = Version::new(1, 1);
+
+/// The maximum length of a file or directory name, in UTF-16 code units,
+/// including the null terminator.
+pub const MAX_PATH: usize = 260;
+
+/// The maximum number of bytes of file data carried by a single write
+/// message.
+pub const DATA_FRAGMENT_SIZE: usize = 6 * 1024;
+
+open_enum! {
+    /// The file copy operation.
+    #[derive(IntoBytes, Immutable, KnownLayout, FromBytes)]
+    pub enum Operation: u32 {
+        /// Create the destination file.
+        START_FILE_COPY = 0,
+        /// Write a fragment of file data.
+        WRITE_TO_FILE = 1,
+        /// Close the destination file.
+        COMPLETE_FCOPY = 2,
+        /// Abandon the copy and remove the partial destination file.
+        CANCEL_FCOPY = 3,
+    }
+}
+
+/// The header for file copy messages.
+#[repr(C)]
+#[derive(IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct FcopyHeader {
+    /// The operation to perform.
+    pub operation: Operation,
+    /// Service ID, ignored by guests.
+    pub service_id0: Guid,
+    /// Service ID, ignored by guests.
+    pub service_id1: Guid,
+}
+
+/// Flags for [`StartCopyMessage`].
+#[bitfield(u32)]
+#[derive(IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct CopyFlags {
+    /// Overwrite the destination file if it exists.
+    pub overwrite: bool,
+    /// Create the destination directory if it does not exist.
+    pub create_path: bool,
+    #[bits(30)]
+    _reserved: u32,
+}
+
+/// Message to start a file copy.
+#[repr(C)]
+#[derive(IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct StartCopyMessage {
+    /// The message header.
+    pub header: FcopyHeader,
+    /// The null-terminated UTF-16 destination file name.
+    pub file_name: [u16; MAX_PATH],
+    /// The null-terminated UTF-16 destination directory.
+    pub path_name: [u16; MAX_PATH],
+    /// Copy flags.
+    pub copy_flags: CopyFlags,
+    /// The total size of the file, in bytes.
+    pub file_size: u64,
+}
+
+/// Message to write a fragment of file data.
+#[repr(C, packed)]
+#[derive(IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct WriteMessage {
+    /// The message header.
+    pub header: FcopyHeader,
+    /// Reserved.
+    pub reserved: u32,
+    /// The offset of the fragment within the file.
+    pub offset: u64,
+    /// The number of valid bytes in `data`.
+    pub size: u32,
+    /// The file data.
+    pub data: [u8; DATA_FRAGMENT_SIZE],
+}
+
+/// Message to complete or cancel a file copy.
+#[repr(C)]
+#[derive(IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct FinishMessage {
+    /// The message header.
+    pub header: FcopyHeader,
+}
@@ -5,6 +5,7 @@
 
 #![forbid(unsafe_code)]
 
+pub mod fcopy;
 pub mod heartbeat;
 pub mod kvp;
 pub mod shutdown;
@@ -113,6 +114,14 @@ pub enum Status: u32 {
         NOT_SUPPORTED = 0x80070032,
         /// Not found.
         NOT_FOUND = 0x80041002,
+        /// The path was not found.
+        PATH_NOT_FOUND = 0x80070003,
+        /// The file already exists.
+        ALREADY_EXISTS = 0x80070050,
+        /// An argument was invalid.
+        INVALID_ARGUMENT = 0x80070057,
+        /// The disk is full.
+        DISK_FULL = 0x80070070,
     }
 }
 
@@ -13,6 +13,11 @@
 use zerocopy::IntoBytes;
 use zerocopy::KnownLayout;
 
+/// The unique vmbus interface ID of the VSS IC.
+pub const INTERFACE_ID: Guid = guid::guid!("35fa2e29-ea23-4236-96ae-3a6ebacba440");
+/// The unique vmbus instance ID of the VSS IC.
+pub const INSTANCE_ID: Guid = guid::guid!("2450ee40-33bf-4fbd-892e-9fb06e9214cf");
+
 pub const VSS_VERSION_WIN8: Version = Version::new(4, 0);
 pub const VSS_VERSION_WINBLUE: Version = Version::new(5, 0);
 pub const VSS_VERSION_THRESHOLD: Version = Version::new(6, 0);
@@ -0,0 +1,42 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource definitions for the guest services (file copy) IC.
+
+use mesh::MeshPayload;
+use mesh::rpc::FailableRpc;
+use vm_resource::ResourceId;
+use vm_resource::kind::VmbusDeviceHandleKind;
+
+/// A handle to a guest services IC.
+#[derive(MeshPayload)]
+pub struct FcopyIcHandle {
+    /// The channel by which to receive file copy requests.
+    pub recv: mesh::Receiver<FcopyRpc>,
+}
+
+impl ResourceId<VmbusDeviceHandleKind> for FcopyIcHandle {
+    const ID: &'static str = "fcopy_ic";
+}
+
+/// An RPC request to the guest services IC.
+#[derive(MeshPayload)]
+pub enum FcopyRpc {
+    /// Copy a host file into the guest.
+    CopyFile(FailableRpc<CopyFileParams, ()>),
+}
+
+/// Parameters for copying a file into the guest.
+#[derive(MeshPayload)]
+pub struct CopyFileParams {
+    /// The host file to copy, read from its start.
+    pub file: std::fs::File,
+    /// The guest directory to copy the file into.
+    pub guest_dir: String,
+    /// The name of the file within `guest_dir`.
+    pub file_name: String,
+    /// Replace the file if it already exists in the guest.
+    pub overwrite: bool,
+    /// Create `guest_dir` if it does not exist.
+    pub create_path: bool,
+}
@@ -0,0 +1,36 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource definitions for the heartbeat IC.
+
+use mesh::MeshPayload;
+use vm_resource::ResourceId;
+use vm_resource::kind::VmbusDeviceHandleKind;
+
+/// A handle to a heartbeat IC.
+#[derive(MeshPayload)]
+pub struct HeartbeatIcHandle {
+    /// Updated with the guest's health as reported by the heartbeat IC.
+    ///
+    /// Create the updater with [`HeartbeatStatus::NoContact`] and keep the
+    /// associated [`mesh::Cell`] to observe the guest's health.
+    pub status: mesh::CellUpdater<HeartbeatStatus>,
+}
+
+impl ResourceId<VmbusDeviceHandleKind> for HeartbeatIcHandle {
+    const ID: &'static str = "heartbeat_ic";
+}
+
+/// The guest's health as reported by the heartbeat IC.
+#[derive(MeshPayload, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeartbeatStatus {
+    /// The guest has not connected to the heartbeat IC, or has reported
+    /// that it is stopping.
+    NoContact,
+    /// The guest is responding to heartbeats.
+    Ok,
+    /// The guest is responding to heartbeats but reports a critical error.
+    ApplicationCritical,
+    /// The guest connected but stopped responding to heartbeats.
+    LostCommunication,
+}
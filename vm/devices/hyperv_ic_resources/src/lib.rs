@@ -5,6 +5,9 @@
 
 #![forbid(unsafe_code)]
 
+pub mod fcopy;
+pub mod heartbeat;
 pub mod kvp;
 pub mod shutdown;
 pub mod timesync;
+pub mod vss;
@@ -0,0 +1,33 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource definitions for the VSS (backup) IC.
+
+use mesh::MeshPayload;
+use mesh::rpc::FailableRpc;
+use vm_resource::ResourceId;
+use vm_resource::kind::VmbusDeviceHandleKind;
+
+/// A handle to a VSS IC.
+#[derive(MeshPayload)]
+pub struct VssIcHandle {
+    /// The channel by which to receive VSS requests.
+    pub recv: mesh::Receiver<VssRpc>,
+}
+
+impl ResourceId<VmbusDeviceHandleKind> for VssIcHandle {
+    const ID: &'static str = "vss_ic";
+}
+
+/// An RPC request to the VSS IC.
+#[derive(MeshPayload)]
+pub enum VssRpc {
+    /// Ask the guest to flush and freeze its filesystems (and, on Windows,
+    /// its VSS-aware applications), so that a consistent snapshot of the
+    /// guest's disks can be taken.
+    ///
+    /// The filesystems stay frozen until [`VssRpc::Thaw`] is sent.
+    Freeze(FailableRpc<(), ()>),
+    /// Thaw the guest's filesystems after a [`VssRpc::Freeze`].
+    Thaw(FailableRpc<(), ()>),
+}
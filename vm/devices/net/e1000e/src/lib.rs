@@ -0,0 +1,302 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! An emulated Intel 82574 (e1000e) gigabit Ethernet controller.
+//!
+//! This provides networking to guests without paravirtualized network
+//! drivers, such as installers and firmware environments, on top of any
+//! [`Endpoint`]. It implements a single receive and transmit queue with the
+//! legacy and extended descriptor formats, checksum and TCP segmentation
+//! offloads, and INTx or MSI-X interrupts. Offloads not supported by the
+//! endpoint are performed in software.
+
+#![forbid(unsafe_code)]
+#![expect(missing_docs)]
+
+mod offload;
+mod phy;
+mod registers;
+mod regs;
+pub mod resolver;
+mod worker;
+
+#[cfg(test)]
+mod tests;
+
+use chipset_device::ChipsetDevice;
+use chipset_device::io::IoResult;
+use chipset_device::mmio::MmioIntercept;
+use chipset_device::mmio::RegisterMmioIntercept;
+use chipset_device::pci::ByteEnabledDwordRead;
+use chipset_device::pci::ByteEnabledDwordWrite;
+use chipset_device::pci::PciConfigSpace;
+use device_emulators::ReadWriteRequestType;
+use device_emulators::read_as_u32_chunks;
+use device_emulators::write_as_u32_chunks;
+use futures::task::AtomicWaker;
+use guestmem::GuestMemory;
+use inspect::InspectMut;
+use net_backend::Endpoint;
+use net_backend_resources::mac_address::MacAddress;
+use parking_lot::Mutex;
+use pci_core::PciInterruptPin;
+use pci_core::capabilities::PciCapability;
+use pci_core::capabilities::msix::MsixEmulator;
+use pci_core::capabilities::pci_express::PciExpressCapability;
+use pci_core::cfg_space_emu::BarMemoryKind;
+use pci_core::cfg_space_emu::ConfigSpaceType0Emulator;
+use pci_core::cfg_space_emu::DeviceBars;
+use pci_core::msi::MsiTarget;
+use pci_core::spec::hwid::ClassCode;
+use pci_core::spec::hwid::HardwareIds;
+use pci_core::spec::hwid::ProgrammingInterface;
+use pci_core::spec::hwid::Subclass;
+use registers::Interrupts;
+use registers::MSIX_VECTORS;
+use registers::Registers;
+use std::sync::Arc;
+use task_control::TaskControl;
+use vmcore::device_state::ChangeDeviceState;
+use vmcore::line_interrupt::LineInterrupt;
+use vmcore::save_restore::RestoreError;
+use vmcore::save_restore::SaveError;
+use vmcore::save_restore::SaveRestore;
+use vmcore::save_restore::SavedStateNotSupported;
+use vmcore::vm_task::VmTaskDriverSource;
+use worker::DataPath;
+use worker::Worker;
+
+const VENDOR_ID: u16 = 0x8086;
+const DEVICE_ID: u16 = 0x10d3;
+
+/// What kind of interrupts [`E1000eDevice`] should use.
+pub enum InterruptModel<'a> {
+    /// MSI-X, for devices on PCIe ports. This also exposes a PCI Express
+    /// capability.
+    Msix(&'a MsiTarget),
+    /// A legacy INT#A line, for devices on a conventional PCI bus.
+    IntX(LineInterrupt),
+}
+
+/// State shared between the MMIO handlers and the data path.
+struct Shared {
+    regs: Mutex<Registers>,
+    /// Woken when the data path needs to look at the registers.
+    waker: AtomicWaker,
+}
+
+impl Shared {
+    fn wake(&self) {
+        self.waker.wake();
+    }
+}
+
+/// An emulated Intel 82574 NIC.
+pub struct E1000eDevice {
+    config: ConfigSpaceType0Emulator,
+    msix: Option<MsixEmulator>,
+    shared: Arc<Shared>,
+    worker: TaskControl<Worker, DataPath>,
+}
+
+impl InspectMut for E1000eDevice {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("config", &self.config)
+            .field("registers", &*self.shared.regs.lock())
+            .merge(&mut self.worker);
+    }
+}
+
+impl E1000eDevice {
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        gm: GuestMemory,
+        interrupt_model: InterruptModel<'_>,
+        mac_address: MacAddress,
+        endpoint: Box<dyn Endpoint>,
+        register_mmio: &mut dyn RegisterMmioIntercept,
+    ) -> Self {
+        let hardware_ids = HardwareIds {
+            vendor_id: VENDOR_ID,
+            device_id: DEVICE_ID,
+            revision_id: 0,
+            prog_if: ProgrammingInterface::NONE,
+            sub_class: Subclass::NETWORK_CONTROLLER_ETHERNET,
+            base_class: ClassCode::NETWORK_CONTROLLER,
+            type0_sub_vendor_id: VENDOR_ID,
+            type0_sub_system_id: 0,
+        };
+
+        let mut bars = DeviceBars::new().bar0(
+            regs::BAR0_LEN,
+            BarMemoryKind::Intercept(register_mmio.new_io_region("regs", regs::BAR0_LEN)),
+        );
+
+        let mut caps: Vec<Box<dyn PciCapability>> = Vec::new();
+        let msix = if let InterruptModel::Msix(msi_target) = &interrupt_model {
+            let (msix, msix_capability) = MsixEmulator::new(2, MSIX_VECTORS, msi_target);
+            caps.push(Box::new(msix_capability));
+            caps.push(Box::new(PciExpressCapability::new(
+                pci_core::spec::caps::pci_express::DevicePortType::Endpoint,
+                None,
+            )));
+            bars = bars.bar2(
+                msix.bar_len(),
+                BarMemoryKind::Intercept(register_mmio.new_io_region("msix", msix.bar_len())),
+            );
+            Some(msix)
+        } else {
+            None
+        };
+
+        let mut config = ConfigSpaceType0Emulator::new(hardware_ids, caps, Vec::new(), bars);
+        let interrupts = match interrupt_model {
+            InterruptModel::Msix(_) => {
+                let msix = msix.as_ref().unwrap();
+                Interrupts::Msix(
+                    (0..MSIX_VECTORS)
+                        .map(|i| msix.interrupt(i).unwrap())
+                        .collect(),
+                )
+            }
+            InterruptModel::IntX(line) => {
+                Interrupts::IntX(config.set_interrupt_pin(PciInterruptPin::IntA, line))
+            }
+        };
+
+        let shared = Arc::new(Shared {
+            regs: Mutex::new(Registers::new(
+                mac_address,
+                interrupts,
+                VENDOR_ID,
+                DEVICE_ID,
+            )),
+            waker: AtomicWaker::new(),
+        });
+
+        let mut worker = TaskControl::new(Worker {
+            shared: shared.clone(),
+        });
+        let driver = driver_source.simple();
+        worker.insert(
+            &driver,
+            "e1000e",
+            DataPath::new(driver.clone(), gm, endpoint),
+        );
+
+        Self {
+            config,
+            msix,
+            shared,
+            worker,
+        }
+    }
+
+    fn read_reg(&mut self, offset: u64, data: &mut [u8]) {
+        let mut regs = self.shared.regs.lock();
+        read_as_u32_chunks(offset, data, |offset| regs.read(offset as u32));
+    }
+
+    fn write_reg(&mut self, offset: u64, data: &[u8]) {
+        let mut regs = self.shared.regs.lock();
+        let mut wake = false;
+        write_as_u32_chunks(offset, data, |offset, ty| match ty {
+            ReadWriteRequestType::Read => Some(regs.read(offset as u32)),
+            ReadWriteRequestType::Write(val) => {
+                wake |= regs.write(offset as u32, val);
+                None
+            }
+        });
+        drop(regs);
+        if wake {
+            self.shared.wake();
+        }
+    }
+}
+
+impl ChangeDeviceState for E1000eDevice {
+    fn start(&mut self) {
+        self.worker.start();
+    }
+
+    async fn stop(&mut self) {
+        self.worker.stop().await;
+    }
+
+    async fn reset(&mut self) {
+        self.worker.stop().await;
+        self.worker.state_mut().unwrap().reset().await;
+        self.shared.regs.lock().reset();
+        self.config.reset();
+    }
+}
+
+impl ChipsetDevice for E1000eDevice {
+    fn supports_mmio(&mut self) -> Option<&mut dyn MmioIntercept> {
+        Some(self)
+    }
+
+    fn supports_pci(&mut self) -> Option<&mut dyn PciConfigSpace> {
+        Some(self)
+    }
+}
+
+impl SaveRestore for E1000eDevice {
+    // This device should be constructed with `omit_saved_state`.
+    type SavedState = SavedStateNotSupported;
+
+    fn save(&mut self) -> Result<Self::SavedState, SaveError> {
+        Err(SaveError::NotSupported)
+    }
+
+    fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
+        match state {}
+    }
+}
+
+impl MmioIntercept for E1000eDevice {
+    fn mmio_read(&mut self, address: u64, data: &mut [u8]) -> IoResult {
+        if let Some((bar, offset)) = self.config.find_bar(address) {
+            match bar {
+                0 => self.read_reg(offset, data),
+                2 => {
+                    let msix = self.msix.as_ref().unwrap();
+                    read_as_u32_chunks(offset, data, |offset| msix.read_u32(offset))
+                }
+                _ => unreachable!(),
+            }
+        }
+        IoResult::Ok
+    }
+
+    fn mmio_write(&mut self, address: u64, data: &[u8]) -> IoResult {
+        if let Some((bar, offset)) = self.config.find_bar(address) {
+            match bar {
+                0 => self.write_reg(offset, data),
+                2 => {
+                    let msix = self.msix.as_mut().unwrap();
+                    write_as_u32_chunks(offset, data, |offset, ty| match ty {
+                        ReadWriteRequestType::Read => Some(msix.read_u32(offset)),
+                        ReadWriteRequestType::Write(val) => {
+                            msix.write_u32(offset, val);
+                            None
+                        }
+                    })
+                }
+                _ => unreachable!(),
+            }
+        }
+        IoResult::Ok
+    }
+}
+
+impl PciConfigSpace for E1000eDevice {
+    fn pci_cfg_read(&mut self, offset: u16, value: ByteEnabledDwordRead<'_>) -> IoResult {
+        self.config.read_byte_enabled(offset, value)
+    }
+
+    fn pci_cfg_write(&mut self, offset: u16, value: ByteEnabledDwordWrite) -> IoResult {
+        self.config.write_byte_enabled(offset, value)
+    }
+}
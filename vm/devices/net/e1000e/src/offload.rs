@@ -0,0 +1,188 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Software transmit offloads, for endpoints that do not support the offloads
+//! requested by the guest.
+//!
+//! These follow the hardware's semantics rather than parsing the packet: a
+//! checksum is the ones' complement sum over a byte range, seeded with
+//! whatever the driver left in the checksum field (the pseudo-header sum, for
+//! TCP and UDP), and segmentation replicates the header in front of each
+//! chunk of payload, fixing up the lengths, IPv4 identification, and TCP
+//! sequence number and flags.
+
+/// A checksum to insert into a packet.
+#[derive(Debug, Copy, Clone)]
+pub struct Checksum {
+    /// The offset of the first byte summed.
+    pub start: usize,
+    /// The offset of the checksum field.
+    pub offset: usize,
+    /// The offset just past the last byte summed, or `None` to sum to the end
+    /// of the packet.
+    pub end: Option<usize>,
+    /// Whether a zero checksum is sent as all ones, as for UDP.
+    pub zero_as_ones: bool,
+}
+
+/// TCP segmentation parameters, from the context descriptor.
+#[derive(Debug, Copy, Clone)]
+pub struct Segmentation {
+    /// Whether the packet is IPv4, rather than IPv6.
+    pub ipv4: bool,
+    /// The offset of the IP header.
+    pub ip_start: usize,
+    /// The offset of the TCP header.
+    pub tcp_start: usize,
+    /// The length of all headers, replicated in each segment.
+    pub header_len: usize,
+    /// The maximum payload length of each segment.
+    pub mss: usize,
+}
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_CWR: u8 = 0x80;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Adds `data`, as big-endian 16-bit words, to a ones' complement sum.
+fn sum(data: &[u8], mut sum: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+fn write_be16(packet: &mut [u8], offset: usize, val: u16) {
+    packet[offset..offset + 2].copy_from_slice(&val.to_be_bytes());
+}
+
+fn read_be16(packet: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([packet[offset], packet[offset + 1]])
+}
+
+impl Checksum {
+    fn is_valid(&self, len: usize) -> bool {
+        let end = self.end.unwrap_or(len);
+        self.start <= end && end <= len && self.offset + 2 <= len
+    }
+
+    /// Computes the checksum and inserts it into `packet`, adding `extra` to
+    /// the sum.
+    ///
+    /// As with hardware, the end of the range is clamped to the end of the
+    /// packet, since a segment may be shorter than the end offset the driver
+    /// specified for the whole packet.
+    fn insert_with(&self, packet: &mut [u8], extra: u32) {
+        let end = self.end.unwrap_or(packet.len()).min(packet.len());
+        let mut csum = !fold(sum(&packet[self.start..end], extra));
+        if csum == 0 && self.zero_as_ones {
+            csum = !0;
+        }
+        write_be16(packet, self.offset, csum);
+    }
+
+    /// Computes the checksum and inserts it into `packet`.
+    ///
+    /// Returns false if the checksum does not fit in the packet.
+    pub fn insert(&self, packet: &mut [u8]) -> bool {
+        if !self.is_valid(packet.len()) {
+            return false;
+        }
+        self.insert_with(packet, 0);
+        true
+    }
+}
+
+impl Segmentation {
+    /// Splits `packet` into segments, calling `f` with each one.
+    ///
+    /// `ip_checksum` and `tcp_checksum` are inserted into each segment if
+    /// present. As with hardware, the driver is expected to have zeroed the
+    /// IPv4 header checksum and to have seeded the TCP checksum with the
+    /// pseudo-header sum, excluding the length.
+    ///
+    /// Returns false if the parameters are inconsistent with the packet.
+    pub fn segment(
+        &self,
+        packet: &[u8],
+        ip_checksum: Option<Checksum>,
+        tcp_checksum: Option<Checksum>,
+        mut f: impl FnMut(&[u8]),
+    ) -> bool {
+        let min_header = if self.ipv4 {
+            self.ip_start + 20
+        } else {
+            self.ip_start + IPV6_HEADER_LEN
+        };
+        if self.mss == 0
+            || self.header_len > packet.len()
+            || min_header > self.tcp_start
+            || self.tcp_start + 20 > self.header_len
+            || ip_checksum.is_some_and(|c| !c.is_valid(self.header_len))
+            || tcp_checksum
+                .is_some_and(|c| c.start > self.header_len || c.offset + 2 > self.header_len)
+        {
+            return false;
+        }
+        let (header, payload) = packet.split_at(self.header_len);
+        let seq = u32::from_be_bytes(packet[self.tcp_start + 4..][..4].try_into().unwrap());
+        let ip_id = read_be16(packet, self.ip_start + 4);
+        let flags = packet[self.tcp_start + 13];
+        let count = payload.len().div_ceil(self.mss).max(1);
+        let mut frame = Vec::with_capacity(self.header_len + self.mss);
+        for (i, chunk) in payload
+            .chunks(self.mss)
+            .chain(payload.is_empty().then_some(&[][..]))
+            .enumerate()
+        {
+            frame.clear();
+            frame.extend_from_slice(header);
+            frame.extend_from_slice(chunk);
+            let len = frame.len();
+            if self.ipv4 {
+                write_be16(&mut frame, self.ip_start + 2, (len - self.ip_start) as u16);
+                write_be16(&mut frame, self.ip_start + 4, ip_id.wrapping_add(i as u16));
+            } else {
+                write_be16(
+                    &mut frame,
+                    self.ip_start + 4,
+                    (len - self.ip_start - IPV6_HEADER_LEN) as u16,
+                );
+            }
+            let offset = (i * self.mss) as u32;
+            frame[self.tcp_start + 4..][..4]
+                .copy_from_slice(&seq.wrapping_add(offset).to_be_bytes());
+            let mut segment_flags = flags;
+            if i + 1 < count {
+                segment_flags &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+            }
+            if i > 0 {
+                segment_flags &= !TCP_FLAG_CWR;
+            }
+            frame[self.tcp_start + 13] = segment_flags;
+            if let Some(csum) = &ip_checksum {
+                csum.insert_with(&mut frame, 0);
+            }
+            if let Some(csum) = &tcp_checksum {
+                // The seeded pseudo-header sum excludes the TCP length, which
+                // differs for each segment.
+                csum.insert_with(&mut frame, (len - self.tcp_start) as u32);
+            }
+            f(&frame);
+        }
+        true
+    }
+}
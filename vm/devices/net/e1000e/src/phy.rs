@@ -0,0 +1,120 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The NVM and the integrated PHY, as seen through the EERD and MDIC
+//! registers.
+
+use inspect::Inspect;
+use net_backend_resources::mac_address::MacAddress;
+
+/// The number of NVM words covered by the checksum.
+const NVM_CHECKSUM_WORDS: usize = 0x40;
+/// The word that makes the NVM checksum come out right.
+const NVM_CHECKSUM_REG: usize = 0x3f;
+/// The sum of the first 64 NVM words.
+const NVM_SUM: u16 = 0xbaba;
+/// The size of the NVM, in words.
+const NVM_WORDS: usize = 0x800;
+
+// NVM words read by drivers, besides the MAC address and checksum.
+const NVM_INIT_CONTROL2: usize = 0x0f;
+const NVM_INIT_CONTROL3: usize = 0x24;
+const NVM_DEVICE_ID: usize = 0x0d;
+const NVM_SUB_VENDOR_ID: usize = 0x0c;
+const NVM_SUB_DEVICE_ID: usize = 0x0b;
+const NVM_VENDOR_ID: usize = 0x0e;
+
+/// The emulated NVM (EEPROM), holding the MAC address.
+pub struct Nvm(Box<[u16; NVM_WORDS]>);
+
+impl Nvm {
+    pub fn new(mac_address: MacAddress, vendor_id: u16, device_id: u16) -> Self {
+        let mut words = Box::new([0; NVM_WORDS]);
+        let mac = mac_address.to_bytes();
+        for (word, bytes) in words.iter_mut().zip(mac.chunks_exact(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        words[NVM_SUB_DEVICE_ID] = device_id;
+        words[NVM_SUB_VENDOR_ID] = vendor_id;
+        words[NVM_DEVICE_ID] = device_id;
+        words[NVM_VENDOR_ID] = vendor_id;
+        // APM enabled, no manageability.
+        words[NVM_INIT_CONTROL2] = 0x7061;
+        words[NVM_INIT_CONTROL3] = 0x0400;
+        let sum = words[..NVM_CHECKSUM_WORDS]
+            .iter()
+            .fold(0u16, |sum, word| sum.wrapping_add(*word));
+        words[NVM_CHECKSUM_REG] = NVM_SUM.wrapping_sub(sum);
+        Self(words)
+    }
+
+    /// Reads a word, returning all ones past the end of the NVM.
+    pub fn read(&self, addr: u32) -> u16 {
+        self.0.get(addr as usize).copied().unwrap_or(!0)
+    }
+}
+
+/// The PHY's MDIO address.
+pub const PHY_ADDR: u32 = 1;
+
+const PHY_BMCR: u8 = 0;
+const PHY_BMSR: u8 = 1;
+const PHY_ID1: u8 = 2;
+const PHY_ID2: u8 = 3;
+const PHY_ANAR: u8 = 4;
+const PHY_ANLPAR: u8 = 5;
+const PHY_ANER: u8 = 6;
+const PHY_1000T_CTRL: u8 = 9;
+const PHY_1000T_STATUS: u8 = 10;
+const PHY_EXT_STATUS: u8 = 15;
+const PHY_SPEC_STATUS: u8 = 17;
+
+const BMCR_RESET: u16 = 1 << 15;
+const BMCR_ANRESTART: u16 = 1 << 9;
+
+/// An 82574 BME1000 PHY with the link up at 1000 Mb/s full duplex and
+/// autonegotiation complete.
+#[derive(Inspect)]
+pub struct Phy {
+    #[inspect(iter_by_index, hex)]
+    regs: [u16; 32],
+}
+
+impl Phy {
+    pub fn new() -> Self {
+        let mut regs = [0; 32];
+        regs[PHY_BMCR as usize] = 0x1140;
+        regs[PHY_BMSR as usize] = 0x796d;
+        regs[PHY_ID1 as usize] = 0x0141;
+        regs[PHY_ID2 as usize] = 0x0cb0;
+        regs[PHY_ANAR as usize] = 0x0de1;
+        regs[PHY_ANLPAR as usize] = 0xcde1;
+        regs[PHY_ANER as usize] = 0x000f;
+        regs[PHY_1000T_CTRL as usize] = 0x0300;
+        regs[PHY_1000T_STATUS as usize] = 0x3c00;
+        regs[PHY_EXT_STATUS as usize] = 0x3000;
+        // 1000 Mb/s, full duplex, speed and duplex resolved, link up.
+        regs[PHY_SPEC_STATUS as usize] = 0xac00;
+        Self { regs }
+    }
+
+    pub fn read(&self, reg: u8) -> u16 {
+        self.regs[reg as usize & 31]
+    }
+
+    pub fn write(&mut self, reg: u8, val: u16) {
+        match reg & 31 {
+            PHY_BMCR => {
+                if val & BMCR_RESET != 0 {
+                    *self = Self::new();
+                } else {
+                    // Autonegotiation completes immediately.
+                    self.regs[PHY_BMCR as usize] = val & !BMCR_ANRESTART;
+                }
+            }
+            PHY_BMSR | PHY_ID1 | PHY_ID2 | PHY_ANLPAR | PHY_ANER | PHY_1000T_STATUS
+            | PHY_EXT_STATUS | PHY_SPEC_STATUS => {}
+            reg => self.regs[reg as usize] = val,
+        }
+    }
+}
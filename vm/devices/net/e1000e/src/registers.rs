@@ -0,0 +1,298 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The register file, shared between the MMIO handlers and the data path.
+
+use crate::phy::Nvm;
+use crate::phy::PHY_ADDR;
+use crate::phy::Phy;
+use crate::regs::*;
+use inspect::Inspect;
+use net_backend_resources::mac_address::MacAddress;
+use pci_core::cfg_space_emu::IntxInterrupt;
+use std::sync::Arc;
+use vmcore::interrupt::Interrupt;
+
+/// The number of MSI-X vectors: one each for the two receive queues, the two
+/// transmit queues, and other causes. Only the first of each queue is
+/// implemented.
+pub const MSIX_VECTORS: u16 = 5;
+
+/// How the device signals interrupts.
+pub enum Interrupts {
+    /// MSI-X, with each cause routed to a vector through IVAR.
+    Msix(Vec<Interrupt>),
+    /// A level-triggered INTx line, asserted while any unmasked cause is
+    /// pending.
+    IntX(Arc<IntxInterrupt>),
+}
+
+/// The MSI-X cause routed by each IVAR entry.
+const MSIX_CAUSES: [(u32, u32); 3] = [
+    (INT_RXQ0, IVAR_RXQ0_SHIFT),
+    (INT_TXQ0, IVAR_TXQ0_SHIFT),
+    (INT_OTHER, IVAR_OTHER_SHIFT),
+];
+
+#[derive(Inspect)]
+pub struct Registers {
+    mac_address: MacAddress,
+    #[inspect(skip)]
+    regs: Box<[u32]>,
+    #[inspect(skip)]
+    nvm: Nvm,
+    phy: Phy,
+    #[inspect(hex)]
+    icr: u32,
+    #[inspect(hex)]
+    ims: u32,
+    /// The causes that have already been signaled through MSI-X, so that a
+    /// pending cause is only signaled once.
+    #[inspect(hex)]
+    msix_signaled: u32,
+    #[inspect(skip)]
+    interrupts: Interrupts,
+    /// Incremented whenever the data path must be torn down, on reset or when
+    /// the guest disables the receive or transmit unit.
+    epoch: u64,
+}
+
+impl Registers {
+    pub fn new(
+        mac_address: MacAddress,
+        interrupts: Interrupts,
+        vendor_id: u16,
+        device_id: u16,
+    ) -> Self {
+        let mut this = Self {
+            mac_address,
+            regs: vec![0; (BAR0_LEN / 4) as usize].into(),
+            nvm: Nvm::new(mac_address, vendor_id, device_id),
+            phy: Phy::new(),
+            icr: 0,
+            ims: 0,
+            msix_signaled: 0,
+            interrupts,
+            epoch: 0,
+        };
+        this.reset();
+        this
+    }
+
+    /// Resets the registers to their power-on values.
+    pub fn reset(&mut self) {
+        self.regs.fill(0);
+        self.set(CTRL, CTRL_FD | CTRL_SLU | CTRL_SPEED_1000);
+        self.set(EECD, EECD_PRES | EECD_AUTO_RD);
+        self.set(EEMNGCTL, EEMNGCTL_CFG_DONE0);
+        self.set(MDIC, MDIC_READY);
+        self.set(PBA, 0x14);
+        self.set(RXCSUM, RXCSUM_IPOFLD | RXCSUM_TUOFLD);
+        let mac = self.mac_address.to_bytes();
+        self.set(RAL0, u32::from_le_bytes(mac[..4].try_into().unwrap()));
+        self.set(RAH0, u16::from_le_bytes([mac[4], mac[5]]) as u32 | RAH_AV);
+        self.phy = Phy::new();
+        self.icr = 0;
+        self.ims = 0;
+        self.msix_signaled = 0;
+        self.epoch += 1;
+        self.update_interrupts();
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Returns the raw value of a register, without read side effects.
+    pub fn get(&self, offset: u32) -> u32 {
+        self.regs[(offset / 4) as usize]
+    }
+
+    /// Sets the raw value of a register, without write side effects.
+    pub fn set(&mut self, offset: u32, val: u32) {
+        self.regs[(offset / 4) as usize] = val;
+    }
+
+    /// Adds to a pair of 32-bit statistics registers holding a 64-bit count.
+    pub fn add_stat64(&mut self, low: u32, high: u32, n: u64) {
+        let count = ((self.get(high) as u64) << 32 | self.get(low) as u64).saturating_add(n);
+        self.set(low, count as u32);
+        self.set(high, (count >> 32) as u32);
+    }
+
+    /// Adds to a 32-bit statistics register.
+    pub fn add_stat(&mut self, offset: u32, n: u32) {
+        self.set(offset, self.get(offset).saturating_add(n));
+    }
+
+    pub fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            STATUS => {
+                let mut status = STATUS_FD | STATUS_LU | STATUS_SPEED_1000;
+                if self.get(CTRL) & CTRL_GIO_MASTER_DISABLE == 0 {
+                    status |= STATUS_GIO_MASTER_ENABLE;
+                }
+                status
+            }
+            ICR => {
+                let mut icr = self.icr;
+                if icr & self.ims != 0 {
+                    icr |= INT_ASSERTED;
+                }
+                if self.get(CTRL_EXT) & CTRL_EXT_IAME != 0 && icr & INT_ASSERTED != 0 {
+                    self.ims &= !self.get(IAM);
+                }
+                self.icr = 0;
+                self.msix_signaled = 0;
+                self.update_interrupts();
+                icr
+            }
+            ICS | IMS => self.ims,
+            IMC => 0,
+            STATS_START..STATS_END => {
+                let val = self.get(offset);
+                self.set(offset, 0);
+                val
+            }
+            _ => self.get(offset),
+        }
+    }
+
+    /// Writes a register.
+    ///
+    /// Returns true if the data path needs to be woken to notice the change.
+    pub fn write(&mut self, offset: u32, val: u32) -> bool {
+        match offset {
+            CTRL => {
+                if val & CTRL_RST != 0 {
+                    tracing::debug!("device reset");
+                    self.reset();
+                    return true;
+                }
+                if val & CTRL_PHY_RST != 0 {
+                    self.phy = Phy::new();
+                }
+                self.set(CTRL, val & !(CTRL_RST | CTRL_PHY_RST));
+            }
+            STATUS => {}
+            EECD => {
+                // The NVM is always granted on request. The NVM is read
+                // through EERD; bit-banged access is not supported.
+                let mut eecd = EECD_PRES | EECD_AUTO_RD | (val & EECD_REQ);
+                if val & EECD_REQ != 0 {
+                    eecd |= EECD_GNT;
+                }
+                self.set(EECD, eecd);
+            }
+            EERD => {
+                if val & EERD_START != 0 {
+                    let addr = (val >> EERD_ADDR_SHIFT) & 0x3fff;
+                    let data = self.nvm.read(addr);
+                    self.set(
+                        EERD,
+                        (data as u32) << EERD_DATA_SHIFT | addr << EERD_ADDR_SHIFT | EERD_DONE,
+                    );
+                }
+            }
+            MDIC => {
+                let reg = ((val >> MDIC_REG_SHIFT) & 31) as u8;
+                let phy = (val >> MDIC_PHY_SHIFT) & 31;
+                let mut mdic = (val & !(MDIC_ERROR | 0xffff)) | MDIC_READY;
+                if phy != PHY_ADDR {
+                    mdic |= MDIC_ERROR;
+                } else if val & MDIC_OP_READ == MDIC_OP_READ {
+                    mdic |= self.phy.read(reg) as u32;
+                } else if val & MDIC_OP_WRITE != 0 {
+                    self.phy.write(reg, val as u16);
+                    mdic |= val & 0xffff;
+                }
+                self.set(MDIC, mdic);
+            }
+            ICR => {
+                self.icr &= !val;
+                self.msix_signaled &= !val;
+                self.update_interrupts();
+            }
+            ICS => self.raise(val),
+            IMS => {
+                self.ims |= val;
+                self.update_interrupts();
+            }
+            IMC => {
+                self.ims &= !val;
+                self.update_interrupts();
+            }
+            RCTL | TCTL => {
+                let old = self.get(offset);
+                self.set(offset, val);
+                // Tear down the data path when either unit is disabled, so
+                // that the rings are reread when it is reenabled.
+                if old & !val & (RCTL_EN | TCTL_EN) != 0 {
+                    self.epoch += 1;
+                }
+                return true;
+            }
+            RDH | TDH => {
+                // The heads may only be written while the unit is disabled.
+                let ctl = if offset == RDH { RCTL } else { TCTL };
+                if self.get(ctl) & (RCTL_EN | TCTL_EN) == 0 {
+                    self.set(offset, val & 0xffff);
+                }
+            }
+            RDT | TDT => {
+                self.set(offset, val & 0xffff);
+                return true;
+            }
+            RDLEN | TDLEN => self.set(offset, val & 0xfff80),
+            RDBAL | TDBAL => self.set(offset, val & !0xf),
+            STATS_START..STATS_END => {}
+            _ => self.set(offset, val),
+        }
+        false
+    }
+
+    /// Sets interrupt causes in ICR.
+    pub fn raise(&mut self, causes: u32) {
+        self.icr |= causes;
+        self.msix_signaled &= !causes;
+        self.update_interrupts();
+    }
+
+    fn update_interrupts(&mut self) {
+        match &self.interrupts {
+            Interrupts::IntX(line) => line.set_level(self.icr & self.ims != 0),
+            Interrupts::Msix(vectors) => {
+                let ivar = self.get(IVAR);
+                let eiac = self.get(EIAC);
+                let iam = self.get(IAM);
+                let eiame = self.get(CTRL_EXT) & CTRL_EXT_EIAME != 0;
+                let pending = self.icr & self.ims & !self.msix_signaled;
+                if pending == 0 {
+                    return;
+                }
+                let routes = MSIX_CAUSES.map(|(cause, shift)| (cause, (ivar >> shift) & 0xf));
+                if routes.iter().all(|&(_, entry)| entry & IVAR_VALID == 0) {
+                    // Without any IVAR routing, as in MSI mode, all causes
+                    // are signaled on the first vector.
+                    self.msix_signaled |= pending;
+                    vectors[0].deliver();
+                    return;
+                }
+                for (cause, entry) in routes {
+                    if pending & cause == 0 || entry & IVAR_VALID == 0 {
+                        continue;
+                    }
+                    self.msix_signaled |= cause;
+                    // Auto-clear and auto-mask the cause as configured.
+                    self.icr &= !(eiac & cause);
+                    if eiame {
+                        self.ims &= !(iam & cause);
+                    }
+                    if let Some(vector) = vectors.get((entry & 7) as usize) {
+                        vector.deliver();
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Register offsets and bit definitions for the Intel 82574 (from the 82574
+//! GbE controller datasheet, chapter 10).
+
+/// The size of the register BAR.
+pub const BAR0_LEN: u64 = 0x20000;
+
+// General registers.
+pub const CTRL: u32 = 0x0;
+pub const STATUS: u32 = 0x8;
+pub const EECD: u32 = 0x10;
+pub const EERD: u32 = 0x14;
+pub const CTRL_EXT: u32 = 0x18;
+pub const MDIC: u32 = 0x20;
+
+// Interrupt registers.
+pub const ICR: u32 = 0xc0;
+pub const ICS: u32 = 0xc8;
+pub const IMS: u32 = 0xd0;
+pub const IMC: u32 = 0xd8;
+pub const EIAC: u32 = 0xdc;
+pub const IAM: u32 = 0xe0;
+pub const IVAR: u32 = 0xe4;
+
+// Receive and transmit control.
+pub const RCTL: u32 = 0x100;
+pub const TCTL: u32 = 0x400;
+pub const PBA: u32 = 0x1000;
+pub const EEMNGCTL: u32 = 0x1010;
+
+// Descriptor rings.
+pub const RDBAL: u32 = 0x2800;
+pub const RDBAH: u32 = 0x2804;
+pub const RDLEN: u32 = 0x2808;
+pub const RDH: u32 = 0x2810;
+pub const RDT: u32 = 0x2818;
+pub const TDBAL: u32 = 0x3800;
+pub const TDBAH: u32 = 0x3804;
+pub const TDLEN: u32 = 0x3808;
+pub const TDH: u32 = 0x3810;
+pub const TDT: u32 = 0x3818;
+
+/// The statistics registers, which clear on read.
+pub const STATS_START: u32 = 0x4000;
+pub const STATS_END: u32 = 0x4100;
+pub const GPRC: u32 = 0x4074;
+pub const GPTC: u32 = 0x4080;
+pub const GORCL: u32 = 0x4088;
+pub const GORCH: u32 = 0x408c;
+pub const GOTCL: u32 = 0x4090;
+pub const GOTCH: u32 = 0x4094;
+pub const TPR: u32 = 0x40d0;
+pub const TPT: u32 = 0x40d4;
+
+pub const RXCSUM: u32 = 0x5000;
+pub const RFCTL: u32 = 0x5008;
+pub const RAL0: u32 = 0x5400;
+pub const RAH0: u32 = 0x5404;
+
+pub const CTRL_FD: u32 = 1 << 0;
+pub const CTRL_GIO_MASTER_DISABLE: u32 = 1 << 2;
+pub const CTRL_SLU: u32 = 1 << 6;
+pub const CTRL_SPEED_1000: u32 = 2 << 8;
+pub const CTRL_RST: u32 = 1 << 26;
+pub const CTRL_VME: u32 = 1 << 30;
+pub const CTRL_PHY_RST: u32 = 1 << 31;
+
+pub const STATUS_FD: u32 = 1 << 0;
+pub const STATUS_LU: u32 = 1 << 1;
+pub const STATUS_SPEED_1000: u32 = 2 << 6;
+pub const STATUS_GIO_MASTER_ENABLE: u32 = 1 << 19;
+
+pub const EECD_REQ: u32 = 1 << 6;
+pub const EECD_GNT: u32 = 1 << 7;
+pub const EECD_PRES: u32 = 1 << 8;
+pub const EECD_AUTO_RD: u32 = 1 << 9;
+
+pub const EERD_START: u32 = 1 << 0;
+pub const EERD_DONE: u32 = 1 << 1;
+pub const EERD_ADDR_SHIFT: u32 = 2;
+pub const EERD_DATA_SHIFT: u32 = 16;
+
+pub const CTRL_EXT_IAME: u32 = 1 << 27;
+pub const CTRL_EXT_EIAME: u32 = 1 << 24;
+
+pub const EEMNGCTL_CFG_DONE0: u32 = 1 << 18;
+
+pub const MDIC_REG_SHIFT: u32 = 16;
+pub const MDIC_PHY_SHIFT: u32 = 21;
+pub const MDIC_OP_WRITE: u32 = 1 << 26;
+pub const MDIC_OP_READ: u32 = 2 << 26;
+pub const MDIC_READY: u32 = 1 << 28;
+pub const MDIC_ERROR: u32 = 1 << 30;
+
+// Interrupt causes, shared by ICR, ICS, IMS, and IMC.
+pub const INT_TXDW: u32 = 1 << 0;
+pub const INT_LSC: u32 = 1 << 2;
+pub const INT_RXT0: u32 = 1 << 7;
+pub const INT_RXQ0: u32 = 1 << 20;
+pub const INT_TXQ0: u32 = 1 << 22;
+pub const INT_OTHER: u32 = 1 << 24;
+pub const INT_ASSERTED: u32 = 1 << 31;
+
+/// The valid bit of an IVAR allocation entry.
+pub const IVAR_VALID: u32 = 0x8;
+pub const IVAR_RXQ0_SHIFT: u32 = 0;
+pub const IVAR_TXQ0_SHIFT: u32 = 8;
+pub const IVAR_OTHER_SHIFT: u32 = 16;
+
+pub const RCTL_EN: u32 = 1 << 1;
+pub const RCTL_DTYP_MASK: u32 = 3 << 10;
+pub const RCTL_BSIZE_SHIFT: u32 = 16;
+pub const RCTL_BSEX: u32 = 1 << 25;
+pub const RCTL_SECRC: u32 = 1 << 26;
+
+pub const TCTL_EN: u32 = 1 << 1;
+
+pub const RXCSUM_IPOFLD: u32 = 1 << 8;
+pub const RXCSUM_TUOFLD: u32 = 1 << 9;
+
+pub const RFCTL_EXSTEN: u32 = 1 << 15;
+
+pub const RAH_AV: u32 = 1 << 31;
+
+/// Receive descriptor status bits, in both the legacy and extended formats.
+pub const RXD_STAT_DD: u32 = 1 << 0;
+pub const RXD_STAT_EOP: u32 = 1 << 1;
+pub const RXD_STAT_IXSM: u32 = 1 << 2;
+pub const RXD_STAT_VP: u32 = 1 << 3;
+pub const RXD_STAT_UDPCS: u32 = 1 << 4;
+pub const RXD_STAT_TCPCS: u32 = 1 << 5;
+pub const RXD_STAT_IPCS: u32 = 1 << 6;
+
+/// Receive descriptor error bits, as in the legacy format. The extended
+/// format holds them in bits 31:24 of the status/error field.
+pub const RXD_ERR_TCPE: u8 = 1 << 5;
+pub const RXD_ERR_IPE: u8 = 1 << 6;
+pub const RXD_ERR_RXE: u8 = 1 << 7;
+
+/// Transmit command bits, in both the legacy (CMD) and extended (DCMD and
+/// TUCMD) formats.
+pub const TXD_CMD_EOP: u8 = 1 << 0;
+pub const TXD_CMD_IC: u8 = 1 << 2;
+pub const TXD_CMD_TSE: u8 = 1 << 2;
+pub const TXD_CMD_RS: u8 = 1 << 3;
+pub const TXD_CMD_DEXT: u8 = 1 << 5;
+pub const TXD_CMD_VLE: u8 = 1 << 6;
+
+/// Context descriptor TUCMD bits.
+pub const TXD_TUCMD_TCP: u8 = 1 << 0;
+pub const TXD_TUCMD_IP: u8 = 1 << 1;
+
+/// Extended descriptor types.
+pub const TXD_DTYP_CONTEXT: u8 = 0;
+pub const TXD_DTYP_DATA: u8 = 1;
+
+/// Data descriptor POPTS bits.
+pub const TXD_POPTS_IXSM: u8 = 1 << 0;
+pub const TXD_POPTS_TXSM: u8 = 1 << 1;
+
+/// Transmit descriptor status bits.
+pub const TXD_STAT_DD: u8 = 1 << 0;
@@ -0,0 +1,70 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolver for the e1000e NIC.
+
+use crate::E1000eDevice;
+use crate::InterruptModel;
+use async_trait::async_trait;
+use e1000e_resources::E1000eDeviceHandle;
+use net_backend::resolve::ResolveEndpointParams;
+use pci_resources::ResolvePciDeviceHandleParams;
+use pci_resources::ResolvedPciDevice;
+use thiserror::Error;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResolveError;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::PciDeviceHandleKind;
+
+/// Resource resolver for [`E1000eDeviceHandle`].
+///
+/// This resolves the device for PCIe ports, using MSI-X. Devices on the
+/// legacy PCI bus are constructed directly, since they need an INTx line.
+pub struct E1000eDeviceResolver;
+
+declare_static_async_resolver! {
+    E1000eDeviceResolver,
+    (PciDeviceHandleKind, E1000eDeviceHandle),
+}
+
+/// Error returned by [`E1000eDeviceResolver`].
+#[derive(Debug, Error)]
+#[expect(missing_docs)]
+pub enum Error {
+    #[error("failed to resolve endpoint")]
+    EndpointResolve(#[source] ResolveError),
+}
+
+#[async_trait]
+impl AsyncResolveResource<PciDeviceHandleKind, E1000eDeviceHandle> for E1000eDeviceResolver {
+    type Output = ResolvedPciDevice;
+    type Error = Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        resource: E1000eDeviceHandle,
+        input: ResolvePciDeviceHandleParams<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let endpoint = resolver
+            .resolve(
+                resource.endpoint,
+                ResolveEndpointParams {
+                    mac_address: resource.mac_address,
+                },
+            )
+            .await
+            .map_err(Error::EndpointResolve)?;
+
+        let device = E1000eDevice::new(
+            input.driver_source,
+            input.dma_target.guest_memory().clone(),
+            InterruptModel::Msix(input.dma_target.msi_target()),
+            resource.mac_address,
+            endpoint.0,
+            input.register_mmio,
+        );
+        Ok(device.into())
+    }
+}
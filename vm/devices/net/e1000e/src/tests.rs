@@ -0,0 +1,316 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::E1000eDevice;
+use crate::InterruptModel;
+use crate::regs::*;
+use chipset_device::mmio::ExternallyManagedMmioIntercepts;
+use chipset_device::mmio::MmioIntercept;
+use chipset_device::pci::ByteEnabledDwordWrite;
+use chipset_device::pci::PciConfigByteEnable;
+use chipset_device::pci::PciConfigSpace;
+use guestmem::GuestMemory;
+use net_backend::loopback::LoopbackEndpoint;
+use net_backend_resources::mac_address::MacAddress;
+use pal_async::DefaultDriver;
+use pal_async::async_test;
+use pal_async::timer::PolledTimer;
+use pci_core::spec::cfg_space;
+use std::time::Duration;
+use test_with_tracing::test;
+use vmcore::device_state::ChangeDeviceState;
+use vmcore::line_interrupt::LineInterrupt;
+use vmcore::vm_task::SingleDriverBackend;
+use vmcore::vm_task::VmTaskDriverSource;
+
+const BAR0: u64 = 0x100000000;
+const RX_RING: u64 = 0x10000;
+const TX_RING: u64 = 0x11000;
+const RX_BUFFERS: u64 = 0x20000;
+const TX_BUFFER: u64 = 0x40000;
+const RING_SIZE: u32 = 8;
+const MAC: MacAddress = MacAddress::new([0x00, 0x15, 0x5d, 0x12, 0x34, 0x56]);
+
+const ETH_LEN: usize = 14;
+const IP_LEN: usize = 20;
+const TCP_LEN: usize = 20;
+const HEADER_LEN: usize = ETH_LEN + IP_LEN + TCP_LEN;
+
+struct TestNic {
+    device: E1000eDevice,
+    gm: GuestMemory,
+    timer: PolledTimer,
+}
+
+impl TestNic {
+    fn new(driver: &DefaultDriver) -> Self {
+        let gm = GuestMemory::allocate(0x100000);
+        let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
+        let mut device = E1000eDevice::new(
+            &driver_source,
+            gm.clone(),
+            InterruptModel::IntX(LineInterrupt::detached()),
+            MAC,
+            Box::new(LoopbackEndpoint::new()),
+            &mut ExternallyManagedMmioIntercepts,
+        );
+        device
+            .pci_cfg_write(
+                0x14,
+                ByteEnabledDwordWrite::with_all_bytes_enabled((BAR0 >> 32) as u32),
+            )
+            .unwrap();
+        device
+            .pci_cfg_write(
+                0x10,
+                ByteEnabledDwordWrite::with_all_bytes_enabled(BAR0 as u32),
+            )
+            .unwrap();
+        device
+            .pci_cfg_write(
+                0x4,
+                ByteEnabledDwordWrite::new(
+                    cfg_space::Command::new()
+                        .with_mmio_enabled(true)
+                        .with_bus_master(true)
+                        .into_bits() as u32,
+                    PciConfigByteEnable::LOW_WORD,
+                ),
+            )
+            .unwrap();
+        device.start();
+        Self {
+            device,
+            gm,
+            timer: PolledTimer::new(driver),
+        }
+    }
+
+    fn read(&mut self, offset: u32) -> u32 {
+        let mut data = [0; 4];
+        self.device
+            .mmio_read(BAR0 + offset as u64, &mut data)
+            .unwrap();
+        u32::from_le_bytes(data)
+    }
+
+    fn write(&mut self, offset: u32, val: u32) {
+        self.device
+            .mmio_write(BAR0 + offset as u64, &val.to_le_bytes())
+            .unwrap();
+    }
+
+    /// Sets up the rings and enables receive and transmit, with all but one
+    /// receive descriptor available.
+    fn enable(&mut self) {
+        for i in 0..RING_SIZE as u64 {
+            self.gm
+                .write_plain(RX_RING + i * 16, &(RX_BUFFERS + i * 2048))
+                .unwrap();
+        }
+        self.write(RDBAL, RX_RING as u32);
+        self.write(RDLEN, RING_SIZE * 16);
+        self.write(TDBAL, TX_RING as u32);
+        self.write(TDLEN, RING_SIZE * 16);
+        self.write(IMS, INT_TXDW | INT_RXT0);
+        self.write(RCTL, RCTL_EN | RCTL_SECRC);
+        self.write(TCTL, TCTL_EN);
+        self.write(RDT, RING_SIZE - 1);
+    }
+
+    async fn wait_for(&mut self, offset: u32, val: u32) {
+        for _ in 0..100 {
+            if self.read(offset) == val {
+                return;
+            }
+            self.timer.sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting for register {offset:#x} to be {val:#x}");
+    }
+
+    /// Writes a TCP/IPv4 context descriptor. A `tucse` of zero sums the TCP
+    /// checksum to the end of the packet.
+    fn write_context(&self, index: u64, mss: u16, tse: bool, tucse: u16) {
+        let mut desc = [0u8; 16];
+        desc[0] = ETH_LEN as u8;
+        desc[1] = (ETH_LEN + 10) as u8;
+        desc[2..4].copy_from_slice(&((ETH_LEN + IP_LEN - 1) as u16).to_le_bytes());
+        desc[4] = (ETH_LEN + IP_LEN) as u8;
+        desc[5] = (ETH_LEN + IP_LEN + 16) as u8;
+        desc[6..8].copy_from_slice(&tucse.to_le_bytes());
+        desc[10] = TXD_DTYP_CONTEXT << 4;
+        desc[11] = TXD_CMD_DEXT | TXD_TUCMD_TCP | TXD_TUCMD_IP | if tse { TXD_CMD_TSE } else { 0 };
+        desc[13] = HEADER_LEN as u8;
+        desc[14..16].copy_from_slice(&mss.to_le_bytes());
+        self.gm.write_at(TX_RING + index * 16, &desc).unwrap();
+    }
+
+    fn write_data(&self, index: u64, len: usize, tse: bool) {
+        let mut desc = [0u8; 16];
+        desc[..8].copy_from_slice(&TX_BUFFER.to_le_bytes());
+        desc[8..12].copy_from_slice(&((len as u32) | (TXD_DTYP_DATA as u32) << 20).to_le_bytes());
+        desc[11] |= TXD_CMD_DEXT | TXD_CMD_EOP | TXD_CMD_RS | if tse { TXD_CMD_TSE } else { 0 };
+        desc[13] = TXD_POPTS_IXSM | TXD_POPTS_TXSM;
+        self.gm.write_at(TX_RING + index * 16, &desc).unwrap();
+    }
+
+    fn rx_packet(&self, index: u64) -> Vec<u8> {
+        let desc: [u8; 16] = self.gm.read_plain(RX_RING + index * 16).unwrap();
+        assert_eq!(
+            desc[12] as u32 & (RXD_STAT_DD | RXD_STAT_EOP),
+            RXD_STAT_DD | RXD_STAT_EOP
+        );
+        let len = u16::from_le_bytes([desc[8], desc[9]]) as usize;
+        let mut data = vec![0; len];
+        self.gm
+            .read_at(RX_BUFFERS + index * 2048, &mut data)
+            .unwrap();
+        data
+    }
+}
+
+fn sum(data: &[u8], mut sum: u32) -> u32 {
+    for word in data.chunks(2) {
+        sum += u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum
+}
+
+/// Builds a TCP/IPv4 packet with the checksums seeded as a driver would for
+/// offload: the IP checksum zeroed, and the TCP checksum set to the
+/// pseudo-header sum, including the TCP length unless segmenting.
+fn tcp_packet(payload_len: usize, tse: bool) -> Vec<u8> {
+    let mut packet = vec![0; HEADER_LEN + payload_len];
+    packet[..6].copy_from_slice(&MAC.to_bytes());
+    packet[6..12].copy_from_slice(&[0x00, 0x15, 0x5d, 0xab, 0xcd, 0xef]);
+    packet[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+    let ip = &mut packet[ETH_LEN..];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&((IP_LEN + TCP_LEN + payload_len) as u16).to_be_bytes());
+    ip[4..6].copy_from_slice(&0x1000u16.to_be_bytes());
+    ip[8] = 64;
+    ip[9] = 6;
+    ip[12..16].copy_from_slice(&[10, 0, 0, 1]);
+    ip[16..20].copy_from_slice(&[10, 0, 0, 2]);
+    let tcp = &mut packet[ETH_LEN + IP_LEN..];
+    tcp[0..2].copy_from_slice(&1234u16.to_be_bytes());
+    tcp[2..4].copy_from_slice(&80u16.to_be_bytes());
+    tcp[4..8].copy_from_slice(&1000u32.to_be_bytes());
+    tcp[12] = 5 << 4;
+    tcp[13] = 0x18; // PSH, ACK
+    for (i, b) in tcp[TCP_LEN..].iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    let mut pseudo = sum(&packet[ETH_LEN + 12..ETH_LEN + 20], 6);
+    if !tse {
+        pseudo = sum(&[], pseudo + (TCP_LEN + payload_len) as u32);
+    }
+    packet[ETH_LEN + IP_LEN + 16..][..2].copy_from_slice(&(pseudo as u16).to_be_bytes());
+    packet
+}
+
+fn check_checksums(packet: &[u8]) {
+    let ip = &packet[ETH_LEN..ETH_LEN + IP_LEN];
+    assert_eq!(sum(ip, 0), 0xffff, "bad IP checksum");
+    let tcp = &packet[ETH_LEN + IP_LEN..];
+    let pseudo = sum(&ip[12..20], 6 + tcp.len() as u32);
+    assert_eq!(sum(tcp, pseudo), 0xffff, "bad TCP checksum");
+}
+
+#[async_test]
+async fn test_nvm_mac_address(driver: DefaultDriver) {
+    let mut nic = TestNic::new(&driver);
+    let mut mac = Vec::new();
+    for addr in 0..3 {
+        nic.write(EERD, addr << EERD_ADDR_SHIFT | EERD_START);
+        let eerd = nic.read(EERD);
+        assert_ne!(eerd & EERD_DONE, 0);
+        mac.extend_from_slice(&((eerd >> EERD_DATA_SHIFT) as u16).to_le_bytes());
+    }
+    assert_eq!(mac, MAC.to_bytes());
+    assert_ne!(nic.read(STATUS) & STATUS_LU, 0);
+}
+
+#[async_test]
+async fn test_checksum_offload(driver: DefaultDriver) {
+    let mut nic = TestNic::new(&driver);
+    nic.enable();
+
+    let packet = tcp_packet(100, false);
+    nic.gm.write_at(TX_BUFFER, &packet).unwrap();
+    nic.write_context(0, 0, false, 0);
+    nic.write_data(1, packet.len(), false);
+    nic.write(TDT, 2);
+
+    nic.wait_for(RDH, 1).await;
+    nic.wait_for(TDH, 2).await;
+    let tx_status: u8 = nic.gm.read_plain(TX_RING + 16 + 12).unwrap();
+    assert_eq!(tx_status & TXD_STAT_DD, TXD_STAT_DD);
+
+    let received = nic.rx_packet(0);
+    assert_eq!(received.len(), packet.len());
+    assert_eq!(received[HEADER_LEN..], packet[HEADER_LEN..]);
+    check_checksums(&received);
+
+    let icr = nic.read(ICR);
+    assert_eq!(icr & (INT_TXDW | INT_RXT0), INT_TXDW | INT_RXT0);
+    assert_eq!(nic.read(ICR), 0);
+    assert_eq!(nic.read(GPTC), 1);
+    assert_eq!(nic.read(GPRC), 1);
+}
+
+#[async_test]
+async fn test_segmentation_offload(driver: DefaultDriver) {
+    let mut nic = TestNic::new(&driver);
+    nic.enable();
+
+    let mss = 100;
+    let packet = tcp_packet(250, true);
+    nic.gm.write_at(TX_BUFFER, &packet).unwrap();
+    nic.write_context(0, mss as u16, true, 0);
+    nic.write_data(1, packet.len(), true);
+    nic.write(TDT, 2);
+
+    nic.wait_for(RDH, 3).await;
+    let mut seq = 1000;
+    for (i, payload_len) in [100, 100, 50].into_iter().enumerate() {
+        let received = nic.rx_packet(i as u64);
+        assert_eq!(received.len(), HEADER_LEN + payload_len);
+        check_checksums(&received);
+        let tcp = &received[ETH_LEN + IP_LEN..];
+        assert_eq!(u32::from_be_bytes(tcp[4..8].try_into().unwrap()), seq);
+        // PSH is only set on the last segment.
+        assert_eq!(tcp[13] & 0x08 != 0, i == 2);
+        assert_eq!(
+            received[HEADER_LEN..],
+            packet[HEADER_LEN + i * mss..][..payload_len]
+        );
+        seq += payload_len as u32;
+    }
+}
+
+#[async_test]
+async fn test_segmentation_offload_large_tucse(driver: DefaultDriver) {
+    let mut nic = TestNic::new(&driver);
+    nic.enable();
+
+    // The checksum end offset is past the end of the short last segment, so
+    // it must be clamped to each segment's length.
+    let mss = 100;
+    let packet = tcp_packet(250, true);
+    nic.gm.write_at(TX_BUFFER, &packet).unwrap();
+    nic.write_context(0, mss as u16, true, 0xfffe);
+    nic.write_data(1, packet.len(), true);
+    nic.write(TDT, 2);
+
+    nic.wait_for(RDH, 3).await;
+    nic.wait_for(TDH, 2).await;
+    for (i, payload_len) in [100, 100, 50].into_iter().enumerate() {
+        let received = nic.rx_packet(i as u64);
+        assert_eq!(received.len(), HEADER_LEN + payload_len);
+        check_checksums(&received);
+    }
+}
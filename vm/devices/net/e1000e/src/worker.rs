@@ -0,0 +1,1043 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The receive and transmit data path, connecting the descriptor rings to the
+//! network endpoint.
+
+use crate::Shared;
+use crate::offload::Checksum;
+use crate::offload::Segmentation;
+use crate::registers::Registers;
+use crate::regs::*;
+use anyhow::Context as _;
+use guestmem::GuestMemory;
+use inspect::Inspect;
+use inspect::InspectMut;
+use net_backend::BufferAccess;
+use net_backend::Endpoint;
+use net_backend::Queue;
+use net_backend::QueueConfig;
+use net_backend::RxBufferSegment;
+use net_backend::RxChecksumState;
+use net_backend::RxId;
+use net_backend::RxMetadata;
+use net_backend::TxId;
+use net_backend::TxMetadata;
+use net_backend::TxOffloadSupport;
+use net_backend::TxSegment;
+use net_backend::TxSegmentType;
+use net_backend::VlanMetadata;
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use task_control::AsyncRun;
+use task_control::InspectTaskMut;
+use task_control::StopTask;
+use vmcore::vm_task::VmTaskDriver;
+
+/// The size of a receive or transmit descriptor.
+const DESCRIPTOR_SIZE: u64 = 16;
+
+/// The most descriptors in a packet passed directly to the endpoint, limited
+/// by [`TxMetadata::segment_count`]. Longer packets are copied.
+const MAX_SEGMENTS: usize = u8::MAX as usize;
+
+/// The largest packet accepted for software offload.
+const MAX_SOFTWARE_PACKET: usize = 256 * 1024;
+
+/// The number of receive or transmit completions polled at a time.
+const POLL_BATCH: usize = 32;
+
+/// The task that runs the data path.
+pub struct Worker {
+    pub(crate) shared: Arc<Shared>,
+}
+
+impl InspectTaskMut<DataPath> for Worker {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>, state: Option<&mut DataPath>) {
+        let mut resp = req.respond();
+        if let Some(state) = state {
+            resp.field_mut("endpoint", state.endpoint.as_mut())
+                .field_mut("queue", &mut state.queue)
+                .field("rx", &state.rx)
+                .field("tx", &state.tx);
+        }
+    }
+}
+
+impl AsyncRun<DataPath> for Worker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut DataPath,
+    ) -> Result<(), task_control::Cancelled> {
+        stop.until_stopped(state.run(&self.shared)).await
+    }
+}
+
+/// The data path state, owning the endpoint.
+pub struct DataPath {
+    driver: VmTaskDriver,
+    endpoint: Box<dyn Endpoint>,
+    offloads: TxOffloadSupport,
+    queue: Option<Box<dyn Queue>>,
+    /// The register epoch that the data path state matches.
+    epoch: u64,
+    /// Set if the data path failed, until the next reset.
+    failed: bool,
+    buffers: Buffers,
+    rx: RxRing,
+    tx: TxRing,
+}
+
+/// The guest receive buffers, indexed by receive descriptor, and the bounce
+/// buffer for packets transmitted with software offloads.
+struct Buffers {
+    gm: GuestMemory,
+    bounce: GuestMemory,
+    bounce_len: usize,
+    buffer_size: u32,
+    strip_crc: bool,
+    rx: Vec<RxBuffer>,
+}
+
+#[derive(Default, Clone)]
+struct RxBuffer {
+    gpa: u64,
+    /// Whether the Ethernet CRC was appended to the packet.
+    crc: bool,
+    done: Option<RxMetadata>,
+}
+
+impl BufferAccess for Buffers {
+    fn guest_memory(&self) -> &GuestMemory {
+        &self.gm
+    }
+
+    fn write_data(&mut self, id: RxId, data: &[u8]) {
+        let buffer = &mut self.rx[id.0 as usize];
+        let len = data.len().min(self.buffer_size as usize);
+        if let Err(err) = self.gm.write_at(buffer.gpa, &data[..len]) {
+            tracelimit::warn_ratelimited!(
+                gpa = buffer.gpa,
+                error = &err as &dyn std::error::Error,
+                "rx memory write failure"
+            );
+        }
+        // The endpoint does not provide the CRC, so compute it if the driver
+        // did not ask for it to be stripped.
+        buffer.crc = !self.strip_crc && len + 4 <= self.buffer_size as usize && len == data.len();
+        if buffer.crc {
+            let crc = crc32fast::hash(data).to_le_bytes();
+            let _ = self.gm.write_at(buffer.gpa + len as u64, &crc);
+        }
+    }
+
+    fn push_guest_addresses(&self, id: RxId, buf: &mut Vec<RxBufferSegment>) {
+        buf.push(RxBufferSegment {
+            gpa: self.rx[id.0 as usize].gpa,
+            len: self.buffer_size,
+        });
+    }
+
+    fn capacity(&self, _id: RxId) -> u32 {
+        self.buffer_size
+    }
+
+    fn write_header(&mut self, id: RxId, metadata: &RxMetadata) {
+        assert_eq!(metadata.offset, 0);
+        self.rx[id.0 as usize].done = Some(*metadata);
+    }
+}
+
+/// A view of [`Buffers`] for transmitting from the bounce buffer.
+struct BounceBuffers<'a>(&'a mut Buffers);
+
+impl BufferAccess for BounceBuffers<'_> {
+    fn guest_memory(&self) -> &GuestMemory {
+        &self.0.bounce
+    }
+
+    fn write_data(&mut self, id: RxId, data: &[u8]) {
+        self.0.write_data(id, data)
+    }
+
+    fn push_guest_addresses(&self, id: RxId, buf: &mut Vec<RxBufferSegment>) {
+        self.0.push_guest_addresses(id, buf)
+    }
+
+    fn capacity(&self, id: RxId) -> u32 {
+        self.0.capacity(id)
+    }
+
+    fn write_header(&mut self, id: RxId, metadata: &RxMetadata) {
+        self.0.write_header(id, metadata)
+    }
+}
+
+#[derive(Inspect, Default)]
+struct RxRing {
+    active: bool,
+    #[inspect(hex)]
+    base: u64,
+    size: u32,
+    /// The next descriptor to write back.
+    head: u32,
+    /// The next descriptor to post to the endpoint.
+    next: u32,
+    /// Whether the extended descriptor format is in use.
+    extended: bool,
+}
+
+#[derive(Inspect, Default)]
+struct TxRing {
+    active: bool,
+    #[inspect(hex)]
+    base: u64,
+    size: u32,
+    /// The descriptor after the last retired packet.
+    head: u32,
+    /// The next descriptor to fetch.
+    next: u32,
+    #[inspect(skip)]
+    context: TxContext,
+    #[inspect(skip)]
+    packet: PacketBuilder,
+    /// A fetched packet waiting for the bounce buffer.
+    #[inspect(skip)]
+    blocked: Option<PacketBuilder>,
+    /// Packets passed to the endpoint, in ring order.
+    #[inspect(with = "VecDeque::len")]
+    inflight: VecDeque<TxPacket>,
+    /// The segments of the last packet not yet accepted by the endpoint.
+    #[inspect(with = "Vec::len")]
+    unsent: Vec<TxSegment>,
+    unsent_bounce: bool,
+    bounce_busy: bool,
+}
+
+/// The offload parameters from the last context descriptor.
+#[derive(Default, Copy, Clone)]
+struct TxContext {
+    ipcss: u8,
+    ipcso: u8,
+    ipcse: u16,
+    tucss: u8,
+    tucso: u8,
+    tucse: u16,
+    tucmd: u8,
+    hdrlen: u8,
+    mss: u16,
+}
+
+/// The offloads requested by a packet's first data descriptor.
+#[derive(Copy, Clone)]
+enum PacketOffload {
+    /// A legacy descriptor's checksum insertion.
+    Legacy { css: u8, cso: u8 },
+    /// An extended data descriptor's offloads, using the context.
+    Extended { popts: u8, tse: bool },
+}
+
+#[derive(Default)]
+struct PacketBuilder {
+    first: Option<u32>,
+    end: u32,
+    segments: Vec<(u64, u32)>,
+    len: usize,
+    /// The descriptors to write back, because they have RS set.
+    report: Vec<u32>,
+    offload: Option<PacketOffload>,
+    vlan: Option<u16>,
+}
+
+struct TxPacket {
+    id: u32,
+    end: u32,
+    report: Vec<u32>,
+    len: usize,
+    /// The number of frames not yet completed by the endpoint.
+    outstanding: usize,
+    bounce: bool,
+}
+
+impl DataPath {
+    pub fn new(driver: VmTaskDriver, gm: GuestMemory, endpoint: Box<dyn Endpoint>) -> Self {
+        Self {
+            driver,
+            endpoint,
+            offloads: TxOffloadSupport::default(),
+            queue: None,
+            epoch: 0,
+            failed: false,
+            buffers: Buffers {
+                gm,
+                bounce: GuestMemory::empty(),
+                bounce_len: 0,
+                buffer_size: 0,
+                strip_crc: false,
+                rx: Vec::new(),
+            },
+            rx: RxRing::default(),
+            tx: TxRing::default(),
+        }
+    }
+
+    /// Tears down the queue, dropping any buffers posted to the endpoint.
+    pub async fn reset(&mut self) {
+        if self.queue.take().is_some() {
+            self.endpoint.stop().await;
+        }
+        self.rx = RxRing::default();
+        self.tx = TxRing::default();
+        self.buffers.rx.clear();
+        self.failed = false;
+    }
+
+    async fn run(&mut self, shared: &Shared) {
+        loop {
+            let (epoch, enabled) = {
+                let regs = shared.regs.lock();
+                (
+                    regs.epoch(),
+                    regs.get(RCTL) & RCTL_EN != 0 || regs.get(TCTL) & TCTL_EN != 0,
+                )
+            };
+            if epoch != self.epoch {
+                self.reset().await;
+                self.epoch = epoch;
+            }
+            if self.queue.is_none() && enabled && !self.failed {
+                if let Err(err) = self.start().await {
+                    tracing::error!(
+                        error = err.as_ref() as &dyn std::error::Error,
+                        "failed to start e1000e data path"
+                    );
+                    self.failed = true;
+                }
+            }
+            if self.queue.is_some() {
+                if let Err(err) = poll_fn(|cx| self.poll(cx, shared)).await {
+                    tracing::error!(
+                        error = err.as_ref() as &dyn std::error::Error,
+                        "e1000e data path failure"
+                    );
+                    self.failed = true;
+                    self.queue = None;
+                    self.endpoint.stop().await;
+                }
+            } else {
+                // Wait for the guest to enable the device, or to reset it
+                // after a failure.
+                poll_fn(|cx| {
+                    shared.waker.register(cx.waker());
+                    let regs = shared.regs.lock();
+                    let enabled = regs.get(RCTL) & RCTL_EN != 0 || regs.get(TCTL) & TCTL_EN != 0;
+                    if regs.epoch() != self.epoch || (enabled && !self.failed) {
+                        Poll::Ready(())
+                    } else {
+                        Poll::Pending
+                    }
+                })
+                .await;
+            }
+        }
+    }
+
+    async fn start(&mut self) -> anyhow::Result<()> {
+        let mut queues = Vec::new();
+        self.endpoint
+            .get_queues(
+                vec![QueueConfig {
+                    driver: Box::new(self.driver.clone()),
+                }],
+                None,
+                &mut queues,
+            )
+            .await
+            .context("failed to get endpoint queue")?;
+        self.queue = Some(
+            queues
+                .drain(..)
+                .next()
+                .context("endpoint returned no queue")?,
+        );
+        self.offloads = self.endpoint.tx_offload_support();
+        Ok(())
+    }
+
+    /// Processes the rings until the device is reset or disabled.
+    fn poll(&mut self, cx: &mut Context<'_>, shared: &Shared) -> Poll<anyhow::Result<()>> {
+        shared.waker.register(cx.waker());
+        loop {
+            let queue = self.queue.as_mut().unwrap();
+            let ready = queue.poll_ready(cx, &mut self.buffers).is_ready();
+            let mut regs = shared.regs.lock();
+            if regs.epoch() != self.epoch {
+                return Poll::Ready(Ok(()));
+            }
+            if !self.rx.active && regs.get(RCTL) & RCTL_EN != 0 {
+                self.init_rx(&regs);
+            }
+            if !self.tx.active && regs.get(TCTL) & TCTL_EN != 0 {
+                self.init_tx(&regs);
+            }
+            let mut progress = false;
+            if self.rx.active {
+                progress |= self.post_rx(&regs);
+                progress |= self.complete_rx(&mut regs)?;
+            }
+            if self.tx.active {
+                progress |= self.process_tx(&mut regs)?;
+                progress |= self.complete_tx(&mut regs)?;
+            }
+            if !progress && !ready {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn init_rx(&mut self, regs: &Registers) {
+        let rctl = regs.get(RCTL);
+        if rctl & RCTL_DTYP_MASK != 0 {
+            tracelimit::warn_ratelimited!(rctl, "packet split receive is not supported");
+            return;
+        }
+        let size = regs.get(RDLEN) / DESCRIPTOR_SIZE as u32;
+        let head = regs.get(RDH);
+        if head >= size {
+            tracelimit::warn_ratelimited!(size, head, "invalid receive ring");
+            return;
+        }
+        let bsize = (rctl >> RCTL_BSIZE_SHIFT) & 3;
+        self.buffers.buffer_size = if rctl & RCTL_BSEX != 0 {
+            [2048, 16384, 8192, 4096][bsize as usize]
+        } else {
+            [2048, 1024, 512, 256][bsize as usize]
+        };
+        self.buffers.strip_crc = rctl & RCTL_SECRC != 0;
+        self.buffers.rx = vec![RxBuffer::default(); size as usize];
+        self.rx = RxRing {
+            active: true,
+            base: (regs.get(RDBAH) as u64) << 32 | regs.get(RDBAL) as u64,
+            size,
+            head,
+            next: head,
+            extended: regs.get(RFCTL) & RFCTL_EXSTEN != 0,
+        };
+    }
+
+    fn init_tx(&mut self, regs: &Registers) {
+        let size = regs.get(TDLEN) / DESCRIPTOR_SIZE as u32;
+        let head = regs.get(TDH);
+        if head >= size {
+            tracelimit::warn_ratelimited!(size, head, "invalid transmit ring");
+            return;
+        }
+        self.tx = TxRing {
+            active: true,
+            base: (regs.get(TDBAH) as u64) << 32 | regs.get(TDBAL) as u64,
+            size,
+            head,
+            next: head,
+            ..Default::default()
+        };
+    }
+
+    fn descriptor_gpa(base: u64, index: u32) -> u64 {
+        base.wrapping_add(index as u64 * DESCRIPTOR_SIZE)
+    }
+
+    /// Posts the receive descriptors made available by the guest.
+    fn post_rx(&mut self, regs: &Registers) -> bool {
+        let tail = regs.get(RDT);
+        if tail >= self.rx.size {
+            return false;
+        }
+        let mut ids = Vec::new();
+        while self.rx.next != tail {
+            let index = self.rx.next;
+            let gpa = match self
+                .buffers
+                .gm
+                .read_plain::<u64>(Self::descriptor_gpa(self.rx.base, index))
+            {
+                Ok(gpa) => gpa,
+                Err(err) => {
+                    tracelimit::warn_ratelimited!(
+                        error = &err as &dyn std::error::Error,
+                        "failed to read receive descriptor"
+                    );
+                    break;
+                }
+            };
+            self.buffers.rx[index as usize] = RxBuffer {
+                gpa,
+                ..Default::default()
+            };
+            ids.push(RxId(index));
+            self.rx.next = (index + 1) % self.rx.size;
+        }
+        if ids.is_empty() {
+            return false;
+        }
+        self.queue
+            .as_mut()
+            .unwrap()
+            .rx_avail(&mut self.buffers, &ids);
+        true
+    }
+
+    /// Writes back completed receives, in ring order.
+    fn complete_rx(&mut self, regs: &mut Registers) -> anyhow::Result<bool> {
+        let queue = self.queue.as_mut().unwrap();
+        let mut ids = [RxId(0); POLL_BATCH];
+        let mut progress = false;
+        loop {
+            let n = queue.rx_poll(&mut self.buffers, &mut ids)?;
+            progress |= n > 0;
+            if n < ids.len() {
+                break;
+            }
+        }
+
+        let rxcsum = regs.get(RXCSUM);
+        let vlan_enabled = regs.get(CTRL) & CTRL_VME != 0;
+        let mut count = 0;
+        let mut bytes = 0;
+        while self.rx.head != self.rx.next {
+            let index = self.rx.head;
+            let buffer = &mut self.buffers.rx[index as usize];
+            let Some(meta) = buffer.done.take() else {
+                break;
+            };
+
+            let mut len = meta.len;
+            let mut status = RXD_STAT_DD | RXD_STAT_EOP;
+            let mut errors = 0;
+            if len > self.buffers.buffer_size as usize {
+                tracelimit::warn_ratelimited!(len, "received packet exceeds buffer size");
+                len = self.buffers.buffer_size as usize;
+                errors |= RXD_ERR_RXE;
+            } else if buffer.crc {
+                len += 4;
+            }
+            let mut checked = false;
+            if rxcsum & RXCSUM_IPOFLD != 0 {
+                match meta.ip_checksum {
+                    RxChecksumState::Good | RxChecksumState::ValidatedButWrong => {
+                        status |= RXD_STAT_IPCS;
+                        checked = true;
+                    }
+                    RxChecksumState::Bad => {
+                        status |= RXD_STAT_IPCS;
+                        errors |= RXD_ERR_IPE;
+                        checked = true;
+                    }
+                    RxChecksumState::Unknown => {}
+                }
+            }
+            if rxcsum & RXCSUM_TUOFLD != 0 {
+                let l4_status = match meta.l4_protocol {
+                    net_backend::L4Protocol::Tcp => RXD_STAT_TCPCS,
+                    net_backend::L4Protocol::Udp => RXD_STAT_UDPCS,
+                    net_backend::L4Protocol::Unknown => 0,
+                };
+                if l4_status != 0 {
+                    match meta.l4_checksum {
+                        RxChecksumState::Good | RxChecksumState::ValidatedButWrong => {
+                            status |= l4_status;
+                            checked = true;
+                        }
+                        RxChecksumState::Bad => {
+                            status |= l4_status;
+                            errors |= RXD_ERR_TCPE;
+                            checked = true;
+                        }
+                        RxChecksumState::Unknown => {}
+                    }
+                }
+            }
+            if !checked {
+                status |= RXD_STAT_IXSM;
+            }
+            let mut vlan = 0;
+            if let Some(tag) = meta.vlan.filter(|_| vlan_enabled) {
+                status |= RXD_STAT_VP;
+                vlan = (tag.priority() as u16) << 13
+                    | (tag.drop_eligible_indicator() as u16) << 12
+                    | tag.vlan_id();
+            }
+
+            let gpa = Self::descriptor_gpa(self.rx.base, index);
+            let r = if self.rx.extended {
+                let mut desc = [0; 16];
+                desc[8..12].copy_from_slice(&(status | (errors as u32) << 24).to_le_bytes());
+                desc[12..14].copy_from_slice(&(len as u16).to_le_bytes());
+                desc[14..16].copy_from_slice(&vlan.to_le_bytes());
+                self.buffers.gm.write_at(gpa, &desc)
+            } else {
+                let mut desc = [0; 8];
+                desc[0..2].copy_from_slice(&(len as u16).to_le_bytes());
+                desc[4] = status as u8;
+                desc[5] = errors;
+                desc[6..8].copy_from_slice(&vlan.to_le_bytes());
+                self.buffers.gm.write_at(gpa + 8, &desc)
+            };
+            if let Err(err) = r {
+                tracelimit::warn_ratelimited!(
+                    error = &err as &dyn std::error::Error,
+                    "failed to write receive descriptor"
+                );
+            }
+
+            self.rx.head = (index + 1) % self.rx.size;
+            count += 1;
+            bytes += len as u64;
+        }
+
+        if count > 0 {
+            regs.set(RDH, self.rx.head);
+            regs.add_stat(GPRC, count);
+            regs.add_stat(TPR, count);
+            regs.add_stat64(GORCL, GORCH, bytes);
+            regs.raise(INT_RXT0 | INT_RXQ0);
+        }
+        Ok(progress || count > 0)
+    }
+
+    /// Fetches transmit descriptors and passes complete packets to the
+    /// endpoint.
+    fn process_tx(&mut self, regs: &mut Registers) -> anyhow::Result<bool> {
+        let vlan_enabled = regs.get(CTRL) & CTRL_VME != 0;
+        let mut progress = false;
+        loop {
+            if !self.tx.unsent.is_empty() {
+                if !self.send_tx()? {
+                    break;
+                }
+                progress = true;
+                continue;
+            }
+            if let Some(packet) = self.tx.blocked.take() {
+                if self.tx.bounce_busy {
+                    self.tx.blocked = Some(packet);
+                    break;
+                }
+                self.build_tx(packet);
+                progress = true;
+                continue;
+            }
+            let tail = regs.get(TDT);
+            if tail >= self.tx.size || self.tx.next == tail {
+                break;
+            }
+            let index = self.tx.next;
+            let desc = self
+                .buffers
+                .gm
+                .read_plain::<[u8; 16]>(Self::descriptor_gpa(self.tx.base, index))
+                .context("failed to read transmit descriptor")?;
+            self.tx.next = (index + 1) % self.tx.size;
+            progress = true;
+            self.handle_tx_descriptor(index, &desc, vlan_enabled);
+        }
+        Ok(progress)
+    }
+
+    fn handle_tx_descriptor(&mut self, index: u32, desc: &[u8; 16], vlan_enabled: bool) {
+        let addr = u64::from_le_bytes(desc[..8].try_into().unwrap());
+        let cmd = desc[11];
+        let special = u16::from_le_bytes([desc[14], desc[15]]);
+        if cmd & TXD_CMD_DEXT == 0 {
+            let len = u16::from_le_bytes([desc[8], desc[9]]) as u32;
+            let offload = (cmd & TXD_CMD_IC != 0).then_some(PacketOffload::Legacy {
+                css: desc[13],
+                cso: desc[10],
+            });
+            self.add_tx_data(index, addr, len, cmd, offload, special, vlan_enabled);
+            return;
+        }
+        match desc[10] >> 4 {
+            TXD_DTYP_CONTEXT => {
+                self.tx.context = TxContext {
+                    ipcss: desc[0],
+                    ipcso: desc[1],
+                    ipcse: u16::from_le_bytes([desc[2], desc[3]]),
+                    tucss: desc[4],
+                    tucso: desc[5],
+                    tucse: u16::from_le_bytes([desc[6], desc[7]]),
+                    tucmd: cmd,
+                    hdrlen: desc[13],
+                    mss: special,
+                };
+                if cmd & TXD_CMD_RS != 0 {
+                    if self.tx.packet.first.is_some() {
+                        self.tx.packet.report.push(index);
+                    } else {
+                        self.tx.inflight.push_back(TxPacket {
+                            id: index,
+                            end: (index + 1) % self.tx.size,
+                            report: vec![index],
+                            len: 0,
+                            outstanding: 0,
+                            bounce: false,
+                        });
+                    }
+                }
+            }
+            TXD_DTYP_DATA => {
+                let len = u32::from_le_bytes(desc[8..12].try_into().unwrap()) & 0xfffff;
+                let offload = PacketOffload::Extended {
+                    popts: desc[13],
+                    tse: cmd & TXD_CMD_TSE != 0,
+                };
+                self.add_tx_data(index, addr, len, cmd, Some(offload), special, vlan_enabled);
+            }
+            dtyp => {
+                tracelimit::warn_ratelimited!(dtyp, "unsupported transmit descriptor type");
+                // Treat it as an empty data descriptor so that the ring
+                // still makes progress.
+                self.add_tx_data(index, 0, 0, cmd, None, 0, false);
+            }
+        }
+    }
+
+    #[expect(clippy::too_many_arguments)]
+    fn add_tx_data(
+        &mut self,
+        index: u32,
+        addr: u64,
+        len: u32,
+        cmd: u8,
+        offload: Option<PacketOffload>,
+        special: u16,
+        vlan_enabled: bool,
+    ) {
+        let packet = &mut self.tx.packet;
+        if packet.first.is_none() {
+            packet.first = Some(index);
+            packet.offload = offload;
+        }
+        if len > 0 {
+            packet.segments.push((addr, len));
+            packet.len += len as usize;
+        }
+        if cmd & TXD_CMD_RS != 0 {
+            packet.report.push(index);
+        }
+        if cmd & TXD_CMD_EOP != 0 {
+            packet.end = (index + 1) % self.tx.size;
+            packet.vlan = (vlan_enabled && cmd & TXD_CMD_VLE != 0).then_some(special);
+            let packet = std::mem::take(packet);
+            if packet.offload.is_some() && self.tx.bounce_busy {
+                // The packet may need the bounce buffer; wait for it.
+                self.tx.blocked = Some(packet);
+            } else {
+                self.build_tx(packet);
+            }
+        }
+    }
+
+    /// Builds the endpoint segments for a fetched packet, computing offloads
+    /// in software if the endpoint does not support them.
+    fn build_tx(&mut self, packet: PacketBuilder) {
+        let id = packet.first.unwrap();
+        let mut entry = TxPacket {
+            id,
+            end: packet.end,
+            report: packet.report,
+            len: packet.len,
+            outstanding: 0,
+            bounce: false,
+        };
+        let vlan = packet.vlan.map(|tci| {
+            VlanMetadata::new()
+                .with_priority((tci >> 13) as u8)
+                .with_drop_eligible_indicator(tci & (1 << 12) != 0)
+                .with_vlan_id(tci & 0xfff)
+        });
+
+        let mut software = SoftwareOffload::default();
+        let mut meta = TxMetadata {
+            id: TxId(id),
+            len: packet.len as u32,
+            vlan,
+            ..Default::default()
+        };
+        match packet.offload {
+            None => {}
+            Some(PacketOffload::Legacy { css, cso }) => {
+                software.l4 = Some(Checksum {
+                    start: css as usize,
+                    offset: cso as usize,
+                    end: None,
+                    zero_as_ones: false,
+                });
+            }
+            Some(PacketOffload::Extended { popts, tse }) => {
+                let ctx = self.tx.context;
+                let ipv4 = ctx.tucmd & TXD_TUCMD_IP != 0;
+                let tcp = ctx.tucmd & TXD_TUCMD_TCP != 0;
+                let ip_csum = popts & TXD_POPTS_IXSM != 0 && ipv4;
+                let l4_csum = popts & TXD_POPTS_TXSM != 0 || tse;
+                let offloads = &self.offloads;
+                let l4_offset = if tcp { 16 } else { 6 };
+                let standard = ctx.tucss > ctx.ipcss
+                    && ctx.tucso as usize == ctx.tucss as usize + l4_offset
+                    && ctx.tucse == 0
+                    && (!ip_csum || ctx.ipcso == ctx.ipcss.wrapping_add(10));
+                let supported = (!ip_csum || offloads.ipv4_header)
+                    && (!l4_csum || if tcp { offloads.tcp } else { offloads.udp })
+                    && (!tse || (tcp && offloads.tso && ctx.hdrlen > ctx.tucss && ctx.mss > 0));
+                if (ip_csum || l4_csum) && standard && supported {
+                    meta.flags = meta
+                        .flags
+                        .with_is_ipv4(ipv4)
+                        .with_is_ipv6(!ipv4)
+                        .with_offload_ip_header_checksum(ip_csum)
+                        .with_offload_tcp_checksum(l4_csum && tcp)
+                        .with_offload_udp_checksum(l4_csum && !tcp)
+                        .with_offload_tcp_segmentation(tse);
+                    meta.l2_len = ctx.ipcss;
+                    meta.l3_len = (ctx.tucss - ctx.ipcss) as u16;
+                    meta.transport_header_offset = ctx.tucss as u16;
+                    if tse {
+                        meta.l4_len = ctx.hdrlen - ctx.tucss;
+                        meta.max_segment_size = ctx.mss;
+                    }
+                } else {
+                    if tse && !tcp {
+                        tracelimit::warn_ratelimited!("UDP segmentation is not supported");
+                        self.tx.inflight.push_back(entry);
+                        return;
+                    }
+                    software.ip = ip_csum.then_some(Checksum {
+                        start: ctx.ipcss as usize,
+                        offset: ctx.ipcso as usize,
+                        end: (ctx.ipcse != 0).then_some(ctx.ipcse as usize + 1),
+                        zero_as_ones: false,
+                    });
+                    software.l4 = l4_csum.then_some(Checksum {
+                        start: ctx.tucss as usize,
+                        offset: ctx.tucso as usize,
+                        end: (ctx.tucse != 0).then_some(ctx.tucse as usize + 1),
+                        zero_as_ones: !tcp,
+                    });
+                    software.tso = tse.then_some(Segmentation {
+                        ipv4,
+                        ip_start: ctx.ipcss as usize,
+                        tcp_start: ctx.tucss as usize,
+                        header_len: ctx.hdrlen as usize,
+                        mss: ctx.mss as usize,
+                    });
+                }
+            }
+        }
+
+        if packet.len == 0 {
+            self.tx.inflight.push_back(entry);
+            return;
+        }
+
+        if software.is_empty() && packet.segments.len() <= MAX_SEGMENTS {
+            meta.segment_count = packet.segments.len() as u8;
+            let mut ty = TxSegmentType::Head(meta);
+            self.tx.unsent = packet
+                .segments
+                .iter()
+                .map(|&(gpa, len)| TxSegment {
+                    ty: std::mem::replace(&mut ty, TxSegmentType::Tail),
+                    gpa,
+                    len,
+                })
+                .collect();
+            self.tx.unsent_bounce = false;
+            entry.outstanding = 1;
+            self.tx.inflight.push_back(entry);
+            return;
+        }
+
+        match self.bounce_tx(&packet.segments, packet.len, &software, id, vlan) {
+            Ok(segments) => {
+                entry.outstanding = segments.len();
+                entry.bounce = true;
+                self.tx.unsent = segments;
+                self.tx.unsent_bounce = true;
+                self.tx.bounce_busy = true;
+            }
+            Err(err) => {
+                tracelimit::warn_ratelimited!(
+                    error = err.as_ref() as &dyn std::error::Error,
+                    "dropping transmit packet"
+                );
+            }
+        }
+        self.tx.inflight.push_back(entry);
+    }
+
+    /// Copies a packet into the bounce buffer, applying software offloads.
+    fn bounce_tx(
+        &mut self,
+        segments: &[(u64, u32)],
+        len: usize,
+        software: &SoftwareOffload,
+        id: u32,
+        vlan: Option<VlanMetadata>,
+    ) -> anyhow::Result<Vec<TxSegment>> {
+        anyhow::ensure!(len <= MAX_SOFTWARE_PACKET, "packet too large: {len} bytes");
+        let mut data = vec![0; len];
+        let mut offset = 0;
+        for &(gpa, len) in segments {
+            self.buffers
+                .gm
+                .read_at(gpa, &mut data[offset..offset + len as usize])
+                .context("failed to read transmit buffer")?;
+            offset += len as usize;
+        }
+
+        let mut frames = Vec::new();
+        if let Some(tso) = &software.tso {
+            let ok = tso.segment(&data, software.ip, software.l4, |frame| {
+                frames.push(frame.to_vec())
+            });
+            anyhow::ensure!(ok, "invalid segmentation parameters");
+        } else {
+            if let Some(ip) = &software.ip {
+                anyhow::ensure!(ip.insert(&mut data), "invalid IP checksum parameters");
+            }
+            if let Some(l4) = &software.l4 {
+                anyhow::ensure!(l4.insert(&mut data), "invalid checksum parameters");
+            }
+            frames.push(data);
+        }
+
+        let total = frames.iter().map(Vec::len).sum::<usize>();
+        if total > self.buffers.bounce_len {
+            self.buffers.bounce = GuestMemory::allocate(total);
+            self.buffers.bounce_len = total;
+        }
+        let mut gpa = 0;
+        let mut segments = Vec::with_capacity(frames.len());
+        for frame in frames {
+            self.buffers.bounce.write_at(gpa, &frame)?;
+            segments.push(TxSegment {
+                ty: TxSegmentType::Head(TxMetadata {
+                    id: TxId(id),
+                    segment_count: 1,
+                    len: frame.len() as u32,
+                    vlan,
+                    ..Default::default()
+                }),
+                gpa,
+                len: frame.len() as u32,
+            });
+            gpa += frame.len() as u64;
+        }
+        Ok(segments)
+    }
+
+    /// Passes the unsent segments to the endpoint.
+    ///
+    /// Returns false if the endpoint did not accept all of them.
+    fn send_tx(&mut self) -> anyhow::Result<bool> {
+        let queue = self.queue.as_mut().unwrap();
+        let (sync, n) = if self.tx.unsent_bounce {
+            queue.tx_avail(&mut BounceBuffers(&mut self.buffers), &self.tx.unsent)?
+        } else {
+            queue.tx_avail(&mut self.buffers, &self.tx.unsent)?
+        };
+        let frames = self.tx.unsent[..n]
+            .iter()
+            .filter(|segment| matches!(segment.ty, TxSegmentType::Head(_)))
+            .count();
+        self.tx.unsent.drain(..n);
+        if sync {
+            let packet = self.tx.inflight.back_mut().unwrap();
+            packet.outstanding -= frames;
+        }
+        Ok(self.tx.unsent.is_empty())
+    }
+
+    /// Retires completed packets, in ring order.
+    fn complete_tx(&mut self, regs: &mut Registers) -> anyhow::Result<bool> {
+        let queue = self.queue.as_mut().unwrap();
+        let mut ids = [TxId(0); POLL_BATCH];
+        let mut progress = false;
+        loop {
+            let n = queue.tx_poll(&mut self.buffers, &mut ids)?;
+            for id in &ids[..n] {
+                if let Some(packet) = self
+                    .tx
+                    .inflight
+                    .iter_mut()
+                    .find(|packet| packet.id == id.0 && packet.outstanding > 0)
+                {
+                    packet.outstanding -= 1;
+                }
+            }
+            progress |= n > 0;
+            if n < ids.len() {
+                break;
+            }
+        }
+
+        let mut count = 0;
+        let mut bytes = 0;
+        let mut report = false;
+        while self
+            .tx
+            .inflight
+            .front()
+            .is_some_and(|packet| packet.outstanding == 0)
+        {
+            let packet = self.tx.inflight.pop_front().unwrap();
+            for &index in &packet.report {
+                let gpa = Self::descriptor_gpa(self.tx.base, index) + 12;
+                if let Err(err) = self.buffers.gm.write_plain(gpa, &TXD_STAT_DD) {
+                    tracelimit::warn_ratelimited!(
+                        error = &err as &dyn std::error::Error,
+                        "failed to write transmit descriptor"
+                    );
+                }
+            }
+            report |= !packet.report.is_empty();
+            if packet.bounce {
+                self.tx.bounce_busy = false;
+            }
+            if packet.len > 0 {
+                count += 1;
+                bytes += packet.len as u64;
+            }
+            self.tx.head = packet.end;
+            progress = true;
+        }
+
+        if progress {
+            regs.set(TDH, self.tx.head);
+            regs.add_stat(GPTC, count);
+            regs.add_stat(TPT, count);
+            regs.add_stat64(GOTCL, GOTCH, bytes);
+            if report {
+                regs.raise(INT_TXDW | INT_TXQ0);
+            }
+        }
+        Ok(progress)
+    }
+}
+
+/// The offloads to perform in software for a packet.
+#[derive(Default)]
+struct SoftwareOffload {
+    ip: Option<Checksum>,
+    l4: Option<Checksum>,
+    tso: Option<Segmentation>,
+}
+
+impl SoftwareOffload {
+    fn is_empty(&self) -> bool {
+        self.ip.is_none() && self.l4.is_none() && self.tso.is_none()
+    }
+}
@@ -0,0 +1,26 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource definitions for the emulated Intel 82574 (e1000e) NIC.
+
+#![forbid(unsafe_code)]
+
+use mesh::MeshPayload;
+use net_backend_resources::mac_address::MacAddress;
+use vm_resource::Resource;
+use vm_resource::ResourceId;
+use vm_resource::kind::NetEndpointHandleKind;
+use vm_resource::kind::PciDeviceHandleKind;
+
+/// A resource handle to an e1000e NIC.
+#[derive(MeshPayload, Debug)]
+pub struct E1000eDeviceHandle {
+    /// The NIC's MAC address, reported to the guest through the NVM.
+    pub mac_address: MacAddress,
+    /// The backend network endpoint.
+    pub endpoint: Resource<NetEndpointHandleKind>,
+}
+
+impl ResourceId<PciDeviceHandleKind> for E1000eDeviceHandle {
+    const ID: &'static str = "e1000e";
+}
@@ -1,16 +1,19 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-// C API bingings based on /usr/include/linux/if.h and
-// /usr/include/linux/if_tun.h.
+// C API bingings based on /usr/include/linux/if.h,
+// /usr/include/linux/if_tun.h, and /usr/include/linux/vhost.h.
 
 #![expect(missing_docs)]
 #![cfg(unix)]
 // UNSAFETY: bindgen generated code.
 #![expect(unsafe_code)]
 
+use nix::ioctl_none;
+use nix::ioctl_read;
 use nix::ioctl_read_bad;
 use nix::ioctl_write_int_bad;
+use nix::ioctl_write_ptr;
 use nix::ioctl_write_ptr_bad;
 use nix::request_code_read;
 use nix::request_code_write;
@@ -72,3 +75,90 @@
     request_code_write!(b'T', 216, size_of::<c_int>()),
     c_int
 );
+
+// Definitions from /usr/include/linux/vhost.h and
+// /usr/include/linux/vhost_types.h.
+
+pub const VHOST_VIRTIO: u8 = 0xaf;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+#[expect(non_camel_case_types)]
+pub struct vhost_vring_state {
+    pub index: u32,
+    pub num: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+#[expect(non_camel_case_types)]
+pub struct vhost_vring_file {
+    pub index: u32,
+    pub fd: c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+#[expect(non_camel_case_types)]
+pub struct vhost_vring_addr {
+    pub index: u32,
+    pub flags: u32,
+    pub desc_user_addr: u64,
+    pub used_user_addr: u64,
+    pub avail_user_addr: u64,
+    pub log_guest_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+#[expect(non_camel_case_types)]
+pub struct vhost_memory_region {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub flags_padding: u64,
+}
+
+/// The header of the `VHOST_SET_MEM_TABLE` argument, which is followed by
+/// `nregions` instances of [`vhost_memory_region`].
+#[repr(C)]
+#[derive(Debug, Default)]
+#[expect(non_camel_case_types)]
+pub struct vhost_memory {
+    pub nregions: u32,
+    pub padding: u32,
+    pub regions: [vhost_memory_region; 0],
+}
+
+// #define VHOST_GET_FEATURES _IOR(VHOST_VIRTIO, 0x00, __u64)
+ioctl_read!(vhost_get_features, VHOST_VIRTIO, 0x00, u64);
+
+// #define VHOST_SET_FEATURES _IOW(VHOST_VIRTIO, 0x00, __u64)
+ioctl_write_ptr!(vhost_set_features, VHOST_VIRTIO, 0x00, u64);
+
+// #define VHOST_SET_OWNER _IO(VHOST_VIRTIO, 0x01)
+ioctl_none!(vhost_set_owner, VHOST_VIRTIO, 0x01);
+
+// #define VHOST_RESET_OWNER _IO(VHOST_VIRTIO, 0x02)
+ioctl_none!(vhost_reset_owner, VHOST_VIRTIO, 0x02);
+
+// #define VHOST_SET_MEM_TABLE _IOW(VHOST_VIRTIO, 0x03, struct vhost_memory)
+ioctl_write_ptr!(vhost_set_mem_table, VHOST_VIRTIO, 0x03, vhost_memory);
+
+// #define VHOST_SET_VRING_NUM _IOW(VHOST_VIRTIO, 0x10, struct vhost_vring_state)
+ioctl_write_ptr!(vhost_set_vring_num, VHOST_VIRTIO, 0x10, vhost_vring_state);
+
+// #define VHOST_SET_VRING_ADDR _IOW(VHOST_VIRTIO, 0x11, struct vhost_vring_addr)
+ioctl_write_ptr!(vhost_set_vring_addr, VHOST_VIRTIO, 0x11, vhost_vring_addr);
+
+// #define VHOST_SET_VRING_BASE _IOW(VHOST_VIRTIO, 0x12, struct vhost_vring_state)
+ioctl_write_ptr!(vhost_set_vring_base, VHOST_VIRTIO, 0x12, vhost_vring_state);
+
+// #define VHOST_SET_VRING_KICK _IOW(VHOST_VIRTIO, 0x20, struct vhost_vring_file)
+ioctl_write_ptr!(vhost_set_vring_kick, VHOST_VIRTIO, 0x20, vhost_vring_file);
+
+// #define VHOST_SET_VRING_CALL _IOW(VHOST_VIRTIO, 0x21, struct vhost_vring_file)
+ioctl_write_ptr!(vhost_set_vring_call, VHOST_VIRTIO, 0x21, vhost_vring_file);
+
+// #define VHOST_NET_SET_BACKEND _IOW(VHOST_VIRTIO, 0x30, struct vhost_vring_file)
+ioctl_write_ptr!(vhost_net_set_backend, VHOST_VIRTIO, 0x30, vhost_vring_file);
@@ -41,7 +41,7 @@ pub enum HostPortProtocol {
     }
 
     /// An IP address, suitable for serialization via mesh.
-    #[derive(Clone, Debug, MeshPayload)]
+    #[derive(Clone, Debug, PartialEq, Eq, MeshPayload)]
     pub enum HostIpAddress {
         /// IPv4 address.
         Ipv4(std::net::Ipv4Addr),
@@ -90,6 +90,100 @@ pub struct HostPortConfig {
         pub guest_port: u16,
     }
 
+    /// The action to take on guest traffic matching an egress rule.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, MeshPayload)]
+    pub enum EgressAction {
+        /// Forward the traffic to the host network.
+        #[default]
+        Allow,
+        /// Drop the traffic.
+        Deny,
+    }
+
+    /// A protocol to match in an egress rule.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, MeshPayload)]
+    pub enum EgressProtocol {
+        /// TCP connections.
+        Tcp,
+        /// UDP datagrams.
+        Udp,
+        /// ICMP echo requests.
+        Icmp,
+    }
+
+    /// A rule matching guest traffic by destination.
+    #[derive(Clone, Debug, PartialEq, Eq, MeshPayload)]
+    pub struct EgressRule {
+        /// The action to take on matching traffic.
+        pub action: EgressAction,
+        /// The protocol to match, or `None` for all protocols.
+        pub protocol: Option<EgressProtocol>,
+        /// The destination network address.
+        pub address: HostIpAddress,
+        /// The destination network prefix length.
+        pub prefix_len: u8,
+        /// The inclusive range of destination ports to match, or `None` for
+        /// all ports.
+        pub ports: Option<(u16, u16)>,
+    }
+
+    /// A policy for guest traffic to the host network. The first matching
+    /// rule applies; traffic matching no rule gets the default action.
+    #[derive(Clone, Debug, Default, PartialEq, Eq, MeshPayload)]
+    pub struct EgressPolicy {
+        /// The action for traffic matching no rule.
+        pub default: EgressAction,
+        /// The rules, in evaluation order.
+        pub rules: Vec<EgressRule>,
+    }
+
+    /// The host endpoint of a guest forward.
+    #[derive(Clone, Debug, PartialEq, Eq, MeshPayload)]
+    pub enum GuestForwardTarget {
+        /// A TCP address.
+        Tcp {
+            /// The host address.
+            address: HostIpAddress,
+            /// The host port.
+            port: u16,
+        },
+        /// The path of a Unix stream socket.
+        Unix(String),
+    }
+
+    /// Configuration for a virtual TCP service in the guest subnet, forwarded
+    /// to a host endpoint regardless of the egress policy.
+    #[derive(Clone, Debug, PartialEq, Eq, MeshPayload)]
+    pub struct GuestForwardConfig {
+        /// The virtual address the guest connects to.
+        pub guest_address: std::net::Ipv4Addr,
+        /// The port the guest connects to.
+        pub guest_port: u16,
+        /// The host endpoint to connect to.
+        pub target: GuestForwardTarget,
+    }
+
+    /// The data of a static DNS record.
+    #[derive(Clone, Debug, PartialEq, Eq, MeshPayload)]
+    pub enum DnsRecordData {
+        /// An IPv4 address.
+        A(std::net::Ipv4Addr),
+        /// An IPv6 address.
+        Aaaa(std::net::Ipv6Addr),
+        /// An alias for another name.
+        Cname(String),
+    }
+
+    /// A static DNS record, answered by Consomme without consulting the host
+    /// resolver.
+    #[derive(Clone, Debug, PartialEq, Eq, MeshPayload)]
+    pub struct DnsRecord {
+        /// The owner name.
+        pub name: String,
+        /// The record data.
+        pub data: DnsRecordData,
+    }
+
     /// A runtime request to bind or unbind a port on a running Consomme endpoint.
     #[derive(MeshPayload)]
     pub enum ConsommeRequest {
@@ -108,6 +202,15 @@ pub struct ConsommeHandle {
         pub ports: Vec<HostPortConfig>,
         /// Optional channel for runtime port bind/unbind after the endpoint starts.
         pub recv: Option<mesh::Receiver<ConsommeRequest>>,
+        /// Policy for guest traffic to the host network.
+        pub egress: EgressPolicy,
+        /// Virtual TCP services in the guest subnet, forwarded to host
+        /// endpoints.
+        pub guest_forwards: Vec<GuestForwardConfig>,
+        /// Static DNS records for guest queries.
+        pub dns_records: Vec<DnsRecord>,
+        /// Whether to log guest DNS queries and responses.
+        pub log_dns_queries: bool,
     }
 
     impl ResourceId<NetEndpointHandleKind> for ConsommeHandle {
@@ -115,6 +218,121 @@ impl ResourceId<NetEndpointHandleKind> for ConsommeHandle {
     }
 }
 
+/// Network impairment wrapper.
+pub mod impair {
+    use inspect::Inspect;
+    use mesh::MeshPayload;
+    use mesh::rpc::FailableRpc;
+    use mesh::rpc::Rpc;
+    use std::time::Duration;
+    use vm_resource::Resource;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::NetEndpointHandleKind;
+
+    /// Impairments applied to packets in one direction.
+    #[derive(Debug, Clone, Default, PartialEq, MeshPayload, Inspect)]
+    pub struct LinkImpairment {
+        /// The fixed delay added to each packet.
+        pub delay: Duration,
+        /// The maximum random variation of the delay, in either direction.
+        pub jitter: Duration,
+        /// The probability, from 0 to 1, that a packet is dropped.
+        pub loss: f64,
+        /// The probability that a packet is delivered twice.
+        pub duplicate: f64,
+        /// The probability that a packet skips the delay, arriving ahead of
+        /// packets sent before it.
+        pub reorder: f64,
+        /// The rate limit in bits per second, or 0 for no limit.
+        pub rate: u64,
+        /// The rate limiter's bucket size in bytes: how much can be sent at
+        /// once after the link has been idle.
+        pub burst: u32,
+    }
+
+    /// Impairment settings for both directions of a network endpoint.
+    #[derive(Debug, Clone, Default, PartialEq, MeshPayload, Inspect)]
+    pub struct ImpairmentConfig {
+        /// Packets sent by the guest.
+        pub tx: LinkImpairment,
+        /// Packets received by the guest.
+        pub rx: LinkImpairment,
+    }
+
+    /// A runtime request to a running impairment endpoint.
+    #[derive(MeshPayload)]
+    pub enum ImpairRequest {
+        /// Get the current settings.
+        Get(Rpc<(), ImpairmentConfig>),
+        /// Replace the current settings.
+        Set(FailableRpc<ImpairmentConfig, ()>),
+    }
+
+    /// Handle to an endpoint that impairs the traffic of another endpoint.
+    #[derive(MeshPayload)]
+    pub struct ImpairHandle {
+        /// The wrapped endpoint.
+        pub endpoint: Resource<NetEndpointHandleKind>,
+        /// The initial settings.
+        pub config: ImpairmentConfig,
+        /// The random seed, for reproducible runs. If `None`, a random seed
+        /// is used.
+        pub seed: Option<u64>,
+        /// Optional channel for changing the settings at runtime.
+        pub recv: Option<mesh::Receiver<ImpairRequest>>,
+    }
+
+    impl ResourceId<NetEndpointHandleKind> for ImpairHandle {
+        const ID: &'static str = "impair";
+    }
+}
+
+/// Packet capture wrapper endpoint.
+pub mod packet_capture {
+    use mesh::MeshPayload;
+    use mesh::rpc::FailableRpc;
+    use vm_resource::Resource;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::NetEndpointHandleKind;
+
+    /// Parameters for starting a packet capture.
+    #[derive(MeshPayload)]
+    pub struct StartCapture {
+        /// The maximum number of bytes captured from each packet.
+        pub snaplen: u32,
+        /// An optional filter expression, in a subset of the `pcap-filter`
+        /// syntax, selecting the packets to capture.
+        pub filter: Option<String>,
+        /// Receives the capture as a `pcapng` stream.
+        pub writer: std::fs::File,
+    }
+
+    /// A runtime request to a packet capture endpoint.
+    #[derive(MeshPayload)]
+    pub enum PacketCaptureRequest {
+        /// Start capturing, replacing any capture in progress.
+        Start(FailableRpc<StartCapture, ()>),
+        /// Stop capturing, closing the writer.
+        Stop(FailableRpc<(), ()>),
+    }
+
+    /// Handle to an endpoint that can capture the traffic of another
+    /// endpoint.
+    #[derive(MeshPayload)]
+    pub struct PacketCaptureHandle {
+        /// The name of the endpoint, for tracing.
+        pub name: String,
+        /// The wrapped endpoint.
+        pub endpoint: Resource<NetEndpointHandleKind>,
+        /// The channel for starting and stopping captures.
+        pub recv: mesh::Receiver<PacketCaptureRequest>,
+    }
+
+    impl ResourceId<NetEndpointHandleKind> for PacketCaptureHandle {
+        const ID: &'static str = "packet_capture";
+    }
+}
+
 /// Windows vmswitch DirectIO backend.
 pub mod dio {
     use guid::Guid;
@@ -161,4 +379,61 @@ pub struct TapHandle {
     impl ResourceId<NetEndpointHandleKind> for TapHandle {
         const ID: &'static str = "tap";
     }
+
+    /// A handle to a TAP device whose frames are moved by the kernel's
+    /// vhost-net driver.
+    #[derive(MeshPayload)]
+    pub struct TapVhostHandle {
+        /// One entry per queue pair.
+        pub queues: Vec<TapVhostQueue>,
+    }
+
+    /// A queue of a [`TapVhostHandle`].
+    #[derive(MeshPayload)]
+    pub struct TapVhostQueue {
+        /// A pre-opened TAP file descriptor, configured as for [`TapHandle`],
+        /// plus `IFF_MULTI_QUEUE` if there is more than one queue.
+        pub tap: std::os::fd::OwnedFd,
+        /// A pre-opened `/dev/vhost-net` file descriptor.
+        pub vhost: std::os::fd::OwnedFd,
+    }
+
+    impl ResourceId<NetEndpointHandleKind> for TapVhostHandle {
+        const ID: &'static str = "tap_vhost";
+    }
+}
+
+/// Unix socket backend.
+#[cfg(unix)]
+pub mod socket {
+    use mesh::MeshPayload;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::NetEndpointHandleKind;
+
+    /// How Ethernet frames are carried on the socket.
+    #[derive(Debug, MeshPayload)]
+    pub enum SocketFraming {
+        /// A connected stream socket, with each frame preceded by its 32-bit
+        /// big-endian length.
+        Stream,
+        /// A bound datagram socket, with one frame per datagram.
+        Datagram {
+            /// The path of the Unix socket to send frames to. If `None`, the
+            /// socket must be connected.
+            remote: Option<String>,
+        },
+    }
+
+    /// A handle to a Unix socket carrying Ethernet frames.
+    #[derive(MeshPayload)]
+    pub struct SocketHandle {
+        /// The socket.
+        pub socket: std::os::fd::OwnedFd,
+        /// How frames are carried on the socket.
+        pub framing: SocketFraming,
+    }
+
+    impl ResourceId<NetEndpointHandleKind> for SocketHandle {
+        const ID: &'static str = "socket";
+    }
 }
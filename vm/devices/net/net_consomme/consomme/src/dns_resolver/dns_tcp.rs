@@ -200,6 +200,7 @@ pub fn poll_read<B: DnsBackend>(
             Phase::InFlight => match ready!(self.receiver.poll_recv(cx)) {
                 Ok(response) => {
                     dns.complete_tcp_query();
+                    dns.log_response(&response);
                     let payload_len = response.response_data.len();
                     tracing::trace!(
                         payload_len,
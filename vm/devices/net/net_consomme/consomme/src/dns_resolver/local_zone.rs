@@ -0,0 +1,292 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Static DNS records answered without consulting the host resolver.
+
+use super::message::CLASS_IN;
+use super::message::FLAG_AUTHORITATIVE;
+use super::message::FLAG_RECURSION_AVAILABLE;
+use super::message::FLAG_RECURSION_DESIRED;
+use super::message::FLAG_RESPONSE;
+use super::message::Header;
+use super::message::Question;
+use super::message::TYPE_A;
+use super::message::TYPE_AAAA;
+use super::message::TYPE_CNAME;
+use super::message::write_name;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use thiserror::Error;
+
+/// The TTL of answers from the local zone, in seconds. This is short so that
+/// guests pick up changes quickly across VM restarts.
+const LOCAL_TTL: u32 = 60;
+
+/// The longest CNAME chain followed within the local zone.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The data of a static DNS record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsRecordData {
+    /// An IPv4 address.
+    A(Ipv4Addr),
+    /// An IPv6 address.
+    Aaaa(Ipv6Addr),
+    /// An alias for another name.
+    Cname(String),
+}
+
+impl DnsRecordData {
+    fn rtype(&self) -> u16 {
+        match self {
+            DnsRecordData::A(_) => TYPE_A,
+            DnsRecordData::Aaaa(_) => TYPE_AAAA,
+            DnsRecordData::Cname(_) => TYPE_CNAME,
+        }
+    }
+}
+
+/// A static DNS record.
+///
+/// Queries for a name with static records are answered by Consomme directly,
+/// without consulting the host resolver. If a name has a CNAME record, its
+/// other records are ignored, and the alias is followed only within the
+/// static records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsRecord {
+    /// The owner name, such as `build.test`.
+    pub name: String,
+    /// The record data.
+    pub data: DnsRecordData,
+}
+
+/// An error indicating that a static DNS record is invalid.
+#[derive(Debug, Error)]
+#[error("invalid DNS name '{0}'")]
+pub struct InvalidDnsRecord(String);
+
+impl DnsRecord {
+    /// Validates the record's names.
+    pub fn validate(&self) -> Result<(), InvalidDnsRecord> {
+        validate_name(&self.name)?;
+        if let DnsRecordData::Cname(target) = &self.data {
+            validate_name(target)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.data {
+            DnsRecordData::A(addr) => write!(f, "{} A {addr}", self.name),
+            DnsRecordData::Aaaa(addr) => write!(f, "{} AAAA {addr}", self.name),
+            DnsRecordData::Cname(target) => write!(f, "{} CNAME {target}", self.name),
+        }
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), InvalidDnsRecord> {
+    let trimmed = name.strip_suffix('.').unwrap_or(name);
+    let valid = !trimmed.is_empty()
+        && trimmed.len() <= 253
+        && trimmed.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && label
+                    .bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+        });
+    if !valid {
+        return Err(InvalidDnsRecord(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// Returns `name` in the form returned by
+/// [`read_name`](super::message::read_name).
+fn normalize(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+
+/// The static DNS records, indexed by name.
+#[derive(Default)]
+pub struct LocalZone {
+    names: HashMap<String, Vec<DnsRecordData>>,
+}
+
+impl LocalZone {
+    pub fn new(records: &[DnsRecord]) -> Self {
+        let mut names = HashMap::<_, Vec<_>>::new();
+        for record in records {
+            let data = match &record.data {
+                DnsRecordData::Cname(target) => DnsRecordData::Cname(normalize(target)),
+                data => data.clone(),
+            };
+            names.entry(normalize(&record.name)).or_default().push(data);
+        }
+        Self { names }
+    }
+
+    /// Returns the response to `query`, if it asks about a name in the zone.
+    ///
+    /// Names in the zone get an authoritative answer even when they have no
+    /// records of the requested type, so that the host resolver is never
+    /// consulted for them.
+    pub fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        if self.names.is_empty() {
+            return None;
+        }
+        let header = Header::parse(query)?;
+        if header.flags & FLAG_RESPONSE != 0 || header.opcode() != 0 || header.qdcount != 1 {
+            return None;
+        }
+        let question = Question::parse(query)?;
+        if question.qclass != CLASS_IN {
+            return None;
+        }
+
+        let mut name = question.name;
+        let mut records = self.names.get(&name)?;
+        let mut answers = Vec::new();
+        for _ in 0..MAX_CNAME_CHAIN {
+            if question.qtype != TYPE_CNAME
+                && let Some((cname, target)) = records.iter().find_map(|data| match data {
+                    DnsRecordData::Cname(target) => Some((data, target)),
+                    _ => None,
+                })
+            {
+                answers.push((name, cname));
+                name = target.clone();
+                match self.names.get(&name) {
+                    Some(target_records) => {
+                        records = target_records;
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            answers.extend(
+                records
+                    .iter()
+                    .filter(|data| data.rtype() == question.qtype)
+                    .map(|data| (name.clone(), data)),
+            );
+            break;
+        }
+
+        let flags = FLAG_RESPONSE
+            | FLAG_AUTHORITATIVE
+            | FLAG_RECURSION_AVAILABLE
+            | (header.flags & FLAG_RECURSION_DESIRED);
+        let mut response = Vec::with_capacity(512);
+        response.extend_from_slice(&header.id.to_be_bytes());
+        response.extend_from_slice(&flags.to_be_bytes());
+        response.extend_from_slice(&1u16.to_be_bytes());
+        response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        response.extend_from_slice(&[0, 0, 0, 0]);
+        response.extend_from_slice(&query[super::DNS_HEADER_SIZE..question.end]);
+        for (owner, data) in answers {
+            write_name(&mut response, &owner);
+            response.extend_from_slice(&data.rtype().to_be_bytes());
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&LOCAL_TTL.to_be_bytes());
+            let rdata = match data {
+                DnsRecordData::A(addr) => addr.octets().to_vec(),
+                DnsRecordData::Aaaa(addr) => addr.octets().to_vec(),
+                DnsRecordData::Cname(target) => {
+                    let mut rdata = Vec::new();
+                    write_name(&mut rdata, target);
+                    rdata
+                }
+            };
+            response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            response.extend_from_slice(&rdata);
+        }
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_resolver::message::Summary;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        write_name(&mut query, name);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    fn record(name: &str, data: DnsRecordData) -> DnsRecord {
+        DnsRecord {
+            name: name.into(),
+            data,
+        }
+    }
+
+    fn zone() -> LocalZone {
+        LocalZone::new(&[
+            record("Build.Test.", DnsRecordData::A(Ipv4Addr::new(10, 0, 0, 50))),
+            record("build.test", DnsRecordData::A(Ipv4Addr::new(10, 0, 0, 51))),
+            record("v6.test", DnsRecordData::Aaaa(Ipv6Addr::LOCALHOST)),
+            record("alias.test", DnsRecordData::Cname("build.test".into())),
+            record("external.test", DnsRecordData::Cname("example.com".into())),
+            record("loop.test", DnsRecordData::Cname("loop.test".into())),
+        ])
+    }
+
+    fn answer(zone: &LocalZone, name: &str, qtype: u16) -> Option<Summary> {
+        let response = zone.answer(&query(name, qtype))?;
+        let summary = Summary::parse(&response).unwrap();
+        assert_eq!(summary.id, 0x1234);
+        assert_eq!(summary.rcode, 0);
+        Some(summary)
+    }
+
+    #[test]
+    fn addresses() {
+        let zone = zone();
+        let summary = answer(&zone, "BUILD.test", TYPE_A).unwrap();
+        assert_eq!(summary.answers, ["A 10.0.0.50", "A 10.0.0.51"]);
+        let summary = answer(&zone, "v6.test", TYPE_AAAA).unwrap();
+        assert_eq!(summary.answers, ["AAAA ::1"]);
+        // Names in the zone get an empty answer for other types.
+        let summary = answer(&zone, "v6.test", TYPE_A).unwrap();
+        assert!(summary.answers.is_empty());
+        // Other names go to the host resolver.
+        assert!(answer(&zone, "example.com", TYPE_A).is_none());
+        assert!(answer(&zone, "sub.build.test", TYPE_A).is_none());
+    }
+
+    #[test]
+    fn aliases() {
+        let zone = zone();
+        let summary = answer(&zone, "alias.test", TYPE_A).unwrap();
+        assert_eq!(
+            summary.answers,
+            ["CNAME build.test", "A 10.0.0.50", "A 10.0.0.51"]
+        );
+        let summary = answer(&zone, "alias.test", TYPE_CNAME).unwrap();
+        assert_eq!(summary.answers, ["CNAME build.test"]);
+        let summary = answer(&zone, "external.test", TYPE_A).unwrap();
+        assert_eq!(summary.answers, ["CNAME example.com"]);
+        let summary = answer(&zone, "loop.test", TYPE_A).unwrap();
+        assert_eq!(summary.answers.len(), MAX_CNAME_CHAIN);
+    }
+
+    #[test]
+    fn validate() {
+        let valid = record("a-b_c.test.", DnsRecordData::Cname("x.test".into()));
+        assert!(valid.validate().is_ok());
+        for name in ["", ".", "a..test", "bad name.test", "a".repeat(64).as_str()] {
+            let invalid = record(name, DnsRecordData::A(Ipv4Addr::LOCALHOST));
+            assert!(invalid.validate().is_err(), "{name:?}");
+        }
+        let invalid = record("a.test", DnsRecordData::Cname("*.test".into()));
+        assert!(invalid.validate().is_err());
+    }
+}
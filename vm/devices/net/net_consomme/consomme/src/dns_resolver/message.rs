@@ -0,0 +1,263 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Minimal DNS message parsing (RFC 1035 §4), enough to answer queries from
+//! the local zone and to summarize queries and responses for logging.
+
+use super::DNS_HEADER_SIZE;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+/// The QR bit, set in responses.
+pub const FLAG_RESPONSE: u16 = 0x8000;
+/// The AA bit, set in authoritative answers.
+pub const FLAG_AUTHORITATIVE: u16 = 0x0400;
+/// The RD bit, copied from the query into the response.
+pub const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+/// The RA bit.
+pub const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+
+/// The longest name accepted, in presentation form.
+const MAX_NAME_LEN: usize = 255;
+
+/// The most compression pointers followed in one name, to bound the work
+/// done on malicious messages.
+const MAX_POINTERS: usize = 16;
+
+/// The most answer records included in a summary.
+const MAX_SUMMARY_ANSWERS: usize = 16;
+
+/// The fixed-size DNS message header.
+pub struct Header {
+    pub id: u16,
+    pub flags: u16,
+    pub qdcount: u16,
+    pub ancount: u16,
+}
+
+impl Header {
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        let header = msg.get(..DNS_HEADER_SIZE)?;
+        let word = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+        Some(Self {
+            id: word(0),
+            flags: word(2),
+            qdcount: word(4),
+            ancount: word(6),
+        })
+    }
+
+    pub fn opcode(&self) -> u8 {
+        ((self.flags >> 11) & 0xf) as u8
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0xf) as u8
+    }
+}
+
+/// The question of a DNS message.
+pub struct Question {
+    /// The name, lowercase and without a trailing dot.
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+    /// The offset just past the question in the message.
+    pub end: usize,
+}
+
+impl Question {
+    /// Parses the first question of `msg`.
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        let (name, offset) = read_name(msg, DNS_HEADER_SIZE)?;
+        let fixed = msg.get(offset..offset + 4)?;
+        Some(Self {
+            name,
+            qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+            qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+            end: offset + 4,
+        })
+    }
+}
+
+/// Reads the name at `offset` in `msg`, following compression pointers.
+///
+/// Returns the name, lowercase and without a trailing dot (the root is `.`),
+/// and the offset just past the name's encoding at `offset`.
+pub fn read_name(msg: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(offset)? as usize;
+        match len & 0xc0 {
+            0x00 => {
+                offset += 1;
+                if len == 0 {
+                    break;
+                }
+                let label = msg.get(offset..offset + len)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.extend(
+                    label
+                        .escape_ascii()
+                        .map(|c| char::from(c.to_ascii_lowercase())),
+                );
+                if name.len() > MAX_NAME_LEN {
+                    return None;
+                }
+                offset += len;
+            }
+            0xc0 => {
+                let low = *msg.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                offset = ((len & 0x3f) << 8) | low;
+            }
+            _ => return None,
+        }
+    }
+    if name.is_empty() {
+        name.push('.');
+    }
+    Some((name, end.unwrap_or(offset)))
+}
+
+/// Appends the uncompressed encoding of `name`, as returned by
+/// [`read_name`], to `buf`.
+pub fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+/// Returns the mnemonic for a record type.
+pub fn type_name(rtype: u16) -> String {
+    match rtype {
+        TYPE_A => "A".into(),
+        2 => "NS".into(),
+        TYPE_CNAME => "CNAME".into(),
+        6 => "SOA".into(),
+        12 => "PTR".into(),
+        15 => "MX".into(),
+        16 => "TXT".into(),
+        TYPE_AAAA => "AAAA".into(),
+        33 => "SRV".into(),
+        64 => "SVCB".into(),
+        65 => "HTTPS".into(),
+        255 => "ANY".into(),
+        n => format!("TYPE{n}"),
+    }
+}
+
+/// Returns the mnemonic for a response code.
+pub fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => "NOERROR".into(),
+        1 => "FORMERR".into(),
+        2 => "SERVFAIL".into(),
+        3 => "NXDOMAIN".into(),
+        4 => "NOTIMP".into(),
+        5 => "REFUSED".into(),
+        n => format!("RCODE{n}"),
+    }
+}
+
+/// A summary of a DNS message, for logging.
+pub struct Summary {
+    pub id: u16,
+    pub name: String,
+    pub qtype: u16,
+    pub rcode: u8,
+    /// The answer records, in presentation form.
+    pub answers: Vec<String>,
+}
+
+impl Summary {
+    /// Summarizes `msg`, returning `None` if it has no parseable question.
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        let header = Header::parse(msg)?;
+        if header.qdcount == 0 {
+            return None;
+        }
+        let question = Question::parse(msg)?;
+        let mut answers = Vec::new();
+        // Answers follow the question when there is only one, as there is in
+        // practice.
+        if header.qdcount == 1 {
+            let mut offset = question.end;
+            for _ in 0..header.ancount.min(MAX_SUMMARY_ANSWERS as u16) {
+                let Some((answer, next)) = read_answer(msg, offset) else {
+                    break;
+                };
+                answers.push(answer);
+                offset = next;
+            }
+        }
+        Some(Self {
+            id: header.id,
+            name: question.name,
+            qtype: question.qtype,
+            rcode: header.rcode(),
+            answers,
+        })
+    }
+}
+
+/// Reads the resource record at `offset`, returning its data in
+/// presentation form and the offset of the next record.
+fn read_answer(msg: &[u8], offset: usize) -> Option<(String, usize)> {
+    let (_, offset) = read_name(msg, offset)?;
+    let fixed = msg.get(offset..offset + 10)?;
+    let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let rdlen = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+    let rdata_offset = offset + 10;
+    let rdata = msg.get(rdata_offset..rdata_offset + rdlen)?;
+    let data = match (rtype, rdata.len()) {
+        (TYPE_A, 4) => Ipv4Addr::from(<[u8; 4]>::try_from(rdata).unwrap()).to_string(),
+        (TYPE_AAAA, 16) => Ipv6Addr::from(<[u8; 16]>::try_from(rdata).unwrap()).to_string(),
+        (TYPE_CNAME, _) => read_name(msg, rdata_offset)?.0,
+        _ => format!("({rdlen} bytes)"),
+    };
+    Some((format!("{} {data}", type_name(rtype)), rdata_offset + rdlen))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_names() {
+        let mut msg = vec![0; DNS_HEADER_SIZE];
+        // www.Example.com at offset 12.
+        write_name(&mut msg, "www.Example.com");
+        // A pointer to example.com, then a pointer loop.
+        let pointer = msg.len();
+        msg.extend_from_slice(&[0xc0, 16]);
+        let looped = msg.len();
+        msg.extend_from_slice(&[0xc0, looped as u8]);
+
+        assert_eq!(
+            read_name(&msg, DNS_HEADER_SIZE).unwrap(),
+            ("www.example.com".to_string(), pointer)
+        );
+        assert_eq!(
+            read_name(&msg, pointer).unwrap(),
+            ("example.com".to_string(), looped)
+        );
+        assert!(read_name(&msg, looped).is_none());
+        assert!(read_name(&msg[..20], DNS_HEADER_SIZE).is_none());
+    }
+}
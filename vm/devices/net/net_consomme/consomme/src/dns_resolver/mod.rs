@@ -2,17 +2,23 @@
 // Licensed under the MIT License.
 
 use inspect::Inspect;
+use inspect_counters::Counter;
 use mesh_channel_core::Receiver;
 use mesh_channel_core::Sender;
 use smoltcp::wire::EthernetAddress;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 
 use crate::DropReason;
+use local_zone::LocalZone;
+use message::Summary;
 
 pub mod dns_tcp;
+pub mod local_zone;
+mod message;
 
 #[cfg(unix)]
 mod unix;
@@ -41,10 +47,8 @@ pub struct DnsFlow {
     pub dst: SocketAddr,
     pub gateway_mac: EthernetAddress,
     pub client_mac: EthernetAddress,
-    // Used by the glibc and Windows DNS backends. The musl resolver
-    // implementation handles TCP internally, so this field is not
-    // used in the musl backend.
-    #[cfg_attr(target_env = "musl", expect(dead_code))]
+    // Used by the glibc and Windows DNS backends and for query logging. The
+    // musl resolver implementation handles TCP internally.
     pub transport: DnsTransport,
 }
 
@@ -75,6 +79,12 @@ pub(crate) trait DnsBackend: Send + Sync {
 pub struct DnsResolver<B: DnsBackend = PlatformDnsBackend> {
     #[inspect(skip)]
     backend: Arc<B>,
+    #[inspect(skip)]
+    local_zone: LocalZone,
+    /// Queries answered from the local zone.
+    local_answers: Counter,
+    /// The recent queries, if query logging is enabled.
+    query_log: Option<QueryLog>,
     /// Channel receiver for UDP DNS responses. Each call to
     /// [`Self::submit_udp_query`] sends the response back through this
     /// channel so that [`Self::poll_udp_response`] can retrieve it.
@@ -89,55 +99,121 @@ pub struct DnsResolver<B: DnsBackend = PlatformDnsBackend> {
 /// Default maximum number of pending DNS requests.
 pub const DEFAULT_MAX_PENDING_DNS_REQUESTS: usize = 256;
 
+/// The number of recent queries kept for inspection when query logging is
+/// enabled.
+const QUERY_LOG_LEN: usize = 64;
+
+/// The recent guest DNS queries and their responses.
+#[derive(Inspect, Default)]
+struct QueryLog {
+    #[inspect(iter_by_index)]
+    entries: VecDeque<QueryLogEntry>,
+}
+
+#[derive(Inspect)]
+struct QueryLogEntry {
+    #[inspect(display)]
+    client: SocketAddr,
+    #[inspect(skip)]
+    id: u16,
+    name: String,
+    qtype: String,
+    local: bool,
+    rcode: Option<String>,
+    #[inspect(iter_by_index)]
+    answers: Vec<String>,
+}
+
 impl DnsResolver {
     /// Creates a new DNS resolver with a configurable limit on pending requests.
     ///
     /// # Arguments
     /// * `max_pending_requests` - Maximum number of concurrent pending DNS requests.
+    /// * `local_zone` - Static records to answer without the host resolver.
+    /// * `log_queries` - Whether to log guest queries and responses.
     #[cfg(windows)]
-    pub fn new(max_pending_requests: usize) -> Result<Self, std::io::Error> {
+    pub fn new(
+        max_pending_requests: usize,
+        local_zone: LocalZone,
+        log_queries: bool,
+    ) -> Result<Self, std::io::Error> {
         use crate::dns_resolver::windows::WindowsDnsResolverBackend;
 
-        let udp_receiver = Receiver::new();
-        Ok(Self {
-            backend: Arc::new(WindowsDnsResolverBackend::new()?),
-            udp_receiver,
-            pending_requests: 0,
+        Ok(Self::with_backend(
+            Arc::new(WindowsDnsResolverBackend::new()?),
             max_pending_requests,
-            next_query_id: 0,
-        })
+            local_zone,
+            log_queries,
+        ))
     }
 
     /// Creates a new DNS resolver with a configurable limit on pending requests.
     ///
     /// # Arguments
     /// * `max_pending_requests` - Maximum number of concurrent pending DNS requests.
+    /// * `local_zone` - Static records to answer without the host resolver.
+    /// * `log_queries` - Whether to log guest queries and responses.
     #[cfg(unix)]
-    pub fn new(max_pending_requests: usize) -> Result<Self, std::io::Error> {
+    pub fn new(
+        max_pending_requests: usize,
+        local_zone: LocalZone,
+        log_queries: bool,
+    ) -> Result<Self, std::io::Error> {
         use crate::dns_resolver::unix::UnixDnsResolverBackend;
 
-        let udp_receiver = Receiver::new();
-        Ok(Self {
-            backend: Arc::new(UnixDnsResolverBackend::new()?),
-            udp_receiver,
-            pending_requests: 0,
+        Ok(Self::with_backend(
+            Arc::new(UnixDnsResolverBackend::new()?),
             max_pending_requests,
-            next_query_id: 0,
-        })
+            local_zone,
+            log_queries,
+        ))
     }
 }
 
 impl<B: DnsBackend> DnsResolver<B> {
     // ── Shared ───────────────────────────────────────────────────────
 
+    fn with_backend(
+        backend: Arc<B>,
+        max_pending_requests: usize,
+        local_zone: LocalZone,
+        log_queries: bool,
+    ) -> Self {
+        Self {
+            backend,
+            local_zone,
+            local_answers: Counter::new(),
+            query_log: log_queries.then(QueryLog::default),
+            udp_receiver: Receiver::new(),
+            pending_requests: 0,
+            max_pending_requests,
+            next_query_id: 0,
+        }
+    }
+
     /// Submit a DNS query to the backend with a caller-supplied response
     /// sender.  Returns `true` if accepted, `false` if the pending-request
     /// limit has been reached.
+    ///
+    /// Queries for names in the local zone are answered immediately through
+    /// the same sender, so that both transports handle them like any other
+    /// response.
     fn submit_query(
         &mut self,
         request: &DnsRequest<'_>,
         response_sender: Sender<DnsResponse>,
     ) -> bool {
+        if let Some(response_data) = self.local_zone.answer(request.dns_query) {
+            self.local_answers.increment();
+            self.log_query(request, true);
+            self.pending_requests += 1;
+            response_sender.send(DnsResponse {
+                flow: request.flow.clone(),
+                response_data,
+            });
+            return true;
+        }
+        self.log_query(request, false);
         if self.pending_requests < self.max_pending_requests {
             let query_id = self.next_query_id;
             self.next_query_id += 1;
@@ -173,10 +249,12 @@ pub fn submit_udp_query(
         if !self.submit_query(request, sender) {
             // Rate-limited: return a SERVFAIL directly so the caller can
             // emit it immediately without going through the async channel.
-            return Ok(Some(DnsResponse {
+            let response = DnsResponse {
                 flow: request.flow.clone(),
                 response_data: build_servfail_response(request.dns_query),
-            }));
+            };
+            self.log_response(&response);
+            return Ok(Some(response));
         }
         Ok(None)
     }
@@ -189,6 +267,7 @@ pub fn poll_udp_response(&mut self, cx: &mut Context<'_>) -> Poll<Option<DnsResp
         match self.udp_receiver.poll_recv(cx) {
             Poll::Ready(Ok(response)) => {
                 self.pending_requests -= 1;
+                self.log_response(&response);
                 Poll::Ready(Some(response))
             }
             Poll::Ready(Err(_)) | Poll::Pending => Poll::Pending,
@@ -216,17 +295,85 @@ pub fn complete_tcp_query(&mut self) {
         self.pending_requests = self.pending_requests.saturating_sub(1);
     }
 
+    /// Logs a guest query, if query logging is enabled.
+    fn log_query(&mut self, request: &DnsRequest<'_>, local: bool) {
+        let Some(log) = &mut self.query_log else {
+            return;
+        };
+        let client = request.flow.src;
+        let Some(summary) = Summary::parse(request.dns_query) else {
+            tracing::info!(%client, len = request.dns_query.len(), "malformed dns query");
+            return;
+        };
+        let qtype = message::type_name(summary.qtype);
+        tracing::info!(
+            %client,
+            id = summary.id,
+            name = %summary.name,
+            %qtype,
+            local,
+            transport = ?request.flow.transport,
+            "dns query"
+        );
+        if log.entries.len() == QUERY_LOG_LEN {
+            log.entries.pop_front();
+        }
+        log.entries.push_back(QueryLogEntry {
+            client,
+            id: summary.id,
+            name: summary.name,
+            qtype,
+            local,
+            rcode: None,
+            answers: Vec::new(),
+        });
+    }
+
+    /// Logs a response to a guest query, if query logging is enabled.
+    ///
+    /// The TCP handler calls this for responses arriving on its own channel.
+    pub fn log_response(&mut self, response: &DnsResponse) {
+        let Some(log) = &mut self.query_log else {
+            return;
+        };
+        let client = response.flow.src;
+        let Some(summary) = Summary::parse(&response.response_data) else {
+            tracing::info!(
+                %client,
+                len = response.response_data.len(),
+                "malformed dns response"
+            );
+            return;
+        };
+        let rcode = message::rcode_name(summary.rcode);
+        tracing::info!(
+            %client,
+            id = summary.id,
+            name = %summary.name,
+            qtype = %message::type_name(summary.qtype),
+            %rcode,
+            answers = ?summary.answers,
+            "dns response"
+        );
+        if let Some(entry) =
+            log.entries.iter_mut().rev().find(|entry| {
+                entry.client == client && entry.id == summary.id && entry.rcode.is_none()
+            })
+        {
+            entry.rcode = Some(rcode);
+            entry.answers = summary.answers;
+        }
+    }
+
     /// Create a resolver with a test backend (for unit tests only).
     #[cfg(test)]
     pub(crate) fn new_for_test(backend: Arc<B>) -> Self {
-        let udp_receiver = Receiver::new();
-        Self {
+        Self::with_backend(
             backend,
-            udp_receiver,
-            pending_requests: 0,
-            max_pending_requests: DEFAULT_MAX_PENDING_DNS_REQUESTS,
-            next_query_id: 0,
-        }
+            DEFAULT_MAX_PENDING_DNS_REQUESTS,
+            LocalZone::default(),
+            false,
+        )
     }
 }
 
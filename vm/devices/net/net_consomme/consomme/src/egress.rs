@@ -0,0 +1,308 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Egress policy and guest-facing service forwarding.
+
+use inspect::Inspect;
+use std::fmt;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// The action to take on guest traffic matching a rule.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Inspect)]
+pub enum EgressAction {
+    /// Forward the traffic to the host network.
+    Allow,
+    /// Drop the traffic. TCP connections are reset.
+    Deny,
+}
+
+/// A protocol to match in an egress rule.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EgressProtocol {
+    /// TCP connections.
+    Tcp,
+    /// UDP datagrams.
+    Udp,
+    /// ICMP echo requests.
+    Icmp,
+}
+
+/// A rule matching guest traffic by destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EgressRule {
+    /// The action to take on matching traffic.
+    pub action: EgressAction,
+    /// The protocol to match, or `None` for all protocols.
+    pub protocol: Option<EgressProtocol>,
+    /// The destination network address.
+    pub address: IpAddr,
+    /// The destination network prefix length.
+    pub prefix_len: u8,
+    /// The inclusive range of destination ports to match, or `None` for all
+    /// ports. A rule with ports never matches ICMP.
+    pub ports: Option<(u16, u16)>,
+}
+
+/// An error indicating that an egress rule is invalid.
+#[derive(Debug, Error)]
+pub enum InvalidEgressRule {
+    /// The prefix length is too long for the address family.
+    #[error("prefix length {0} is too long")]
+    PrefixLength(u8),
+    /// The port range is empty.
+    #[error("port range {0}-{1} is empty")]
+    PortRange(u16, u16),
+}
+
+impl EgressRule {
+    /// Validates the rule.
+    pub fn validate(&self) -> Result<(), InvalidEgressRule> {
+        let max_prefix = match self.address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if self.prefix_len > max_prefix {
+            return Err(InvalidEgressRule::PrefixLength(self.prefix_len));
+        }
+        if let Some((first, last)) = self.ports
+            && first > last
+        {
+            return Err(InvalidEgressRule::PortRange(first, last));
+        }
+        Ok(())
+    }
+
+    fn matches(&self, protocol: EgressProtocol, dst: &SocketAddr) -> bool {
+        if self.protocol.is_some_and(|p| p != protocol) {
+            return false;
+        }
+        if let Some((first, last)) = self.ports
+            && (protocol == EgressProtocol::Icmp || !(first..=last).contains(&dst.port()))
+        {
+            return false;
+        }
+        match (self.address, dst.ip()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_match(
+                net.to_bits().into(),
+                ip.to_bits().into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_match(net.to_bits(), ip.to_bits(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_match(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    let shift = bits.saturating_sub(prefix_len);
+    shift >= bits || net >> shift == ip >> shift
+}
+
+impl fmt::Display for EgressRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            EgressAction::Allow => "allow",
+            EgressAction::Deny => "deny",
+        };
+        let protocol = match self.protocol {
+            None => "any",
+            Some(EgressProtocol::Tcp) => "tcp",
+            Some(EgressProtocol::Udp) => "udp",
+            Some(EgressProtocol::Icmp) => "icmp",
+        };
+        write!(
+            f,
+            "{action} {protocol} {}/{}",
+            self.address, self.prefix_len
+        )?;
+        match self.ports {
+            Some((first, last)) if first == last => write!(f, " port {first}"),
+            Some((first, last)) => write!(f, " ports {first}-{last}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A policy for traffic the guest sends to the host network.
+///
+/// Rules are evaluated in order, and the first matching rule decides. Traffic
+/// matching no rule gets the default action. The policy does not apply to
+/// the services Consomme provides itself (DHCP, DNS, and pings to the
+/// gateway), to guest forwards, or to connections from forwarded host ports.
+#[derive(Debug, Clone, Inspect)]
+pub struct EgressPolicy {
+    /// The action for traffic matching no rule.
+    pub default: EgressAction,
+    /// The rules, in evaluation order.
+    #[inspect(with = "|x| inspect::iter_by_index(x).map_value(inspect::AsDisplay)")]
+    pub rules: Vec<EgressRule>,
+}
+
+impl Default for EgressPolicy {
+    fn default() -> Self {
+        Self {
+            default: EgressAction::Allow,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl EgressPolicy {
+    /// Returns a policy that isolates the guest from the host network.
+    pub fn restricted() -> Self {
+        Self {
+            default: EgressAction::Deny,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Returns whether the guest may send `protocol` traffic to `dst`.
+    pub fn allows(&self, protocol: EgressProtocol, dst: &SocketAddr) -> bool {
+        let action = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(protocol, dst))
+            .map_or(self.default, |rule| rule.action);
+        action == EgressAction::Allow
+    }
+}
+
+/// The host endpoint of a guest forward.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuestForwardTarget {
+    /// A TCP address.
+    Tcp(SocketAddr),
+    /// A Unix stream socket path.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for GuestForwardTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuestForwardTarget::Tcp(addr) => write!(f, "tcp:{addr}"),
+            GuestForwardTarget::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A virtual TCP service in the guest subnet, forwarded to a host endpoint.
+///
+/// Guest connections to `guest_address` are connected to `target` instead,
+/// regardless of the egress policy. This gives an isolated guest a stable
+/// address for reaching a specific host service.
+#[derive(Debug, Clone, PartialEq, Eq, Inspect)]
+pub struct GuestForward {
+    /// The virtual address and port the guest connects to.
+    #[inspect(display)]
+    pub guest_address: SocketAddrV4,
+    /// The host endpoint to connect to.
+    #[inspect(display)]
+    pub target: GuestForwardTarget,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        action: EgressAction,
+        protocol: Option<EgressProtocol>,
+        net: &str,
+        ports: Option<(u16, u16)>,
+    ) -> EgressRule {
+        let (address, prefix_len) = net.split_once('/').unwrap();
+        EgressRule {
+            action,
+            protocol,
+            address: address.parse().unwrap(),
+            prefix_len: prefix_len.parse().unwrap(),
+            ports,
+        }
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn default_allows() {
+        let policy = EgressPolicy::default();
+        assert!(policy.allows(EgressProtocol::Tcp, &addr("192.168.1.1:80")));
+        assert!(policy.allows(EgressProtocol::Icmp, &addr("[::1]:0")));
+    }
+
+    #[test]
+    fn restricted_denies() {
+        let policy = EgressPolicy::restricted();
+        assert!(!policy.allows(EgressProtocol::Tcp, &addr("192.168.1.1:80")));
+        assert!(!policy.allows(EgressProtocol::Udp, &addr("[2001:db8::1]:53")));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let policy = EgressPolicy {
+            default: EgressAction::Allow,
+            rules: vec![
+                rule(
+                    EgressAction::Allow,
+                    Some(EgressProtocol::Tcp),
+                    "10.1.2.3/32",
+                    Some((443, 443)),
+                ),
+                rule(EgressAction::Deny, None, "10.0.0.0/8", None),
+                rule(EgressAction::Deny, None, "fd00::/8", None),
+            ],
+        };
+        assert!(policy.allows(EgressProtocol::Tcp, &addr("10.1.2.3:443")));
+        assert!(!policy.allows(EgressProtocol::Tcp, &addr("10.1.2.3:80")));
+        assert!(!policy.allows(EgressProtocol::Udp, &addr("10.1.2.3:443")));
+        assert!(!policy.allows(EgressProtocol::Icmp, &addr("10.200.0.1:0")));
+        assert!(policy.allows(EgressProtocol::Tcp, &addr("11.0.0.1:443")));
+        assert!(!policy.allows(EgressProtocol::Tcp, &addr("[fdab::1]:443")));
+        assert!(policy.allows(EgressProtocol::Tcp, &addr("[2001:db8::1]:443")));
+    }
+
+    #[test]
+    fn port_ranges_and_icmp() {
+        let policy = EgressPolicy {
+            default: EgressAction::Deny,
+            rules: vec![rule(
+                EgressAction::Allow,
+                None,
+                "0.0.0.0/0",
+                Some((8000, 8099)),
+            )],
+        };
+        assert!(policy.allows(EgressProtocol::Tcp, &addr("1.2.3.4:8000")));
+        assert!(policy.allows(EgressProtocol::Udp, &addr("1.2.3.4:8099")));
+        assert!(!policy.allows(EgressProtocol::Tcp, &addr("1.2.3.4:8100")));
+        assert!(!policy.allows(EgressProtocol::Icmp, &addr("1.2.3.4:0")));
+        assert!(!policy.allows(EgressProtocol::Tcp, &addr("[::1]:8000")));
+    }
+
+    #[test]
+    fn validate() {
+        assert!(
+            rule(EgressAction::Deny, None, "10.0.0.0/33", None)
+                .validate()
+                .is_err()
+        );
+        assert!(
+            rule(EgressAction::Deny, None, "::/128", None)
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            rule(EgressAction::Deny, None, "::/0", Some((2, 1)))
+                .validate()
+                .is_err()
+        );
+    }
+}
@@ -9,6 +9,7 @@
 use super::ConsommeState;
 use super::DropReason;
 use crate::ChecksumState;
+use crate::EgressProtocol;
 use crate::Ipv4Addresses;
 use crate::MIN_MTU;
 
@@ -239,6 +240,17 @@ pub(crate) fn handle_icmp(
             return self.handle_icmp_gateway_echo(frame, addresses, payload);
         }
 
+        let dst = SocketAddr::V4(SocketAddrV4::new(addresses.dst_addr, 0));
+        if !self
+            .inner
+            .state
+            .params
+            .egress
+            .allows(EgressProtocol::Icmp, &dst)
+        {
+            return Err(DropReason::EgressDenied);
+        }
+
         let icmp_packet = Icmpv4Packet::new_unchecked(payload);
         let guest_addr = SocketAddrV4::new(addresses.src_addr, 0);
 
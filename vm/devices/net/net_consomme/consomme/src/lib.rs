@@ -13,6 +13,13 @@
 //! guest OS networking by leveraging the host's network stack.
 //!
 //! This implementation includes a small DHCP server for address assignment.
+//!
+//! Guest traffic to the host network can be restricted with an
+//! [`EgressPolicy`], and [`GuestForward`]s expose selected host services at
+//! virtual addresses in the guest subnet.
+//!
+//! The built-in DNS forwarder answers queries for names with static
+//! [`DnsRecord`]s itself, and can log every guest query and response.
 
 mod arp;
 mod dhcp;
@@ -21,6 +28,7 @@
 #[cfg_attr(windows, path = "dns_windows.rs")]
 mod dns;
 mod dns_resolver;
+mod egress;
 mod icmp;
 mod local_addr_map;
 mod ndp;
@@ -68,6 +76,17 @@
 use std::time::Duration;
 use thiserror::Error;
 
+pub use dns_resolver::local_zone::DnsRecord;
+pub use dns_resolver::local_zone::DnsRecordData;
+pub use dns_resolver::local_zone::InvalidDnsRecord;
+pub use egress::EgressAction;
+pub use egress::EgressPolicy;
+pub use egress::EgressProtocol;
+pub use egress::EgressRule;
+pub use egress::GuestForward;
+pub use egress::GuestForwardTarget;
+pub use egress::InvalidEgressRule;
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 struct FourTuple {
     src: SocketAddr,
@@ -186,6 +205,18 @@ pub struct ConsommeParams {
     pub tcp_rx_buffer: TcpBufferBounds,
     /// Per-connection TCP transmit ring buffer bounds (host-to-guest).
     pub tcp_tx_buffer: TcpBufferBounds,
+    /// Policy for guest traffic to the host network.
+    pub egress: EgressPolicy,
+    /// Virtual TCP services in the guest subnet, forwarded to host endpoints.
+    #[inspect(iter_by_index)]
+    pub guest_forwards: Vec<GuestForward>,
+    /// Static DNS records, answered before consulting the host resolver.
+    /// Only used if the DNS forwarder is available.
+    #[inspect(with = "|x| inspect::iter_by_index(x).map_value(inspect::AsDisplay)")]
+    pub dns_records: Vec<DnsRecord>,
+    /// If true, log every guest DNS query and response, and keep the recent
+    /// ones for inspection.
+    pub log_dns_queries: bool,
 }
 
 /// Bounds for a per-connection TCP ring buffer.
@@ -249,6 +280,10 @@ pub fn new() -> Result<Self, Error> {
             allow_host_local_access: false,
             tcp_rx_buffer: DEFAULT_TCP_BUFFER_BOUNDS,
             tcp_tx_buffer: DEFAULT_TCP_BUFFER_BOUNDS,
+            egress: EgressPolicy::default(),
+            guest_forwards: Vec::new(),
+            dns_records: Vec::new(),
+            log_dns_queries: false,
         })
     }
 
@@ -368,6 +403,16 @@ fn internal_nameservers(&self, host_has_ipv6: bool) -> Vec<IpAddress> {
         ns
     }
 
+    /// Returns the guest forward for connections to `addr`, if any.
+    fn guest_forward(&self, addr: &SocketAddr) -> Option<&GuestForward> {
+        let SocketAddr::V4(addr) = addr else {
+            return None;
+        };
+        self.guest_forwards
+            .iter()
+            .find(|fwd| fwd.guest_address == *addr)
+    }
+
     fn is_local_address(&self, addr: &SocketAddr) -> bool {
         match addr {
             SocketAddr::V4(v4) => v4.ip().is_loopback() || v4.ip() == &self.client_ip,
@@ -666,6 +711,9 @@ pub enum DropReason {
     /// or link-local when host-local access is disabled).
     #[error("destination address not allowed")]
     DestinationNotAllowed,
+    /// The egress policy denies traffic to the destination.
+    #[error("denied by egress policy")]
+    EgressDenied,
 }
 
 /// An error from a port bind or unbind operation.
@@ -787,20 +835,23 @@ pub fn new(mut params: ConsommeParams) -> Self {
                 }
             }
         };
-        let dns =
-            match dns_resolver::DnsResolver::new(dns_resolver::DEFAULT_MAX_PENDING_DNS_REQUESTS) {
-                Ok(dns) => {
-                    // When the DNS resolver is available, use the default internal nameserver.
-                    params.nameservers = params.internal_nameservers(host_has_ipv6);
-                    Some(dns)
-                }
-                Err(_) => {
-                    tracelimit::warn_ratelimited!(
-                        "failed to initialize DNS resolver, falling back to using host DNS settings"
-                    );
-                    None
-                }
-            };
+        let dns = match dns_resolver::DnsResolver::new(
+            dns_resolver::DEFAULT_MAX_PENDING_DNS_REQUESTS,
+            dns_resolver::local_zone::LocalZone::new(&params.dns_records),
+            params.log_dns_queries,
+        ) {
+            Ok(dns) => {
+                // When the DNS resolver is available, use the default internal nameserver.
+                params.nameservers = params.internal_nameservers(host_has_ipv6);
+                Some(dns)
+            }
+            Err(_) => {
+                tracelimit::warn_ratelimited!(
+                    "failed to initialize DNS resolver, falling back to using host DNS settings"
+                );
+                None
+            }
+        };
         let timeout = params.udp_timeout;
         let tcp_rx_buffer = params.tcp_rx_buffer;
         let tcp_tx_buffer = params.tcp_tx_buffer;
@@ -10,7 +10,9 @@
 use super::DropReason;
 use crate::ChecksumState;
 use crate::ConsommeState;
+use crate::EgressProtocol;
 use crate::FourTuple;
+use crate::GuestForwardTarget;
 use crate::IpAddresses;
 use crate::IpVersion;
 use crate::PortForwardKey;
@@ -41,7 +43,6 @@
 use smoltcp::wire::TcpPacket;
 use smoltcp::wire::TcpRepr;
 use smoltcp::wire::TcpSeqNumber;
-use socket2::Domain;
 use socket2::Protocol;
 use socket2::SockAddr;
 use socket2::Socket;
@@ -575,6 +576,26 @@ pub(crate) fn handle_tcp(
                             &tcp,
                             &self.inner.tcp.connection_params,
                         )?
+                    } else if let Some(target) = sender
+                        .state
+                        .params
+                        .guest_forward(&sender.ft.dst)
+                        .map(|fwd| fwd.target.clone())
+                    {
+                        // Guest forwards bypass the egress policy.
+                        let target = match target {
+                            GuestForwardTarget::Tcp(addr) => SockAddr::from(addr),
+                            GuestForwardTarget::Unix(path) => {
+                                SockAddr::unix(path).map_err(DropReason::Io)?
+                            }
+                        };
+                        TcpConnection::new(
+                            &mut sender,
+                            &tcp,
+                            &self.inner.tcp.connection_params,
+                            &target,
+                            false,
+                        )?
                     } else {
                         // Resolve virtual mapped addresses back to real host
                         // addresses before establishing the connection.
@@ -599,6 +620,20 @@ pub(crate) fn handle_tcp(
                         } else {
                             ft
                         };
+                        if !sender
+                            .state
+                            .params
+                            .egress
+                            .allows(EgressProtocol::Tcp, &ft.dst)
+                        {
+                            tracing::debug!(
+                                src = %ft.src,
+                                dst = %ft.dst,
+                                "tcp connection denied by egress policy"
+                            );
+                            sender.rst(TcpSeqNumber(0), Some(tcp.seq_number + tcp.segment_len()));
+                            return Err(DropReason::EgressDenied);
+                        }
                         let mut sender = Sender {
                             ft: &ft,
                             client: sender.client,
@@ -608,6 +643,7 @@ pub(crate) fn handle_tcp(
                             &mut sender,
                             &tcp,
                             &self.inner.tcp.connection_params,
+                            &SockAddr::from(ft.dst),
                             is_local_address,
                         )?
                     };
@@ -806,41 +842,46 @@ fn new_base(params: &ConnectionParams) -> TcpConnectionInner {
         }
     }
 
+    /// Creates a connection for a guest SYN, connecting a new host socket to
+    /// `dst`, which is either a TCP address or a Unix stream socket path.
     fn new(
         sender: &mut Sender<'_, impl Client>,
         tcp: &TcpRepr<'_>,
         params: &ConnectionParams,
+        dst: &SockAddr,
         is_local_address: bool,
     ) -> Result<Self, DropReason> {
         let mut inner = Self::new_base(params);
         inner.initialize_from_first_client_packet(tcp)?;
 
+        let is_unix = dst.is_unix();
         let socket = Socket::new(
-            match sender.ft.dst {
-                SocketAddr::V4(_) => Domain::IPV4,
-                SocketAddr::V6(_) => Domain::IPV6,
-            },
+            dst.domain(),
             Type::STREAM,
-            Some(Protocol::TCP),
+            (!is_unix).then_some(Protocol::TCP),
         )
         .map_err(DropReason::Io)?;
 
         // Disable Nagle's algorithm to reduce latency for small packets.
-        socket.set_tcp_nodelay(true).map_err(DropReason::Io)?;
+        if !is_unix {
+            socket.set_tcp_nodelay(true).map_err(DropReason::Io)?;
+        }
 
         // On Windows the default behavior for non-existent loopback sockets is
         // to wait and try again. This is different than the Linux behavior of
         // immediately failing. Default to the Linux behavior.
         #[cfg(windows)]
-        if sender.ft.dst.ip().is_loopback() {
+        if dst.as_socket().is_some_and(|addr| addr.ip().is_loopback()) {
             if let Err(err) = crate::windows::disable_connection_retries(&socket) {
                 tracing::trace!(err, "Failed to disable loopback retries");
             }
         }
 
         let socket = PolledSocket::new(sender.client.driver(), socket).map_err(DropReason::Io)?;
-        match socket.get().connect(&SockAddr::from(sender.ft.dst)) {
-            Ok(_) => unreachable!(),
+        match socket.get().connect(dst) {
+            // Unix sockets may connect immediately. The connection completes
+            // when the socket becomes writable, as for a pending connect.
+            Ok(()) => (),
             Err(err) if is_connect_incomplete_error(&err) => (),
             Err(err) => {
                 log_connect_error(sender.ft, &err);
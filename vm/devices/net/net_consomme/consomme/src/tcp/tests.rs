@@ -17,6 +17,7 @@
 use smoltcp::wire::EthernetAddress;
 use smoltcp::wire::Ipv4Address;
 use smoltcp::wire::Ipv4Repr;
+use socket2::Domain;
 use socket2::SockRef;
 use std::io::ErrorKind;
 use std::net::Ipv4Addr;
@@ -1878,3 +1879,136 @@ async fn test_tcp_zero_window_reopen_sends_update(driver: DefaultDriver) {
          host drains the backlog; got {window_update:?}"
     );
 }
+
+/// Builds a guest SYN frame to `dst_ip:dst_port` and sends it to consomme.
+fn send_guest_syn(
+    consomme: &mut Consomme,
+    client: &mut TestClient,
+    dst_ip: Ipv4Address,
+    dst_port: u16,
+) -> Result<(), DropReason> {
+    let guest_mac = consomme.params_mut().client_mac;
+    let gateway_mac = consomme.params_mut().gateway_mac;
+    let guest_ip = consomme.params_mut().client_ip;
+    let syn = TcpRepr {
+        src_port: 44444,
+        dst_port,
+        control: TcpControl::Syn,
+        seq_number: TcpSeqNumber(1000),
+        ack_number: None,
+        window_len: 64240,
+        window_scale: Some(7),
+        max_seg_size: Some(1460),
+        sack_permitted: false,
+        sack_ranges: [None, None, None],
+        timestamp: None,
+        payload: &[],
+    };
+    let mut buf = vec![0u8; 1514];
+    let len = build_tcp_packet(&mut buf, guest_mac, gateway_mac, guest_ip, dst_ip, &syn);
+    consomme
+        .access(client)
+        .send(&buf[..len], &ChecksumState::NONE)
+}
+
+/// A connection denied by the egress policy is reset without reaching the
+/// host.
+#[pal_async::async_test]
+async fn test_tcp_egress_denied(driver: DefaultDriver) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let mut consomme = Consomme::new({
+        let mut params = ConsommeParams::new().unwrap();
+        params.allow_host_local_access = true;
+        params.egress = crate::EgressPolicy::restricted();
+        params
+    });
+    let mut client = TestClient::new(driver);
+    let received = client.received_packets.clone();
+
+    let err = send_guest_syn(&mut consomme, &mut client, Ipv4Addr::LOCALHOST, port).unwrap_err();
+    assert!(matches!(err, DropReason::EgressDenied));
+
+    let packets = received.lock();
+    let rst = packets
+        .iter()
+        .find_map(|p| TcpTestHarness::is_tcp_packet(p))
+        .expect("should have sent a reset");
+    assert_eq!(rst.control, TcpControl::Rst);
+    assert_eq!(rst.dst_port, 44444);
+    assert_eq!(
+        listener.accept().unwrap_err().kind(),
+        ErrorKind::WouldBlock,
+        "host should not see a connection"
+    );
+}
+
+/// Polls consomme until `listener` accepts a connection.
+async fn accept_forwarded<T: pal_async::socket::Listener>(
+    consomme: &mut Consomme,
+    client: &mut TestClient,
+    listener: &mut PolledSocket<T>,
+) {
+    std::future::poll_fn(|cx| {
+        consomme.access(client).poll(cx);
+        std::task::ready!(listener.poll_accept(cx)).unwrap();
+        Poll::Ready(())
+    })
+    .await;
+}
+
+/// A guest forward connects to its TCP target despite a restrictive egress
+/// policy.
+#[pal_async::async_test]
+async fn test_tcp_guest_forward(driver: DefaultDriver) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = listener.local_addr().unwrap();
+    let mut listener = PolledSocket::new(&driver, listener).unwrap();
+    let virtual_ip = Ipv4Addr::new(10, 0, 0, 100);
+
+    let mut consomme = Consomme::new({
+        let mut params = ConsommeParams::new().unwrap();
+        params.egress = crate::EgressPolicy::restricted();
+        params.guest_forwards.push(crate::GuestForward {
+            guest_address: SocketAddrV4::new(virtual_ip, 8080),
+            target: GuestForwardTarget::Tcp(target),
+        });
+        params
+    });
+    let mut client = TestClient::new(driver);
+
+    send_guest_syn(&mut consomme, &mut client, virtual_ip, 8080).unwrap();
+    accept_forwarded(&mut consomme, &mut client, &mut listener).await;
+
+    // Other ports on the virtual address are not forwarded.
+    let err = send_guest_syn(&mut consomme, &mut client, virtual_ip, 8081).unwrap_err();
+    assert!(matches!(err, DropReason::EgressDenied));
+}
+
+/// A guest forward connects to its Unix socket target.
+#[cfg(unix)]
+#[pal_async::async_test]
+async fn test_tcp_guest_forward_unix(driver: DefaultDriver) {
+    let path = std::env::temp_dir().join(format!("consomme-guestfwd-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    let mut listener = PolledSocket::new(&driver, listener).unwrap();
+    let virtual_ip = Ipv4Addr::new(10, 0, 0, 100);
+
+    let mut consomme = Consomme::new({
+        let mut params = ConsommeParams::new().unwrap();
+        params.egress = crate::EgressPolicy::restricted();
+        params.guest_forwards.push(crate::GuestForward {
+            guest_address: SocketAddrV4::new(virtual_ip, 80),
+            target: GuestForwardTarget::Unix(path.clone()),
+        });
+        params
+    });
+    let mut client = TestClient::new(driver);
+
+    send_guest_syn(&mut consomme, &mut client, virtual_ip, 80).unwrap();
+    accept_forwarded(&mut consomme, &mut client, &mut listener).await;
+    let _ = std::fs::remove_file(&path);
+}
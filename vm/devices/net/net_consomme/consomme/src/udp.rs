@@ -10,6 +10,7 @@
 use super::dhcpv6::DHCPV6_SERVER;
 use crate::ChecksumState;
 use crate::ConsommeState;
+use crate::EgressProtocol;
 use crate::FourTuple;
 use crate::IpAddresses;
 use crate::IpVersion;
@@ -446,6 +447,21 @@ pub(crate) fn handle_udp(
             }
         }
 
+        if !self
+            .inner
+            .state
+            .params
+            .egress
+            .allows(EgressProtocol::Udp, &dst_sock_addr)
+        {
+            tracing::debug!(
+                src = %guest_addr,
+                dst = %dst_sock_addr,
+                "udp datagram denied by egress policy"
+            );
+            return Err(DropReason::EgressDenied);
+        }
+
         let conn = self.get_or_insert(guest_addr, Some(frame.src_addr))?;
         let socket = conn.socket.as_ref().unwrap().get();
         if conn.gso_size != checksum.gso {
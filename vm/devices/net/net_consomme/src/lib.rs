@@ -676,6 +676,7 @@ fn poll_ready(&mut self, cx: &mut Context<'_>, pool: &mut dyn BufferAccess) -> P
                     | consomme::DropReason::DestinationNotAllowed => {
                         self.stats.tx_dropped.increment()
                     }
+                    consomme::DropReason::EgressDenied => self.stats.tx_denied.increment(),
                     consomme::DropReason::UnsupportedEthertype(_)
                     | consomme::DropReason::UnsupportedIpProtocol(_)
                     | consomme::DropReason::UnsupportedIcmpv6(_)
@@ -764,6 +765,8 @@ struct QueueState {
 struct Stats {
     rx_dropped: Counter,
     tx_dropped: Counter,
+    /// Packets dropped by the egress policy.
+    tx_denied: Counter,
     tx_errors: Counter,
     tx_unknown: Counter,
 }
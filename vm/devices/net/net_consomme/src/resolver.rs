@@ -9,8 +9,18 @@
 use net_backend::resolve::ResolveEndpointParams;
 use net_backend::resolve::ResolvedEndpoint;
 use net_backend_resources::consomme::ConsommeHandle;
+use net_backend_resources::consomme::DnsRecord;
+use net_backend_resources::consomme::DnsRecordData;
+use net_backend_resources::consomme::EgressAction;
+use net_backend_resources::consomme::EgressPolicy;
+use net_backend_resources::consomme::EgressProtocol;
+use net_backend_resources::consomme::GuestForwardConfig;
+use net_backend_resources::consomme::GuestForwardTarget;
 use net_backend_resources::consomme::HostPort;
 use net_backend_resources::consomme::HostPortProtocol;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
 use thiserror::Error;
 use vm_resource::ResolveResource;
 use vm_resource::declare_static_resolver;
@@ -29,6 +39,12 @@ pub enum ResolveConsommeError {
     Consomme(consomme::Error),
     #[error(transparent)]
     InvalidCidr(consomme::InvalidCidr),
+    #[error("invalid egress rule")]
+    InvalidEgressRule(#[source] consomme::InvalidEgressRule),
+    #[error("guest forward address {0} is not an unused address in the guest subnet")]
+    InvalidGuestForward(Ipv4Addr),
+    #[error("invalid static DNS record")]
+    InvalidDnsRecord(#[source] consomme::InvalidDnsRecord),
     #[error("failed to create socket for port forward ({details})")]
     SocketCreation {
         #[source]
@@ -53,6 +69,18 @@ fn resolve(
                 .set_cidr(cidr)
                 .map_err(ResolveConsommeError::InvalidCidr)?;
         }
+        state.egress = egress_policy(resource.egress)?;
+        state.guest_forwards = resource
+            .guest_forwards
+            .into_iter()
+            .map(|fwd| guest_forward(&state, fwd))
+            .collect::<Result<_, _>>()?;
+        state.dns_records = resource
+            .dns_records
+            .into_iter()
+            .map(dns_record)
+            .collect::<Result<_, _>>()?;
+        state.log_dns_queries = resource.log_dns_queries;
         let port_forwards: Vec<PortForwardConfig> = resource
             .ports
             .into_iter()
@@ -104,3 +132,73 @@ fn resolve(
         Ok(endpoint.into())
     }
 }
+
+fn egress_policy(policy: EgressPolicy) -> Result<consomme::EgressPolicy, ResolveConsommeError> {
+    let action = |action| match action {
+        EgressAction::Allow => consomme::EgressAction::Allow,
+        EgressAction::Deny => consomme::EgressAction::Deny,
+    };
+    let rules = policy
+        .rules
+        .into_iter()
+        .map(|rule| {
+            let rule = consomme::EgressRule {
+                action: action(rule.action),
+                protocol: rule.protocol.map(|protocol| match protocol {
+                    EgressProtocol::Tcp => consomme::EgressProtocol::Tcp,
+                    EgressProtocol::Udp => consomme::EgressProtocol::Udp,
+                    EgressProtocol::Icmp => consomme::EgressProtocol::Icmp,
+                }),
+                address: rule.address.into(),
+                prefix_len: rule.prefix_len,
+                ports: rule.ports,
+            };
+            rule.validate()
+                .map_err(ResolveConsommeError::InvalidEgressRule)?;
+            Ok(rule)
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(consomme::EgressPolicy {
+        default: action(policy.default),
+        rules,
+    })
+}
+
+fn guest_forward(
+    state: &ConsommeParams,
+    fwd: GuestForwardConfig,
+) -> Result<consomme::GuestForward, ResolveConsommeError> {
+    let mask = state.net_mask.to_bits();
+    let ip = fwd.guest_address;
+    if ip.to_bits() & mask != state.gateway_ip.to_bits() & mask
+        || ip == state.gateway_ip
+        || ip == state.client_ip
+    {
+        return Err(ResolveConsommeError::InvalidGuestForward(ip));
+    }
+    let target = match fwd.target {
+        GuestForwardTarget::Tcp { address, port } => {
+            consomme::GuestForwardTarget::Tcp(SocketAddr::new(address.into(), port))
+        }
+        GuestForwardTarget::Unix(path) => consomme::GuestForwardTarget::Unix(path.into()),
+    };
+    Ok(consomme::GuestForward {
+        guest_address: SocketAddrV4::new(ip, fwd.guest_port),
+        target,
+    })
+}
+
+fn dns_record(record: DnsRecord) -> Result<consomme::DnsRecord, ResolveConsommeError> {
+    let record = consomme::DnsRecord {
+        name: record.name,
+        data: match record.data {
+            DnsRecordData::A(addr) => consomme::DnsRecordData::A(addr),
+            DnsRecordData::Aaaa(addr) => consomme::DnsRecordData::Aaaa(addr),
+            DnsRecordData::Cname(target) => consomme::DnsRecordData::Cname(target),
+        },
+    };
+    record
+        .validate()
+        .map_err(ResolveConsommeError::InvalidDnsRecord)?;
+    Ok(record)
+}
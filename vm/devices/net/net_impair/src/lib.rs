@@ -0,0 +1,788 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! An endpoint that impairs the traffic of another endpoint.
+//!
+//! [`ImpairEndpoint`] sits in front of any other endpoint and adds delay,
+//! jitter, packet loss, duplication, reordering, and rate limiting, separately
+//! for each direction. This is useful for testing guest behavior on poor
+//! networks. The settings can be changed while the endpoint is running; they
+//! apply to packets that arrive after the change.
+
+#![forbid(unsafe_code)]
+
+mod link;
+pub mod resolver;
+
+use async_trait::async_trait;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures_concurrency::future::Race;
+use inspect::InspectMut;
+use link::Link;
+use link::Rng;
+use link::Verdict;
+use net_backend::BufferAccess;
+use net_backend::Endpoint;
+use net_backend::EndpointAction;
+use net_backend::MultiQueueSupport;
+use net_backend::Queue;
+use net_backend::QueueConfig;
+use net_backend::RssConfig;
+use net_backend::RxBufferSegment;
+use net_backend::RxId;
+use net_backend::RxMetadata;
+use net_backend::TxError;
+use net_backend::TxId;
+use net_backend::TxOffloadSupport;
+use net_backend::TxSegment;
+use net_backend::TxSegmentType;
+use net_backend::next_packet;
+use net_backend_resources::impair::ImpairRequest;
+use net_backend_resources::impair::ImpairmentConfig;
+use net_backend_resources::impair::LinkImpairment;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use thiserror::Error;
+
+/// The maximum number of guest receive buffers held back from the wrapped
+/// endpoint to receive duplicated packets.
+const MAX_SPARE_RX: usize = 8;
+
+/// The number of receives or transmit completions taken from the wrapped
+/// queue at a time.
+const POLL_BATCH: usize = 64;
+
+/// An invalid impairment setting.
+#[derive(Debug, Error)]
+#[error("{direction} {name} probability {value} is not between 0 and 1")]
+pub struct InvalidImpairment {
+    direction: &'static str,
+    name: &'static str,
+    value: f64,
+}
+
+/// Validates impairment settings.
+pub fn validate(config: &ImpairmentConfig) -> Result<(), InvalidImpairment> {
+    for (direction, link) in [("tx", &config.tx), ("rx", &config.rx)] {
+        for (name, value) in [
+            ("loss", link.loss),
+            ("duplicate", link.duplicate),
+            ("reorder", link.reorder),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(InvalidImpairment {
+                    direction,
+                    name,
+                    value,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An endpoint that impairs the traffic of another endpoint.
+pub struct ImpairEndpoint {
+    endpoint: Box<dyn Endpoint>,
+    config: Arc<Mutex<ImpairmentConfig>>,
+    seed: u64,
+    recv: Option<mesh::Receiver<ImpairRequest>>,
+}
+
+impl ImpairEndpoint {
+    /// Wraps `endpoint`, impairing its traffic as specified by `config`.
+    ///
+    /// Impairment decisions are drawn from a pseudorandom sequence starting
+    /// at `seed`. If `recv` is provided, the settings can be queried and
+    /// changed through it.
+    pub fn new(
+        endpoint: Box<dyn Endpoint>,
+        config: ImpairmentConfig,
+        seed: u64,
+        recv: Option<mesh::Receiver<ImpairRequest>>,
+    ) -> Result<Self, InvalidImpairment> {
+        validate(&config)?;
+        Ok(Self {
+            endpoint,
+            config: Arc::new(Mutex::new(config)),
+            seed,
+            recv,
+        })
+    }
+
+    fn handle_request(&self, req: ImpairRequest) {
+        match req {
+            ImpairRequest::Get(rpc) => rpc.handle_sync(|()| self.config.lock().clone()),
+            ImpairRequest::Set(rpc) => {
+                rpc.handle_failable_sync(|config| -> Result<(), InvalidImpairment> {
+                    validate(&config)?;
+                    tracing::info!(?config, "updating network impairment");
+                    *self.config.lock() = config;
+                    Ok(())
+                })
+            }
+        }
+    }
+}
+
+impl InspectMut for ImpairEndpoint {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("impairment", &*self.config.lock())
+            .merge(self.endpoint.as_mut());
+    }
+}
+
+#[async_trait]
+impl Endpoint for ImpairEndpoint {
+    fn endpoint_type(&self) -> &'static str {
+        self.endpoint.endpoint_type()
+    }
+
+    async fn get_queues(
+        &mut self,
+        config: Vec<QueueConfig>,
+        rss: Option<&RssConfig<'_>>,
+        queues: &mut Vec<Box<dyn Queue>>,
+    ) -> anyhow::Result<()> {
+        let timers = config
+            .iter()
+            .map(|config| PolledTimer::new(config.driver.as_ref()))
+            .collect::<Vec<_>>();
+        let mut inner = Vec::new();
+        self.endpoint.get_queues(config, rss, &mut inner).await?;
+        queues.extend(
+            inner
+                .into_iter()
+                .zip(timers)
+                .enumerate()
+                .map(|(i, (queue, timer))| {
+                    Box::new(ImpairQueue::new(
+                        queue,
+                        self.config.clone(),
+                        timer,
+                        Rng::new(self.seed.wrapping_add(i as u64)),
+                    )) as _
+                }),
+        );
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        self.endpoint.stop().await
+    }
+
+    fn is_ordered(&self) -> bool {
+        // Packets can be reordered and dropped packets complete early.
+        false
+    }
+
+    fn tx_offload_support(&self) -> TxOffloadSupport {
+        self.endpoint.tx_offload_support()
+    }
+
+    fn multiqueue_support(&self) -> MultiQueueSupport {
+        self.endpoint.multiqueue_support()
+    }
+
+    fn tx_fast_completions(&self) -> bool {
+        // Delayed packets are not completed until they are sent.
+        false
+    }
+
+    async fn set_data_path_to_guest_vf(&self, use_vf: bool) -> anyhow::Result<()> {
+        self.endpoint.set_data_path_to_guest_vf(use_vf).await
+    }
+
+    async fn get_data_path_to_guest_vf(&self) -> anyhow::Result<bool> {
+        self.endpoint.get_data_path_to_guest_vf().await
+    }
+
+    async fn wait_for_endpoint_action(&mut self) -> EndpointAction {
+        enum Message {
+            Request(Option<ImpairRequest>),
+            Action(EndpointAction),
+        }
+
+        loop {
+            let Some(recv) = &mut self.recv else {
+                break self.endpoint.wait_for_endpoint_action().await;
+            };
+            let message = (
+                recv.next().map(Message::Request),
+                self.endpoint
+                    .wait_for_endpoint_action()
+                    .map(Message::Action),
+            )
+                .race()
+                .await;
+            match message {
+                Message::Request(Some(req)) => self.handle_request(req),
+                Message::Request(None) => self.recv = None,
+                Message::Action(action) => break action,
+            }
+        }
+    }
+
+    fn link_speed(&self) -> u64 {
+        let rate = self.config.lock().rx.rate;
+        let speed = self.endpoint.link_speed();
+        if rate != 0 { speed.min(rate) } else { speed }
+    }
+}
+
+/// A receive reported by the wrapped queue, as written through
+/// [`RecordingPool`].
+#[derive(Default)]
+struct Recorded {
+    metadata: Option<RxMetadata>,
+    /// The packet data, kept only while receive duplication is enabled.
+    data: Option<Vec<u8>>,
+}
+
+/// A [`BufferAccess`] that records what the wrapped queue writes to each
+/// receive buffer, so that received packets can be sized and duplicated
+/// without reading them back from guest memory.
+struct RecordingPool<'a> {
+    pool: &'a mut dyn BufferAccess,
+    recorded: &'a mut HashMap<u32, Recorded>,
+    record_data: bool,
+}
+
+impl BufferAccess for RecordingPool<'_> {
+    fn guest_memory(&self) -> &guestmem::GuestMemory {
+        self.pool.guest_memory()
+    }
+
+    fn write_data(&mut self, id: RxId, data: &[u8]) {
+        if self.record_data {
+            self.recorded.entry(id.0).or_default().data = Some(data.to_vec());
+        }
+        self.pool.write_data(id, data)
+    }
+
+    fn push_guest_addresses(&self, id: RxId, buf: &mut Vec<RxBufferSegment>) {
+        self.pool.push_guest_addresses(id, buf)
+    }
+
+    fn capacity(&self, id: RxId) -> u32 {
+        self.pool.capacity(id)
+    }
+
+    fn write_header(&mut self, id: RxId, metadata: &RxMetadata) {
+        self.recorded.entry(id.0).or_default().metadata = Some(*metadata);
+        self.pool.write_header(id, metadata)
+    }
+
+    fn write_packet(&mut self, id: RxId, metadata: &RxMetadata, data: &[u8]) {
+        let recorded = self.recorded.entry(id.0).or_default();
+        recorded.metadata = Some(*metadata);
+        if self.record_data {
+            recorded.data = Some(data.to_vec());
+        }
+        self.pool.write_packet(id, metadata, data)
+    }
+}
+
+/// Tracks transmits sent to the wrapped queue on behalf of the guest.
+///
+/// Each copy of a guest packet is sent with its own ID, so that duplicates
+/// can be in flight together. The guest's packet completes when all of its
+/// copies have.
+#[derive(Default)]
+struct TxTracker {
+    /// The guest ID for each ID sent to the wrapped queue.
+    ids: HashMap<u32, TxId>,
+    /// The number of copies of each guest packet still in flight.
+    copies: HashMap<u32, u32>,
+    next_id: u32,
+    /// Completed guest packets.
+    done: VecDeque<TxId>,
+}
+
+impl TxTracker {
+    /// Returns a copy of the guest packet `segments`, relabeled with a new ID.
+    fn copy(&mut self, segments: &[TxSegment]) -> Vec<TxSegment> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut segments = segments.to_vec();
+        let TxSegmentType::Head(metadata) = &mut segments[0].ty else {
+            unreachable!("packet must start with a head segment")
+        };
+        self.ids.insert(id, metadata.id);
+        *self.copies.entry(metadata.id.0).or_default() += 1;
+        metadata.id = TxId(id);
+        segments
+    }
+
+    /// Completes a copy sent to the wrapped queue.
+    fn complete(&mut self, id: TxId) {
+        let Some(guest_id) = self.ids.remove(&id.0) else {
+            tracelimit::warn_ratelimited!(id = id.0, "unknown transmit completion");
+            return;
+        };
+        let copies = self.copies.get_mut(&guest_id.0).unwrap();
+        *copies -= 1;
+        if *copies == 0 {
+            self.copies.remove(&guest_id.0);
+            self.done.push_back(guest_id);
+        }
+    }
+}
+
+struct ImpairQueue {
+    inner: Box<dyn Queue>,
+    config: Arc<Mutex<ImpairmentConfig>>,
+    timer: PolledTimer,
+    rng: Rng,
+    /// Guest transmits waiting for their delay to elapse.
+    tx: Link<Vec<TxSegment>>,
+    /// Transmit segments ready to go, waiting for the wrapped queue to accept
+    /// them.
+    tx_ready: VecDeque<TxSegment>,
+    tx_tracker: TxTracker,
+    /// An error from sending to the wrapped queue outside of `tx_avail`, to
+    /// be reported from `tx_poll`.
+    tx_error: Option<anyhow::Error>,
+    tx_scratch: Vec<TxId>,
+    /// Receives waiting for their delay to elapse.
+    rx: Link<RxId>,
+    /// Receives ready to be reported to the guest.
+    rx_ready: VecDeque<RxId>,
+    /// Guest buffers held back to receive duplicated packets.
+    rx_spare: Vec<RxId>,
+    rx_recorded: HashMap<u32, Recorded>,
+    rx_scratch: Vec<RxId>,
+}
+
+impl InspectMut for ImpairQueue {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("tx", &self.tx)
+            .field("rx", &self.rx)
+            .field("rx_spare", self.rx_spare.len())
+            .merge(self.inner.as_mut());
+    }
+}
+
+impl ImpairQueue {
+    fn new(
+        inner: Box<dyn Queue>,
+        config: Arc<Mutex<ImpairmentConfig>>,
+        timer: PolledTimer,
+        rng: Rng,
+    ) -> Self {
+        Self {
+            inner,
+            config,
+            timer,
+            rng,
+            tx: Link::new(),
+            tx_ready: VecDeque::new(),
+            tx_tracker: TxTracker::default(),
+            tx_error: None,
+            tx_scratch: vec![TxId(0); POLL_BATCH],
+            rx: Link::new(),
+            rx_ready: VecDeque::new(),
+            rx_spare: Vec::new(),
+            rx_recorded: HashMap::new(),
+            rx_scratch: vec![RxId(0); POLL_BATCH],
+        }
+    }
+
+    fn link_config(&self) -> (LinkImpairment, LinkImpairment) {
+        let config = self.config.lock();
+        (config.tx.clone(), config.rx.clone())
+    }
+
+    /// Sends transmits whose delay has elapsed to the wrapped queue.
+    fn flush_tx(&mut self, pool: &mut dyn BufferAccess, now: Instant) -> anyhow::Result<()> {
+        while let Some(packet) = self.tx.pop_due(now) {
+            self.tx_ready.extend(packet);
+        }
+        if self.tx_ready.is_empty() {
+            return Ok(());
+        }
+        let mut pool = RecordingPool {
+            pool: &mut *pool,
+            recorded: &mut self.rx_recorded,
+            record_data: self.config.lock().rx.duplicate > 0.0,
+        };
+        let segments = self.tx_ready.make_contiguous();
+        let (sync, n) = self.inner.tx_avail(&mut pool, segments)?;
+        if sync {
+            let mut done = &segments[..n];
+            while !done.is_empty() {
+                let (metadata, _, rest) = next_packet(done);
+                done = rest;
+                self.tx_tracker.complete(metadata.id);
+            }
+        }
+        self.tx_ready.drain(..n);
+        Ok(())
+    }
+
+    /// Takes receives from the wrapped queue and schedules their delivery.
+    fn receive(&mut self, pool: &mut dyn BufferAccess, now: Instant) -> anyhow::Result<()> {
+        let (_, config) = self.link_config();
+        loop {
+            let n = self.inner.rx_poll(
+                &mut RecordingPool {
+                    pool: &mut *pool,
+                    recorded: &mut self.rx_recorded,
+                    record_data: config.duplicate > 0.0,
+                },
+                &mut self.rx_scratch,
+            )?;
+            for &id in &self.rx_scratch[..n] {
+                let recorded = self.rx_recorded.remove(&id.0).unwrap_or_default();
+                let len = recorded.metadata.map_or(0, |m| m.len);
+                match self.rx.schedule(&config, &mut self.rng, now, len) {
+                    Verdict::Drop => self.inner.rx_avail(pool, &[id]),
+                    Verdict::Deliver { at, duplicate } => {
+                        self.rx.push(at, id);
+                        if duplicate {
+                            // The duplicate can only be delivered if the
+                            // packet was written through the pool and a
+                            // spare buffer is available.
+                            if let (Some(metadata), Some(data)) = (recorded.metadata, recorded.data)
+                                && let Some(spare) = self.rx_spare.pop()
+                            {
+                                pool.write_packet(spare, &metadata, &data);
+                                self.rx.push(at, spare);
+                            }
+                        }
+                    }
+                }
+            }
+            if n < self.rx_scratch.len() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves receives whose delay has elapsed to the ready list.
+    fn flush_rx(&mut self, now: Instant) {
+        while let Some(id) = self.rx.pop_due(now) {
+            self.rx_ready.push_back(id);
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        match (self.tx.next_deadline(), self.rx.next_deadline()) {
+            (Some(tx), Some(rx)) => Some(tx.min(rx)),
+            (tx, rx) => tx.or(rx),
+        }
+    }
+}
+
+#[async_trait]
+impl Queue for ImpairQueue {
+    async fn update_target_vp(&mut self, target_vp: u32) {
+        self.inner.update_target_vp(target_vp).await
+    }
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>, pool: &mut dyn BufferAccess) -> Poll<()> {
+        loop {
+            let now = Instant::now();
+            if self.tx_error.is_none()
+                && let Err(err) = self.flush_tx(pool, now)
+            {
+                self.tx_error = Some(err);
+            }
+            self.flush_rx(now);
+            if self.tx_error.is_some()
+                || !self.tx_tracker.done.is_empty()
+                || !self.rx_ready.is_empty()
+            {
+                return Poll::Ready(());
+            }
+            let mut recording = RecordingPool {
+                pool: &mut *pool,
+                recorded: &mut self.rx_recorded,
+                record_data: self.config.lock().rx.duplicate > 0.0,
+            };
+            if self.inner.poll_ready(cx, &mut recording).is_ready() {
+                return Poll::Ready(());
+            }
+            match self.next_deadline() {
+                Some(deadline) if self.timer.poll_until(cx, deadline).is_ready() => {}
+                _ => return Poll::Pending,
+            }
+        }
+    }
+
+    fn rx_avail(&mut self, pool: &mut dyn BufferAccess, done: &[RxId]) {
+        let mut done = done;
+        if self.config.lock().rx.duplicate > 0.0 {
+            // Hold back up to half of each batch, so that the wrapped queue
+            // still gets buffers to receive into.
+            let n = (MAX_SPARE_RX - self.rx_spare.len()).min(done.len().div_ceil(2));
+            self.rx_spare.extend_from_slice(&done[..n]);
+            done = &done[n..];
+        } else if !self.rx_spare.is_empty() {
+            self.inner.rx_avail(pool, &self.rx_spare);
+            self.rx_spare.clear();
+        }
+        if !done.is_empty() {
+            self.inner.rx_avail(pool, done);
+        }
+    }
+
+    fn rx_poll(
+        &mut self,
+        pool: &mut dyn BufferAccess,
+        packets: &mut [RxId],
+    ) -> anyhow::Result<usize> {
+        let now = Instant::now();
+        self.receive(pool, now)?;
+        self.flush_rx(now);
+        let n = packets.len().min(self.rx_ready.len());
+        for (d, s) in packets.iter_mut().zip(self.rx_ready.drain(..n)) {
+            *d = s;
+        }
+        Ok(n)
+    }
+
+    fn tx_avail(
+        &mut self,
+        pool: &mut dyn BufferAccess,
+        segments: &[TxSegment],
+    ) -> anyhow::Result<(bool, usize)> {
+        let (config, _) = self.link_config();
+        let now = Instant::now();
+        let mut rest = segments;
+        while !rest.is_empty() {
+            let (metadata, this, next) = next_packet(rest);
+            rest = next;
+            match self
+                .tx
+                .schedule(&config, &mut self.rng, now, metadata.len as usize)
+            {
+                Verdict::Drop => self.tx_tracker.done.push_back(metadata.id),
+                Verdict::Deliver { at, duplicate } => {
+                    for _ in 0..1 + duplicate as usize {
+                        let packet = self.tx_tracker.copy(this);
+                        self.tx.push(at, packet);
+                    }
+                }
+            }
+        }
+        self.flush_tx(pool, now)?;
+        // Completions are always reported through `tx_poll`.
+        Ok((false, segments.len()))
+    }
+
+    fn tx_poll(
+        &mut self,
+        pool: &mut dyn BufferAccess,
+        done: &mut [TxId],
+    ) -> Result<usize, TxError> {
+        if let Some(err) = self.tx_error.take() {
+            return Err(TxError::TryRestart(err));
+        }
+        let n = self.inner.tx_poll(
+            &mut RecordingPool {
+                pool: &mut *pool,
+                recorded: &mut self.rx_recorded,
+                record_data: self.config.lock().rx.duplicate > 0.0,
+            },
+            &mut self.tx_scratch,
+        )?;
+        for &id in &self.tx_scratch[..n] {
+            self.tx_tracker.complete(id);
+        }
+        let n = done.len().min(self.tx_tracker.done.len());
+        for (d, s) in done.iter_mut().zip(self.tx_tracker.done.drain(..n)) {
+            *d = s;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::poll_fn;
+    use guestmem::GuestMemory;
+    use net_backend::TxMetadata;
+    use net_backend::loopback::LoopbackEndpoint;
+    use net_backend::tests::Bufs;
+    use net_backend::tests::test_layout;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use std::time::Duration;
+
+    const TX_GPA: u64 = 0x8000;
+
+    async fn loopback_queue(
+        driver: &DefaultDriver,
+        config: ImpairmentConfig,
+    ) -> (Box<dyn Queue>, Bufs) {
+        let mut endpoint =
+            ImpairEndpoint::new(Box::new(LoopbackEndpoint::new()), config, 0, None).unwrap();
+        let mut queues = Vec::new();
+        endpoint
+            .get_queues(
+                vec![QueueConfig {
+                    driver: Box::new(driver.clone()),
+                }],
+                None,
+                &mut queues,
+            )
+            .await
+            .unwrap();
+        let mem = GuestMemory::allocate(test_layout().end_of_ram() as usize);
+        let mut pool = Bufs::new(mem);
+        let mut queue = queues.pop().unwrap();
+        queue.rx_avail(&mut pool, &[RxId(1), RxId(2), RxId(3), RxId(4)]);
+        (queue, pool)
+    }
+
+    fn send(queue: &mut dyn Queue, pool: &mut Bufs, data: &[u8]) {
+        pool.guest_memory().write_at(TX_GPA, data).unwrap();
+        let segments = [TxSegment {
+            ty: TxSegmentType::Head(TxMetadata {
+                id: TxId(7),
+                segment_count: 1,
+                len: data.len() as u32,
+                ..Default::default()
+            }),
+            gpa: TX_GPA,
+            len: data.len() as u32,
+        }];
+        assert_eq!(queue.tx_avail(pool, &segments).unwrap(), (false, 1));
+    }
+
+    async fn wait_rx(queue: &mut dyn Queue, pool: &mut Bufs) -> Vec<RxId> {
+        loop {
+            poll_fn(|cx| queue.poll_ready(cx, pool)).await;
+            let mut packets = [RxId(0); 8];
+            let n = queue.rx_poll(pool, &mut packets).unwrap();
+            if n > 0 {
+                return packets[..n].to_vec();
+            }
+        }
+    }
+
+    fn tx_done(queue: &mut dyn Queue, pool: &mut Bufs) -> Vec<u32> {
+        let mut done = [TxId(0); 8];
+        let n = queue.tx_poll(pool, &mut done).unwrap();
+        done[..n].iter().map(|id| id.0).collect()
+    }
+
+    fn rx_data(pool: &Bufs, id: RxId) -> Vec<u8> {
+        let metadata = pool.rx_metadata(id).unwrap();
+        let mut data = vec![0; metadata.len];
+        pool.guest_memory()
+            .read_at(id.0 as u64 * 2048 + metadata.offset as u64, &mut data)
+            .unwrap();
+        data
+    }
+
+    #[async_test]
+    async fn passthrough(driver: DefaultDriver) {
+        let (mut queue, mut pool) = loopback_queue(&driver, ImpairmentConfig::default()).await;
+        send(queue.as_mut(), &mut pool, b"hello world");
+        let rx = wait_rx(queue.as_mut(), &mut pool).await;
+        assert_eq!(rx.len(), 1);
+        assert_eq!(rx_data(&pool, rx[0]), b"hello world");
+        assert_eq!(tx_done(queue.as_mut(), &mut pool), [7]);
+    }
+
+    #[async_test]
+    async fn loss(driver: DefaultDriver) {
+        let config = ImpairmentConfig {
+            tx: LinkImpairment {
+                loss: 1.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut queue, mut pool) = loopback_queue(&driver, config).await;
+        send(queue.as_mut(), &mut pool, b"lost");
+        // The dropped packet completes without being sent.
+        assert_eq!(tx_done(queue.as_mut(), &mut pool), [7]);
+        let mut packets = [RxId(0); 8];
+        assert_eq!(queue.rx_poll(&mut pool, &mut packets).unwrap(), 0);
+    }
+
+    #[async_test]
+    async fn delay(driver: DefaultDriver) {
+        let config = ImpairmentConfig {
+            rx: LinkImpairment {
+                delay: Duration::from_millis(20),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut queue, mut pool) = loopback_queue(&driver, config).await;
+        let start = Instant::now();
+        send(queue.as_mut(), &mut pool, b"slow");
+        let rx = wait_rx(queue.as_mut(), &mut pool).await;
+        assert!(Instant::now() - start >= Duration::from_millis(20));
+        assert_eq!(rx_data(&pool, rx[0]), b"slow");
+    }
+
+    #[async_test]
+    async fn rx_duplicate(driver: DefaultDriver) {
+        let config = ImpairmentConfig {
+            rx: LinkImpairment {
+                duplicate: 1.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut queue, mut pool) = loopback_queue(&driver, config).await;
+        send(queue.as_mut(), &mut pool, b"twice");
+        let rx = wait_rx(queue.as_mut(), &mut pool).await;
+        assert_eq!(rx.len(), 2);
+        for id in rx {
+            assert_eq!(rx_data(&pool, id), b"twice");
+        }
+    }
+
+    #[async_test]
+    async fn tx_duplicate(driver: DefaultDriver) {
+        let config = ImpairmentConfig {
+            tx: LinkImpairment {
+                duplicate: 1.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut queue, mut pool) = loopback_queue(&driver, config).await;
+        send(queue.as_mut(), &mut pool, b"twice");
+        let mut rx = wait_rx(queue.as_mut(), &mut pool).await;
+        if rx.len() < 2 {
+            rx.extend(wait_rx(queue.as_mut(), &mut pool).await);
+        }
+        assert_eq!(rx.len(), 2);
+        // The guest's packet completes once, after both copies are sent.
+        assert_eq!(tx_done(queue.as_mut(), &mut pool), [7]);
+    }
+
+    #[test]
+    fn invalid() {
+        let config = ImpairmentConfig {
+            rx: LinkImpairment {
+                loss: 1.5,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let err = validate(&config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "rx loss probability 1.5 is not between 0 and 1"
+        );
+    }
+}
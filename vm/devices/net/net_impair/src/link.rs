@@ -0,0 +1,366 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Per-direction packet scheduling: delay, jitter, loss, duplication,
+//! reordering, and rate limiting.
+
+use inspect::Inspect;
+use inspect_counters::Counter;
+use net_backend_resources::impair::LinkImpairment;
+use pal_async::timer::Instant;
+use std::cmp::Ordering;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::Duration;
+
+/// The maximum number of packets held in one direction of a queue. Further
+/// packets are dropped, as from a full router queue.
+pub const MAX_PENDING_PACKETS: usize = 1024;
+
+/// A small xorshift64* generator. Impairment decisions do not need to be
+/// unpredictable, and a fixed seed makes test runs reproducible.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Scramble the seed with a splitmix64 step so that nearby seeds (such
+        // as per-queue seeds) produce unrelated sequences, and so that the
+        // state is never zero.
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        Self((z ^ (z >> 31)) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// Returns a value uniformly distributed in `[0, 1)`.
+    fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns true with probability `p`.
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.uniform() < p
+    }
+}
+
+/// The outcome of scheduling a packet.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Drop the packet.
+    Drop,
+    /// Deliver the packet at the given time, and again at the same time if
+    /// `duplicate` is set.
+    Deliver { at: Instant, duplicate: bool },
+}
+
+#[derive(Inspect, Default)]
+pub struct LinkStats {
+    packets: Counter,
+    dropped: Counter,
+    duplicated: Counter,
+    reordered: Counter,
+    overflowed: Counter,
+}
+
+/// Packets waiting to be delivered in one direction, with the state needed to
+/// schedule new ones.
+pub struct Link<T> {
+    pending: BinaryHeap<Reverse<Pending<T>>>,
+    seq: u64,
+    /// Rate limiter tokens, in bytes, as of `tokens_time`.
+    tokens: f64,
+    tokens_time: Option<Instant>,
+    stats: LinkStats,
+}
+
+impl<T> Inspect for Link<T> {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("pending", self.pending.len())
+            .merge(&self.stats);
+    }
+}
+
+struct Pending<T> {
+    at: Instant,
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Pending<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Pending<T> {}
+
+impl<T> PartialOrd for Pending<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Pending<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+impl<T> Link<T> {
+    pub fn new() -> Self {
+        Self {
+            pending: BinaryHeap::new(),
+            seq: 0,
+            tokens: 0.0,
+            tokens_time: None,
+            stats: Default::default(),
+        }
+    }
+
+    /// Decides the fate of a `len`-byte packet arriving at `now`.
+    pub fn schedule(
+        &mut self,
+        config: &LinkImpairment,
+        rng: &mut Rng,
+        now: Instant,
+        len: usize,
+    ) -> Verdict {
+        self.stats.packets.increment();
+        if rng.chance(config.loss) {
+            self.stats.dropped.increment();
+            return Verdict::Drop;
+        }
+        if self.pending.len() >= MAX_PENDING_PACKETS {
+            self.stats.overflowed.increment();
+            return Verdict::Drop;
+        }
+
+        // A reordered packet skips the delay, overtaking the packets before
+        // it.
+        let mut at = if rng.chance(config.reorder) {
+            self.stats.reordered.increment();
+            now
+        } else {
+            let jitter = config.jitter.as_secs_f64() * (rng.uniform() * 2.0 - 1.0);
+            let delay = (config.delay.as_secs_f64() + jitter).max(0.0);
+            now.saturating_add(Duration::from_secs_f64(delay))
+        };
+
+        if config.rate != 0 {
+            at = self.shape(config, at, len);
+        }
+
+        let duplicate = rng.chance(config.duplicate);
+        if duplicate {
+            self.stats.duplicated.increment();
+        }
+        Verdict::Deliver { at, duplicate }
+    }
+
+    /// Applies the token bucket rate limit to a packet that is otherwise
+    /// ready at `at`, returning when it can be sent.
+    fn shape(&mut self, config: &LinkImpairment, at: Instant, len: usize) -> Instant {
+        let bytes_per_sec = config.rate as f64 / 8.0;
+        let burst = config.burst as f64;
+        // Packets leave the bucket in order.
+        let at = self.tokens_time.map_or(at, |t| at.max(t));
+        let elapsed = self
+            .tokens_time
+            .map_or(Duration::ZERO, |t| at.saturating_sub(t));
+        let tokens = (self.tokens + elapsed.as_secs_f64() * bytes_per_sec).min(burst);
+        let len = len as f64;
+        let (at, tokens) = if tokens >= len {
+            (at, tokens - len)
+        } else {
+            // Wait for enough tokens to accumulate.
+            let wait = (len - tokens) / bytes_per_sec;
+            (at.saturating_add(Duration::from_secs_f64(wait)), 0.0)
+        };
+        self.tokens = tokens;
+        self.tokens_time = Some(at);
+        at
+    }
+
+    /// Queues `item` for delivery at `at`.
+    pub fn push(&mut self, at: Instant, item: T) {
+        let seq = self.seq;
+        self.seq += 1;
+        self.pending.push(Reverse(Pending { at, seq, item }));
+    }
+
+    /// Removes the next packet due at or before `now`.
+    pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+        if self.pending.peek()?.0.at <= now {
+            Some(self.pending.pop().unwrap().0.item)
+        } else {
+            None
+        }
+    }
+
+    /// Returns when the next packet is due.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.peek().map(|p| p.0.at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_nanos(ms * 1_000_000)
+    }
+
+    fn deliver_time(verdict: Verdict) -> Instant {
+        match verdict {
+            Verdict::Deliver { at, .. } => at,
+            Verdict::Drop => panic!("packet dropped"),
+        }
+    }
+
+    #[test]
+    fn delay() {
+        let mut link = Link::<()>::new();
+        let mut rng = Rng::new(0);
+        let config = LinkImpairment {
+            delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+        assert_eq!(
+            link.schedule(&config, &mut rng, at(10), 100),
+            Verdict::Deliver {
+                at: at(60),
+                duplicate: false
+            }
+        );
+    }
+
+    #[test]
+    fn jitter_within_bounds() {
+        let mut link = Link::<()>::new();
+        let mut rng = Rng::new(1);
+        let config = LinkImpairment {
+            delay: Duration::from_millis(50),
+            jitter: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut distinct = std::collections::HashSet::new();
+        for _ in 0..100 {
+            let t = deliver_time(link.schedule(&config, &mut rng, at(100), 100));
+            assert!(t >= at(140) && t <= at(160), "{t:?}");
+            distinct.insert(t);
+        }
+        assert!(distinct.len() > 1);
+    }
+
+    #[test]
+    fn loss_rate() {
+        let mut link = Link::<()>::new();
+        let mut rng = Rng::new(2);
+        let config = LinkImpairment {
+            loss: 0.25,
+            ..Default::default()
+        };
+        let dropped = (0..10000)
+            .filter(|_| link.schedule(&config, &mut rng, at(0), 100) == Verdict::Drop)
+            .count();
+        assert!((2200..2800).contains(&dropped), "{dropped}");
+
+        let config = LinkImpairment {
+            loss: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(link.schedule(&config, &mut rng, at(0), 100), Verdict::Drop);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut link = Link::<()>::new();
+        let mut rng = Rng::new(3);
+        // 8 Mbit/s is 1000 bytes per millisecond.
+        let config = LinkImpairment {
+            rate: 8_000_000,
+            burst: 2000,
+            ..Default::default()
+        };
+        // The bucket starts empty, so the first packet waits.
+        assert_eq!(
+            deliver_time(link.schedule(&config, &mut rng, at(0), 1000)),
+            at(1)
+        );
+        // Back-to-back packets are serialized at the link rate.
+        assert_eq!(
+            deliver_time(link.schedule(&config, &mut rng, at(0), 1000)),
+            at(2)
+        );
+        // After an idle period, a burst goes out immediately.
+        assert_eq!(
+            deliver_time(link.schedule(&config, &mut rng, at(100), 1000)),
+            at(100)
+        );
+        assert_eq!(
+            deliver_time(link.schedule(&config, &mut rng, at(100), 1000)),
+            at(100)
+        );
+        assert_eq!(
+            deliver_time(link.schedule(&config, &mut rng, at(100), 1000)),
+            at(101)
+        );
+    }
+
+    #[test]
+    fn reorder_and_duplicate() {
+        let mut link = Link::<u32>::new();
+        let mut rng = Rng::new(4);
+        let config = LinkImpairment {
+            delay: Duration::from_millis(10),
+            reorder: 1.0,
+            duplicate: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            link.schedule(&config, &mut rng, at(5), 100),
+            Verdict::Deliver {
+                at: at(5),
+                duplicate: true
+            }
+        );
+    }
+
+    #[test]
+    fn delivery_order() {
+        let mut link = Link::new();
+        link.push(at(20), 1);
+        link.push(at(10), 2);
+        link.push(at(20), 3);
+        assert_eq!(link.next_deadline(), Some(at(10)));
+        assert_eq!(link.pop_due(at(5)), None);
+        assert_eq!(link.pop_due(at(20)), Some(2));
+        assert_eq!(link.pop_due(at(20)), Some(1));
+        assert_eq!(link.pop_due(at(20)), Some(3));
+        assert_eq!(link.pop_due(at(20)), None);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut link = Link::new();
+        let mut rng = Rng::new(5);
+        for i in 0..MAX_PENDING_PACKETS {
+            link.push(at(0), i);
+        }
+        assert_eq!(
+            link.schedule(&LinkImpairment::default(), &mut rng, at(0), 100),
+            Verdict::Drop
+        );
+    }
+}
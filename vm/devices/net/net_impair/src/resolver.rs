@@ -0,0 +1,40 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolver for the impairment endpoint.
+
+use crate::ImpairEndpoint;
+use async_trait::async_trait;
+use net_backend::resolve::ResolveEndpointParams;
+use net_backend::resolve::ResolvedEndpoint;
+use net_backend_resources::impair::ImpairHandle;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::NetEndpointHandleKind;
+
+/// A resolver for impairment endpoints.
+pub struct ImpairResolver;
+declare_static_async_resolver!(ImpairResolver, (NetEndpointHandleKind, ImpairHandle));
+
+#[async_trait]
+impl AsyncResolveResource<NetEndpointHandleKind, ImpairHandle> for ImpairResolver {
+    type Output = ResolvedEndpoint;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: ImpairHandle,
+        input: ResolveEndpointParams,
+    ) -> Result<Self::Output, Self::Error> {
+        let inner: ResolvedEndpoint = resolver.resolve(rsrc.endpoint, input).await?;
+        let seed = rsrc.seed.unwrap_or_else(|| {
+            let mut seed = [0; 8];
+            getrandom::fill(&mut seed).expect("rng failure");
+            u64::from_ne_bytes(seed)
+        });
+        let endpoint = ImpairEndpoint::new(inner.0, rsrc.config, seed, rsrc.recv)?;
+        Ok(endpoint.into())
+    }
+}
@@ -0,0 +1,450 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Packet capture filters.
+//!
+//! Filters use a subset of the `pcap-filter` (tcpdump/BPF) syntax, matching
+//! packets on their addresses, ports, and protocols:
+//!
+//! ```text
+//! expr      := term ("or" term)*
+//! term      := factor ("and" factor)*
+//! factor    := "not" factor | "(" expr ")" | primitive
+//! primitive := "ip" | "ip6" | "arp" | "tcp" | "udp" | "icmp" | "icmp6"
+//!            | ["src" | "dst"] "host" ADDRESS
+//!            | ["src" | "dst"] "net" ADDRESS "/" PREFIX_LEN
+//!            | ["src" | "dst"] "port" PORT ["-" PORT]
+//! ```
+//!
+//! `&&`, `||` and `!` may be used in place of `and`, `or` and `not`. For
+//! example, `tcp and port 443 and not host 10.0.0.1` captures HTTPS traffic
+//! except that of one host. IPv6 extension headers are not followed.
+
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// The number of bytes of a packet examined by a filter.
+pub(crate) const FILTER_HEADER_LEN: usize = 128;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const IP_PROTOCOL_ICMP: u8 = 1;
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+const IP_PROTOCOL_ICMPV6: u8 = 58;
+
+/// A parsed packet capture filter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketFilter(Expr);
+
+/// An error parsing a [`PacketFilter`].
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("unexpected end of filter")]
+    UnexpectedEnd,
+    #[error("unexpected '{0}' in filter")]
+    Unexpected(String),
+    #[error("invalid address '{0}'")]
+    InvalidAddress(String),
+    #[error("invalid network '{0}'")]
+    InvalidNetwork(String),
+    #[error("invalid port '{0}'")]
+    InvalidPort(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Primitive(Primitive),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Direction {
+    Src,
+    Dst,
+    Either,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Primitive {
+    Ip,
+    Ip6,
+    Arp,
+    Tcp,
+    Udp,
+    Icmp,
+    Icmp6,
+    Net(Direction, IpAddr, u8),
+    Port(Direction, u16, u16),
+}
+
+impl FromStr for PacketFilter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s);
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let expr = parser.expr()?;
+        if let Some(token) = parser.peek() {
+            return Err(FilterError::Unexpected(token.to_owned()));
+        }
+        Ok(Self(expr))
+    }
+}
+
+/// Splits a filter into words, with parentheses as separate tokens.
+fn tokenize(s: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    for word in s.split_whitespace() {
+        let mut rest = word;
+        while let Some(i) = rest.find(['(', ')']) {
+            if i > 0 {
+                tokens.push(&rest[..i]);
+            }
+            tokens.push(&rest[i..i + 1]);
+            rest = &rest[i + 1..];
+        }
+        if !rest.is_empty() {
+            tokens.push(rest);
+        }
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: &'a [&'a str],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<&'a str, FilterError> {
+        let token = self.peek().ok_or(FilterError::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, keywords: &[&str]) -> bool {
+        if self.peek().is_some_and(|t| keywords.contains(&t)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.term()?;
+        while self.eat(&["or", "||"]) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.term()?));
+        }
+        Ok(expr)
+    }
+
+    fn term(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.factor()?;
+        while self.eat(&["and", "&&"]) {
+            expr = Expr::And(Box::new(expr), Box::new(self.factor()?));
+        }
+        Ok(expr)
+    }
+
+    fn factor(&mut self) -> Result<Expr, FilterError> {
+        if self.eat(&["not", "!"]) {
+            return Ok(Expr::Not(Box::new(self.factor()?)));
+        }
+        if self.eat(&["("]) {
+            let expr = self.expr()?;
+            return match self.next()? {
+                ")" => Ok(expr),
+                token => Err(FilterError::Unexpected(token.to_owned())),
+            };
+        }
+        self.primitive().map(Expr::Primitive)
+    }
+
+    fn primitive(&mut self) -> Result<Primitive, FilterError> {
+        let direction = if self.eat(&["src"]) {
+            Direction::Src
+        } else if self.eat(&["dst"]) {
+            Direction::Dst
+        } else {
+            Direction::Either
+        };
+        let primitive = match (direction, self.next()?) {
+            (Direction::Either, "ip") => Primitive::Ip,
+            (Direction::Either, "ip6") => Primitive::Ip6,
+            (Direction::Either, "arp") => Primitive::Arp,
+            (Direction::Either, "tcp") => Primitive::Tcp,
+            (Direction::Either, "udp") => Primitive::Udp,
+            (Direction::Either, "icmp") => Primitive::Icmp,
+            (Direction::Either, "icmp6") => Primitive::Icmp6,
+            (_, "host") => {
+                let value = self.next()?;
+                let addr: IpAddr = value
+                    .parse()
+                    .map_err(|_| FilterError::InvalidAddress(value.to_owned()))?;
+                let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+                Primitive::Net(direction, addr, prefix_len)
+            }
+            (_, "net") => {
+                let value = self.next()?;
+                let (addr, prefix_len) = value
+                    .split_once('/')
+                    .and_then(|(addr, len)| Some((addr.parse().ok()?, len.parse().ok()?)))
+                    .filter(|&(addr, len): &(IpAddr, u8)| {
+                        len <= if addr.is_ipv4() { 32 } else { 128 }
+                    })
+                    .ok_or_else(|| FilterError::InvalidNetwork(value.to_owned()))?;
+                Primitive::Net(direction, addr, prefix_len)
+            }
+            (_, "port") => {
+                let value = self.next()?;
+                let parse = |s: &str| {
+                    s.parse::<u16>()
+                        .map_err(|_| FilterError::InvalidPort(value.to_owned()))
+                };
+                let (low, high) = match value.split_once('-') {
+                    Some((low, high)) => (parse(low)?, parse(high)?),
+                    None => (parse(value)?, parse(value)?),
+                };
+                if low > high {
+                    return Err(FilterError::InvalidPort(value.to_owned()));
+                }
+                Primitive::Port(direction, low, high)
+            }
+            (_, token) => return Err(FilterError::Unexpected(token.to_owned())),
+        };
+        Ok(primitive)
+    }
+}
+
+/// The fields of a packet that filters match on.
+#[derive(Default)]
+struct PacketFields {
+    ethertype: u16,
+    protocol: Option<u8>,
+    src: Option<IpAddr>,
+    dst: Option<IpAddr>,
+    src_port: Option<u16>,
+    dst_port: Option<u16>,
+}
+
+impl PacketFields {
+    fn parse(packet: &[u8]) -> Self {
+        let mut fields = Self::default();
+        let Some(mut ethertype) = read_u16(packet, 12) else {
+            return fields;
+        };
+        let mut offset = 14;
+        if ethertype == ETHERTYPE_VLAN {
+            let Some(inner) = read_u16(packet, 16) else {
+                return fields;
+            };
+            ethertype = inner;
+            offset += 4;
+        }
+        fields.ethertype = ethertype;
+        let l3 = &packet[offset..];
+        let l4 = match ethertype {
+            ETHERTYPE_IPV4 if l3.len() >= 20 => {
+                fields.protocol = Some(l3[9]);
+                fields.src = Some(Ipv4Addr::from(<[u8; 4]>::try_from(&l3[12..16]).unwrap()).into());
+                fields.dst = Some(Ipv4Addr::from(<[u8; 4]>::try_from(&l3[16..20]).unwrap()).into());
+                // Only the first fragment has the transport header.
+                let fragment_offset = read_u16(l3, 6).unwrap() & 0x1fff;
+                let header_len = (l3[0] & 0xf) as usize * 4;
+                (fragment_offset == 0)
+                    .then(|| l3.get(header_len..))
+                    .flatten()
+            }
+            ETHERTYPE_IPV6 if l3.len() >= 40 => {
+                fields.protocol = Some(l3[6]);
+                fields.src = Some(Ipv6Addr::from(<[u8; 16]>::try_from(&l3[8..24]).unwrap()).into());
+                fields.dst =
+                    Some(Ipv6Addr::from(<[u8; 16]>::try_from(&l3[24..40]).unwrap()).into());
+                Some(&l3[40..])
+            }
+            // IPv4 over Ethernet: the sender and target protocol addresses.
+            ETHERTYPE_ARP if l3.len() >= 28 && read_u16(l3, 2) == Some(ETHERTYPE_IPV4) => {
+                fields.src = Some(Ipv4Addr::from(<[u8; 4]>::try_from(&l3[14..18]).unwrap()).into());
+                fields.dst = Some(Ipv4Addr::from(<[u8; 4]>::try_from(&l3[24..28]).unwrap()).into());
+                None
+            }
+            _ => None,
+        };
+        if let Some(l4) = l4
+            && matches!(fields.protocol, Some(IP_PROTOCOL_TCP | IP_PROTOCOL_UDP))
+        {
+            fields.src_port = read_u16(l4, 0);
+            fields.dst_port = read_u16(l4, 2);
+        }
+        fields
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+fn in_network(addr: Option<IpAddr>, net: IpAddr, prefix_len: u8) -> bool {
+    match (addr, net) {
+        (Some(IpAddr::V4(addr)), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(net) & mask
+        }
+        (Some(IpAddr::V6(addr)), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+fn in_range(port: Option<u16>, low: u16, high: u16) -> bool {
+    port.is_some_and(|port| (low..=high).contains(&port))
+}
+
+impl Primitive {
+    fn matches(&self, fields: &PacketFields) -> bool {
+        match *self {
+            Primitive::Ip => fields.ethertype == ETHERTYPE_IPV4,
+            Primitive::Ip6 => fields.ethertype == ETHERTYPE_IPV6,
+            Primitive::Arp => fields.ethertype == ETHERTYPE_ARP,
+            Primitive::Tcp => fields.protocol == Some(IP_PROTOCOL_TCP),
+            Primitive::Udp => fields.protocol == Some(IP_PROTOCOL_UDP),
+            Primitive::Icmp => {
+                fields.ethertype == ETHERTYPE_IPV4 && fields.protocol == Some(IP_PROTOCOL_ICMP)
+            }
+            Primitive::Icmp6 => {
+                fields.ethertype == ETHERTYPE_IPV6 && fields.protocol == Some(IP_PROTOCOL_ICMPV6)
+            }
+            Primitive::Net(direction, net, prefix_len) => {
+                let src = in_network(fields.src, net, prefix_len);
+                let dst = in_network(fields.dst, net, prefix_len);
+                match direction {
+                    Direction::Src => src,
+                    Direction::Dst => dst,
+                    Direction::Either => src || dst,
+                }
+            }
+            Primitive::Port(direction, low, high) => {
+                let src = in_range(fields.src_port, low, high);
+                let dst = in_range(fields.dst_port, low, high);
+                match direction {
+                    Direction::Src => src,
+                    Direction::Dst => dst,
+                    Direction::Either => src || dst,
+                }
+            }
+        }
+    }
+}
+
+impl Expr {
+    fn matches(&self, fields: &PacketFields) -> bool {
+        match self {
+            Expr::Or(a, b) => a.matches(fields) || b.matches(fields),
+            Expr::And(a, b) => a.matches(fields) && b.matches(fields),
+            Expr::Not(a) => !a.matches(fields),
+            Expr::Primitive(p) => p.matches(fields),
+        }
+    }
+}
+
+impl PacketFilter {
+    /// Returns whether the Ethernet frame `packet` matches the filter.
+    ///
+    /// Only the first [`FILTER_HEADER_LEN`] bytes are examined.
+    pub fn matches(&self, packet: &[u8]) -> bool {
+        let packet = &packet[..packet.len().min(FILTER_HEADER_LEN)];
+        self.0.matches(&PacketFields::parse(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PacketFilter;
+
+    fn ipv4_packet(protocol: u8, src: [u8; 4], dst: [u8; 4], ports: (u16, u16)) -> Vec<u8> {
+        let mut packet = vec![0; 14 + 20 + 8];
+        packet[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        let ip = &mut packet[14..];
+        ip[0] = 0x45;
+        ip[9] = protocol;
+        ip[12..16].copy_from_slice(&src);
+        ip[16..20].copy_from_slice(&dst);
+        ip[20..22].copy_from_slice(&ports.0.to_be_bytes());
+        ip[22..24].copy_from_slice(&ports.1.to_be_bytes());
+        packet
+    }
+
+    fn filter(s: &str) -> PacketFilter {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn five_tuple() {
+        let https = ipv4_packet(6, [10, 0, 0, 2], [93, 184, 216, 34], (50000, 443));
+        let dns = ipv4_packet(17, [10, 0, 0, 2], [10, 0, 0, 1], (50001, 53));
+
+        let f = filter("tcp and dst port 443 and src host 10.0.0.2");
+        assert!(f.matches(&https));
+        assert!(!f.matches(&dns));
+
+        let f = filter("udp && port 53");
+        assert!(!f.matches(&https));
+        assert!(f.matches(&dns));
+
+        let f = filter("net 10.0.0.0/24 and not (port 443 or port 80)");
+        assert!(!f.matches(&https));
+        assert!(f.matches(&dns));
+
+        let f = filter("dst net 10.0.0.0/8 or src port 50000-50010");
+        assert!(f.matches(&https));
+        assert!(f.matches(&dns));
+
+        assert!(!filter("ip6 or arp").matches(&https));
+        assert!(filter("!icmp").matches(&https));
+    }
+
+    #[test]
+    fn vlan_tagged() {
+        let mut packet = ipv4_packet(6, [10, 0, 0, 2], [10, 0, 0, 3], (1, 2));
+        packet.splice(12..12, [0x81, 0x00, 0x00, 0x05]);
+        assert!(filter("tcp and host 10.0.0.3").matches(&packet));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for s in [
+            "",
+            "tcp and",
+            "(tcp",
+            "tcp)",
+            "host 10.0.0",
+            "net 10.0.0.0/33",
+            "port 70000",
+            "port 10-5",
+            "src tcp",
+            "bogus",
+        ] {
+            assert!(s.parse::<PacketFilter>().is_err(), "{s}");
+        }
+    }
+}
@@ -6,11 +6,20 @@
 #![expect(missing_docs)]
 #![forbid(unsafe_code)]
 
+mod filter;
+pub mod resolver;
+
+pub use filter::FilterError;
+pub use filter::PacketFilter;
+
+use anyhow::Context as _;
 use async_trait::async_trait;
+use filter::FILTER_HEADER_LEN;
 use futures::FutureExt;
 use futures::StreamExt;
 use futures::lock::Mutex;
 use futures_concurrency::future::Race;
+use guestmem::GuestMemory;
 use inspect::InspectMut;
 use mesh::error::RemoteError;
 use mesh::rpc::FailableRpc;
@@ -28,6 +37,7 @@
 use net_backend::TxOffloadSupport;
 use net_backend::TxSegment;
 use net_backend::next_packet;
+use net_backend_resources::packet_capture::PacketCaptureRequest;
 use pcap_file::DataLink;
 use pcap_file::PcapError;
 use pcap_file::PcapResult;
@@ -61,6 +71,9 @@ pub enum PacketCaptureOperation {
 #[derive(Debug, mesh::MeshPayload)]
 pub struct StartData<W: Write> {
     pub snaplen: u32,
+    /// An optional [`PacketFilter`] expression selecting the packets to
+    /// capture.
+    pub filter: Option<String>,
     pub writers: Vec<W>,
 }
 
@@ -105,11 +118,16 @@ fn write_pcapng_block_id(&mut self, block: InterfaceDescriptionBlock<'_>) -> Pca
 struct PacketCaptureOptions {
     operation: PacketCaptureOperation,
     snaplen: usize,
+    filter: Option<Arc<PacketFilter>>,
     writer: Option<Box<dyn PcapWriter>>,
 }
 
 impl PacketCaptureOptions {
-    fn new_with_start<W: Write + Send + Sync + 'static>(snaplen: u32, writer: W) -> Self {
+    fn new_with_start<W: Write + Send + Sync + 'static>(
+        snaplen: u32,
+        filter: Option<PacketFilter>,
+        writer: W,
+    ) -> Self {
         //TODO: Native endianness?
         let pcap_ng_writer =
             PcapNgWriter::with_endianness(writer, pcap_file::Endianness::Big).unwrap();
@@ -121,6 +139,7 @@ fn new_with_start<W: Write + Send + Sync + 'static>(snaplen: u32, writer: W) ->
         Self {
             operation: PacketCaptureOperation::Start,
             snaplen: snaplen as usize,
+            filter: filter.map(Arc::new),
             writer: Some(Box::new(local_writer)),
         }
     }
@@ -129,6 +148,7 @@ fn new_with_stop() -> Self {
         Self {
             operation: PacketCaptureOperation::Stop,
             snaplen: 0,
+            filter: None,
             writer: None,
         }
     }
@@ -167,8 +187,9 @@ pub async fn packet_capture<W: Write + Send + Sync + 'static>(
                         if data.writers.is_empty() {
                             anyhow::bail!("Insufficient streams");
                         }
+                        let filter = parse_filter(data.filter.as_deref())?;
                         let socket = data.writers.remove(0);
-                        PacketCaptureOptions::new_with_start(data.snaplen, socket)
+                        PacketCaptureOptions::new_with_start(data.snaplen, filter, socket)
                     }
                 }
             }
@@ -183,12 +204,21 @@ pub async fn packet_capture<W: Write + Send + Sync + 'static>(
     }
 }
 
+fn parse_filter(filter: Option<&str>) -> anyhow::Result<Option<PacketFilter>> {
+    filter
+        .map(str::parse)
+        .transpose()
+        .context("invalid packet capture filter")
+}
+
 pub struct PacketCaptureEndpoint {
     /// Some identifier that this endpoint can identify itself using for things
     /// like tracing, filtering etc..
     id: String,
     endpoint: Box<dyn Endpoint>,
     control_rx: Arc<Mutex<mesh::Receiver<PacketCaptureEndpointCommand>>>,
+    /// Requests from a [`PacketCaptureHandle`](net_backend_resources::packet_capture::PacketCaptureHandle).
+    requests: Option<mesh::Receiver<PacketCaptureRequest>>,
     pcap: Arc<Pcap>,
 }
 
@@ -210,6 +240,7 @@ pub fn new(endpoint: Box<dyn Endpoint>, id: String) -> (Self, PacketCaptureEndpo
                 id,
                 endpoint,
                 control_rx: Arc::new(Mutex::new(control_rx)),
+                requests: None,
                 pcap,
             },
             control,
@@ -220,6 +251,35 @@ fn current(&self) -> &dyn Endpoint {
         self.endpoint.as_ref()
     }
 
+    /// Applies new capture options, returning whether the queues must be
+    /// recreated.
+    fn apply(&self, options: PacketCaptureOptions) -> anyhow::Result<bool> {
+        let id = &self.id;
+        let start = match options.operation {
+            PacketCaptureOperation::Start => {
+                tracing::info!(id, "starting trace");
+                true
+            }
+            PacketCaptureOperation::Stop => {
+                tracing::info!(id, "stopping trace");
+                false
+            }
+            _ => anyhow::bail!("Unexpected packet capture option {id}"),
+        };
+
+        // Keep the lock until all values are being set to make the update atomic.
+        let mut pcap_writer = self.pcap.pcap_writer.lock();
+        let restart_required = start != self.pcap.enabled.load(Ordering::Relaxed);
+        self.pcap.snaplen.store(options.snaplen, Ordering::Relaxed);
+        *self.pcap.filter.lock() = options.filter;
+        self.pcap
+            .interface_descriptor_written
+            .store(false, Ordering::Relaxed);
+        self.pcap.enabled.store(start, Ordering::Relaxed);
+        *pcap_writer = options.writer;
+        Ok(restart_required)
+    }
+
     fn current_mut(&mut self) -> &mut dyn Endpoint {
         self.endpoint.as_mut()
     }
@@ -288,6 +348,7 @@ async fn get_data_path_to_guest_vf(&self) -> anyhow::Result<bool> {
     async fn wait_for_endpoint_action(&mut self) -> EndpointAction {
         enum Message {
             PacketCaptureEndpointCommand(PacketCaptureEndpointCommand),
+            Request(PacketCaptureRequest),
             UpdateFromEndpoint(EndpointAction),
         }
         loop {
@@ -302,52 +363,48 @@ enum Message {
                     }
                 }
             };
+            let requests = self.requests.as_mut();
+            let request = async {
+                match requests {
+                    Some(requests) => match requests.next().await {
+                        Some(req) => Message::Request(req),
+                        None => std::future::pending().await,
+                    },
+                    None => std::future::pending().await,
+                }
+            };
             let ep_update = self
-                .current_mut()
+                .endpoint
                 .wait_for_endpoint_action()
                 .map(Message::UpdateFromEndpoint);
-            let m = (update, ep_update).race().await;
-            match m {
+            let m = (update, request, ep_update).race().await;
+            let (options, response) = match m {
                 Message::PacketCaptureEndpointCommand(
                     PacketCaptureEndpointCommand::PacketCapture(rpc),
                 ) => {
                     let (options, response) = rpc.split();
-                    let result = async {
-                        let id = &self.id;
-                        let start = match options.operation {
-                            PacketCaptureOperation::Start => {
-                                tracing::info!(id, "starting trace");
-                                true
-                            }
-                            PacketCaptureOperation::Stop => {
-                                tracing::info!(id, "stopping trace");
-                                false
-                            }
-                            _ => Err(anyhow::anyhow!("Unexpected packet capture option {id}"))?,
-                        };
-
-                        // Keep the lock until all values are being set to make the update atomic.
-                        let mut pcap_writer = self.pcap.pcap_writer.lock();
-                        let restart_required = start != self.pcap.enabled.load(Ordering::Relaxed);
-                        self.pcap.snaplen.store(options.snaplen, Ordering::Relaxed);
-                        self.pcap
-                            .interface_descriptor_written
-                            .store(false, Ordering::Relaxed);
-                        self.pcap.enabled.store(start, Ordering::Relaxed);
-                        *pcap_writer = options.writer;
-                        anyhow::Ok(restart_required)
-                    }
-                    .await;
-                    let (result, restart_required) = match result {
-                        Err(e) => (Err(e), false),
-                        Ok(value) => (Ok(()), value),
-                    };
-                    response.complete(result.map_err(RemoteError::new));
-                    if restart_required {
-                        break EndpointAction::RestartRequired;
-                    }
+                    (Ok(options), response)
+                }
+                Message::Request(PacketCaptureRequest::Start(rpc)) => {
+                    let (start, response) = rpc.split();
+                    let options = parse_filter(start.filter.as_deref()).map(|filter| {
+                        PacketCaptureOptions::new_with_start(start.snaplen, filter, start.writer)
+                    });
+                    (options, response)
+                }
+                Message::Request(PacketCaptureRequest::Stop(rpc)) => {
+                    let ((), response) = rpc.split();
+                    (Ok(PacketCaptureOptions::new_with_stop()), response)
                 }
                 Message::UpdateFromEndpoint(update) => break update,
+            };
+            let (result, restart_required) = match options.and_then(|o| self.apply(o)) {
+                Err(e) => (Err(e), false),
+                Ok(value) => (Ok(()), value),
+            };
+            response.complete(result.map_err(RemoteError::new));
+            if restart_required {
+                break EndpointAction::RestartRequired;
             }
         }
     }
@@ -364,6 +421,7 @@ struct Pcap {
     interface_descriptor_written: AtomicBool,
     enabled: AtomicBool,
     snaplen: AtomicUsize,
+    filter: parking_lot::Mutex<Option<Arc<PacketFilter>>>,
     endpoint_control: mesh::Sender<PacketCaptureEndpointCommand>,
 }
 
@@ -372,12 +430,71 @@ fn new(endpoint_control: mesh::Sender<PacketCaptureEndpointCommand>) -> Self {
         Self {
             enabled: AtomicBool::new(false),
             snaplen: AtomicUsize::new(65535),
+            filter: parking_lot::Mutex::new(None),
             pcap_writer: parking_lot::Mutex::new(None),
             interface_descriptor_written: AtomicBool::new(false),
             endpoint_control,
         }
     }
 
+    /// Samples the capture settings for a batch of packets.
+    fn settings(&self) -> CaptureSettings {
+        CaptureSettings {
+            snaplen: self.snaplen.load(Ordering::Relaxed),
+            filter: self.filter.lock().clone(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::new(0, 0)),
+        }
+    }
+
+    /// Captures the packet in `segments` of guest memory, as `(gpa, len)`
+    /// pairs, if it matches the filter.
+    ///
+    /// Returns false if the capture has stopped.
+    fn capture(
+        &self,
+        guest_memory: &GuestMemory,
+        segments: impl IntoIterator<Item = (u64, usize)>,
+        original_len: u32,
+        settings: &CaptureSettings,
+    ) -> bool {
+        // Read enough for the filter to look at the headers, even if the
+        // snap length is shorter.
+        let read_len = if settings.filter.is_some() {
+            settings.snaplen.max(FILTER_HEADER_LEN)
+        } else {
+            settings.snaplen
+        };
+        let mut buf = vec![0; read_len];
+        let mut len = 0;
+        for (gpa, segment_len) in segments {
+            if len == buf.len() {
+                break;
+            }
+            let copy_length = std::cmp::min(buf.len() - len, segment_len);
+            let _ = guest_memory.read_at(gpa, &mut buf[len..len + copy_length]);
+            len += copy_length;
+        }
+
+        if len == 0 {
+            return true;
+        }
+        if let Some(filter) = &settings.filter
+            && !filter.matches(&buf[..len])
+        {
+            return true;
+        }
+
+        let len = len.min(settings.snaplen);
+        self.write_packet(
+            &buf[..len],
+            original_len,
+            settings.snaplen as u32,
+            &settings.timestamp,
+        )
+    }
+
     fn write_packet(
         &self,
         buf: &[u8],
@@ -440,6 +557,12 @@ fn write_packet(
     }
 }
 
+struct CaptureSettings {
+    snaplen: usize,
+    filter: Option<Arc<PacketFilter>>,
+    timestamp: Duration,
+}
+
 struct PacketCaptureQueue {
     queue: Box<dyn Queue>,
     pcap: Arc<Pcap>,
@@ -473,34 +596,18 @@ fn rx_poll(
     ) -> anyhow::Result<usize> {
         let n = self.current_mut().rx_poll(pool, packets)?;
         if self.pcap.enabled.load(Ordering::Relaxed) {
-            let timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or(Duration::new(0, 0));
-            let snaplen = self.pcap.snaplen.load(Ordering::Relaxed);
+            let settings = self.pcap.settings();
             for id in &packets[..n] {
-                let mut buf = vec![0; snaplen];
-                let mut len = 0;
-                let mut pkt_len = 0;
                 self.scratch_segments.clear();
                 pool.push_guest_addresses(*id, &mut self.scratch_segments);
-                for segment in &self.scratch_segments {
-                    pkt_len += segment.len;
-                    if len == buf.len() {
-                        continue;
-                    }
-
-                    let copy_length = std::cmp::min(buf.len() - len, segment.len as usize);
-                    let _ = pool.guest_memory().read_at(segment.gpa, &mut buf[len..]);
-                    len += copy_length;
-                }
-
-                if len == 0 {
-                    continue;
-                }
-
+                let pkt_len = self.scratch_segments.iter().map(|s| s.len).sum();
+                let segments = self
+                    .scratch_segments
+                    .iter()
+                    .map(|s| (s.gpa, s.len as usize));
                 if !self
                     .pcap
-                    .write_packet(&buf[..len], pkt_len, snaplen as u32, &timestamp)
+                    .capture(pool.guest_memory(), segments, pkt_len, &settings)
                 {
                     break;
                 }
@@ -516,35 +623,17 @@ fn tx_avail(
     ) -> anyhow::Result<(bool, usize)> {
         if self.pcap.enabled.load(Ordering::Relaxed) {
             let mut segments = segments;
-            let timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or(Duration::new(0, 0));
-            let snaplen = self.pcap.snaplen.load(Ordering::Relaxed);
+            let settings = self.pcap.settings();
             while !segments.is_empty() {
                 let (metadata, this, rest) = next_packet(segments);
                 segments = rest;
                 if metadata.len == 0 {
                     continue;
                 }
-                let mut buf = vec![0; snaplen];
-                let mut len = 0;
-                for segment in this {
-                    if len == buf.len() {
-                        break;
-                    }
-
-                    let copy_length = std::cmp::min(buf.len() - len, segment.len as usize);
-                    let _ = pool.guest_memory().read_at(segment.gpa, &mut buf[len..]);
-                    len += copy_length;
-                }
-
-                if len == 0 {
-                    continue;
-                }
-
+                let this = this.iter().map(|s| (s.gpa, s.len as usize));
                 if !self
                     .pcap
-                    .write_packet(&buf[..len], metadata.len, snaplen as u32, &timestamp)
+                    .capture(pool.guest_memory(), this, metadata.len, &settings)
                 {
                     break;
                 }
@@ -0,0 +1,40 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolver for the packet capture endpoint.
+
+use crate::PacketCaptureEndpoint;
+use async_trait::async_trait;
+use net_backend::resolve::ResolveEndpointParams;
+use net_backend::resolve::ResolvedEndpoint;
+use net_backend_resources::packet_capture::PacketCaptureHandle;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::NetEndpointHandleKind;
+
+/// A resolver for packet capture endpoints.
+pub struct PacketCaptureResolver;
+declare_static_async_resolver!(
+    PacketCaptureResolver,
+    (NetEndpointHandleKind, PacketCaptureHandle)
+);
+
+#[async_trait]
+impl AsyncResolveResource<NetEndpointHandleKind, PacketCaptureHandle> for PacketCaptureResolver {
+    type Output = ResolvedEndpoint;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: PacketCaptureHandle,
+        input: ResolveEndpointParams,
+    ) -> Result<Self::Output, Self::Error> {
+        let inner: ResolvedEndpoint = resolver.resolve(rsrc.endpoint, input).await?;
+        // Captures are controlled through the handle's request channel.
+        let (mut endpoint, _control) = PacketCaptureEndpoint::new(inner.0, rsrc.name);
+        endpoint.requests = Some(rsrc.recv);
+        Ok(endpoint.into())
+    }
+}
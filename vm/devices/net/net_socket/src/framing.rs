@@ -0,0 +1,246 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Ethernet frame framing on stream and datagram sockets.
+//!
+//! On stream sockets, each frame is preceded by its length as a 32-bit
+//! big-endian integer. This is the format used by QEMU's `-netdev stream`.
+
+use pal_async::interest::InterestSlot;
+use pal_async::interest::PollEvents;
+use pal_async::socket::PolledSocket;
+use socket2::SockAddr;
+use socket2::Socket;
+use std::io;
+use std::io::Read;
+use std::ops::Range;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+
+/// The size of the length prefix of each frame on a stream socket.
+const LENGTH_PREFIX: usize = 4;
+
+/// The largest frame accepted. This allows for jumbo frames and leaves room
+/// for frames from backends that do not segment TCP.
+pub const MAX_FRAME_SIZE: usize = 65535;
+
+/// The most data buffered for sending on a stream socket. Frames beyond this
+/// are dropped, as from a full NIC queue.
+const MAX_PENDING_SEND: usize = 1024 * 1024;
+
+/// Reassembles frames from a stream socket.
+pub struct FrameReader {
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![0; 2 * (LENGTH_PREFIX + MAX_FRAME_SIZE)].into_boxed_slice(),
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Returns the range of the next complete frame in the buffer, if any.
+    fn next_buffered(&mut self) -> io::Result<Option<Range<usize>>> {
+        let data = &self.buf[self.start..self.end];
+        let Some(prefix) = data.get(..LENGTH_PREFIX) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame length {len} is too large"),
+            ));
+        }
+        if data.len() < LENGTH_PREFIX + len {
+            return Ok(None);
+        }
+        let frame = self.start + LENGTH_PREFIX..self.start + LENGTH_PREFIX + len;
+        self.start = frame.end;
+        Ok(Some(frame))
+    }
+
+    /// Polls for the next frame, returning its range for use with
+    /// [`Self::frame`], or `None` if the peer closed the connection.
+    pub fn poll_next(
+        &mut self,
+        cx: &mut Context<'_>,
+        socket: &mut PolledSocket<Socket>,
+    ) -> Poll<io::Result<Option<Range<usize>>>> {
+        loop {
+            if let Some(frame) = self.next_buffered()? {
+                return Poll::Ready(Ok(Some(frame)));
+            }
+            // Make room for a whole frame.
+            if self.buf.len() - self.start < LENGTH_PREFIX + MAX_FRAME_SIZE {
+                self.buf.copy_within(self.start..self.end, 0);
+                self.end -= self.start;
+                self.start = 0;
+            }
+            let n = ready!(
+                socket.poll_io(cx, InterestSlot::Read, PollEvents::IN, |socket| socket
+                    .get()
+                    .read(&mut self.buf[self.end..]))
+            )?;
+            if n == 0 {
+                return Poll::Ready(Ok(None));
+            }
+            self.end += n;
+        }
+    }
+
+    /// Returns the frame at `range`, as returned by [`Self::poll_next`].
+    pub fn frame(&self, range: Range<usize>) -> &[u8] {
+        &self.buf[range]
+    }
+}
+
+/// Buffers frames for sending on a stream socket.
+pub struct FrameWriter {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl FrameWriter {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Queues `frame` for sending. Returns false, dropping the frame, if too
+    /// much data is already queued.
+    pub fn push(&mut self, frame: &[u8]) -> bool {
+        if self.buf.len() - self.pos + frame.len() > MAX_PENDING_SEND {
+            return false;
+        }
+        self.buf
+            .extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        self.buf.extend_from_slice(frame);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    /// Polls to send all the queued data.
+    pub fn poll_flush(
+        &mut self,
+        cx: &mut Context<'_>,
+        socket: &mut PolledSocket<Socket>,
+    ) -> Poll<io::Result<()>> {
+        while !self.is_empty() {
+            let n = ready!(
+                socket.poll_io(cx, InterestSlot::Write, PollEvents::OUT, |socket| socket
+                    .get()
+                    .send(&self.buf[self.pos..]))
+            )?;
+            self.pos += n;
+        }
+        self.buf.clear();
+        self.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Polls to receive a frame from a datagram socket into `buf`.
+pub fn poll_recv_datagram(
+    cx: &mut Context<'_>,
+    socket: &mut PolledSocket<Socket>,
+    buf: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    socket.poll_io(cx, InterestSlot::Read, PollEvents::IN, |socket| {
+        socket.get().read(buf)
+    })
+}
+
+/// Sends a frame on a datagram socket, to `remote` or to the connected peer.
+pub fn send_datagram(socket: &Socket, remote: Option<&SockAddr>, frame: &[u8]) -> io::Result<()> {
+    match remote {
+        Some(remote) => socket.send_to(frame, remote)?,
+        None => socket.send(frame)?,
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::poll_fn;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use socket2::Domain;
+    use socket2::Type;
+    use std::io::Write;
+
+    #[async_test]
+    async fn stream_round_trip(driver: DefaultDriver) {
+        let (a, b) = Socket::pair(Domain::UNIX, Type::STREAM, None).unwrap();
+        let mut a = PolledSocket::new(&driver, a).unwrap();
+        let mut b = PolledSocket::new(&driver, b).unwrap();
+
+        let mut writer = FrameWriter::new();
+        assert!(writer.push(b"first"));
+        assert!(writer.push(&[]));
+        assert!(writer.push(&[0xab; 3000]));
+        poll_fn(|cx| writer.poll_flush(cx, &mut a)).await.unwrap();
+        assert!(writer.is_empty());
+
+        let mut reader = FrameReader::new();
+        let mut next = async || {
+            let range = poll_fn(|cx| reader.poll_next(cx, &mut b))
+                .await
+                .unwrap()
+                .unwrap();
+            reader.frame(range).to_vec()
+        };
+        assert_eq!(next().await, b"first");
+        assert_eq!(next().await, b"");
+        assert_eq!(next().await, [0xab; 3000]);
+
+        drop(a);
+        assert!(
+            poll_fn(|cx| reader.poll_next(cx, &mut b))
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[async_test]
+    async fn stream_partial_writes(driver: DefaultDriver) {
+        let (mut a, b) = Socket::pair(Domain::UNIX, Type::STREAM, None).unwrap();
+        let mut b = PolledSocket::new(&driver, b).unwrap();
+        let mut reader = FrameReader::new();
+
+        // Send a frame a byte at a time.
+        for byte in [0, 0, 0, 2, 0x11, 0x22] {
+            a.write_all(&[byte]).unwrap();
+        }
+        let range = poll_fn(|cx| reader.poll_next(cx, &mut b))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reader.frame(range), [0x11, 0x22]);
+    }
+
+    #[async_test]
+    async fn stream_oversized_frame(driver: DefaultDriver) {
+        let (mut a, b) = Socket::pair(Domain::UNIX, Type::STREAM, None).unwrap();
+        let mut b = PolledSocket::new(&driver, b).unwrap();
+        let mut reader = FrameReader::new();
+        a.write_all(&0x10000u32.to_be_bytes()).unwrap();
+        let err = poll_fn(|cx| reader.poll_next(cx, &mut b))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,445 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! An endpoint that exchanges Ethernet frames over a Unix socket.
+//!
+//! This connects a NIC to another VM, or to the learning [`switch`], without
+//! needing privileges to create TAP devices or bridges. The framing matches
+//! QEMU's `-netdev stream` and `-netdev dgram`: a stream socket carries each
+//! frame preceded by its 32-bit big-endian length, and a datagram socket
+//! carries one frame per datagram.
+
+#![cfg(unix)]
+#![forbid(unsafe_code)]
+
+mod framing;
+pub mod resolver;
+pub mod switch;
+
+use async_trait::async_trait;
+use framing::FrameReader;
+use framing::FrameWriter;
+use framing::MAX_FRAME_SIZE;
+use inspect::Inspect;
+use inspect::InspectMut;
+use inspect_counters::Counter;
+use net_backend::BufferAccess;
+use net_backend::Endpoint;
+use net_backend::Queue;
+use net_backend::QueueConfig;
+use net_backend::RssConfig;
+use net_backend::RxId;
+use net_backend::RxMetadata;
+use net_backend::TxError;
+use net_backend::TxId;
+use net_backend::TxSegment;
+use net_backend::linearize;
+use pal_async::driver::Driver;
+use pal_async::socket::PolledSocket;
+use parking_lot::Mutex;
+use socket2::SockAddr;
+use socket2::Socket;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+
+/// How frames are carried on the socket.
+#[derive(Debug, Clone)]
+pub enum Framing {
+    /// A connected stream socket, with each frame preceded by its length.
+    Stream,
+    /// A datagram socket, with one frame per datagram. Frames are sent to
+    /// `remote`, or to the connected peer if `None`.
+    Datagram {
+        /// The address to send to.
+        remote: Option<SockAddr>,
+    },
+}
+
+/// An endpoint that exchanges Ethernet frames over a Unix socket.
+pub struct SocketEndpoint {
+    socket: Arc<Mutex<Option<Socket>>>,
+    framing: Framing,
+}
+
+impl SocketEndpoint {
+    /// Returns a new endpoint using `socket`, which must already be connected
+    /// (for stream sockets) or bound (for datagram sockets).
+    pub fn new(socket: Socket, framing: Framing) -> Self {
+        Self {
+            socket: Arc::new(Mutex::new(Some(socket))),
+            framing,
+        }
+    }
+}
+
+impl InspectMut for SocketEndpoint {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond().field(
+            "framing",
+            match self.framing {
+                Framing::Stream => "stream",
+                Framing::Datagram { .. } => "datagram",
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl Endpoint for SocketEndpoint {
+    fn endpoint_type(&self) -> &'static str {
+        "socket"
+    }
+
+    async fn get_queues(
+        &mut self,
+        mut config: Vec<QueueConfig>,
+        _rss: Option<&RssConfig<'_>>,
+        queues: &mut Vec<Box<dyn Queue>>,
+    ) -> anyhow::Result<()> {
+        assert_eq!(config.len(), 1);
+        let config = config.drain(..).next().unwrap();
+        queues.push(Box::new(SocketQueue::new(
+            config.driver.as_ref(),
+            self.socket.clone(),
+            self.framing.clone(),
+        )?));
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        assert!(self.socket.lock().is_some(), "queue has not been dropped");
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Inspect, Default)]
+struct QueueStats {
+    rx_packets: Counter,
+    rx_errors: Counter,
+    tx_packets: Counter,
+    tx_dropped: Counter,
+    tx_errors: Counter,
+}
+
+#[derive(InspectMut)]
+struct SocketQueue {
+    #[inspect(skip)]
+    slot: Arc<Mutex<Option<Socket>>>,
+    #[inspect(skip)]
+    socket: Option<PolledSocket<Socket>>,
+    #[inspect(skip)]
+    framing: Framing,
+    #[inspect(with = "|x| x.len()")]
+    rx_free: VecDeque<RxId>,
+    #[inspect(with = "|x| x.len()")]
+    rx_ready: VecDeque<RxId>,
+    #[inspect(skip)]
+    reader: FrameReader,
+    #[inspect(skip)]
+    writer: FrameWriter,
+    #[inspect(skip)]
+    datagram: Box<[u8]>,
+    /// The peer closed the connection or the socket failed.
+    disconnected: bool,
+    #[inspect(flatten)]
+    stats: QueueStats,
+}
+
+impl Drop for SocketQueue {
+    fn drop(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            *self.slot.lock() = Some(socket.into_inner());
+        }
+    }
+}
+
+impl SocketQueue {
+    fn new(
+        driver: &dyn Driver,
+        slot: Arc<Mutex<Option<Socket>>>,
+        framing: Framing,
+    ) -> io::Result<Self> {
+        let socket = slot.lock().take().expect("queue is already in use");
+        let socket = PolledSocket::new(driver, socket)?;
+        Ok(Self {
+            slot,
+            socket: Some(socket),
+            framing,
+            rx_free: VecDeque::new(),
+            rx_ready: VecDeque::new(),
+            reader: FrameReader::new(),
+            writer: FrameWriter::new(),
+            datagram: vec![0; MAX_FRAME_SIZE].into_boxed_slice(),
+            disconnected: false,
+            stats: Default::default(),
+        })
+    }
+
+    fn disconnect(&mut self, err: Option<io::Error>) {
+        match err {
+            Some(err) => tracing::warn!(
+                error = &err as &dyn std::error::Error,
+                "socket endpoint failed"
+            ),
+            None => tracing::info!("socket endpoint peer disconnected"),
+        }
+        self.disconnected = true;
+    }
+
+    /// Receives frames into free guest buffers.
+    fn poll_rx(&mut self, cx: &mut Context<'_>, pool: &mut dyn BufferAccess) {
+        let socket = self.socket.as_mut().unwrap();
+        while let Some(&id) = self.rx_free.front() {
+            let r = match self.framing {
+                Framing::Stream => match self.reader.poll_next(cx, socket) {
+                    Poll::Ready(Ok(Some(range))) => Ok(Some(self.reader.frame(range))),
+                    Poll::Ready(Ok(None)) => Ok(None),
+                    Poll::Ready(Err(err)) => Err(err),
+                    Poll::Pending => break,
+                },
+                Framing::Datagram { .. } => {
+                    match framing::poll_recv_datagram(cx, socket, &mut self.datagram) {
+                        Poll::Ready(Ok(n)) => Ok(Some(&self.datagram[..n])),
+                        Poll::Ready(Err(err)) => Err(err),
+                        Poll::Pending => break,
+                    }
+                }
+            };
+            match r {
+                Ok(Some(frame)) => {
+                    if frame.len() > pool.capacity(id) as usize {
+                        tracelimit::warn_ratelimited!(
+                            len = frame.len(),
+                            "frame too large for receive buffer"
+                        );
+                        self.stats.rx_errors.increment();
+                        continue;
+                    }
+                    pool.write_packet(
+                        id,
+                        &RxMetadata {
+                            offset: 0,
+                            len: frame.len(),
+                            ..Default::default()
+                        },
+                        frame,
+                    );
+                    self.stats.rx_packets.increment();
+                    self.rx_free.pop_front();
+                    self.rx_ready.push_back(id);
+                }
+                Ok(None) => {
+                    self.disconnect(None);
+                    break;
+                }
+                Err(err) => {
+                    self.disconnect(Some(err));
+                    break;
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        let socket = self.socket.as_ref().unwrap();
+        match &self.framing {
+            Framing::Stream => {
+                if !self.writer.push(frame) {
+                    self.stats.tx_dropped.increment();
+                    return;
+                }
+            }
+            Framing::Datagram { remote } => {
+                match framing::send_datagram(socket.get(), remote.as_ref(), frame) {
+                    Ok(()) => {}
+                    // The socket buffer is full, or nothing is listening at
+                    // the remote address yet. Drop the frame.
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::WouldBlock
+                                | io::ErrorKind::NotFound
+                                | io::ErrorKind::ConnectionRefused
+                        ) =>
+                    {
+                        self.stats.tx_dropped.increment();
+                        return;
+                    }
+                    Err(err) => {
+                        tracelimit::warn_ratelimited!(
+                            error = &err as &dyn std::error::Error,
+                            "socket send failed"
+                        );
+                        self.stats.tx_errors.increment();
+                        return;
+                    }
+                }
+            }
+        }
+        self.stats.tx_packets.increment();
+    }
+}
+
+impl Queue for SocketQueue {
+    fn poll_ready(&mut self, cx: &mut Context<'_>, pool: &mut dyn BufferAccess) -> Poll<()> {
+        if self.socket.is_some() && !self.disconnected {
+            if let Poll::Ready(Err(err)) = self.writer.poll_flush(cx, self.socket.as_mut().unwrap())
+            {
+                self.disconnect(Some(err));
+            } else {
+                self.poll_rx(cx, pool);
+            }
+        }
+        if self.rx_ready.is_empty() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+
+    fn rx_avail(&mut self, _pool: &mut dyn BufferAccess, done: &[RxId]) {
+        self.rx_free.extend(done);
+    }
+
+    fn rx_poll(
+        &mut self,
+        _pool: &mut dyn BufferAccess,
+        packets: &mut [RxId],
+    ) -> anyhow::Result<usize> {
+        let n = packets.len().min(self.rx_ready.len());
+        for (d, s) in packets.iter_mut().zip(self.rx_ready.drain(..n)) {
+            *d = s;
+        }
+        Ok(n)
+    }
+
+    fn tx_avail(
+        &mut self,
+        pool: &mut dyn BufferAccess,
+        mut segments: &[TxSegment],
+    ) -> anyhow::Result<(bool, usize)> {
+        let n = segments.len();
+        while !segments.is_empty() {
+            let frame = linearize(pool, &mut segments)?;
+            if self.socket.is_none() || self.disconnected {
+                self.stats.tx_dropped.increment();
+                continue;
+            }
+            self.send(&frame);
+        }
+        // Stream frames are copied into the send buffer, so every transmit
+        // completes synchronously. The buffer is flushed in `poll_ready`.
+        Ok((true, n))
+    }
+
+    fn tx_poll(
+        &mut self,
+        _pool: &mut dyn BufferAccess,
+        _done: &mut [TxId],
+    ) -> Result<usize, TxError> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::poll_fn;
+    use guestmem::GuestMemory;
+    use net_backend::TxMetadata;
+    use net_backend::TxSegmentType;
+    use net_backend::tests::Bufs;
+    use net_backend::tests::test_layout;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use socket2::Domain;
+    use socket2::Type;
+
+    const TX_GPA: u64 = 0x8000;
+
+    async fn queue(driver: &DefaultDriver, socket: Socket, framing: Framing) -> Box<dyn Queue> {
+        let mut endpoint = SocketEndpoint::new(socket, framing);
+        let mut queues = Vec::new();
+        endpoint
+            .get_queues(
+                vec![QueueConfig {
+                    driver: Box::new(driver.clone()),
+                }],
+                None,
+                &mut queues,
+            )
+            .await
+            .unwrap();
+        queues.pop().unwrap()
+    }
+
+    fn send(queue: &mut dyn Queue, pool: &mut Bufs, data: &[u8]) {
+        pool.guest_memory().write_at(TX_GPA, data).unwrap();
+        let segments = [TxSegment {
+            ty: TxSegmentType::Head(TxMetadata {
+                id: TxId(1),
+                segment_count: 1,
+                len: data.len() as u32,
+                ..Default::default()
+            }),
+            gpa: TX_GPA,
+            len: data.len() as u32,
+        }];
+        assert_eq!(queue.tx_avail(pool, &segments).unwrap(), (true, 1));
+    }
+
+    async fn receive(queue: &mut dyn Queue, pool: &mut Bufs) -> Vec<u8> {
+        poll_fn(|cx| queue.poll_ready(cx, pool)).await;
+        let mut packets = [RxId(0); 1];
+        assert_eq!(queue.rx_poll(pool, &mut packets).unwrap(), 1);
+        let metadata = pool.rx_metadata(packets[0]).unwrap();
+        let mut data = vec![0; metadata.len];
+        pool.guest_memory()
+            .read_at(packets[0].0 as u64 * 2048, &mut data)
+            .unwrap();
+        data
+    }
+
+    async fn exchange(driver: &DefaultDriver, ty: Type, framing: Framing) {
+        let (a, b) = Socket::pair(Domain::UNIX, ty, None).unwrap();
+        let mut a = queue(driver, a, framing.clone()).await;
+        let mut b = queue(driver, b, framing).await;
+        let mem = GuestMemory::allocate(test_layout().end_of_ram() as usize);
+        let mut pool = Bufs::new(mem);
+        a.rx_avail(&mut pool, &[RxId(1)]);
+        b.rx_avail(&mut pool, &[RxId(2)]);
+
+        send(a.as_mut(), &mut pool, b"from a");
+        // Flush the stream send buffer.
+        assert!(
+            poll_fn(|cx| Poll::Ready(a.poll_ready(cx, &mut pool)))
+                .await
+                .is_pending()
+        );
+        assert_eq!(receive(b.as_mut(), &mut pool).await, b"from a");
+
+        send(b.as_mut(), &mut pool, b"from b");
+        assert!(
+            poll_fn(|cx| Poll::Ready(b.poll_ready(cx, &mut pool)))
+                .await
+                .is_pending()
+        );
+        assert_eq!(receive(a.as_mut(), &mut pool).await, b"from b");
+    }
+
+    #[async_test]
+    async fn stream(driver: DefaultDriver) {
+        exchange(&driver, Type::STREAM, Framing::Stream).await;
+    }
+
+    #[async_test]
+    async fn datagram(driver: DefaultDriver) {
+        exchange(&driver, Type::DGRAM, Framing::Datagram { remote: None }).await;
+    }
+}
@@ -0,0 +1,39 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::Framing;
+use crate::SocketEndpoint;
+use net_backend::resolve::ResolveEndpointParams;
+use net_backend::resolve::ResolvedEndpoint;
+use net_backend_resources::socket::SocketFraming;
+use net_backend_resources::socket::SocketHandle;
+use socket2::SockAddr;
+use vm_resource::ResolveResource;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::NetEndpointHandleKind;
+
+pub struct SocketResolver;
+
+declare_static_resolver! {
+    SocketResolver,
+    (NetEndpointHandleKind, SocketHandle),
+}
+
+impl ResolveResource<NetEndpointHandleKind, SocketHandle> for SocketResolver {
+    type Output = ResolvedEndpoint;
+    type Error = std::io::Error;
+
+    fn resolve(
+        &self,
+        resource: SocketHandle,
+        _input: ResolveEndpointParams,
+    ) -> Result<Self::Output, Self::Error> {
+        let framing = match resource.framing {
+            SocketFraming::Stream => Framing::Stream,
+            SocketFraming::Datagram { remote } => Framing::Datagram {
+                remote: remote.map(SockAddr::unix).transpose()?,
+            },
+        };
+        Ok(SocketEndpoint::new(resource.socket.into(), framing).into())
+    }
+}
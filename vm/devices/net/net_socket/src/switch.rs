@@ -0,0 +1,293 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A learning Ethernet switch for stream socket endpoints.
+//!
+//! Each connection to the switch's listening socket is a port, using the same
+//! stream framing as [`Framing::Stream`](crate::Framing::Stream). Frames are
+//! forwarded to the port where their destination MAC address was last seen,
+//! or flooded to all other ports for broadcast, multicast, and unknown
+//! destinations.
+
+use crate::framing::FrameReader;
+use crate::framing::FrameWriter;
+use pal_async::driver::Driver;
+use pal_async::socket::PolledSocket;
+use socket2::Socket;
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::io;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How long a learned MAC address is remembered without being seen again.
+const MAC_AGE: Duration = Duration::from_secs(300);
+
+/// The most MAC addresses learned at once.
+const MAX_MACS: usize = 4096;
+
+/// The most frames read from one port before giving the others a turn.
+const FRAMES_PER_POLL: usize = 64;
+
+type MacAddress = [u8; 6];
+
+struct Port {
+    socket: PolledSocket<Socket>,
+    reader: FrameReader,
+    writer: FrameWriter,
+}
+
+struct Learned {
+    port: u64,
+    seen: Instant,
+}
+
+/// A learning Ethernet switch.
+pub struct Switch {
+    driver: Box<dyn Driver>,
+    listener: PolledSocket<Socket>,
+    ports: HashMap<u64, Port>,
+    next_port: u64,
+    macs: HashMap<MacAddress, Learned>,
+    frame: Vec<u8>,
+}
+
+impl Switch {
+    /// Returns a switch accepting ports on `listener`, a listening Unix
+    /// stream socket.
+    pub fn new(driver: impl Driver, listener: Socket) -> io::Result<Self> {
+        let listener = PolledSocket::new(&driver, listener)?;
+        Ok(Self {
+            driver: Box::new(driver),
+            listener,
+            ports: HashMap::new(),
+            next_port: 0,
+            macs: HashMap::new(),
+            frame: Vec::new(),
+        })
+    }
+
+    /// Runs the switch. Only returns if the listening socket fails.
+    pub async fn run(&mut self) -> io::Result<()> {
+        poll_fn(|cx| self.poll(cx)).await
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Poll::Ready((socket, _)) = self.listener.poll_accept(cx)? {
+            match PolledSocket::new(self.driver.as_ref(), socket) {
+                Ok(socket) => {
+                    let id = self.next_port;
+                    self.next_port += 1;
+                    tracing::info!(port = id, "switch port connected");
+                    self.ports.insert(
+                        id,
+                        Port {
+                            socket,
+                            reader: FrameReader::new(),
+                            writer: FrameWriter::new(),
+                        },
+                    );
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        error = &err as &dyn std::error::Error,
+                        "failed to add switch port"
+                    );
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let mut disconnected = Vec::new();
+        let mut yielded = false;
+        let ids = self.ports.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            for i in 0.. {
+                if i == FRAMES_PER_POLL {
+                    yielded = true;
+                    break;
+                }
+                let port = self.ports.get_mut(&id).unwrap();
+                match port.reader.poll_next(cx, &mut port.socket) {
+                    Poll::Ready(Ok(Some(range))) => {
+                        let mut frame = std::mem::take(&mut self.frame);
+                        frame.clear();
+                        frame.extend_from_slice(port.reader.frame(range));
+                        self.forward(id, &frame, now);
+                        self.frame = frame;
+                    }
+                    Poll::Ready(Ok(None)) => {
+                        disconnected.push((id, None));
+                        break;
+                    }
+                    Poll::Ready(Err(err)) => {
+                        disconnected.push((id, Some(err)));
+                        break;
+                    }
+                    Poll::Pending => break,
+                }
+            }
+        }
+
+        for (&id, port) in &mut self.ports {
+            if let Poll::Ready(Err(err)) = port.writer.poll_flush(cx, &mut port.socket) {
+                disconnected.push((id, Some(err)));
+            }
+        }
+
+        for (id, err) in disconnected {
+            if self.ports.remove(&id).is_none() {
+                continue;
+            }
+            match err {
+                Some(err) => tracing::warn!(
+                    port = id,
+                    error = &err as &dyn std::error::Error,
+                    "switch port failed"
+                ),
+                None => tracing::info!(port = id, "switch port disconnected"),
+            }
+            self.macs.retain(|_, learned| learned.port != id);
+        }
+
+        if yielded {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+
+    fn forward(&mut self, ingress: u64, frame: &[u8], now: Instant) {
+        let (Some(dst), Some(src)) = (frame.get(..6), frame.get(6..12)) else {
+            return;
+        };
+        let dst: MacAddress = dst.try_into().unwrap();
+        let src: MacAddress = src.try_into().unwrap();
+
+        if !is_multicast(&src) {
+            self.learn(src, ingress, now);
+        }
+
+        let egress = if is_multicast(&dst) {
+            None
+        } else {
+            self.macs
+                .get(&dst)
+                .filter(|learned| now.duration_since(learned.seen) < MAC_AGE)
+                .map(|learned| learned.port)
+        };
+
+        match egress {
+            // The destination is on the port the frame came from.
+            Some(port) if port == ingress => {}
+            Some(port) => {
+                if let Some(port) = self.ports.get_mut(&port) {
+                    port.writer.push(frame);
+                }
+            }
+            None => {
+                for (_, port) in self.ports.iter_mut().filter(|(&id, _)| id != ingress) {
+                    port.writer.push(frame);
+                }
+            }
+        }
+    }
+
+    fn learn(&mut self, mac: MacAddress, port: u64, now: Instant) {
+        if self.macs.len() >= MAX_MACS && !self.macs.contains_key(&mac) {
+            self.macs
+                .retain(|_, learned| now.duration_since(learned.seen) < MAC_AGE);
+            if self.macs.len() >= MAX_MACS {
+                return;
+            }
+        }
+        self.macs.insert(mac, Learned { port, seen: now });
+    }
+}
+
+fn is_multicast(mac: &MacAddress) -> bool {
+    mac[0] & 1 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::AsyncReadExt;
+    use futures::AsyncWriteExt;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use pal_async::task::Spawn;
+    use socket2::Domain;
+    use socket2::SockAddr;
+    use socket2::Type;
+
+    const BROADCAST: MacAddress = [0xff; 6];
+
+    fn frame(dst: MacAddress, src: MacAddress, payload: u8) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&src);
+        frame.extend_from_slice(&[0x88, 0xb5, payload]);
+        frame
+    }
+
+    fn mac(n: u8) -> MacAddress {
+        [0x02, 0, 0, 0, 0, n]
+    }
+
+    async fn send(socket: &mut PolledSocket<Socket>, frame: &[u8]) {
+        socket
+            .write_all(&(frame.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        socket.write_all(frame).await.unwrap();
+    }
+
+    async fn recv(socket: &mut PolledSocket<Socket>) -> Vec<u8> {
+        let mut len = [0; 4];
+        socket.read_exact(&mut len).await.unwrap();
+        let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+        socket.read_exact(&mut frame).await.unwrap();
+        frame
+    }
+
+    #[async_test]
+    async fn learning(driver: DefaultDriver) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("switch.sock");
+        let addr = SockAddr::unix(&path).unwrap();
+        let listener = Socket::new(Domain::UNIX, Type::STREAM, None).unwrap();
+        listener.bind(&addr).unwrap();
+        listener.listen(8).unwrap();
+        let mut switch = Switch::new(driver.clone(), listener).unwrap();
+        let _task = driver.spawn("switch", async move { switch.run().await });
+
+        let mut ports = Vec::new();
+        for _ in 0..3 {
+            let socket = Socket::new(Domain::UNIX, Type::STREAM, None).unwrap();
+            socket.connect(&addr).unwrap();
+            ports.push(PolledSocket::new(&driver, socket).unwrap());
+        }
+        let [a, b, c] = &mut ports[..] else {
+            unreachable!()
+        };
+
+        // Broadcasts are flooded to the other ports.
+        let hello = frame(BROADCAST, mac(1), 1);
+        send(a, &hello).await;
+        assert_eq!(recv(b).await, hello);
+        assert_eq!(recv(c).await, hello);
+
+        // A reply to a learned address goes only to that port.
+        let reply = frame(mac(1), mac(2), 2);
+        send(b, &reply).await;
+        assert_eq!(recv(a).await, reply);
+
+        // Unknown unicast destinations are flooded.
+        let unknown = frame(mac(9), mac(2), 3);
+        send(b, &unknown).await;
+        assert_eq!(recv(a).await, unknown);
+        assert_eq!(recv(c).await, unknown);
+    }
+}
@@ -8,6 +8,7 @@
 
 pub mod resolver;
 pub mod tap;
+pub mod vhost;
 
 use async_trait::async_trait;
 use futures::io::AsyncRead;
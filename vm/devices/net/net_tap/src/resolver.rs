@@ -3,9 +3,12 @@
 
 use crate::TapEndpoint;
 use crate::tap;
+use crate::vhost;
+use crate::vhost::VhostNetEndpoint;
 use net_backend::resolve::ResolveEndpointParams;
 use net_backend::resolve::ResolvedEndpoint;
 use net_backend_resources::tap::TapHandle;
+use net_backend_resources::tap::TapVhostHandle;
 use vm_resource::ResolveResource;
 use vm_resource::declare_static_resolver;
 use vm_resource::kind::NetEndpointHandleKind;
@@ -15,6 +18,7 @@
 declare_static_resolver! {
     TapResolver,
     (NetEndpointHandleKind, TapHandle),
+    (NetEndpointHandleKind, TapVhostHandle),
 }
 
 impl ResolveResource<NetEndpointHandleKind, TapHandle> for TapResolver {
@@ -31,3 +35,22 @@ fn resolve(
         Ok(TapEndpoint::new(tap)?.into())
     }
 }
+
+impl ResolveResource<NetEndpointHandleKind, TapVhostHandle> for TapResolver {
+    type Output = ResolvedEndpoint;
+    type Error = vhost::Error;
+
+    fn resolve(
+        &self,
+        resource: TapVhostHandle,
+        _input: ResolveEndpointParams,
+    ) -> Result<Self::Output, Self::Error> {
+        let queues = resource
+            .queues
+            .into_iter()
+            .map(|queue| Ok((tap::Tap::new(queue.tap)?, queue.vhost)))
+            .collect::<Result<_, vhost::Error>>()?;
+
+        Ok(VhostNetEndpoint::new(queues)?.into())
+    }
+}
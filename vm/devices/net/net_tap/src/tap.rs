@@ -21,6 +21,8 @@
 use std::fs::File;
 use std::io;
 use std::io::Write;
+use std::os::fd::AsFd;
+use std::os::fd::BorrowedFd;
 use std::os::fd::OwnedFd;
 use std::os::raw::c_short;
 use std::os::unix::prelude::AsRawFd;
@@ -57,6 +59,24 @@ pub enum Error {
 ///
 /// The fd is configured with `IFF_TAP | IFF_NO_PI | IFF_VNET_HDR`.
 pub fn open_tap(name: &str) -> Result<OwnedFd, Error> {
+    open_tap_with_flags(name, 0)
+}
+
+/// Opens `count` queues of a TAP interface by name and returns their fds.
+///
+/// A single queue is opened as by [`open_tap`]. Otherwise, each fd is also
+/// configured with `IFF_MULTI_QUEUE`, so an existing interface must have been
+/// created as a multiqueue interface.
+pub fn open_tap_queues(name: &str, count: usize) -> Result<Vec<OwnedFd>, Error> {
+    if count == 1 {
+        return Ok(vec![open_tap(name)?]);
+    }
+    (0..count)
+        .map(|_| open_tap_with_flags(name, gen_if_tun::IFF_MULTI_QUEUE))
+        .collect()
+}
+
+fn open_tap_with_flags(name: &str, flags: u32) -> Result<OwnedFd, Error> {
     let tap_file = std::fs::OpenOptions::new()
         .read(true)
         .write(true)
@@ -81,7 +101,7 @@ pub fn open_tap(name: &str) -> Result<OwnedFd, Error> {
         name_slice[i] = tap_name_bytes[i] as libc::c_char;
     }
     ifreq.ifr_ifru.ifru_flags =
-        (gen_if_tun::IFF_TAP | gen_if_tun::IFF_NO_PI | gen_if_tun::IFF_VNET_HDR) as c_short;
+        (gen_if_tun::IFF_TAP | gen_if_tun::IFF_NO_PI | gen_if_tun::IFF_VNET_HDR | flags) as c_short;
 
     // SAFETY: calling the ioctl according to implementation requirements.
     unsafe {
@@ -170,6 +190,12 @@ pub fn polled(self, driver: &(impl Driver + ?Sized)) -> io::Result<PolledTap> {
     }
 }
 
+impl AsFd for Tap {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.tap.as_fd()
+    }
+}
+
 /// A version of [`Tap`] that implements [`AsyncRead`].
 pub struct PolledTap {
     tap: PolledPipe,
@@ -0,0 +1,922 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A TAP endpoint accelerated by the kernel's vhost-net driver.
+//!
+//! [`TapEndpoint`](super::TapEndpoint) issues one `read` or `write` per frame.
+//! Here, each queue instead shares a pair of virtio rings with a vhost-net
+//! instance, and a kernel thread moves frames between the rings and a TAP
+//! queue. Frames are copied between guest buffers and ring buffers in this
+//! process, and the kernel is notified through an eventfd at most once per
+//! batch.
+
+// UNSAFETY: Issuing vhost ioctls and accessing memory shared with the kernel.
+#![expect(unsafe_code)]
+
+use crate::VirtioNetHdr;
+use crate::build_vnet_hdr;
+use crate::fixup_ipv4_header_checksum;
+use crate::fixup_ipv6_payload_length;
+use crate::parse_vnet_hdr;
+use crate::tap;
+use async_trait::async_trait;
+use inspect::InspectMut;
+use linux_net_bindings::vhost_get_features;
+use linux_net_bindings::vhost_memory;
+use linux_net_bindings::vhost_memory_region;
+use linux_net_bindings::vhost_net_set_backend;
+use linux_net_bindings::vhost_reset_owner;
+use linux_net_bindings::vhost_set_features;
+use linux_net_bindings::vhost_set_mem_table;
+use linux_net_bindings::vhost_set_owner;
+use linux_net_bindings::vhost_set_vring_addr;
+use linux_net_bindings::vhost_set_vring_base;
+use linux_net_bindings::vhost_set_vring_call;
+use linux_net_bindings::vhost_set_vring_kick;
+use linux_net_bindings::vhost_set_vring_num;
+use linux_net_bindings::vhost_vring_addr;
+use linux_net_bindings::vhost_vring_file;
+use linux_net_bindings::vhost_vring_state;
+use net_backend::BufferAccess;
+use net_backend::ETHERNET_VLAN_HEADER_LEN;
+use net_backend::Endpoint;
+use net_backend::MultiQueueSupport;
+use net_backend::Queue;
+use net_backend::QueueConfig;
+use net_backend::RssConfig;
+use net_backend::RxId;
+use net_backend::RxMetadata;
+use net_backend::TxError;
+use net_backend::TxId;
+use net_backend::TxOffloadSupport;
+use net_backend::TxSegment;
+use net_backend::next_packet;
+use pal_async::driver::Driver;
+use pal_async::wait::PolledWait;
+use pal_event::Event;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::os::fd::OwnedFd;
+use std::ptr::NonNull;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU16;
+use std::sync::atomic::Ordering;
+use std::sync::atomic::fence;
+use std::task::Context;
+use std::task::Poll;
+use thiserror::Error;
+use zerocopy::FromBytes;
+use zerocopy::IntoBytes;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to open /dev/vhost-net")]
+    OpenVhostNet(#[source] io::Error),
+    #[error("at least one TAP queue is required")]
+    NoQueues,
+    #[error(transparent)]
+    Tap(#[from] tap::Error),
+    #[error("vhost-net does not support required features {0:#x}")]
+    MissingFeatures(u64),
+    #[error("failed to allocate vhost-net rings")]
+    AllocateRings(#[source] io::Error),
+    #[error("failed to create vhost-net notification")]
+    Notification(#[source] io::Error),
+    #[error("{0} ioctl failed")]
+    Ioctl(&'static str, #[source] io::Error),
+}
+
+fn ioctl_error<E>(name: &'static str) -> impl FnOnce(E) -> Error {
+    move |_| Error::Ioctl(name, io::Error::last_os_error())
+}
+
+/// Opens the vhost-net control device, for passing to
+/// [`VhostNetEndpoint::new`] alongside a TAP queue.
+pub fn open_vhost_net() -> Result<OwnedFd, Error> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/vhost-net")
+        .map_err(Error::OpenVhostNet)?;
+    Ok(file.into())
+}
+
+const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// `VIRTIO_F_VERSION_1` fixes the header size at 12 bytes, matching the size
+/// set by [`tap::Tap::new`], and mergeable receive buffers let large frames
+/// span several small ring buffers.
+const FEATURES: u64 = VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MRG_RXBUF;
+
+const VRING_DESC_F_WRITE: u16 = 2;
+const VRING_USED_F_NO_NOTIFY: u16 = 1;
+
+const RX_VQ: usize = 0;
+const TX_VQ: usize = 1;
+
+/// The number of descriptors in each ring.
+const QUEUE_SIZE: u16 = 256;
+/// The size of each receive buffer.
+const RX_BUFFER_SIZE: usize = 4096;
+/// The size of the buffer that transmitted frames are copied into.
+const TX_ARENA_SIZE: usize = 1024 * 1024;
+/// The largest frame that can be transmitted: a segmentation offload packet
+/// with a maximum size IP datagram.
+const MAX_TX_FRAME: usize = ETHERNET_VLAN_HEADER_LEN as usize + 65535;
+
+const RX_RING_OFFSET: usize = 0;
+const TX_RING_OFFSET: usize = RX_RING_OFFSET + RingLayout::SIZE;
+const RX_BUFFERS_OFFSET: usize = TX_RING_OFFSET + RingLayout::SIZE;
+const TX_ARENA_OFFSET: usize = RX_BUFFERS_OFFSET + QUEUE_SIZE as usize * RX_BUFFER_SIZE;
+const MEMORY_SIZE: usize = TX_ARENA_OFFSET + TX_ARENA_SIZE;
+
+/// An endpoint that moves frames through the kernel's vhost-net driver.
+///
+/// Each queue pair is backed by one queue of a (possibly multiqueue) TAP
+/// interface and its own vhost-net instance.
+pub struct VhostNetEndpoint {
+    queues: Vec<Arc<Mutex<Option<QueueResources>>>>,
+}
+
+/// A TAP queue and the vhost-net instance that services it.
+struct QueueResources {
+    tap: tap::Tap,
+    vhost: File,
+}
+
+impl VhostNetEndpoint {
+    /// Creates an endpoint from pairs of TAP queues and vhost-net fds, as
+    /// returned by [`tap::open_tap_queues`] and [`open_vhost_net`].
+    pub fn new(queues: Vec<(tap::Tap, OwnedFd)>) -> Result<Self, Error> {
+        if queues.is_empty() {
+            return Err(Error::NoQueues);
+        }
+        let queues = queues
+            .into_iter()
+            .map(|(tap, vhost)| {
+                // Do not enable any RX offloads, for the reasons given in
+                // `TapEndpoint::new`.
+                tap.set_offloads(0)?;
+                let vhost = File::from(vhost);
+                let mut features = 0;
+                // SAFETY: calling the ioctl with a valid fd and correct
+                // argument type.
+                unsafe { vhost_get_features(vhost.as_raw_fd(), &mut features) }
+                    .map_err(ioctl_error("VHOST_GET_FEATURES"))?;
+                if features & FEATURES != FEATURES {
+                    return Err(Error::MissingFeatures(FEATURES & !features));
+                }
+                Ok(Arc::new(Mutex::new(Some(QueueResources { tap, vhost }))))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { queues })
+    }
+}
+
+impl InspectMut for VhostNetEndpoint {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond().field("queues", self.queues.len());
+    }
+}
+
+#[async_trait]
+impl Endpoint for VhostNetEndpoint {
+    fn endpoint_type(&self) -> &'static str {
+        "vhost-net"
+    }
+
+    async fn get_queues(
+        &mut self,
+        config: Vec<QueueConfig>,
+        _rss: Option<&RssConfig<'_>>,
+        queues: &mut Vec<Box<dyn Queue>>,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            config.len() <= self.queues.len(),
+            "requested {} queues but only {} TAP queues are available",
+            config.len(),
+            self.queues.len()
+        );
+        for (config, slot) in config.into_iter().zip(&self.queues) {
+            queues.push(Box::new(VhostQueue::new(
+                config.driver.as_ref(),
+                slot.clone(),
+            )?));
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        for slot in &self.queues {
+            assert!(slot.lock().is_some(), "queue has not been dropped");
+        }
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+
+    fn tx_offload_support(&self) -> TxOffloadSupport {
+        // Transmit offloads are passed to the TAP interface in the virtio
+        // header, as with `TapEndpoint`.
+        TxOffloadSupport {
+            ipv4_header: true,
+            tcp: true,
+            udp: true,
+            tso: true,
+            uso: true,
+        }
+    }
+
+    fn multiqueue_support(&self) -> MultiQueueSupport {
+        MultiQueueSupport {
+            max_queues: self.queues.len().try_into().unwrap_or(u16::MAX),
+            // The TAP driver spreads received flows across its queues itself,
+            // so there is no indirection table for the guest to program.
+            indirection_table_size: 0,
+        }
+    }
+}
+
+/// Anonymous memory holding the rings and buffers shared with the vhost-net
+/// worker thread.
+struct SharedMemory {
+    ptr: NonNull<u8>,
+    len: usize,
+    /// Set if the kernel may still be accessing the memory, so it must not be
+    /// unmapped.
+    leaked: bool,
+}
+
+// SAFETY: the memory is only accessed through `&self` and `&mut self` methods
+// that follow the ring protocol, so it can be sent and shared like a
+// `Vec<u8>`.
+unsafe impl Send for SharedMemory {}
+// SAFETY: see above.
+unsafe impl Sync for SharedMemory {}
+
+impl SharedMemory {
+    fn new(len: usize) -> io::Result<Self> {
+        // SAFETY: creating a new anonymous mapping, which aliases nothing.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: NonNull::new(ptr.cast()).unwrap(),
+            len,
+            leaked: false,
+        })
+    }
+
+    fn addr(&self, offset: usize) -> u64 {
+        self.ptr.as_ptr() as u64 + offset as u64
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + size_of::<T>() <= self.len);
+        self.ptr.as_ptr().wrapping_add(offset).cast()
+    }
+
+    /// Returns the bytes at `offset..offset + len`.
+    ///
+    /// # Safety
+    /// The caller must ensure that the kernel does not write the range while
+    /// the slice is live.
+    unsafe fn slice(&self, offset: usize, len: usize) -> &[u8] {
+        assert!(offset + len <= self.len);
+        // SAFETY: the range is within the mapping, and the caller guarantees
+        // it is not concurrently written.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().add(offset), len) }
+    }
+
+    /// Returns the bytes at `offset..offset + len` for writing.
+    ///
+    /// # Safety
+    /// The caller must ensure that the kernel does not access the range while
+    /// the slice is live.
+    unsafe fn slice_mut(&mut self, offset: usize, len: usize) -> &mut [u8] {
+        assert!(offset + len <= self.len);
+        // SAFETY: the range is within the mapping, and the caller guarantees
+        // it is not concurrently accessed.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr().add(offset), len) }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        if !self.leaked {
+            // SAFETY: unmapping the mapping created in `new`, which nothing
+            // references anymore.
+            unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+        }
+    }
+}
+
+/// The offsets of a split virtqueue's parts in [`SharedMemory`].
+#[derive(Copy, Clone)]
+struct RingLayout {
+    desc: usize,
+    avail: usize,
+    used: usize,
+}
+
+impl RingLayout {
+    /// Address, length, flags, and next index.
+    const DESC_SIZE: usize = 16 * QUEUE_SIZE as usize;
+    /// Flags, index, ring, and the unused event field.
+    const AVAIL_SIZE: usize = 4 + 2 * QUEUE_SIZE as usize + 2;
+    /// Flags, index, ring, and the unused event field.
+    const USED_SIZE: usize = 4 + 8 * QUEUE_SIZE as usize + 2;
+    /// The size of the ring, rounded up so that the next ring's descriptor
+    /// table is page aligned.
+    const SIZE: usize =
+        (Self::DESC_SIZE + (Self::AVAIL_SIZE.next_multiple_of(4)) + Self::USED_SIZE)
+            .next_multiple_of(4096);
+
+    const fn new(offset: usize) -> Self {
+        let avail = offset + Self::DESC_SIZE;
+        Self {
+            desc: offset,
+            avail,
+            used: (avail + Self::AVAIL_SIZE).next_multiple_of(4),
+        }
+    }
+}
+
+/// This side of a split virtqueue, as the driver.
+struct Ring {
+    layout: RingLayout,
+    next_avail: u16,
+    next_used: u16,
+}
+
+impl Ring {
+    fn new(offset: usize) -> Self {
+        Self {
+            layout: RingLayout::new(offset),
+            next_avail: 0,
+            next_used: 0,
+        }
+    }
+
+    fn write_descriptor(&self, mem: &SharedMemory, index: u16, addr: u64, len: u32, flags: u16) {
+        let desc = self.layout.desc + index as usize * 16;
+        // SAFETY: the descriptor is within the mapping and is not available
+        // to the kernel.
+        unsafe {
+            mem.ptr::<u64>(desc).write_volatile(addr.to_le());
+            mem.ptr::<u32>(desc + 8).write_volatile(len.to_le());
+            mem.ptr::<u16>(desc + 12).write_volatile(flags.to_le());
+            mem.ptr::<u16>(desc + 14).write_volatile(0);
+        }
+    }
+
+    /// Adds a descriptor to the available ring, without publishing it.
+    fn push_avail(&mut self, mem: &SharedMemory, index: u16) {
+        let slot = (self.next_avail % QUEUE_SIZE) as usize;
+        let entry = mem.ptr::<u16>(self.layout.avail + 4 + slot * 2);
+        // SAFETY: the entry is within the mapping and the kernel does not
+        // read it until the index is published.
+        unsafe { entry.write_volatile(index.to_le()) };
+        self.next_avail = self.next_avail.wrapping_add(1);
+    }
+
+    /// Publishes the descriptors added by `push_avail` to the kernel.
+    fn publish_avail(&self, mem: &SharedMemory) {
+        // SAFETY: the index is within the mapping and suitably aligned, and is
+        // only accessed atomically.
+        let idx = unsafe { AtomicU16::from_ptr(mem.ptr(self.layout.avail + 2)) };
+        idx.store(self.next_avail.to_le(), Ordering::Release);
+    }
+
+    /// Returns whether the kernel wants to be notified of new descriptors.
+    fn needs_kick(&self, mem: &SharedMemory) -> bool {
+        // Order the index update before reading the flags, so that the
+        // kernel either sees the new descriptors or has cleared the flag.
+        fence(Ordering::SeqCst);
+        // SAFETY: the flags are within the mapping and suitably aligned, and
+        // are only accessed atomically.
+        let flags = unsafe { AtomicU16::from_ptr(mem.ptr(self.layout.used)) };
+        u16::from_le(flags.load(Ordering::Relaxed)) & VRING_USED_F_NO_NOTIFY == 0
+    }
+
+    /// Returns the next descriptor returned by the kernel and the number of
+    /// bytes it wrote.
+    fn pop_used(&mut self, mem: &SharedMemory) -> Option<(u16, u32)> {
+        // SAFETY: the index is within the mapping and suitably aligned, and is
+        // only accessed atomically.
+        let idx = unsafe { AtomicU16::from_ptr(mem.ptr(self.layout.used + 2)) };
+        if u16::from_le(idx.load(Ordering::Acquire)) == self.next_used {
+            return None;
+        }
+        let slot = (self.next_used % QUEUE_SIZE) as usize;
+        let entry = mem.ptr::<[u32; 2]>(self.layout.used + 4 + slot * 8);
+        // SAFETY: the entry is within the mapping and the kernel published it
+        // before updating the index.
+        let [id, len] = unsafe { entry.read_volatile() };
+        self.next_used = self.next_used.wrapping_add(1);
+        Some((u32::from_le(id) as u16 % QUEUE_SIZE, u32::from_le(len)))
+    }
+}
+
+/// Allocates transmit buffers from the arena in ring buffer order.
+struct TxArena {
+    head: usize,
+    /// Buffers not yet returned to the arena, in allocation order.
+    in_flight: VecDeque<TxBuffer>,
+}
+
+struct TxBuffer {
+    desc: u16,
+    start: usize,
+    done: bool,
+}
+
+impl TxArena {
+    /// Returns the offset of a free `len` byte buffer.
+    fn reserve(&self, len: usize) -> Option<usize> {
+        let Some(tail) = self.in_flight.front().map(|b| b.start) else {
+            return (len <= TX_ARENA_SIZE).then_some(0);
+        };
+        // Keep `head != tail` while buffers are in flight, so that a full
+        // arena is not mistaken for an empty one.
+        if self.head > tail {
+            if TX_ARENA_SIZE - self.head >= len {
+                Some(self.head)
+            } else if tail > len {
+                Some(0)
+            } else {
+                None
+            }
+        } else if tail - self.head > len {
+            Some(self.head)
+        } else {
+            None
+        }
+    }
+
+    fn commit(&mut self, desc: u16, start: usize, len: usize) {
+        self.head = start + len;
+        self.in_flight.push_back(TxBuffer {
+            desc,
+            start,
+            done: false,
+        });
+    }
+
+    /// Marks the buffer for `desc` done, and returns the descriptors of the
+    /// buffers that can now be reused.
+    fn complete(&mut self, desc: u16, free: &mut Vec<u16>) {
+        if let Some(buffer) = self.in_flight.iter_mut().find(|b| b.desc == desc) {
+            buffer.done = true;
+        }
+        while self.in_flight.front().is_some_and(|b| b.done) {
+            free.push(self.in_flight.pop_front().unwrap().desc);
+        }
+        if self.in_flight.is_empty() {
+            self.head = 0;
+        }
+    }
+}
+
+struct VhostQueue {
+    slot: Arc<Mutex<Option<QueueResources>>>,
+    resources: Option<QueueResources>,
+    /// Whether this process owns the vhost-net instance, which must then be
+    /// reset before the memory is freed.
+    owned: bool,
+    mem: SharedMemory,
+    rx: Ring,
+    tx: Ring,
+    kick: [Event; 2],
+    call: [PolledWait<Event>; 2],
+    rx_free: VecDeque<RxId>,
+    rx_ready: VecDeque<RxId>,
+    /// Scratch space for frames that span several receive buffers.
+    rx_frame: Vec<u8>,
+    tx_free: Vec<u16>,
+    tx_arena: TxArena,
+    /// Whether `tx_avail` ran out of descriptors or arena space.
+    tx_blocked: bool,
+}
+
+impl InspectMut for VhostQueue {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("rx_free", self.rx_free.len())
+            .field("rx_ready", self.rx_ready.len())
+            .field("tx_in_flight", self.tx_arena.in_flight.len());
+    }
+}
+
+impl Drop for VhostQueue {
+    fn drop(&mut self) {
+        if self.owned {
+            let vhost = &self.resources.as_ref().unwrap().vhost;
+            // SAFETY: calling the ioctl with a valid fd. This stops the
+            // worker thread and detaches the TAP queue.
+            if let Err(err) = unsafe { vhost_reset_owner(vhost.as_raw_fd()) } {
+                tracing::error!(
+                    error = &err as &dyn std::error::Error,
+                    "failed to reset vhost-net, leaking its rings"
+                );
+                self.mem.leaked = true;
+            }
+        }
+        *self.slot.lock() = self.resources.take();
+    }
+}
+
+impl VhostQueue {
+    fn new(driver: &dyn Driver, slot: Arc<Mutex<Option<QueueResources>>>) -> Result<Self, Error> {
+        let mem = SharedMemory::new(MEMORY_SIZE).map_err(Error::AllocateRings)?;
+        let new_call = || PolledWait::new(driver, Event::new()).map_err(Error::Notification);
+        let call = [new_call()?, new_call()?];
+        let resources = slot.lock().take().expect("queue is already in use");
+        let mut this = Self {
+            slot,
+            resources: Some(resources),
+            owned: false,
+            mem,
+            rx: Ring::new(RX_RING_OFFSET),
+            tx: Ring::new(TX_RING_OFFSET),
+            kick: [Event::new(), Event::new()],
+            call,
+            rx_free: VecDeque::new(),
+            rx_ready: VecDeque::new(),
+            rx_frame: Vec::new(),
+            tx_free: (0..QUEUE_SIZE).rev().collect(),
+            tx_arena: TxArena {
+                head: 0,
+                in_flight: VecDeque::new(),
+            },
+            tx_blocked: false,
+        };
+        this.start()?;
+        Ok(this)
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        let resources = self.resources.as_ref().unwrap();
+        let fd = resources.vhost.as_raw_fd();
+
+        // SAFETY: calling the ioctl with a valid fd.
+        unsafe { vhost_set_owner(fd) }.map_err(ioctl_error("VHOST_SET_OWNER"))?;
+        self.owned = true;
+
+        // SAFETY: calling the ioctl with a valid fd and correct argument type.
+        unsafe { vhost_set_features(fd, &FEATURES) }.map_err(ioctl_error("VHOST_SET_FEATURES"))?;
+
+        // Map the shared memory at its own address, so that descriptors can
+        // hold process addresses.
+        #[repr(C)]
+        struct MemoryTable {
+            header: vhost_memory,
+            region: vhost_memory_region,
+        }
+        let table = MemoryTable {
+            header: vhost_memory {
+                nregions: 1,
+                ..Default::default()
+            },
+            region: vhost_memory_region {
+                guest_phys_addr: self.mem.addr(0),
+                memory_size: MEMORY_SIZE as u64,
+                userspace_addr: self.mem.addr(0),
+                flags_padding: 0,
+            },
+        };
+        // SAFETY: calling the ioctl with a valid fd and a memory table with
+        // the number of regions given in its header.
+        unsafe { vhost_set_mem_table(fd, std::ptr::from_ref(&table).cast()) }
+            .map_err(ioctl_error("VHOST_SET_MEM_TABLE"))?;
+
+        // Make all receive buffers available before the kernel starts
+        // polling the TAP queue.
+        for i in 0..QUEUE_SIZE {
+            self.rx.write_descriptor(
+                &self.mem,
+                i,
+                self.mem
+                    .addr(RX_BUFFERS_OFFSET + i as usize * RX_BUFFER_SIZE),
+                RX_BUFFER_SIZE as u32,
+                VRING_DESC_F_WRITE,
+            );
+            self.rx.push_avail(&self.mem, i);
+        }
+        self.rx.publish_avail(&self.mem);
+
+        for (index, ring) in [(RX_VQ, &self.rx), (TX_VQ, &self.tx)] {
+            let index = index as u32;
+            let num = vhost_vring_state {
+                index,
+                num: QUEUE_SIZE.into(),
+            };
+            // SAFETY: calling the ioctl with a valid fd and correct argument
+            // type.
+            unsafe { vhost_set_vring_num(fd, &num) }.map_err(ioctl_error("VHOST_SET_VRING_NUM"))?;
+            let base = vhost_vring_state { index, num: 0 };
+            // SAFETY: calling the ioctl with a valid fd and correct argument
+            // type.
+            unsafe { vhost_set_vring_base(fd, &base) }
+                .map_err(ioctl_error("VHOST_SET_VRING_BASE"))?;
+            let addr = vhost_vring_addr {
+                index,
+                flags: 0,
+                desc_user_addr: self.mem.addr(ring.layout.desc),
+                used_user_addr: self.mem.addr(ring.layout.used),
+                avail_user_addr: self.mem.addr(ring.layout.avail),
+                log_guest_addr: 0,
+            };
+            // SAFETY: calling the ioctl with a valid fd and ring addresses
+            // within the registered memory, which outlives the vhost-net
+            // instance's ownership.
+            unsafe { vhost_set_vring_addr(fd, &addr) }
+                .map_err(ioctl_error("VHOST_SET_VRING_ADDR"))?;
+            let kick = vhost_vring_file {
+                index,
+                fd: self.kick[index as usize].as_fd().as_raw_fd(),
+            };
+            // SAFETY: calling the ioctl with a valid fd and eventfd.
+            unsafe { vhost_set_vring_kick(fd, &kick) }
+                .map_err(ioctl_error("VHOST_SET_VRING_KICK"))?;
+            let call = vhost_vring_file {
+                index,
+                fd: self.call[index as usize].get().as_fd().as_raw_fd(),
+            };
+            // SAFETY: calling the ioctl with a valid fd and eventfd.
+            unsafe { vhost_set_vring_call(fd, &call) }
+                .map_err(ioctl_error("VHOST_SET_VRING_CALL"))?;
+        }
+
+        for index in [RX_VQ, TX_VQ] {
+            let backend = vhost_vring_file {
+                index: index as u32,
+                fd: resources.tap.as_fd().as_raw_fd(),
+            };
+            // SAFETY: calling the ioctl with a valid fd and TAP fd.
+            unsafe { vhost_net_set_backend(fd, &backend) }
+                .map_err(ioctl_error("VHOST_NET_SET_BACKEND"))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the bytes written by the kernel to receive buffer `desc`.
+    fn rx_buffer(mem: &SharedMemory, desc: u16, len: u32) -> &[u8] {
+        let len = (len as usize).min(RX_BUFFER_SIZE);
+        // SAFETY: the kernel returned the buffer in the used ring, and does
+        // not write it again until it is made available.
+        unsafe { mem.slice(RX_BUFFERS_OFFSET + desc as usize * RX_BUFFER_SIZE, len) }
+    }
+
+    /// Copies received frames into guest buffers, while there are free ones.
+    fn receive(&mut self, pool: &mut dyn BufferAccess) {
+        let mut reposted = false;
+        while let Some(&rx_id) = self.rx_free.front() {
+            let Some((desc, len)) = self.rx.pop_used(&self.mem) else {
+                break;
+            };
+            let data = Self::rx_buffer(&self.mem, desc, len);
+            let Ok((hdr, frame)) = VirtioNetHdr::read_from_prefix(data) else {
+                tracing::warn!(len, "vhost-net receive too short for vnet header");
+                self.rx.push_avail(&self.mem, desc);
+                reposted = true;
+                continue;
+            };
+            // The kernel publishes all of a frame's buffers at once.
+            let frame = if hdr.num_buffers > 1 {
+                self.rx_frame.clear();
+                self.rx_frame.extend_from_slice(frame);
+                for _ in 1..hdr.num_buffers {
+                    let Some((desc, len)) = self.rx.pop_used(&self.mem) else {
+                        tracing::warn!("vhost-net receive missing buffers");
+                        break;
+                    };
+                    self.rx_frame
+                        .extend_from_slice(Self::rx_buffer(&self.mem, desc, len));
+                    self.rx.push_avail(&self.mem, desc);
+                }
+                &self.rx_frame[..]
+            } else {
+                frame
+            };
+            pool.write_packet(
+                rx_id,
+                &RxMetadata {
+                    offset: 0,
+                    len: frame.len(),
+                    ..parse_vnet_hdr(&hdr)
+                },
+                frame,
+            );
+            self.rx.push_avail(&self.mem, desc);
+            reposted = true;
+            self.rx_free.pop_front();
+            self.rx_ready.push_back(rx_id);
+        }
+        if reposted {
+            self.rx.publish_avail(&self.mem);
+            if self.rx.needs_kick(&self.mem) {
+                self.kick[RX_VQ].signal();
+            }
+        }
+    }
+
+    /// Returns transmit buffers that the kernel is done with to the arena.
+    fn reclaim_tx(&mut self) {
+        while let Some((desc, _)) = self.tx.pop_used(&self.mem) {
+            self.tx_arena.complete(desc, &mut self.tx_free);
+        }
+    }
+}
+
+impl Queue for VhostQueue {
+    fn poll_ready(&mut self, cx: &mut Context<'_>, pool: &mut dyn BufferAccess) -> Poll<()> {
+        loop {
+            let tx_in_flight = self.tx_arena.in_flight.len();
+            self.reclaim_tx();
+            let tx_reclaimed = self.tx_arena.in_flight.len() < tx_in_flight;
+            self.receive(pool);
+            // Wake the frontend for received frames, or to retry transmits
+            // once buffers have been reclaimed.
+            if !self.rx_ready.is_empty() || (self.tx_blocked && tx_reclaimed) {
+                self.tx_blocked = false;
+                return Poll::Ready(());
+            }
+            // The kernel signals after updating the used ring, so a signal
+            // that arrives after the checks above is not lost.
+            let rx = self.call[RX_VQ].poll_wait(cx).is_ready();
+            let tx = self.call[TX_VQ].poll_wait(cx).is_ready();
+            if !rx && !tx {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn rx_avail(&mut self, _pool: &mut dyn BufferAccess, done: &[RxId]) {
+        self.rx_free.extend(done);
+    }
+
+    fn rx_poll(
+        &mut self,
+        _pool: &mut dyn BufferAccess,
+        packets: &mut [RxId],
+    ) -> anyhow::Result<usize> {
+        let n = std::cmp::min(self.rx_ready.len(), packets.len());
+        for (done, id) in packets[..n].iter_mut().zip(self.rx_ready.drain(..n)) {
+            *done = id;
+        }
+        Ok(n)
+    }
+
+    fn tx_avail(
+        &mut self,
+        pool: &mut dyn BufferAccess,
+        segments: &[TxSegment],
+    ) -> anyhow::Result<(bool, usize)> {
+        let hdr_len = size_of::<VirtioNetHdr>();
+        self.reclaim_tx();
+        let mut consumed = 0;
+        while consumed < segments.len() {
+            let (meta, this, _) = next_packet(&segments[consumed..]);
+            let frame_len = meta.len as usize;
+            if frame_len > MAX_TX_FRAME {
+                tracing::warn!(frame_len, "dropping oversized transmit packet");
+                consumed += this.len();
+                continue;
+            }
+            // Stop when out of descriptors or arena space. The frontend
+            // retries the remaining segments after `poll_ready` returns.
+            let Some(&desc) = self.tx_free.last() else {
+                self.tx_blocked = true;
+                break;
+            };
+            let Some(offset) = self.tx_arena.reserve(hdr_len + frame_len) else {
+                self.tx_blocked = true;
+                break;
+            };
+
+            // SAFETY: the reserved range is not in flight, so the kernel does
+            // not access it.
+            let buf = unsafe {
+                self.mem
+                    .slice_mut(TX_ARENA_OFFSET + offset, hdr_len + frame_len)
+            };
+            let (hdr, packet) = buf.split_at_mut(hdr_len);
+            hdr.copy_from_slice(build_vnet_hdr(meta).as_bytes());
+            let mut packet_offset = 0;
+            for segment in this {
+                let dest = &mut packet[packet_offset..packet_offset + segment.len as usize];
+                pool.guest_memory().read_at(segment.gpa, dest)?;
+                packet_offset += segment.len as usize;
+            }
+
+            // The virtio header cannot express these offloads, so perform
+            // them here as `TapQueue` does.
+            if meta.flags.offload_ip_header_checksum() && meta.flags.is_ipv4() {
+                fixup_ipv4_header_checksum(packet, meta.l2_len as usize);
+            }
+            if meta.flags.offload_tcp_segmentation() && meta.flags.is_ipv6() {
+                fixup_ipv6_payload_length(packet, meta.l2_len as usize);
+            }
+
+            self.tx.write_descriptor(
+                &self.mem,
+                desc,
+                self.mem.addr(TX_ARENA_OFFSET + offset),
+                (hdr_len + frame_len) as u32,
+                0,
+            );
+            self.tx.push_avail(&self.mem, desc);
+            self.tx.publish_avail(&self.mem);
+            self.tx_free.pop();
+            self.tx_arena.commit(desc, offset, hdr_len + frame_len);
+            consumed += this.len();
+        }
+        if consumed > 0 && self.tx.needs_kick(&self.mem) {
+            self.kick[TX_VQ].signal();
+        }
+        // The frames have been copied, so the guest's buffers can be
+        // completed now.
+        Ok((true, consumed))
+    }
+
+    fn tx_poll(
+        &mut self,
+        _pool: &mut dyn BufferAccess,
+        _done: &mut [TxId],
+    ) -> Result<usize, TxError> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arena() -> TxArena {
+        TxArena {
+            head: 0,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn ring_layout_alignment() {
+        let layout = RingLayout::new(TX_RING_OFFSET);
+        assert_eq!(layout.desc % 4096, 0);
+        assert_eq!(layout.avail % 2, 0);
+        assert_eq!(layout.used % 4, 0);
+        assert!(layout.used + RingLayout::USED_SIZE <= RX_BUFFERS_OFFSET);
+    }
+
+    #[test]
+    fn tx_arena_wraps_in_order() {
+        let mut arena = arena();
+        let mut free = Vec::new();
+        let len = TX_ARENA_SIZE / 4;
+
+        // Fill the arena, leaving less than a buffer at the end.
+        for desc in 0..3 {
+            let offset = arena.reserve(len).unwrap();
+            assert_eq!(offset, desc as usize * len);
+            arena.commit(desc, offset, len);
+        }
+        assert_eq!(arena.reserve(len + 1), None);
+
+        // Completing out of order does not free anything.
+        arena.complete(1, &mut free);
+        assert!(free.is_empty());
+        assert_eq!(arena.reserve(len + 1), None);
+
+        // Completing the oldest frees both, and the next buffer wraps.
+        arena.complete(0, &mut free);
+        assert_eq!(free, [0, 1]);
+        let offset = arena.reserve(len + 1).unwrap();
+        assert_eq!(offset, 0);
+        arena.commit(3, offset, len + 1);
+
+        // The wrapped head cannot catch up with the tail.
+        assert_eq!(arena.reserve(len - 1), None);
+        assert_eq!(arena.reserve(len - 2), Some(len + 1));
+
+        arena.complete(2, &mut free);
+        arena.complete(3, &mut free);
+        assert_eq!(free, [0, 1, 2, 3]);
+        assert_eq!(arena.reserve(TX_ARENA_SIZE), Some(0));
+    }
+}
@@ -32,6 +32,8 @@ mod tap_tests {
     use net_backend::TxSegmentType;
     use net_tap::TapEndpoint;
     use net_tap::tap;
+    use net_tap::vhost;
+    use net_tap::vhost::VhostNetEndpoint;
     use pal_async::DefaultDriver;
     use std::future::poll_fn;
     use std::os::fd::AsRawFd;
@@ -606,6 +608,81 @@ async fn test_tap_tso_ipv4_checksum(driver: DefaultDriver) {
         );
     }
 
+    /// Validates that frames flow in both directions through vhost-net.
+    async fn test_vhost_tx_rx(driver: DefaultDriver) {
+        // /dev/vhost-net may not be accessible from the test's namespace.
+        let vhost = match vhost::open_vhost_net() {
+            Ok(vhost) => vhost,
+            Err(e) => {
+                eprintln!("note: skipping vhost-net test — {e}");
+                return;
+            }
+        };
+        let tap = tap::Tap::new(tap::open_tap("tap0").unwrap()).unwrap();
+        let mut endpoint = VhostNetEndpoint::new(vec![(tap, vhost)]).unwrap();
+        assert_eq!(endpoint.multiqueue_support().max_queues, 1);
+        configure_tap("tap0", "10.0.0.1/24");
+
+        let (mut pool, mem) = make_pool();
+        let initial_rx: Vec<_> = (1..128).map(RxId).collect();
+        let config = vec![QueueConfig {
+            driver: Box::new(driver.clone()),
+        }];
+        let mut queues = Vec::new();
+        endpoint
+            .get_queues(config, None, &mut queues)
+            .await
+            .unwrap();
+        let queue = &mut queues[0];
+        queue.rx_avail(&mut pool, &initial_rx);
+
+        // Trigger an ARP request out of the TAP interface, as in
+        // `test_tap_rx_receives_packet`.
+        let sock = std::net::UdpSocket::bind("10.0.0.1:0").unwrap();
+        sock.send_to(b"hello", "10.0.0.2:12345").unwrap();
+
+        let mut found_arp = false;
+        while !found_arp {
+            poll_fn(|cx| queue.poll_ready(cx, &mut pool)).await;
+            let mut packets = [RxId(0); 128];
+            let n = queue.rx_poll(&mut pool, &mut packets).unwrap();
+            for &rx_id in &packets[..n] {
+                let mut buf = [0u8; 14];
+                mem.read_at(rx_id.0 as u64 * 2048, &mut buf).unwrap();
+                found_arp |= u16::from_be_bytes([buf[12], buf[13]]) == 0x0806;
+            }
+        }
+
+        // Transmit enough frames to wrap the rings several times.
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff]); // dst: broadcast
+        frame.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x01]); // src
+        frame.extend_from_slice(&[0x08, 0x00]); // ethertype: IPv4
+        frame.resize(1500, 0xAA);
+        let frame_len = frame.len() as u32;
+        mem.write_at(0, &frame).unwrap();
+        let segments = [TxSegment {
+            ty: TxSegmentType::Head(TxMetadata {
+                id: TxId(0),
+                segment_count: 1,
+                len: frame_len,
+                ..Default::default()
+            }),
+            gpa: 0,
+            len: frame_len,
+        }];
+        let mut sent = 0;
+        while sent < 2000 {
+            let (completed, count) = queue.tx_avail(&mut pool, &segments).unwrap();
+            assert!(completed, "tx should complete synchronously");
+            if count == 0 {
+                // Wait for the kernel to return transmit buffers.
+                poll_fn(|cx| queue.poll_ready(cx, &mut pool)).await;
+            }
+            sent += count;
+        }
+    }
+
     // ---------------------------------------------------------------------------
     // Harness
     // ---------------------------------------------------------------------------
@@ -636,6 +713,7 @@ pub(crate) fn main() {
             async_trial("tap_tx_wouldblock_drops", test_tap_tx_wouldblock_drops),
             async_trial("tap_tx_with_offloads", test_tap_tx_with_offloads),
             async_trial("tap_tso_ipv4_checksum", test_tap_tso_ipv4_checksum),
+            async_trial("vhost_tx_rx", test_vhost_tx_rx),
         ]
         .map(|t| t.with_ignored_flag(ignored))
         .into();
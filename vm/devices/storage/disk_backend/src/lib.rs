@@ -45,6 +45,8 @@
 //!   changes (e.g., `BlockDeviceDisk` via Linux uevent, `NvmeDisk` via AEN)
 //!   should override this. Decorators and layered disks delegate to the
 //!   inner backend.
+//! - [`DiskIo::zoned`] — optionally exposes a [`zoned::ZonedDisk`] for
+//!   backends with a zoned address space (host-managed SMR, ZNS).
 //!
 //! # Error model
 //!
@@ -59,7 +61,9 @@
 //! | Backend | Crate | Description |
 //! |---------|-------|-------------|
 //! | `FileDisk` | `disk_file` | Host file, cross-platform |
-//! | `Vhd1Disk` | `disk_vhd1` | VHD1 fixed format |
+//! | `Vhd1Disk` | `disk_vhd1` | VHD1 image, including dynamic and differencing |
+//! | `Qcow2Disk` | `disk_qcow2` | QCOW2 image with backing file chains |
+//! | `VhdxDisk` | `disk_vhdx` | VHDX image, including differencing chains |
 //! | `VhdmpDisk` | `disk_vhdmp` | Windows vhdmp driver |
 //! | `BlobDisk` | `disk_blob` | Read-only HTTP / Azure Blob |
 //! | `BlockDeviceDisk` | `disk_blockdevice` | Linux block device (io_uring) |
@@ -75,6 +79,7 @@
 pub mod pr;
 pub mod resolve;
 pub mod sync_wrapper;
+pub mod zoned;
 
 use guestmem::AccessError;
 use inspect::Inspect;
@@ -201,6 +206,18 @@ fn pr(&self) -> Option<&dyn pr::PersistentReservation> {
         None
     }
 
+    /// Optionally returns a trait object to issue zone management requests.
+    ///
+    /// Backends that return `Some` here must enforce the zone write rules
+    /// in [`DiskIo::write_vectored`].
+    ///
+    /// No production disk backend implements this yet; it is only
+    /// implemented by test disks, to exercise the zoned support in the
+    /// virtio-blk frontend.
+    fn zoned(&self) -> Option<&dyn zoned::ZonedDisk> {
+        None
+    }
+
     /// Issues an asynchronous eject media operation to the disk.
     ///
     /// The default implementation returns [`DiskError::UnsupportedEject`].
@@ -399,6 +416,11 @@ pub fn pr(&self) -> Option<&dyn pr::PersistentReservation> {
         self.0.disk.pr()
     }
 
+    /// Optionally returns a trait object to issue zone management requests.
+    pub fn zoned(&self) -> Option<&dyn zoned::ZonedDisk> {
+        self.0.disk.zoned()
+    }
+
     /// Issues an asynchronous eject media operation to the disk.
     pub fn eject(&self) -> impl use<'_> + Future<Output = Result<(), DiskError>> + Send {
         self.0.disk.eject()
@@ -482,6 +504,7 @@ trait DynDisk: Send + Sync + Inspect {
     fn unmap(&self, sector_offset: u64, sector_count: u64, block_level_only: bool) -> IoFuture<'_>;
 
     fn pr(&self) -> Option<&dyn pr::PersistentReservation>;
+    fn zoned(&self) -> Option<&dyn zoned::ZonedDisk>;
     fn eject(&self) -> IoFuture<'_>;
 
     fn read_vectored<'a>(&'a self, buffers: &'a RequestBuffers<'_>, sector: u64) -> IoFuture<'a>;
@@ -498,10 +521,7 @@ fn write_vectored<'a>(
     fn wait_resize<'a>(
         &'a self,
         sector_count: u64,
-    ) -> Pin<Box<dyn 'a + Send + Future<Output = u64>>> {
-        let _ = sector_count;
-        Box::pin(std::future::pending())
-    }
+    ) -> Pin<Box<dyn 'a + Send + Future<Output = u64>>>;
 }
 
 impl<T: DiskIo> DynDisk for T {
@@ -526,6 +546,10 @@ fn pr(&self) -> Option<&dyn pr::PersistentReservation> {
         self.pr()
     }
 
+    fn zoned(&self) -> Option<&dyn zoned::ZonedDisk> {
+        self.zoned()
+    }
+
     fn eject(&self) -> IoFuture<'_> {
         StackFuture::from_or_box(self.eject())
     }
@@ -546,4 +570,11 @@ fn write_vectored<'a>(
     fn sync_cache(&self) -> IoFuture<'_> {
         StackFuture::from_or_box(self.sync_cache())
     }
+
+    fn wait_resize<'a>(
+        &'a self,
+        sector_count: u64,
+    ) -> Pin<Box<dyn 'a + Send + Future<Output = u64>>> {
+        Box::pin(self.wait_resize(sector_count))
+    }
 }
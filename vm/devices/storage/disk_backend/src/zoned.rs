@@ -0,0 +1,158 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Zoned block device support.
+//!
+//! This is currently only used by test disks. There is no backend yet that
+//! exposes a zoned host device to the guest.
+
+use crate::DiskError;
+use inspect::Inspect;
+use scsi_buffers::RequestBuffers;
+use thiserror::Error;
+
+/// Trait implemented by disks whose address space is divided into zones
+/// that must be written sequentially, such as host-managed SMR drives or
+/// NVMe ZNS namespaces.
+///
+/// All sector values are in units of the disk's logical sector size.
+///
+/// Regular writes still go through
+/// [`DiskIo::write_vectored`](crate::DiskIo::write_vectored). Writes to a
+/// sequential-write-required zone that do not start at the zone's write
+/// pointer must fail with [`DiskError::InvalidInput`].
+#[async_trait::async_trait]
+pub trait ZonedDisk: Sync {
+    /// Returns the zone layout and resource limits of the disk.
+    fn zone_geometry(&self) -> ZoneGeometry;
+
+    /// Returns descriptors for up to `max_zones` zones, starting with the
+    /// zone containing `sector`.
+    async fn report_zones(
+        &self,
+        sector: u64,
+        max_zones: usize,
+    ) -> Result<Vec<ZoneDescriptor>, ZoneError>;
+
+    /// Performs `action` on the zone starting at `sector`.
+    async fn manage_zone(&self, action: ZoneAction, sector: u64) -> Result<(), ZoneError>;
+
+    /// Resets the write pointer of every sequential zone on the disk.
+    async fn reset_all_zones(&self) -> Result<(), ZoneError>;
+
+    /// Writes `buffers` at the current write pointer of the zone starting at
+    /// `zone_sector`, returning the sector the data was written to.
+    async fn zone_append(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        zone_sector: u64,
+    ) -> Result<u64, ZoneError>;
+}
+
+/// The zone model of a disk.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Inspect)]
+pub enum ZonedModel {
+    /// The host must follow the zone write rules; violating writes fail.
+    HostManaged,
+    /// The disk accepts random writes but performs best when the host
+    /// follows the zone write rules.
+    HostAware,
+}
+
+/// The zone layout and resource limits returned by
+/// [`ZonedDisk::zone_geometry`].
+#[derive(Debug, Clone, Inspect)]
+pub struct ZoneGeometry {
+    /// The zone model.
+    pub model: ZonedModel,
+    /// The size of each zone, in sectors.
+    pub zone_sectors: u64,
+    /// The maximum number of zones that can be open at once, or 0 for no
+    /// limit.
+    pub max_open_zones: u32,
+    /// The maximum number of zones that can be open or closed at once, or 0
+    /// for no limit.
+    pub max_active_zones: u32,
+    /// The maximum size of a single zone append, in sectors.
+    pub max_append_sectors: u32,
+}
+
+/// The type of a zone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Inspect)]
+pub enum ZoneType {
+    /// A zone that can be written randomly and has no write pointer.
+    Conventional,
+    /// A zone that must be written sequentially at its write pointer.
+    SequentialWriteRequired,
+    /// A zone that should be written sequentially at its write pointer.
+    SequentialWritePreferred,
+}
+
+/// The condition of a zone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Inspect)]
+pub enum ZoneState {
+    /// The zone has no write pointer (conventional zones).
+    NotWritePointer,
+    /// The write pointer is at the start of the zone.
+    Empty,
+    /// The zone was opened by a write.
+    ImplicitlyOpen,
+    /// The zone was opened by [`ZoneAction::Open`].
+    ExplicitlyOpen,
+    /// The zone has been written to but is not open.
+    Closed,
+    /// The zone is read-only.
+    ReadOnly,
+    /// The zone has been completely written or finished.
+    Full,
+    /// The zone cannot be read or written.
+    Offline,
+}
+
+/// A zone, as returned by [`ZonedDisk::report_zones`].
+#[derive(Debug, Clone, Inspect)]
+pub struct ZoneDescriptor {
+    /// The first sector of the zone.
+    pub start: u64,
+    /// The number of usable sectors in the zone.
+    pub capacity: u64,
+    /// The next sector to be written in the zone.
+    pub write_pointer: u64,
+    /// The zone type.
+    pub zone_type: ZoneType,
+    /// The zone state.
+    pub state: ZoneState,
+}
+
+/// A zone management operation for [`ZonedDisk::manage_zone`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ZoneAction {
+    /// Explicitly open the zone.
+    Open,
+    /// Close the zone, releasing its open resource.
+    Close,
+    /// Move the write pointer to the end of the zone, making it full.
+    Finish,
+    /// Move the write pointer back to the start of the zone.
+    Reset,
+}
+
+/// An error returned by [`ZonedDisk`] operations.
+#[derive(Debug, Error)]
+pub enum ZoneError {
+    /// The operation is not valid for the zone's type or state.
+    #[error("invalid zone command")]
+    InvalidCommand,
+    /// A write did not start at the zone's write pointer.
+    #[error("write not at the zone write pointer")]
+    UnalignedWritePointer,
+    /// Opening the zone would exceed the maximum number of open zones.
+    #[error("too many open zones")]
+    TooManyOpenZones,
+    /// Opening the zone would exceed the maximum number of active zones.
+    #[error("too many active zones")]
+    TooManyActiveZones,
+    /// The underlying disk operation failed.
+    #[error("disk error")]
+    Disk(#[from] DiskError),
+}
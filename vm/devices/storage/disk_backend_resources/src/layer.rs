@@ -74,3 +74,56 @@ pub struct SqliteAutoCacheDiskLayerHandle {
 impl ResourceId<DiskLayerHandleKind> for SqliteAutoCacheDiskLayerHandle {
     const ID: &'static str = "sqlite-autocache";
 }
+
+/// Parameters used when formatting a QCOW2 disk layer.
+#[derive(MeshPayload)]
+pub struct Qcow2DiskLayerFormatParams {
+    /// Desired layer size. If `None`, lazily selects a size only once after
+    /// being attached to an existing layer.
+    pub len: Option<u64>,
+}
+
+/// QCOW2 disk layer handle.
+///
+/// Any backing file recorded in the image is ignored; the layers below this
+/// one are used instead.
+#[derive(MeshPayload)]
+pub struct Qcow2DiskLayerHandle {
+    /// The image file.
+    pub file: std::fs::File,
+    /// If this is provided, the file will be formatted as a new, empty image
+    /// with the provided params.
+    pub format: Option<Qcow2DiskLayerFormatParams>,
+}
+
+impl ResourceId<DiskLayerHandleKind> for Qcow2DiskLayerHandle {
+    const ID: &'static str = "qcow2";
+}
+
+/// Dynamic or differencing VHD1 disk layer handle.
+///
+/// Any parent recorded in the image is ignored; the layers below this one are
+/// used instead.
+#[derive(MeshPayload)]
+pub struct Vhd1DiskLayerHandle {
+    /// The image file.
+    pub file: std::fs::File,
+}
+
+impl ResourceId<DiskLayerHandleKind> for Vhd1DiskLayerHandle {
+    const ID: &'static str = "vhd1";
+}
+
+/// VHDX disk layer handle.
+///
+/// Any parent recorded in the image is ignored; the layers below this one are
+/// used instead.
+#[derive(MeshPayload)]
+pub struct VhdxDiskLayerHandle {
+    /// The image file.
+    pub file: std::fs::File,
+}
+
+impl ResourceId<DiskLayerHandleKind> for VhdxDiskLayerHandle {
+    const ID: &'static str = "vhdx";
+}
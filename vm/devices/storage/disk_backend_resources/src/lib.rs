@@ -9,6 +9,7 @@
 
 use mesh::Cell;
 use mesh::MeshPayload;
+use mesh::rpc::FailableRpc;
 use std::time::Duration;
 use vm_resource::IntoResource;
 use vm_resource::Resource;
@@ -69,6 +70,46 @@ impl ResourceId<DiskHandleKind> for FixedVhd1DiskHandle {
     const ID: &'static str = "fixed_vhd1";
 }
 
+/// Disk handle for a VHD1 disk of any type, including dynamic and
+/// differencing disks.
+#[derive(MeshPayload)]
+pub struct Vhd1DiskHandle {
+    /// The image file.
+    pub file: std::fs::File,
+    /// The parent disk, required if the image is a differencing disk.
+    pub parent: Option<Resource<DiskHandleKind>>,
+}
+
+impl ResourceId<DiskHandleKind> for Vhd1DiskHandle {
+    const ID: &'static str = "vhd1";
+}
+
+/// Disk handle for a QCOW2 image.
+#[derive(MeshPayload)]
+pub struct Qcow2DiskHandle {
+    /// The image file.
+    pub file: std::fs::File,
+    /// The backing disk, required if the image has a backing file.
+    pub backing: Option<Resource<DiskHandleKind>>,
+}
+
+impl ResourceId<DiskHandleKind> for Qcow2DiskHandle {
+    const ID: &'static str = "qcow2";
+}
+
+/// Disk handle for a VHDX image.
+#[derive(MeshPayload)]
+pub struct VhdxDiskHandle {
+    /// The image file.
+    pub file: std::fs::File,
+    /// The parent disk, required if the image is a differencing disk.
+    pub parent: Option<Resource<DiskHandleKind>>,
+}
+
+impl ResourceId<DiskHandleKind> for VhdxDiskHandle {
+    const ID: &'static str = "vhdx";
+}
+
 /// Disk configuration for a striped disk.
 #[derive(MeshPayload)]
 pub struct StripedDiskHandle {
@@ -124,8 +165,8 @@ impl ResourceId<DiskHandleKind> for BlobDiskHandle {
 pub enum BlobDiskFormat {
     /// A flat blob, with no additional metadata.
     Flat,
-    /// A fixed VHD1, with a VHD footer specifying disk metadata.
-    FixedVhd1,
+    /// A fixed or dynamic VHD1, with a VHD footer specifying disk metadata.
+    Vhd1,
 }
 
 /// Handle for a disk that is backed by one or more layers.
@@ -133,6 +174,8 @@ pub enum BlobDiskFormat {
 pub struct LayeredDiskHandle {
     /// The layers that make up the disk. The first layer is the top-most layer.
     pub layers: Vec<DiskLayerDescription>,
+    /// Runtime request channel.
+    pub requests: Option<mesh::Receiver<LayeredDiskRequest>>,
 }
 
 impl LayeredDiskHandle {
@@ -140,6 +183,7 @@ impl LayeredDiskHandle {
     pub fn single_layer(layer: impl IntoResource<DiskLayerHandleKind>) -> Self {
         Self {
             layers: vec![layer.into_resource().into()],
+            requests: None,
         }
     }
 }
@@ -148,6 +192,32 @@ impl ResourceId<DiskHandleKind> for LayeredDiskHandle {
     const ID: &'static str = "layered";
 }
 
+/// A runtime request to a layered disk.
+///
+/// The VM should be paused while these requests are processed, so that the
+/// layer stack changes at a consistent point.
+#[derive(MeshPayload)]
+pub enum LayeredDiskRequest {
+    /// Attach a new top layer. Subsequent writes go to the new layer, leaving
+    /// the existing layers as a point-in-time snapshot.
+    AddTopLayer(FailableRpc<DiskLayerDescription, ()>),
+    /// Copy the contents of the top layer into the layer below it, then
+    /// remove the top layer.
+    CommitTopLayer(FailableRpc<(), ()>),
+    /// Remove layers from the top of the stack, discarding their contents,
+    /// and attach new layers in their place.
+    ReplaceTopLayers(FailableRpc<ReplaceTopLayers, ()>),
+}
+
+/// Parameters for [`LayeredDiskRequest::ReplaceTopLayers`].
+#[derive(MeshPayload)]
+pub struct ReplaceTopLayers {
+    /// The number of layers to remove from the top of the stack.
+    pub remove: u32,
+    /// The layers to attach in their place, top first.
+    pub layers: Vec<DiskLayerDescription>,
+}
+
 /// Description of a disk layer.
 #[derive(MeshPayload)]
 pub struct DiskLayerDescription {
@@ -15,9 +15,12 @@
 use disk_backend::UnmapBehavior;
 use guestmem::MemoryWrite;
 use inspect::Inspect;
+use parking_lot::Mutex;
 use scsi_buffers::RequestBuffers;
+use std::collections::HashMap;
 use std::sync::Arc;
 use thiserror::Error;
+use vhd1_defs::VhdDynamicHeader;
 use vhd1_defs::VhdFooter;
 use zerocopy::FromZeros;
 use zerocopy::IntoBytes;
@@ -32,6 +35,94 @@ pub struct BlobDisk {
     sector_size: u32,
     sector_shift: u32,
     disk_id: Option<[u8; 16]>,
+    dynamic: Option<DynamicVhd1>,
+}
+
+/// The block allocation state of a dynamic VHD1 blob.
+#[derive(Inspect)]
+struct DynamicVhd1 {
+    block_size: u32,
+    /// The length of each block's sector bitmap, including padding.
+    bitmap_len: u64,
+    #[inspect(skip)]
+    bat: Vec<u32>,
+    /// The sector bitmaps of the blocks read so far, by block index. The blob
+    /// is read only, so these never change.
+    #[inspect(with = "|x| x.lock().len()")]
+    bitmaps: Mutex<HashMap<usize, Arc<[u8]>>>,
+}
+
+impl DynamicVhd1 {
+    /// Reads `buf.len()` bytes at disk offset `offset`, leaving unallocated
+    /// sectors (which must already be zero in `buf`) untouched.
+    async fn read(
+        &self,
+        blob: &(dyn Blob + Send + Sync),
+        buf: &mut [u8],
+        offset: u64,
+    ) -> std::io::Result<()> {
+        let block_size = self.block_size as u64;
+        let sector_size = DEFAULT_SECTOR_SIZE as u64;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let disk_offset = offset + pos as u64;
+            let block_offset = disk_offset % block_size;
+            let len = (block_size - block_offset).min((buf.len() - pos) as u64) as usize;
+            let block = (disk_offset / block_size) as usize;
+            let entry = self
+                .bat
+                .get(block)
+                .copied()
+                .unwrap_or(VhdDynamicHeader::BAT_ENTRY_UNUSED);
+            if entry != VhdDynamicHeader::BAT_ENTRY_UNUSED {
+                let block_start = entry as u64 * sector_size;
+                let bitmap = self.bitmap(blob, block, block_start).await?;
+                let first_sector = block_offset / sector_size;
+                let is_present = |i: usize| {
+                    let sector = first_sector + i as u64;
+                    bitmap[(sector / 8) as usize] & (0x80 >> (sector % 8)) != 0
+                };
+                let data = &mut buf[pos..pos + len];
+                let sectors = len / DEFAULT_SECTOR_SIZE as usize;
+                let mut i = 0;
+                while i < sectors {
+                    let mut end = i + 1;
+                    while end < sectors && is_present(end) == is_present(i) {
+                        end += 1;
+                    }
+                    if is_present(i) {
+                        let start = i * DEFAULT_SECTOR_SIZE as usize;
+                        blob.read(
+                            &mut data[start..end * DEFAULT_SECTOR_SIZE as usize],
+                            block_start + self.bitmap_len + block_offset + start as u64,
+                        )
+                        .await?;
+                    }
+                    i = end;
+                }
+            }
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Returns the sector bitmap of block `block`, which starts at
+    /// `block_start` in the blob.
+    async fn bitmap(
+        &self,
+        blob: &(dyn Blob + Send + Sync),
+        block: usize,
+        block_start: u64,
+    ) -> std::io::Result<Arc<[u8]>> {
+        if let Some(bitmap) = self.bitmaps.lock().get(&block) {
+            return Ok(bitmap.clone());
+        }
+        let mut bitmap = vec![0; self.bitmap_len as usize];
+        blob.read(&mut bitmap, block_start).await?;
+        let bitmap: Arc<[u8]> = bitmap.into();
+        self.bitmaps.lock().insert(block, bitmap.clone());
+        Ok(bitmap)
+    }
 }
 
 #[derive(Debug, Error)]
@@ -46,10 +137,24 @@ enum ErrorInner {
     VhdFooterChecksum,
     #[error("unsupported vhd version: {0:#x}")]
     UnsupportedVhdVersion(u32),
-    #[error("not a fixed vhd")]
-    NotFixedVhd,
+    #[error("differencing vhds are not supported")]
+    DifferencingVhd,
+    #[error("unsupported vhd disk type: {0}")]
+    UnsupportedVhdType(u32),
     #[error("invalid disk size: {0}")]
     InvalidDiskSize(u64),
+    #[error("failed to read the vhd dynamic header")]
+    VhdDynamicHeader(#[source] std::io::Error),
+    #[error("invalid vhd1 dynamic header cookie")]
+    VhdDynamicHeaderCookie,
+    #[error("invalid vhd1 dynamic header checksum")]
+    VhdDynamicHeaderChecksum,
+    #[error("invalid vhd block size: {0}")]
+    InvalidBlockSize(u32),
+    #[error("invalid vhd block allocation table size: {0} entries")]
+    InvalidBatSize(u32),
+    #[error("failed to read the vhd block allocation table")]
+    VhdBat(#[source] std::io::Error),
 }
 
 /// An error when attempting to open a blob in VHD1 format.
@@ -62,11 +167,14 @@ impl BlobDisk {
     pub fn new(blob: impl 'static + Blob + Send + Sync) -> Self {
         let blob = Arc::new(blob);
         let sector_count = blob.len() / DEFAULT_SECTOR_SIZE as u64;
-        Self::new_inner(blob, sector_count, None)
+        Self::new_inner(blob, sector_count, None, None)
     }
 
-    /// Returns a new blob disk where the blob is a fixed VHD1.
-    pub async fn new_fixed_vhd1(blob: impl 'static + Blob + Send + Sync) -> anyhow::Result<Self> {
+    /// Returns a new blob disk where the blob is a fixed or dynamic VHD1.
+    ///
+    /// Differencing VHD1s are not supported, since there is no way to locate
+    /// the parent blob.
+    pub async fn new_vhd1(blob: impl 'static + Blob + Send + Sync) -> anyhow::Result<Self> {
         let blob = Arc::new(blob);
         let blob_len = blob.len();
         let footer_offset = blob_len
@@ -87,25 +195,86 @@ pub async fn new_fixed_vhd1(blob: impl 'static + Blob + Send + Sync) -> anyhow::
         if footer.file_format_version.get() != VhdFooter::FILE_FORMAT_VERSION_MAGIC {
             return Err(ErrorInner::UnsupportedVhdVersion(footer.file_format_version.get()).into());
         }
-        if footer.disk_type.get() != VhdFooter::DISK_TYPE_FIXED {
-            return Err(ErrorInner::NotFixedVhd.into());
-        }
         let disk_size = footer.current_size.get();
-        if disk_size > footer_offset || disk_size % (DEFAULT_SECTOR_SIZE as u64) != 0 {
+        if disk_size % (DEFAULT_SECTOR_SIZE as u64) != 0 {
             return Err(ErrorInner::InvalidDiskSize(disk_size).into());
         }
+        let dynamic = match footer.disk_type.get() {
+            VhdFooter::DISK_TYPE_FIXED => {
+                if disk_size > footer_offset {
+                    return Err(ErrorInner::InvalidDiskSize(disk_size).into());
+                }
+                None
+            }
+            VhdFooter::DISK_TYPE_DYNAMIC => Some(Self::read_dynamic_header(&*blob, &footer).await?),
+            VhdFooter::DISK_TYPE_DIFFERENCING => return Err(ErrorInner::DifferencingVhd.into()),
+            disk_type => return Err(ErrorInner::UnsupportedVhdType(disk_type).into()),
+        };
 
         Ok(Self::new_inner(
             blob,
             disk_size / DEFAULT_SECTOR_SIZE as u64,
             Some(footer.unique_id.into()),
+            dynamic,
         ))
     }
 
+    async fn read_dynamic_header(
+        blob: &(dyn Blob + Send + Sync),
+        footer: &VhdFooter,
+    ) -> Result<DynamicVhd1, Vhd1Error> {
+        let mut header = VhdDynamicHeader::new_zeroed();
+        blob.read(header.as_mut_bytes(), footer.data_offset.get())
+            .await
+            .map_err(ErrorInner::VhdDynamicHeader)?;
+        if header.cookie != VhdDynamicHeader::COOKIE_MAGIC {
+            return Err(ErrorInner::VhdDynamicHeaderCookie.into());
+        }
+        if header.checksum.get() != header.compute_checksum() {
+            return Err(ErrorInner::VhdDynamicHeaderChecksum.into());
+        }
+        if header.header_version.get() != VhdDynamicHeader::HEADER_VERSION {
+            return Err(ErrorInner::UnsupportedVhdVersion(header.header_version.get()).into());
+        }
+        let block_size = header.block_size.get();
+        if !block_size.is_power_of_two() || block_size < DEFAULT_SECTOR_SIZE {
+            return Err(ErrorInner::InvalidBlockSize(block_size).into());
+        }
+        // Only the entries covering the disk are used. Bound them by the blob
+        // length too, since the disk size is not otherwise limited.
+        let max_table_entries = header.max_table_entries.get();
+        let entries = footer.current_size.get().div_ceil(block_size as u64);
+        if entries > max_table_entries as u64
+            || header
+                .table_offset
+                .get()
+                .checked_add(entries * 4)
+                .is_none_or(|end| end > blob.len())
+        {
+            return Err(ErrorInner::InvalidBatSize(max_table_entries).into());
+        }
+        let mut bat = vec![0; entries as usize * 4];
+        blob.read(&mut bat, header.table_offset.get())
+            .await
+            .map_err(ErrorInner::VhdBat)?;
+        Ok(DynamicVhd1 {
+            block_size,
+            bitmap_len: (block_size / DEFAULT_SECTOR_SIZE)
+                .div_ceil(8)
+                .next_multiple_of(DEFAULT_SECTOR_SIZE) as u64,
+            bat: bat
+                .chunks_exact(4)
+                .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+                .collect(),
+            bitmaps: Default::default(),
+        })
+    }
+
     fn new_inner(
         blob: Arc<dyn Blob + Send + Sync>,
         sector_count: u64,
         disk_id: Option<[u8; 16]>,
+        dynamic: Option<DynamicVhd1>,
     ) -> Self {
         Self {
             blob,
@@ -113,6 +282,7 @@ fn new_inner(
             sector_size: DEFAULT_SECTOR_SIZE,
             sector_shift: DEFAULT_SECTOR_SIZE.trailing_zeros(),
             disk_id,
+            dynamic,
         }
     }
 }
@@ -152,10 +322,13 @@ async fn read_vectored(
         sector: u64,
     ) -> Result<(), DiskError> {
         let mut buf = vec![0; buffers.len()];
-        self.blob
-            .read(&mut buf, sector << self.sector_shift)
-            .await
-            .map_err(DiskError::Io)?;
+        let offset = sector << self.sector_shift;
+        if let Some(dynamic) = &self.dynamic {
+            dynamic.read(&*self.blob, &mut buf, offset).await
+        } else {
+            self.blob.read(&mut buf, offset).await
+        }
+        .map_err(DiskError::Io)?;
 
         buffers.writer().write(&buf)?;
         Ok(())
@@ -187,3 +360,158 @@ fn unmap_behavior(&self) -> UnmapBehavior {
         UnmapBehavior::Ignored
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::BlobDisk;
+    use crate::blob::file::FileBlob;
+    use disk_backend::Disk;
+    use disk_backend::DiskIo;
+    use disk_file::readwriteat::ReadWriteAt;
+    use disk_vhd1::FormatParams;
+    use disk_vhd1::ParentParams;
+    use disk_vhd1::Vhd1Disk;
+    use guestmem::GuestMemory;
+    use pal_async::async_test;
+    use scsi_buffers::OwnedRequestBuffers;
+    use std::fs::File;
+    use vhd1_defs::VhdDynamicHeader;
+    use vhd1_defs::VhdFooter;
+    use zerocopy::FromZeros;
+    use zerocopy::IntoBytes;
+
+    const MB: u64 = 1024 * 1024;
+    const SIZE: u64 = 8 * MB;
+
+    fn new_vhd1(params: &FormatParams<'_>) -> File {
+        let file = tempfile::tempfile().unwrap();
+        disk_vhd1::format_dynamic(&file, params).unwrap();
+        file
+    }
+
+    async fn open_blob(file: &File) -> anyhow::Result<BlobDisk> {
+        BlobDisk::new_vhd1(FileBlob::new(file.try_clone().unwrap()).unwrap()).await
+    }
+
+    async fn read(disk: &Disk, sector: u64, len: usize) -> Vec<u8> {
+        let mem = GuestMemory::allocate(len);
+        disk.read_vectored(
+            &OwnedRequestBuffers::linear(0, len, true).buffer(&mem),
+            sector,
+        )
+        .await
+        .unwrap();
+        let mut data = vec![0; len];
+        mem.read_at(0, &mut data).unwrap();
+        data
+    }
+
+    async fn write(disk: &Disk, sector: u64, data: &[u8]) {
+        let mem = GuestMemory::allocate(data.len());
+        mem.write_at(0, data).unwrap();
+        disk.write_vectored(
+            &OwnedRequestBuffers::linear(0, data.len(), false).buffer(&mem),
+            sector,
+            false,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[async_test]
+    async fn dynamic_vhd1() {
+        let file = new_vhd1(&FormatParams::new(SIZE));
+        let vhd =
+            Disk::new(Vhd1Disk::open(file.try_clone().unwrap(), None, false).unwrap()).unwrap();
+        // Across the boundary of the first two blocks, and a single sector in
+        // the last block.
+        let sector = 2 * MB / 512 - 4;
+        write(&vhd, sector, &[1; 4096]).await;
+        write(&vhd, SIZE / 512 - 1, &[2; 512]).await;
+        drop(vhd);
+
+        let mut expected = vec![0; SIZE as usize];
+        expected[sector as usize * 512..][..4096].fill(1);
+        expected[SIZE as usize - 512..].fill(2);
+
+        let blob = open_blob(&file).await.unwrap();
+        let disk = Disk::new(blob).unwrap();
+        assert_eq!(disk.sector_count(), SIZE / 512);
+        assert!(read(&disk, 0, SIZE as usize).await == expected);
+        assert!(read(&disk, sector, 4096).await == expected[sector as usize * 512..][..4096]);
+    }
+
+    #[async_test]
+    async fn bitmaps_are_cached() {
+        let file = new_vhd1(&FormatParams::new(SIZE));
+        let vhd =
+            Disk::new(Vhd1Disk::open(file.try_clone().unwrap(), None, false).unwrap()).unwrap();
+        write(&vhd, 0, &[1; 512]).await;
+        write(&vhd, 2 * MB / 512, &[2; 512]).await;
+        drop(vhd);
+
+        let blob = open_blob(&file).await.unwrap();
+        let bitmaps = |blob: &BlobDisk| blob.dynamic.as_ref().unwrap().bitmaps.lock().len();
+        let mem = GuestMemory::allocate(1024);
+        for _ in 0..2 {
+            blob.read_vectored(&OwnedRequestBuffers::linear(0, 1024, true).buffer(&mem), 0)
+                .await
+                .unwrap();
+            assert_eq!(bitmaps(&blob), 1);
+        }
+        blob.read_vectored(
+            &OwnedRequestBuffers::linear(0, 1024, true).buffer(&mem),
+            2 * MB / 512,
+        )
+        .await
+        .unwrap();
+        assert_eq!(bitmaps(&blob), 2);
+        let mut data = [0; 1024];
+        mem.read_at(0, &mut data).unwrap();
+        assert!(data[..512].iter().all(|&b| b == 2));
+        assert!(data[512..].iter().all(|&b| b == 0));
+    }
+
+    #[async_test]
+    async fn differencing_vhd1_rejected() {
+        let file = new_vhd1(&FormatParams {
+            parent: Some(ParentParams {
+                unique_id: guid::Guid::new_random(),
+                time_stamp: 0,
+                relative_path: "parent.vhd",
+                absolute_path: None,
+            }),
+            ..FormatParams::new(SIZE)
+        });
+        let err = open_blob(&file).await.err().unwrap();
+        assert!(err.to_string().contains("differencing"), "{err:#}");
+    }
+
+    #[async_test]
+    async fn oversized_bat_rejected() {
+        let file = new_vhd1(&FormatParams::new(SIZE));
+        let footer_offset = file.metadata().unwrap().len() - VhdFooter::LEN;
+        let mut footer = VhdFooter::new_zeroed();
+        file.read_exact_at(footer.as_mut_bytes(), footer_offset)
+            .unwrap();
+        let mut header = VhdDynamicHeader::new_zeroed();
+        file.read_exact_at(header.as_mut_bytes(), footer.data_offset.get())
+            .unwrap();
+
+        // A disk size and table that would need a much larger BAT than the
+        // blob holds.
+        footer.current_size = disk_vhd1::MAX_DISK_SIZE.into();
+        footer.checksum = footer.compute_checksum().into();
+        file.write_all_at(footer.as_bytes(), footer_offset).unwrap();
+        header.max_table_entries = u32::MAX.into();
+        header.checksum = header.compute_checksum().into();
+        file.write_all_at(header.as_bytes(), footer.data_offset.get())
+            .unwrap();
+
+        let err = open_blob(&file).await.err().unwrap();
+        assert!(
+            err.to_string().contains("block allocation table size"),
+            "{err:#}"
+        );
+    }
+}
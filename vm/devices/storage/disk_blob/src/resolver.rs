@@ -38,7 +38,7 @@ async fn resolve(
         let blob = HttpBlob::new(&rsrc.url).await?;
         let disk = match rsrc.format {
             BlobDiskFormat::Flat => BlobDisk::new(blob),
-            BlobDiskFormat::FixedVhd1 => BlobDisk::new_fixed_vhd1(blob).await?,
+            BlobDiskFormat::Vhd1 => BlobDisk::new_vhd1(blob).await?,
         };
 
         Ok(ResolvedDisk::new(disk)?)
@@ -4,7 +4,7 @@
 #![expect(missing_docs)]
 #![forbid(unsafe_code)]
 
-mod readwriteat;
+pub mod readwriteat;
 
 use self::readwriteat::ReadWriteAt;
 use blocking::unblock;
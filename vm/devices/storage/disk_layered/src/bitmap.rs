@@ -79,11 +79,19 @@ pub fn set_count(&self) -> usize {
     }
 
     pub(crate) fn unset_iter(&self) -> impl '_ + Iterator<Item = Range<u64>> {
+        self.iter_runs(false)
+    }
+
+    pub(crate) fn set_iter(&self) -> impl '_ + Iterator<Item = Range<u64>> {
+        self.iter_runs(true)
+    }
+
+    fn iter_runs(&self, value: bool) -> impl '_ + Iterator<Item = Range<u64>> {
         let mut n = self.start_sector;
         self.bits.chunk_by(|&a, &b| a == b).filter_map(move |bits| {
             let start = n;
             n += bits.len() as u64;
-            if bits.first().is_some_and(|&x| !x) {
+            if bits.first().is_some_and(|&x| x == value) {
                 Some(start..n)
             } else {
                 None
@@ -161,6 +169,12 @@ fn test_bitmap() {
             assert_eq!(range.len(), 4);
             range.view(4).set(base + 7);
             assert_eq!(range.set_count(), 1);
+            assert!(range.set_iter().eq([base + 7..base + 8]));
+            assert!(
+                range
+                    .unset_iter()
+                    .eq([base + 6..base + 7, base + 8..base + 10])
+            );
         }
         {
             let mut iter = bitmap.unset_iter();
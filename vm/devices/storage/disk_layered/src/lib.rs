@@ -24,12 +24,17 @@
 //! # Layer types
 //!
 //! Each layer implements [`LayerIo`], which is similar to [`DiskIo`]
-//! but adds per-sector presence tracking via [`SectorMarker`]. Two concrete
-//! layer implementations exist:
+//! but adds per-sector presence tracking via [`SectorMarker`]. The concrete
+//! layer implementations are:
 //!
 //! - **`RamDiskLayer`** (`disklayer_ram`) — ephemeral, in-memory.
 //! - **`SqliteDiskLayer`** (`disklayer_sqlite`) — persistent, file-backed
 //!   (dev/test only).
+//! - **`Qcow2DiskLayer`** (`disk_qcow2`) — persistent, stored in a QCOW2
+//!   image.
+//! - **`Vhd1DiskLayer`** (`disk_vhd1`) — persistent, stored in a dynamic or
+//!   differencing VHD1 image.
+//! - **`VhdxDiskLayer`** (`disk_vhdx`) — persistent, stored in a VHDX image.
 //!
 //! A full [`Disk`] can appear at the bottom of the stack
 //! as a fully-present layer via `DiskLayer::from_disk`, which wraps it in
@@ -44,6 +49,16 @@
 //! - The last layer must not be write-through.
 //! - Layers used as read caches must support [`WriteNoOverwrite`].
 //! - If the disk is writable, all layers in the write path must be writable.
+//!
+//! # Runtime layer changes
+//!
+//! The layer stack of a running disk can be modified through a
+//! [`LayeredDiskControl`]. A new writable layer can be pushed on top of the
+//! stack, turning the existing layers into a consistent point-in-time
+//! snapshot, and the top layer can later be committed back into the layer
+//! below it and removed. The disk's advertised properties (sector size, FUA
+//! and unmap behavior) are fixed when the disk is created and are not
+//! recomputed when the stack changes.
 
 #![forbid(unsafe_code)]
 
@@ -61,18 +76,21 @@
 use guestmem::GuestMemory;
 use guestmem::MemoryWrite;
 use inspect::Inspect;
+use parking_lot::RwLock;
 use scsi_buffers::OwnedRequestBuffers;
 use scsi_buffers::RequestBuffers;
 use std::convert::Infallible;
 use std::future::Future;
+use std::ops::Range;
 use std::pin::Pin;
+use std::sync::Arc;
 use thiserror::Error;
 
 /// A disk composed of multiple layers.
 #[derive(Inspect)]
 pub struct LayeredDisk {
-    #[inspect(iter_by_index)]
-    layers: Vec<Layer>,
+    #[inspect(flatten)]
+    stack: Arc<LayerStack>,
     read_only: bool,
     is_fua_respected: bool,
     sector_shift: u32,
@@ -82,9 +100,32 @@ pub struct LayeredDisk {
     optimal_unmap_sectors: u32,
 }
 
-#[derive(Inspect)]
+/// The current layers of a [`LayeredDisk`], shared with any
+/// [`LayeredDiskControl`] handles.
+struct LayerStack {
+    /// The layers, top first. IOs take a reference to the current stack so
+    /// that they see a consistent set of layers even if the stack changes
+    /// while they are in flight.
+    layers: RwLock<Arc<Vec<Layer>>>,
+    /// Serializes changes to the stack.
+    update_lock: futures::lock::Mutex<()>,
+    read_only: bool,
+    sector_shift: u32,
+}
+
+impl Inspect for LayerStack {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        let layers = self.layers.read().clone();
+        req.respond()
+            .field("layers", inspect::iter_by_index(layers.iter()));
+    }
+}
+
+#[derive(Clone, Inspect)]
 struct Layer {
-    backing: Box<dyn DynLayerIo>,
+    backing: Arc<dyn DynLayerIo>,
+    #[inspect(skip)]
+    meta: DiskLayerMetadata,
     visible_sector_count: u64,
     read_cache: bool,
     write_through: bool,
@@ -121,9 +162,9 @@ pub struct DiskLayerMetadata {
     pub is_fua_respected: bool,
 }
 
-// DEVNOTE: this is a transient object, used solely in LayeredDisk::new.
+// DEVNOTE: this is a transient object, used while building a layer stack.
 struct AttachedDiskLayer {
-    backing: Box<dyn DynLayerIo>,
+    backing: Arc<dyn DynLayerIo>,
     meta: DiskLayerMetadata,
 }
 
@@ -167,6 +208,39 @@ pub enum InvalidLayeredDisk {
     Layer(usize, #[source] InvalidLayer),
 }
 
+/// An error returned when modifying the layers of a running [`LayeredDisk`].
+#[derive(Debug, Error)]
+pub enum ModifyLayersError {
+    /// The resulting layer stack is invalid.
+    #[error("invalid layer stack")]
+    InvalidStack(#[source] InvalidLayeredDisk),
+    /// The disk has a single layer, so there is nothing to commit into.
+    #[error("there is no lower layer to commit into")]
+    NoLowerLayer,
+    /// The layer below the top layer is read only.
+    #[error("the lower layer is read only")]
+    LowerLayerReadOnly,
+    /// The top layer has more sectors than the layer below it.
+    #[error("the top layer has {top} sectors but the lower layer only has {lower}")]
+    LowerLayerTooSmall {
+        /// The sector count of the top layer.
+        top: u64,
+        /// The sector count of the lower layer.
+        lower: u64,
+    },
+    /// Copying data from the top layer into the lower layer failed.
+    #[error("failed to commit the top layer")]
+    Commit(#[source] DiskError),
+    /// Removing the requested number of layers would leave no layers.
+    #[error("cannot remove {remove} layers from a stack of {count}")]
+    RemoveAllLayers {
+        /// The number of layers to remove.
+        remove: usize,
+        /// The number of layers in the stack.
+        count: usize,
+    },
+}
+
 /// A configuration for a layer in a [`LayeredDisk`].
 pub struct LayerConfiguration<L = DiskLayer> {
     /// The backing store for the layer.
@@ -190,7 +264,7 @@ pub async fn new(
             return Err(InvalidLayeredDisk::NoLayers);
         }
 
-        let mut attached_layers: Vec<LayerConfiguration<AttachedDiskLayer>> = {
+        let attached_layers: Vec<LayerConfiguration<AttachedDiskLayer>> = {
             let mut attached_layers = Vec::new();
 
             // layers are attached to one another from the bottom-up, hence the need
@@ -222,21 +296,12 @@ pub async fn new(
                 });
 
                 // perform some layer validation prior to attaching subsequent layers
-                if read_cache && !layer_meta.can_read_cache {
-                    return Err(layer_error(InvalidLayer::ReadCacheNotSupported));
-                }
-                if !layer_meta.sector_size.is_power_of_two() {
-                    return Err(layer_error(InvalidLayer::InvalidSectorSize(
-                        layer_meta.sector_size,
-                    )));
-                }
-                if layer_meta.sector_size != attached_layers[0].layer.meta.sector_size {
-                    // FUTURE: consider supporting different sector sizes, within reason.
-                    return Err(layer_error(InvalidLayer::MismatchedSectorSize {
-                        expected: attached_layers[0].layer.meta.sector_size,
-                        found: layer_meta.sector_size,
-                    }));
-                }
+                check_attached_layer(
+                    &layer_meta,
+                    read_cache,
+                    attached_layers[0].layer.meta.sector_size,
+                )
+                .map_err(layer_error)?;
 
                 lower_layer_metadata = Some(layer_meta);
             }
@@ -245,109 +310,447 @@ pub async fn new(
             attached_layers
         };
 
-        // perform top-down validation of the layer-stack, collecting various
-        // common properties of the stack along the way.
-        let mut last_write_through = true;
-        let mut is_fua_respected = true;
-        let mut optimal_unmap_sectors = 1;
-        let mut unmap_must_zero = false;
-        let mut disk_id = None;
-        let mut unmap_behavior = UnmapBehavior::Zeroes;
-        for (
-            i,
-            &LayerConfiguration {
-                ref layer,
-                write_through,
-                read_cache: _,
-            },
-        ) in attached_layers.iter().enumerate()
-        {
-            let layer_error = |e| InvalidLayeredDisk::Layer(i, e);
+        let (properties, layers) = build_stack(read_only, attached_layers)?;
 
-            if last_write_through {
-                if layer.meta.read_only && !read_only {
-                    return Err(layer_error(InvalidLayer::ReadOnly));
-                }
-                is_fua_respected &= layer.meta.is_fua_respected;
-                // Merge the unmap behavior. If any affected layer ignores
-                // unmap, then force the whole disk to. If all affected layers
-                // zero the sectors, then report that the disk zeroes sectors.
-                //
-                // If there is at least one write-through layer, then unmap only
-                // works if the unmap operation will produce the same result in
-                // all the layers that are being written to. Otherwise, the
-                // guest could see inconsistent disk contents when the write
-                // through layer is removed.
-                unmap_must_zero |= write_through;
-                unmap_behavior = match (unmap_behavior, layer.meta.unmap_behavior) {
-                    (UnmapBehavior::Zeroes, UnmapBehavior::Zeroes) => UnmapBehavior::Zeroes,
-                    _ if unmap_must_zero => UnmapBehavior::Ignored,
-                    (UnmapBehavior::Ignored, _) => UnmapBehavior::Ignored,
-                    (_, UnmapBehavior::Ignored) => UnmapBehavior::Ignored,
-                    _ => UnmapBehavior::Unspecified,
-                };
-                optimal_unmap_sectors = optimal_unmap_sectors.max(layer.meta.optimal_unmap_sectors);
-            } else if write_through {
-                // The write-through layers must all come first.
-                return Err(layer_error(InvalidLayer::UselessWriteThrough));
-            }
-            last_write_through = write_through;
-            if disk_id.is_none() {
-                disk_id = layer.meta.disk_id;
-            }
-        }
+        let sector_size = layers[0].meta.sector_size;
+        let physical_sector_size = layers[0].meta.physical_sector_size;
+        let sector_shift = sector_size.trailing_zeros();
+
+        Ok(Self {
+            stack: Arc::new(LayerStack {
+                layers: RwLock::new(Arc::new(layers)),
+                update_lock: Default::default(),
+                read_only,
+                sector_shift,
+            }),
+            is_fua_respected: properties.is_fua_respected,
+            read_only,
+            sector_shift,
+            disk_id: properties.disk_id,
+            physical_sector_size,
+            unmap_behavior: properties.unmap_behavior,
+            optimal_unmap_sectors: properties.optimal_unmap_sectors,
+        })
+    }
+
+    /// Returns a handle that can be used to modify the layers of the disk
+    /// while it is in use.
+    pub fn control(&self) -> LayeredDiskControl {
+        LayeredDiskControl(self.stack.clone())
+    }
+
+    fn layers(&self) -> Arc<Vec<Layer>> {
+        self.stack.layers.read().clone()
+    }
+}
+
+/// Properties of a layer stack, collected while validating it.
+struct StackProperties {
+    is_fua_respected: bool,
+    disk_id: Option<[u8; 16]>,
+    unmap_behavior: UnmapBehavior,
+    optimal_unmap_sectors: u32,
+}
+
+/// Validates a newly attached layer against the layer stack it is being
+/// attached to.
+fn check_attached_layer(
+    layer_meta: &DiskLayerMetadata,
+    read_cache: bool,
+    expected_sector_size: u32,
+) -> Result<(), InvalidLayer> {
+    if read_cache && !layer_meta.can_read_cache {
+        return Err(InvalidLayer::ReadCacheNotSupported);
+    }
+    if !layer_meta.sector_size.is_power_of_two() {
+        return Err(InvalidLayer::InvalidSectorSize(layer_meta.sector_size));
+    }
+    if layer_meta.sector_size != expected_sector_size {
+        // FUTURE: consider supporting different sector sizes, within reason.
+        return Err(InvalidLayer::MismatchedSectorSize {
+            expected: expected_sector_size,
+            found: layer_meta.sector_size,
+        });
+    }
+    Ok(())
+}
+
+/// Validates a stack of attached layers, ordered from top to bottom, and
+/// converts it into the layers of a [`LayeredDisk`].
+fn build_stack(
+    read_only: bool,
+    attached_layers: Vec<LayerConfiguration<AttachedDiskLayer>>,
+) -> Result<(StackProperties, Vec<Layer>), InvalidLayeredDisk> {
+    // perform top-down validation of the layer-stack, collecting various
+    // common properties of the stack along the way.
+    let mut last_write_through = true;
+    let mut is_fua_respected = true;
+    let mut optimal_unmap_sectors = 1;
+    let mut unmap_must_zero = false;
+    let mut disk_id = None;
+    let mut unmap_behavior = UnmapBehavior::Zeroes;
+    for (
+        i,
+        &LayerConfiguration {
+            ref layer,
+            write_through,
+            read_cache: _,
+        },
+    ) in attached_layers.iter().enumerate()
+    {
+        let layer_error = |e| InvalidLayeredDisk::Layer(i, e);
 
         if last_write_through {
-            return Err(InvalidLayeredDisk::Layer(
-                attached_layers.len() - 1,
-                InvalidLayer::UselessWriteThrough,
-            ));
+            if layer.meta.read_only && !read_only {
+                return Err(layer_error(InvalidLayer::ReadOnly));
+            }
+            is_fua_respected &= layer.meta.is_fua_respected;
+            // Merge the unmap behavior. If any affected layer ignores
+            // unmap, then force the whole disk to. If all affected layers
+            // zero the sectors, then report that the disk zeroes sectors.
+            //
+            // If there is at least one write-through layer, then unmap only
+            // works if the unmap operation will produce the same result in
+            // all the layers that are being written to. Otherwise, the
+            // guest could see inconsistent disk contents when the write
+            // through layer is removed.
+            unmap_must_zero |= write_through;
+            unmap_behavior = match (unmap_behavior, layer.meta.unmap_behavior) {
+                (UnmapBehavior::Zeroes, UnmapBehavior::Zeroes) => UnmapBehavior::Zeroes,
+                _ if unmap_must_zero => UnmapBehavior::Ignored,
+                (UnmapBehavior::Ignored, _) => UnmapBehavior::Ignored,
+                (_, UnmapBehavior::Ignored) => UnmapBehavior::Ignored,
+                _ => UnmapBehavior::Unspecified,
+            };
+            optimal_unmap_sectors = optimal_unmap_sectors.max(layer.meta.optimal_unmap_sectors);
+        } else if write_through {
+            // The write-through layers must all come first.
+            return Err(layer_error(InvalidLayer::UselessWriteThrough));
         }
+        last_write_through = write_through;
+        if disk_id.is_none() {
+            disk_id = layer.meta.disk_id;
+        }
+    }
 
-        let sector_size = attached_layers[0].layer.meta.sector_size;
-        let physical_sector_size = attached_layers[0].layer.meta.physical_sector_size;
-
-        let mut last_sector_count = None;
-        let sector_counts_rev = attached_layers
-            .iter_mut()
-            .rev()
-            .map(|config| *last_sector_count.insert(config.layer.backing.sector_count()))
-            .collect::<Vec<_>>();
-
-        let mut visible_sector_count = !0;
-        let layers = attached_layers
-            .into_iter()
-            .zip(sector_counts_rev.into_iter().rev())
-            .map(|(config, sector_count)| {
-                let LayerConfiguration {
-                    layer,
-                    write_through,
-                    read_cache,
-                } = config;
-                visible_sector_count = sector_count.min(visible_sector_count);
-                Layer {
-                    backing: layer.backing,
-                    visible_sector_count,
-                    read_cache,
-                    write_through,
-                }
-            })
-            .collect::<Vec<_>>();
+    if last_write_through {
+        return Err(InvalidLayeredDisk::Layer(
+            attached_layers.len() - 1,
+            InvalidLayer::UselessWriteThrough,
+        ));
+    }
 
-        Ok(Self {
+    let mut visible_sector_count = !0;
+    let layers = attached_layers
+        .into_iter()
+        .map(|config| {
+            let LayerConfiguration {
+                layer,
+                write_through,
+                read_cache,
+            } = config;
+            visible_sector_count = layer.backing.sector_count().min(visible_sector_count);
+            Layer {
+                backing: layer.backing,
+                meta: layer.meta,
+                visible_sector_count,
+                read_cache,
+                write_through,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok((
+        StackProperties {
             is_fua_respected,
-            read_only,
-            sector_shift: sector_size.trailing_zeros(),
             disk_id,
-            physical_sector_size,
             unmap_behavior,
             optimal_unmap_sectors,
-            layers,
-        })
+        },
+        layers,
+    ))
+}
+
+/// The number of bytes read from a layer at a time when scanning or committing
+/// it.
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// A handle for modifying the layers of a running [`LayeredDisk`], obtained
+/// via [`LayeredDisk::control`].
+///
+/// The caller is responsible for ensuring that the disk is quiesced (e.g., by
+/// pausing the VM) while the stack is being modified. IOs that race with a
+/// commit may be lost.
+#[derive(Clone)]
+pub struct LayeredDiskControl(Arc<LayerStack>);
+
+impl LayeredDiskControl {
+    /// Returns the number of layers in the stack.
+    pub fn layer_count(&self) -> usize {
+        self.0.layers.read().len()
+    }
+
+    /// Attaches a new layer on top of the stack.
+    ///
+    /// Writes go to the new layer from then on, so the layers below it form a
+    /// point-in-time snapshot of the disk.
+    pub async fn add_top_layer(&self, config: LayerConfiguration) -> Result<(), ModifyLayersError> {
+        let _guard = self.0.update_lock.lock().await;
+        let old_layers = self.0.layers.read().clone();
+        let LayerConfiguration {
+            layer,
+            write_through,
+            read_cache,
+        } = config;
+
+        let layer_error = |e| ModifyLayersError::InvalidStack(InvalidLayeredDisk::Layer(0, e));
+        let layer = layer
+            .0
+            .attach(Some(old_layers[0].meta.clone()))
+            .await
+            .map_err(|e| layer_error(InvalidLayer::AttachFailed(e)))?;
+
+        check_attached_layer(&layer.meta, read_cache, old_layers[0].meta.sector_size)
+            .map_err(layer_error)?;
+
+        let attached_layers = [LayerConfiguration {
+            layer,
+            write_through,
+            read_cache,
+        }]
+        .into_iter()
+        .chain(old_layers.iter().map(Layer::to_config))
+        .collect();
+
+        let (_, layers) = build_stack(self.0.read_only, attached_layers)
+            .map_err(ModifyLayersError::InvalidStack)?;
+
+        *self.0.layers.write() = Arc::new(layers);
+        Ok(())
+    }
+
+    /// Copies the contents of the top layer into the layer below it, then
+    /// removes the top layer from the stack.
+    pub async fn commit_top_layer(&self) -> Result<(), ModifyLayersError> {
+        let _guard = self.0.update_lock.lock().await;
+        let old_layers = self.0.layers.read().clone();
+        let [top, lower, ..] = &old_layers[..] else {
+            return Err(ModifyLayersError::NoLowerLayer);
+        };
+
+        // A write-through layer's contents are already in the lower layer.
+        if !top.write_through {
+            if lower.meta.read_only {
+                return Err(ModifyLayersError::LowerLayerReadOnly);
+            }
+            let sector_count = top.backing.sector_count();
+            let lower_sector_count = lower.backing.sector_count();
+            if sector_count > lower_sector_count {
+                return Err(ModifyLayersError::LowerLayerTooSmall {
+                    top: sector_count,
+                    lower: lower_sector_count,
+                });
+            }
+            self.copy_layer(top, lower, sector_count)
+                .await
+                .map_err(ModifyLayersError::Commit)?;
+        }
+
+        let (_, layers) = build_stack(
+            self.0.read_only,
+            old_layers[1..].iter().map(Layer::to_config).collect(),
+        )
+        .map_err(ModifyLayersError::InvalidStack)?;
+
+        *self.0.layers.write() = Arc::new(layers);
+        Ok(())
+    }
+
+    /// Removes the top `remove` layers from the stack, discarding their
+    /// contents, and attaches `layers` (ordered top to bottom) in their place.
+    ///
+    /// This rolls the disk back to the point-in-time snapshot formed by the
+    /// remaining layers, optionally with a different set of differencing
+    /// layers on top.
+    pub async fn replace_top_layers(
+        &self,
+        remove: usize,
+        layers: Vec<LayerConfiguration>,
+    ) -> Result<(), ModifyLayersError> {
+        let _guard = self.0.update_lock.lock().await;
+        let old_layers = self.0.layers.read().clone();
+        if remove >= old_layers.len() {
+            return Err(ModifyLayersError::RemoveAllLayers {
+                remove,
+                count: old_layers.len(),
+            });
+        }
+        let kept = &old_layers[remove..];
+
+        // Attach the new layers bottom up, since each layer is attached on top
+        // of the one below it.
+        let mut lower_meta = kept[0].meta.clone();
+        let mut attached_layers = Vec::with_capacity(layers.len() + kept.len());
+        for (i, config) in layers.into_iter().enumerate().rev() {
+            let LayerConfiguration {
+                layer,
+                write_through,
+                read_cache,
+            } = config;
+            let layer_error = |e| ModifyLayersError::InvalidStack(InvalidLayeredDisk::Layer(i, e));
+            let layer = layer
+                .0
+                .attach(Some(lower_meta))
+                .await
+                .map_err(|e| layer_error(InvalidLayer::AttachFailed(e)))?;
+            check_attached_layer(&layer.meta, read_cache, kept[0].meta.sector_size)
+                .map_err(layer_error)?;
+            lower_meta = layer.meta.clone();
+            attached_layers.push(LayerConfiguration {
+                layer,
+                write_through,
+                read_cache,
+            });
+        }
+        attached_layers.reverse();
+        attached_layers.extend(kept.iter().map(Layer::to_config));
+
+        let (_, layers) = build_stack(self.0.read_only, attached_layers)
+            .map_err(ModifyLayersError::InvalidStack)?;
+
+        *self.0.layers.write() = Arc::new(layers);
+        Ok(())
+    }
+
+    /// Returns information about each layer in the stack, top first.
+    pub fn layers(&self) -> Vec<LayerInfo> {
+        self.0
+            .layers
+            .read()
+            .iter()
+            .map(|layer| LayerInfo {
+                layer_type: layer.backing.layer_type().to_owned(),
+                sector_count: layer.backing.sector_count(),
+                read_only: layer.meta.read_only,
+                read_cache: layer.read_cache,
+                write_through: layer.write_through,
+            })
+            .collect()
+    }
+
+    /// Returns the ranges of sectors that are present in the layer at `index`,
+    /// where index 0 is the top layer.
+    ///
+    /// This reads the entire contents of the layer, so it can be slow for
+    /// large layers.
+    ///
+    /// Panics if `index` is out of range.
+    pub async fn layer_allocation(&self, index: usize) -> Result<Vec<Range<u64>>, DiskError> {
+        let layers = self.0.layers.read().clone();
+        let layer = &layers[index];
+        let sector_count = layer.backing.sector_count();
+        let chunk_sectors = (COPY_CHUNK_SIZE >> self.0.sector_shift) as u64;
+        let mem = GuestMemory::allocate(COPY_CHUNK_SIZE);
+        let owned_buf = OwnedRequestBuffers::linear(0, COPY_CHUNK_SIZE, true);
+        let buffers = owned_buf.buffer(&mem);
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        let mut sector = 0;
+        while sector < sector_count {
+            let count = (sector_count - sector).min(chunk_sectors);
+            for present in self.read_present(layer, &buffers, sector, count).await? {
+                match ranges.last_mut() {
+                    Some(last) if last.end == present.start => last.end = present.end,
+                    _ => ranges.push(present),
+                }
+            }
+            sector += count;
+        }
+        Ok(ranges)
+    }
+
+    /// Writes the sectors present in `src` to `dest`.
+    async fn copy_layer(
+        &self,
+        src: &Layer,
+        dest: &Layer,
+        sector_count: u64,
+    ) -> Result<(), DiskError> {
+        let sector_shift = self.0.sector_shift;
+        let chunk_sectors = (COPY_CHUNK_SIZE >> sector_shift) as u64;
+        let mem = GuestMemory::allocate(COPY_CHUNK_SIZE);
+        let owned_buf = OwnedRequestBuffers::linear(0, COPY_CHUNK_SIZE, true);
+        let buffers = owned_buf.buffer(&mem);
+        let mut sector = 0;
+        while sector < sector_count {
+            let count = (sector_count - sector).min(chunk_sectors);
+            for present in self.read_present(src, &buffers, sector, count).await? {
+                let offset = ((present.start - sector) as usize) << sector_shift;
+                let len = ((present.end - present.start) as usize) << sector_shift;
+                dest.backing
+                    .write(&buffers.subrange(offset, len), present.start, false, false)
+                    .await?;
+            }
+            sector += count;
+        }
+        dest.backing.sync_cache().await
+    }
+
+    /// Reads `count` sectors starting at `sector` from `layer` into the start
+    /// of `buffers`, returning the ranges of sectors that are present in the
+    /// layer.
+    async fn read_present(
+        &self,
+        layer: &Layer,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        count: u64,
+    ) -> Result<Vec<Range<u64>>, DiskError> {
+        let mut bitmap = Bitmap::new(sector, count as usize);
+        let mut range = bitmap.unset_iter().next().unwrap();
+        layer
+            .backing
+            .read(
+                &buffers.subrange(0, (count as usize) << self.0.sector_shift),
+                sector,
+                range.view(count),
+            )
+            .await?;
+        Ok(range.set_iter().collect())
+    }
+}
+
+/// Information about a layer of a [`LayeredDisk`], returned by
+/// [`LayeredDiskControl::layers`].
+#[derive(Debug, Clone)]
+pub struct LayerInfo {
+    /// The layer type, as reported by [`LayerIo::layer_type`].
+    pub layer_type: String,
+    /// The number of sectors in the layer.
+    pub sector_count: u64,
+    /// Whether the layer is read only.
+    pub read_only: bool,
+    /// Reads that miss this layer are written back to this layer.
+    pub read_cache: bool,
+    /// Writes are written both to this layer and the next one.
+    pub write_through: bool,
+}
+
+impl Layer {
+    fn to_config(&self) -> LayerConfiguration<AttachedDiskLayer> {
+        LayerConfiguration {
+            layer: AttachedDiskLayer {
+                backing: self.backing.clone(),
+                meta: self.meta.clone(),
+            },
+            write_through: self.write_through,
+            read_cache: self.read_cache,
+        }
     }
 }
 
 trait DynLayerIo: Send + Sync + Inspect {
+    fn layer_type(&self) -> &str;
+
     fn sector_count(&self) -> u64;
 
     fn read<'a>(
@@ -379,6 +782,10 @@ fn unmap(
 }
 
 impl<T: LayerIo> DynLayerIo for T {
+    fn layer_type(&self) -> &str {
+        self.layer_type()
+    }
+
     fn sector_count(&self) -> u64 {
         self.sector_count()
     }
@@ -461,7 +868,7 @@ fn attach(
                         read_only: backing.is_logically_read_only(),
                         can_read_cache,
                     },
-                    backing: Box::new(backing),
+                    backing: Arc::new(backing),
                 }
             })
         })
@@ -646,7 +1053,7 @@ fn disk_type(&self) -> &str {
     }
 
     fn sector_count(&self) -> u64 {
-        self.layers[0].backing.sector_count()
+        self.layers()[0].backing.sector_count()
     }
 
     fn sector_size(&self) -> u32 {
@@ -679,8 +1086,9 @@ async fn read_vectored(
         let mut bitmap = Bitmap::new(sector, sector_count);
         let mut bits_set = 0;
         let mut populate_cache = Vec::new();
+        let layers = self.layers();
         // FUTURE: queue the reads to the layers in parallel.
-        'done: for (i, layer) in self.layers.iter().enumerate() {
+        'done: for (i, layer) in layers.iter().enumerate() {
             if bits_set == sector_count {
                 break;
             }
@@ -787,7 +1195,7 @@ async fn write_vectored(
         sector: u64,
         fua: bool,
     ) -> Result<(), DiskError> {
-        for layer in &self.layers {
+        for layer in self.layers().iter() {
             layer.backing.write(buffers, sector, fua, false).await?;
             if !layer.write_through {
                 break;
@@ -797,7 +1205,7 @@ async fn write_vectored(
     }
 
     async fn sync_cache(&self) -> Result<(), DiskError> {
-        for layer in &self.layers {
+        for layer in self.layers().iter() {
             layer.backing.sync_cache().await?;
             if !layer.write_through {
                 break;
@@ -807,7 +1215,8 @@ async fn sync_cache(&self) -> Result<(), DiskError> {
     }
 
     fn wait_resize(&self, sector_count: u64) -> impl Future<Output = u64> + Send {
-        self.layers[0].backing.wait_resize(sector_count)
+        let layers = self.layers();
+        async move { layers[0].backing.wait_resize(sector_count).await }
     }
 
     async fn unmap(
@@ -820,10 +1229,10 @@ async fn unmap(
             return Ok(());
         }
 
-        for (layer, next_layer) in self
-            .layers
+        let layers = self.layers();
+        for (layer, next_layer) in layers
             .iter()
-            .zip(self.layers.iter().map(Some).skip(1).chain([None]))
+            .zip(layers.iter().map(Some).skip(1).chain([None]))
         {
             let next_is_zero = if let Some(next_layer) = next_layer {
                 // Sectors beyond the layer's visible sector count are logically
@@ -935,6 +1344,7 @@ mod tests {
     use crate::LayerConfiguration;
     use crate::LayerIo;
     use crate::LayeredDisk;
+    use crate::ModifyLayersError;
     use crate::SectorMarker;
     use crate::WriteNoOverwrite;
     use disk_backend::DiskIo;
@@ -1167,4 +1577,150 @@ async fn test_read_cache() {
             );
         }
     }
+
+    #[async_test]
+    async fn test_snapshot_commit() {
+        const SIZE: u64 = 2048;
+        let bottom = Arc::new(TestLayer::new(SIZE));
+        let disk = LayeredDisk::new(
+            false,
+            vec![LayerConfiguration {
+                layer: DiskLayer::new(bottom.clone()),
+                read_cache: false,
+                write_through: false,
+            }],
+        )
+        .await
+        .unwrap();
+        let control = disk.control();
+
+        let mut mem = GuestMemory::allocate(0x1000);
+        let buffers = OwnedRequestBuffers::linear(0, 0x1000, true);
+        let write = async |mem: &mut GuestMemory, sector: u64, fill: u8| {
+            mem.inner_buf_mut().unwrap()[..512].fill(fill);
+            disk.write_vectored(&buffers.buffer(mem).subrange(0, 512), sector, false)
+                .await
+                .unwrap();
+        };
+        let read = async |mem: &mut GuestMemory, sector: u64| {
+            disk.read_vectored(&buffers.buffer(mem).subrange(0, 512), sector)
+                .await
+                .unwrap();
+            mem.inner_buf_mut().unwrap()[0]
+        };
+
+        write(&mut mem, 3, 1).await;
+
+        let top = Arc::new(TestLayer::new(SIZE));
+        control
+            .add_top_layer(LayerConfiguration {
+                layer: DiskLayer::new(top.clone()),
+                read_cache: false,
+                write_through: false,
+            })
+            .await
+            .unwrap();
+        assert_eq!(control.layer_count(), 2);
+
+        // Writes after the snapshot only land in the new top layer.
+        write(&mut mem, 3, 2).await;
+        write(&mut mem, 5, 3).await;
+        assert_eq!(bottom.sectors.lock()[&3].0[0], 1);
+        assert!(!bottom.sectors.lock().contains_key(&5));
+        assert_eq!(read(&mut mem, 3).await, 2);
+        assert_eq!(read(&mut mem, 5).await, 3);
+        assert_eq!(control.layer_allocation(0).await.unwrap(), [3..4, 5..6]);
+        assert_eq!(control.layers()[1].sector_count, SIZE);
+
+        control.commit_top_layer().await.unwrap();
+        assert_eq!(control.layer_count(), 1);
+        assert_eq!(bottom.sectors.lock()[&3].0[0], 2);
+        assert_eq!(bottom.sectors.lock()[&5].0[0], 3);
+        assert_eq!(bottom.sectors.lock().len(), 2);
+
+        // The top layer is gone, so new writes go to the bottom layer.
+        write(&mut mem, 7, 4).await;
+        assert!(!top.sectors.lock().contains_key(&7));
+        assert_eq!(read(&mut mem, 7).await, 4);
+
+        assert!(matches!(
+            control.commit_top_layer().await,
+            Err(ModifyLayersError::NoLowerLayer)
+        ));
+    }
+
+    #[async_test]
+    async fn test_replace_top_layers() {
+        const SIZE: u64 = 2048;
+        let bottom = Arc::new(TestLayer::new(SIZE));
+        let disk = LayeredDisk::new(
+            false,
+            vec![LayerConfiguration {
+                layer: DiskLayer::new(bottom.clone()),
+                read_cache: false,
+                write_through: false,
+            }],
+        )
+        .await
+        .unwrap();
+        let control = disk.control();
+        let layer = |layer: &Arc<TestLayer>| LayerConfiguration {
+            layer: DiskLayer::new(layer.clone()),
+            read_cache: false,
+            write_through: false,
+        };
+
+        let mut mem = GuestMemory::allocate(0x1000);
+        let buffers = OwnedRequestBuffers::linear(0, 0x1000, true);
+        let write = async |mem: &mut GuestMemory, sector: u64, fill: u8| {
+            mem.inner_buf_mut().unwrap()[..512].fill(fill);
+            disk.write_vectored(&buffers.buffer(mem).subrange(0, 512), sector, false)
+                .await
+                .unwrap();
+        };
+        let read = async |mem: &mut GuestMemory, sector: u64| {
+            disk.read_vectored(&buffers.buffer(mem).subrange(0, 512), sector)
+                .await
+                .unwrap();
+            mem.inner_buf_mut().unwrap()[0]
+        };
+
+        write(&mut mem, 3, 1).await;
+        let first = Arc::new(TestLayer::new(SIZE));
+        control.add_top_layer(layer(&first)).await.unwrap();
+        write(&mut mem, 3, 2).await;
+        let second = Arc::new(TestLayer::new(SIZE));
+        control.add_top_layer(layer(&second)).await.unwrap();
+        write(&mut mem, 3, 3).await;
+        assert_eq!(read(&mut mem, 3).await, 3);
+
+        // Roll back to the bottom layer with a fresh top layer.
+        let fresh = Arc::new(TestLayer::new(SIZE));
+        control
+            .replace_top_layers(2, vec![layer(&fresh)])
+            .await
+            .unwrap();
+        assert_eq!(control.layer_count(), 2);
+        assert_eq!(read(&mut mem, 3).await, 1);
+        write(&mut mem, 3, 4).await;
+        assert_eq!(fresh.sectors.lock()[&3].0[0], 4);
+        assert_eq!(bottom.sectors.lock()[&3].0[0], 1);
+
+        // Reattach the first layer beneath a new top layer.
+        let top = Arc::new(TestLayer::new(SIZE));
+        control
+            .replace_top_layers(1, vec![layer(&top), layer(&first)])
+            .await
+            .unwrap();
+        assert_eq!(control.layer_count(), 3);
+        assert_eq!(read(&mut mem, 3).await, 2);
+
+        assert!(matches!(
+            control.replace_top_layers(3, Vec::new()).await,
+            Err(ModifyLayersError::RemoveAllLayers {
+                remove: 3,
+                count: 3
+            })
+        ));
+    }
 }
@@ -6,6 +6,7 @@
 use super::InvalidLayeredDisk;
 use super::LayerConfiguration;
 use super::LayeredDisk;
+use super::LayeredDiskControl;
 use super::resolve::ResolveDiskLayerParameters;
 use super::resolve::ResolvedDiskLayer;
 use crate::DiskLayer;
@@ -13,9 +14,14 @@
 use disk_backend::InvalidDisk;
 use disk_backend::resolve::ResolveDiskParameters;
 use disk_backend::resolve::ResolvedDisk;
+use disk_backend_resources::DiskLayerDescription;
 use disk_backend_resources::LayeredDiskHandle;
+use disk_backend_resources::LayeredDiskRequest;
+use disk_backend_resources::ReplaceTopLayers;
 use disk_backend_resources::layer::DiskLayerHandle;
+use futures::StreamExt;
 use futures::future::TryJoinAll;
+use pal_async::task::Spawn;
 use thiserror::Error;
 use vm_resource::AsyncResolveResource;
 use vm_resource::ResolveError;
@@ -23,6 +29,7 @@
 use vm_resource::declare_static_async_resolver;
 use vm_resource::kind::DiskHandleKind;
 use vm_resource::kind::DiskLayerHandleKind;
+use vmcore::vm_task::VmTaskDriverSource;
 
 declare_static_async_resolver! {
     LayeredDiskResolver,
@@ -94,10 +101,109 @@ async fn resolve(
             .await
             .map_err(ResolveLayeredDiskError::CreateDisk)?;
 
+        if let Some(requests) = resource.requests {
+            input
+                .driver_source
+                .simple()
+                .spawn(
+                    "layered-disk-requests",
+                    handle_requests(
+                        input.driver_source.clone(),
+                        disk.control(),
+                        resolver.clone(),
+                        input.read_only,
+                        requests,
+                    ),
+                )
+                .detach();
+        }
+
         ResolvedDisk::new(disk).map_err(ResolveLayeredDiskError::InvalidDisk)
     }
 }
 
+async fn handle_requests(
+    driver_source: VmTaskDriverSource,
+    control: LayeredDiskControl,
+    resolver: ResourceResolver,
+    read_only: bool,
+    mut requests: mesh::Receiver<LayeredDiskRequest>,
+) {
+    while let Some(req) = requests.next().await {
+        match req {
+            LayeredDiskRequest::AddTopLayer(rpc) => {
+                rpc.handle_failable(async |desc: DiskLayerDescription| {
+                    let layer = resolver
+                        .resolve(
+                            desc.layer,
+                            ResolveDiskLayerParameters {
+                                read_only: read_only && !desc.read_cache,
+                                driver_source: &driver_source,
+                            },
+                        )
+                        .await
+                        .map_err(|err| ResolveLayeredDiskError::ResolveLayer(0, err))?;
+
+                    control
+                        .add_top_layer(LayerConfiguration {
+                            layer: layer.0,
+                            write_through: desc.write_through,
+                            read_cache: desc.read_cache,
+                        })
+                        .await?;
+                    tracing::info!(layers = control.layer_count(), "added disk layer");
+                    anyhow::Ok(())
+                })
+                .await
+            }
+            LayeredDiskRequest::CommitTopLayer(rpc) => {
+                rpc.handle_failable(async |()| {
+                    control.commit_top_layer().await?;
+                    tracing::info!(layers = control.layer_count(), "committed disk layer");
+                    anyhow::Ok(())
+                })
+                .await
+            }
+            LayeredDiskRequest::ReplaceTopLayers(rpc) => {
+                rpc.handle_failable(async |req: ReplaceTopLayers| {
+                    let (resolver, driver_source) = (&resolver, &driver_source);
+                    let layers = req
+                        .layers
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, desc)| async move {
+                            let layer = resolver
+                                .resolve(
+                                    desc.layer,
+                                    ResolveDiskLayerParameters {
+                                        read_only: read_only && !desc.read_cache,
+                                        driver_source,
+                                    },
+                                )
+                                .await
+                                .map_err(|err| ResolveLayeredDiskError::ResolveLayer(i, err))?;
+
+                            anyhow::Ok(LayerConfiguration {
+                                layer: layer.0,
+                                write_through: desc.write_through,
+                                read_cache: desc.read_cache,
+                            })
+                        })
+                        .collect::<TryJoinAll<_>>()
+                        .await?;
+
+                    control
+                        .replace_top_layers(req.remove as usize, layers)
+                        .await?;
+                    tracing::info!(layers = control.layer_count(), "replaced disk layers");
+                    anyhow::Ok(())
+                })
+                .await
+            }
+        }
+    }
+}
+
 #[async_trait]
 impl AsyncResolveResource<DiskLayerHandleKind, DiskLayerHandle> for LayeredDiskResolver {
     type Output = ResolvedDiskLayer;
@@ -0,0 +1,228 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! QCOW2 on-disk format definitions.
+//!
+//! See the QEMU `docs/interop/qcow2.txt` specification for details. All
+//! multi-byte on-disk values are big-endian.
+
+use self::packed_nums::*;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+#[expect(non_camel_case_types)]
+mod packed_nums {
+    pub type u32_be = zerocopy::U32<zerocopy::BigEndian>;
+    pub type u64_be = zerocopy::U64<zerocopy::BigEndian>;
+}
+
+/// The QCOW2 magic number, `QFI\xfb`.
+pub const MAGIC: u32 = 0x514649fb;
+
+/// The length of the version 2 header.
+pub const V2_HEADER_LEN: u32 = 72;
+/// The minimum length of the version 3 header.
+pub const V3_MIN_HEADER_LEN: u32 = 104;
+
+/// The QCOW2 header, including the version 3 fields.
+///
+/// For version 2 images, only the first [`V2_HEADER_LEN`] bytes are valid.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct Header {
+    pub magic: u32_be,
+    pub version: u32_be,
+    pub backing_file_offset: u64_be,
+    pub backing_file_size: u32_be,
+    pub cluster_bits: u32_be,
+    pub size: u64_be,
+    pub crypt_method: u32_be,
+    pub l1_size: u32_be,
+    pub l1_table_offset: u64_be,
+    pub refcount_table_offset: u64_be,
+    pub refcount_table_clusters: u32_be,
+    pub nb_snapshots: u32_be,
+    pub snapshots_offset: u64_be,
+    // Version 3 and later.
+    pub incompatible_features: u64_be,
+    pub compatible_features: u64_be,
+    pub autoclear_features: u64_be,
+    pub refcount_order: u32_be,
+    pub header_length: u32_be,
+    // Only valid if `header_length` > 104.
+    pub compression_type: u8,
+    pub padding: [u8; 7],
+}
+
+impl Header {
+    /// Byte offset of the `l1_table_offset` field.
+    pub const L1_TABLE_OFFSET_OFFSET: u64 = 40;
+    /// Byte offset of the `refcount_table_offset` field. The
+    /// `refcount_table_clusters` field immediately follows it.
+    pub const REFCOUNT_TABLE_OFFSET_OFFSET: u64 = 48;
+    /// Byte offset of the `incompatible_features` field.
+    pub const INCOMPATIBLE_FEATURES_OFFSET: u64 = 72;
+    /// Byte offset of the `autoclear_features` field.
+    pub const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+}
+
+/// The header of a header extension. The extension data follows, padded to
+/// a multiple of 8 bytes.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct HeaderExtension {
+    pub extension_type: u32_be,
+    pub length: u32_be,
+}
+
+/// Header extension types.
+pub mod extension {
+    /// End of the header extension area.
+    pub const END: u32 = 0;
+    /// The format name of the backing file (e.g. `raw` or `qcow2`).
+    pub const BACKING_FORMAT: u32 = 0xe2792aca;
+    /// Human-readable names for feature bits.
+    pub const FEATURE_NAME_TABLE: u32 = 0x6803f857;
+    /// Persistent dirty bitmaps.
+    pub const BITMAPS: u32 = 0x23852875;
+    /// Full disk encryption header pointer.
+    pub const FULL_DISK_ENCRYPTION: u32 = 0x0537be77;
+    /// External data file name.
+    pub const EXTERNAL_DATA_FILE: u32 = 0x44415441;
+}
+
+/// Incompatible feature bits.
+pub mod incompatible {
+    /// Refcounts may be inconsistent (lazy refcounts were in use).
+    pub const DIRTY: u64 = 1 << 0;
+    /// The image metadata is known to be corrupt.
+    pub const CORRUPT: u64 = 1 << 1;
+    /// Guest data lives in an external data file.
+    pub const EXTERNAL_DATA_FILE: u64 = 1 << 2;
+    /// The `compression_type` header field is valid.
+    pub const COMPRESSION_TYPE: u64 = 1 << 3;
+    /// L2 entries are 128 bits, with a subcluster allocation bitmap.
+    pub const EXTENDED_L2: u64 = 1 << 4;
+
+    /// The set of incompatible features understood by this implementation.
+    pub const KNOWN: u64 = DIRTY | CORRUPT | EXTERNAL_DATA_FILE | COMPRESSION_TYPE | EXTENDED_L2;
+}
+
+/// Compatible feature bits.
+pub mod compatible {
+    /// Refcount updates may be deferred, marked by [`super::incompatible::DIRTY`].
+    pub const LAZY_REFCOUNTS: u64 = 1 << 0;
+}
+
+/// The zlib (raw deflate) compression type.
+pub const COMPRESSION_TYPE_ZLIB: u8 = 0;
+
+/// No encryption.
+pub const CRYPT_METHOD_NONE: u32 = 0;
+
+/// The refcount order used for newly created images (16-bit refcounts).
+pub const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+
+/// The minimum supported cluster size, in bits.
+pub const MIN_CLUSTER_BITS: u32 = 9;
+/// The maximum supported cluster size, in bits.
+pub const MAX_CLUSTER_BITS: u32 = 21;
+/// The minimum cluster size, in bits, for images with extended L2 entries.
+pub const MIN_EXTENDED_L2_CLUSTER_BITS: u32 = 14;
+
+/// The number of subclusters per cluster when using extended L2 entries.
+pub const SUBCLUSTERS_PER_CLUSTER: u32 = 32;
+/// log2 of [`SUBCLUSTERS_PER_CLUSTER`].
+pub const SUBCLUSTER_SHIFT: u32 = 5;
+
+/// The host offset bits of L1 and standard L2 entries.
+pub const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The host offset bits of refcount table entries.
+pub const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+/// Set in L1 and L2 entries whose target has a refcount of exactly one, and
+/// can therefore be written in place.
+pub const FLAG_COPIED: u64 = 1 << 63;
+/// Set in L2 entries that describe compressed clusters.
+pub const FLAG_COMPRESSED: u64 = 1 << 62;
+/// Set in standard L2 entries whose cluster reads as all zeroes (version 3
+/// only, and not with extended L2 entries).
+pub const FLAG_ZERO: u64 = 1 << 0;
+
+/// The size of the units used to describe compressed cluster lengths.
+pub const COMPRESSED_SECTOR_SIZE: u64 = 512;
+
+/// Returns the mask of subcluster bits in the allocation half of an
+/// extended L2 bitmap.
+pub fn subcluster_mask(start: u32, end: u32) -> u32 {
+    debug_assert!(start <= end && end <= SUBCLUSTERS_PER_CLUSTER);
+    if start == end {
+        0
+    } else {
+        (!0u32 >> (SUBCLUSTERS_PER_CLUSTER - (end - start))) << start
+    }
+}
+
+/// A parsed compressed cluster descriptor.
+#[derive(Debug, Copy, Clone)]
+pub struct CompressedDescriptor {
+    /// The host byte offset of the compressed data.
+    pub offset: u64,
+    /// The number of bytes that may contain compressed data.
+    pub len: u64,
+}
+
+impl CompressedDescriptor {
+    /// Parses the descriptor portion of a compressed L2 entry.
+    pub fn parse(entry: u64, cluster_bits: u32) -> Self {
+        let offset_bits = 62 - (cluster_bits - 8);
+        let offset = entry & ((1 << offset_bits) - 1);
+        let additional_sectors = (entry >> offset_bits) & ((1 << (cluster_bits - 8)) - 1);
+        let len = (additional_sectors + 1) * COMPRESSED_SECTOR_SIZE
+            - (offset & (COMPRESSED_SECTOR_SIZE - 1));
+        Self { offset, len }
+    }
+
+    /// Builds the descriptor portion of a compressed L2 entry.
+    pub fn encode(&self, cluster_bits: u32) -> u64 {
+        let offset_bits = 62 - (cluster_bits - 8);
+        let end = self.offset + self.len;
+        let first_sector = self.offset / COMPRESSED_SECTOR_SIZE;
+        let last_sector = (end - 1) / COMPRESSED_SECTOR_SIZE;
+        let additional_sectors = last_sector - first_sector;
+        self.offset | (additional_sectors << offset_bits) | FLAG_COMPRESSED
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompressedDescriptor;
+    use super::Header;
+    use super::subcluster_mask;
+
+    #[test]
+    fn header_layout() {
+        assert_eq!(size_of::<Header>(), 112);
+    }
+
+    #[test]
+    fn subcluster_masks() {
+        assert_eq!(subcluster_mask(0, 0), 0);
+        assert_eq!(subcluster_mask(0, 32), !0);
+        assert_eq!(subcluster_mask(1, 3), 0b110);
+        assert_eq!(subcluster_mask(31, 32), 1 << 31);
+    }
+
+    #[test]
+    fn compressed_descriptor_round_trip() {
+        let desc = CompressedDescriptor {
+            offset: 0x30_0123,
+            len: 0x1234,
+        };
+        let parsed = CompressedDescriptor::parse(desc.encode(16), 16);
+        assert_eq!(parsed.offset, desc.offset);
+        assert!(parsed.len >= desc.len);
+        assert!(parsed.len < desc.len + 512);
+    }
+}
@@ -0,0 +1,1498 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The QCOW2 image engine: metadata parsing, cluster mapping, allocation, and
+//! refcount management.
+//!
+//! All methods in this module perform blocking file IO and are expected to be
+//! called from a blocking thread pool (via `blocking::unblock`).
+//!
+//! Metadata updates are written through to the file immediately, ordered so
+//! that a crash can leak clusters but never leave an L2 entry pointing at a
+//! cluster with a zero refcount. The image is not marked dirty while open.
+
+use crate::FormatParams;
+use crate::OpenError;
+use crate::format;
+use crate::format::CompressedDescriptor;
+use crate::format::Header;
+use crate::format::HeaderExtension;
+use disk_file::readwriteat::ReadWriteAt;
+use inspect::Inspect;
+use parking_lot::Mutex;
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+/// The number of L2 tables to keep cached.
+const L2_CACHE_TABLES: usize = 64;
+/// The number of refcount blocks to keep cached.
+const REFBLOCK_CACHE_BLOCKS: usize = 16;
+/// The largest L1 table to load, in bytes. This matches QEMU's limit, which
+/// is enough for any disk QEMU can create.
+const MAX_L1_TABLE_SIZE: u64 = 32 << 20;
+/// The largest refcount table to load, in bytes. This matches QEMU's limit.
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 << 20;
+
+/// Immutable image geometry, derived from the header at open.
+#[derive(Debug, Clone, Inspect)]
+pub(crate) struct Geometry {
+    pub version: u32,
+    pub cluster_bits: u32,
+    pub size: u64,
+    pub extended_l2: bool,
+    pub refcount_order: u32,
+    /// log2 of the allocation granularity: the subcluster size with extended
+    /// L2 entries, the cluster size otherwise.
+    pub granule_bits: u32,
+    /// log2 of the number of entries per L2 table.
+    #[inspect(skip)]
+    l2_bits: u32,
+}
+
+impl Geometry {
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    pub fn granule_size(&self) -> u64 {
+        1 << self.granule_bits
+    }
+
+    fn granules_per_cluster(&self) -> u32 {
+        1 << (self.cluster_bits - self.granule_bits)
+    }
+
+    /// The number of u64 words per L2 entry.
+    fn l2_entry_words(&self) -> usize {
+        if self.extended_l2 { 2 } else { 1 }
+    }
+
+    fn l1_index(&self, offset: u64) -> usize {
+        (offset >> (self.cluster_bits + self.l2_bits)) as usize
+    }
+
+    fn l2_index(&self, offset: u64) -> usize {
+        ((offset >> self.cluster_bits) & ((1 << self.l2_bits) - 1)) as usize
+    }
+
+    /// log2 of the number of refcount entries per refcount block.
+    fn refblock_bits(&self) -> u32 {
+        self.cluster_bits + 3 - self.refcount_order
+    }
+
+    fn max_refcount(&self) -> u64 {
+        if self.refcount_order == 6 {
+            u64::MAX
+        } else {
+            (1 << (1 << self.refcount_order)) - 1
+        }
+    }
+}
+
+/// Information about an image's backing file, as recorded in its header.
+#[derive(Debug, Clone, Inspect)]
+pub struct BackingFile {
+    /// The backing file path. Relative paths are relative to the directory
+    /// containing the image.
+    pub path: String,
+    /// The format of the backing file, if recorded (e.g. `raw` or `qcow2`).
+    pub format: Option<String>,
+}
+
+/// An open QCOW2 image.
+#[derive(Inspect)]
+#[inspect(extra = "Self::inspect_extra")]
+pub(crate) struct Qcow2Image {
+    #[inspect(skip)]
+    file: File,
+    #[inspect(flatten)]
+    geometry: Geometry,
+    read_only: bool,
+    backing_file: Option<BackingFile>,
+    #[inspect(skip)]
+    state: RwLock<State>,
+    #[inspect(skip)]
+    cache: Mutex<MetadataCache>,
+}
+
+struct State {
+    l1_offset: u64,
+    l1: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    /// The lowest host cluster index that might be free.
+    free_cluster_hint: u64,
+    /// The number of host clusters below the end of the file (or allocated
+    /// beyond it).
+    file_clusters: u64,
+}
+
+/// The per-granule allocation state.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Granule {
+    /// Not allocated in this image; read from the backing file.
+    Unallocated,
+    /// Reads as zero.
+    Zero,
+    /// Allocated in the image's host cluster.
+    Allocated,
+}
+
+/// A raw L2 entry. `bitmap` is only used with extended L2 entries.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct L2Entry {
+    descriptor: u64,
+    bitmap: u64,
+}
+
+impl L2Entry {
+    const UNALLOCATED: Self = Self {
+        descriptor: 0,
+        bitmap: 0,
+    };
+}
+
+enum Cluster {
+    Standard {
+        /// The host offset of the cluster, or zero if none is allocated.
+        host: u64,
+        granules: [Granule; format::SUBCLUSTERS_PER_CLUSTER as usize],
+    },
+    Compressed(CompressedDescriptor),
+}
+
+/// The kind of a contiguous run of guest data.
+#[derive(Debug, Copy, Clone)]
+enum ExtentKind {
+    Unallocated,
+    Zero,
+    Data {
+        host: u64,
+    },
+    Compressed {
+        desc: CompressedDescriptor,
+        offset_in_cluster: u64,
+    },
+}
+
+#[derive(Debug)]
+struct Extent {
+    guest_offset: u64,
+    len: u64,
+    kind: ExtentKind,
+}
+
+/// The source of the existing contents of unallocated granules that are
+/// partially overwritten.
+#[derive(Copy, Clone)]
+pub(crate) enum CowSource<'a> {
+    /// There is nothing below this image; unallocated data reads as zero.
+    Zero,
+    /// Granule-aligned data read from below this image, keyed by guest
+    /// offset.
+    Provided(&'a BTreeMap<u64, Vec<u8>>),
+}
+
+/// The result of [`Qcow2Image::write`].
+pub(crate) enum WriteOutcome {
+    /// The write completed.
+    Done,
+    /// The write needs the contents of these granule-aligned guest ranges from
+    /// below the image before it can proceed. Nothing was written.
+    NeedBacking(Vec<Range<u64>>),
+}
+
+/// The state to move discarded granules to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum DiscardTarget {
+    /// Drop the data, exposing whatever is below this image.
+    Unallocate,
+    /// Make the data read as zero.
+    Zero,
+}
+
+struct MetadataCache {
+    l2: TableCache<Box<[u64]>>,
+    refblocks: TableCache<Box<[u8]>>,
+    /// The most recently decompressed cluster, keyed by host offset.
+    decompressed: Option<(u64, Box<[u8]>)>,
+}
+
+/// A small FIFO cache of metadata tables, keyed by host offset.
+struct TableCache<T> {
+    map: HashMap<u64, T>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl<T> TableCache<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            map: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn get_or_load(
+        &mut self,
+        offset: u64,
+        load: impl FnOnce() -> io::Result<T>,
+    ) -> io::Result<&mut T> {
+        if !self.map.contains_key(&offset) {
+            let value = load()?;
+            self.insert(offset, value);
+        }
+        Ok(self.map.get_mut(&offset).unwrap())
+    }
+
+    fn insert(&mut self, offset: u64, value: T) {
+        if self.map.insert(offset, value).is_none() {
+            self.order.push_back(offset);
+            while self.order.len() > self.capacity {
+                let old = self.order.pop_front().unwrap();
+                self.map.remove(&old);
+            }
+        }
+    }
+}
+
+fn corrupt(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn read_be_u64s(bytes: &[u8]) -> Vec<u64> {
+    bytes
+        .chunks_exact(8)
+        .map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+        .collect()
+}
+
+fn get_refcount(block: &[u8], index: u64, order: u32) -> u64 {
+    let index = index as usize;
+    match order {
+        0..=3 => {
+            let bits = 1 << order;
+            let bit = index * bits;
+            ((block[bit / 8] >> (bit % 8)) & ((1 << bits) - 1) as u8) as u64
+        }
+        4 => u16::from_be_bytes(block[index * 2..][..2].try_into().unwrap()).into(),
+        5 => u32::from_be_bytes(block[index * 4..][..4].try_into().unwrap()).into(),
+        6 => u64::from_be_bytes(block[index * 8..][..8].try_into().unwrap()),
+        _ => unreachable!(),
+    }
+}
+
+/// Sets a refcount in a refcount block, returning the byte range of the block
+/// that changed.
+fn set_refcount(block: &mut [u8], index: u64, order: u32, value: u64) -> Range<usize> {
+    let index = index as usize;
+    match order {
+        0..=3 => {
+            let bits = 1 << order;
+            let bit = index * bits;
+            let mask = (((1u16 << bits) - 1) as u8) << (bit % 8);
+            let byte = &mut block[bit / 8];
+            *byte = (*byte & !mask) | (((value as u8) << (bit % 8)) & mask);
+            bit / 8..bit / 8 + 1
+        }
+        4 => {
+            block[index * 2..][..2].copy_from_slice(&(value as u16).to_be_bytes());
+            index * 2..index * 2 + 2
+        }
+        5 => {
+            block[index * 4..][..4].copy_from_slice(&(value as u32).to_be_bytes());
+            index * 4..index * 4 + 4
+        }
+        6 => {
+            block[index * 8..][..8].copy_from_slice(&value.to_be_bytes());
+            index * 8..index * 8 + 8
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// The result of parsing an image header.
+struct ParsedHeader {
+    header: Header,
+    geometry: Geometry,
+    backing_file: Option<BackingFile>,
+}
+
+fn parse_header(file: &File, read_only: bool) -> Result<ParsedHeader, OpenError> {
+    let file_len = file.metadata()?.len();
+    if file_len < format::V2_HEADER_LEN as u64 {
+        return Err(OpenError::InvalidFileSize(file_len));
+    }
+    let mut header = Header::new_zeroed();
+    let n = file.read_up_to_at(header.as_mut_bytes(), 0)?;
+    if header.magic.get() != format::MAGIC {
+        return Err(OpenError::InvalidMagic);
+    }
+    let version = header.version.get();
+    let header_len = match version {
+        2 => {
+            // The version 3 fields overlap with the header extensions.
+            header.incompatible_features = 0u64.into();
+            header.compatible_features = 0u64.into();
+            header.autoclear_features = 0u64.into();
+            header.refcount_order = format::DEFAULT_REFCOUNT_ORDER.into();
+            header.header_length = format::V2_HEADER_LEN.into();
+            header.compression_type = format::COMPRESSION_TYPE_ZLIB;
+            format::V2_HEADER_LEN
+        }
+        3 => {
+            let len = header.header_length.get();
+            if len < format::V3_MIN_HEADER_LEN
+                || len % 8 != 0
+                || (len as usize).min(size_of::<Header>()) > n
+            {
+                return Err(OpenError::InvalidHeaderLength(len));
+            }
+            if len <= format::V3_MIN_HEADER_LEN {
+                header.compression_type = format::COMPRESSION_TYPE_ZLIB;
+            }
+            len
+        }
+        version => return Err(OpenError::UnsupportedVersion(version)),
+    };
+
+    let cluster_bits = header.cluster_bits.get();
+    if !(format::MIN_CLUSTER_BITS..=format::MAX_CLUSTER_BITS).contains(&cluster_bits) {
+        return Err(OpenError::InvalidClusterBits(cluster_bits));
+    }
+    let cluster_size = 1u64 << cluster_bits;
+    if header.crypt_method.get() != format::CRYPT_METHOD_NONE {
+        return Err(OpenError::Encrypted);
+    }
+
+    let incompatible = header.incompatible_features.get();
+    if incompatible & !format::incompatible::KNOWN != 0 {
+        return Err(OpenError::UnsupportedFeatures(
+            incompatible & !format::incompatible::KNOWN,
+        ));
+    }
+    if incompatible & format::incompatible::EXTERNAL_DATA_FILE != 0 {
+        return Err(OpenError::ExternalDataFile);
+    }
+    if incompatible & format::incompatible::COMPRESSION_TYPE != 0
+        && header.compression_type != format::COMPRESSION_TYPE_ZLIB
+    {
+        return Err(OpenError::UnsupportedCompressionType(
+            header.compression_type,
+        ));
+    }
+    if !read_only {
+        if incompatible & format::incompatible::CORRUPT != 0 {
+            return Err(OpenError::Corrupt);
+        }
+        if incompatible & format::incompatible::DIRTY != 0 {
+            return Err(OpenError::Dirty);
+        }
+        if header.nb_snapshots.get() != 0 {
+            return Err(OpenError::InternalSnapshots);
+        }
+    }
+
+    let extended_l2 = incompatible & format::incompatible::EXTENDED_L2 != 0;
+    if extended_l2 && cluster_bits < format::MIN_EXTENDED_L2_CLUSTER_BITS {
+        return Err(OpenError::InvalidClusterBits(cluster_bits));
+    }
+    let refcount_order = header.refcount_order.get();
+    if refcount_order > 6 {
+        return Err(OpenError::InvalidRefcountOrder(refcount_order));
+    }
+
+    let l2_entry_bits = if extended_l2 { 4 } else { 3 };
+    let geometry = Geometry {
+        version,
+        cluster_bits,
+        size: header.size.get(),
+        extended_l2,
+        refcount_order,
+        granule_bits: if extended_l2 {
+            cluster_bits - format::SUBCLUSTER_SHIFT
+        } else {
+            cluster_bits
+        },
+        l2_bits: cluster_bits - l2_entry_bits,
+    };
+
+    // Bound the tables, which are loaded into memory on open, by both a fixed
+    // limit and the file size.
+    let l1_needed = geometry.size.div_ceil(cluster_size << geometry.l2_bits);
+    let l1_len = header.l1_size.get() as u64 * 8;
+    if (header.l1_size.get() as u64) < l1_needed || l1_len > MAX_L1_TABLE_SIZE {
+        return Err(OpenError::InvalidL1Size(header.l1_size.get()));
+    }
+    let refcount_table_len = header.refcount_table_clusters.get() as u64 * cluster_size;
+    if refcount_table_len > MAX_REFCOUNT_TABLE_SIZE {
+        return Err(OpenError::InvalidRefcountTableSize(
+            header.refcount_table_clusters.get(),
+        ));
+    }
+    for (name, offset, len) in [
+        ("L1 table", header.l1_table_offset.get(), l1_len),
+        (
+            "refcount table",
+            header.refcount_table_offset.get(),
+            refcount_table_len,
+        ),
+    ] {
+        if offset == 0 || offset % cluster_size != 0 {
+            return Err(OpenError::InvalidTableOffset(name, offset));
+        }
+        if offset.checked_add(len).is_none_or(|end| end > file_len) {
+            return Err(OpenError::TableOutOfBounds(name));
+        }
+    }
+
+    // Parse the header extensions, which run to the end of the first cluster.
+    let mut backing_format = None;
+    let mut offset = header_len as u64;
+    while offset + size_of::<HeaderExtension>() as u64 <= cluster_size {
+        let mut ext = HeaderExtension::new_zeroed();
+        file.read_exact_at(ext.as_mut_bytes(), offset)?;
+        offset += size_of::<HeaderExtension>() as u64;
+        let len = ext.length.get() as u64;
+        if offset + len > cluster_size {
+            return Err(OpenError::InvalidHeaderExtension(ext.extension_type.get()));
+        }
+        match ext.extension_type.get() {
+            format::extension::END => break,
+            format::extension::BACKING_FORMAT => {
+                let mut data = vec![0; len as usize];
+                file.read_exact_at(&mut data, offset)?;
+                backing_format =
+                    Some(String::from_utf8(data).map_err(|_| {
+                        OpenError::InvalidHeaderExtension(ext.extension_type.get())
+                    })?);
+            }
+            // Other extensions are either optional or already rejected via
+            // feature bits.
+            _ => {}
+        }
+        offset += len.next_multiple_of(8);
+    }
+
+    let backing_file = if header.backing_file_offset.get() != 0 {
+        let len = header.backing_file_size.get();
+        let offset = header.backing_file_offset.get();
+        if len == 0 || len > 1023 || offset + len as u64 > cluster_size {
+            return Err(OpenError::InvalidBackingFileName);
+        }
+        let mut data = vec![0; len as usize];
+        file.read_exact_at(&mut data, offset)?;
+        Some(BackingFile {
+            path: String::from_utf8(data).map_err(|_| OpenError::InvalidBackingFileName)?,
+            format: backing_format,
+        })
+    } else {
+        None
+    };
+
+    Ok(ParsedHeader {
+        header,
+        geometry,
+        backing_file,
+    })
+}
+
+/// Reads the backing file information from an image header.
+pub(crate) fn read_backing_file(file: &File) -> Result<Option<BackingFile>, OpenError> {
+    Ok(parse_header(file, true)?.backing_file)
+}
+
+impl Qcow2Image {
+    /// Opens an image.
+    pub fn open(file: File, read_only: bool) -> Result<Self, OpenError> {
+        let ParsedHeader {
+            header,
+            geometry,
+            backing_file,
+        } = parse_header(&file, read_only)?;
+
+        let cluster_size = geometry.cluster_size();
+        let l1_offset = header.l1_table_offset.get();
+        let mut l1 = vec![0; header.l1_size.get() as usize * 8];
+        file.read_exact_at(&mut l1, l1_offset)?;
+        let l1 = read_be_u64s(&l1);
+
+        let refcount_table_offset = header.refcount_table_offset.get();
+        let mut refcount_table =
+            vec![0; header.refcount_table_clusters.get() as usize * cluster_size as usize];
+        file.read_exact_at(&mut refcount_table, refcount_table_offset)?;
+        let refcount_table = read_be_u64s(&refcount_table);
+
+        if !read_only && header.autoclear_features.get() != 0 {
+            // Clear any autoclear features (such as persistent bitmaps), since
+            // their data will not be kept consistent with writes.
+            file.write_all_at(
+                &0u64.to_be_bytes(),
+                format::Header::AUTOCLEAR_FEATURES_OFFSET,
+            )?;
+        }
+
+        let file_clusters = file.metadata()?.len().div_ceil(cluster_size);
+        Ok(Self {
+            file,
+            geometry,
+            read_only,
+            backing_file,
+            state: RwLock::new(State {
+                l1_offset,
+                l1,
+                refcount_table_offset,
+                refcount_table,
+                free_cluster_hint: 0,
+                file_clusters,
+            }),
+            cache: Mutex::new(MetadataCache {
+                l2: TableCache::new(L2_CACHE_TABLES),
+                refblocks: TableCache::new(REFBLOCK_CACHE_BLOCKS),
+                decompressed: None,
+            }),
+        })
+    }
+
+    fn inspect_extra(&self, resp: &mut inspect::Response<'_>) {
+        resp.field("host_clusters", self.state.read().file_clusters);
+    }
+
+    pub fn geometry(&self) -> &Geometry {
+        &self.geometry
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn backing_file(&self) -> Option<&BackingFile> {
+        self.backing_file.as_ref()
+    }
+
+    /// Flushes the image file to stable storage.
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn load_l2(&self, offset: u64) -> io::Result<Box<[u64]>> {
+        let mut data = vec![0; self.geometry.cluster_size() as usize];
+        self.file.read_exact_at(&mut data, offset)?;
+        Ok(read_be_u64s(&data).into())
+    }
+
+    fn load_refblock(&self, offset: u64) -> io::Result<Box<[u8]>> {
+        let mut data = vec![0; self.geometry.cluster_size() as usize];
+        self.file.read_exact_at(&mut data, offset)?;
+        Ok(data.into())
+    }
+
+    /// Looks up the L2 entry for the cluster containing `offset`.
+    fn lookup(&self, state: &State, offset: u64) -> io::Result<L2Entry> {
+        let l1_index = self.geometry.l1_index(offset);
+        let Some(&l1_entry) = state.l1.get(l1_index) else {
+            return Ok(L2Entry::UNALLOCATED);
+        };
+        let l2_offset = l1_entry & format::OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(L2Entry::UNALLOCATED);
+        }
+        let index = self.geometry.l2_index(offset) * self.geometry.l2_entry_words();
+        let mut cache = self.cache.lock();
+        let table = cache
+            .l2
+            .get_or_load(l2_offset, || self.load_l2(l2_offset))?;
+        Ok(L2Entry {
+            descriptor: table[index],
+            bitmap: if self.geometry.extended_l2 {
+                table[index + 1]
+            } else {
+                0
+            },
+        })
+    }
+
+    fn classify(&self, entry: L2Entry) -> io::Result<Cluster> {
+        if entry.descriptor & format::FLAG_COMPRESSED != 0 {
+            return Ok(Cluster::Compressed(CompressedDescriptor::parse(
+                entry.descriptor & !(format::FLAG_COMPRESSED | format::FLAG_COPIED),
+                self.geometry.cluster_bits,
+            )));
+        }
+        let host = entry.descriptor & format::OFFSET_MASK;
+        if host % self.geometry.cluster_size() != 0 {
+            return Err(corrupt(format!("unaligned cluster offset {host:#x}")));
+        }
+        let mut granules = [Granule::Unallocated; format::SUBCLUSTERS_PER_CLUSTER as usize];
+        if self.geometry.extended_l2 {
+            let alloc = entry.bitmap as u32;
+            let zero = (entry.bitmap >> 32) as u32;
+            if alloc & zero != 0 || (alloc != 0 && host == 0) {
+                return Err(corrupt(format!(
+                    "invalid subcluster bitmap {:#x}",
+                    entry.bitmap
+                )));
+            }
+            for (i, granule) in granules.iter_mut().enumerate() {
+                if alloc & (1 << i) != 0 {
+                    *granule = Granule::Allocated;
+                } else if zero & (1 << i) != 0 {
+                    *granule = Granule::Zero;
+                }
+            }
+        } else {
+            granules[0] = if entry.descriptor & format::FLAG_ZERO != 0 {
+                Granule::Zero
+            } else if host != 0 {
+                Granule::Allocated
+            } else {
+                Granule::Unallocated
+            };
+        }
+        Ok(Cluster::Standard { host, granules })
+    }
+
+    fn encode(&self, host: u64, granules: &[Granule]) -> L2Entry {
+        let any_allocated = granules.contains(&Granule::Allocated);
+        let host = if any_allocated { host } else { 0 };
+        let mut entry = L2Entry {
+            descriptor: if host != 0 {
+                host | format::FLAG_COPIED
+            } else {
+                0
+            },
+            bitmap: 0,
+        };
+        if self.geometry.extended_l2 {
+            for (i, &granule) in granules.iter().enumerate() {
+                match granule {
+                    Granule::Unallocated => {}
+                    Granule::Zero => entry.bitmap |= 1 << (i + 32),
+                    Granule::Allocated => entry.bitmap |= 1 << i,
+                }
+            }
+        } else if granules[0] == Granule::Zero {
+            entry.descriptor |= format::FLAG_ZERO;
+        }
+        entry
+    }
+
+    /// Maps a guest byte range to a list of extents.
+    fn map(&self, state: &State, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let g = &self.geometry;
+        let mut extents: Vec<Extent> = Vec::new();
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let cluster_base = pos & !(g.cluster_size() - 1);
+            let cluster_end = (cluster_base + g.cluster_size()).min(end);
+            let cluster = self.classify(self.lookup(state, pos)?)?;
+            while pos < cluster_end {
+                let granule_end = ((pos | (g.granule_size() - 1)) + 1).min(cluster_end);
+                let kind = match &cluster {
+                    Cluster::Compressed(desc) => ExtentKind::Compressed {
+                        desc: *desc,
+                        offset_in_cluster: pos - cluster_base,
+                    },
+                    Cluster::Standard { host, granules } => {
+                        match granules[((pos - cluster_base) >> g.granule_bits) as usize] {
+                            Granule::Unallocated => ExtentKind::Unallocated,
+                            Granule::Zero => ExtentKind::Zero,
+                            Granule::Allocated => ExtentKind::Data {
+                                host: host + (pos - cluster_base),
+                            },
+                        }
+                    }
+                };
+                let len = granule_end - pos;
+                let merged = extents.last_mut().is_some_and(|last| {
+                    let mergeable = match (last.kind, kind) {
+                        (ExtentKind::Unallocated, ExtentKind::Unallocated)
+                        | (ExtentKind::Zero, ExtentKind::Zero) => true,
+                        (ExtentKind::Data { host: a }, ExtentKind::Data { host: b }) => {
+                            a + last.len == b
+                        }
+                        (
+                            ExtentKind::Compressed { desc: a, .. },
+                            ExtentKind::Compressed { desc: b, .. },
+                        ) => a.offset == b.offset,
+                        _ => false,
+                    };
+                    if mergeable {
+                        last.len += len;
+                    }
+                    mergeable
+                });
+                if !merged {
+                    extents.push(Extent {
+                        guest_offset: pos,
+                        len,
+                        kind,
+                    });
+                }
+                pos = granule_end;
+            }
+        }
+        Ok(extents)
+    }
+
+    /// Returns the decompressed contents of a compressed cluster.
+    fn decompress(&self, desc: CompressedDescriptor) -> io::Result<Box<[u8]>> {
+        if let Some((_, data)) = self
+            .cache
+            .lock()
+            .decompressed
+            .as_ref()
+            .filter(|(offset, _)| *offset == desc.offset)
+        {
+            return Ok(data.clone());
+        }
+        let mut input = vec![0; desc.len as usize];
+        let n = self.file.read_up_to_at(&mut input, desc.offset)?;
+        input.truncate(n);
+        let mut output = vec![0; self.geometry.cluster_size() as usize];
+        let mut decompress = flate2::Decompress::new(false);
+        decompress
+            .decompress(&input, &mut output, flate2::FlushDecompress::Finish)
+            .map_err(|err| corrupt(format!("failed to decompress cluster: {err}")))?;
+        if decompress.total_out() != output.len() as u64 {
+            return Err(corrupt("compressed cluster is truncated"));
+        }
+        let output: Box<[u8]> = output.into();
+        self.cache.lock().decompressed = Some((desc.offset, output.clone()));
+        Ok(output)
+    }
+
+    /// Reads `buf.len()` bytes at guest offset `offset`.
+    ///
+    /// Unallocated ranges are zeroed, and their guest byte ranges are returned
+    /// so that the caller can fill them from below the image.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<Vec<Range<u64>>> {
+        if offset
+            .checked_add(buf.len() as u64)
+            .is_none_or(|end| end > self.geometry.size)
+        {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        // Hold the state lock across the data reads so that clusters cannot
+        // be freed and reused while they are being read.
+        let state = self.state.read();
+        let extents = self.map(&state, offset, buf.len() as u64)?;
+        let mut unallocated: Vec<Range<u64>> = Vec::new();
+        for extent in extents {
+            let data = &mut buf[(extent.guest_offset - offset) as usize..][..extent.len as usize];
+            match extent.kind {
+                ExtentKind::Unallocated => {
+                    data.fill(0);
+                    unallocated.push(extent.guest_offset..extent.guest_offset + extent.len);
+                }
+                ExtentKind::Zero => data.fill(0),
+                ExtentKind::Data { host } => self.file.read_exact_at(data, host)?,
+                ExtentKind::Compressed {
+                    desc,
+                    offset_in_cluster,
+                } => {
+                    let cluster = self.decompress(desc)?;
+                    data.copy_from_slice(&cluster[offset_in_cluster as usize..][..data.len()]);
+                }
+            }
+        }
+        Ok(unallocated)
+    }
+
+    /// Writes `data` at guest offset `offset`.
+    pub fn write(&self, offset: u64, data: &[u8], cow: CowSource<'_>) -> io::Result<WriteOutcome> {
+        assert!(!self.read_only);
+        let g = &self.geometry;
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|&end| end <= g.size)
+            .ok_or(io::ErrorKind::InvalidInput)?;
+
+        // Fast path: every touched granule is already allocated in a cluster
+        // that can be written in place, so no metadata changes are needed.
+        {
+            let state = self.state.read();
+            if let Some(targets) = self.in_place_targets(&state, offset, end)? {
+                for (guest, host, len) in targets {
+                    self.file
+                        .write_all_at(&data[(guest - offset) as usize..][..len as usize], host)?;
+                }
+                return Ok(WriteOutcome::Done);
+            }
+        }
+
+        let mut state = self.state.write();
+        if let CowSource::Provided(provided) = cow {
+            let needed = self.needed_backing(&state, offset, end)?;
+            let missing = needed
+                .into_iter()
+                .filter(|range| !provided.contains_key(&range.start))
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                return Ok(WriteOutcome::NeedBacking(missing));
+            }
+        }
+
+        let mut pos = offset;
+        while pos < end {
+            let cluster_end = ((pos | (g.cluster_size() - 1)) + 1).min(end);
+            self.write_cluster(
+                &mut state,
+                pos,
+                &data[(pos - offset) as usize..(cluster_end - offset) as usize],
+                &cow,
+            )?;
+            pos = cluster_end;
+        }
+        Ok(WriteOutcome::Done)
+    }
+
+    /// Returns the host locations to write if the write can be performed
+    /// without any metadata changes.
+    fn in_place_targets(
+        &self,
+        state: &State,
+        offset: u64,
+        end: u64,
+    ) -> io::Result<Option<Vec<(u64, u64, u64)>>> {
+        let g = &self.geometry;
+        let mut targets = Vec::new();
+        let mut pos = offset;
+        while pos < end {
+            let cluster_base = pos & !(g.cluster_size() - 1);
+            let cluster_end = (cluster_base + g.cluster_size()).min(end);
+            let entry = self.lookup(state, pos)?;
+            if entry.descriptor & format::FLAG_COPIED == 0 {
+                return Ok(None);
+            }
+            let Cluster::Standard { host, granules } = self.classify(entry)? else {
+                return Ok(None);
+            };
+            let first = (pos - cluster_base) >> g.granule_bits;
+            let last = (cluster_end - 1 - cluster_base) >> g.granule_bits;
+            if granules[first as usize..=last as usize]
+                .iter()
+                .any(|&granule| granule != Granule::Allocated)
+            {
+                return Ok(None);
+            }
+            targets.push((pos, host + (pos - cluster_base), cluster_end - pos));
+            pos = cluster_end;
+        }
+        Ok(Some(targets))
+    }
+
+    /// Returns the granule-aligned ranges that are partially covered by a
+    /// write and whose existing contents come from below the image.
+    fn needed_backing(&self, state: &State, offset: u64, end: u64) -> io::Result<Vec<Range<u64>>> {
+        let g = &self.geometry;
+        let mut needed = Vec::new();
+        let mut check = |pos: u64| -> io::Result<()> {
+            let granule_start = pos & !(g.granule_size() - 1);
+            let granule_end = granule_start + g.granule_size();
+            if offset <= granule_start && end >= granule_end {
+                return Ok(());
+            }
+            if needed
+                .last()
+                .is_some_and(|r: &Range<u64>| r.start == granule_start)
+            {
+                return Ok(());
+            }
+            let cluster_base = pos & !(g.cluster_size() - 1);
+            if let Cluster::Standard { granules, .. } = self.classify(self.lookup(state, pos)?)? {
+                let index = (granule_start - cluster_base) >> g.granule_bits;
+                if granules[index as usize] == Granule::Unallocated {
+                    needed.push(granule_start..granule_end.min(g.size));
+                }
+            }
+            Ok(())
+        };
+        check(offset)?;
+        check(end - 1)?;
+        Ok(needed)
+    }
+
+    /// Writes data within a single cluster, allocating as necessary.
+    fn write_cluster(
+        &self,
+        state: &mut State,
+        pos: u64,
+        data: &[u8],
+        cow: &CowSource<'_>,
+    ) -> io::Result<()> {
+        let g = &self.geometry;
+        let cs = g.cluster_size();
+        let gs = g.granule_size();
+        let cluster_base = pos & !(cs - 1);
+        let l2_offset = self.l2_for_write(state, pos)?;
+        let l2_index = g.l2_index(pos);
+        let old_entry = self.lookup(state, pos)?;
+
+        let mut to_free = None;
+        let (host, mut granules) = match self.classify(old_entry)? {
+            Cluster::Compressed(desc) => {
+                let content = self.decompress(desc)?;
+                let host = self.alloc_cluster(state)?;
+                self.file.write_all_at(&content, host)?;
+                to_free = Some(Cluster::Compressed(desc));
+                (
+                    host,
+                    [Granule::Allocated; format::SUBCLUSTERS_PER_CLUSTER as usize],
+                )
+            }
+            Cluster::Standard { host, granules } => {
+                if host == 0 {
+                    (self.alloc_cluster(state)?, granules)
+                } else if old_entry.descriptor & format::FLAG_COPIED != 0
+                    || self.refcount(state, host >> g.cluster_bits)? == 1
+                {
+                    (host, granules)
+                } else {
+                    // The cluster is shared; copy it before writing.
+                    let mut content = vec![0; cs as usize];
+                    self.file.read_exact_at(&mut content, host)?;
+                    let new_host = self.alloc_cluster(state)?;
+                    self.file.write_all_at(&content, new_host)?;
+                    to_free = Some(Cluster::Standard { host, granules });
+                    (new_host, granules)
+                }
+            }
+        };
+
+        let start = pos - cluster_base;
+        let end = start + data.len() as u64;
+        let first = start / gs;
+        let last = (end - 1) / gs;
+        let span_start = first * gs;
+        let span_end = (last + 1) * gs;
+
+        // Fill in the parts of partially written granules that are not being
+        // overwritten from their current contents.
+        let fill = |granule: u64, range: Range<u64>, buf: &mut [u8]| -> io::Result<()> {
+            match granules[granule as usize] {
+                Granule::Allocated => self.file.read_exact_at(buf, host + range.start),
+                Granule::Zero => {
+                    buf.fill(0);
+                    Ok(())
+                }
+                Granule::Unallocated => match cow {
+                    CowSource::Zero => {
+                        buf.fill(0);
+                        Ok(())
+                    }
+                    CowSource::Provided(provided) => {
+                        let granule_offset = cluster_base + granule * gs;
+                        let source = provided.get(&granule_offset).ok_or_else(|| {
+                            io::Error::other("missing data for copy-on-write from backing disk")
+                        })?;
+                        let src_start = (range.start - granule * gs) as usize;
+                        // The source may be short at the end of the disk.
+                        for (i, b) in buf.iter_mut().enumerate() {
+                            *b = source.get(src_start + i).copied().unwrap_or(0);
+                        }
+                        Ok(())
+                    }
+                },
+            }
+        };
+
+        if span_start == start && span_end == end {
+            self.file.write_all_at(data, host + start)?;
+        } else {
+            let mut buf = vec![0; (span_end - span_start) as usize];
+            if start > span_start {
+                fill(
+                    first,
+                    span_start..start,
+                    &mut buf[..(start - span_start) as usize],
+                )?;
+            }
+            if end < span_end {
+                fill(last, end..span_end, &mut buf[(end - span_start) as usize..])?;
+            }
+            buf[(start - span_start) as usize..(end - span_start) as usize].copy_from_slice(data);
+            self.file.write_all_at(&buf, host + span_start)?;
+        }
+
+        for granule in &mut granules[first as usize..=last as usize] {
+            *granule = Granule::Allocated;
+        }
+        let new_entry = self.encode(host, &granules[..g.granules_per_cluster() as usize]);
+        if new_entry != old_entry {
+            self.write_l2_entry(l2_offset, l2_index, new_entry)?;
+        }
+        if let Some(cluster) = to_free {
+            self.free_cluster(state, cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Discards the granules fully contained in `offset..offset + len`.
+    pub fn discard(&self, offset: u64, len: u64, target: DiscardTarget) -> io::Result<()> {
+        assert!(!self.read_only);
+        let g = &self.geometry;
+        if target == DiscardTarget::Zero && g.version < 3 {
+            // Version 2 images cannot represent zero clusters.
+            return Ok(());
+        }
+        let mut state = self.state.write();
+        let end = offset.saturating_add(len).min(g.size);
+        let mut pos = offset;
+        while pos < end {
+            let cluster_base = pos & !(g.cluster_size() - 1);
+            let cluster_end = (cluster_base + g.cluster_size()).min(end);
+            let first = (pos - cluster_base).div_ceil(g.granule_size());
+            let last = if cluster_end == g.size {
+                g.granules_per_cluster() as u64
+            } else {
+                (cluster_end - cluster_base) >> g.granule_bits
+            };
+            let chunk = pos;
+            pos = cluster_end;
+            if first >= last {
+                continue;
+            }
+            let entry = self.lookup(&state, chunk)?;
+            if entry == L2Entry::UNALLOCATED && target == DiscardTarget::Unallocate {
+                continue;
+            }
+            let (host, mut granules, to_free) = match self.classify(entry)? {
+                Cluster::Compressed(desc) => {
+                    if first != 0 || last != g.granules_per_cluster() as u64 {
+                        // Partially discarding a compressed cluster would
+                        // require decompressing it; skip it.
+                        continue;
+                    }
+                    (
+                        0,
+                        [Granule::Unallocated; format::SUBCLUSTERS_PER_CLUSTER as usize],
+                        Some(Cluster::Compressed(desc)),
+                    )
+                }
+                Cluster::Standard { host, granules } => (host, granules, None),
+            };
+            let new_state = match target {
+                DiscardTarget::Unallocate => Granule::Unallocated,
+                DiscardTarget::Zero => Granule::Zero,
+            };
+            for granule in &mut granules[first as usize..last as usize] {
+                *granule = new_state;
+            }
+            let granules = &granules[..g.granules_per_cluster() as usize];
+            let new_entry = self.encode(host, granules);
+            if new_entry == entry {
+                continue;
+            }
+            let l2_offset = self.l2_for_write(&mut state, chunk)?;
+            self.write_l2_entry(l2_offset, g.l2_index(chunk), new_entry)?;
+            let to_free = to_free.or_else(|| {
+                (host != 0 && !granules.contains(&Granule::Allocated)).then_some(
+                    Cluster::Standard {
+                        host,
+                        granules: [Granule::Allocated; format::SUBCLUSTERS_PER_CLUSTER as usize],
+                    },
+                )
+            });
+            if let Some(cluster) = to_free {
+                self.free_cluster(&mut state, cluster)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the offset of a writable L2 table covering `offset`, allocating
+    /// one if necessary.
+    fn l2_for_write(&self, state: &mut State, offset: u64) -> io::Result<u64> {
+        let l1_index = self.geometry.l1_index(offset);
+        let l1_entry = *state
+            .l1
+            .get(l1_index)
+            .ok_or_else(|| corrupt("offset is beyond the L1 table"))?;
+        let l2_offset = l1_entry & format::OFFSET_MASK;
+        if l2_offset != 0 {
+            if l1_entry & format::FLAG_COPIED == 0 {
+                if self.refcount(state, l2_offset >> self.geometry.cluster_bits)? != 1 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "writing to shared L2 tables is not supported",
+                    ));
+                }
+                state.l1[l1_index] = l1_entry | format::FLAG_COPIED;
+                self.write_l1_entry(state, l1_index)?;
+            }
+            return Ok(l2_offset);
+        }
+
+        let l2_offset = self.alloc_cluster(state)?;
+        let cs = self.geometry.cluster_size() as usize;
+        self.file.write_all_at(&vec![0; cs], l2_offset)?;
+        self.cache
+            .lock()
+            .l2
+            .insert(l2_offset, vec![0; cs / 8].into());
+        state.l1[l1_index] = l2_offset | format::FLAG_COPIED;
+        self.write_l1_entry(state, l1_index)?;
+        Ok(l2_offset)
+    }
+
+    fn write_l1_entry(&self, state: &State, index: usize) -> io::Result<()> {
+        self.file.write_all_at(
+            &state.l1[index].to_be_bytes(),
+            state.l1_offset + index as u64 * 8,
+        )
+    }
+
+    fn write_l2_entry(&self, l2_offset: u64, index: usize, entry: L2Entry) -> io::Result<()> {
+        let words = self.geometry.l2_entry_words();
+        let index = index * words;
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&entry.descriptor.to_be_bytes());
+        bytes[8..].copy_from_slice(&entry.bitmap.to_be_bytes());
+        self.file
+            .write_all_at(&bytes[..words * 8], l2_offset + index as u64 * 8)?;
+        let mut cache = self.cache.lock();
+        if let Some(table) = cache.l2.map.get_mut(&l2_offset) {
+            table[index] = entry.descriptor;
+            if words == 2 {
+                table[index + 1] = entry.bitmap;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the refcount of a host cluster.
+    fn refcount(&self, state: &State, cluster: u64) -> io::Result<u64> {
+        let g = &self.geometry;
+        let table_index = (cluster >> g.refblock_bits()) as usize;
+        let Some(&entry) = state.refcount_table.get(table_index) else {
+            return Ok(0);
+        };
+        let block_offset = entry & format::REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            return Ok(0);
+        }
+        let mut cache = self.cache.lock();
+        let block = cache
+            .refblocks
+            .get_or_load(block_offset, || self.load_refblock(block_offset))?;
+        Ok(get_refcount(
+            block,
+            cluster & ((1 << g.refblock_bits()) - 1),
+            g.refcount_order,
+        ))
+    }
+
+    /// Adds `delta` to the refcount of a host cluster.
+    fn update_refcount(&self, state: &mut State, cluster: u64, delta: i64) -> io::Result<()> {
+        let g = &self.geometry;
+        let table_index = (cluster >> g.refblock_bits()) as usize;
+        if table_index >= state.refcount_table.len() {
+            self.grow_refcount_table(state, table_index)?;
+        }
+        let mut block_offset =
+            state.refcount_table[table_index] & format::REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            block_offset = self.alloc_refblock(state, table_index)?;
+        }
+        let index = cluster & ((1 << g.refblock_bits()) - 1);
+        let (new, range, bytes) = {
+            let mut cache = self.cache.lock();
+            let block = cache
+                .refblocks
+                .get_or_load(block_offset, || self.load_refblock(block_offset))?;
+            let old = get_refcount(block, index, g.refcount_order);
+            let new = old
+                .checked_add_signed(delta)
+                .filter(|&new| new <= g.max_refcount())
+                .ok_or_else(|| {
+                    corrupt(format!(
+                        "refcount overflow for cluster {cluster:#x} ({old} + {delta})"
+                    ))
+                })?;
+            let range = set_refcount(block, index, g.refcount_order, new);
+            let bytes = block[range.clone()].to_vec();
+            (new, range, bytes)
+        };
+        self.file
+            .write_all_at(&bytes, block_offset + range.start as u64)?;
+        if new == 0 && cluster < state.free_cluster_hint {
+            state.free_cluster_hint = cluster;
+        }
+        Ok(())
+    }
+
+    /// Finds the first cluster at or after `start` with a zero refcount.
+    fn find_free_cluster(&self, state: &State, start: u64) -> io::Result<u64> {
+        let mut cluster = start;
+        while self.refcount(state, cluster)? != 0 {
+            cluster += 1;
+        }
+        Ok(cluster)
+    }
+
+    /// Allocates a single host cluster, returning its offset.
+    fn alloc_cluster(&self, state: &mut State) -> io::Result<u64> {
+        let cluster = self.find_free_cluster(state, state.free_cluster_hint)?;
+        // Advance the hint first so that any refcount blocks allocated while
+        // updating the refcount do not pick the same cluster.
+        state.free_cluster_hint = cluster + 1;
+        state.file_clusters = state.file_clusters.max(cluster + 1);
+        self.update_refcount(state, cluster, 1)?;
+        Ok(cluster << self.geometry.cluster_bits)
+    }
+
+    /// Allocates a refcount block for refcount table entry `table_index`.
+    fn alloc_refblock(&self, state: &mut State, table_index: usize) -> io::Result<u64> {
+        let g = &self.geometry;
+        let cluster = self.find_free_cluster(state, state.free_cluster_hint)?;
+        state.free_cluster_hint = cluster + 1;
+        state.file_clusters = state.file_clusters.max(cluster + 1);
+        let offset = cluster << g.cluster_bits;
+        let mut block: Box<[u8]> = vec![0; g.cluster_size() as usize].into();
+        let self_describing = (cluster >> g.refblock_bits()) as usize == table_index;
+        if self_describing {
+            set_refcount(
+                &mut block,
+                cluster & ((1 << g.refblock_bits()) - 1),
+                g.refcount_order,
+                1,
+            );
+        }
+        self.file.write_all_at(&block, offset)?;
+        self.cache.lock().refblocks.insert(offset, block);
+        if !self_describing {
+            self.update_refcount(state, cluster, 1)?;
+        }
+        state.refcount_table[table_index] = offset;
+        self.file.write_all_at(
+            &offset.to_be_bytes(),
+            state.refcount_table_offset + table_index as u64 * 8,
+        )?;
+        Ok(offset)
+    }
+
+    /// Relocates the refcount table to the end of the file, growing it so that
+    /// it has at least `min_index + 1` entries.
+    fn grow_refcount_table(&self, state: &mut State, min_index: usize) -> io::Result<()> {
+        let g = &self.geometry;
+        let cs = g.cluster_size();
+        let entries_per_cluster = cs / 8;
+        let old_len = state.refcount_table.len() as u64;
+        let table_start = state.file_clusters;
+
+        // Find a table size large enough to cover itself and the refcount
+        // blocks describing it, all placed at the end of the file.
+        let mut new_len = (old_len * 2).max(min_index as u64 + 1);
+        let (table_clusters, new_blocks) = loop {
+            let table_clusters = new_len.div_ceil(entries_per_cluster);
+            let mut new_blocks = Vec::new();
+            loop {
+                let end = table_start + table_clusters + new_blocks.len() as u64;
+                let needed = ((table_start >> g.refblock_bits())
+                    ..=((end - 1) >> g.refblock_bits()))
+                    .filter(|&i| {
+                        state
+                            .refcount_table
+                            .get(i as usize)
+                            .is_none_or(|&e| e & format::REFCOUNT_TABLE_OFFSET_MASK == 0)
+                    })
+                    .collect::<Vec<_>>();
+                if needed.len() == new_blocks.len() {
+                    break;
+                }
+                new_blocks = needed;
+            }
+            let end = table_start + table_clusters + new_blocks.len() as u64;
+            let last_index = (end - 1) >> g.refblock_bits();
+            if last_index < table_clusters * entries_per_cluster {
+                break (table_clusters, new_blocks);
+            }
+            new_len = last_index + 1;
+        };
+        let end = table_start + table_clusters + new_blocks.len() as u64;
+
+        // Account for the new clusters in existing refcount blocks first, via
+        // the old table.
+        for cluster in table_start..end {
+            let index = cluster >> g.refblock_bits();
+            if !new_blocks.contains(&index) {
+                self.update_refcount(state, cluster, 1)?;
+            }
+        }
+
+        let mut new_table = state.refcount_table.clone();
+        new_table.resize((table_clusters * entries_per_cluster) as usize, 0);
+        for (i, &index) in new_blocks.iter().enumerate() {
+            let block_cluster = table_start + table_clusters + i as u64;
+            let block_offset = block_cluster << g.cluster_bits;
+            let mut block: Box<[u8]> = vec![0; cs as usize].into();
+            for cluster in table_start..end {
+                if cluster >> g.refblock_bits() == index {
+                    set_refcount(
+                        &mut block,
+                        cluster & ((1 << g.refblock_bits()) - 1),
+                        g.refcount_order,
+                        1,
+                    );
+                }
+            }
+            self.file.write_all_at(&block, block_offset)?;
+            self.cache.lock().refblocks.insert(block_offset, block);
+            new_table[index as usize] = block_offset;
+        }
+
+        let table_offset = table_start << g.cluster_bits;
+        let table_bytes = new_table
+            .iter()
+            .flat_map(|e| e.to_be_bytes())
+            .collect::<Vec<_>>();
+        self.file.write_all_at(&table_bytes, table_offset)?;
+        self.file.sync_data()?;
+
+        let mut header_bytes = [0; 12];
+        header_bytes[..8].copy_from_slice(&table_offset.to_be_bytes());
+        header_bytes[8..].copy_from_slice(&(table_clusters as u32).to_be_bytes());
+        self.file
+            .write_all_at(&header_bytes, Header::REFCOUNT_TABLE_OFFSET_OFFSET)?;
+        self.file.sync_data()?;
+
+        let old_offset = state.refcount_table_offset;
+        let old_clusters = old_len.div_ceil(entries_per_cluster);
+        state.refcount_table = new_table;
+        state.refcount_table_offset = table_offset;
+        state.file_clusters = state.file_clusters.max(end);
+        state.free_cluster_hint = state.free_cluster_hint.max(end);
+        for cluster in 0..old_clusters {
+            self.update_refcount(state, (old_offset >> g.cluster_bits) + cluster, -1)?;
+        }
+        Ok(())
+    }
+
+    fn free_cluster(&self, state: &mut State, cluster: Cluster) -> io::Result<()> {
+        let g = &self.geometry;
+        match cluster {
+            Cluster::Standard { host, .. } => {
+                self.update_refcount(state, host >> g.cluster_bits, -1)?;
+            }
+            Cluster::Compressed(desc) => {
+                let start = desc.offset & !(format::COMPRESSED_SECTOR_SIZE - 1);
+                let end = desc.offset + desc.len;
+                for cluster in (start >> g.cluster_bits)..=((end - 1) >> g.cluster_bits) {
+                    self.update_refcount(state, cluster, -1)?;
+                }
+                let mut cache = self.cache.lock();
+                if cache
+                    .decompressed
+                    .as_ref()
+                    .is_some_and(|(offset, _)| *offset == desc.offset)
+                {
+                    cache.decompressed = None;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats `file` as an empty QCOW2 version 3 image.
+pub(crate) fn format_image(file: &File, params: &FormatParams<'_>) -> io::Result<()> {
+    let cluster_bits = params.cluster_bits;
+    let cs = 1u64 << cluster_bits;
+    let l2_entry_bits = if params.extended_l2 { 4 } else { 3 };
+    let l2_entries = cs >> l2_entry_bits;
+    let l1_entries = params.size.div_ceil(cs * l2_entries).max(1);
+    let l1_clusters = (l1_entries * 8).div_ceil(cs);
+    let refcount_order = format::DEFAULT_REFCOUNT_ORDER;
+    let refblock_entries = (cs * 8) >> refcount_order;
+
+    // Find a fixed point for the number of metadata clusters, which must all
+    // be described by the initial refcount blocks.
+    let mut table_clusters = 1;
+    let mut blocks = 1;
+    let total = loop {
+        let total = 1 + table_clusters + blocks + l1_clusters;
+        let needed_blocks = total.div_ceil(refblock_entries);
+        let needed_table_clusters = (needed_blocks * 8).div_ceil(cs);
+        if needed_blocks == blocks && needed_table_clusters == table_clusters {
+            break total;
+        }
+        blocks = needed_blocks;
+        table_clusters = needed_table_clusters;
+    };
+    let table_offset = cs;
+    let blocks_offset = table_offset + table_clusters * cs;
+    let l1_offset = blocks_offset + blocks * cs;
+
+    let mut header = Header::new_zeroed();
+    header.magic = format::MAGIC.into();
+    header.version = 3u32.into();
+    header.cluster_bits = cluster_bits.into();
+    header.size = params.size.into();
+    header.l1_size = (l1_entries as u32).into();
+    header.l1_table_offset = l1_offset.into();
+    header.refcount_table_offset = table_offset.into();
+    header.refcount_table_clusters = (table_clusters as u32).into();
+    header.refcount_order = refcount_order.into();
+    header.header_length = (size_of::<Header>() as u32).into();
+    if params.extended_l2 {
+        header.incompatible_features = format::incompatible::EXTENDED_L2.into();
+    }
+
+    let mut first_cluster = header.as_bytes().to_vec();
+    if let Some(format_name) = params.backing_format {
+        first_cluster.extend_from_slice(
+            HeaderExtension {
+                extension_type: format::extension::BACKING_FORMAT.into(),
+                length: (format_name.len() as u32).into(),
+            }
+            .as_bytes(),
+        );
+        first_cluster.extend_from_slice(format_name.as_bytes());
+        first_cluster.resize(first_cluster.len().next_multiple_of(8), 0);
+    }
+    first_cluster.extend_from_slice(HeaderExtension::new_zeroed().as_bytes());
+    if let Some(backing_file) = params.backing_file {
+        let offset = first_cluster.len() as u64;
+        first_cluster.extend_from_slice(backing_file.as_bytes());
+        let header = Header::mut_from_prefix(&mut first_cluster)
+            .expect("header is at the front")
+            .0;
+        header.backing_file_offset = offset.into();
+        header.backing_file_size = (backing_file.len() as u32).into();
+    }
+    if first_cluster.len() as u64 > cs {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "header does not fit in the first cluster",
+        ));
+    }
+
+    let mut table = vec![0u8; (table_clusters * cs) as usize];
+    for i in 0..blocks {
+        table[i as usize * 8..][..8].copy_from_slice(&(blocks_offset + i * cs).to_be_bytes());
+    }
+    let mut refblocks = vec![0u8; (blocks * cs) as usize];
+    for cluster in 0..total {
+        set_refcount(&mut refblocks, cluster, refcount_order, 1);
+    }
+
+    file.set_len(0)?;
+    file.set_len(total * cs)?;
+    file.write_all_at(&first_cluster, 0)?;
+    file.write_all_at(&table, table_offset)?;
+    file.write_all_at(&refblocks, blocks_offset)?;
+    // The L1 table is already zero.
+    file.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,1051 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A native QCOW2 disk implementation.
+//!
+//! Supports QCOW2 version 2 and 3 images, including:
+//!
+//! * backing file chains, with copy-on-write of partially written clusters,
+//! * zero clusters and subcluster allocation (extended L2 entries),
+//! * reading compressed (zlib) clusters, which are decompressed on write, and
+//! * discard, which frees host clusters when no longer referenced.
+//!
+//! Encrypted images, external data files, and writes to images with internal
+//! snapshots are not supported.
+//!
+//! The image can be used either as a standalone disk via [`Qcow2Disk`], with
+//! its backing file (if any) opened by the caller and passed in as a [`Disk`],
+//! or as a layer of a [`disk_layered::LayeredDisk`] via
+//! [`LazyQcow2DiskLayer`], in which case the layers below it take the place
+//! of the backing file.
+
+#![expect(missing_docs)]
+#![forbid(unsafe_code)]
+
+pub mod format;
+mod image;
+pub mod resolver;
+
+pub use image::BackingFile;
+
+use anyhow::Context;
+use blocking::unblock;
+use disk_backend::Disk;
+use disk_backend::DiskError;
+use disk_backend::DiskIo;
+use disk_backend::UnmapBehavior;
+use disk_layered::DiskLayerMetadata;
+use disk_layered::LayerAttach;
+use disk_layered::LayerIo;
+use disk_layered::SectorMarker;
+use guestmem::GuestMemory;
+use image::CowSource;
+use image::DiscardTarget;
+use image::Qcow2Image;
+use image::WriteOutcome;
+use inspect::Inspect;
+use scsi_buffers::OwnedRequestBuffers;
+use scsi_buffers::RequestBuffers;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// The default cluster size for new images, in bits (64KiB).
+pub const DEFAULT_CLUSTER_BITS: u32 = 16;
+
+const DEFAULT_SECTOR_SIZE: u32 = 512;
+
+/// An error encountered while opening or creating a QCOW2 image.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum OpenError {
+    #[error("io error")]
+    Io(#[from] io::Error),
+    #[error("invalid QCOW2 file size: {0}")]
+    InvalidFileSize(u64),
+    #[error("not a QCOW2 image")]
+    InvalidMagic,
+    #[error("unsupported QCOW2 version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("invalid header length: {0}")]
+    InvalidHeaderLength(u32),
+    #[error("invalid cluster bits: {0}")]
+    InvalidClusterBits(u32),
+    #[error("invalid refcount order: {0}")]
+    InvalidRefcountOrder(u32),
+    #[error("invalid L1 table size: {0}")]
+    InvalidL1Size(u32),
+    #[error("invalid {0} offset: {1:#x}")]
+    InvalidTableOffset(&'static str, u64),
+    #[error("invalid refcount table size: {0} clusters")]
+    InvalidRefcountTableSize(u32),
+    #[error("{0} extends past the end of the file")]
+    TableOutOfBounds(&'static str),
+    #[error("invalid header extension {0:#x}")]
+    InvalidHeaderExtension(u32),
+    #[error("invalid backing file name")]
+    InvalidBackingFileName,
+    #[error("invalid disk size: {0}")]
+    InvalidDiskSize(u64),
+    #[error("encrypted images are not supported")]
+    Encrypted,
+    #[error("external data files are not supported")]
+    ExternalDataFile,
+    #[error("unsupported incompatible features: {0:#x}")]
+    UnsupportedFeatures(u64),
+    #[error("unsupported compression type: {0}")]
+    UnsupportedCompressionType(u8),
+    #[error("image is marked corrupt and can only be opened read-only")]
+    Corrupt,
+    #[error("image has inconsistent refcounts and can only be opened read-only")]
+    Dirty,
+    #[error("images with internal snapshots can only be opened read-only")]
+    InternalSnapshots,
+    #[error("image has a backing file, but no backing disk was provided")]
+    BackingDiskRequired,
+    #[error("backing disk sector size {0} is larger than the image's allocation granularity")]
+    BackingSectorSize(u32),
+    #[error(
+        "image allocation granularity {granule} does not match the sector size {sector_size} of the layers below"
+    )]
+    LayerGranularity { granule: u64, sector_size: u32 },
+}
+
+/// Parameters for formatting a new QCOW2 image.
+#[derive(Debug, Clone)]
+pub struct FormatParams<'a> {
+    /// The virtual disk size, in bytes.
+    pub size: u64,
+    /// The cluster size, in bits.
+    pub cluster_bits: u32,
+    /// Whether to use extended L2 entries, which allocate in units of
+    /// 1/32nd of a cluster.
+    pub extended_l2: bool,
+    /// The backing file path to record in the header.
+    pub backing_file: Option<&'a str>,
+    /// The backing file format to record in the header.
+    pub backing_format: Option<&'a str>,
+}
+
+impl FormatParams<'_> {
+    /// Returns parameters for an image of `size` bytes with the default
+    /// cluster size and no backing file.
+    pub fn new(size: u64) -> Self {
+        Self {
+            size,
+            cluster_bits: DEFAULT_CLUSTER_BITS,
+            extended_l2: false,
+            backing_file: None,
+            backing_format: None,
+        }
+    }
+}
+
+/// Formats `file` as an empty QCOW2 image, truncating any existing contents.
+pub fn format(file: &File, params: &FormatParams<'_>) -> Result<(), OpenError> {
+    let min_cluster_bits = if params.extended_l2 {
+        format::MIN_EXTENDED_L2_CLUSTER_BITS
+    } else {
+        format::MIN_CLUSTER_BITS
+    };
+    if !(min_cluster_bits..=format::MAX_CLUSTER_BITS).contains(&params.cluster_bits) {
+        return Err(OpenError::InvalidClusterBits(params.cluster_bits));
+    }
+    if params.size == 0 || params.size % DEFAULT_SECTOR_SIZE as u64 != 0 {
+        return Err(OpenError::InvalidDiskSize(params.size));
+    }
+    if params
+        .backing_file
+        .is_some_and(|name| name.is_empty() || name.len() > 1023)
+    {
+        return Err(OpenError::InvalidBackingFileName);
+    }
+    image::format_image(file, params)?;
+    Ok(())
+}
+
+/// Returns the backing file recorded in the header of the image in `file`,
+/// if any.
+pub fn read_backing_file(file: &File) -> Result<Option<BackingFile>, OpenError> {
+    image::read_backing_file(file)
+}
+
+fn validate_sector_size(image: &Qcow2Image, sector_size: u32) -> Result<u32, OpenError> {
+    let geometry = image.geometry();
+    if sector_size as u64 > geometry.granule_size() {
+        return Err(OpenError::BackingSectorSize(sector_size));
+    }
+    if geometry.size % sector_size as u64 != 0 {
+        return Err(OpenError::InvalidDiskSize(geometry.size));
+    }
+    Ok(sector_size.trailing_zeros())
+}
+
+/// Returns the guest byte range of a `len`-byte request at `sector`, failing
+/// if it does not fit within the image.
+fn guest_range(
+    image: &Qcow2Image,
+    sector_shift: u32,
+    sector: u64,
+    len: usize,
+) -> Result<Range<u64>, DiskError> {
+    let start = sector
+        .checked_mul(1 << sector_shift)
+        .ok_or(DiskError::IllegalBlock)?;
+    let end = start
+        .checked_add(len as u64)
+        .filter(|&end| end <= image.geometry().size)
+        .ok_or(DiskError::IllegalBlock)?;
+    Ok(start..end)
+}
+
+/// Returns the ranges of `range` that are not in `holes`, which must be sorted
+/// and contained within `range`.
+fn complement(range: Range<u64>, holes: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut ranges = Vec::new();
+    let mut pos = range.start;
+    for hole in holes {
+        if hole.start > pos {
+            ranges.push(pos..hole.start);
+        }
+        pos = hole.end;
+    }
+    if pos < range.end {
+        ranges.push(pos..range.end);
+    }
+    ranges
+}
+
+/// An open QCOW2 disk.
+#[derive(Inspect)]
+pub struct Qcow2Disk {
+    #[inspect(flatten)]
+    image: Arc<Qcow2Image>,
+    backing: Option<Disk>,
+    sector_shift: u32,
+}
+
+impl Qcow2Disk {
+    /// Opens a QCOW2 image.
+    ///
+    /// If the image has a backing file, then `backing` must be the opened
+    /// backing file. Reads of data not allocated in the image are satisfied
+    /// from `backing`, which is never written to.
+    pub fn open(file: File, backing: Option<Disk>, read_only: bool) -> Result<Self, OpenError> {
+        let image = Qcow2Image::open(file, read_only)?;
+        if image.backing_file().is_some() && backing.is_none() {
+            return Err(OpenError::BackingDiskRequired);
+        }
+        let sector_size = backing
+            .as_ref()
+            .map_or(DEFAULT_SECTOR_SIZE, |disk| disk.sector_size());
+        let sector_shift = validate_sector_size(&image, sector_size)?;
+        Ok(Self {
+            image: Arc::new(image),
+            backing,
+            sector_shift,
+        })
+    }
+
+    /// Returns the backing file recorded in the image header, if any.
+    pub fn backing_file(&self) -> Option<&BackingFile> {
+        self.image.backing_file()
+    }
+
+    /// Reads `len` bytes at `offset` from the backing disk, zero-extending
+    /// past its end.
+    async fn read_backing(
+        &self,
+        backing: &Disk,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, DiskError> {
+        let mut data = vec![0; len as usize];
+        let backing_len = backing.sector_count() << self.sector_shift;
+        let len = len.min(backing_len.saturating_sub(offset));
+        if len > 0 {
+            let mem = GuestMemory::allocate(len as usize);
+            backing
+                .read_vectored(
+                    &OwnedRequestBuffers::linear(0, len as usize, true).buffer(&mem),
+                    offset >> self.sector_shift,
+                )
+                .await?;
+            mem.read_at(0, &mut data[..len as usize])
+                .expect("bounce buffer is large enough");
+        }
+        Ok(data)
+    }
+}
+
+impl DiskIo for Qcow2Disk {
+    fn disk_type(&self) -> &str {
+        "qcow2"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.image.geometry().size >> self.sector_shift
+    }
+
+    fn sector_size(&self) -> u32 {
+        1 << self.sector_shift
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        None
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        1 << self.sector_shift
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        true
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.image.is_read_only()
+    }
+
+    async fn read_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+    ) -> Result<(), DiskError> {
+        let offset = guest_range(&self.image, self.sector_shift, sector, buffers.len())?.start;
+        let image = self.image.clone();
+        let mut buffer = vec![0; buffers.len()];
+        let (buffer, unallocated) = unblock(move || -> io::Result<_> {
+            let unallocated = image.read(offset, &mut buffer)?;
+            Ok((buffer, unallocated))
+        })
+        .await
+        .map_err(DiskError::Io)?;
+        buffers.writer().write(&buffer)?;
+        if let Some(backing) = &self.backing {
+            for range in unallocated {
+                let backing_len = backing.sector_count() << self.sector_shift;
+                let end = range.end.min(backing_len);
+                if range.start >= end {
+                    continue;
+                }
+                backing
+                    .read_vectored(
+                        &buffers.subrange(
+                            (range.start - offset) as usize,
+                            (end - range.start) as usize,
+                        ),
+                        range.start >> self.sector_shift,
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        if self.image.is_read_only() {
+            return Err(DiskError::ReadOnly);
+        }
+        let offset = guest_range(&self.image, self.sector_shift, sector, buffers.len())?.start;
+        let mut data = vec![0; buffers.len()];
+        buffers.reader().read(&mut data)?;
+        let mut provided = BTreeMap::new();
+        loop {
+            let image = self.image.clone();
+            let has_backing = self.backing.is_some();
+            let (outcome, returned_data, returned_provided) = unblock(move || {
+                let cow = if has_backing {
+                    CowSource::Provided(&provided)
+                } else {
+                    CowSource::Zero
+                };
+                let outcome = image.write(offset, &data, cow);
+                (outcome, data, provided)
+            })
+            .await;
+            data = returned_data;
+            provided = returned_provided;
+            match outcome.map_err(DiskError::Io)? {
+                WriteOutcome::Done => break,
+                WriteOutcome::NeedBacking(ranges) => {
+                    let backing = self
+                        .backing
+                        .as_ref()
+                        .expect("only requested with a backing disk");
+                    for range in ranges {
+                        let backing_data = self
+                            .read_backing(backing, range.start, range.end - range.start)
+                            .await?;
+                        provided.insert(range.start, backing_data);
+                    }
+                }
+            }
+        }
+        if fua {
+            self.sync_cache().await?;
+        }
+        Ok(())
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        let image = self.image.clone();
+        unblock(move || image.flush()).await.map_err(DiskError::Io)
+    }
+
+    async fn unmap(
+        &self,
+        sector: u64,
+        count: u64,
+        _block_level_only: bool,
+    ) -> Result<(), DiskError> {
+        if self.image.is_read_only() {
+            return Err(DiskError::ReadOnly);
+        }
+        if sector
+            .checked_add(count)
+            .is_none_or(|end| end > self.sector_count())
+        {
+            return Err(DiskError::IllegalBlock);
+        }
+        let target = if self.backing.is_some() {
+            DiscardTarget::Zero
+        } else {
+            DiscardTarget::Unallocate
+        };
+        let image = self.image.clone();
+        let offset = sector << self.sector_shift;
+        let len = count << self.sector_shift;
+        unblock(move || image.discard(offset, len, target))
+            .await
+            .map_err(DiskError::Io)
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        let geometry = self.image.geometry();
+        if geometry.granule_size() == 1 << self.sector_shift
+            && (self.backing.is_none() || geometry.version >= 3)
+        {
+            UnmapBehavior::Zeroes
+        } else {
+            UnmapBehavior::Unspecified
+        }
+    }
+
+    fn optimal_unmap_sectors(&self) -> u32 {
+        (self.image.geometry().granule_size() >> self.sector_shift) as u32
+    }
+}
+
+/// Parameters for formatting a QCOW2 disk layer on attach.
+#[derive(Debug, Clone, Default)]
+pub struct LayerFormatParams {
+    /// The disk size, in bytes. If `None`, the size of the layers below is
+    /// used.
+    pub len: Option<u64>,
+}
+
+/// A QCOW2 disk layer that opens (and optionally formats) its image when
+/// attached to a layer stack, so that it can pick up the sector size and
+/// length of the layers below it.
+pub struct LazyQcow2DiskLayer {
+    file: File,
+    read_only: bool,
+    format: Option<LayerFormatParams>,
+}
+
+impl LazyQcow2DiskLayer {
+    /// Returns a new layer for `file`. If `format` is provided, the file will
+    /// be formatted as a new, empty image on attach.
+    pub fn new(file: File, read_only: bool, format: Option<LayerFormatParams>) -> Self {
+        Self {
+            file,
+            read_only,
+            format,
+        }
+    }
+}
+
+impl LayerAttach for LazyQcow2DiskLayer {
+    type Error = anyhow::Error;
+    type Layer = Qcow2DiskLayer;
+
+    async fn attach(
+        self,
+        lower_layer_metadata: Option<DiskLayerMetadata>,
+    ) -> Result<Self::Layer, Self::Error> {
+        let sector_size = lower_layer_metadata
+            .as_ref()
+            .map_or(DEFAULT_SECTOR_SIZE, |m| m.sector_size);
+        let Self {
+            file,
+            read_only,
+            format,
+        } = self;
+        let image = unblock(move || -> anyhow::Result<_> {
+            if let Some(params) = format {
+                let size = params
+                    .len
+                    .or(lower_layer_metadata
+                        .as_ref()
+                        .map(|m| m.sector_count * m.sector_size as u64))
+                    .context("no base layer to infer the disk size from")?;
+                // Allocate in units of one sector so that no copy-on-write
+                // from the layers below is ever required.
+                let cluster_bits = (sector_size.trailing_zeros() + format::SUBCLUSTER_SHIFT)
+                    .max(format::MIN_EXTENDED_L2_CLUSTER_BITS);
+                crate::format(
+                    &file,
+                    &FormatParams {
+                        size,
+                        cluster_bits,
+                        extended_l2: true,
+                        ..FormatParams::new(size)
+                    },
+                )
+                .context("failed to format qcow2 layer")?;
+            }
+            Ok(Qcow2Image::open(file, read_only).context("failed to open qcow2 layer")?)
+        })
+        .await?;
+        let sector_shift = validate_sector_size(&image, sector_size)?;
+        // A layer cannot read the layers below it, so a partial write of an
+        // unallocated granule could not copy the rest of the granule up. This
+        // does not matter if the layer is never written.
+        let has_lower_layers = lower_layer_metadata.is_some();
+        let granule = image.geometry().granule_size();
+        if has_lower_layers && !read_only && granule != sector_size as u64 {
+            return Err(OpenError::LayerGranularity {
+                granule,
+                sector_size,
+            }
+            .into());
+        }
+        Ok(Qcow2DiskLayer {
+            image: Arc::new(image),
+            sector_shift,
+            has_lower_layers,
+        })
+    }
+}
+
+/// A QCOW2 image used as a disk layer.
+///
+/// Any backing file recorded in the image header is ignored; the layers below
+/// this one provide the data for unallocated sectors instead.
+#[derive(Inspect)]
+pub struct Qcow2DiskLayer {
+    #[inspect(flatten)]
+    image: Arc<Qcow2Image>,
+    sector_shift: u32,
+    has_lower_layers: bool,
+}
+
+impl LayerIo for Qcow2DiskLayer {
+    fn layer_type(&self) -> &str {
+        "qcow2"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.image.geometry().size >> self.sector_shift
+    }
+
+    fn sector_size(&self) -> u32 {
+        1 << self.sector_shift
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        None
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        1 << self.sector_shift
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        true
+    }
+
+    fn is_logically_read_only(&self) -> bool {
+        self.image.is_read_only()
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        let image = self.image.clone();
+        unblock(move || image.flush()).await.map_err(DiskError::Io)
+    }
+
+    async fn read(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        mut marker: SectorMarker<'_>,
+    ) -> Result<(), DiskError> {
+        let Range { start: offset, end } =
+            guest_range(&self.image, self.sector_shift, sector, buffers.len())?;
+        let image = self.image.clone();
+        let mut buffer = vec![0; buffers.len()];
+        let (buffer, unallocated) = unblock(move || -> io::Result<_> {
+            let unallocated = image.read(offset, &mut buffer)?;
+            Ok((buffer, unallocated))
+        })
+        .await
+        .map_err(DiskError::Io)?;
+        for range in complement(offset..end, &unallocated) {
+            let start = (range.start - offset) as usize;
+            let len = (range.end - range.start) as usize;
+            buffers
+                .subrange(start, len)
+                .writer()
+                .write(&buffer[start..start + len])?;
+            marker.set_range(range.start >> self.sector_shift..range.end >> self.sector_shift);
+        }
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        let offset = guest_range(&self.image, self.sector_shift, sector, buffers.len())?.start;
+        let mut data = vec![0; buffers.len()];
+        buffers.reader().read(&mut data)?;
+        let image = self.image.clone();
+        let has_lower_layers = self.has_lower_layers;
+        unblock(move || {
+            // With layers below, the granule size was validated on attach to
+            // match the sector size, so whole-sector writes never need their
+            // data. Without, unallocated data reads as zero.
+            let empty = BTreeMap::new();
+            let cow = if has_lower_layers {
+                CowSource::Provided(&empty)
+            } else {
+                CowSource::Zero
+            };
+            match image.write(offset, &data, cow)? {
+                WriteOutcome::Done => Ok(()),
+                WriteOutcome::NeedBacking(_) => Err(io::Error::other(
+                    "partial write of an unallocated cluster requires data from a lower layer",
+                )),
+            }
+        })
+        .await
+        .map_err(DiskError::Io)?;
+        if fua {
+            self.sync_cache().await?;
+        }
+        Ok(())
+    }
+
+    async fn unmap(
+        &self,
+        sector: u64,
+        count: u64,
+        _block_level_only: bool,
+        next_is_zero: bool,
+    ) -> Result<(), DiskError> {
+        if sector
+            .checked_add(count)
+            .is_none_or(|end| end > self.sector_count())
+        {
+            return Err(DiskError::IllegalBlock);
+        }
+        let target = if next_is_zero {
+            DiscardTarget::Unallocate
+        } else {
+            DiscardTarget::Zero
+        };
+        let image = self.image.clone();
+        let offset = sector << self.sector_shift;
+        let len = count << self.sector_shift;
+        unblock(move || image.discard(offset, len, target))
+            .await
+            .map_err(DiskError::Io)
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        let geometry = self.image.geometry();
+        if geometry.granule_size() == 1 << self.sector_shift && geometry.version >= 3 {
+            UnmapBehavior::Zeroes
+        } else {
+            UnmapBehavior::Unspecified
+        }
+    }
+
+    fn optimal_unmap_sectors(&self) -> u32 {
+        (self.image.geometry().granule_size() >> self.sector_shift) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FormatParams;
+    use super::LayerFormatParams;
+    use super::LazyQcow2DiskLayer;
+    use super::Qcow2Disk;
+    use crate::format;
+    use disk_backend::Disk;
+    use disk_backend::DiskError;
+    use disk_file::readwriteat::ReadWriteAt;
+    use disk_layered::DiskLayer;
+    use disk_layered::LayerConfiguration;
+    use disk_layered::LayeredDisk;
+    use disklayer_ram::ram_disk;
+    use guestmem::GuestMemory;
+    use pal_async::async_test;
+    use scsi_buffers::OwnedRequestBuffers;
+    use std::fs::File;
+    use std::io::Write;
+
+    const SIZE: u64 = 16 * 1024 * 1024;
+
+    fn new_image(params: &FormatParams<'_>) -> File {
+        let file = tempfile::tempfile().unwrap();
+        super::format(&file, params).unwrap();
+        file
+    }
+
+    fn open(file: &File, backing: Option<Disk>, read_only: bool) -> Disk {
+        Disk::new(Qcow2Disk::open(file.try_clone().unwrap(), backing, read_only).unwrap()).unwrap()
+    }
+
+    async fn read(disk: &Disk, sector: u64, len: usize) -> Vec<u8> {
+        let mem = GuestMemory::allocate(len);
+        disk.read_vectored(
+            &OwnedRequestBuffers::linear(0, len, true).buffer(&mem),
+            sector,
+        )
+        .await
+        .unwrap();
+        let mut data = vec![0; len];
+        mem.read_at(0, &mut data).unwrap();
+        data
+    }
+
+    async fn write(disk: &Disk, sector: u64, data: &[u8]) {
+        let mem = GuestMemory::allocate(data.len());
+        mem.write_at(0, data).unwrap();
+        disk.write_vectored(
+            &OwnedRequestBuffers::linear(0, data.len(), false).buffer(&mem),
+            sector,
+            false,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn pattern(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    #[async_test]
+    async fn read_write_reopen() {
+        let file = new_image(&FormatParams::new(SIZE));
+        let disk = open(&file, None, false);
+        assert_eq!(disk.sector_count(), SIZE / 512);
+        assert!(read(&disk, 0, 4096).await.iter().all(|&b| b == 0));
+
+        // Unaligned to the cluster size, spanning several clusters.
+        let data = pattern(1, 200 * 1024);
+        write(&disk, 3, &data).await;
+        // A small overwrite within an allocated cluster.
+        write(&disk, 10, &[0xaa; 512]).await;
+        disk.sync_cache().await.unwrap();
+
+        let mut expected = data.clone();
+        expected[7 * 512..8 * 512].fill(0xaa);
+        assert_eq!(read(&disk, 3, data.len()).await, expected);
+        assert!(read(&disk, 0, 3 * 512).await.iter().all(|&b| b == 0));
+        drop(disk);
+
+        let disk = open(&file, None, true);
+        assert_eq!(read(&disk, 3, data.len()).await, expected);
+        assert!(disk.is_read_only());
+    }
+
+    #[async_test]
+    async fn refcount_table_growth() {
+        // With 512-byte clusters, the initial refcount table and block cover
+        // few clusters, so filling the disk requires growing them.
+        let file = new_image(&FormatParams {
+            cluster_bits: 9,
+            ..FormatParams::new(1024 * 1024)
+        });
+        let disk = open(&file, None, false);
+        let data = pattern(2, 1024 * 1024);
+        for (i, chunk) in data.chunks(64 * 1024).enumerate() {
+            write(&disk, i as u64 * 128, chunk).await;
+        }
+        drop(disk);
+        let disk = open(&file, None, false);
+        assert_eq!(read(&disk, 0, data.len()).await, data);
+    }
+
+    #[async_test]
+    async fn backing_chain() {
+        let base_data = pattern(3, SIZE as usize);
+        let mut base_file = tempfile::tempfile().unwrap();
+        base_file.write_all(&base_data).unwrap();
+        let base = Disk::new(disk_file::FileDisk::open(base_file, true).unwrap()).unwrap();
+
+        let file = new_image(&FormatParams {
+            backing_file: Some("base.img"),
+            backing_format: Some("raw"),
+            ..FormatParams::new(SIZE)
+        });
+        let backing = super::read_backing_file(&file).unwrap().unwrap();
+        assert_eq!(backing.path, "base.img");
+        assert_eq!(backing.format.as_deref(), Some("raw"));
+        assert!(matches!(
+            Qcow2Disk::open(file.try_clone().unwrap(), None, false),
+            Err(super::OpenError::BackingDiskRequired)
+        ));
+
+        let disk = open(&file, Some(base), false);
+        assert_eq!(read(&disk, 100, 4096).await, base_data[100 * 512..][..4096]);
+
+        // A partial cluster write must preserve the rest of the cluster from
+        // the backing file.
+        write(&disk, 129, &[0x55; 1024]).await;
+        let mut expected = base_data[..256 * 512].to_vec();
+        expected[129 * 512..131 * 512].fill(0x55);
+        assert_eq!(read(&disk, 0, expected.len()).await, expected);
+
+        // Discarding with a backing file zeroes rather than exposing the
+        // backing data.
+        disk.unmap(128, 128, false).await.unwrap();
+        assert!(read(&disk, 128, 128 * 512).await.iter().all(|&b| b == 0));
+        assert_eq!(read(&disk, 0, 512).await, base_data[..512]);
+    }
+
+    #[async_test]
+    async fn discard_frees_clusters() {
+        let file = new_image(&FormatParams {
+            cluster_bits: 14,
+            extended_l2: true,
+            ..FormatParams::new(SIZE)
+        });
+        let disk = open(&file, None, false);
+        write(&disk, 0, &pattern(4, 64 * 1024)).await;
+        let len = file.metadata().unwrap().len();
+
+        // Discard part of a cluster, then the rest.
+        disk.unmap(0, 1, false).await.unwrap();
+        assert!(read(&disk, 0, 512).await.iter().all(|&b| b == 0));
+        assert_eq!(read(&disk, 1, 512).await, pattern(4, 1024)[512..]);
+        disk.unmap(0, 128, false).await.unwrap();
+        assert!(read(&disk, 0, 64 * 1024).await.iter().all(|&b| b == 0));
+
+        // Freed clusters are reused rather than growing the file.
+        write(&disk, 0, &pattern(5, 64 * 1024)).await;
+        assert_eq!(file.metadata().unwrap().len(), len);
+        assert_eq!(read(&disk, 0, 64 * 1024).await, pattern(5, 64 * 1024));
+    }
+
+    #[async_test]
+    async fn compressed_cluster() {
+        let file = new_image(&FormatParams::new(SIZE));
+        let disk = open(&file, None, false);
+        write(&disk, 0, &[1; 512]).await;
+        drop(disk);
+
+        // Replace the first cluster with a compressed one.
+        let cluster_size = 1 << super::DEFAULT_CLUSTER_BITS;
+        let data = pattern(6, cluster_size);
+        let mut compress = flate2::Compress::new(flate2::Compression::default(), false);
+        let mut compressed = Vec::with_capacity(cluster_size);
+        compress
+            .compress_vec(&data, &mut compressed, flate2::FlushCompress::Finish)
+            .unwrap();
+        let offset = file.metadata().unwrap().len() + 100;
+        file.write_all_at(&compressed, offset).unwrap();
+        let mut l1_offset = [0; 8];
+        file.read_exact_at(&mut l1_offset, format::Header::L1_TABLE_OFFSET_OFFSET)
+            .unwrap();
+        let mut l2_offset = [0; 8];
+        file.read_exact_at(&mut l2_offset, u64::from_be_bytes(l1_offset))
+            .unwrap();
+        let l2_offset = u64::from_be_bytes(l2_offset) & format::OFFSET_MASK;
+        let desc = format::CompressedDescriptor {
+            offset,
+            len: compressed.len() as u64,
+        };
+        file.write_all_at(
+            &desc.encode(super::DEFAULT_CLUSTER_BITS).to_be_bytes(),
+            l2_offset,
+        )
+        .unwrap();
+
+        let disk = open(&file, None, false);
+        assert_eq!(read(&disk, 0, cluster_size).await, data);
+
+        // Writing to the cluster decompresses it.
+        write(&disk, 1, &[2; 512]).await;
+        let mut expected = data;
+        expected[512..1024].fill(2);
+        assert_eq!(read(&disk, 0, cluster_size).await, expected);
+    }
+
+    #[async_test]
+    async fn layered_diff() {
+        let base = ram_disk(SIZE, false).unwrap();
+        write(&base, 0, &pattern(7, 8192)).await;
+
+        let file = tempfile::tempfile().unwrap();
+        let disk = Disk::new(
+            LayeredDisk::new(
+                false,
+                vec![
+                    LayerConfiguration {
+                        layer: DiskLayer::new(LazyQcow2DiskLayer::new(
+                            file.try_clone().unwrap(),
+                            false,
+                            Some(LayerFormatParams::default()),
+                        )),
+                        write_through: false,
+                        read_cache: false,
+                    },
+                    LayerConfiguration {
+                        layer: DiskLayer::from_disk(base.clone()),
+                        write_through: false,
+                        read_cache: false,
+                    },
+                ],
+            )
+            .await
+            .unwrap(),
+        )
+        .unwrap();
+
+        write(&disk, 3, &[9; 512]).await;
+        let mut expected = pattern(7, 8192);
+        expected[3 * 512..4 * 512].fill(9);
+        assert_eq!(read(&disk, 0, 8192).await, expected);
+        // The base layer is untouched.
+        assert_eq!(read(&base, 0, 8192).await, pattern(7, 8192));
+        drop(disk);
+
+        // The layer can be reopened on its own, with the base as its backing
+        // disk.
+        let disk = open(&file, Some(base), true);
+        assert_eq!(read(&disk, 0, 8192).await, expected);
+    }
+
+    fn layer_config(layer: DiskLayer) -> LayerConfiguration {
+        LayerConfiguration {
+            layer,
+            write_through: false,
+            read_cache: false,
+        }
+    }
+
+    #[async_test]
+    async fn layer_large_granule() {
+        // 64 KiB clusters without extended L2 entries allocate whole clusters,
+        // which a layer cannot fill from the layers below it.
+        let file = new_image(&FormatParams::new(SIZE));
+        let base = ram_disk(SIZE, false).unwrap();
+        let result = LayeredDisk::new(
+            false,
+            vec![
+                layer_config(DiskLayer::new(LazyQcow2DiskLayer::new(
+                    file.try_clone().unwrap(),
+                    false,
+                    None,
+                ))),
+                layer_config(DiskLayer::from_disk(base.clone())),
+            ],
+        )
+        .await;
+        assert!(result.is_err());
+
+        // As the bottom layer, a partial write zero fills the rest of the
+        // cluster.
+        let disk = Disk::new(
+            LayeredDisk::new(
+                false,
+                vec![layer_config(DiskLayer::new(LazyQcow2DiskLayer::new(
+                    file.try_clone().unwrap(),
+                    false,
+                    None,
+                )))],
+            )
+            .await
+            .unwrap(),
+        )
+        .unwrap();
+        write(&disk, 3, &[9; 512]).await;
+        let mut expected = vec![0; 1 << super::DEFAULT_CLUSTER_BITS];
+        expected[3 * 512..4 * 512].fill(9);
+        assert_eq!(read(&disk, 0, expected.len()).await, expected);
+        drop(disk);
+
+        // Read only, it can be stacked, and its unallocated clusters expose
+        // the layer below.
+        write(&base, 0, &[7; 2 << super::DEFAULT_CLUSTER_BITS]).await;
+        let disk = Disk::new(
+            LayeredDisk::new(
+                true,
+                vec![
+                    layer_config(DiskLayer::new(LazyQcow2DiskLayer::new(file, true, None))),
+                    layer_config(DiskLayer::from_disk(base)),
+                ],
+            )
+            .await
+            .unwrap(),
+        )
+        .unwrap();
+        expected.extend_from_slice(&[7; 1 << super::DEFAULT_CLUSTER_BITS]);
+        assert_eq!(read(&disk, 0, expected.len()).await, expected);
+    }
+
+    #[async_test]
+    async fn oversized_tables_and_requests() {
+        let file = new_image(&FormatParams::new(SIZE));
+        let open_err = |file: &File| Qcow2Disk::open(file.try_clone().unwrap(), None, true).err();
+
+        // An L1 table far larger than the disk needs.
+        let l1_size_offset = format::Header::L1_TABLE_OFFSET_OFFSET - 4;
+        file.write_all_at(&u32::MAX.to_be_bytes(), l1_size_offset)
+            .unwrap();
+        assert!(matches!(
+            open_err(&file),
+            Some(super::OpenError::InvalidL1Size(u32::MAX))
+        ));
+        file.write_all_at(&1u32.to_be_bytes(), l1_size_offset)
+            .unwrap();
+
+        // A refcount table past the end of the file.
+        let clusters_offset = format::Header::REFCOUNT_TABLE_OFFSET_OFFSET + 8;
+        file.write_all_at(&64u32.to_be_bytes(), clusters_offset)
+            .unwrap();
+        assert!(matches!(
+            open_err(&file),
+            Some(super::OpenError::TableOutOfBounds("refcount table"))
+        ));
+        file.write_all_at(&1u32.to_be_bytes(), clusters_offset)
+            .unwrap();
+
+        // Requests whose byte offsets overflow are rejected.
+        let disk = open(&file, None, true);
+        let mem = GuestMemory::allocate(512);
+        assert!(matches!(
+            disk.read_vectored(
+                &OwnedRequestBuffers::linear(0, 512, true).buffer(&mem),
+                u64::MAX >> 1,
+            )
+            .await,
+            Err(DiskError::IllegalBlock)
+        ));
+    }
+}
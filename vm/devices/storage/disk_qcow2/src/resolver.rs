@@ -0,0 +1,87 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolvers for QCOW2 disks and disk layers.
+
+use crate::LayerFormatParams;
+use crate::LazyQcow2DiskLayer;
+use crate::Qcow2Disk;
+use anyhow::Context;
+use async_trait::async_trait;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend::resolve::ResolvedDisk;
+use disk_backend_resources::Qcow2DiskHandle;
+use disk_backend_resources::layer::Qcow2DiskLayerFormatParams;
+use disk_backend_resources::layer::Qcow2DiskLayerHandle;
+use disk_layered::resolve::ResolveDiskLayerParameters;
+use disk_layered::resolve::ResolvedDiskLayer;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::DiskHandleKind;
+use vm_resource::kind::DiskLayerHandleKind;
+
+/// Resolver for a [`Qcow2DiskHandle`].
+pub struct Qcow2Resolver;
+declare_static_async_resolver!(Qcow2Resolver, (DiskHandleKind, Qcow2DiskHandle));
+
+#[async_trait]
+impl AsyncResolveResource<DiskHandleKind, Qcow2DiskHandle> for Qcow2Resolver {
+    type Output = ResolvedDisk;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: Qcow2DiskHandle,
+        input: ResolveDiskParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let backing = if let Some(backing) = rsrc.backing {
+            // The backing disk is never written to.
+            let backing = resolver
+                .resolve(
+                    backing,
+                    ResolveDiskParameters {
+                        read_only: true,
+                        driver_source: input.driver_source,
+                    },
+                )
+                .await
+                .context("failed to open qcow2 backing disk")?;
+            Some(backing.0)
+        } else {
+            None
+        };
+        let disk = blocking::unblock(move || Qcow2Disk::open(rsrc.file, backing, input.read_only))
+            .await
+            .context("failed to open qcow2 image")?;
+        Ok(ResolvedDisk::new(disk)?)
+    }
+}
+
+/// Resolver for a [`Qcow2DiskLayerHandle`].
+pub struct Qcow2DiskLayerResolver;
+declare_static_resolver!(
+    Qcow2DiskLayerResolver,
+    (DiskLayerHandleKind, Qcow2DiskLayerHandle)
+);
+
+impl ResolveResource<DiskLayerHandleKind, Qcow2DiskLayerHandle> for Qcow2DiskLayerResolver {
+    type Output = ResolvedDiskLayer;
+    type Error = anyhow::Error;
+
+    fn resolve(
+        &self,
+        rsrc: Qcow2DiskLayerHandle,
+        input: ResolveDiskLayerParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let Qcow2DiskLayerHandle { file, format } = rsrc;
+        Ok(ResolvedDiskLayer::new(LazyQcow2DiskLayer::new(
+            file,
+            input.read_only,
+            format.map(|Qcow2DiskLayerFormatParams { len }| LayerFormatParams { len }),
+        )))
+    }
+}
@@ -0,0 +1,560 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Dynamic and differencing VHD1 images.
+//!
+//! A dynamic image consists of a copy of the footer, the dynamic header, the
+//! block allocation table (BAT), and then the allocated blocks, followed by
+//! the footer. Each block is preceded by a sector bitmap recording which of
+//! its sectors are present in the image. Sectors that are not present read as
+//! zero for a dynamic disk, or come from the parent for a differencing disk.
+
+use crate::OpenError;
+use disk_file::readwriteat::ReadWriteAt;
+use guid::Guid;
+use inspect::Inspect;
+use parking_lot::RwLock;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use vhd1_defs::VhdDynamicHeader;
+use vhd1_defs::VhdFooter;
+use vhd1_defs::VhdParentLocator;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+pub(crate) const SECTOR_SIZE: u64 = 512;
+
+/// The offset of the dynamic header in images created by [`format`].
+const HEADER_OFFSET: u64 = VhdFooter::LEN;
+
+/// The maximum length of a parent locator's path, in bytes.
+const MAX_LOCATOR_LEN: u32 = 0x10000;
+
+/// The location of the parent of a differencing disk, as recorded in its
+/// dynamic header.
+#[derive(Debug, Clone, Inspect)]
+pub struct ParentLocator {
+    /// The parent's unique ID, which must match the parent's footer.
+    pub unique_id: Guid,
+    /// The parent's modification time stamp, in seconds since January 1,
+    /// 2000 UTC.
+    pub time_stamp: u32,
+    /// The parent's file name.
+    pub name: String,
+    /// The path to the parent, relative to the child, from the `W2ru`
+    /// locator.
+    pub relative_path: Option<String>,
+    /// The absolute path to the parent, from the `W2ku` locator.
+    pub absolute_path: Option<String>,
+}
+
+/// The parent of a new differencing disk.
+#[derive(Debug, Clone)]
+pub struct ParentParams<'a> {
+    /// The parent's unique ID, from [`Vhd1Info::unique_id`](crate::Vhd1Info).
+    pub unique_id: Guid,
+    /// The parent's time stamp, from
+    /// [`Vhd1Info::time_stamp`](crate::Vhd1Info).
+    pub time_stamp: u32,
+    /// The path to the parent, relative to the directory of the new disk.
+    pub relative_path: &'a str,
+    /// The absolute Windows path to the parent, if known.
+    pub absolute_path: Option<&'a str>,
+}
+
+/// Parameters for formatting a new dynamic or differencing VHD1 image.
+#[derive(Debug, Clone)]
+pub struct FormatParams<'a> {
+    /// The virtual disk size, in bytes.
+    pub size: u64,
+    /// The block size, in bytes. Must be a power of two of at least 4KB.
+    pub block_size: u32,
+    /// The parent, for a differencing disk. `size` must match the parent's
+    /// size.
+    pub parent: Option<ParentParams<'a>>,
+}
+
+impl FormatParams<'_> {
+    /// Returns parameters for a dynamic image of `size` bytes with the
+    /// default block size.
+    pub fn new(size: u64) -> Self {
+        Self {
+            size,
+            block_size: VhdDynamicHeader::DEFAULT_BLOCK_SIZE,
+            parent: None,
+        }
+    }
+}
+
+/// The maximum size of a dynamic VHD, 2040GB.
+pub const MAX_DISK_SIZE: u64 = 2040 << 30;
+
+const MIN_BLOCK_SIZE: u32 = 4096;
+
+fn decode_utf16(units: impl Iterator<Item = u16>) -> Result<String, OpenError> {
+    let units = units.collect::<Vec<_>>();
+    let len = units.iter().position(|&c| c == 0).unwrap_or(units.len());
+    String::from_utf16(&units[..len]).map_err(|_| OpenError::InvalidParentLocator)
+}
+
+/// Reads and validates the dynamic header referenced by `footer`.
+pub(crate) fn read_header(
+    file: &File,
+    footer: &VhdFooter,
+) -> Result<(VhdDynamicHeader, Option<ParentLocator>), OpenError> {
+    let mut header = VhdDynamicHeader::new_zeroed();
+    file.read_exact_at(header.as_mut_bytes(), footer.data_offset.get())?;
+    if header.cookie != VhdDynamicHeader::COOKIE_MAGIC {
+        return Err(OpenError::InvalidDynamicHeaderCookie);
+    }
+    if header.checksum.get() != header.compute_checksum() {
+        return Err(OpenError::InvalidDynamicHeaderChecksum);
+    }
+    if header.header_version.get() != VhdDynamicHeader::HEADER_VERSION {
+        return Err(OpenError::UnsupportedVersion(header.header_version.get()));
+    }
+    let block_size = header.block_size.get();
+    if !block_size.is_power_of_two() || block_size < MIN_BLOCK_SIZE {
+        return Err(OpenError::InvalidBlockSize(block_size));
+    }
+    let disk_size = footer.current_size.get();
+    if disk_size.div_ceil(block_size as u64) > header.max_table_entries.get() as u64 {
+        return Err(OpenError::InvalidDiskSize(disk_size));
+    }
+    let parent = if footer.disk_type.get() == VhdFooter::DISK_TYPE_DIFFERENCING {
+        Some(read_parent_locator(file, &header)?)
+    } else {
+        None
+    };
+    Ok((header, parent))
+}
+
+fn read_parent_locator(file: &File, header: &VhdDynamicHeader) -> Result<ParentLocator, OpenError> {
+    let name = decode_utf16(
+        header
+            .parent_unicode_name
+            .chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]])),
+    )?;
+    let mut locator = ParentLocator {
+        unique_id: header.parent_unique_id,
+        time_stamp: header.parent_time_stamp.get(),
+        name,
+        relative_path: None,
+        absolute_path: None,
+    };
+    for entry in &header.parent_locators {
+        let path = match entry.platform_code.get() {
+            VhdParentLocator::PLATFORM_CODE_W2RU => &mut locator.relative_path,
+            VhdParentLocator::PLATFORM_CODE_W2KU => &mut locator.absolute_path,
+            _ => continue,
+        };
+        let len = entry.platform_data_length.get();
+        if len > MAX_LOCATOR_LEN || len % 2 != 0 {
+            return Err(OpenError::InvalidParentLocator);
+        }
+        let mut data = vec![0; len as usize];
+        file.read_exact_at(&mut data, entry.platform_data_offset.get())?;
+        *path = Some(decode_utf16(
+            data.chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]])),
+        )?);
+    }
+    Ok(locator)
+}
+
+/// Formats `file` as an empty dynamic or differencing image, truncating any
+/// existing contents.
+pub(crate) fn format(file: &File, params: &FormatParams<'_>) -> Result<(), OpenError> {
+    let block_size = params.block_size;
+    if !block_size.is_power_of_two() || block_size < MIN_BLOCK_SIZE {
+        return Err(OpenError::InvalidBlockSize(block_size));
+    }
+    let size = params.size;
+    if size == 0 || size > MAX_DISK_SIZE || size % SECTOR_SIZE != 0 {
+        return Err(OpenError::InvalidDiskSize(size));
+    }
+
+    let max_table_entries = size.div_ceil(block_size as u64);
+    let table_offset = HEADER_OFFSET + VhdDynamicHeader::LEN;
+    let table_len = (max_table_entries * 4).next_multiple_of(SECTOR_SIZE);
+    let mut next_offset = table_offset + table_len;
+
+    let mut header = VhdDynamicHeader {
+        cookie: VhdDynamicHeader::COOKIE_MAGIC,
+        data_offset: VhdDynamicHeader::DATA_OFFSET.into(),
+        table_offset: table_offset.into(),
+        header_version: VhdDynamicHeader::HEADER_VERSION.into(),
+        max_table_entries: (max_table_entries as u32).into(),
+        block_size: block_size.into(),
+        ..FromZeros::new_zeroed()
+    };
+
+    let mut locators = Vec::new();
+    let disk_type = if let Some(parent) = &params.parent {
+        header.parent_unique_id = parent.unique_id;
+        header.parent_time_stamp = parent.time_stamp.into();
+
+        // The name is the final component of the path, in either style.
+        let name = parent
+            .relative_path
+            .rsplit(['\\', '/'])
+            .next()
+            .unwrap_or_default();
+        let name = name
+            .encode_utf16()
+            .flat_map(u16::to_be_bytes)
+            .collect::<Vec<_>>();
+        header
+            .parent_unicode_name
+            .get_mut(..name.len())
+            .ok_or(OpenError::InvalidParentLocator)?
+            .copy_from_slice(&name);
+
+        let paths = [
+            (
+                VhdParentLocator::PLATFORM_CODE_W2RU,
+                Some(parent.relative_path),
+            ),
+            (VhdParentLocator::PLATFORM_CODE_W2KU, parent.absolute_path),
+        ];
+        for (entry, (code, path)) in header.parent_locators.iter_mut().zip(
+            paths
+                .into_iter()
+                .filter_map(|(code, path)| Some((code, path?))),
+        ) {
+            let data = path
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<_>>();
+            if data.len() as u64 > MAX_LOCATOR_LEN as u64 {
+                return Err(OpenError::InvalidParentLocator);
+            }
+            let space = (data.len() as u64)
+                .next_multiple_of(SECTOR_SIZE)
+                .max(SECTOR_SIZE);
+            *entry = VhdParentLocator {
+                platform_code: code.into(),
+                platform_data_space: (space as u32).into(),
+                platform_data_length: (data.len() as u32).into(),
+                reserved: 0.into(),
+                platform_data_offset: next_offset.into(),
+            };
+            locators.push((next_offset, data));
+            next_offset += space;
+        }
+        VhdFooter::DISK_TYPE_DIFFERENCING
+    } else {
+        VhdFooter::DISK_TYPE_DYNAMIC
+    };
+    header.checksum = header.compute_checksum().into();
+
+    let footer = VhdFooter::new_dynamic(size, Guid::new_random(), disk_type, HEADER_OFFSET);
+
+    file.set_len(0)?;
+    file.write_all_at(footer.as_bytes(), 0)?;
+    file.write_all_at(header.as_bytes(), HEADER_OFFSET)?;
+    file.write_all_at(&vec![0xff; table_len as usize], table_offset)?;
+    for (offset, data) in locators {
+        file.write_all_at(&data, offset)?;
+    }
+    file.write_all_at(footer.as_bytes(), next_offset)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// The immutable layout of an open image.
+#[derive(Debug, Inspect)]
+pub(crate) struct Geometry {
+    pub disk_size: u64,
+    pub block_size: u32,
+    /// The length of each block's sector bitmap, including padding.
+    bitmap_len: u64,
+    #[inspect(hex)]
+    table_offset: u64,
+    pub unique_id: Guid,
+    pub parent: Option<ParentLocator>,
+}
+
+#[derive(Debug)]
+struct State {
+    bat: Vec<u32>,
+    /// The offset of the footer at the end of the file, which is where the
+    /// next block will be allocated.
+    footer_offset: u64,
+}
+
+/// An open dynamic or differencing image.
+#[derive(Debug, Inspect)]
+pub(crate) struct DynamicImage {
+    #[inspect(skip)]
+    file: File,
+    read_only: bool,
+    #[inspect(flatten)]
+    geometry: Geometry,
+    #[inspect(skip)]
+    footer: VhdFooter,
+    #[inspect(rename = "allocated_blocks", with = "allocated_blocks")]
+    state: RwLock<State>,
+}
+
+fn allocated_blocks(state: &RwLock<State>) -> usize {
+    state
+        .read()
+        .bat
+        .iter()
+        .filter(|&&entry| entry != VhdDynamicHeader::BAT_ENTRY_UNUSED)
+        .count()
+}
+
+impl DynamicImage {
+    /// Opens the image in `file`, whose footer is `footer` at offset
+    /// `footer_offset`.
+    pub fn open(
+        file: File,
+        footer: VhdFooter,
+        footer_offset: u64,
+        read_only: bool,
+    ) -> Result<Self, OpenError> {
+        let (header, parent) = read_header(&file, &footer)?;
+        let block_size = header.block_size.get();
+        let bitmap_len = (block_size as u64 / SECTOR_SIZE)
+            .div_ceil(8)
+            .next_multiple_of(SECTOR_SIZE);
+        let table_offset = header.table_offset.get();
+        let mut bat_bytes = vec![0; header.max_table_entries.get() as usize * 4];
+        file.read_exact_at(&mut bat_bytes, table_offset)?;
+        let bat = bat_bytes
+            .chunks_exact(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        for (i, &entry) in bat.iter().enumerate() {
+            if entry != VhdDynamicHeader::BAT_ENTRY_UNUSED
+                && entry as u64 * SECTOR_SIZE + bitmap_len + block_size as u64 > footer_offset
+            {
+                return Err(OpenError::InvalidBatEntry(i as u64, entry));
+            }
+        }
+        Ok(Self {
+            file,
+            read_only,
+            geometry: Geometry {
+                disk_size: footer.current_size.get(),
+                block_size,
+                bitmap_len,
+                table_offset,
+                unique_id: footer.unique_id,
+                parent,
+            },
+            footer,
+            state: RwLock::new(State { bat, footer_offset }),
+        })
+    }
+
+    pub fn geometry(&self) -> &Geometry {
+        &self.geometry
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    fn read_bitmap(&self, block_start: u64) -> io::Result<Vec<u8>> {
+        let mut bitmap = vec![0; self.geometry.bitmap_len as usize];
+        self.file.read_exact_at(&mut bitmap, block_start)?;
+        Ok(bitmap)
+    }
+
+    /// Sets or clears the bitmap bits for `sectors` (relative to the start of
+    /// the block) of the block at `block_start`.
+    fn update_bitmap(&self, block_start: u64, sectors: Range<u64>, set: bool) -> io::Result<()> {
+        let first_byte = sectors.start / 8;
+        let mut bytes = vec![0; (sectors.end.div_ceil(8) - first_byte) as usize];
+        self.file
+            .read_exact_at(&mut bytes, block_start + first_byte)?;
+        for sector in sectors {
+            let bit = 0x80 >> (sector % 8);
+            let byte = &mut bytes[(sector / 8 - first_byte) as usize];
+            if set {
+                *byte |= bit;
+            } else {
+                *byte &= !bit;
+            }
+        }
+        self.file.write_all_at(&bytes, block_start + first_byte)
+    }
+
+    /// Calls `f` for each portion of `offset..offset + len` that falls in a
+    /// single block, with the disk offset, the block index, the offset within
+    /// the block, and the length.
+    fn for_each_block(
+        &self,
+        offset: u64,
+        len: u64,
+        mut f: impl FnMut(u64, usize, u64, u64) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let block_size = self.geometry.block_size as u64;
+        let mut pos = 0;
+        while pos < len {
+            let disk_offset = offset + pos;
+            let block_offset = disk_offset % block_size;
+            let this_len = (block_size - block_offset).min(len - pos);
+            f(
+                disk_offset,
+                (disk_offset / block_size) as usize,
+                block_offset,
+                this_len,
+            )?;
+            pos += this_len;
+        }
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes at `offset`, returning the ranges of the disk
+    /// that are not present in the image. The corresponding portions of `buf`
+    /// are left untouched.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<Vec<Range<u64>>> {
+        let state = self.state.read();
+        let mut absent: Vec<Range<u64>> = Vec::new();
+        let mut add_absent = |range: Range<u64>| match absent.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => absent.push(range),
+        };
+        self.for_each_block(
+            offset,
+            buf.len() as u64,
+            |disk_offset, block, block_offset, len| {
+                let entry = state.bat[block];
+                if entry == VhdDynamicHeader::BAT_ENTRY_UNUSED {
+                    add_absent(disk_offset..disk_offset + len);
+                    return Ok(());
+                }
+                let block_start = entry as u64 * SECTOR_SIZE;
+                let data_start = block_start + self.geometry.bitmap_len;
+                let bitmap = self.read_bitmap(block_start)?;
+                let is_present = |i: u64| {
+                    let sector = block_offset / SECTOR_SIZE + i;
+                    bitmap[(sector / 8) as usize] & (0x80 >> (sector % 8)) != 0
+                };
+                let sectors = len / SECTOR_SIZE;
+                let mut i = 0;
+                while i < sectors {
+                    let present = is_present(i);
+                    let mut end = i + 1;
+                    while end < sectors && is_present(end) == present {
+                        end += 1;
+                    }
+                    let range = i * SECTOR_SIZE..end * SECTOR_SIZE;
+                    if present {
+                        let buf_start = (disk_offset - offset + range.start) as usize;
+                        self.file.read_exact_at(
+                            &mut buf[buf_start..buf_start + (range.end - range.start) as usize],
+                            data_start + block_offset + range.start,
+                        )?;
+                    } else {
+                        add_absent(disk_offset + range.start..disk_offset + range.end);
+                    }
+                    i = end;
+                }
+                Ok(())
+            },
+        )?;
+        Ok(absent)
+    }
+
+    /// Allocates a new block at the end of the file, moving the footer past
+    /// it. Returns the new BAT entry.
+    ///
+    /// The BAT itself is not updated until the block's data and bitmap have
+    /// been written, so a crash leaves at most an unreferenced block behind.
+    fn allocate_block(&self, state: &mut State) -> io::Result<u32> {
+        let block_start = state.footer_offset;
+        let entry = u32::try_from(block_start / SECTOR_SIZE)
+            .ok()
+            .filter(|&entry| entry != VhdDynamicHeader::BAT_ENTRY_UNUSED)
+            .ok_or_else(|| io::Error::other("vhd file is too large"))?;
+        let footer_offset =
+            block_start + self.geometry.bitmap_len + self.geometry.block_size as u64;
+        // The old footer is overwritten by the new bitmap. If the new footer
+        // does not make it to disk, the copy at the start of the file is used
+        // on the next open.
+        self.file
+            .write_all_at(&vec![0; self.geometry.bitmap_len as usize], block_start)?;
+        self.file
+            .write_all_at(self.footer.as_bytes(), footer_offset)?;
+        state.footer_offset = footer_offset;
+        Ok(entry)
+    }
+
+    /// Writes `data` at `offset`.
+    pub fn write(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        assert!(!self.read_only);
+        let mut state = self.state.write();
+        self.for_each_block(
+            offset,
+            data.len() as u64,
+            |disk_offset, block, block_offset, len| {
+                let mut entry = state.bat[block];
+                let new_block = entry == VhdDynamicHeader::BAT_ENTRY_UNUSED;
+                if new_block {
+                    entry = self.allocate_block(&mut state)?;
+                }
+                let block_start = entry as u64 * SECTOR_SIZE;
+                let buf_start = (disk_offset - offset) as usize;
+                self.file.write_all_at(
+                    &data[buf_start..buf_start + len as usize],
+                    block_start + self.geometry.bitmap_len + block_offset,
+                )?;
+                let first_sector = block_offset / SECTOR_SIZE;
+                self.update_bitmap(
+                    block_start,
+                    first_sector..first_sector + len / SECTOR_SIZE,
+                    true,
+                )?;
+                if new_block {
+                    self.file.write_all_at(
+                        &entry.to_be_bytes(),
+                        self.geometry.table_offset + block as u64 * 4,
+                    )?;
+                    state.bat[block] = entry;
+                }
+                Ok(())
+            },
+        )
+    }
+
+    /// Marks the sectors in `offset..offset + len` as not present, so that
+    /// they read as zero (or from the parent, for a differencing disk).
+    ///
+    /// The blocks themselves are not freed, since the format has no way to
+    /// reclaim space in the middle of the file.
+    pub fn unmap(&self, offset: u64, len: u64) -> io::Result<()> {
+        assert!(!self.read_only);
+        let state = self.state.write();
+        self.for_each_block(
+            offset,
+            len,
+            |_disk_offset, block, block_offset, this_len| {
+                let entry = state.bat[block];
+                if entry != VhdDynamicHeader::BAT_ENTRY_UNUSED {
+                    let first_sector = block_offset / SECTOR_SIZE;
+                    self.update_bitmap(
+                        entry as u64 * SECTOR_SIZE,
+                        first_sector..first_sector + this_len / SECTOR_SIZE,
+                        false,
+                    )?;
+                }
+                Ok(())
+            },
+        )
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
@@ -1,17 +1,40 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-//! A VHD1 disk implementation. Currently only supports fixed VHD1.
+//! A VHD1 disk implementation.
+//!
+//! Fixed VHDs are read and written directly through [`FileDisk`]. Dynamic and
+//! differencing VHDs are parsed here, with the parent of a differencing disk
+//! opened by the caller and passed to [`Vhd1Disk::open`]. Dynamic and
+//! differencing images can also be used as a layer of a
+//! [`disk_layered::LayeredDisk`] via [`Vhd1DiskLayer`], in which case the
+//! layers below take the place of the parent.
 
 #![expect(missing_docs)]
 #![forbid(unsafe_code)]
 
+mod dynamic;
+pub mod resolver;
+
+pub use dynamic::FormatParams;
+pub use dynamic::MAX_DISK_SIZE;
+pub use dynamic::ParentLocator;
+pub use dynamic::ParentParams;
+
+use blocking::unblock;
+use disk_backend::Disk;
 use disk_backend::DiskError;
 use disk_backend::DiskIo;
+use disk_backend::UnmapBehavior;
 use disk_backend::resolve::ResolveDiskParameters;
 use disk_backend::resolve::ResolvedDisk;
 use disk_backend_resources::FixedVhd1DiskHandle;
 use disk_file::FileDisk;
+use disk_file::readwriteat::ReadWriteAt;
+use disk_layered::LayerIo;
+use disk_layered::SectorMarker;
+use dynamic::DynamicImage;
+use dynamic::SECTOR_SIZE;
 use guid::Guid;
 use inspect::Inspect;
 use scsi_buffers::RequestBuffers;
@@ -20,6 +43,8 @@
 use std::io::Read;
 use std::io::Seek;
 use std::io::Write;
+use std::ops::Range;
+use std::sync::Arc;
 use thiserror::Error;
 use vhd1_defs::VhdFooter;
 use vm_resource::ResolveResource;
@@ -58,15 +83,39 @@ fn resolve(
 #[derive(Debug, Inspect)]
 pub struct Vhd1Disk {
     #[inspect(flatten)]
-    file: FileDisk,
+    backing: Backing,
     unique_id: Guid,
 }
 
+#[derive(Debug, Inspect)]
+#[inspect(untagged)]
+enum Backing {
+    Fixed(#[inspect(flatten)] FileDisk),
+    Dynamic {
+        #[inspect(flatten)]
+        image: Arc<DynamicImage>,
+        parent: Option<Disk>,
+    },
+}
+
 const DEFAULT_SECTOR_SIZE: u32 = 512;
 const DEFAULT_PHYSICAL_SECTOR_SIZE: u32 = 512;
 
+/// The type of a VHD1 image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Vhd1Type {
+    /// The disk contents are stored directly, followed by the footer.
+    Fixed,
+    /// Blocks are allocated on first write.
+    Dynamic,
+    /// Blocks are allocated on first write, and sectors that have not been
+    /// written are read from a parent disk.
+    Differencing,
+}
+
 #[derive(Debug)]
 struct Metadata {
+    disk_type: Vhd1Type,
     disk_size: u64,
     sector_size: u32,
     unique_id: Guid,
@@ -74,7 +123,7 @@ struct Metadata {
 
 impl Metadata {
     /// Parses the essential metadata out of the footer.
-    fn from_footer(footer: VhdFooter, file_size: u64) -> Result<Metadata, OpenError> {
+    fn from_footer(footer: &VhdFooter) -> Result<Metadata, OpenError> {
         if footer.cookie != VhdFooter::COOKIE_MAGIC {
             return Err(OpenError::InvalidFooterCookie);
         }
@@ -86,18 +135,21 @@ fn from_footer(footer: VhdFooter, file_size: u64) -> Result<Metadata, OpenError>
                 footer.file_format_version.into(),
             ));
         }
-        // FUTURE: support parsing non-fixed VHDs.
-        if footer.disk_type != VhdFooter::DISK_TYPE_FIXED.to_be_bytes() {
-            return Err(OpenError::NotFixed);
-        }
+        let disk_type = match footer.disk_type.get() {
+            VhdFooter::DISK_TYPE_FIXED => Vhd1Type::Fixed,
+            VhdFooter::DISK_TYPE_DYNAMIC => Vhd1Type::Dynamic,
+            VhdFooter::DISK_TYPE_DIFFERENCING => Vhd1Type::Differencing,
+            disk_type => return Err(OpenError::UnsupportedDiskType(disk_type)),
+        };
         let disk_size = footer.current_size.into();
         let sector_size = DEFAULT_SECTOR_SIZE;
-        if disk_size > file_size - VhdFooter::LEN || disk_size % (sector_size as u64) != 0 {
+        if disk_size % (sector_size as u64) != 0 {
             return Err(OpenError::InvalidDiskSize(disk_size));
         }
 
         let unique_id = footer.unique_id;
         Ok(Metadata {
+            disk_type,
             disk_size,
             sector_size,
             unique_id,
@@ -123,6 +175,96 @@ pub enum OpenError {
     UnsupportedVersion(u32),
     #[error("not a fixed VHD")]
     NotFixed,
+    #[error("not a dynamic or differencing VHD")]
+    NotDynamic,
+    #[error("unsupported VHD disk type: {0}")]
+    UnsupportedDiskType(u32),
+    #[error("VHD dynamic header is missing")]
+    InvalidDynamicHeaderCookie,
+    #[error("invalid VHD dynamic header checksum")]
+    InvalidDynamicHeaderChecksum,
+    #[error("invalid VHD block size: {0}")]
+    InvalidBlockSize(u32),
+    #[error("invalid VHD BAT entry {0}: {1:#x}")]
+    InvalidBatEntry(u64, u32),
+    #[error("invalid VHD parent locator")]
+    InvalidParentLocator,
+    #[error("VHD is a differencing disk, but no parent disk was provided")]
+    ParentDiskRequired,
+    #[error("parent disk sector size {0} does not match the VHD sector size")]
+    ParentSectorSize(u32),
+}
+
+/// Reads the footer from the end of `file`, returning it along with its
+/// offset.
+///
+/// If the footer at the end of a dynamic or differencing image is damaged,
+/// such as by a crash while a block was being allocated, then the copy at the
+/// start of the file is used instead.
+fn read_footer(file: &File) -> Result<(VhdFooter, Metadata, u64), OpenError> {
+    let len = file.metadata()?.len();
+    if len < VhdFooter::LEN {
+        return Err(OpenError::InvalidFileSize(len));
+    }
+    let mut footer: VhdFooter = FromZeros::new_zeroed();
+    let offset = (len - VhdFooter::LEN) & !(VhdFooter::ALIGNMENT - 1);
+    file.read_exact_at(footer.as_mut_bytes(), offset)?;
+    let err = match Metadata::from_footer(&footer) {
+        Ok(metadata) => return Ok((footer, metadata, offset)),
+        Err(err) => err,
+    };
+    let mut copy: VhdFooter = FromZeros::new_zeroed();
+    file.read_exact_at(copy.as_mut_bytes(), 0)?;
+    match Metadata::from_footer(&copy) {
+        Ok(metadata) if metadata.disk_type != Vhd1Type::Fixed => {
+            Ok((copy, metadata, len.next_multiple_of(VhdFooter::ALIGNMENT)))
+        }
+        _ => Err(err),
+    }
+}
+
+/// Information about a VHD1 image, as returned by [`read_info`].
+#[derive(Debug, Clone)]
+pub struct Vhd1Info {
+    /// The image type.
+    pub disk_type: Vhd1Type,
+    /// The virtual disk size, in bytes.
+    pub disk_size: u64,
+    /// The unique ID of the image, which differencing disks use to refer to
+    /// their parent.
+    pub unique_id: Guid,
+    /// The modification time stamp from the footer.
+    pub time_stamp: u32,
+    /// The block size, for a dynamic or differencing image.
+    pub block_size: Option<u32>,
+    /// The parent locator, for a differencing image.
+    pub parent_locator: Option<ParentLocator>,
+}
+
+/// Reads the footer and, for a dynamic or differencing image, the dynamic
+/// header of the image in `file`.
+pub fn read_info(file: &File) -> Result<Vhd1Info, OpenError> {
+    let (footer, metadata, _) = read_footer(file)?;
+    let (block_size, parent_locator) = if metadata.disk_type != Vhd1Type::Fixed {
+        let (header, parent_locator) = dynamic::read_header(file, &footer)?;
+        (Some(header.block_size.get()), parent_locator)
+    } else {
+        (None, None)
+    };
+    Ok(Vhd1Info {
+        disk_type: metadata.disk_type,
+        disk_size: metadata.disk_size,
+        unique_id: metadata.unique_id,
+        time_stamp: footer.time_stamp.get(),
+        block_size,
+        parent_locator,
+    })
+}
+
+/// Formats `file` as an empty dynamic or differencing VHD, truncating any
+/// existing contents.
+pub fn format_dynamic(file: &File, params: &FormatParams<'_>) -> Result<(), OpenError> {
+    dynamic::format(file, params)
 }
 
 impl Vhd1Disk {
@@ -148,7 +290,13 @@ pub fn open_fixed(mut file: File, read_only: bool) -> Result<Self, OpenError> {
         file.seek(io::SeekFrom::End(-512))?;
         let mut footer: VhdFooter = FromZeros::new_zeroed();
         file.read_exact(footer.as_mut_bytes())?;
-        let metadata = Metadata::from_footer(footer, len)?;
+        let metadata = Metadata::from_footer(&footer)?;
+        if metadata.disk_type != Vhd1Type::Fixed {
+            return Err(OpenError::NotFixed);
+        }
+        if metadata.disk_size > len - VhdFooter::LEN {
+            return Err(OpenError::InvalidDiskSize(metadata.disk_size));
+        }
 
         // Just wrap FileDisk for handling actual IO.
         let file = FileDisk::with_metadata(
@@ -162,15 +310,129 @@ pub fn open_fixed(mut file: File, read_only: bool) -> Result<Self, OpenError> {
         );
 
         Ok(Self {
-            file,
+            backing: Backing::Fixed(file),
             unique_id: metadata.unique_id,
         })
     }
 
+    /// Opens a fixed, dynamic, or differencing VHD.
+    ///
+    /// If the image is a differencing disk, then `parent` must be the opened
+    /// parent disk. Reads of sectors not present in the image are satisfied
+    /// from `parent`, which is never written to.
+    pub fn open(file: File, parent: Option<Disk>, read_only: bool) -> Result<Self, OpenError> {
+        let (footer, metadata, footer_offset) = read_footer(&file)?;
+        if metadata.disk_type == Vhd1Type::Fixed {
+            return Self::open_fixed(file, read_only);
+        }
+        let unique_id = metadata.unique_id;
+        let image = DynamicImage::open(file, footer, footer_offset, read_only)?;
+        let parent = if image.geometry().parent.is_some() {
+            let parent = parent.ok_or(OpenError::ParentDiskRequired)?;
+            if parent.sector_size() != DEFAULT_SECTOR_SIZE {
+                return Err(OpenError::ParentSectorSize(parent.sector_size()));
+            }
+            Some(parent)
+        } else {
+            None
+        };
+        Ok(Self {
+            backing: Backing::Dynamic {
+                image: Arc::new(image),
+                parent,
+            },
+            unique_id,
+        })
+    }
+
+    /// Returns the parent locator, if this is a differencing disk.
+    pub fn parent_locator(&self) -> Option<&ParentLocator> {
+        match &self.backing {
+            Backing::Fixed(_) => None,
+            Backing::Dynamic { image, .. } => image.geometry().parent.as_ref(),
+        }
+    }
+
     /// Drops the parsing state, returning the file handle.
     pub fn into_inner(self) -> File {
-        self.file.into_inner()
+        match self.backing {
+            Backing::Fixed(file) => file.into_inner(),
+            // The image is only shared while an IO is in flight, which cannot
+            // be the case when `self` is owned.
+            Backing::Dynamic { image, .. } => Arc::into_inner(image)
+                .expect("no IOs in flight")
+                .into_inner(),
+        }
+    }
+}
+
+/// Returns the byte offset of `sector`, failing if `sector..sector + len`
+/// extends past the end of a disk of `disk_size` bytes.
+fn check_range(disk_size: u64, sector: u64, len: u64) -> Result<u64, DiskError> {
+    let offset = sector
+        .checked_mul(SECTOR_SIZE)
+        .ok_or(DiskError::IllegalBlock)?;
+    if offset.checked_add(len).is_none_or(|end| end > disk_size) {
+        return Err(DiskError::IllegalBlock);
     }
+    Ok(offset)
+}
+
+/// Reads from `image` into `buffers`, returning the ranges that are not
+/// present in the image, which are zeroed.
+async fn read_dynamic(
+    image: &Arc<DynamicImage>,
+    buffers: &RequestBuffers<'_>,
+    offset: u64,
+) -> Result<Vec<Range<u64>>, DiskError> {
+    let image = image.clone();
+    let mut buffer = vec![0; buffers.len()];
+    let (buffer, absent) = unblock(move || -> io::Result<_> {
+        let absent = image.read(offset, &mut buffer)?;
+        Ok((buffer, absent))
+    })
+    .await
+    .map_err(DiskError::Io)?;
+    buffers.writer().write(&buffer)?;
+    Ok(absent)
+}
+
+async fn write_dynamic(
+    image: &Arc<DynamicImage>,
+    buffers: &RequestBuffers<'_>,
+    offset: u64,
+    fua: bool,
+) -> Result<(), DiskError> {
+    if image.is_read_only() {
+        return Err(DiskError::ReadOnly);
+    }
+    let mut data = vec![0; buffers.len()];
+    buffers.reader().read(&mut data)?;
+    let image = image.clone();
+    unblock(move || -> io::Result<()> {
+        image.write(offset, &data)?;
+        if fua {
+            image.flush()?;
+        }
+        Ok(())
+    })
+    .await
+    .map_err(DiskError::Io)
+}
+
+async fn flush_dynamic(image: &Arc<DynamicImage>) -> Result<(), DiskError> {
+    let image = image.clone();
+    unblock(move || image.flush()).await.map_err(DiskError::Io)
+}
+
+async fn unmap_dynamic(image: &Arc<DynamicImage>, offset: u64, len: u64) -> Result<(), DiskError> {
+    if image.is_read_only() {
+        return Err(DiskError::ReadOnly);
+    }
+    let image = image.clone();
+    unblock(move || image.unmap(offset, len))
+        .await
+        .map_err(DiskError::Io)
 }
 
 impl DiskIo for Vhd1Disk {
@@ -179,15 +441,24 @@ fn disk_type(&self) -> &str {
     }
 
     fn sector_count(&self) -> u64 {
-        self.file.sector_count()
+        match &self.backing {
+            Backing::Fixed(file) => file.sector_count(),
+            Backing::Dynamic { image, .. } => image.geometry().disk_size / SECTOR_SIZE,
+        }
     }
 
     fn sector_size(&self) -> u32 {
-        self.file.sector_size()
+        match &self.backing {
+            Backing::Fixed(file) => file.sector_size(),
+            Backing::Dynamic { .. } => DEFAULT_SECTOR_SIZE,
+        }
     }
 
     fn is_read_only(&self) -> bool {
-        self.file.is_read_only()
+        match &self.backing {
+            Backing::Fixed(file) => file.is_read_only(),
+            Backing::Dynamic { image, .. } => image.is_read_only(),
+        }
     }
 
     fn disk_id(&self) -> Option<[u8; 16]> {
@@ -195,11 +466,17 @@ fn is_read_only(&self) -> bool {
     }
 
     fn physical_sector_size(&self) -> u32 {
-        self.file.physical_sector_size()
+        match &self.backing {
+            Backing::Fixed(file) => file.physical_sector_size(),
+            Backing::Dynamic { .. } => DEFAULT_PHYSICAL_SECTOR_SIZE,
+        }
     }
 
     fn is_fua_respected(&self) -> bool {
-        self.file.is_fua_respected()
+        match &self.backing {
+            Backing::Fixed(file) => file.is_fua_respected(),
+            Backing::Dynamic { .. } => true,
+        }
     }
 
     async fn read_vectored(
@@ -207,7 +484,32 @@ async fn read_vectored(
         buffers: &RequestBuffers<'_>,
         sector: u64,
     ) -> Result<(), DiskError> {
-        self.file.read_vectored(buffers, sector).await
+        let (image, parent) = match &self.backing {
+            Backing::Fixed(file) => return file.read_vectored(buffers, sector).await,
+            Backing::Dynamic { image, parent } => (image, parent),
+        };
+        let offset = check_range(image.geometry().disk_size, sector, buffers.len() as u64)?;
+        let absent = read_dynamic(image, buffers, offset).await?;
+        if let Some(parent) = parent {
+            let parent_len = parent.sector_count() * SECTOR_SIZE;
+            for range in absent {
+                // Sectors past the end of a smaller parent read as zero.
+                let end = range.end.min(parent_len);
+                if range.start >= end {
+                    continue;
+                }
+                parent
+                    .read_vectored(
+                        &buffers.subrange(
+                            (range.start - offset) as usize,
+                            (end - range.start) as usize,
+                        ),
+                        range.start / SECTOR_SIZE,
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
     }
 
     async fn write_vectored(
@@ -216,37 +518,243 @@ async fn write_vectored(
         sector: u64,
         fua: bool,
     ) -> Result<(), DiskError> {
-        self.file.write_vectored(buffers, sector, fua).await
+        match &self.backing {
+            Backing::Fixed(file) => file.write_vectored(buffers, sector, fua).await,
+            Backing::Dynamic { image, .. } => {
+                let offset = check_range(image.geometry().disk_size, sector, buffers.len() as u64)?;
+                write_dynamic(image, buffers, offset, fua).await
+            }
+        }
     }
 
     async fn sync_cache(&self) -> Result<(), DiskError> {
-        self.file.sync_cache().await
+        match &self.backing {
+            Backing::Fixed(file) => file.sync_cache().await,
+            Backing::Dynamic { image, .. } => flush_dynamic(image).await,
+        }
     }
 
     async fn unmap(
         &self,
-        _sector: u64,
-        _count: u64,
+        sector: u64,
+        count: u64,
         _block_level_only: bool,
     ) -> Result<(), DiskError> {
+        match &self.backing {
+            // Sectors of a differencing disk cannot be zeroed without
+            // writing them, so unmap is ignored.
+            Backing::Fixed(_)
+            | Backing::Dynamic {
+                parent: Some(_), ..
+            } => Ok(()),
+            Backing::Dynamic {
+                image,
+                parent: None,
+            } => {
+                let len = count
+                    .checked_mul(SECTOR_SIZE)
+                    .ok_or(DiskError::IllegalBlock)?;
+                let offset = check_range(image.geometry().disk_size, sector, len)?;
+                unmap_dynamic(image, offset, len).await
+            }
+        }
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        match &self.backing {
+            Backing::Fixed(_)
+            | Backing::Dynamic {
+                parent: Some(_), ..
+            } => UnmapBehavior::Ignored,
+            Backing::Dynamic { parent: None, .. } => UnmapBehavior::Zeroes,
+        }
+    }
+}
+
+/// A dynamic or differencing VHD used as a disk layer.
+///
+/// Any parent recorded in the image is ignored; the layers below this one
+/// provide the data for sectors not present in the image instead.
+#[derive(Inspect)]
+pub struct Vhd1DiskLayer {
+    #[inspect(flatten)]
+    image: Arc<DynamicImage>,
+}
+
+impl Vhd1DiskLayer {
+    /// Opens a dynamic or differencing VHD as a layer.
+    pub fn open(file: File, read_only: bool) -> Result<Self, OpenError> {
+        let (footer, metadata, footer_offset) = read_footer(&file)?;
+        if metadata.disk_type == Vhd1Type::Fixed {
+            return Err(OpenError::NotDynamic);
+        }
+        let image = DynamicImage::open(file, footer, footer_offset, read_only)?;
+        Ok(Self {
+            image: Arc::new(image),
+        })
+    }
+}
+
+impl LayerIo for Vhd1DiskLayer {
+    fn layer_type(&self) -> &str {
+        "vhd1"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.image.geometry().disk_size / SECTOR_SIZE
+    }
+
+    fn sector_size(&self) -> u32 {
+        DEFAULT_SECTOR_SIZE
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        Some(self.image.geometry().unique_id.into())
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        DEFAULT_PHYSICAL_SECTOR_SIZE
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        true
+    }
+
+    fn is_logically_read_only(&self) -> bool {
+        self.image.is_read_only()
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        flush_dynamic(&self.image).await
+    }
+
+    async fn read(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        mut marker: SectorMarker<'_>,
+    ) -> Result<(), DiskError> {
+        let offset = check_range(
+            self.image.geometry().disk_size,
+            sector,
+            buffers.len() as u64,
+        )?;
+        let absent = read_dynamic(&self.image, buffers, offset).await?;
+        // Only mark the sectors that are present, so that the rest are read
+        // from the next layer.
+        let mut pos = sector;
+        for range in absent
+            .iter()
+            .map(|range| range.start / SECTOR_SIZE..range.end / SECTOR_SIZE)
+            .chain(std::iter::once(
+                sector + buffers.len() as u64 / SECTOR_SIZE..u64::MAX,
+            ))
+        {
+            marker.set_range(pos..range.start);
+            pos = range.end;
+        }
         Ok(())
     }
 
-    fn unmap_behavior(&self) -> disk_backend::UnmapBehavior {
-        disk_backend::UnmapBehavior::Ignored
+    async fn write(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        let offset = check_range(
+            self.image.geometry().disk_size,
+            sector,
+            buffers.len() as u64,
+        )?;
+        write_dynamic(&self.image, buffers, offset, fua).await
+    }
+
+    async fn unmap(
+        &self,
+        sector: u64,
+        count: u64,
+        _block_level_only: bool,
+        next_is_zero: bool,
+    ) -> Result<(), DiskError> {
+        // Sectors can only be discarded, which exposes the next layer's
+        // contents, so there is nothing to do unless those are zero.
+        if !next_is_zero {
+            return Ok(());
+        }
+        let len = count
+            .checked_mul(SECTOR_SIZE)
+            .ok_or(DiskError::IllegalBlock)?;
+        let offset = check_range(self.image.geometry().disk_size, sector, len)?;
+        unmap_dynamic(&self.image, offset, len).await
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        UnmapBehavior::Unspecified
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::FormatParams;
+    use super::ParentParams;
     use super::Vhd1Disk;
+    use super::Vhd1DiskLayer;
+    use super::Vhd1Type;
     use disk_backend::Disk;
+    use disk_layered::DiskLayer;
+    use disk_layered::LayerConfiguration;
+    use disk_layered::LayeredDisk;
+    use disklayer_ram::ram_disk;
     use guestmem::GuestMemory;
     use pal_async::async_test;
     use scsi_buffers::OwnedRequestBuffers;
+    use std::fs::File;
     use std::io::Write;
     use zerocopy::IntoBytes;
 
+    const MB: u64 = 1024 * 1024;
+    const SIZE: u64 = 16 * MB;
+
+    fn new_image(params: &FormatParams<'_>) -> File {
+        let file = tempfile::tempfile().unwrap();
+        super::format_dynamic(&file, params).unwrap();
+        file
+    }
+
+    fn open(file: &File, parent: Option<Disk>, read_only: bool) -> Disk {
+        Disk::new(Vhd1Disk::open(file.try_clone().unwrap(), parent, read_only).unwrap()).unwrap()
+    }
+
+    async fn read(disk: &Disk, sector: u64, len: usize) -> Vec<u8> {
+        let mem = GuestMemory::allocate(len);
+        disk.read_vectored(
+            &OwnedRequestBuffers::linear(0, len, true).buffer(&mem),
+            sector,
+        )
+        .await
+        .unwrap();
+        let mut data = vec![0; len];
+        mem.read_at(0, &mut data).unwrap();
+        data
+    }
+
+    async fn write(disk: &Disk, sector: u64, data: &[u8]) {
+        let mem = GuestMemory::allocate(data.len());
+        mem.write_at(0, data).unwrap();
+        disk.write_vectored(
+            &OwnedRequestBuffers::linear(0, data.len(), false).buffer(&mem),
+            sector,
+            false,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn pattern(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
     #[async_test]
     async fn open_fixed() {
         let mut file = tempfile::tempfile().unwrap();
@@ -267,4 +775,141 @@ async fn open_fixed() {
         mem.read_at(0, buf.as_mut_bytes()).unwrap();
         assert!(buf.iter().copied().eq(1000_u32 * 128..1001 * 128));
     }
+
+    #[async_test]
+    async fn dynamic_read_write_reopen() {
+        let file = new_image(&FormatParams::new(SIZE));
+        let info = super::read_info(&file).unwrap();
+        assert_eq!(info.disk_type, Vhd1Type::Dynamic);
+        assert_eq!(info.disk_size, SIZE);
+        assert!(matches!(
+            Vhd1Disk::open_fixed(file.try_clone().unwrap(), false),
+            Err(super::OpenError::NotFixed)
+        ));
+
+        let disk = open(&file, None, false);
+        assert_eq!(disk.sector_count(), SIZE / 512);
+        assert_eq!(read(&disk, 0, 8192).await, vec![0; 8192]);
+
+        // Write across a block boundary.
+        let data = pattern(1, 8192);
+        let sector = 2 * MB / 512 - 4;
+        write(&disk, sector, &data).await;
+        assert_eq!(read(&disk, sector, data.len()).await, data);
+        assert_eq!(read(&disk, sector - 4, 2048).await, vec![0; 2048]);
+
+        // Unmapped sectors read as zero.
+        disk.unmap(sector, 2, false).await.unwrap();
+        let mut expected = data.clone();
+        expected[..1024].fill(0);
+        assert_eq!(read(&disk, sector, expected.len()).await, expected);
+        drop(disk);
+
+        let disk = open(&file, None, true);
+        assert_eq!(read(&disk, sector, expected.len()).await, expected);
+        drop(disk);
+
+        // If the footer at the end of the file is lost, the copy at the start
+        // is used.
+        file.set_len(file.metadata().unwrap().len() - 512).unwrap();
+        let disk = open(&file, None, false);
+        assert_eq!(read(&disk, sector, expected.len()).await, expected);
+        write(&disk, 0, &data).await;
+        drop(disk);
+        let disk = Disk::new(Vhd1Disk::open(file, None, true).unwrap()).unwrap();
+        assert_eq!(read(&disk, 0, data.len()).await, data);
+    }
+
+    #[async_test]
+    async fn differencing() {
+        let parent = ram_disk(SIZE, false).unwrap();
+        let parent_data = pattern(4, 4 * MB as usize);
+        write(&parent, 0, &parent_data).await;
+
+        let parent_id = guid::Guid::new_random();
+        let file = new_image(&FormatParams {
+            parent: Some(ParentParams {
+                unique_id: parent_id,
+                time_stamp: 1234,
+                relative_path: ".\\parent.vhd",
+                absolute_path: Some("C:\\disks\\parent.vhd"),
+            }),
+            ..FormatParams::new(SIZE)
+        });
+        let info = super::read_info(&file).unwrap();
+        assert_eq!(info.disk_type, Vhd1Type::Differencing);
+        let locator = info.parent_locator.unwrap();
+        assert_eq!(locator.unique_id, parent_id);
+        assert_eq!(locator.time_stamp, 1234);
+        assert_eq!(locator.name, "parent.vhd");
+        assert_eq!(locator.relative_path.as_deref(), Some(".\\parent.vhd"));
+        assert_eq!(
+            locator.absolute_path.as_deref(),
+            Some("C:\\disks\\parent.vhd")
+        );
+        assert!(matches!(
+            Vhd1Disk::open(file.try_clone().unwrap(), None, false),
+            Err(super::OpenError::ParentDiskRequired)
+        ));
+
+        let disk = open(&file, Some(parent.clone()), false);
+        assert_eq!(read(&disk, 0, parent_data.len()).await, parent_data);
+
+        // Partial writes to a block only hide the written sectors of the
+        // parent.
+        write(&disk, 5, &[0x55; 1024]).await;
+        let mut expected = parent_data.clone();
+        expected[5 * 512..7 * 512].fill(0x55);
+        assert_eq!(read(&disk, 0, expected.len()).await, expected);
+        drop(disk);
+
+        // The parent is untouched, and the changes persist.
+        assert_eq!(read(&parent, 0, parent_data.len()).await, parent_data);
+        let disk = open(&file, Some(parent), true);
+        assert_eq!(read(&disk, 0, expected.len()).await, expected);
+    }
+
+    #[async_test]
+    async fn layered() {
+        let base = ram_disk(SIZE, false).unwrap();
+        write(&base, 0, &pattern(7, 8192)).await;
+
+        let file = new_image(&FormatParams::new(SIZE));
+        let disk = Disk::new(
+            LayeredDisk::new(
+                false,
+                vec![
+                    LayerConfiguration {
+                        layer: DiskLayer::new(
+                            Vhd1DiskLayer::open(file.try_clone().unwrap(), false).unwrap(),
+                        ),
+                        write_through: false,
+                        read_cache: false,
+                    },
+                    LayerConfiguration {
+                        layer: DiskLayer::from_disk(base.clone()),
+                        write_through: false,
+                        read_cache: false,
+                    },
+                ],
+            )
+            .await
+            .unwrap(),
+        )
+        .unwrap();
+
+        write(&disk, 3, &[9; 512]).await;
+        let mut expected = pattern(7, 8192);
+        expected[3 * 512..4 * 512].fill(9);
+        assert_eq!(read(&disk, 0, 8192).await, expected);
+        // The base layer is untouched.
+        assert_eq!(read(&base, 0, 8192).await, pattern(7, 8192));
+        drop(disk);
+
+        // Only the written sector is present in the image.
+        let disk = open(&file, None, true);
+        let mut expected = vec![0; 8192];
+        expected[3 * 512..4 * 512].fill(9);
+        assert_eq!(read(&disk, 0, 8192).await, expected);
+    }
 }
@@ -0,0 +1,82 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolvers for dynamic and differencing VHD1 disks and disk layers.
+
+use crate::Vhd1Disk;
+use crate::Vhd1DiskLayer;
+use anyhow::Context;
+use async_trait::async_trait;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend::resolve::ResolvedDisk;
+use disk_backend_resources::Vhd1DiskHandle;
+use disk_backend_resources::layer::Vhd1DiskLayerHandle;
+use disk_layered::resolve::ResolveDiskLayerParameters;
+use disk_layered::resolve::ResolvedDiskLayer;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::DiskHandleKind;
+use vm_resource::kind::DiskLayerHandleKind;
+
+/// Resolver for a [`Vhd1DiskHandle`].
+pub struct Vhd1DiskResolver;
+declare_static_async_resolver!(Vhd1DiskResolver, (DiskHandleKind, Vhd1DiskHandle));
+
+#[async_trait]
+impl AsyncResolveResource<DiskHandleKind, Vhd1DiskHandle> for Vhd1DiskResolver {
+    type Output = ResolvedDisk;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: Vhd1DiskHandle,
+        input: ResolveDiskParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let parent = if let Some(parent) = rsrc.parent {
+            // The parent disk is never written to.
+            let parent = resolver
+                .resolve(
+                    parent,
+                    ResolveDiskParameters {
+                        read_only: true,
+                        driver_source: input.driver_source,
+                    },
+                )
+                .await
+                .context("failed to open vhd parent disk")?;
+            Some(parent.0)
+        } else {
+            None
+        };
+        let disk = blocking::unblock(move || Vhd1Disk::open(rsrc.file, parent, input.read_only))
+            .await
+            .context("failed to open vhd")?;
+        Ok(ResolvedDisk::new(disk)?)
+    }
+}
+
+/// Resolver for a [`Vhd1DiskLayerHandle`].
+pub struct Vhd1DiskLayerResolver;
+declare_static_resolver!(
+    Vhd1DiskLayerResolver,
+    (DiskLayerHandleKind, Vhd1DiskLayerHandle)
+);
+
+impl ResolveResource<DiskLayerHandleKind, Vhd1DiskLayerHandle> for Vhd1DiskLayerResolver {
+    type Output = ResolvedDiskLayer;
+    type Error = anyhow::Error;
+
+    fn resolve(
+        &self,
+        rsrc: Vhd1DiskLayerHandle,
+        input: ResolveDiskLayerParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let layer =
+            Vhd1DiskLayer::open(rsrc.file, input.read_only).context("failed to open vhd layer")?;
+        Ok(ResolvedDiskLayer::new(layer))
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! VHDX file format definitions.
+//!
+//! All integers are stored little endian. See the MS-VHDX specification for
+//! details.
+
+use guid::Guid;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+pub const KB: u64 = 1024;
+pub const MB: u64 = 1024 * KB;
+
+/// The file is laid out in 1MB-aligned objects after the first 1MB.
+pub const REGION_ALIGNMENT: u64 = MB;
+/// The unit of log writes and of metadata updates.
+pub const LOG_SECTOR_SIZE: u64 = 4 * KB;
+
+pub const FILE_IDENTIFIER_OFFSET: u64 = 0;
+pub const HEADER_OFFSETS: [u64; 2] = [64 * KB, 128 * KB];
+pub const REGION_TABLE_OFFSETS: [u64; 2] = [192 * KB, 256 * KB];
+pub const HEADER_SIZE: usize = 4 * KB as usize;
+pub const REGION_TABLE_SIZE: usize = 64 * KB as usize;
+
+pub const FILE_IDENTIFIER_SIGNATURE: u64 = u64::from_le_bytes(*b"vhdxfile");
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct FileIdentifier {
+    pub signature: u64,
+    /// UTF-16 name of the creating application.
+    pub creator: [u16; 256],
+}
+
+pub const HEADER_SIGNATURE: u32 = u32::from_le_bytes(*b"head");
+pub const VERSION: u16 = 1;
+pub const LOG_VERSION: u16 = 0;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct Header {
+    pub signature: u32,
+    pub checksum: u32,
+    pub sequence_number: u64,
+    pub file_write_guid: Guid,
+    pub data_write_guid: Guid,
+    pub log_guid: Guid,
+    pub log_version: u16,
+    pub version: u16,
+    pub log_length: u32,
+    pub log_offset: u64,
+    pub reserved: [u8; 4016],
+}
+
+const _: () = assert!(size_of::<Header>() == HEADER_SIZE);
+
+pub const REGION_TABLE_SIGNATURE: u32 = u32::from_le_bytes(*b"regi");
+pub const MAX_REGION_ENTRIES: u32 = 2047;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct RegionTableHeader {
+    pub signature: u32,
+    pub checksum: u32,
+    pub entry_count: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct RegionTableEntry {
+    pub guid: Guid,
+    pub file_offset: u64,
+    pub length: u32,
+    pub flags: u32,
+}
+
+pub const REGION_ENTRY_REQUIRED: u32 = 0x1;
+
+pub const BAT_REGION_GUID: Guid = guid::guid!("2dc27766-f623-4200-9d64-115e9bfd4a08");
+pub const METADATA_REGION_GUID: Guid = guid::guid!("8b7ca206-4790-4b9a-b8fe-575f050f886e");
+
+pub const METADATA_TABLE_SIGNATURE: u64 = u64::from_le_bytes(*b"metadata");
+pub const METADATA_TABLE_SIZE: u64 = 64 * KB;
+pub const MAX_METADATA_ENTRIES: u16 = 2047;
+pub const MAX_METADATA_ITEM_LENGTH: u32 = MB as u32;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct MetadataTableHeader {
+    pub signature: u64,
+    pub reserved: u16,
+    pub entry_count: u16,
+    pub reserved2: [u16; 10],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct MetadataTableEntry {
+    pub item_id: Guid,
+    pub offset: u32,
+    pub length: u32,
+    pub flags: u32,
+    pub reserved: u32,
+}
+
+pub const METADATA_FLAG_IS_USER: u32 = 0x1;
+pub const METADATA_FLAG_IS_VIRTUAL_DISK: u32 = 0x2;
+pub const METADATA_FLAG_IS_REQUIRED: u32 = 0x4;
+
+pub const FILE_PARAMETERS_GUID: Guid = guid::guid!("caa16737-fa36-4d43-b3b6-33f0aa44e76b");
+pub const VIRTUAL_DISK_SIZE_GUID: Guid = guid::guid!("2fa54224-cd1b-4876-b211-5dbed83bf4b8");
+pub const PAGE_83_DATA_GUID: Guid = guid::guid!("beca12ab-b2e6-4523-93ef-c309e000c746");
+pub const LOGICAL_SECTOR_SIZE_GUID: Guid = guid::guid!("8141bf1d-a96f-4709-ba47-f233a8faab5f");
+pub const PHYSICAL_SECTOR_SIZE_GUID: Guid = guid::guid!("cda348c7-445d-4471-9cc9-e9885251c556");
+pub const PARENT_LOCATOR_GUID: Guid = guid::guid!("a8d35f2d-b30b-454d-abf7-d3d84834ab0c");
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct FileParameters {
+    pub block_size: u32,
+    pub flags: u32,
+}
+
+pub const FILE_PARAMETERS_LEAVE_BLOCKS_ALLOCATED: u32 = 0x1;
+pub const FILE_PARAMETERS_HAS_PARENT: u32 = 0x2;
+
+pub const MIN_BLOCK_SIZE: u32 = MB as u32;
+pub const MAX_BLOCK_SIZE: u32 = 256 * MB as u32;
+pub const DEFAULT_BLOCK_SIZE: u32 = 32 * MB as u32;
+pub const DEFAULT_DIFFERENCING_BLOCK_SIZE: u32 = 2 * MB as u32;
+
+pub const MAX_DISK_SIZE: u64 = 64 * 1024 * 1024 * MB;
+
+/// The type of the only parent locator defined by the specification.
+pub const PARENT_LOCATOR_TYPE_VHDX: Guid = guid::guid!("b04aefb7-d19e-4a81-b789-25b8e9445913");
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct ParentLocatorHeader {
+    pub locator_type: Guid,
+    pub reserved: u16,
+    pub key_value_count: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct ParentLocatorEntry {
+    pub key_offset: u32,
+    pub value_offset: u32,
+    pub key_length: u16,
+    pub value_length: u16,
+}
+
+pub mod parent_locator_key {
+    pub const PARENT_LINKAGE: &str = "parent_linkage";
+    pub const PARENT_LINKAGE2: &str = "parent_linkage2";
+    pub const RELATIVE_PATH: &str = "relative_path";
+    pub const VOLUME_PATH: &str = "volume_path";
+    pub const ABSOLUTE_WIN32_PATH: &str = "absolute_win32_path";
+}
+
+/// BAT entry states for payload blocks.
+pub mod payload_block {
+    pub const NOT_PRESENT: u64 = 0;
+    pub const UNDEFINED: u64 = 1;
+    pub const ZERO: u64 = 2;
+    pub const UNMAPPED: u64 = 3;
+    pub const FULLY_PRESENT: u64 = 6;
+    pub const PARTIALLY_PRESENT: u64 = 7;
+}
+
+/// BAT entry states for sector bitmap blocks.
+pub mod sector_bitmap_block {
+    pub const NOT_PRESENT: u64 = 0;
+    pub const PRESENT: u64 = 6;
+}
+
+pub const BAT_ENTRY_SIZE: u64 = 8;
+pub const BAT_STATE_MASK: u64 = 0x7;
+pub const BAT_FILE_OFFSET_SHIFT: u32 = 20;
+
+/// Returns the state of a BAT entry.
+pub fn bat_state(entry: u64) -> u64 {
+    entry & BAT_STATE_MASK
+}
+
+/// Returns the file offset, in bytes, of a BAT entry.
+pub fn bat_file_offset(entry: u64) -> u64 {
+    (entry >> BAT_FILE_OFFSET_SHIFT) << BAT_FILE_OFFSET_SHIFT
+}
+
+/// Builds a BAT entry from a state and a 1MB-aligned file offset.
+pub fn bat_entry(state: u64, file_offset: u64) -> u64 {
+    debug_assert_eq!(file_offset % MB, 0);
+    file_offset | state
+}
+
+/// The number of sectors described by one sector bitmap block.
+pub const SECTORS_PER_BITMAP_BLOCK: u64 = 1 << 23;
+
+pub const LOG_ENTRY_SIGNATURE: u32 = u32::from_le_bytes(*b"loge");
+pub const ZERO_DESCRIPTOR_SIGNATURE: u32 = u32::from_le_bytes(*b"zero");
+pub const DATA_DESCRIPTOR_SIGNATURE: u32 = u32::from_le_bytes(*b"desc");
+pub const DATA_SECTOR_SIGNATURE: u32 = u32::from_le_bytes(*b"data");
+pub const DEFAULT_LOG_LENGTH: u32 = MB as u32;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct LogEntryHeader {
+    pub signature: u32,
+    pub checksum: u32,
+    pub entry_length: u32,
+    pub tail: u32,
+    pub sequence_number: u64,
+    pub descriptor_count: u32,
+    pub reserved: u32,
+    pub log_guid: Guid,
+    pub flushed_file_offset: u64,
+    pub last_file_offset: u64,
+}
+
+/// A zero or data descriptor. Zero descriptors store the zero length in
+/// `leading_bytes`; data descriptors store the first eight and last four
+/// bytes of the described sector in `leading_bytes` and `trailing_bytes`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct LogDescriptor {
+    pub signature: u32,
+    pub trailing_bytes: u32,
+    pub leading_bytes: u64,
+    pub file_offset: u64,
+    pub sequence_number: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct LogDataSector {
+    pub signature: u32,
+    pub sequence_high: u32,
+    pub data: [u8; 4084],
+    pub sequence_low: u32,
+}
+
+const _: () = assert!(size_of::<LogEntryHeader>() == 64);
+const _: () = assert!(size_of::<LogDescriptor>() == 32);
+const _: () = assert!(size_of::<LogDataSector>() == LOG_SECTOR_SIZE as usize);
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes the CRC-32C (Castagnoli) checksum used by VHDX structures.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Computes the checksum of `data`, which contains a 32-bit checksum field at
+/// `checksum_offset` that is treated as zero.
+pub fn checksum_with_field(data: &[u8], checksum_offset: usize) -> u32 {
+    let mut crc = !0u32;
+    for (i, &b) in data.iter().enumerate() {
+        let b = if (checksum_offset..checksum_offset + 4).contains(&i) {
+            0
+        } else {
+            b
+        };
+        crc = CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// The offset of the checksum field in headers, region tables, and log
+/// entries.
+pub const CHECKSUM_OFFSET: usize = 4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+        let mut data = *b"abcd\x01\x02\x03\x04efgh";
+        let crc = checksum_with_field(&data, CHECKSUM_OFFSET);
+        data[4..8].fill(0);
+        assert_eq!(crc, crc32c(&data));
+    }
+
+    #[test]
+    fn bat_entry_roundtrip() {
+        let entry = bat_entry(payload_block::FULLY_PRESENT, 5 * MB);
+        assert_eq!(bat_state(entry), payload_block::FULLY_PRESENT);
+        assert_eq!(bat_file_offset(entry), 5 * MB);
+    }
+}
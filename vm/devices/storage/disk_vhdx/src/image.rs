@@ -0,0 +1,1110 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! VHDX image parsing and block management.
+
+use crate::FormatParams;
+use crate::OpenError;
+use crate::format;
+use crate::format::FileParameters;
+use crate::format::Header;
+use crate::format::KB;
+use crate::format::LOG_SECTOR_SIZE;
+use crate::format::MB;
+use crate::format::MetadataTableEntry;
+use crate::format::MetadataTableHeader;
+use crate::format::ParentLocatorEntry;
+use crate::format::ParentLocatorHeader;
+use crate::format::RegionTableEntry;
+use crate::format::RegionTableHeader;
+use crate::format::payload_block;
+use crate::format::sector_bitmap_block;
+use crate::log;
+use crate::log::LogWriter;
+use disk_file::readwriteat::ReadWriteAt;
+use guid::Guid;
+use inspect::Inspect;
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+/// The parent locator of a differencing disk.
+#[derive(Debug, Clone, Inspect)]
+pub struct ParentLocator {
+    /// The data write GUID of the parent.
+    #[inspect(display)]
+    pub parent_linkage: Guid,
+    /// An alternate data write GUID of the parent, used while the parent is
+    /// being updated.
+    #[inspect(with = "|x| x.map(|g| g.to_string())")]
+    pub parent_linkage2: Option<Guid>,
+    /// The path to the parent, relative to the directory of the child.
+    pub relative_path: Option<String>,
+    /// The path to the parent, in volume GUID form.
+    pub volume_path: Option<String>,
+    /// The absolute Windows path to the parent.
+    pub absolute_win32_path: Option<String>,
+}
+
+impl ParentLocator {
+    /// Returns true if `data_write_guid` identifies the parent.
+    pub fn matches(&self, data_write_guid: &Guid) -> bool {
+        self.parent_linkage == *data_write_guid || self.parent_linkage2 == Some(*data_write_guid)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, OpenError> {
+        use format::parent_locator_key as key;
+
+        let (header, _) = ParentLocatorHeader::read_from_prefix(data)
+            .map_err(|_| OpenError::InvalidParentLocator)?;
+        if header.locator_type != format::PARENT_LOCATOR_TYPE_VHDX {
+            return Err(OpenError::InvalidParentLocator);
+        }
+        let read_string = |offset: u32, len: u16| -> Result<String, OpenError> {
+            let bytes = data
+                .get(offset as usize..offset as usize + len as usize)
+                .filter(|b| b.len() % 2 == 0)
+                .ok_or(OpenError::InvalidParentLocator)?;
+            let chars = bytes
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>();
+            String::from_utf16(&chars).map_err(|_| OpenError::InvalidParentLocator)
+        };
+
+        let mut entries = BTreeMap::new();
+        for i in 0..header.key_value_count as usize {
+            let offset = size_of::<ParentLocatorHeader>() + i * size_of::<ParentLocatorEntry>();
+            let (entry, _) = data
+                .get(offset..)
+                .and_then(|d| ParentLocatorEntry::read_from_prefix(d).ok())
+                .ok_or(OpenError::InvalidParentLocator)?;
+            let key = read_string(entry.key_offset, entry.key_length)?;
+            let value = read_string(entry.value_offset, entry.value_length)?;
+            entries.insert(key, value);
+        }
+
+        let parse_guid = |s: &String| {
+            s.parse::<Guid>()
+                .map_err(|_| OpenError::InvalidParentLocator)
+        };
+        Ok(Self {
+            parent_linkage: entries
+                .get(key::PARENT_LINKAGE)
+                .ok_or(OpenError::InvalidParentLocator)
+                .and_then(parse_guid)?,
+            parent_linkage2: entries
+                .get(key::PARENT_LINKAGE2)
+                .map(parse_guid)
+                .transpose()?,
+            relative_path: entries.remove(key::RELATIVE_PATH),
+            volume_path: entries.remove(key::VOLUME_PATH),
+            absolute_win32_path: entries.remove(key::ABSOLUTE_WIN32_PATH),
+        })
+    }
+
+    fn build(&self) -> Vec<u8> {
+        use format::parent_locator_key as key;
+        let linkage = format!("{{{:X}}}", self.parent_linkage);
+        let linkage2 = self.parent_linkage2.map(|g| format!("{{{g:X}}}"));
+        let pairs = [
+            (key::PARENT_LINKAGE, Some(&linkage)),
+            (key::PARENT_LINKAGE2, linkage2.as_ref()),
+            (key::RELATIVE_PATH, self.relative_path.as_ref()),
+            (key::VOLUME_PATH, self.volume_path.as_ref()),
+            (key::ABSOLUTE_WIN32_PATH, self.absolute_win32_path.as_ref()),
+        ];
+        let pairs = pairs
+            .into_iter()
+            .filter_map(|(k, v)| Some((k, v?)))
+            .collect::<Vec<_>>();
+
+        let mut strings = Vec::new();
+        let strings_offset =
+            size_of::<ParentLocatorHeader>() + pairs.len() * size_of::<ParentLocatorEntry>();
+        let mut push_string = |s: &str| {
+            let offset = (strings_offset + strings.len()) as u32;
+            for c in s.encode_utf16() {
+                strings.extend_from_slice(&c.to_le_bytes());
+            }
+            (
+                offset,
+                (strings.len() + strings_offset - offset as usize) as u16,
+            )
+        };
+        let mut data = ParentLocatorHeader {
+            locator_type: format::PARENT_LOCATOR_TYPE_VHDX,
+            reserved: 0,
+            key_value_count: pairs.len() as u16,
+        }
+        .as_bytes()
+        .to_vec();
+        for (k, v) in pairs {
+            let (key_offset, key_length) = push_string(k);
+            let (value_offset, value_length) = push_string(v);
+            data.extend_from_slice(
+                ParentLocatorEntry {
+                    key_offset,
+                    value_offset,
+                    key_length,
+                    value_length,
+                }
+                .as_bytes(),
+            );
+        }
+        data.extend_from_slice(&strings);
+        data
+    }
+}
+
+/// The parsed contents of the metadata region.
+#[derive(Debug, Clone)]
+pub(crate) struct Metadata {
+    pub block_size: u32,
+    pub leave_blocks_allocated: bool,
+    pub has_parent: bool,
+    pub disk_size: u64,
+    pub logical_sector_size: u32,
+    pub physical_sector_size: u32,
+    pub page_83_data: Option<Guid>,
+    pub parent_locator: Option<ParentLocator>,
+}
+
+struct Regions {
+    bat_offset: u64,
+    bat_length: u64,
+    metadata_offset: u64,
+    metadata_length: u64,
+}
+
+fn header_checksum(data: &[u8]) -> u32 {
+    format::checksum_with_field(data, format::CHECKSUM_OFFSET)
+}
+
+fn read_file_identifier(file: &File) -> Result<(), OpenError> {
+    let mut signature = [0; 8];
+    file.read_exact_at(&mut signature, format::FILE_IDENTIFIER_OFFSET)
+        .map_err(|err| {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                OpenError::InvalidFileIdentifier
+            } else {
+                err.into()
+            }
+        })?;
+    if u64::from_le_bytes(signature) != format::FILE_IDENTIFIER_SIGNATURE {
+        return Err(OpenError::InvalidFileIdentifier);
+    }
+    Ok(())
+}
+
+/// Reads the current header, returning it and its slot.
+pub(crate) fn read_header(file: &File) -> Result<(Header, usize), OpenError> {
+    read_file_identifier(file)?;
+    let mut current: Option<(Header, usize)> = None;
+    for (slot, &offset) in format::HEADER_OFFSETS.iter().enumerate() {
+        let mut header = Header::new_zeroed();
+        file.read_exact_at(header.as_mut_bytes(), offset)?;
+        if header.signature != format::HEADER_SIGNATURE
+            || header.checksum != header_checksum(header.as_bytes())
+        {
+            continue;
+        }
+        if current
+            .as_ref()
+            .is_none_or(|(h, _)| header.sequence_number > h.sequence_number)
+        {
+            current = Some((header, slot));
+        }
+    }
+    let (header, slot) = current.ok_or(OpenError::NoValidHeader)?;
+    if header.version != format::VERSION {
+        return Err(OpenError::UnsupportedVersion(header.version));
+    }
+    if header.log_version != format::LOG_VERSION {
+        return Err(OpenError::UnsupportedLogVersion(header.log_version));
+    }
+    if header.log_offset < MB
+        || header.log_offset % MB != 0
+        || header.log_length == 0
+        || header.log_length as u64 % MB != 0
+    {
+        return Err(OpenError::InvalidLog);
+    }
+    Ok((header, slot))
+}
+
+fn write_header(file: &File, slot: usize, header: &mut Header) -> io::Result<()> {
+    header.checksum = 0;
+    header.checksum = format::crc32c(header.as_bytes());
+    file.write_all_at(header.as_bytes(), format::HEADER_OFFSETS[slot])
+}
+
+fn read_region_table(file: &File) -> Result<Regions, OpenError> {
+    for offset in format::REGION_TABLE_OFFSETS {
+        let mut data = vec![0; format::REGION_TABLE_SIZE];
+        file.read_exact_at(&mut data, offset)?;
+        let (header, _) = RegionTableHeader::read_from_prefix(&data).unwrap();
+        if header.signature != format::REGION_TABLE_SIGNATURE
+            || header.checksum != header_checksum(&data)
+            || header.entry_count > format::MAX_REGION_ENTRIES
+        {
+            continue;
+        }
+
+        let mut bat = None;
+        let mut metadata = None;
+        for i in 0..header.entry_count as usize {
+            let offset = size_of::<RegionTableHeader>() + i * size_of::<RegionTableEntry>();
+            let (entry, _) = RegionTableEntry::read_from_prefix(&data[offset..]).unwrap();
+            let region = match entry.guid {
+                format::BAT_REGION_GUID => &mut bat,
+                format::METADATA_REGION_GUID => &mut metadata,
+                guid => {
+                    if entry.flags & format::REGION_ENTRY_REQUIRED != 0 {
+                        return Err(OpenError::UnknownRequiredRegion(guid));
+                    }
+                    continue;
+                }
+            };
+            if entry.file_offset < MB
+                || entry.file_offset % MB != 0
+                || entry.length == 0
+                || entry.length as u64 % MB != 0
+            {
+                return Err(OpenError::InvalidRegion(entry.guid));
+            }
+            *region = Some((entry.file_offset, entry.length as u64));
+        }
+
+        let (bat_offset, bat_length) =
+            bat.ok_or(OpenError::MissingRegion(format::BAT_REGION_GUID))?;
+        let (metadata_offset, metadata_length) =
+            metadata.ok_or(OpenError::MissingRegion(format::METADATA_REGION_GUID))?;
+        return Ok(Regions {
+            bat_offset,
+            bat_length,
+            metadata_offset,
+            metadata_length,
+        });
+    }
+    Err(OpenError::NoValidRegionTable)
+}
+
+fn read_metadata(file: &File, regions: &Regions) -> Result<Metadata, OpenError> {
+    let mut table = vec![0; format::METADATA_TABLE_SIZE as usize];
+    file.read_exact_at(&mut table, regions.metadata_offset)?;
+    let (header, _) = MetadataTableHeader::read_from_prefix(&table).unwrap();
+    if header.signature != format::METADATA_TABLE_SIGNATURE
+        || header.entry_count > format::MAX_METADATA_ENTRIES
+    {
+        return Err(OpenError::InvalidMetadataTable);
+    }
+
+    let mut items = BTreeMap::new();
+    for i in 0..header.entry_count as usize {
+        let offset = size_of::<MetadataTableHeader>() + i * size_of::<MetadataTableEntry>();
+        let (entry, _) = MetadataTableEntry::read_from_prefix(&table[offset..]).unwrap();
+        let known = matches!(
+            entry.item_id,
+            format::FILE_PARAMETERS_GUID
+                | format::VIRTUAL_DISK_SIZE_GUID
+                | format::PAGE_83_DATA_GUID
+                | format::LOGICAL_SECTOR_SIZE_GUID
+                | format::PHYSICAL_SECTOR_SIZE_GUID
+                | format::PARENT_LOCATOR_GUID
+        );
+        if !known {
+            if entry.flags & format::METADATA_FLAG_IS_REQUIRED != 0 {
+                return Err(OpenError::UnknownRequiredMetadata(entry.item_id));
+            }
+            continue;
+        }
+        if entry.length > format::MAX_METADATA_ITEM_LENGTH
+            || (entry.length != 0
+                && (entry.offset < format::METADATA_TABLE_SIZE as u32
+                    || entry.offset as u64 + entry.length as u64 > regions.metadata_length))
+        {
+            return Err(OpenError::InvalidMetadata(entry.item_id));
+        }
+        let mut data = vec![0; entry.length as usize];
+        file.read_exact_at(&mut data, regions.metadata_offset + entry.offset as u64)?;
+        items.insert(entry.item_id, data);
+    }
+
+    let item = |guid: Guid| items.get(&guid).ok_or(OpenError::MissingMetadata(guid));
+    let u32_item = |guid: Guid| -> Result<u32, OpenError> {
+        u32::read_from_prefix(item(guid)?)
+            .map(|(v, _)| v)
+            .map_err(|_| OpenError::InvalidMetadata(guid))
+    };
+
+    let (file_parameters, _) =
+        FileParameters::read_from_prefix(item(format::FILE_PARAMETERS_GUID)?)
+            .map_err(|_| OpenError::InvalidMetadata(format::FILE_PARAMETERS_GUID))?;
+    let (disk_size, _) = u64::read_from_prefix(item(format::VIRTUAL_DISK_SIZE_GUID)?)
+        .map_err(|_| OpenError::InvalidMetadata(format::VIRTUAL_DISK_SIZE_GUID))?;
+    let logical_sector_size = u32_item(format::LOGICAL_SECTOR_SIZE_GUID)?;
+    let physical_sector_size = u32_item(format::PHYSICAL_SECTOR_SIZE_GUID)?;
+    let page_83_data = items
+        .get(&format::PAGE_83_DATA_GUID)
+        .and_then(|data| Guid::read_from_prefix(data).ok())
+        .map(|(guid, _)| guid);
+
+    let block_size = file_parameters.block_size;
+    if !block_size.is_power_of_two()
+        || !(format::MIN_BLOCK_SIZE..=format::MAX_BLOCK_SIZE).contains(&block_size)
+    {
+        return Err(OpenError::InvalidBlockSize(block_size));
+    }
+    for sector_size in [logical_sector_size, physical_sector_size] {
+        if sector_size != 512 && sector_size != 4096 {
+            return Err(OpenError::InvalidSectorSize(sector_size));
+        }
+    }
+    if disk_size == 0
+        || disk_size > format::MAX_DISK_SIZE
+        || disk_size % logical_sector_size as u64 != 0
+    {
+        return Err(OpenError::InvalidDiskSize(disk_size));
+    }
+
+    let has_parent = file_parameters.flags & format::FILE_PARAMETERS_HAS_PARENT != 0;
+    let parent_locator = if has_parent {
+        Some(ParentLocator::parse(item(format::PARENT_LOCATOR_GUID)?)?)
+    } else {
+        None
+    };
+
+    Ok(Metadata {
+        block_size,
+        leave_blocks_allocated: file_parameters.flags
+            & format::FILE_PARAMETERS_LEAVE_BLOCKS_ALLOCATED
+            != 0,
+        has_parent,
+        disk_size,
+        logical_sector_size,
+        physical_sector_size,
+        page_83_data,
+        parent_locator,
+    })
+}
+
+/// Reads the header and metadata of the image in `file` without replaying
+/// the log.
+pub(crate) fn read_info(file: &File) -> Result<(Header, Metadata), OpenError> {
+    let (header, _) = read_header(file)?;
+    let regions = read_region_table(file)?;
+    let metadata = read_metadata(file, &regions)?;
+    Ok((header, metadata))
+}
+
+/// The fixed layout of the image, derived from the metadata.
+#[derive(Debug, Inspect)]
+pub(crate) struct Geometry {
+    pub disk_size: u64,
+    pub block_size: u64,
+    pub logical_sector_size: u32,
+    pub physical_sector_size: u32,
+    pub has_parent: bool,
+    pub leave_blocks_allocated: bool,
+    #[inspect(skip)]
+    pub page_83_data: Option<Guid>,
+    #[inspect(skip)]
+    pub parent_locator: Option<ParentLocator>,
+    pub chunk_ratio: u64,
+    #[inspect(hex)]
+    pub bat_offset: u64,
+}
+
+impl Geometry {
+    fn new(metadata: Metadata, bat_offset: u64) -> Self {
+        Self {
+            disk_size: metadata.disk_size,
+            block_size: metadata.block_size as u64,
+            logical_sector_size: metadata.logical_sector_size,
+            physical_sector_size: metadata.physical_sector_size,
+            has_parent: metadata.has_parent,
+            leave_blocks_allocated: metadata.leave_blocks_allocated,
+            page_83_data: metadata.page_83_data,
+            parent_locator: metadata.parent_locator,
+            chunk_ratio: chunk_ratio(metadata.logical_sector_size, metadata.block_size),
+            bat_offset,
+        }
+    }
+
+    fn sectors_per_block(&self) -> u64 {
+        self.block_size / self.logical_sector_size as u64
+    }
+
+    fn payload_index(&self, block: u64) -> usize {
+        (block + block / self.chunk_ratio) as usize
+    }
+
+    fn bitmap_index(&self, chunk: u64) -> usize {
+        (chunk * (self.chunk_ratio + 1) + self.chunk_ratio) as usize
+    }
+}
+
+fn chunk_ratio(logical_sector_size: u32, block_size: u32) -> u64 {
+    format::SECTORS_PER_BITMAP_BLOCK * logical_sector_size as u64 / block_size as u64
+}
+
+fn bat_entry_count(disk_size: u64, block_size: u32, chunk_ratio: u64, has_parent: bool) -> u64 {
+    let data_blocks = disk_size.div_ceil(block_size as u64);
+    if has_parent {
+        data_blocks.div_ceil(chunk_ratio) * (chunk_ratio + 1)
+    } else {
+        data_blocks + (data_blocks - 1) / chunk_ratio
+    }
+}
+
+#[derive(Inspect)]
+struct State {
+    #[inspect(skip)]
+    header: Header,
+    header_slot: usize,
+    #[inspect(skip)]
+    bat: Vec<u64>,
+    #[inspect(hex)]
+    file_end: u64,
+    #[inspect(with = "Option::is_some")]
+    log: Option<LogWriter>,
+}
+
+/// Metadata updates accumulated during an operation, committed through the
+/// log at the end.
+#[derive(Default)]
+struct Batch {
+    bat_sectors: BTreeSet<u64>,
+    bitmap_sectors: BTreeMap<u64, Vec<u8>>,
+}
+
+/// An open VHDX image.
+#[derive(Inspect)]
+pub(crate) struct VhdxImage {
+    #[inspect(skip)]
+    file: File,
+    read_only: bool,
+    geometry: Geometry,
+    state: RwLock<State>,
+}
+
+impl VhdxImage {
+    /// Opens an image, replaying its log if necessary.
+    pub fn open(file: File, read_only: bool) -> Result<Self, OpenError> {
+        let (mut header, mut header_slot) = read_header(&file)?;
+        if !header.log_guid.is_zero() {
+            if read_only {
+                return Err(OpenError::LogReplayRequired);
+            }
+            log::replay(&file, &header)?;
+            header.log_guid = Guid::ZERO;
+            update_headers(&file, &mut header, &mut header_slot)?;
+        }
+
+        let regions = read_region_table(&file)?;
+        let metadata = read_metadata(&file, &regions)?;
+        let geometry = Geometry::new(metadata, regions.bat_offset);
+        let bat_entries = bat_entry_count(
+            geometry.disk_size,
+            geometry.block_size as u32,
+            geometry.chunk_ratio,
+            geometry.has_parent,
+        );
+        if bat_entries * format::BAT_ENTRY_SIZE > regions.bat_length {
+            return Err(OpenError::InvalidRegion(format::BAT_REGION_GUID));
+        }
+        let mut bat = vec![0u64; bat_entries as usize];
+        file.read_exact_at(bat.as_mut_bytes(), regions.bat_offset)?;
+
+        let mut file_end = file
+            .metadata()?
+            .len()
+            .max(header.log_offset + header.log_length as u64)
+            .max(regions.bat_offset + regions.bat_length)
+            .max(regions.metadata_offset + regions.metadata_length);
+        for (i, &entry) in bat.iter().enumerate() {
+            let is_bitmap = i as u64 % (geometry.chunk_ratio + 1) == geometry.chunk_ratio;
+            let (present, len) = if is_bitmap {
+                (format::bat_state(entry) == sector_bitmap_block::PRESENT, MB)
+            } else {
+                (
+                    matches!(
+                        format::bat_state(entry),
+                        payload_block::FULLY_PRESENT | payload_block::PARTIALLY_PRESENT
+                    ),
+                    geometry.block_size,
+                )
+            };
+            if present {
+                let offset = format::bat_file_offset(entry);
+                if offset < MB {
+                    return Err(OpenError::InvalidBatEntry(i as u64, entry));
+                }
+                file_end = file_end.max(offset + len);
+            }
+        }
+        file_end = file_end.next_multiple_of(MB);
+
+        Ok(Self {
+            file,
+            read_only,
+            geometry,
+            state: RwLock::new(State {
+                header,
+                header_slot,
+                bat,
+                file_end,
+                log: None,
+            }),
+        })
+    }
+
+    pub fn geometry(&self) -> &Geometry {
+        &self.geometry
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn data_write_guid(&self) -> Guid {
+        self.state.read().header.data_write_guid
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Returns the presence of `count` sectors starting at `first` in
+    /// `block`, from the block's sector bitmap.
+    fn read_bitmap(
+        &self,
+        state: &State,
+        block: u64,
+        first: u64,
+        count: u64,
+    ) -> io::Result<Vec<bool>> {
+        let geometry = &self.geometry;
+        let entry = state.bat[geometry.bitmap_index(block / geometry.chunk_ratio)];
+        if format::bat_state(entry) != sector_bitmap_block::PRESENT {
+            return Ok(vec![false; count as usize]);
+        }
+        let bit = (block % geometry.chunk_ratio) * geometry.sectors_per_block() + first;
+        let start = bit / 8;
+        let end = (bit + count).div_ceil(8);
+        let mut bytes = vec![0; (end - start) as usize];
+        self.file
+            .read_exact_at(&mut bytes, format::bat_file_offset(entry) + start)?;
+        let shift = bit % 8;
+        Ok((shift..shift + count)
+            .map(|i| bytes[(i / 8) as usize] & (1 << (i % 8)) != 0)
+            .collect())
+    }
+
+    /// Reads `buf.len()` bytes at `offset`.
+    ///
+    /// Returns the ranges of the disk that are not present in the image; these
+    /// are zeroed in `buf`, and must instead be read from the parent disk, if
+    /// there is one.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<Vec<Range<u64>>> {
+        let geometry = &self.geometry;
+        let sector_size = geometry.logical_sector_size as u64;
+        let state = self.state.read();
+        let mut absent: Vec<Range<u64>> = Vec::new();
+        let mut add_absent = |range: Range<u64>| match absent.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => absent.push(range),
+        };
+
+        let mut pos = 0;
+        while pos < buf.len() {
+            let disk_offset = offset + pos as u64;
+            let block = disk_offset / geometry.block_size;
+            let block_offset = disk_offset % geometry.block_size;
+            let len = (geometry.block_size - block_offset).min((buf.len() - pos) as u64) as usize;
+            let data = &mut buf[pos..pos + len];
+            let entry = state.bat[geometry.payload_index(block)];
+            match format::bat_state(entry) {
+                payload_block::FULLY_PRESENT => {
+                    self.file
+                        .read_exact_at(data, format::bat_file_offset(entry) + block_offset)?;
+                }
+                payload_block::PARTIALLY_PRESENT => {
+                    let first = block_offset / sector_size;
+                    let count = len as u64 / sector_size;
+                    let present = self.read_bitmap(&state, block, first, count)?;
+                    let mut i = 0;
+                    while i < present.len() {
+                        let run = present[i..]
+                            .iter()
+                            .take_while(|&&p| p == present[i])
+                            .count();
+                        let run_offset = i * sector_size as usize;
+                        let run_data =
+                            &mut data[run_offset..run_offset + run * sector_size as usize];
+                        let run_block_offset = block_offset + run_offset as u64;
+                        if present[i] {
+                            self.file.read_exact_at(
+                                run_data,
+                                format::bat_file_offset(entry) + run_block_offset,
+                            )?;
+                        } else {
+                            run_data.fill(0);
+                            let start = disk_offset + run_offset as u64;
+                            add_absent(start..start + run_data.len() as u64);
+                        }
+                        i += run;
+                    }
+                }
+                payload_block::NOT_PRESENT => {
+                    data.fill(0);
+                    add_absent(disk_offset..disk_offset + len as u64);
+                }
+                _ => data.fill(0),
+            }
+            pos += len;
+        }
+        Ok(absent)
+    }
+
+    /// Writes `data` at `offset`.
+    pub fn write(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        assert!(!self.read_only);
+        let geometry = &self.geometry;
+        let sector_size = geometry.logical_sector_size as u64;
+        let mut state = self.state.write();
+        self.begin_session(&mut state)?;
+        let mut batch = Batch::default();
+
+        let result = (|| -> io::Result<()> {
+            let mut pos = 0;
+            while pos < data.len() {
+                let disk_offset = offset + pos as u64;
+                let block = disk_offset / geometry.block_size;
+                let block_offset = disk_offset % geometry.block_size;
+                let len =
+                    (geometry.block_size - block_offset).min((data.len() - pos) as u64) as usize;
+                let chunk = &data[pos..pos + len];
+                let index = geometry.payload_index(block);
+                let entry = state.bat[index];
+                match format::bat_state(entry) {
+                    payload_block::FULLY_PRESENT => {
+                        self.file
+                            .write_all_at(chunk, format::bat_file_offset(entry) + block_offset)?;
+                    }
+                    payload_block::PARTIALLY_PRESENT => {
+                        self.file
+                            .write_all_at(chunk, format::bat_file_offset(entry) + block_offset)?;
+                        self.set_bitmap(
+                            &mut state,
+                            &mut batch,
+                            block,
+                            block_offset / sector_size,
+                            len as u64 / sector_size,
+                        )?;
+                    }
+                    block_state => {
+                        // Blocks that are not present in a differencing disk
+                        // fall through to the parent, so only the written
+                        // sectors become present. Other blocks read as zero,
+                        // which the newly allocated block already contains.
+                        let partial = geometry.has_parent
+                            && block_state == payload_block::NOT_PRESENT
+                            && len as u64 != geometry.block_size;
+                        let file_offset = allocate(&self.file, &mut state, geometry.block_size)?;
+                        self.file.write_all_at(chunk, file_offset + block_offset)?;
+                        if partial {
+                            self.set_bitmap(
+                                &mut state,
+                                &mut batch,
+                                block,
+                                block_offset / sector_size,
+                                len as u64 / sector_size,
+                            )?;
+                        }
+                        let new_state = if partial {
+                            payload_block::PARTIALLY_PRESENT
+                        } else {
+                            payload_block::FULLY_PRESENT
+                        };
+                        self.set_bat(
+                            &mut state,
+                            &mut batch,
+                            index,
+                            format::bat_entry(new_state, file_offset),
+                        );
+                    }
+                }
+                pos += len;
+            }
+            Ok(())
+        })();
+
+        // Commit whatever metadata changes were made, even on failure, so that
+        // the in-memory BAT matches the file.
+        let commit = self.commit(&mut state, batch);
+        result.and(commit)
+    }
+
+    /// Deallocates the blocks entirely within `offset..offset + len`.
+    ///
+    /// Deallocated blocks read as zero. Partial blocks are left untouched.
+    pub fn unmap(&self, offset: u64, len: u64) -> io::Result<()> {
+        assert!(!self.read_only);
+        let geometry = &self.geometry;
+        if geometry.leave_blocks_allocated {
+            return Ok(());
+        }
+        let first = offset.div_ceil(geometry.block_size);
+        let end = (offset + len) / geometry.block_size;
+        if first >= end {
+            return Ok(());
+        }
+        // A block that is not present in a differencing disk reads from the
+        // parent, so use the explicit zero state instead.
+        let new_entry = if geometry.has_parent {
+            format::bat_entry(payload_block::ZERO, 0)
+        } else {
+            format::bat_entry(payload_block::NOT_PRESENT, 0)
+        };
+        let mut state = self.state.write();
+        let mut batch = Batch::default();
+        for block in first..end {
+            let index = geometry.payload_index(block);
+            if state.bat[index] != new_entry {
+                self.begin_session(&mut state)?;
+                self.set_bat(&mut state, &mut batch, index, new_entry);
+            }
+        }
+        self.commit(&mut state, batch)
+    }
+
+    /// Updates the headers with new write GUIDs and activates the log before
+    /// the first modification of the file.
+    fn begin_session(&self, state: &mut State) -> io::Result<()> {
+        if state.log.is_some() {
+            return Ok(());
+        }
+        let log_guid = Guid::new_random();
+        let mut header = state.header;
+        header.file_write_guid = Guid::new_random();
+        header.data_write_guid = Guid::new_random();
+        header.log_guid = log_guid;
+        update_headers(&self.file, &mut header, &mut state.header_slot)?;
+        state.header = header;
+        state.log = Some(LogWriter::new(
+            header.log_offset,
+            header.log_length,
+            log_guid,
+        ));
+        Ok(())
+    }
+
+    fn set_bat(&self, state: &mut State, batch: &mut Batch, index: usize, entry: u64) {
+        state.bat[index] = entry;
+        batch
+            .bat_sectors
+            .insert(index as u64 * format::BAT_ENTRY_SIZE / LOG_SECTOR_SIZE);
+    }
+
+    /// Marks `count` sectors starting at `first` in `block` as present.
+    fn set_bitmap(
+        &self,
+        state: &mut State,
+        batch: &mut Batch,
+        block: u64,
+        first: u64,
+        count: u64,
+    ) -> io::Result<()> {
+        let geometry = &self.geometry;
+        let index = geometry.bitmap_index(block / geometry.chunk_ratio);
+        let mut entry = state.bat[index];
+        if format::bat_state(entry) != sector_bitmap_block::PRESENT {
+            let file_offset = allocate(&self.file, state, MB)?;
+            entry = format::bat_entry(sector_bitmap_block::PRESENT, file_offset);
+            self.set_bat(state, batch, index, entry);
+        }
+        let bitmap_offset = format::bat_file_offset(entry);
+        let first_bit = (block % geometry.chunk_ratio) * geometry.sectors_per_block() + first;
+        let sector_bits = LOG_SECTOR_SIZE * 8;
+        let mut bit = first_bit;
+        while bit < first_bit + count {
+            let sector_offset = bitmap_offset + bit / sector_bits * LOG_SECTOR_SIZE;
+            let sector = match batch.bitmap_sectors.entry(sector_offset) {
+                std::collections::btree_map::Entry::Occupied(e) => e.into_mut(),
+                std::collections::btree_map::Entry::Vacant(e) => {
+                    let mut data = vec![0; LOG_SECTOR_SIZE as usize];
+                    self.file.read_exact_at(&mut data, sector_offset)?;
+                    e.insert(data)
+                }
+            };
+            let end = (bit / sector_bits + 1) * sector_bits;
+            while bit < end.min(first_bit + count) {
+                let i = bit % sector_bits;
+                sector[(i / 8) as usize] |= 1 << (i % 8);
+                bit += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn commit(&self, state: &mut State, batch: Batch) -> io::Result<()> {
+        if batch.bat_sectors.is_empty() && batch.bitmap_sectors.is_empty() {
+            return Ok(());
+        }
+        let entries_per_sector = (LOG_SECTOR_SIZE / format::BAT_ENTRY_SIZE) as usize;
+        let bat_sectors = batch
+            .bat_sectors
+            .iter()
+            .map(|&sector| {
+                let mut data = vec![0; LOG_SECTOR_SIZE as usize];
+                let start = sector as usize * entries_per_sector;
+                let end = (start + entries_per_sector).min(state.bat.len());
+                let bytes = state.bat[start..end].as_bytes();
+                data[..bytes.len()].copy_from_slice(bytes);
+                (self.geometry.bat_offset + sector * LOG_SECTOR_SIZE, data)
+            })
+            .collect::<Vec<_>>();
+        let sectors = batch
+            .bitmap_sectors
+            .iter()
+            .map(|(&offset, data)| (offset, data.as_slice()))
+            .chain(
+                bat_sectors
+                    .iter()
+                    .map(|(offset, data)| (*offset, data.as_slice())),
+            )
+            .collect::<Vec<_>>();
+        let file_end = state.file_end;
+        state
+            .log
+            .as_mut()
+            .expect("session started")
+            .write(&self.file, file_end, &sectors)
+    }
+}
+
+impl Drop for VhdxImage {
+    fn drop(&mut self) {
+        // Mark the log empty so that other implementations do not need to
+        // replay it. This is best effort; the log is replayed on the next
+        // open otherwise.
+        let state = self.state.get_mut();
+        if state.log.take().is_some() && self.file.sync_data().is_ok() {
+            let mut header = state.header;
+            header.log_guid = Guid::ZERO;
+            if let Err(err) = update_headers(&self.file, &mut header, &mut state.header_slot) {
+                tracing::warn!(
+                    error = &err as &dyn std::error::Error,
+                    "failed to clear vhdx log"
+                );
+            }
+        }
+    }
+}
+
+/// Allocates `len` bytes at the end of the file, which reads as zero.
+fn allocate(file: &File, state: &mut State, len: u64) -> io::Result<u64> {
+    let offset = state.file_end;
+    file.set_len(offset + len)?;
+    state.file_end = offset + len;
+    Ok(offset)
+}
+
+/// Writes `header` to both header slots, so that both are valid, flushing
+/// after each write.
+fn update_headers(file: &File, header: &mut Header, slot: &mut usize) -> io::Result<()> {
+    for _ in 0..2 {
+        header.sequence_number += 1;
+        *slot = 1 - *slot;
+        write_header(file, *slot, header)?;
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Formats `file` as a new VHDX image.
+pub(crate) fn format_image(file: &File, params: &FormatParams<'_>) -> Result<(), OpenError> {
+    let block_size = params.block_size;
+    let logical_sector_size = params.logical_sector_size;
+    let has_parent = params.parent.is_some();
+    let chunk_ratio = chunk_ratio(logical_sector_size, block_size);
+    let data_blocks = params.size.div_ceil(block_size as u64);
+    let bat_entries = bat_entry_count(params.size, block_size, chunk_ratio, has_parent);
+
+    let log_offset = MB;
+    let log_length = format::DEFAULT_LOG_LENGTH;
+    let metadata_offset = log_offset + log_length as u64;
+    let metadata_length = MB;
+    let bat_offset = metadata_offset + metadata_length;
+    let bat_length = (bat_entries * format::BAT_ENTRY_SIZE).next_multiple_of(MB);
+    let payload_offset = bat_offset + bat_length;
+
+    let mut bat = vec![0u64; bat_entries as usize];
+    let mut file_end = payload_offset;
+    if params.fixed {
+        for block in 0..data_blocks {
+            let index = (block + block / chunk_ratio) as usize;
+            bat[index] = format::bat_entry(payload_block::FULLY_PRESENT, file_end);
+            file_end += block_size as u64;
+        }
+    }
+
+    file.set_len(0)?;
+    file.set_len(file_end)?;
+
+    // File identifier.
+    let mut identifier = format::FileIdentifier::new_zeroed();
+    identifier.signature = format::FILE_IDENTIFIER_SIGNATURE;
+    for (dest, c) in identifier.creator.iter_mut().zip("openvmm".encode_utf16()) {
+        *dest = c;
+    }
+    file.write_all_at(identifier.as_bytes(), format::FILE_IDENTIFIER_OFFSET)?;
+
+    // Region tables.
+    let mut region_table = vec![0; format::REGION_TABLE_SIZE];
+    RegionTableHeader {
+        signature: format::REGION_TABLE_SIGNATURE,
+        checksum: 0,
+        entry_count: 2,
+        reserved: 0,
+    }
+    .write_to_prefix(&mut region_table)
+    .unwrap();
+    let regions = [
+        (format::BAT_REGION_GUID, bat_offset, bat_length),
+        (
+            format::METADATA_REGION_GUID,
+            metadata_offset,
+            metadata_length,
+        ),
+    ];
+    for (i, (guid, file_offset, length)) in regions.into_iter().enumerate() {
+        RegionTableEntry {
+            guid,
+            file_offset,
+            length: length as u32,
+            flags: format::REGION_ENTRY_REQUIRED,
+        }
+        .write_to_prefix(
+            &mut region_table[size_of::<RegionTableHeader>() + i * size_of::<RegionTableEntry>()..],
+        )
+        .unwrap();
+    }
+    let checksum = format::crc32c(&region_table);
+    region_table[format::CHECKSUM_OFFSET..format::CHECKSUM_OFFSET + 4]
+        .copy_from_slice(&checksum.to_le_bytes());
+    for offset in format::REGION_TABLE_OFFSETS {
+        file.write_all_at(&region_table, offset)?;
+    }
+
+    // Metadata.
+    let mut flags = 0;
+    if params.fixed {
+        flags |= format::FILE_PARAMETERS_LEAVE_BLOCKS_ALLOCATED;
+    }
+    if has_parent {
+        flags |= format::FILE_PARAMETERS_HAS_PARENT;
+    }
+    let required_disk_item =
+        format::METADATA_FLAG_IS_REQUIRED | format::METADATA_FLAG_IS_VIRTUAL_DISK;
+    let mut items = vec![
+        (
+            format::FILE_PARAMETERS_GUID,
+            format::METADATA_FLAG_IS_REQUIRED,
+            FileParameters { block_size, flags }.as_bytes().to_vec(),
+        ),
+        (
+            format::VIRTUAL_DISK_SIZE_GUID,
+            required_disk_item,
+            params.size.to_le_bytes().to_vec(),
+        ),
+        (
+            format::PAGE_83_DATA_GUID,
+            required_disk_item,
+            Guid::new_random().as_bytes().to_vec(),
+        ),
+        (
+            format::LOGICAL_SECTOR_SIZE_GUID,
+            required_disk_item,
+            logical_sector_size.to_le_bytes().to_vec(),
+        ),
+        (
+            format::PHYSICAL_SECTOR_SIZE_GUID,
+            required_disk_item,
+            params.physical_sector_size.to_le_bytes().to_vec(),
+        ),
+    ];
+    if let Some(parent) = &params.parent {
+        let locator = ParentLocator {
+            parent_linkage: parent.data_write_guid,
+            parent_linkage2: None,
+            relative_path: Some(parent.relative_path.to_owned()),
+            volume_path: None,
+            absolute_win32_path: parent.absolute_win32_path.map(str::to_owned),
+        };
+        items.push((
+            format::PARENT_LOCATOR_GUID,
+            format::METADATA_FLAG_IS_REQUIRED,
+            locator.build(),
+        ));
+    }
+    let mut table = vec![0; format::METADATA_TABLE_SIZE as usize];
+    MetadataTableHeader {
+        signature: format::METADATA_TABLE_SIGNATURE,
+        reserved: 0,
+        entry_count: items.len() as u16,
+        reserved2: [0; 10],
+    }
+    .write_to_prefix(&mut table)
+    .unwrap();
+    let mut item_offset = format::METADATA_TABLE_SIZE;
+    for (i, (item_id, flags, data)) in items.into_iter().enumerate() {
+        if item_offset + data.len() as u64 > metadata_length {
+            return Err(OpenError::InvalidParentLocator);
+        }
+        MetadataTableEntry {
+            item_id,
+            offset: item_offset as u32,
+            length: data.len() as u32,
+            flags,
+            reserved: 0,
+        }
+        .write_to_prefix(
+            &mut table[size_of::<MetadataTableHeader>() + i * size_of::<MetadataTableEntry>()..],
+        )
+        .unwrap();
+        file.write_all_at(&data, metadata_offset + item_offset)?;
+        item_offset = (item_offset + data.len() as u64).next_multiple_of(KB);
+    }
+    file.write_all_at(&table, metadata_offset)?;
+
+    // BAT.
+    file.write_all_at(bat.as_bytes(), bat_offset)?;
+
+    // Headers, last, so that the file is not recognized until it is complete.
+    let mut header = Header {
+        signature: format::HEADER_SIGNATURE,
+        checksum: 0,
+        sequence_number: 0,
+        file_write_guid: Guid::new_random(),
+        data_write_guid: Guid::new_random(),
+        log_guid: Guid::ZERO,
+        log_version: format::LOG_VERSION,
+        version: format::VERSION,
+        log_length,
+        log_offset,
+        reserved: [0; 4016],
+    };
+    file.sync_data()?;
+    let mut slot = 1;
+    update_headers(file, &mut header, &mut slot)?;
+    Ok(())
+}
@@ -0,0 +1,828 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A native VHDX disk implementation.
+//!
+//! Supports fixed, dynamic, and differencing VHDX images. Unlike
+//! `disk_vhdmp`, this does not rely on the Windows VHD parser, so it can be
+//! used on any host.
+//!
+//! Metadata updates (the block allocation table and sector bitmaps) are made
+//! through the VHDX log, and a log left behind by a crash or by another
+//! implementation is replayed when the image is opened for write.
+//!
+//! The parent of a differencing disk is opened by the caller and passed to
+//! [`VhdxDisk::open`] as a [`Disk`]. Use [`read_info`] to get the image's
+//! [`ParentLocator`] and to check a candidate parent's data write GUID
+//! against it. Images can also be used as a layer of a
+//! [`disk_layered::LayeredDisk`] via [`LazyVhdxDiskLayer`], in which case the
+//! layers below take the place of the parent.
+
+#![expect(missing_docs)]
+#![forbid(unsafe_code)]
+
+pub mod format;
+mod image;
+mod log;
+pub mod resolver;
+
+pub use image::ParentLocator;
+
+use blocking::unblock;
+use disk_backend::Disk;
+use disk_backend::DiskError;
+use disk_backend::DiskIo;
+use disk_backend::UnmapBehavior;
+use disk_layered::DiskLayerMetadata;
+use disk_layered::LayerAttach;
+use disk_layered::LayerIo;
+use disk_layered::SectorMarker;
+use guid::Guid;
+use image::VhdxImage;
+use inspect::Inspect;
+use scsi_buffers::RequestBuffers;
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// An error encountered while opening or creating a VHDX image.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum OpenError {
+    #[error("io error")]
+    Io(#[from] io::Error),
+    #[error("not a VHDX image")]
+    InvalidFileIdentifier,
+    #[error("no valid VHDX header")]
+    NoValidHeader,
+    #[error("unsupported VHDX version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("unsupported VHDX log version: {0}")]
+    UnsupportedLogVersion(u16),
+    #[error("invalid log location")]
+    InvalidLog,
+    #[error("the log is corrupt")]
+    CorruptLog,
+    #[error("the image must be opened for write to replay its log")]
+    LogReplayRequired,
+    #[error("no valid region table")]
+    NoValidRegionTable,
+    #[error("missing required region {0}")]
+    MissingRegion(Guid),
+    #[error("unknown required region {0}")]
+    UnknownRequiredRegion(Guid),
+    #[error("invalid region {0}")]
+    InvalidRegion(Guid),
+    #[error("invalid metadata table")]
+    InvalidMetadataTable,
+    #[error("missing required metadata item {0}")]
+    MissingMetadata(Guid),
+    #[error("unknown required metadata item {0}")]
+    UnknownRequiredMetadata(Guid),
+    #[error("invalid metadata item {0}")]
+    InvalidMetadata(Guid),
+    #[error("invalid block size: {0}")]
+    InvalidBlockSize(u32),
+    #[error("invalid sector size: {0}")]
+    InvalidSectorSize(u32),
+    #[error("invalid disk size: {0}")]
+    InvalidDiskSize(u64),
+    #[error("invalid parent locator")]
+    InvalidParentLocator,
+    #[error("invalid BAT entry {0}: {1:#x}")]
+    InvalidBatEntry(u64, u64),
+    #[error("image is a differencing disk, but no parent disk was provided")]
+    ParentDiskRequired,
+    #[error("parent disk sector size {0} does not match the image's logical sector size")]
+    ParentSectorSize(u32),
+    #[error("lower layer sector size {0} does not match the image's logical sector size")]
+    LayerSectorSize(u32),
+    #[error("only a differencing image can be written on top of other layers")]
+    LayerNotDifferencing,
+}
+
+/// The parent of a new differencing disk.
+#[derive(Debug, Clone)]
+pub struct ParentParams<'a> {
+    /// The parent's data write GUID, from [`VhdxInfo::data_write_guid`].
+    pub data_write_guid: Guid,
+    /// The path to the parent, relative to the directory of the new disk.
+    pub relative_path: &'a str,
+    /// The absolute Windows path to the parent, if known.
+    pub absolute_win32_path: Option<&'a str>,
+}
+
+/// Parameters for formatting a new VHDX image.
+#[derive(Debug, Clone)]
+pub struct FormatParams<'a> {
+    /// The virtual disk size, in bytes.
+    pub size: u64,
+    /// The block size, in bytes. Must be a power of two between 1MB and
+    /// 256MB.
+    pub block_size: u32,
+    /// The logical sector size, 512 or 4096.
+    pub logical_sector_size: u32,
+    /// The physical sector size, 512 or 4096.
+    pub physical_sector_size: u32,
+    /// Whether to allocate all blocks up front.
+    pub fixed: bool,
+    /// The parent, for a differencing disk.
+    pub parent: Option<ParentParams<'a>>,
+}
+
+impl FormatParams<'_> {
+    /// Returns parameters for a dynamic image of `size` bytes with the
+    /// default block size and 512-byte logical sectors.
+    pub fn new(size: u64) -> Self {
+        Self {
+            size,
+            block_size: format::DEFAULT_BLOCK_SIZE,
+            logical_sector_size: 512,
+            physical_sector_size: 4096,
+            fixed: false,
+            parent: None,
+        }
+    }
+}
+
+/// Formats `file` as an empty VHDX image, truncating any existing contents.
+pub fn format(file: &File, params: &FormatParams<'_>) -> Result<(), OpenError> {
+    if !params.block_size.is_power_of_two()
+        || !(format::MIN_BLOCK_SIZE..=format::MAX_BLOCK_SIZE).contains(&params.block_size)
+    {
+        return Err(OpenError::InvalidBlockSize(params.block_size));
+    }
+    for sector_size in [params.logical_sector_size, params.physical_sector_size] {
+        if sector_size != 512 && sector_size != 4096 {
+            return Err(OpenError::InvalidSectorSize(sector_size));
+        }
+    }
+    if params.size == 0
+        || params.size > format::MAX_DISK_SIZE
+        || params.size % params.logical_sector_size as u64 != 0
+        || (params.fixed && params.parent.is_some())
+    {
+        return Err(OpenError::InvalidDiskSize(params.size));
+    }
+    image::format_image(file, params)
+}
+
+/// Information about a VHDX image, as returned by [`read_info`].
+#[derive(Debug, Clone)]
+pub struct VhdxInfo {
+    /// The virtual disk size, in bytes.
+    pub disk_size: u64,
+    /// The block size, in bytes.
+    pub block_size: u32,
+    /// The logical sector size.
+    pub logical_sector_size: u32,
+    /// The physical sector size.
+    pub physical_sector_size: u32,
+    /// Whether all blocks are allocated up front.
+    pub fixed: bool,
+    /// The GUID identifying the current contents of the disk, which
+    /// differencing disks use to refer to their parent.
+    pub data_write_guid: Guid,
+    /// The parent locator, for a differencing disk.
+    pub parent_locator: Option<ParentLocator>,
+}
+
+/// Reads the header and metadata of the image in `file`.
+///
+/// This does not replay the log, so it can be used on an image that is
+/// opened read-only.
+pub fn read_info(file: &File) -> Result<VhdxInfo, OpenError> {
+    let (header, metadata) = image::read_info(file)?;
+    Ok(VhdxInfo {
+        disk_size: metadata.disk_size,
+        block_size: metadata.block_size,
+        logical_sector_size: metadata.logical_sector_size,
+        physical_sector_size: metadata.physical_sector_size,
+        fixed: metadata.leave_blocks_allocated,
+        data_write_guid: header.data_write_guid,
+        parent_locator: metadata.parent_locator,
+    })
+}
+
+/// An open VHDX disk.
+#[derive(Inspect)]
+pub struct VhdxDisk {
+    #[inspect(flatten)]
+    image: Arc<VhdxImage>,
+    parent: Option<Disk>,
+}
+
+impl VhdxDisk {
+    /// Opens a VHDX image.
+    ///
+    /// If the image is a differencing disk, then `parent` must be the opened
+    /// parent disk. Reads of sectors not present in the image are satisfied
+    /// from `parent`, which is never written to.
+    pub fn open(file: File, parent: Option<Disk>, read_only: bool) -> Result<Self, OpenError> {
+        let image = VhdxImage::open(file, read_only)?;
+        let geometry = image.geometry();
+        let parent = if geometry.has_parent {
+            let parent = parent.ok_or(OpenError::ParentDiskRequired)?;
+            if parent.sector_size() != geometry.logical_sector_size {
+                return Err(OpenError::ParentSectorSize(parent.sector_size()));
+            }
+            Some(parent)
+        } else {
+            None
+        };
+        Ok(Self {
+            image: Arc::new(image),
+            parent,
+        })
+    }
+
+    /// Returns the parent locator, if this is a differencing disk.
+    pub fn parent_locator(&self) -> Option<&ParentLocator> {
+        self.image.geometry().parent_locator.as_ref()
+    }
+
+    /// Returns the GUID identifying the current contents of the disk.
+    pub fn data_write_guid(&self) -> Guid {
+        self.image.data_write_guid()
+    }
+
+    fn sector_shift(&self) -> u32 {
+        sector_shift(&self.image)
+    }
+
+    fn check_range(&self, sector: u64, len: u64) -> Result<u64, DiskError> {
+        check_range(&self.image, sector, len)
+    }
+}
+
+fn sector_shift(image: &VhdxImage) -> u32 {
+    image.geometry().logical_sector_size.trailing_zeros()
+}
+
+/// Returns the byte offset of `sector`, after checking that `len` bytes from
+/// there are within the disk.
+fn check_range(image: &VhdxImage, sector: u64, len: u64) -> Result<u64, DiskError> {
+    let offset = sector << sector_shift(image);
+    if offset
+        .checked_add(len)
+        .is_none_or(|end| end > image.geometry().disk_size)
+    {
+        return Err(DiskError::IllegalBlock);
+    }
+    Ok(offset)
+}
+
+impl DiskIo for VhdxDisk {
+    fn disk_type(&self) -> &str {
+        "vhdx"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.image.geometry().disk_size >> self.sector_shift()
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.image.geometry().logical_sector_size
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        self.image.geometry().page_83_data.map(Into::into)
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        self.image.geometry().physical_sector_size
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        true
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.image.is_read_only()
+    }
+
+    async fn read_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+    ) -> Result<(), DiskError> {
+        let offset = self.check_range(sector, buffers.len() as u64)?;
+        let image = self.image.clone();
+        let mut buffer = vec![0; buffers.len()];
+        let (buffer, parent_ranges) = unblock(move || -> io::Result<_> {
+            let parent_ranges = image.read(offset, &mut buffer)?;
+            Ok((buffer, parent_ranges))
+        })
+        .await
+        .map_err(DiskError::Io)?;
+        buffers.writer().write(&buffer)?;
+        if let Some(parent) = &self.parent {
+            let parent_len = parent.sector_count() << self.sector_shift();
+            for range in parent_ranges {
+                // Sectors past the end of a smaller parent read as zero.
+                let end = range.end.min(parent_len);
+                if range.start >= end {
+                    continue;
+                }
+                parent
+                    .read_vectored(
+                        &buffers.subrange(
+                            (range.start - offset) as usize,
+                            (end - range.start) as usize,
+                        ),
+                        range.start >> self.sector_shift(),
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        if self.image.is_read_only() {
+            return Err(DiskError::ReadOnly);
+        }
+        let offset = self.check_range(sector, buffers.len() as u64)?;
+        let mut data = vec![0; buffers.len()];
+        buffers.reader().read(&mut data)?;
+        let image = self.image.clone();
+        unblock(move || image.write(offset, &data))
+            .await
+            .map_err(DiskError::Io)?;
+        if fua {
+            self.sync_cache().await?;
+        }
+        Ok(())
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        let image = self.image.clone();
+        unblock(move || image.flush()).await.map_err(DiskError::Io)
+    }
+
+    async fn unmap(
+        &self,
+        sector: u64,
+        count: u64,
+        _block_level_only: bool,
+    ) -> Result<(), DiskError> {
+        if self.image.is_read_only() {
+            return Err(DiskError::ReadOnly);
+        }
+        let len = count
+            .checked_mul(self.sector_size() as u64)
+            .ok_or(DiskError::IllegalBlock)?;
+        let offset = self.check_range(sector, len)?;
+        let image = self.image.clone();
+        unblock(move || image.unmap(offset, len))
+            .await
+            .map_err(DiskError::Io)
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        // Only whole blocks are unmapped.
+        UnmapBehavior::Unspecified
+    }
+
+    fn optimal_unmap_sectors(&self) -> u32 {
+        (self.image.geometry().block_size >> self.sector_shift()) as u32
+    }
+}
+
+/// A VHDX disk layer that opens its image when attached to a layer stack, so
+/// that the image can be checked against the layers below it.
+pub struct LazyVhdxDiskLayer {
+    file: File,
+    read_only: bool,
+}
+
+impl LazyVhdxDiskLayer {
+    /// Returns a new layer for `file`.
+    pub fn new(file: File, read_only: bool) -> Self {
+        Self { file, read_only }
+    }
+}
+
+impl LayerAttach for LazyVhdxDiskLayer {
+    type Error = OpenError;
+    type Layer = VhdxDiskLayer;
+
+    async fn attach(
+        self,
+        lower_layer_metadata: Option<DiskLayerMetadata>,
+    ) -> Result<Self::Layer, Self::Error> {
+        let Self { file, read_only } = self;
+        let image = unblock(move || VhdxImage::open(file, read_only)).await?;
+        let geometry = image.geometry();
+        if let Some(lower) = lower_layer_metadata {
+            if lower.sector_size != geometry.logical_sector_size {
+                return Err(OpenError::LayerSectorSize(lower.sector_size));
+            }
+            // Only a differencing image tracks the presence of individual
+            // sectors. In any other image, a partial write of a new block
+            // would hide the rest of the block from the layers below.
+            if !geometry.has_parent && !read_only {
+                return Err(OpenError::LayerNotDifferencing);
+            }
+        }
+        Ok(VhdxDiskLayer {
+            image: Arc::new(image),
+        })
+    }
+}
+
+/// A VHDX image used as a disk layer.
+///
+/// Any parent recorded in the image is ignored; the layers below this one
+/// provide the data for sectors not present in the image instead.
+#[derive(Inspect)]
+pub struct VhdxDiskLayer {
+    #[inspect(flatten)]
+    image: Arc<VhdxImage>,
+}
+
+impl LayerIo for VhdxDiskLayer {
+    fn layer_type(&self) -> &str {
+        "vhdx"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.image.geometry().disk_size >> sector_shift(&self.image)
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.image.geometry().logical_sector_size
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        self.image.geometry().page_83_data.map(Into::into)
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        self.image.geometry().physical_sector_size
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        true
+    }
+
+    fn is_logically_read_only(&self) -> bool {
+        self.image.is_read_only()
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        let image = self.image.clone();
+        unblock(move || image.flush()).await.map_err(DiskError::Io)
+    }
+
+    async fn read(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        mut marker: SectorMarker<'_>,
+    ) -> Result<(), DiskError> {
+        let offset = check_range(&self.image, sector, buffers.len() as u64)?;
+        let image = self.image.clone();
+        let mut buffer = vec![0; buffers.len()];
+        let (buffer, absent) = unblock(move || -> io::Result<_> {
+            let absent = image.read(offset, &mut buffer)?;
+            Ok((buffer, absent))
+        })
+        .await
+        .map_err(DiskError::Io)?;
+        buffers.writer().write(&buffer)?;
+        // Only mark the sectors that are present, so that the rest are read
+        // from the next layer.
+        let shift = sector_shift(&self.image);
+        let mut pos = sector;
+        for range in absent
+            .iter()
+            .map(|range| range.start >> shift..range.end >> shift)
+            .chain(std::iter::once(
+                sector + (buffers.len() as u64 >> shift)..u64::MAX,
+            ))
+        {
+            marker.set_range(pos..range.start);
+            pos = range.end;
+        }
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        if self.image.is_read_only() {
+            return Err(DiskError::ReadOnly);
+        }
+        let offset = check_range(&self.image, sector, buffers.len() as u64)?;
+        let mut data = vec![0; buffers.len()];
+        buffers.reader().read(&mut data)?;
+        let image = self.image.clone();
+        unblock(move || image.write(offset, &data))
+            .await
+            .map_err(DiskError::Io)?;
+        if fua {
+            self.sync_cache().await?;
+        }
+        Ok(())
+    }
+
+    async fn unmap(
+        &self,
+        sector: u64,
+        count: u64,
+        _block_level_only: bool,
+        _next_is_zero: bool,
+    ) -> Result<(), DiskError> {
+        if self.image.is_read_only() {
+            return Err(DiskError::ReadOnly);
+        }
+        // Unmapped blocks of a differencing image are put in the zero state
+        // rather than exposing the layers below, and a writable image of any
+        // other type has no layers below it, so these read as zero either
+        // way.
+        let len = count
+            .checked_mul(self.sector_size() as u64)
+            .ok_or(DiskError::IllegalBlock)?;
+        let offset = check_range(&self.image, sector, len)?;
+        let image = self.image.clone();
+        unblock(move || image.unmap(offset, len))
+            .await
+            .map_err(DiskError::Io)
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        // Only whole blocks are unmapped.
+        UnmapBehavior::Unspecified
+    }
+
+    fn optimal_unmap_sectors(&self) -> u32 {
+        (self.image.geometry().block_size >> sector_shift(&self.image)) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FormatParams;
+    use super::LazyVhdxDiskLayer;
+    use super::ParentParams;
+    use super::VhdxDisk;
+    use crate::format::MB;
+    use disk_backend::Disk;
+    use disk_file::readwriteat::ReadWriteAt;
+    use disk_layered::DiskLayer;
+    use disk_layered::LayerConfiguration;
+    use disk_layered::LayeredDisk;
+    use disklayer_ram::ram_disk;
+    use guestmem::GuestMemory;
+    use pal_async::async_test;
+    use scsi_buffers::OwnedRequestBuffers;
+    use std::fs::File;
+
+    const SIZE: u64 = 64 * MB;
+
+    fn new_image(params: &FormatParams<'_>) -> File {
+        let file = tempfile::tempfile().unwrap();
+        super::format(&file, params).unwrap();
+        file
+    }
+
+    fn open(file: &File, parent: Option<Disk>, read_only: bool) -> Disk {
+        Disk::new(VhdxDisk::open(file.try_clone().unwrap(), parent, read_only).unwrap()).unwrap()
+    }
+
+    async fn read(disk: &Disk, sector: u64, len: usize) -> Vec<u8> {
+        let mem = GuestMemory::allocate(len);
+        disk.read_vectored(
+            &OwnedRequestBuffers::linear(0, len, true).buffer(&mem),
+            sector,
+        )
+        .await
+        .unwrap();
+        let mut data = vec![0; len];
+        mem.read_at(0, &mut data).unwrap();
+        data
+    }
+
+    async fn write(disk: &Disk, sector: u64, data: &[u8]) {
+        let mem = GuestMemory::allocate(data.len());
+        mem.write_at(0, data).unwrap();
+        disk.write_vectored(
+            &OwnedRequestBuffers::linear(0, data.len(), false).buffer(&mem),
+            sector,
+            false,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn pattern(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    #[async_test]
+    async fn dynamic_read_write_reopen() {
+        let file = new_image(&FormatParams {
+            block_size: MB as u32,
+            ..FormatParams::new(SIZE)
+        });
+        let disk = open(&file, None, false);
+        assert_eq!(disk.sector_count(), SIZE / 512);
+        assert!(read(&disk, 0, 4096).await.iter().all(|&b| b == 0));
+
+        // Spanning a block boundary.
+        let data = pattern(1, 256 * 1024);
+        let sector = MB / 512 - 100;
+        write(&disk, sector, &data).await;
+        write(&disk, sector + 1, &[0xaa; 512]).await;
+        let mut expected = data.clone();
+        expected[512..1024].fill(0xaa);
+        assert_eq!(read(&disk, sector, data.len()).await, expected);
+        assert!(read(&disk, 0, 4096).await.iter().all(|&b| b == 0));
+        drop(disk);
+
+        // Dropping the disk clears the log.
+        let info = super::read_info(&file).unwrap();
+        assert!(!info.fixed);
+        assert!(info.parent_locator.is_none());
+        let disk = open(&file, None, true);
+        assert_eq!(read(&disk, sector, data.len()).await, expected);
+        assert!(disk.is_read_only());
+    }
+
+    #[async_test]
+    async fn fixed() {
+        let file = new_image(&FormatParams {
+            block_size: MB as u32,
+            fixed: true,
+            ..FormatParams::new(4 * MB)
+        });
+        assert!(file.metadata().unwrap().len() >= 4 * MB);
+        let len = file.metadata().unwrap().len();
+        let disk = open(&file, None, false);
+        write(&disk, 7, &pattern(2, 8192)).await;
+        assert_eq!(read(&disk, 7, 8192).await, pattern(2, 8192));
+        // Fixed disks never grow.
+        assert_eq!(file.metadata().unwrap().len(), len);
+    }
+
+    #[async_test]
+    async fn log_replay() {
+        let file = new_image(&FormatParams {
+            block_size: MB as u32,
+            ..FormatParams::new(SIZE)
+        });
+        let disk = open(&file, None, false);
+        write(&disk, 0, &pattern(3, 4096)).await;
+
+        // Simulate a crash: capture the file before the log is cleared, and
+        // undo the in-place BAT update.
+        let mut contents = vec![0; file.metadata().unwrap().len() as usize];
+        file.read_exact_at(&mut contents, 0).unwrap();
+        drop(disk);
+        let crashed = tempfile::tempfile().unwrap();
+        crashed.write_all_at(&contents, 0).unwrap();
+        let bat_offset = 3 * MB;
+        crashed.write_all_at(&[0; 8], bat_offset).unwrap();
+
+        assert!(matches!(
+            VhdxDisk::open(crashed.try_clone().unwrap(), None, true),
+            Err(super::OpenError::LogReplayRequired)
+        ));
+        let disk = open(&crashed, None, false);
+        assert_eq!(read(&disk, 0, 4096).await, pattern(3, 4096));
+    }
+
+    #[async_test]
+    async fn differencing() {
+        let parent = ram_disk(SIZE, false).unwrap();
+        let parent_data = pattern(4, 2 * MB as usize);
+        write(&parent, 0, &parent_data).await;
+
+        let parent_guid = guid::Guid::new_random();
+        let file = new_image(&FormatParams {
+            block_size: MB as u32,
+            parent: Some(ParentParams {
+                data_write_guid: parent_guid,
+                relative_path: ".\\parent.vhdx",
+                absolute_win32_path: None,
+            }),
+            ..FormatParams::new(SIZE)
+        });
+        let info = super::read_info(&file).unwrap();
+        let locator = info.parent_locator.unwrap();
+        assert!(locator.matches(&parent_guid));
+        assert_eq!(locator.relative_path.as_deref(), Some(".\\parent.vhdx"));
+        assert!(matches!(
+            VhdxDisk::open(file.try_clone().unwrap(), None, false),
+            Err(super::OpenError::ParentDiskRequired)
+        ));
+
+        let disk = open(&file, Some(parent.clone()), false);
+        assert_eq!(read(&disk, 0, parent_data.len()).await, parent_data);
+
+        // Partial writes to a block only hide the written sectors of the
+        // parent.
+        write(&disk, 5, &[0x55; 1024]).await;
+        let mut expected = parent_data.clone();
+        expected[5 * 512..7 * 512].fill(0x55);
+        assert_eq!(read(&disk, 0, expected.len()).await, expected);
+
+        // Unmapping a whole block zeroes it rather than exposing the parent.
+        disk.unmap(MB / 512, MB / 512, false).await.unwrap();
+        expected[MB as usize..].fill(0);
+        assert_eq!(read(&disk, 0, expected.len()).await, expected);
+        drop(disk);
+
+        // The parent is untouched, and the changes persist.
+        assert_eq!(read(&parent, 0, parent_data.len()).await, parent_data);
+        let disk = open(&file, Some(parent), true);
+        assert_eq!(read(&disk, 0, expected.len()).await, expected);
+    }
+
+    async fn layered_disk(file: &File, read_only: bool, base: &Disk) -> anyhow::Result<Disk> {
+        let disk = LayeredDisk::new(
+            read_only,
+            vec![
+                LayerConfiguration {
+                    layer: DiskLayer::new(LazyVhdxDiskLayer::new(
+                        file.try_clone().unwrap(),
+                        read_only,
+                    )),
+                    write_through: false,
+                    read_cache: false,
+                },
+                LayerConfiguration {
+                    layer: DiskLayer::from_disk(base.clone()),
+                    write_through: false,
+                    read_cache: false,
+                },
+            ],
+        )
+        .await?;
+        Ok(Disk::new(disk)?)
+    }
+
+    #[async_test]
+    async fn layered() {
+        let base = ram_disk(SIZE, false).unwrap();
+        write(&base, 0, &pattern(7, 8192)).await;
+
+        let file = new_image(&FormatParams {
+            block_size: MB as u32,
+            parent: Some(ParentParams {
+                data_write_guid: guid::Guid::new_random(),
+                relative_path: ".\\parent.vhdx",
+                absolute_win32_path: None,
+            }),
+            ..FormatParams::new(SIZE)
+        });
+        let disk = layered_disk(&file, false, &base).await.unwrap();
+        write(&disk, 3, &[9; 512]).await;
+        let mut expected = pattern(7, 8192);
+        expected[3 * 512..4 * 512].fill(9);
+        assert_eq!(read(&disk, 0, 8192).await, expected);
+        // The base layer is untouched.
+        assert_eq!(read(&base, 0, 8192).await, pattern(7, 8192));
+        drop(disk);
+
+        // Only the written sector is present in the image.
+        let disk = open(&file, Some(ram_disk(SIZE, true).unwrap()), true);
+        let mut expected = vec![0; 8192];
+        expected[3 * 512..4 * 512].fill(9);
+        assert_eq!(read(&disk, 0, 8192).await, expected);
+    }
+
+    #[async_test]
+    async fn layered_not_differencing() {
+        let base = ram_disk(SIZE, false).unwrap();
+        write(&base, 0, &pattern(8, 8192)).await;
+
+        // A partial write would hide the rest of the block, so a writable
+        // dynamic image cannot be stacked on another layer.
+        let file = new_image(&FormatParams {
+            block_size: MB as u32,
+            ..FormatParams::new(SIZE)
+        });
+        assert!(layered_disk(&file, false, &base).await.is_err());
+
+        // Read only, the unallocated blocks expose the base layer.
+        let disk = open(&file, None, false);
+        write(&disk, MB / 512, &[9; 512]).await;
+        drop(disk);
+        let disk = layered_disk(&file, true, &base).await.unwrap();
+        assert_eq!(read(&disk, 0, 8192).await, pattern(8, 8192));
+        let mut expected = vec![0; 1024];
+        expected[..512].fill(9);
+        assert_eq!(read(&disk, MB / 512, 1024).await, expected);
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The VHDX metadata log.
+//!
+//! Updates to VHDX metadata (the BAT, sector bitmaps) are first written to a
+//! circular log and flushed, then applied in place. On open, any entries that
+//! may not have been applied are replayed.
+//!
+//! Each entry written by [`LogWriter`] uses itself as the log tail. This is
+//! valid because the in-place writes of the previous entry are made durable by
+//! the flush that follows writing the next entry, and because entries are
+//! limited to half the log, so that a new entry never overwrites the previous
+//! one.
+
+use crate::OpenError;
+use crate::format;
+use crate::format::LOG_SECTOR_SIZE;
+use crate::format::LogDataSector;
+use crate::format::LogDescriptor;
+use crate::format::LogEntryHeader;
+use disk_file::readwriteat::ReadWriteAt;
+use guid::Guid;
+use std::fs::File;
+use std::io;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+const SECTOR: usize = LOG_SECTOR_SIZE as usize;
+
+enum Operation {
+    Zero { file_offset: u64, len: u64 },
+    Data { file_offset: u64, data: Vec<u8> },
+}
+
+struct Entry {
+    len: usize,
+    sequence_number: u64,
+    tail: usize,
+    flushed_file_offset: u64,
+    last_file_offset: u64,
+    operations: Vec<Operation>,
+}
+
+/// Returns the number of log sectors needed for an entry with `count`
+/// data descriptors.
+fn entry_sectors(count: usize) -> usize {
+    count + (size_of::<LogEntryHeader>() + count * size_of::<LogDescriptor>()).div_ceil(SECTOR)
+}
+
+/// Parses the entry at `offset` in `log`, returning `None` if there is no
+/// valid entry for `guid` there.
+fn parse_entry(log: &[u8], offset: usize, guid: &Guid) -> Option<Entry> {
+    let (header, _) = LogEntryHeader::read_from_prefix(&log[offset..]).ok()?;
+    if header.signature != format::LOG_ENTRY_SIGNATURE || header.log_guid != *guid {
+        return None;
+    }
+    let len = header.entry_length as usize;
+    let tail = header.tail as usize;
+    if len == 0 || len % SECTOR != 0 || len > log.len() - offset {
+        return None;
+    }
+    if tail % SECTOR != 0 || tail >= log.len() {
+        return None;
+    }
+    let entry = &log[offset..offset + len];
+    if format::checksum_with_field(entry, format::CHECKSUM_OFFSET) != header.checksum {
+        return None;
+    }
+    let count = header.descriptor_count as usize;
+    let descriptors_len = size_of::<LogEntryHeader>() + count * size_of::<LogDescriptor>();
+    if descriptors_len > len {
+        return None;
+    }
+    let mut data_sector = descriptors_len.div_ceil(SECTOR);
+    let mut operations = Vec::with_capacity(count);
+    for i in 0..count {
+        let descriptor_offset = size_of::<LogEntryHeader>() + i * size_of::<LogDescriptor>();
+        let (descriptor, _) = LogDescriptor::read_from_prefix(&entry[descriptor_offset..]).ok()?;
+        if descriptor.sequence_number != header.sequence_number
+            || descriptor.file_offset % LOG_SECTOR_SIZE != 0
+        {
+            return None;
+        }
+        match descriptor.signature {
+            format::ZERO_DESCRIPTOR_SIGNATURE => {
+                if descriptor.leading_bytes % LOG_SECTOR_SIZE != 0 {
+                    return None;
+                }
+                operations.push(Operation::Zero {
+                    file_offset: descriptor.file_offset,
+                    len: descriptor.leading_bytes,
+                });
+            }
+            format::DATA_DESCRIPTOR_SIGNATURE => {
+                let sector = entry.get(data_sector * SECTOR..(data_sector + 1) * SECTOR)?;
+                let sector = LogDataSector::read_from_bytes(sector).ok()?;
+                let sequence_number =
+                    (sector.sequence_high as u64) << 32 | sector.sequence_low as u64;
+                if sector.signature != format::DATA_SECTOR_SIGNATURE
+                    || sequence_number != header.sequence_number
+                {
+                    return None;
+                }
+                let mut data = Vec::with_capacity(SECTOR);
+                data.extend_from_slice(&descriptor.leading_bytes.to_le_bytes());
+                data.extend_from_slice(&sector.data);
+                data.extend_from_slice(&descriptor.trailing_bytes.to_le_bytes());
+                operations.push(Operation::Data {
+                    file_offset: descriptor.file_offset,
+                    data,
+                });
+                data_sector += 1;
+            }
+            _ => return None,
+        }
+    }
+    Some(Entry {
+        len,
+        sequence_number: header.sequence_number,
+        tail,
+        flushed_file_offset: header.flushed_file_offset,
+        last_file_offset: header.last_file_offset,
+        operations,
+    })
+}
+
+/// Finds the active sequence of entries in `log`: the complete sequence with
+/// the highest sequence number, in order from its tail to its head.
+fn find_active_sequence(log: &[u8], guid: &Guid) -> Vec<Entry> {
+    let sectors = log.len() / SECTOR;
+    let mut entries = (0..sectors)
+        .map(|i| parse_entry(log, i * SECTOR, guid))
+        .collect::<Vec<_>>();
+
+    let mut best: Option<(u64, Vec<usize>)> = None;
+    for start in 0..sectors {
+        // Collect the run of entries with consecutive sequence numbers
+        // starting here.
+        let mut run = Vec::new();
+        let mut index = start;
+        let mut prev_sequence_number = None;
+        while let Some(entry) = &entries[index] {
+            if prev_sequence_number.is_some_and(|seq: u64| seq + 1 != entry.sequence_number) {
+                break;
+            }
+            prev_sequence_number = Some(entry.sequence_number);
+            run.push(index);
+            index = (index + entry.len / SECTOR) % sectors;
+            if index == start || run.len() == sectors {
+                break;
+            }
+        }
+        // The run is a complete sequence up to the last entry whose tail is
+        // part of the run.
+        for (head_pos, &head) in run.iter().enumerate().rev() {
+            let head_entry = entries[head].as_ref().unwrap();
+            if let Some(tail_pos) = run[..=head_pos]
+                .iter()
+                .position(|&i| i * SECTOR == head_entry.tail)
+            {
+                if best
+                    .as_ref()
+                    .is_none_or(|(seq, _)| head_entry.sequence_number > *seq)
+                {
+                    best = Some((
+                        head_entry.sequence_number,
+                        run[tail_pos..=head_pos].to_vec(),
+                    ));
+                }
+                break;
+            }
+        }
+    }
+
+    best.map_or_else(Vec::new, |(_, indexes)| {
+        indexes
+            .into_iter()
+            .map(|i| entries[i].take().unwrap())
+            .collect()
+    })
+}
+
+/// Replays the log described by `header` into `file`.
+pub(crate) fn replay(file: &File, header: &format::Header) -> Result<(), OpenError> {
+    let mut log = vec![0; header.log_length as usize];
+    file.read_exact_at(&mut log, header.log_offset)?;
+    let sequence = find_active_sequence(&log, &header.log_guid);
+    let Some(head) = sequence.last() else {
+        return Ok(());
+    };
+    let file_len = file.metadata()?.len();
+    if file_len < head.flushed_file_offset {
+        return Err(OpenError::CorruptLog);
+    }
+    tracing::info!(
+        entries = sequence.len(),
+        sequence_number = head.sequence_number,
+        "replaying vhdx log"
+    );
+    for entry in &sequence {
+        for operation in &entry.operations {
+            match operation {
+                &Operation::Zero { file_offset, len } => {
+                    let zero = vec![0; SECTOR];
+                    for offset in (file_offset..file_offset + len).step_by(SECTOR) {
+                        file.write_all_at(&zero, offset)?;
+                    }
+                }
+                Operation::Data { file_offset, data } => {
+                    file.write_all_at(data, *file_offset)?;
+                }
+            }
+        }
+    }
+    if file.metadata()?.len() < head.last_file_offset {
+        file.set_len(head.last_file_offset)?;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Writes metadata updates through the log.
+pub(crate) struct LogWriter {
+    offset: u64,
+    len: usize,
+    guid: Guid,
+    head: usize,
+    sequence_number: u64,
+    max_sectors_per_entry: usize,
+}
+
+impl LogWriter {
+    /// Returns a writer for a new, empty log session identified by `guid`.
+    pub fn new(offset: u64, len: u32, guid: Guid) -> Self {
+        let len = len as usize;
+        // Limit entries to half the log so that an entry never overwrites the
+        // previous one.
+        let max_entry_sectors = len / SECTOR / 2;
+        let mut max_sectors_per_entry = max_entry_sectors;
+        while max_sectors_per_entry > 0 && entry_sectors(max_sectors_per_entry) > max_entry_sectors
+        {
+            max_sectors_per_entry -= 1;
+        }
+        Self {
+            offset,
+            len,
+            guid,
+            head: 0,
+            sequence_number: 1,
+            max_sectors_per_entry,
+        }
+    }
+
+    /// Logs, flushes, and then applies the 4KB sector writes in `sectors`.
+    ///
+    /// `file_size` is the current size of the file.
+    pub fn write(
+        &mut self,
+        file: &File,
+        file_size: u64,
+        sectors: &[(u64, &[u8])],
+    ) -> io::Result<()> {
+        for chunk in sectors.chunks(self.max_sectors_per_entry.max(1)) {
+            self.write_entry(file, file_size, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_entry(
+        &mut self,
+        file: &File,
+        file_size: u64,
+        sectors: &[(u64, &[u8])],
+    ) -> io::Result<()> {
+        let len = entry_sectors(sectors.len()) * SECTOR;
+        if len > self.len / 2 {
+            return Err(io::Error::other("vhdx log is too small"));
+        }
+        if self.head + len > self.len {
+            self.head = 0;
+        }
+
+        let mut entry = vec![0; len];
+        let header = LogEntryHeader {
+            signature: format::LOG_ENTRY_SIGNATURE,
+            checksum: 0,
+            entry_length: len as u32,
+            tail: self.head as u32,
+            sequence_number: self.sequence_number,
+            descriptor_count: sectors.len() as u32,
+            reserved: 0,
+            log_guid: self.guid,
+            flushed_file_offset: file_size,
+            last_file_offset: file_size,
+        };
+        header.write_to_prefix(&mut entry).unwrap();
+        let mut data_sector = len / SECTOR - sectors.len();
+        for (i, &(file_offset, data)) in sectors.iter().enumerate() {
+            assert_eq!(data.len(), SECTOR);
+            let descriptor = LogDescriptor {
+                signature: format::DATA_DESCRIPTOR_SIGNATURE,
+                trailing_bytes: u32::from_le_bytes(data[SECTOR - 4..].try_into().unwrap()),
+                leading_bytes: u64::from_le_bytes(data[..8].try_into().unwrap()),
+                file_offset,
+                sequence_number: self.sequence_number,
+            };
+            let descriptor_offset = size_of::<LogEntryHeader>() + i * size_of::<LogDescriptor>();
+            descriptor
+                .write_to_prefix(&mut entry[descriptor_offset..])
+                .unwrap();
+            let mut sector = LogDataSector::new_zeroed();
+            sector.signature = format::DATA_SECTOR_SIGNATURE;
+            sector.sequence_high = (self.sequence_number >> 32) as u32;
+            sector.sequence_low = self.sequence_number as u32;
+            sector.data.copy_from_slice(&data[8..SECTOR - 4]);
+            entry[data_sector * SECTOR..(data_sector + 1) * SECTOR]
+                .copy_from_slice(sector.as_bytes());
+            data_sector += 1;
+        }
+        let checksum = format::crc32c(&entry);
+        entry[format::CHECKSUM_OFFSET..format::CHECKSUM_OFFSET + 4]
+            .copy_from_slice(&checksum.to_le_bytes());
+
+        file.write_all_at(&entry, self.offset + self.head as u64)?;
+        file.sync_data()?;
+        for &(file_offset, data) in sectors {
+            file.write_all_at(data, file_offset)?;
+        }
+        self.head += len;
+        self.sequence_number += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_and_find_entries() {
+        let file = tempfile::tempfile().unwrap();
+        let len = 64 * SECTOR;
+        let offset = 16 * SECTOR as u64;
+        file.set_len(offset + len as u64).unwrap();
+        let guid = Guid::new_random();
+        let mut writer = LogWriter::new(offset, len as u32, guid);
+
+        let a = vec![0xaa; SECTOR];
+        let b = vec![0xbb; SECTOR];
+        // Write enough entries to wrap the log.
+        for i in 0..40u64 {
+            let data = if i % 2 == 0 { &a } else { &b };
+            writer
+                .write(&file, 0, &[(i % 4 * SECTOR as u64, data.as_slice())])
+                .unwrap();
+        }
+
+        let mut log = vec![0; len];
+        file.read_exact_at(&mut log, offset).unwrap();
+        let sequence = find_active_sequence(&log, &guid);
+        assert_eq!(sequence.len(), 1);
+        assert_eq!(sequence[0].sequence_number, 40);
+        match &sequence[0].operations[..] {
+            [Operation::Data { file_offset, data }] => {
+                assert_eq!(*file_offset, 3 * SECTOR as u64);
+                assert_eq!(data, &b);
+            }
+            _ => panic!("unexpected operations"),
+        }
+
+        // Entries for another log session are ignored.
+        assert!(find_active_sequence(&log, &Guid::new_random()).is_empty());
+    }
+}
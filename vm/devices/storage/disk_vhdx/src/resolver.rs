@@ -0,0 +1,83 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolvers for VHDX disks and disk layers.
+
+use crate::LazyVhdxDiskLayer;
+use crate::VhdxDisk;
+use anyhow::Context;
+use async_trait::async_trait;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend::resolve::ResolvedDisk;
+use disk_backend_resources::VhdxDiskHandle;
+use disk_backend_resources::layer::VhdxDiskLayerHandle;
+use disk_layered::resolve::ResolveDiskLayerParameters;
+use disk_layered::resolve::ResolvedDiskLayer;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::DiskHandleKind;
+use vm_resource::kind::DiskLayerHandleKind;
+
+/// Resolver for a [`VhdxDiskHandle`].
+pub struct VhdxResolver;
+declare_static_async_resolver!(VhdxResolver, (DiskHandleKind, VhdxDiskHandle));
+
+#[async_trait]
+impl AsyncResolveResource<DiskHandleKind, VhdxDiskHandle> for VhdxResolver {
+    type Output = ResolvedDisk;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: VhdxDiskHandle,
+        input: ResolveDiskParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let parent = if let Some(parent) = rsrc.parent {
+            // The parent disk is never written to.
+            let parent = resolver
+                .resolve(
+                    parent,
+                    ResolveDiskParameters {
+                        read_only: true,
+                        driver_source: input.driver_source,
+                    },
+                )
+                .await
+                .context("failed to open vhdx parent disk")?;
+            Some(parent.0)
+        } else {
+            None
+        };
+        let disk = blocking::unblock(move || VhdxDisk::open(rsrc.file, parent, input.read_only))
+            .await
+            .context("failed to open vhdx image")?;
+        Ok(ResolvedDisk::new(disk)?)
+    }
+}
+
+/// Resolver for a [`VhdxDiskLayerHandle`].
+pub struct VhdxDiskLayerResolver;
+declare_static_resolver!(
+    VhdxDiskLayerResolver,
+    (DiskLayerHandleKind, VhdxDiskLayerHandle)
+);
+
+impl ResolveResource<DiskLayerHandleKind, VhdxDiskLayerHandle> for VhdxDiskLayerResolver {
+    type Output = ResolvedDiskLayer;
+    type Error = std::convert::Infallible;
+
+    fn resolve(
+        &self,
+        rsrc: VhdxDiskLayerHandle,
+        input: ResolveDiskLayerParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(ResolvedDiskLayer::new(LazyVhdxDiskLayer::new(
+            rsrc.file,
+            input.read_only,
+        )))
+    }
+}
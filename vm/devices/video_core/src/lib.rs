@@ -5,6 +5,7 @@
 
 #![forbid(unsafe_code)]
 
+use guestmem::GuestMemory;
 use inspect::Inspect;
 use mesh::MeshPayload;
 use mesh::payload::Protobuf;
@@ -80,4 +81,11 @@ pub trait FramebufferControl: Send {
     async fn unmap(&mut self);
     /// Updates the framebuffer format.
     async fn set_format(&mut self, format: FramebufferFormat);
+    /// Returns a [`GuestMemory`] for writing the framebuffer contents from
+    /// the host, along with the framebuffer size in bytes. Used by devices
+    /// that render into the framebuffer rather than mapping it into the
+    /// guest.
+    fn vram(&self) -> std::io::Result<(GuestMemory, usize)> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
 }
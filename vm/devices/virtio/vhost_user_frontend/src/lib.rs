@@ -13,15 +13,22 @@
 //! frontend connects to that server and presents it to the VMM as a
 //! standard virtio device.
 
+pub mod net;
 pub mod resolver;
 
 use anyhow::Context as _;
 use guestmem::GuestMemory;
+use guestmem::MappedMemoryRegion;
 use guestmem::ShareableRegion;
 use inspect::InspectMut;
+use pal_async::socket::PolledSocket;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
 use std::os::fd::AsFd;
 use std::os::fd::OwnedFd;
+use std::sync::Arc;
 use unix_socket::ScmReceiver;
+use unix_socket::UnixStream;
 use vhost_user_protocol::*;
 use virtio::DeviceTraits;
 use virtio::DeviceTraitsSharedMemory;
@@ -55,6 +62,8 @@
 /// - FS: `use_backend_config: false`, full config as a patch at offset 0
 /// - BLK: `use_backend_config: true`, num_queues patched
 /// - Generic: `use_backend_config: true`, no patches
+/// - NET: `use_backend_config: false`, config patch built by
+///   [`net::VhostUserNetDevice`], `queue_pairs: true`
 pub struct VhostUserConfig {
     /// Virtio device ID (e.g., BLK, FS).
     pub device_id: VirtioDeviceType,
@@ -72,11 +81,18 @@ pub struct VhostUserConfig {
     /// or zeros. Writes pass through to SET_CONFIG unchanged when
     /// `use_backend_config` is true.
     pub config_patches: Vec<(u16, Vec<u8>)>,
+    /// When true, the queues are receive/transmit pairs and the backend's
+    /// GET_QUEUE_NUM reply is a count of pairs, as vhost-user-net backends
+    /// report it.
+    pub queue_pairs: bool,
 }
 
 /// Per-queue tracking state.
 struct FrontendQueueState {
     active: bool,
+    /// Whether the backend should process the queue once it is started. See
+    /// [`VhostUserFrontend::set_queue_enabled`].
+    enabled: bool,
     /// Saved queue params for reading used ring index during stop.
     params: Option<virtio::queue::QueueParams>,
     /// Keeps the interrupt event proxy task alive (if one was needed).
@@ -115,6 +131,12 @@ pub struct VhostUserFrontend {
     /// Set on the first `start_queue` call, used by `stop_queue` to read
     /// the used index from the guest-visible used ring.
     guest_memory: Option<GuestMemory>,
+    /// The backend request channel, if the device has a shared memory
+    /// region. Taken by `set_shared_memory_region` to start serving the
+    /// backend's mapping requests.
+    backend_channel: Option<VhostUserSocket>,
+    /// Serves the backend's requests on the backend request channel.
+    backend_task: Option<Task<()>>,
 }
 
 impl VhostUserFrontend {
@@ -146,7 +168,10 @@ pub async fn from_socket(
                 .with_mq(true)
                 .with_reply_ack(true)
                 .with_config(config.use_backend_config)
-                .with_reset_device(true);
+                .with_reset_device(true)
+                .with_backend_req(true)
+                .with_backend_send_fd(true)
+                .with_shmem(true);
             let negotiated =
                 VhostUserProtocolFeatures::from_bits(proto_features_raw & wanted.into_bits());
             send_set_u64(
@@ -197,13 +222,87 @@ pub async fn from_socket(
             )
         })?;
         if let Some(backend_max) = backend_max_queues {
-            anyhow::ensure!(
-                max_queues <= backend_max,
-                "requested {max_queues} queues but backend supports at most {backend_max}"
-            );
+            if config.queue_pairs {
+                let pairs = max_queues.div_ceil(2);
+                anyhow::ensure!(
+                    pairs <= backend_max,
+                    "requested {pairs} queue pairs but backend supports at most {backend_max}"
+                );
+            } else {
+                anyhow::ensure!(
+                    max_queues <= backend_max,
+                    "requested {max_queues} queues but backend supports at most {backend_max}"
+                );
+            }
         }
         let queue_sizes = config.queue_sizes;
 
+        // 5. GET_SHMEM_CONFIG (requires SHMEM protocol feature)
+        let shared_memory = if negotiated_proto.shmem() {
+            let (payload, _fds) = send_and_recv(
+                &socket,
+                &mut receiver,
+                VhostUserRequestCode::GET_SHMEM_CONFIG,
+                &[],
+                &[] as &[OwnedFd],
+            )
+            .await?;
+            let shmem_config = VhostUserShmemConfig::read_from_prefix(&payload)
+                .map(|(val, _)| val)
+                .map_err(|_| anyhow::anyhow!("GET_SHMEM_CONFIG reply payload too small"))?;
+            let nregions = (shmem_config.nregions as usize).min(VHOST_USER_MAX_SHMEM_REGIONS);
+            let mut regions = shmem_config.memory_sizes[..nregions]
+                .iter()
+                .enumerate()
+                .filter(|&(_, &size)| size != 0);
+            let region = regions.next();
+            // The transports only support one shared memory region.
+            anyhow::ensure!(
+                regions.next().is_none(),
+                "backend has more than one shared memory region"
+            );
+            region.map_or_else(DeviceTraitsSharedMemory::default, |(id, &size)| {
+                DeviceTraitsSharedMemory { id: id as u8, size }
+            })
+        } else {
+            DeviceTraitsSharedMemory::default()
+        };
+        tracing::trace!(
+            id = shared_memory.id,
+            size = shared_memory.size,
+            "GET_SHMEM_CONFIG"
+        );
+
+        // 6. SET_BACKEND_REQ_FD, so that the backend can ask us to map into
+        // the shared memory region.
+        let backend_channel = if shared_memory.size > 0 {
+            anyhow::ensure!(
+                negotiated_proto.backend_req() && negotiated_proto.backend_send_fd(),
+                "backend has a shared memory region but no backend request channel"
+            );
+            let (ours, theirs) =
+                UnixStream::pair().context("failed to create backend request channel")?;
+            let hdr = VhostUserMsgHeader {
+                request: VhostUserRequestCode::SET_BACKEND_REQ_FD.0,
+                flags: request_flags(negotiated_proto.reply_ack()),
+                size: 0,
+            };
+            socket.send_message(&hdr, &[], &[theirs]).await?;
+            if negotiated_proto.reply_ack() {
+                recv_ack(
+                    &socket,
+                    &mut receiver,
+                    VhostUserRequestCode::SET_BACKEND_REQ_FD,
+                )
+                .await?;
+            }
+            let polled = PolledSocket::new(&driver, ours)
+                .context("failed to register backend request channel")?;
+            Some(VhostUserSocket::new(polled))
+        } else {
+            None
+        };
+
         // Build DeviceTraits from the wire features.
         let device_features = device_features_raw.with_vhost_user_protocol_features(false);
 
@@ -229,12 +328,13 @@ pub async fn from_socket(
             device_features,
             max_queues,
             device_register_length,
-            shared_memory: DeviceTraitsSharedMemory::default(),
+            shared_memory,
         };
 
         let queues = (0..max_queues)
             .map(|_| FrontendQueueState {
                 active: false,
+                enabled: true,
                 params: None,
                 _event_proxy: None,
             })
@@ -254,8 +354,40 @@ pub async fn from_socket(
             packed_ring: false,
             queues,
             guest_memory: None,
+            backend_channel,
+            backend_task: None,
         })
     }
+
+    /// Enables or disables queue `idx` on the backend.
+    ///
+    /// A disabled queue is still set up by `start_queue`, but the backend
+    /// does not process it until it is enabled. This lets virtio-net change
+    /// the number of active queue pairs without restarting any queues. The
+    /// setting persists across stops and resets.
+    pub async fn set_queue_enabled(&mut self, idx: u16, enable: bool) -> anyhow::Result<()> {
+        let q = self
+            .queues
+            .get_mut(idx as usize)
+            .with_context(|| format!("invalid queue index {idx}"))?;
+        if q.enabled == enable {
+            return Ok(());
+        }
+        q.enabled = enable;
+        if q.active {
+            tracing::trace!(idx, enable, "SET_VRING_ENABLE");
+            send_vring_state(
+                &self.socket,
+                &mut self.receiver,
+                VhostUserRequestCode::SET_VRING_ENABLE,
+                idx,
+                enable as u32,
+                self.protocol_features.reply_ack(),
+            )
+            .await?;
+        }
+        Ok(())
+    }
 }
 
 impl VirtioDevice for VhostUserFrontend {
@@ -263,6 +395,22 @@ fn traits(&self) -> DeviceTraits {
         self.device_traits.clone()
     }
 
+    fn set_shared_memory_region(
+        &mut self,
+        region: &Arc<dyn MappedMemoryRegion>,
+    ) -> anyhow::Result<()> {
+        // Requests the backend sent before now are queued on the channel
+        // and served once the task starts.
+        if let Some(channel) = self.backend_channel.take() {
+            let shmid = self.device_traits.shared_memory.id;
+            self.backend_task = Some(self.driver.spawn(
+                "vhost-user-backend-req",
+                serve_backend_requests(channel, shmid, region.clone()),
+            ));
+        }
+        Ok(())
+    }
+
     fn queue_size(&self, queue_index: u16) -> u16 {
         self.queue_sizes[queue_index as usize]
     }
@@ -496,12 +644,13 @@ async fn start_queue(
         .await?;
 
         // SET_VRING_ENABLE
+        let enable = self.queues.get(idx as usize).is_none_or(|q| q.enabled);
         send_vring_state(
             &self.socket,
             &mut self.receiver,
             VhostUserRequestCode::SET_VRING_ENABLE,
             idx,
-            1,
+            enable as u32,
             self.protocol_features.reply_ack(),
         )
         .await?;
@@ -648,6 +797,103 @@ fn supports_save_restore(&self) -> bool {
     }
 }
 
+/// Serves the backend's requests to map into and unmap from the shared
+/// memory region, until the backend closes the channel.
+async fn serve_backend_requests(
+    channel: VhostUserSocket,
+    shmid: u8,
+    region: Arc<dyn MappedMemoryRegion>,
+) {
+    let mut receiver = ScmReceiver::new(VHOST_USER_MAX_FDS);
+    loop {
+        let (hdr, payload, fds) = match channel.recv_message(&mut receiver).await {
+            Ok(msg) => msg,
+            Err(SocketError::Closed) => break,
+            Err(e) => {
+                tracing::error!(
+                    error = &e as &dyn std::error::Error,
+                    "backend request channel failed"
+                );
+                break;
+            }
+        };
+        let result = handle_backend_request(&hdr, &payload, fds, shmid, region.as_ref());
+        if let Err(e) = &result {
+            tracelimit::warn_ratelimited!(
+                error = &**e as &dyn std::error::Error,
+                code = hdr.request,
+                "backend request failed"
+            );
+        }
+        if hdr.need_reply() {
+            let reply = VhostUserMsgHeader::reply(&hdr, size_of::<VhostUserU64Msg>() as u32);
+            let payload = VhostUserU64Msg {
+                value: result.is_err() as u64,
+            };
+            if let Err(e) = channel
+                .send_message(&reply, payload.as_bytes(), &[] as &[OwnedFd])
+                .await
+            {
+                tracing::error!(
+                    error = &e as &dyn std::error::Error,
+                    "failed to reply on backend request channel"
+                );
+                break;
+            }
+        }
+    }
+}
+
+/// Handles a single request from the backend.
+fn handle_backend_request(
+    hdr: &VhostUserMsgHeader,
+    payload: &[u8],
+    fds: Vec<OwnedFd>,
+    shmid: u8,
+    region: &dyn MappedMemoryRegion,
+) -> anyhow::Result<()> {
+    let code = VhostUserBackendRequestCode(hdr.request);
+    match code {
+        VhostUserBackendRequestCode::SHMEM_MAP | VhostUserBackendRequestCode::SHMEM_UNMAP => {
+            let msg = VhostUserMMap::read_from_prefix(payload)
+                .map(|(val, _)| val)
+                .map_err(|_| anyhow::anyhow!("{code:?}: payload too small"))?;
+            anyhow::ensure!(
+                msg.shmid == shmid,
+                "{code:?}: unknown shared memory region {}",
+                msg.shmid
+            );
+            tracing::trace!(
+                ?code,
+                shm_offset = %format!("0x{:x}", msg.shm_offset),
+                fd_offset = %format!("0x{:x}", msg.fd_offset),
+                len = %format!("0x{:x}", msg.len),
+                flags = msg.flags,
+                "backend request",
+            );
+            let offset = usize::try_from(msg.shm_offset)?;
+            let len = usize::try_from(msg.len)?;
+            if code == VhostUserBackendRequestCode::SHMEM_MAP {
+                let fd = fds
+                    .into_iter()
+                    .next()
+                    .with_context(|| format!("{code:?}: missing fd"))?;
+                region.map(
+                    offset,
+                    &fd,
+                    msg.fd_offset,
+                    len,
+                    msg.flags & VHOST_USER_FLAG_MAP_RW != 0,
+                )?;
+            } else {
+                region.unmap(offset, len)?;
+            }
+            Ok(())
+        }
+        _ => anyhow::bail!("unsupported backend request {code:?}"),
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Protocol helper functions
 // ---------------------------------------------------------------------------
@@ -1245,6 +1491,7 @@ async fn setup_frontend_backend(
                 use_backend_config: true,
                 queue_sizes: vec![DEFAULT_QUEUE_SIZE; 2],
                 config_patches: vec![],
+                queue_pairs: false,
             },
         )
         .await
@@ -1441,6 +1688,7 @@ async fn setup_frontend_backend_with_device(
                 use_backend_config: true,
                 queue_sizes: vec![DEFAULT_QUEUE_SIZE; 2],
                 config_patches: vec![],
+                queue_pairs: false,
             },
         )
         .await
@@ -1661,6 +1909,7 @@ async fn frontend_owned_config_space(driver: DefaultDriver) {
                 use_backend_config: false,
                 queue_sizes: vec![1024; 2], // hiprio + 1 request queue
                 config_patches: vec![(0, config_bytes.clone())],
+                queue_pairs: false,
             },
         )
         .await
@@ -1717,6 +1966,7 @@ async fn fs_multi_queue_config(driver: DefaultDriver) {
             use_backend_config: false,
             queue_sizes: vec![queue_size; total_queues],
             config_patches: vec![(0, fs_config.as_bytes().to_vec())],
+            queue_pairs: false,
         };
 
         // Need a mock device with enough queues (3).
@@ -1757,6 +2007,7 @@ async fn blk_multi_queue_config(driver: DefaultDriver) {
             use_backend_config: true,
             queue_sizes: vec![queue_size; num_queues as usize],
             config_patches: vec![(num_queues_offset, num_queues.to_le_bytes().to_vec())],
+            queue_pairs: false,
         };
 
         // Backend needs config space so CONFIG protocol feature is
@@ -1794,6 +2045,7 @@ async fn generic_per_queue_sizes(driver: DefaultDriver) {
             use_backend_config: true,
             queue_sizes: queue_sizes.clone(),
             config_patches: vec![],
+            queue_pairs: false,
         };
 
         let (frontend, _guest_memory, backend_task) =
@@ -1819,6 +2071,7 @@ async fn queue_count_exceeds_backend(driver: DefaultDriver) {
             use_backend_config: true,
             queue_sizes: vec![256; 4], // 4 > 2
             config_patches: vec![],
+            queue_pairs: false,
         };
 
         let (frontend_stream, backend_stream) = socket_pair();
@@ -1850,4 +2103,309 @@ async fn queue_count_exceeds_backend(driver: DefaultDriver) {
 
         backend_task.await;
     }
+
+    /// A mock net backend that records which queues it has started.
+    struct NetMockDevice {
+        started_queues: Arc<std::sync::Mutex<Vec<u16>>>,
+    }
+
+    impl InspectMut for NetMockDevice {
+        fn inspect_mut(&mut self, _req: inspect::Request<'_>) {}
+    }
+
+    impl VirtioDevice for NetMockDevice {
+        fn traits(&self) -> DeviceTraits {
+            DeviceTraits {
+                device_id: VirtioDeviceType::NET,
+                // CSUM, MRG_RXBUF, and CTRL_RX.
+                device_features: VirtioDeviceFeatures::new()
+                    .with_bank(0, (1 << 0) | (1 << 15) | (1 << 18))
+                    .with_ring_event_idx(true),
+                max_queues: 4,
+                device_register_length: 0,
+                shared_memory: DeviceTraitsSharedMemory::default(),
+            }
+        }
+
+        async fn read_registers_u32(&mut self, _offset: u16) -> u32 {
+            0
+        }
+
+        async fn write_registers_u32(&mut self, _offset: u16, _val: u32) {}
+
+        async fn start_queue(
+            &mut self,
+            idx: u16,
+            _resources: QueueResources,
+            _features: &VirtioDeviceFeatures,
+            _initial_state: Option<QueueState>,
+        ) -> anyhow::Result<()> {
+            self.started_queues.lock().unwrap().push(idx);
+            Ok(())
+        }
+
+        async fn stop_queue(&mut self, idx: u16) -> Option<QueueState> {
+            self.started_queues.lock().unwrap().retain(|&x| x != idx);
+            None
+        }
+    }
+
+    /// vhost-user-net: the frontend owns the config space and control
+    /// queue, and a VQ_PAIRS_SET command enables the second queue pair on
+    /// the backend.
+    #[async_test]
+    async fn net_control_queue_pairs(driver: DefaultDriver) {
+        use crate::net::VhostUserNetConfig;
+        use crate::net::VhostUserNetDevice;
+
+        let started_queues = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (frontend_stream, backend_stream) = socket_pair();
+        let backend_socket =
+            VhostUserSocket::new(PolledSocket::new(&driver, backend_stream).unwrap());
+        let server = VhostUserDeviceServer::new(Box::new(NetMockDevice {
+            started_queues: started_queues.clone(),
+        }));
+        let backend_task = driver.spawn("backend", async move {
+            server.serve_connection(backend_socket).await.unwrap();
+        });
+
+        let frontend_socket =
+            VhostUserSocket::new(PolledSocket::new(&driver, frontend_stream).unwrap());
+        let guest_memory = ShareableGuestMemory::new(65536).into_guest_memory();
+        let vm_driver = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone())).simple();
+        let mac_address = [0x00, 0x15, 0x5d, 0x12, 0x34, 0x56];
+        let mut device = VhostUserNetDevice::new(
+            vm_driver,
+            frontend_socket,
+            VhostUserNetConfig {
+                mac_address,
+                queue_pairs: 2,
+                queue_size: 256,
+            },
+        )
+        .await
+        .expect("frontend handshake failed");
+
+        // Two queue pairs plus the control queue. The backend's CSUM and
+        // MRG_RXBUF are offered, but not CTRL_RX, which needs control queue
+        // support.
+        let traits = device.traits();
+        assert_eq!(traits.device_id, VirtioDeviceType::NET);
+        assert_eq!(traits.max_queues, 5);
+        assert_eq!(
+            traits.device_features.bank(0) & 0xffffff,
+            (1 << 0) | (1 << 5) | (1 << 15) | (1 << 16) | (1 << 17) | (1 << 22)
+        );
+        assert!(traits.device_features.ring_event_idx());
+        assert_eq!(device.queue_size(3), 256);
+        assert_eq!(device.queue_size(4), 64);
+
+        // The config space has the MAC address, link up, and two pairs.
+        assert_eq!(device.read_registers_u32(0).await, 0x125d1500);
+        assert_eq!(device.read_registers_u32(4).await, 0x0001_5634);
+        assert_eq!(device.read_registers_u32(8).await, 2);
+
+        // Only the first pair is enabled on the backend.
+        let features = traits.device_features;
+        for idx in 0..4 {
+            let resources =
+                dummy_queue_resources(Interrupt::from_event(Event::new()), guest_memory.clone());
+            device
+                .start_queue(idx, resources, &features, None)
+                .await
+                .expect("start_queue failed");
+        }
+        assert_eq!(*started_queues.lock().unwrap(), [0, 1]);
+
+        // Set up the control queue at 0x4000, with a VQ_PAIRS_SET command
+        // at 0x7000 and its ack at 0x7100.
+        let control_event = Event::new();
+        let control = QueueResources {
+            params: QueueParams {
+                size: 16,
+                enable: true,
+                desc_addr: 0x4000,
+                avail_addr: 0x5000,
+                used_addr: 0x6000,
+            },
+            notify: Interrupt::from_event(Event::new()),
+            event: control_event.clone(),
+            guest_memory: guest_memory.clone(),
+        };
+        device
+            .start_queue(
+                4,
+                control,
+                &VirtioDeviceFeatures::new().with_bank(0, 1 << 22),
+                None,
+            )
+            .await
+            .expect("start_queue failed");
+
+        // Class MQ (4), command VQ_PAIRS_SET (0), two pairs.
+        guest_memory.write_at(0x7000, &[4, 0, 2, 0]).unwrap();
+        guest_memory.write_at(0x7100, &[0xff]).unwrap();
+        // Descriptors: the command (NEXT), then the ack (WRITE).
+        let mut desc = Vec::new();
+        desc.extend_from_slice(&0x7000u64.to_le_bytes());
+        desc.extend_from_slice(&4u32.to_le_bytes());
+        desc.extend_from_slice(&1u16.to_le_bytes());
+        desc.extend_from_slice(&1u16.to_le_bytes());
+        desc.extend_from_slice(&0x7100u64.to_le_bytes());
+        desc.extend_from_slice(&1u32.to_le_bytes());
+        desc.extend_from_slice(&2u16.to_le_bytes());
+        desc.extend_from_slice(&0u16.to_le_bytes());
+        guest_memory.write_at(0x4000, &desc).unwrap();
+        // Avail ring: flags 0, idx 1, ring[0] = 0.
+        guest_memory.write_at(0x5000, &[0, 0, 1, 0, 0, 0]).unwrap();
+        control_event.signal();
+
+        let mut timer = pal_async::timer::PolledTimer::new(&driver);
+        let mut used = [0; 2];
+        for _ in 0..1000 {
+            guest_memory.read_at(0x6002, &mut used).unwrap();
+            if used == [1, 0] {
+                break;
+            }
+            timer.sleep(std::time::Duration::from_millis(1)).await;
+        }
+        assert_eq!(used, [1, 0], "control command was not completed");
+        let mut ack = [0];
+        guest_memory.read_at(0x7100, &mut ack).unwrap();
+        assert_eq!(ack[0], 0);
+        assert_eq!(*started_queues.lock().unwrap(), [0, 1, 2, 3]);
+
+        // Reset returns to a single pair.
+        for idx in 0..5 {
+            device.stop_queue(idx).await;
+        }
+        device.reset().await;
+        assert!(started_queues.lock().unwrap().is_empty());
+        for idx in 0..4 {
+            let resources =
+                dummy_queue_resources(Interrupt::from_event(Event::new()), guest_memory.clone());
+            device
+                .start_queue(idx, resources, &features, None)
+                .await
+                .expect("start_queue failed");
+        }
+        assert_eq!(*started_queues.lock().unwrap(), [0, 1]);
+
+        drop(device);
+        backend_task.await;
+    }
+
+    /// A mock device with a shared memory region, which maps a page into
+    /// the region as soon as it has one, then unmaps it.
+    struct ShmemMockDevice;
+
+    impl InspectMut for ShmemMockDevice {
+        fn inspect_mut(&mut self, _req: inspect::Request<'_>) {}
+    }
+
+    impl VirtioDevice for ShmemMockDevice {
+        fn traits(&self) -> DeviceTraits {
+            DeviceTraits {
+                device_id: VirtioDeviceType::PMEM,
+                device_features: VirtioDeviceFeatures::new(),
+                max_queues: 1,
+                device_register_length: 0,
+                shared_memory: DeviceTraitsSharedMemory {
+                    id: 2,
+                    size: 0x10000,
+                },
+            }
+        }
+
+        fn set_shared_memory_region(
+            &mut self,
+            region: &Arc<dyn MappedMemoryRegion>,
+        ) -> anyhow::Result<()> {
+            let fd = sparse_mmap::alloc_shared_memory(0x2000, "shmem-test")?;
+            region.map(0x3000, &fd, 0x1000, 0x1000, true)?;
+            region.unmap(0x3000, 0x1000)?;
+            Ok(())
+        }
+
+        async fn read_registers_u32(&mut self, _offset: u16) -> u32 {
+            0
+        }
+
+        async fn write_registers_u32(&mut self, _offset: u16, _val: u32) {}
+
+        async fn start_queue(
+            &mut self,
+            _idx: u16,
+            _resources: QueueResources,
+            _features: &VirtioDeviceFeatures,
+            _initial_state: Option<QueueState>,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn stop_queue(&mut self, _idx: u16) -> Option<QueueState> {
+            None
+        }
+    }
+
+    /// Records the mappings made in a shared memory region.
+    struct RecordingRegion(futures::channel::mpsc::UnboundedSender<(usize, u64, usize, bool)>);
+
+    impl MappedMemoryRegion for RecordingRegion {
+        fn map(
+            &self,
+            offset: usize,
+            _section: &dyn sparse_mmap::AsMappableRef,
+            file_offset: u64,
+            len: usize,
+            writable: bool,
+        ) -> std::io::Result<()> {
+            self.0
+                .unbounded_send((offset, file_offset, len, writable))
+                .unwrap();
+            Ok(())
+        }
+
+        fn unmap(&self, offset: usize, len: usize) -> std::io::Result<()> {
+            // Recorded as a zero file offset, read-only mapping.
+            self.0.unbounded_send((offset, 0, len, false)).unwrap();
+            Ok(())
+        }
+    }
+
+    /// The backend's shared memory region is reported in the traits, and
+    /// its mappings are forwarded to the frontend's region, including the
+    /// ones requested before the frontend had a region.
+    #[async_test]
+    async fn shared_memory_mappings(driver: DefaultDriver) {
+        use futures::StreamExt;
+
+        let (mut frontend, _guest_memory, backend_task) = setup_frontend_backend_with_config(
+            &driver,
+            ShmemMockDevice,
+            VhostUserConfig {
+                device_id: VirtioDeviceType::PMEM,
+                use_backend_config: true,
+                queue_sizes: vec![DEFAULT_QUEUE_SIZE],
+                config_patches: vec![],
+                queue_pairs: false,
+            },
+        )
+        .await;
+
+        let traits = frontend.traits();
+        assert_eq!(traits.shared_memory.id, 2);
+        assert_eq!(traits.shared_memory.size, 0x10000);
+
+        let (send, mut recv) = futures::channel::mpsc::unbounded();
+        let region: Arc<dyn MappedMemoryRegion> = Arc::new(RecordingRegion(send));
+        frontend.set_shared_memory_region(&region).unwrap();
+        drop(region);
+
+        assert_eq!(recv.next().await, Some((0x3000, 0x1000, 0x1000, true)));
+        assert_eq!(recv.next().await, Some((0x3000, 0, 0x1000, false)));
+
+        drop(frontend);
+        backend_task.await;
+    }
 }
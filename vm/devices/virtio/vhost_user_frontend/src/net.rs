@@ -0,0 +1,344 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! vhost-user-net: a virtio-net device whose data path is served by a
+//! vhost-user backend.
+//!
+//! As with QEMU's vhost-user-net, the backend only processes the receive and
+//! transmit queues. The frontend owns the config space (MAC address, link
+//! status, and queue pair count) and handles the control queue itself,
+//! turning `VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET` commands into
+//! `SET_VRING_ENABLE` requests so that the guest can change the number of
+//! active queue pairs.
+
+use crate::VhostUserConfig;
+use crate::VhostUserFrontend;
+use anyhow::Context as _;
+use futures::StreamExt;
+use futures::lock::Mutex;
+use guestmem::GuestMemory;
+use inspect::InspectMut;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
+use pal_async::wait::PolledWait;
+use std::sync::Arc;
+use vhost_user_protocol::VhostUserSocket;
+use virtio::DeviceTraits;
+use virtio::QueueResources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use virtio::VirtioQueueCallbackWork;
+use virtio::queue::QueueState;
+use virtio::spec::VirtioDeviceFeatures;
+use virtio::spec::VirtioDeviceType;
+use vmcore::vm_task::VmTaskDriver;
+
+// VIRTIO_NET_F_ feature bits handled by the frontend.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+const VIRTIO_NET_F_CTRL_VQ: u64 = 1 << 17;
+const VIRTIO_NET_F_MQ: u64 = 1 << 22;
+
+/// The VIRTIO_NET_F_ feature bits passed through from the backend: checksum
+/// and segmentation offloads (CSUM, GUEST_CSUM, GUEST_TSO4/6, GUEST_ECN,
+/// GUEST_UFO, HOST_TSO4/6, HOST_ECN, HOST_UFO, GUEST_USO4/6, HOST_USO) and
+/// mergeable receive buffers (MRG_RXBUF). Features that need config space
+/// fields or control commands are not offered, since the frontend does not
+/// implement them.
+const BACKEND_NET_FEATURES: u64 =
+    0b11 | (0xff << 7) | (1 << 15) | (1 << 54) | (1 << 55) | (1 << 56);
+
+/// Feature bits 24 through 49 are reserved for the transport and ring
+/// layout, and are passed through from the backend.
+const TRANSPORT_FEATURES: u64 = ((1 << 50) - 1) & !((1 << 24) - 1);
+
+/// VIRTIO_NET_S_LINK_UP.
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+// Control queue commands (virtio spec §5.1.6.5).
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+const VIRTIO_NET_OK: u8 = 0;
+const VIRTIO_NET_ERR: u8 = 1;
+
+/// The size of the frontend-owned control queue.
+const CONTROL_QUEUE_SIZE: u16 = 64;
+
+/// Configuration for a [`VhostUserNetDevice`].
+pub struct VhostUserNetConfig {
+    /// The MAC address reported to the guest.
+    pub mac_address: [u8; 6],
+    /// The number of receive/transmit queue pairs.
+    pub queue_pairs: u16,
+    /// The size of each receive and transmit queue.
+    pub queue_size: u16,
+}
+
+/// A virtio-net device backed by a vhost-user-net backend.
+#[derive(InspectMut)]
+#[inspect(skip)]
+pub struct VhostUserNetDevice {
+    driver: VmTaskDriver,
+    frontend: Arc<Mutex<VhostUserFrontend>>,
+    /// The frontend's traits, with the control queue and the frontend-owned
+    /// features added.
+    traits: DeviceTraits,
+    queue_pairs: u16,
+    queue_sizes: Vec<u16>,
+    control: Option<Task<()>>,
+}
+
+impl VhostUserNetDevice {
+    /// Performs the vhost-user handshake over `socket` and creates the
+    /// device.
+    pub async fn new(
+        driver: VmTaskDriver,
+        socket: VhostUserSocket,
+        config: VhostUserNetConfig,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            (1..=0x8000).contains(&config.queue_pairs),
+            "invalid queue pair count {}",
+            config.queue_pairs
+        );
+
+        // The config space: mac, status, max_virtqueue_pairs, and mtu
+        // (zero, since VIRTIO_NET_F_MTU is not offered).
+        let mut config_space = Vec::with_capacity(12);
+        config_space.extend_from_slice(&config.mac_address);
+        config_space.extend_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        config_space.extend_from_slice(&config.queue_pairs.to_le_bytes());
+        config_space.extend_from_slice(&0u16.to_le_bytes());
+
+        let queue_sizes = vec![config.queue_size; 2 * config.queue_pairs as usize];
+        let mut frontend = VhostUserFrontend::from_socket(
+            driver.clone(),
+            socket,
+            VhostUserConfig {
+                device_id: VirtioDeviceType::NET,
+                use_backend_config: false,
+                queue_sizes: queue_sizes.clone(),
+                config_patches: vec![(0, config_space)],
+                queue_pairs: true,
+            },
+        )
+        .await?;
+
+        let backend_features = frontend.traits().device_features.into_bits();
+        let mut features = (backend_features & (BACKEND_NET_FEATURES | TRANSPORT_FEATURES))
+            | VIRTIO_NET_F_MAC
+            | VIRTIO_NET_F_STATUS
+            | VIRTIO_NET_F_CTRL_VQ;
+        if config.queue_pairs > 1 {
+            features |= VIRTIO_NET_F_MQ;
+        }
+
+        // The device starts with a single active queue pair.
+        set_active_pairs(&mut frontend, config.queue_pairs, 1).await?;
+
+        let traits = DeviceTraits {
+            device_features: VirtioDeviceFeatures::from_bits(features),
+            max_queues: 2 * config.queue_pairs + 1,
+            ..frontend.traits()
+        };
+
+        Ok(Self {
+            driver,
+            frontend: Arc::new(Mutex::new(frontend)),
+            traits,
+            queue_pairs: config.queue_pairs,
+            queue_sizes,
+            control: None,
+        })
+    }
+
+    fn control_queue_index(&self) -> u16 {
+        2 * self.queue_pairs
+    }
+
+    /// Stops the control queue task, if it is running.
+    async fn stop_control(&mut self) {
+        if let Some(task) = self.control.take() {
+            // Hold the lock while cancelling so that the task is not
+            // cancelled in the middle of a request to the backend.
+            let _frontend = self.frontend.lock().await;
+            task.cancel().await;
+        }
+    }
+}
+
+impl VirtioDevice for VhostUserNetDevice {
+    fn traits(&self) -> DeviceTraits {
+        self.traits.clone()
+    }
+
+    fn queue_size(&self, queue_index: u16) -> u16 {
+        self.queue_sizes
+            .get(queue_index as usize)
+            .copied()
+            .unwrap_or(CONTROL_QUEUE_SIZE)
+    }
+
+    async fn read_registers_u32(&mut self, offset: u16) -> u32 {
+        self.frontend.lock().await.read_registers_u32(offset).await
+    }
+
+    async fn write_registers_u32(&mut self, _offset: u16, _val: u32) {}
+
+    async fn start_queue(
+        &mut self,
+        idx: u16,
+        resources: QueueResources,
+        features: &VirtioDeviceFeatures,
+        initial_state: Option<QueueState>,
+    ) -> anyhow::Result<()> {
+        if idx == self.control_queue_index() {
+            self.stop_control().await;
+            let queue_event = PolledWait::new(&self.driver, resources.event)
+                .context("failed creating queue event")?;
+            let queue = VirtioQueue::new(
+                *features,
+                resources.params,
+                resources.guest_memory.clone(),
+                resources.notify,
+                queue_event,
+                initial_state,
+            )
+            .context("failed creating control queue")?;
+            let control = ControlQueue {
+                queue,
+                mem: resources.guest_memory,
+                frontend: self.frontend.clone(),
+                queue_pairs: self.queue_pairs,
+                mq: features.into_bits() & VIRTIO_NET_F_MQ != 0,
+            };
+            self.control = Some(self.driver.spawn("vhost-user-net-control", control.run()));
+            return Ok(());
+        }
+
+        // Don't forward the features the frontend implements itself.
+        let features = VirtioDeviceFeatures::from_bits(
+            features.into_bits()
+                & !(VIRTIO_NET_F_MAC
+                    | VIRTIO_NET_F_STATUS
+                    | VIRTIO_NET_F_CTRL_VQ
+                    | VIRTIO_NET_F_MQ),
+        );
+        self.frontend
+            .lock()
+            .await
+            .start_queue(idx, resources, &features, initial_state)
+            .await
+    }
+
+    async fn stop_queue(&mut self, idx: u16) -> Option<QueueState> {
+        if idx == self.control_queue_index() {
+            self.stop_control().await;
+            return None;
+        }
+        self.frontend.lock().await.stop_queue(idx).await
+    }
+
+    async fn reset(&mut self) {
+        self.stop_control().await;
+        let mut frontend = self.frontend.lock().await;
+        frontend.reset().await;
+        if let Err(err) = set_active_pairs(&mut frontend, self.queue_pairs, 1).await {
+            tracelimit::warn_ratelimited!(
+                error = &*err as &dyn std::error::Error,
+                "failed to reset active queue pairs"
+            );
+        }
+    }
+
+    // Save/restore is left unsupported: the active queue pair count lives in
+    // the control queue state, which is not saved.
+}
+
+/// Enables the first `active` of `queue_pairs` queue pairs and disables the
+/// rest.
+async fn set_active_pairs(
+    frontend: &mut VhostUserFrontend,
+    queue_pairs: u16,
+    active: u16,
+) -> anyhow::Result<()> {
+    for idx in 0..2 * queue_pairs {
+        frontend.set_queue_enabled(idx, idx / 2 < active).await?;
+    }
+    Ok(())
+}
+
+/// The frontend-owned control queue.
+struct ControlQueue {
+    queue: VirtioQueue,
+    mem: GuestMemory,
+    frontend: Arc<Mutex<VhostUserFrontend>>,
+    queue_pairs: u16,
+    /// Whether VIRTIO_NET_F_MQ was negotiated.
+    mq: bool,
+}
+
+impl ControlQueue {
+    async fn run(mut self) {
+        while let Some(work) = self.queue.next().await {
+            let work = match work {
+                Ok(work) => work,
+                Err(err) => {
+                    tracing::error!(
+                        error = &err as &dyn std::error::Error,
+                        "failed to read control queue"
+                    );
+                    break;
+                }
+            };
+            let ack = match self.handle_command(&work).await {
+                Ok(()) => VIRTIO_NET_OK,
+                Err(err) => {
+                    tracelimit::warn_ratelimited!(
+                        error = &*err as &dyn std::error::Error,
+                        "control command failed"
+                    );
+                    VIRTIO_NET_ERR
+                }
+            };
+            let len = match work.write(&self.mem, &[ack]) {
+                Ok(()) => 1,
+                Err(err) => {
+                    tracelimit::warn_ratelimited!(
+                        error = &err as &dyn std::error::Error,
+                        "failed to write control command ack"
+                    );
+                    0
+                }
+            };
+            self.queue.complete(work, len);
+        }
+    }
+
+    async fn handle_command(&mut self, work: &VirtioQueueCallbackWork) -> anyhow::Result<()> {
+        // The command header and a queue pair count, the largest command
+        // supported.
+        let mut buf = [0; 4];
+        let len = work
+            .read(&self.mem, &mut buf)
+            .context("failed to read control command")?;
+        let (class, command) = match buf[..len] {
+            [class, command, ..] => (class, command),
+            _ => anyhow::bail!("truncated control command"),
+        };
+        match (class, command) {
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) if self.mq => {
+                anyhow::ensure!(len == 4, "truncated queue pair count");
+                let pairs = u16::from_le_bytes([buf[2], buf[3]]);
+                anyhow::ensure!(
+                    (1..=self.queue_pairs).contains(&pairs),
+                    "invalid queue pair count {pairs}"
+                );
+                tracing::debug!(pairs, "setting active queue pairs");
+                let mut frontend = self.frontend.lock().await;
+                set_active_pairs(&mut frontend, self.queue_pairs, pairs).await
+            }
+            _ => anyhow::bail!("unsupported control command {class}.{command}"),
+        }
+    }
+}
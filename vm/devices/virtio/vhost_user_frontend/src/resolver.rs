@@ -4,6 +4,8 @@
 //! Resource resolver for vhost-user frontend devices.
 
 use crate::VhostUserFrontend;
+use crate::net::VhostUserNetConfig;
+use crate::net::VhostUserNetDevice;
 use anyhow::Context as _;
 use async_trait::async_trait;
 use pal_async::socket::PolledSocket;
@@ -17,10 +19,12 @@
 use virtio_resources::vhost_user::VhostUserBlkHandle;
 use virtio_resources::vhost_user::VhostUserFsHandle;
 use virtio_resources::vhost_user::VhostUserGenericHandle;
+use virtio_resources::vhost_user::VhostUserNetHandle;
 use vm_resource::AsyncResolveResource;
 use vm_resource::ResourceResolver;
 use vm_resource::declare_static_async_resolver;
 use vm_resource::kind::VirtioDeviceHandle;
+use vmcore::vm_task::VmTaskDriver;
 use zerocopy::IntoBytes;
 
 /// Resolver for vhost-user frontend devices.
@@ -31,6 +35,15 @@
     (VirtioDeviceHandle, VhostUserGenericHandle),
     (VirtioDeviceHandle, VhostUserFsHandle),
     (VirtioDeviceHandle, VhostUserBlkHandle),
+    (VirtioDeviceHandle, VhostUserNetHandle),
+}
+
+/// Register a connected vhost-user socket fd with `driver`.
+fn connect_socket(driver: &VmTaskDriver, socket_fd: OwnedFd) -> anyhow::Result<VhostUserSocket> {
+    let stream = UnixStream::from(socket_fd);
+    let polled =
+        PolledSocket::new(driver, stream).context("failed to register vhost-user socket")?;
+    Ok(VhostUserSocket::new(polled))
 }
 
 /// Connect a vhost-user socket fd and create the frontend.
@@ -40,10 +53,7 @@ async fn connect_frontend(
     config: crate::VhostUserConfig,
 ) -> anyhow::Result<VhostUserFrontend> {
     let driver = input.driver_source.simple();
-    let stream = UnixStream::from(socket_fd);
-    let polled =
-        PolledSocket::new(&driver, stream).context("failed to register vhost-user socket")?;
-    let socket = VhostUserSocket::new(polled);
+    let socket = connect_socket(&driver, socket_fd)?;
 
     VhostUserFrontend::from_socket(driver, socket, config)
         .await
@@ -72,6 +82,7 @@ async fn resolve(
             use_backend_config: true,
             queue_sizes: resource.queue_sizes,
             config_patches: vec![],
+            queue_pairs: false,
         };
         let frontend = connect_frontend(input, resource.socket, config).await?;
         Ok(frontend.into())
@@ -116,6 +127,7 @@ async fn resolve(
             use_backend_config: false,
             queue_sizes,
             config_patches: vec![(0, config.as_bytes().to_vec())],
+            queue_pairs: false,
         };
 
         let frontend = connect_frontend(input, resource.socket, vhost_config)
@@ -153,6 +165,7 @@ async fn resolve(
             use_backend_config: true,
             queue_sizes,
             config_patches,
+            queue_pairs: false,
         };
 
         let frontend = connect_frontend(input, resource.socket, config)
@@ -162,3 +175,30 @@ async fn resolve(
         Ok(frontend.into())
     }
 }
+
+#[async_trait]
+impl AsyncResolveResource<VirtioDeviceHandle, VhostUserNetHandle> for VhostUserFrontendResolver {
+    type Output = ResolvedVirtioDevice;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        _resolver: &ResourceResolver,
+        resource: VhostUserNetHandle,
+        input: VirtioResolveInput<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let driver = input.driver_source.simple();
+        let socket = connect_socket(&driver, resource.socket)?;
+        let config = VhostUserNetConfig {
+            mac_address: resource.mac_address.into(),
+            queue_pairs: resource.queue_pairs.unwrap_or(1),
+            queue_size: resource.queue_size.unwrap_or(256),
+        };
+
+        let device = VhostUserNetDevice::new(driver, socket, config)
+            .await
+            .context("failed to set up vhost-user-net device")?;
+
+        Ok(device.into())
+    }
+}
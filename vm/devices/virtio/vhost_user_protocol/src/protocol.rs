@@ -74,6 +74,25 @@ pub enum VhostUserRequestCode: u32 {
         GET_SHARED_OBJECT = 41,
         SET_DEVICE_STATE_FD = 42,
         CHECK_DEVICE_STATE = 43,
+        GET_SHMEM_CONFIG = 44,
+    }
+}
+
+open_enum! {
+    /// vhost-user backend-to-frontend request types, sent on the channel
+    /// established by SET_BACKEND_REQ_FD.
+    pub enum VhostUserBackendRequestCode: u32 {
+        NONE = 0,
+        IOTLB_MSG = 1,
+        CONFIG_CHANGE_MSG = 2,
+        VRING_HOST_NOTIFIER_MSG = 3,
+        VRING_CALL = 4,
+        VRING_ERR = 5,
+        SHARED_OBJECT_ADD = 6,
+        SHARED_OBJECT_REMOVE = 7,
+        SHARED_OBJECT_LOOKUP = 8,
+        SHMEM_MAP = 9,
+        SHMEM_UNMAP = 10,
     }
 }
 
@@ -172,6 +191,35 @@ pub struct VhostUserU64Msg {
     pub value: u64,
 }
 
+/// Maximum number of shared memory regions in a GET_SHMEM_CONFIG reply.
+pub const VHOST_USER_MAX_SHMEM_REGIONS: usize = 256;
+
+/// Reply payload for GET_SHMEM_CONFIG: the size of each shared memory
+/// region, indexed by shmid.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct VhostUserShmemConfig {
+    pub nregions: u32,
+    pub padding: u32,
+    pub memory_sizes: [u64; VHOST_USER_MAX_SHMEM_REGIONS],
+}
+
+/// SHMEM_MAP flag: map the range writable.
+pub const VHOST_USER_FLAG_MAP_RW: u64 = 0x1;
+
+/// Payload for the backend's SHMEM_MAP and SHMEM_UNMAP requests. The fd to
+/// map is passed in ancillary data.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct VhostUserMMap {
+    pub shmid: u8,
+    pub padding: [u8; 7],
+    pub fd_offset: u64,
+    pub shm_offset: u64,
+    pub len: u64,
+    pub flags: u64,
+}
+
 /// Protocol feature flags negotiated via GET/SET_PROTOCOL_FEATURES.
 #[bitfield(u64)]
 pub struct VhostUserProtocolFeatures {
@@ -196,7 +244,8 @@ pub struct VhostUserProtocolFeatures {
     pub shared_object: bool,
     pub device_state: bool,
     pub get_vring_base_inflight: bool,
-    #[bits(43)]
+    pub shmem: bool,
+    #[bits(42)]
     _reserved: u64,
 }
 
@@ -230,6 +279,12 @@ fn config_header_size() {
         assert_eq!(size_of::<VhostUserConfigHeader>(), 12);
     }
 
+    #[test]
+    fn shmem_sizes() {
+        assert_eq!(size_of::<VhostUserShmemConfig>(), 8 + 8 * 256);
+        assert_eq!(size_of::<VhostUserMMap>(), 40);
+    }
+
     #[test]
     fn u64_msg_size() {
         assert_eq!(size_of::<VhostUserU64Msg>(), 8);
@@ -271,6 +326,12 @@ fn protocol_features() {
         assert!(pf.mq());
         assert!(pf.config());
         assert!(!pf.rarp());
+        assert_eq!(
+            VhostUserProtocolFeatures::new()
+                .with_shmem(true)
+                .into_bits(),
+            1 << 21
+        );
     }
 
     #[test]
@@ -14,6 +14,8 @@
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
 
 /// Per-queue virtio device trait. Ergonomic async fn — not object-safe.
 ///
@@ -105,6 +107,18 @@ fn reset(&mut self) -> impl Future<Output = ()> + Send {
         async {}
     }
 
+    /// Poll for a change to the device-specific config space.
+    ///
+    /// Returns `Poll::Ready(())` once for each change the device wants the
+    /// guest to observe (e.g. a virtio-blk capacity change). The transport
+    /// bumps `config_generation` and raises a config-change interrupt.
+    ///
+    /// Default: never signals a change.
+    fn poll_config_change(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let _ = cx;
+        Poll::Pending
+    }
+
     /// Whether the device supports save/restore.
     ///
     /// Devices that return `false` will cause the transport's `save()` to
@@ -164,6 +178,9 @@ fn stop_queue(
     /// Reset device-internal state.
     fn reset(&mut self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
 
+    /// Poll for a change to the device-specific config space.
+    fn poll_config_change(&mut self, cx: &mut Context<'_>) -> Poll<()>;
+
     /// Whether the device supports save/restore.
     fn supports_save_restore(&self) -> bool;
 }
@@ -226,6 +243,10 @@ fn reset(&mut self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
         Box::pin(VirtioDevice::reset(self))
     }
 
+    fn poll_config_change(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        VirtioDevice::poll_config_change(self, cx)
+    }
+
     fn supports_save_restore(&self) -> bool {
         VirtioDevice::supports_save_restore(self)
     }
@@ -5351,3 +5351,88 @@ fn write_packed_desc(
         "used wrap counter must toggle after a full-ring lap"
     );
 }
+
+/// A minimal VirtioDevice that reports a config change whenever a message
+/// arrives on `changes`.
+#[derive(InspectMut)]
+#[inspect(skip)]
+struct ConfigChangeTestDevice {
+    changes: mesh::Receiver<()>,
+}
+
+impl VirtioDevice for ConfigChangeTestDevice {
+    fn traits(&self) -> DeviceTraits {
+        DeviceTraits {
+            device_id: VirtioDeviceType::BLK,
+            max_queues: 1,
+            device_register_length: 4,
+            ..Default::default()
+        }
+    }
+
+    async fn read_registers_u32(&mut self, _offset: u16) -> u32 {
+        0
+    }
+
+    async fn write_registers_u32(&mut self, _offset: u16, _val: u32) {}
+
+    async fn start_queue(
+        &mut self,
+        _idx: u16,
+        _resources: QueueResources,
+        _features: &VirtioDeviceFeatures,
+        _initial_state: Option<QueueState>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn stop_queue(&mut self, _idx: u16) -> Option<QueueState> {
+        None
+    }
+
+    fn poll_config_change(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<()> {
+        self.changes.poll_next_unpin(cx).map(|_| ())
+    }
+}
+
+/// A device-initiated config change bumps `config_generation` and raises
+/// the config-change interrupt once the driver is running.
+#[async_test]
+async fn verify_device_config_change(driver: DefaultDriver) {
+    let test_mem = VirtioTestMemoryAccess::new();
+    let mem = GuestMemory::new("test", test_mem);
+    let target = TestLineInterruptTarget::new_arc();
+    let interrupt = LineInterrupt::new_with_target("test", target.clone(), 0);
+    let (send, changes) = mesh::channel();
+
+    let mut dev = VirtioMmioDevice::new(
+        Box::new(ConfigChangeTestDevice { changes }),
+        &driver,
+        mem,
+        interrupt,
+        None,
+        0,
+        1,
+    )
+    .unwrap();
+
+    // Bring the device up with no queues enabled.
+    let status = VIRTIO_ACKNOWLEDGE | VIRTIO_DRIVER | VIRTIO_FEATURES_OK;
+    dev.write_u32(112, status);
+    dev.write_u32(112, status | VIRTIO_DRIVER_OK);
+    while !target.is_high(0) {
+        yield_and_poll_device(&mut dev).await;
+    }
+    let v = take_mmio_interrupt_status(&mut dev, VIRTIO_MMIO_INTERRUPT_STATUS_CONFIG_CHANGE);
+    assert_ne!(v & VIRTIO_MMIO_INTERRUPT_STATUS_CONFIG_CHANGE, 0);
+    assert!(!target.is_high(0));
+    let generation = dev.read_u32(0xfc);
+
+    send.send(());
+    while !target.is_high(0) {
+        yield_and_poll_device(&mut dev).await;
+    }
+    let v = take_mmio_interrupt_status(&mut dev, VIRTIO_MMIO_INTERRUPT_STATUS_CONFIG_CHANGE);
+    assert_ne!(v & VIRTIO_MMIO_INTERRUPT_STATUS_CONFIG_CHANGE, 0);
+    assert_eq!(dev.read_u32(0xfc), generation.wrapping_add(1));
+}
@@ -17,6 +17,7 @@
 use crate::spec::VirtioDeviceFeatures;
 use crate::spec::VirtioDeviceStatus;
 use chipset_device::io::deferred::DeferredWrite;
+use futures::StreamExt;
 use guestmem::DoorbellRegistration;
 use guestmem::GuestMemory;
 use inspect::Inspect;
@@ -93,6 +94,8 @@ pub(crate) struct VirtioTransportCore {
     pub pending_status_deferred: Option<DeferredWrite>,
     #[inspect(with = "Vec::len")]
     pub stalled_io: Vec<StalledIo>,
+    #[inspect(skip)]
+    pub config_changed: mesh::Receiver<()>,
 }
 
 impl VirtioTransportCore {
@@ -131,8 +134,9 @@ pub fn new(
         let supports_save_restore = device.supports_save_restore();
 
         let (sender, receiver) = mesh::channel();
+        let (config_changed_send, config_changed) = mesh::channel();
         let _device_task = driver.spawn("virtio-device-task", async move {
-            run_device_task(device, receiver).await;
+            run_device_task(device, receiver, config_changed_send).await;
         });
 
         Ok(Self {
@@ -153,6 +157,7 @@ pub fn new(
             guest_memory,
             pending_status_deferred: None,
             stalled_io: Vec::new(),
+            config_changed,
         })
     }
 
@@ -190,6 +195,7 @@ pub fn reset_status(&mut self, ops: &mut dyn TransportOps) {
             device_feature: _,
             supports_save_restore: _,
             guest_memory: _,
+            config_changed: _,
 
             // Async state machine — not owned by reset_status.
             state: _,
@@ -346,6 +352,10 @@ pub fn write_device_status(&mut self, ops: &mut dyn TransportOps, val: u8) {
     pub fn poll_device(&mut self, ops: &mut dyn TransportOps, cx: &mut std::task::Context<'_>) {
         self.poll_waker = Some(cx.waker().clone());
 
+        while let Poll::Ready(Some(())) = self.config_changed.poll_next_unpin(cx) {
+            self.update_config_generation(ops);
+        }
+
         if let Poll::Ready(result) = self.state.poll(cx) {
             // Complete the deferred STATUS write before applying the
             // result, since apply_transport_result may call reset_status
@@ -23,6 +23,7 @@
 use mesh::rpc::PendingRpc;
 use mesh::rpc::Rpc;
 use mesh::rpc::RpcSend;
+use std::future::poll_fn;
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
@@ -252,16 +253,46 @@ async fn stop_all_queues(&mut self) {
 }
 
 /// Runs the device task, processing commands from the transport.
+///
+/// Device config changes reported by
+/// [`DynVirtioDevice::poll_config_change`] are forwarded to the transport
+/// via `config_changed`.
 pub async fn run_device_task(
     device: Box<dyn DynVirtioDevice>,
     mut recv: mesh::Receiver<DeviceCommand>,
+    config_changed: mesh::Sender<()>,
 ) {
     let mut task = DeviceTask {
         max_queues: device.traits().max_queues,
         device,
     };
 
-    while let Some(cmd) = recv.next().await {
+    loop {
+        enum Event {
+            Command(Option<DeviceCommand>),
+            ConfigChange,
+        }
+
+        let event = poll_fn(|cx| {
+            if let Poll::Ready(cmd) = recv.poll_next_unpin(cx) {
+                return Poll::Ready(Event::Command(cmd));
+            }
+            if task.device.poll_config_change(cx).is_ready() {
+                return Poll::Ready(Event::ConfigChange);
+            }
+            Poll::Pending
+        })
+        .await;
+
+        let cmd = match event {
+            Event::Command(Some(cmd)) => cmd,
+            Event::Command(None) => break,
+            Event::ConfigChange => {
+                config_changed.send(());
+                continue;
+            }
+        };
+
         match cmd {
             DeviceCommand::Enable(rpc) => {
                 rpc.handle(async |params| task.enable(params).await).await;
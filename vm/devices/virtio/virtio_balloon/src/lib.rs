@@ -0,0 +1,747 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Virtio memory balloon device implementation.
+//!
+//! Implements the virtio-balloon device (device ID 5) as specified in the
+//! VIRTIO 1.2 specification, §5.5 "Traditional Memory Balloon Device". The
+//! host sets a target balloon size, and the guest inflates the balloon by
+//! handing pages to the device, which releases their host backing. The device
+//! also collects guest memory statistics and releases the host backing of
+//! pages the guest reports as free via free page reporting.
+
+#![expect(missing_docs)]
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+
+use anyhow::Context as _;
+use futures::StreamExt;
+use guestmem::GuestMemory;
+use inspect::Inspect;
+use inspect::InspectMut;
+use inspect_counters::Counter;
+use memory_range::MemoryRange;
+use pal_async::timer::PolledTimer;
+use pal_async::wait::PolledWait;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use task_control::AsyncRun;
+use task_control::Cancelled;
+use task_control::InspectTaskMut;
+use task_control::StopTask;
+use task_control::TaskControl;
+use virtio::DeviceTraits;
+use virtio::DeviceTraitsSharedMemory;
+use virtio::QueueResources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use virtio::VirtioQueueCallbackWork;
+use virtio::queue::QueueState;
+use virtio::spec::VirtioDeviceFeatures;
+use virtio::spec::balloon::*;
+use virtio_resources::balloon::BalloonRequest;
+use virtio_resources::balloon::DiscardRam;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+/// The number of queues: inflate, deflate, stats, and free page reporting.
+const MAX_QUEUES: u16 = 4;
+
+/// The size of a balloon page.
+const PAGE_SIZE: u64 = 1 << VIRTIO_BALLOON_PFN_SHIFT;
+
+/// Maximum PFNs to process per inflate or deflate request, to prevent a
+/// malicious guest from causing unbounded host memory allocation. Linux sends
+/// at most 256 per request.
+const MAX_PFNS_PER_REQUEST: usize = 4096;
+
+/// Maximum number of statistics to read from a stats buffer.
+const MAX_STATS: usize = 64;
+
+/// How long to hold the guest's stats buffer before asking for an update.
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The virtio-balloon device.
+pub struct VirtioBalloonDevice {
+    driver: VmTaskDriver,
+    workers: Vec<TaskControl<BalloonWorker, BalloonQueue>>,
+    deflate_on_oom: bool,
+    config: Config,
+    requests: mesh::Receiver<BalloonRequest>,
+}
+
+impl InspectMut for VirtioBalloonDevice {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        let target_pages = self.config.num_pages.get();
+        let actual_pages = self.config.actual.get();
+        req.respond()
+            .field("deflate_on_oom", self.deflate_on_oom)
+            .field("target_pages", target_pages)
+            .field("actual_pages", actual_pages)
+            .field("target_bytes", target_pages as u64 * PAGE_SIZE)
+            .field("actual_bytes", actual_pages as u64 * PAGE_SIZE)
+            .fields_mut("queues", self.workers.iter_mut().enumerate());
+    }
+}
+
+impl VirtioBalloonDevice {
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        discard: Arc<dyn DiscardRam>,
+        deflate_on_oom: bool,
+        requests: mesh::Receiver<BalloonRequest>,
+    ) -> Self {
+        Self {
+            driver: driver_source.simple(),
+            workers: (0..MAX_QUEUES)
+                .map(|_| {
+                    TaskControl::new(BalloonWorker {
+                        discard: discard.clone(),
+                        stats: Default::default(),
+                        guest_stats: Default::default(),
+                    })
+                })
+                .collect(),
+            deflate_on_oom,
+            config: Config::new_zeroed(),
+            requests,
+        }
+    }
+
+    fn set_target(&mut self, bytes: u64) {
+        let pages = (bytes / PAGE_SIZE).try_into().unwrap_or(u32::MAX);
+        tracing::info!(bytes, pages, "setting balloon target");
+        self.config.num_pages = pages.into();
+    }
+}
+
+/// The role of a virtqueue, which depends on its index and the negotiated
+/// features since optional queues are only present when negotiated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Inspect)]
+enum QueueKind {
+    Inflate,
+    Deflate,
+    Stats,
+    Reporting,
+}
+
+fn queue_kind(idx: u16, features: &VirtioDeviceFeatures) -> Option<QueueKind> {
+    let device_features = features.device_specific_low();
+    [
+        Some(QueueKind::Inflate),
+        Some(QueueKind::Deflate),
+        (device_features & VIRTIO_BALLOON_F_STATS_VQ != 0).then_some(QueueKind::Stats),
+        (device_features & VIRTIO_BALLOON_F_REPORTING != 0).then_some(QueueKind::Reporting),
+    ]
+    .into_iter()
+    .flatten()
+    .nth(idx.into())
+}
+
+impl VirtioDevice for VirtioBalloonDevice {
+    fn traits(&self) -> DeviceTraits {
+        let mut features = VIRTIO_BALLOON_F_STATS_VQ | VIRTIO_BALLOON_F_REPORTING;
+        if self.deflate_on_oom {
+            features |= VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+
+        DeviceTraits {
+            device_id: virtio::spec::VirtioDeviceType::BALLOON,
+            device_features: VirtioDeviceFeatures::new()
+                .with_device_specific_low(features)
+                .with_ring_event_idx(true)
+                .with_ring_indirect_desc(true)
+                .with_ring_packed(true),
+            max_queues: MAX_QUEUES,
+            device_register_length: size_of::<Config>() as u32,
+            shared_memory: DeviceTraitsSharedMemory::default(),
+        }
+    }
+
+    async fn read_registers_u32(&mut self, offset: u16) -> u32 {
+        let offset = offset as usize;
+        self.config
+            .as_bytes()
+            .get(offset..offset + 4)
+            .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    async fn write_registers_u32(&mut self, offset: u16, val: u32) {
+        // Only `actual` is writable by the driver.
+        if offset as usize == std::mem::offset_of!(Config, actual) {
+            tracing::debug!(pages = val, "guest balloon size changed");
+            self.config.actual = val.into();
+        }
+    }
+
+    async fn start_queue(
+        &mut self,
+        idx: u16,
+        resources: QueueResources,
+        features: &VirtioDeviceFeatures,
+        initial_state: Option<QueueState>,
+    ) -> anyhow::Result<()> {
+        let kind = queue_kind(idx, features).context("queue not present")?;
+
+        let queue_event = PolledWait::new(&self.driver, resources.event)
+            .context("failed to create polled wait")?;
+        let queue = VirtioQueue::new(
+            *features,
+            resources.params,
+            resources.guest_memory.clone(),
+            resources.notify,
+            queue_event,
+            initial_state,
+        )
+        .context("failed to create virtio queue")?;
+
+        let worker = &mut self.workers[idx as usize];
+        worker.insert(
+            self.driver.clone(),
+            "virtio-balloon-queue",
+            BalloonQueue {
+                kind,
+                queue,
+                mem: resources.guest_memory,
+                timer: PolledTimer::new(&self.driver),
+                pending_stats: None,
+            },
+        );
+        worker.start();
+        Ok(())
+    }
+
+    async fn stop_queue(&mut self, idx: u16) -> Option<QueueState> {
+        let worker = &mut self.workers[idx as usize];
+        if !worker.has_state() {
+            return None;
+        }
+        worker.stop().await;
+        // Any held stats buffer is dropped rather than completed, since the
+        // guest may already be tearing down the queue.
+        let state = worker.remove().queue.queue_state();
+        Some(state)
+    }
+
+    async fn reset(&mut self) {
+        self.config.actual = 0.into();
+    }
+
+    fn poll_config_change(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut changed = false;
+        while let Poll::Ready(Some(req)) = self.requests.poll_next_unpin(cx) {
+            match req {
+                BalloonRequest::SetTarget(rpc) => {
+                    let (bytes, rpc) = rpc.split();
+                    self.set_target(bytes);
+                    rpc.complete(());
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Persistent worker state, which survives across queue restarts.
+#[derive(InspectMut)]
+struct BalloonWorker {
+    #[inspect(skip)]
+    discard: Arc<dyn DiscardRam>,
+    stats: WorkerStats,
+    guest_stats: GuestStats,
+}
+
+#[derive(Inspect, Default)]
+struct WorkerStats {
+    inflated_pages: Counter,
+    deflated_pages: Counter,
+    reported_pages: Counter,
+    stats_updates: Counter,
+    discard_errors: Counter,
+}
+
+/// The memory statistics most recently reported by the guest, in bytes or
+/// event counts as defined by the spec.
+#[derive(Inspect, Default)]
+struct GuestStats {
+    swap_in: Option<u64>,
+    swap_out: Option<u64>,
+    major_faults: Option<u64>,
+    minor_faults: Option<u64>,
+    free_memory: Option<u64>,
+    total_memory: Option<u64>,
+    available_memory: Option<u64>,
+    disk_caches: Option<u64>,
+    hugetlb_allocations: Option<u64>,
+    hugetlb_failures: Option<u64>,
+}
+
+impl GuestStats {
+    /// Updates the statistics from a stats buffer. Unknown tags are ignored.
+    fn update(&mut self, buf: &[u8]) {
+        for stat in buf.chunks_exact(size_of::<Stat>()) {
+            let stat = Stat::read_from_bytes(stat).unwrap();
+            let field = match stat.tag.get() {
+                VIRTIO_BALLOON_S_SWAP_IN => &mut self.swap_in,
+                VIRTIO_BALLOON_S_SWAP_OUT => &mut self.swap_out,
+                VIRTIO_BALLOON_S_MAJFLT => &mut self.major_faults,
+                VIRTIO_BALLOON_S_MINFLT => &mut self.minor_faults,
+                VIRTIO_BALLOON_S_MEMFREE => &mut self.free_memory,
+                VIRTIO_BALLOON_S_MEMTOT => &mut self.total_memory,
+                VIRTIO_BALLOON_S_AVAIL => &mut self.available_memory,
+                VIRTIO_BALLOON_S_CACHES => &mut self.disk_caches,
+                VIRTIO_BALLOON_S_HTLB_PGALLOC => &mut self.hugetlb_allocations,
+                VIRTIO_BALLOON_S_HTLB_PGFAIL => &mut self.hugetlb_failures,
+                _ => continue,
+            };
+            *field = Some(stat.val.get());
+        }
+    }
+}
+
+/// Transient queue state, created in `start_queue` and removed in
+/// `stop_queue`.
+#[derive(InspectMut)]
+struct BalloonQueue {
+    kind: QueueKind,
+    queue: VirtioQueue,
+    mem: GuestMemory,
+    #[inspect(skip)]
+    timer: PolledTimer,
+    /// The stats buffer held by the device until the next update is wanted.
+    #[inspect(with = "Option::is_some")]
+    pending_stats: Option<VirtioQueueCallbackWork>,
+}
+
+impl InspectTaskMut<BalloonQueue> for BalloonWorker {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>, state: Option<&mut BalloonQueue>) {
+        req.respond().merge(self).merge(state);
+    }
+}
+
+impl AsyncRun<BalloonQueue> for BalloonWorker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut BalloonQueue,
+    ) -> Result<(), Cancelled> {
+        loop {
+            if state.pending_stats.is_some() {
+                // Returning the held buffer asks the guest for fresh
+                // statistics.
+                stop.until_stopped(state.timer.sleep(STATS_INTERVAL))
+                    .await?;
+                let work = state.pending_stats.take().unwrap();
+                state.queue.complete(work, 0);
+            }
+            let work = stop.until_stopped(state.queue.next()).await?;
+            let Some(work) = work else { break };
+            match work {
+                Ok(work) => self.process(state, work),
+                Err(err) => {
+                    tracelimit::error_ratelimited!(
+                        err = &err as &dyn std::error::Error,
+                        "queue error"
+                    );
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BalloonWorker {
+    fn process(&mut self, state: &mut BalloonQueue, work: VirtioQueueCallbackWork) {
+        match state.kind {
+            QueueKind::Inflate => {
+                let pfns = read_pfns(&state.mem, &work);
+                self.stats.inflated_pages.add(pfns.len() as u64);
+                for range in pfn_ranges(&pfns) {
+                    self.discard(range);
+                }
+            }
+            QueueKind::Deflate => {
+                // The pages are backed again on the next access, so there is
+                // nothing to do beyond accounting.
+                let pfns = read_pfns(&state.mem, &work);
+                self.stats.deflated_pages.add(pfns.len() as u64);
+            }
+            QueueKind::Stats => {
+                let len =
+                    (work.get_payload_length(false) as usize).min(MAX_STATS * size_of::<Stat>());
+                let mut buf = vec![0; len];
+                match work.read(&state.mem, &mut buf) {
+                    Ok(_) => {
+                        self.guest_stats.update(&buf);
+                        self.stats.stats_updates.increment();
+                    }
+                    Err(err) => {
+                        tracelimit::error_ratelimited!(
+                            err = &err as &dyn std::error::Error,
+                            "failed to read balloon stats"
+                        );
+                    }
+                }
+                // Hold the buffer until the next update is wanted.
+                state.pending_stats = Some(work);
+                return;
+            }
+            QueueKind::Reporting => {
+                for payload in &work.payload {
+                    let range = MemoryRange::try_new(
+                        payload.address..payload.address.wrapping_add(payload.length.into()),
+                    );
+                    match range {
+                        Ok(range) if range.is_empty() => {}
+                        Ok(range) => {
+                            self.stats.reported_pages.add(range.len() / PAGE_SIZE);
+                            self.discard(range);
+                        }
+                        Err(err) => {
+                            tracelimit::warn_ratelimited!(
+                                error = &err as &dyn std::error::Error,
+                                "invalid free page report"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        state.queue.complete(work, 0);
+    }
+
+    fn discard(&mut self, range: MemoryRange) {
+        if let Err(err) = self.discard.discard_ram(range) {
+            self.stats.discard_errors.increment();
+            tracelimit::warn_ratelimited!(
+                error = &err as &dyn std::error::Error,
+                %range,
+                "failed to discard balloon pages"
+            );
+        }
+    }
+}
+
+/// Reads the PFN array from an inflate or deflate request.
+fn read_pfns(mem: &GuestMemory, work: &VirtioQueueCallbackWork) -> Vec<u32> {
+    let len = (work.get_payload_length(false) as usize / 4).min(MAX_PFNS_PER_REQUEST);
+    let mut pfns = vec![0u32; len];
+    if let Err(err) = work.read(mem, pfns.as_mut_bytes()) {
+        tracelimit::error_ratelimited!(
+            err = &err as &dyn std::error::Error,
+            "failed to read balloon PFNs"
+        );
+        return Vec::new();
+    }
+    for pfn in &mut pfns {
+        *pfn = u32::from_le(*pfn);
+    }
+    pfns
+}
+
+/// Coalesces runs of consecutive PFNs into memory ranges.
+fn pfn_ranges(pfns: &[u32]) -> Vec<MemoryRange> {
+    let mut ranges: Vec<std::ops::Range<u64>> = Vec::new();
+    for &pfn in pfns {
+        let pfn = pfn as u64;
+        match ranges.last_mut() {
+            Some(last) if last.end == pfn => last.end += 1,
+            _ => ranges.push(pfn..pfn + 1),
+        }
+    }
+    ranges
+        .into_iter()
+        .map(MemoryRange::from_4k_gpn_range)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mesh::rpc::RpcSend;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use pal_event::Event;
+    use parking_lot::Mutex;
+    use test_with_tracing::test;
+    use virtio::queue::QueueParams;
+    use virtio::spec::queue::DescriptorFlags;
+    use virtio::test_helpers::init_avail_ring;
+    use virtio::test_helpers::init_used_ring;
+    use virtio::test_helpers::make_available;
+    use virtio::test_helpers::wait_for_used;
+    use virtio::test_helpers::write_descriptor;
+    use vmcore::interrupt::Interrupt;
+    use vmcore::vm_task::SingleDriverBackend;
+
+    const QUEUE_SIZE: u16 = 16;
+    const QUEUE_BASE: u64 = 0x10000;
+    const QUEUE_STRIDE: u64 = 0x3000;
+    const DATA_BASE: u64 = 0x100000;
+    const TOTAL_MEM_SIZE: usize = 0x200000;
+
+    #[derive(Default)]
+    struct MockDiscard {
+        ranges: Mutex<Vec<MemoryRange>>,
+    }
+
+    impl DiscardRam for MockDiscard {
+        fn discard_ram(&self, range: MemoryRange) -> std::io::Result<()> {
+            self.ranges.lock().push(range);
+            Ok(())
+        }
+    }
+
+    struct TestQueue {
+        desc_addr: u64,
+        avail_addr: u64,
+        used_addr: u64,
+        queue_event: Event,
+        interrupt_event: Event,
+        avail_idx: u16,
+        used_idx: u16,
+    }
+
+    struct TestHarness {
+        device: VirtioBalloonDevice,
+        mem: GuestMemory,
+        driver: DefaultDriver,
+        discard: Arc<MockDiscard>,
+        requests: mesh::Sender<BalloonRequest>,
+        queues: Vec<TestQueue>,
+    }
+
+    impl TestHarness {
+        fn new(driver: &DefaultDriver) -> Self {
+            let mem = GuestMemory::allocate(TOTAL_MEM_SIZE);
+            let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
+            let discard = Arc::new(MockDiscard::default());
+            let (requests, recv) = mesh::channel();
+            let device = VirtioBalloonDevice::new(&driver_source, discard.clone(), false, recv);
+            let queues = (0..MAX_QUEUES as u64)
+                .map(|i| {
+                    let desc_addr = QUEUE_BASE + i * QUEUE_STRIDE;
+                    let queue = TestQueue {
+                        desc_addr,
+                        avail_addr: desc_addr + 0x1000,
+                        used_addr: desc_addr + 0x2000,
+                        queue_event: Event::new(),
+                        interrupt_event: Event::new(),
+                        avail_idx: 0,
+                        used_idx: 0,
+                    };
+                    init_avail_ring(&mem, queue.avail_addr);
+                    init_used_ring(&mem, queue.used_addr);
+                    queue
+                })
+                .collect();
+
+            Self {
+                device,
+                mem,
+                driver: driver.clone(),
+                discard,
+                requests,
+                queues,
+            }
+        }
+
+        async fn start_queue(&mut self, idx: u16, features: &VirtioDeviceFeatures) {
+            let queue = &self.queues[idx as usize];
+            self.device
+                .start_queue(
+                    idx,
+                    QueueResources {
+                        params: QueueParams {
+                            size: QUEUE_SIZE,
+                            enable: true,
+                            desc_addr: queue.desc_addr,
+                            avail_addr: queue.avail_addr,
+                            used_addr: queue.used_addr,
+                        },
+                        notify: Interrupt::from_event(queue.interrupt_event.clone()),
+                        event: queue.queue_event.clone(),
+                        guest_memory: self.mem.clone(),
+                    },
+                    features,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        /// Submits a chain of buffers on a queue and waits for completion.
+        async fn submit_and_wait(&mut self, idx: u16, buffers: &[(u64, u32, bool)]) -> (u16, u32) {
+            let queue = &mut self.queues[idx as usize];
+            for (i, &(addr, len, write)) in buffers.iter().enumerate() {
+                let next = i + 1 < buffers.len();
+                let flags = DescriptorFlags::new().with_write(write).with_next(next);
+                write_descriptor(
+                    &self.mem,
+                    queue.desc_addr,
+                    i as u16,
+                    addr,
+                    len,
+                    flags,
+                    if next { i as u16 + 1 } else { 0 },
+                );
+            }
+            make_available(
+                &self.mem,
+                queue.avail_addr,
+                QUEUE_SIZE,
+                0,
+                &mut queue.avail_idx,
+            );
+            queue.queue_event.signal();
+            wait_for_used(
+                &self.driver,
+                &queue.interrupt_event,
+                &self.mem,
+                queue.used_addr,
+                QUEUE_SIZE,
+                &mut queue.used_idx,
+            )
+            .await
+        }
+
+        async fn submit_pfns(&mut self, idx: u16, pfns: &[u32]) {
+            let bytes: Vec<u8> = pfns.iter().flat_map(|pfn| pfn.to_le_bytes()).collect();
+            self.mem.write_at(DATA_BASE, &bytes).unwrap();
+            let (_, written) = self
+                .submit_and_wait(idx, &[(DATA_BASE, bytes.len() as u32, false)])
+                .await;
+            assert_eq!(written, 0);
+        }
+    }
+
+    fn all_features() -> VirtioDeviceFeatures {
+        VirtioDeviceFeatures::new()
+            .with_device_specific_low(VIRTIO_BALLOON_F_STATS_VQ | VIRTIO_BALLOON_F_REPORTING)
+    }
+
+    #[async_test]
+    async fn balloon_reports_correct_traits(driver: DefaultDriver) {
+        let harness = TestHarness::new(&driver);
+        let traits = harness.device.traits();
+        assert_eq!(traits.device_id, virtio::spec::VirtioDeviceType::BALLOON);
+        assert_eq!(traits.max_queues, MAX_QUEUES);
+        assert_eq!(traits.device_register_length, size_of::<Config>() as u32);
+        let features = traits.device_features.device_specific_low();
+        assert_ne!(features & VIRTIO_BALLOON_F_STATS_VQ, 0);
+        assert_ne!(features & VIRTIO_BALLOON_F_REPORTING, 0);
+        assert_eq!(features & VIRTIO_BALLOON_F_DEFLATE_ON_OOM, 0);
+    }
+
+    #[test]
+    fn queue_kinds_follow_negotiated_features() {
+        let features = all_features();
+        assert_eq!(queue_kind(0, &features), Some(QueueKind::Inflate));
+        assert_eq!(queue_kind(1, &features), Some(QueueKind::Deflate));
+        assert_eq!(queue_kind(2, &features), Some(QueueKind::Stats));
+        assert_eq!(queue_kind(3, &features), Some(QueueKind::Reporting));
+
+        let features =
+            VirtioDeviceFeatures::new().with_device_specific_low(VIRTIO_BALLOON_F_REPORTING);
+        assert_eq!(queue_kind(2, &features), Some(QueueKind::Reporting));
+        assert_eq!(queue_kind(3, &features), None);
+    }
+
+    #[async_test]
+    async fn balloon_set_target_changes_config(driver: DefaultDriver) {
+        let mut harness = TestHarness::new(&driver);
+        assert_eq!(harness.device.read_registers_u32(0).await, 0);
+
+        let call = harness
+            .requests
+            .call(BalloonRequest::SetTarget, 64 * PAGE_SIZE + 1);
+        std::future::poll_fn(|cx| harness.device.poll_config_change(cx)).await;
+        call.await.unwrap();
+        assert_eq!(harness.device.read_registers_u32(0).await, 64);
+
+        // The guest reports its progress through `actual`.
+        harness.device.write_registers_u32(4, 32).await;
+        assert_eq!(harness.device.read_registers_u32(4).await, 32);
+        // `num_pages` is not writable by the guest.
+        harness.device.write_registers_u32(0, 0).await;
+        assert_eq!(harness.device.read_registers_u32(0).await, 64);
+
+        harness.device.reset().await;
+        assert_eq!(harness.device.read_registers_u32(4).await, 0);
+    }
+
+    #[async_test]
+    async fn balloon_inflate_discards_pages(driver: DefaultDriver) {
+        let mut harness = TestHarness::new(&driver);
+        harness.start_queue(0, &all_features()).await;
+        harness.submit_pfns(0, &[0x20, 0x21, 0x22, 0x30]).await;
+        assert_eq!(
+            *harness.discard.ranges.lock(),
+            [
+                MemoryRange::new(0x20000..0x23000),
+                MemoryRange::new(0x30000..0x31000)
+            ]
+        );
+    }
+
+    #[async_test]
+    async fn balloon_deflate_does_not_discard(driver: DefaultDriver) {
+        let mut harness = TestHarness::new(&driver);
+        harness.start_queue(1, &all_features()).await;
+        harness.submit_pfns(1, &[0x20, 0x21]).await;
+        assert!(harness.discard.ranges.lock().is_empty());
+    }
+
+    #[async_test]
+    async fn balloon_free_page_reporting_discards_ranges(driver: DefaultDriver) {
+        let mut harness = TestHarness::new(&driver);
+        harness.start_queue(3, &all_features()).await;
+        let (_, written) = harness
+            .submit_and_wait(3, &[(0x40000, 0x10000, true), (0x80000, 0x1000, true)])
+            .await;
+        assert_eq!(written, 0);
+        assert_eq!(
+            *harness.discard.ranges.lock(),
+            [
+                MemoryRange::new(0x40000..0x50000),
+                MemoryRange::new(0x80000..0x81000)
+            ]
+        );
+    }
+
+    #[test]
+    fn guest_stats_update() {
+        let mut stats = GuestStats::default();
+        let buf: Vec<u8> = [
+            (VIRTIO_BALLOON_S_MEMFREE, 0x1000u64),
+            (VIRTIO_BALLOON_S_MEMTOT, 0x8000),
+            (0xffff, 1),
+        ]
+        .iter()
+        .flat_map(|&(tag, val)| {
+            Stat {
+                tag: tag.into(),
+                val: val.into(),
+            }
+            .as_bytes()
+            .to_vec()
+        })
+        .collect();
+        stats.update(&buf);
+        assert_eq!(stats.free_memory, Some(0x1000));
+        assert_eq!(stats.total_memory, Some(0x8000));
+        assert_eq!(stats.swap_in, None);
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Defines the resource resolver for virtio-balloon devices.
+
+use crate::VirtioBalloonDevice;
+use anyhow::Context as _;
+use async_trait::async_trait;
+use virtio::resolve::ResolvedVirtioDevice;
+use virtio::resolve::VirtioResolveInput;
+use virtio_resources::balloon::DiscardRamHandleKind;
+use virtio_resources::balloon::ResolvedDiscardRam;
+use virtio_resources::balloon::VirtioBalloonHandle;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::VirtioDeviceHandle;
+
+/// Resolver for virtio-balloon devices.
+pub struct VirtioBalloonResolver;
+
+declare_static_async_resolver! {
+    VirtioBalloonResolver,
+    (VirtioDeviceHandle, VirtioBalloonHandle),
+}
+
+#[async_trait]
+impl AsyncResolveResource<VirtioDeviceHandle, VirtioBalloonHandle> for VirtioBalloonResolver {
+    type Output = ResolvedVirtioDevice;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        resource: VirtioBalloonHandle,
+        input: VirtioResolveInput<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let ResolvedDiscardRam(discard) = resolver
+            .resolve::<DiscardRamHandleKind, _>(resource.discard_ram, ())
+            .await
+            .context("failed to resolve discard_ram platform resource")?;
+
+        let device = VirtioBalloonDevice::new(
+            input.driver_source,
+            discard,
+            resource.deflate_on_oom,
+            resource.recv,
+        );
+        Ok(device.into())
+    }
+}
@@ -11,6 +11,14 @@
 use disk_backend::Disk;
 use disk_backend::DiskError;
 use disk_backend::DiskIo;
+use disk_backend::zoned::ZoneAction;
+use disk_backend::zoned::ZoneDescriptor;
+use disk_backend::zoned::ZoneError;
+use disk_backend::zoned::ZoneGeometry;
+use disk_backend::zoned::ZoneState;
+use disk_backend::zoned::ZoneType;
+use disk_backend::zoned::ZonedDisk;
+use disk_backend::zoned::ZonedModel;
 use guestmem::GuestMemory;
 use guestmem::MemoryRead;
 use guestmem::MemoryWrite;
@@ -20,6 +28,8 @@
 use pal_event::Event;
 use parking_lot::Mutex;
 use scsi_buffers::RequestBuffers;
+use std::future::poll_fn;
+use std::mem::offset_of;
 use test_with_tracing::test;
 use virtio::QueueResources;
 use virtio::VirtioDevice;
@@ -70,13 +80,18 @@ struct TestHarness {
 impl TestHarness {
     /// Create a harness with a RAM disk of the given size.
     fn new(driver: &DefaultDriver, disk: Disk, read_only: bool) -> Self {
+        Self::with_queues(driver, disk, read_only, 1)
+    }
+
+    /// Create a harness for a device with `num_queues` request queues.
+    fn with_queues(driver: &DefaultDriver, disk: Disk, read_only: bool, num_queues: u16) -> Self {
         let mem = GuestMemory::allocate(TOTAL_MEM_SIZE);
 
         init_avail_ring(&mem, AVAIL_ADDR);
         init_used_ring(&mem, USED_ADDR);
 
         let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
-        let device = VirtioBlkDevice::new(&driver_source, disk, read_only);
+        let device = VirtioBlkDevice::new(&driver_source, disk, read_only, num_queues);
 
         let queue_event = Event::new();
         let interrupt_event = Event::new();
@@ -95,11 +110,16 @@ fn new(driver: &DefaultDriver, disk: Disk, read_only: bool) -> Self {
 
     /// Enable the device with one queue.
     async fn enable(&mut self) {
+        self.enable_queue(0).await;
+    }
+
+    /// Enable only the queue at `idx`, backed by the harness's ring memory.
+    async fn enable_queue(&mut self, idx: u16) {
         let interrupt = Interrupt::from_event(self.interrupt_event.clone());
 
         self.device
             .start_queue(
-                0,
+                idx,
                 QueueResources {
                     params: QueueParams {
                         size: QUEUE_SIZE,
@@ -136,14 +156,31 @@ fn alloc_data(&mut self, size: u32) -> u64 {
     ///   desc 0 (readable): VirtioBlkReqHeader { type=IN, sector }
     ///   desc 1 (writable): data buffer (data_len bytes) + 1 status byte
     ///
-    /// Returns the head descriptor index.
+    /// Returns the GPA of the data buffer.
     fn post_read_request(&mut self, head_desc: u16, sector: u64, data_len: u32) -> u64 {
+        self.post_in_request(head_desc, VIRTIO_BLK_T_IN, sector, data_len)
+    }
+
+    /// Build a zone report request descriptor chain, with the same layout as
+    /// a read request.
+    fn post_zone_report_request(&mut self, head_desc: u16, sector: u64, data_len: u32) -> u64 {
+        self.post_in_request(head_desc, VIRTIO_BLK_T_ZONE_REPORT, sector, data_len)
+    }
+
+    /// Build a request whose data buffer is written by the device.
+    fn post_in_request(
+        &mut self,
+        head_desc: u16,
+        request_type: u32,
+        sector: u64,
+        data_len: u32,
+    ) -> u64 {
         let header_gpa = self.alloc_data(REQ_HEADER_SIZE);
         let data_gpa = self.alloc_data(data_len + 1); // +1 for status byte
 
         // Write the request header
         let header = VirtioBlkReqHeader {
-            request_type: VIRTIO_BLK_T_IN,
+            request_type,
             reserved: 0,
             sector,
         };
@@ -196,15 +233,40 @@ fn post_read_request(&mut self, head_desc: u16, sector: u64, data_len: u32) -> u
     ///   desc 1 (readable): data to write
     ///   desc 2 (writable): 1-byte status
     ///
-    /// Returns the head descriptor index.
-    fn post_write_request(&mut self, head_desc: u16, sector: u64, data: &[u8]) {
+    /// Returns the GPA of the status byte.
+    fn post_write_request(&mut self, head_desc: u16, sector: u64, data: &[u8]) -> u64 {
+        self.post_out_request(head_desc, VIRTIO_BLK_T_OUT, sector, data, 1)
+    }
+
+    /// Build a zone append request descriptor chain.
+    ///
+    /// Layout (per virtio-blk spec §5.2.6):
+    ///   desc 0 (readable): VirtioBlkReqHeader { type=ZONE_APPEND, sector }
+    ///   desc 1 (readable): data to write
+    ///   desc 2 (writable): le64 append sector + 1-byte status
+    ///
+    /// Returns the GPA of the append sector.
+    fn post_zone_append_request(&mut self, head_desc: u16, sector: u64, data: &[u8]) -> u64 {
+        self.post_out_request(head_desc, VIRTIO_BLK_T_ZONE_APPEND, sector, data, 9)
+    }
+
+    /// Build a request whose data buffer is read by the device, followed by
+    /// `in_len` device-writable bytes ending in the status byte.
+    fn post_out_request(
+        &mut self,
+        head_desc: u16,
+        request_type: u32,
+        sector: u64,
+        data: &[u8],
+        in_len: u32,
+    ) -> u64 {
         let header_gpa = self.alloc_data(REQ_HEADER_SIZE);
         let data_gpa = self.alloc_data(data.len() as u32);
-        let status_gpa = self.alloc_data(1);
+        let status_gpa = self.alloc_data(in_len);
 
         // Write the request header
         let header = VirtioBlkReqHeader {
-            request_type: VIRTIO_BLK_T_OUT,
+            request_type,
             reserved: 0,
             sector,
         };
@@ -213,8 +275,9 @@ fn post_write_request(&mut self, head_desc: u16, sector: u64, data: &[u8]) {
         // Write the data payload
         self.mem.write_at(data_gpa, data).unwrap();
 
-        // Zero the status byte
-        self.mem.write_at(status_gpa, &[0u8]).unwrap();
+        // Zero the status byte (and any preceding device-writable bytes)
+        let zeroes = vec![0u8; in_len as usize];
+        self.mem.write_at(status_gpa, &zeroes).unwrap();
 
         // desc 0: header (readable)
         let flags0 = DescriptorFlags::new().with_next(true);
@@ -247,7 +310,7 @@ fn post_write_request(&mut self, head_desc: u16, sector: u64, data: &[u8]) {
             DESC_ADDR,
             head_desc + 2,
             status_gpa,
-            1,
+            in_len,
             flags2,
             0,
         );
@@ -260,6 +323,8 @@ fn post_write_request(&mut self, head_desc: u16, sector: u64, data: &[u8]) {
             &mut self.avail_idx,
         );
         self.queue_event.signal();
+
+        status_gpa
     }
 
     /// Build a flush request descriptor chain.
@@ -771,6 +836,8 @@ struct TestDisk4K {
     storage: Mutex<Vec<u8>>,
     #[inspect(skip)]
     supports_discard: bool,
+    #[inspect(skip)]
+    pending_resize: Mutex<Option<usize>>,
 }
 
 impl TestDisk4K {
@@ -781,6 +848,7 @@ fn new(total_bytes: usize, sector_size: u32) -> Self {
             sector_size,
             storage: Mutex::new(vec![0u8; total_bytes]),
             supports_discard: false,
+            pending_resize: Mutex::new(None),
         }
     }
 
@@ -788,6 +856,13 @@ fn with_discard(mut self) -> Self {
         self.supports_discard = true;
         self
     }
+
+    /// Resize the disk to `total_bytes` the first time the frontend waits
+    /// for a resize.
+    fn with_resize(self, total_bytes: usize) -> Self {
+        *self.pending_resize.lock() = Some(total_bytes);
+        self
+    }
 }
 
 impl DiskIo for TestDisk4K {
@@ -870,6 +945,16 @@ fn unmap_behavior(&self) -> disk_backend::UnmapBehavior {
             disk_backend::UnmapBehavior::Ignored
         }
     }
+
+    async fn wait_resize(&self, sector_count: u64) -> u64 {
+        let Some(total_bytes) = self.pending_resize.lock().take() else {
+            return std::future::pending().await;
+        };
+        self.storage.lock().resize(total_bytes, 0);
+        let new_count = self.sector_count();
+        assert_ne!(new_count, sector_count);
+        new_count
+    }
 }
 
 // --- Sector shift regression tests ---
@@ -1284,3 +1369,381 @@ async fn bounce_buffer_write_read_roundtrip(driver: DefaultDriver) {
         .unwrap();
     assert_eq!(read_status[0], VIRTIO_BLK_S_OK, "read should succeed");
 }
+
+// --- Multi-queue and resize tests ---
+
+/// A device with several request queues advertises VIRTIO_BLK_F_MQ and
+/// serves IO on a queue other than the first.
+#[async_test]
+async fn multiqueue_io_on_second_queue(driver: DefaultDriver) {
+    let disk = ram_disk(64 * 1024, false);
+    let mut harness = TestHarness::with_queues(&driver, disk, false, 4);
+
+    let traits = harness.device.traits();
+    assert_eq!(traits.max_queues, 4);
+    assert_ne!(
+        traits.device_features.device_specific_low() & VIRTIO_BLK_F_MQ,
+        0
+    );
+    let num_queues = harness
+        .device
+        .read_registers_u32(offset_of!(VirtioBlkConfig, num_queues) as u16)
+        .await;
+    assert_eq!(num_queues as u16, 4);
+
+    harness.enable_queue(1).await;
+
+    let data = [0x3C; 512];
+    harness.post_write_request(0, 5, &data);
+    let (_id, used_len) = harness.wait_for_used().await;
+    assert_eq!(used_len, 1);
+
+    let data_gpa = harness.post_read_request(3, 5, 512);
+    let (_id, used_len) = harness.wait_for_used().await;
+    assert_eq!(used_len, 513);
+    let mut readback = [0u8; 512];
+    harness.mem.read_at(data_gpa, &mut readback).unwrap();
+    assert_eq!(readback, data);
+}
+
+/// A single-queue device does not advertise VIRTIO_BLK_F_MQ.
+#[async_test]
+async fn single_queue_omits_mq(driver: DefaultDriver) {
+    let disk = ram_disk(64 * 1024, false);
+    let harness = TestHarness::new(&driver, disk, false);
+
+    let traits = harness.device.traits();
+    assert_eq!(traits.max_queues, 1);
+    assert_eq!(
+        traits.device_features.device_specific_low() & VIRTIO_BLK_F_MQ,
+        0
+    );
+}
+
+/// Resizing the backing disk updates the capacity in config space and
+/// signals a config change.
+#[async_test]
+async fn resize_updates_capacity(driver: DefaultDriver) {
+    let disk = Disk::new(TestDisk4K::new(64 * 1024, 512).with_resize(128 * 1024)).unwrap();
+    let mut harness = TestHarness::new(&driver, disk, false);
+
+    // Capacity is the low half of the first config field, in 512-byte sectors.
+    assert_eq!(harness.device.read_registers_u32(0).await, 128);
+
+    poll_fn(|cx| harness.device.poll_config_change(cx)).await;
+    assert_eq!(harness.device.read_registers_u32(0).await, 256);
+
+    // No further change is pending.
+    assert!(futures::poll!(poll_fn(|cx| harness.device.poll_config_change(cx))).is_pending());
+}
+
+// --- Zoned test disk ---
+
+/// A host-managed zoned disk with 512-byte sectors, made up of equally
+/// sized sequential-write-required zones.
+#[derive(Inspect)]
+struct TestZonedDisk {
+    zone_sectors: u64,
+    #[inspect(skip)]
+    storage: Mutex<Vec<u8>>,
+    #[inspect(skip)]
+    write_pointers: Mutex<Vec<u64>>,
+}
+
+impl TestZonedDisk {
+    fn new(zone_count: usize, zone_sectors: u64) -> Self {
+        Self {
+            zone_sectors,
+            storage: Mutex::new(vec![0; zone_count * zone_sectors as usize * 512]),
+            write_pointers: Mutex::new((0..zone_count as u64).map(|i| i * zone_sectors).collect()),
+        }
+    }
+
+    fn zone_index(&self, sector: u64) -> Result<usize, ZoneError> {
+        let index = (sector / self.zone_sectors) as usize;
+        if index < self.write_pointers.lock().len() {
+            Ok(index)
+        } else {
+            Err(ZoneError::InvalidCommand)
+        }
+    }
+
+    /// Writes `buffers` at `sector`, which must be its zone's write pointer.
+    fn write_at_write_pointer(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+    ) -> Result<(), ZoneError> {
+        let index = self.zone_index(sector)?;
+        let sectors = buffers.len() as u64 / 512;
+        let mut write_pointers = self.write_pointers.lock();
+        if sector != write_pointers[index] {
+            return Err(ZoneError::UnalignedWritePointer);
+        }
+        if sector + sectors > (index as u64 + 1) * self.zone_sectors {
+            return Err(ZoneError::InvalidCommand);
+        }
+        let offset = sector as usize * 512;
+        buffers
+            .reader()
+            .read(&mut self.storage.lock()[offset..offset + buffers.len()])
+            .map_err(DiskError::from)?;
+        write_pointers[index] += sectors;
+        Ok(())
+    }
+}
+
+impl DiskIo for TestZonedDisk {
+    fn disk_type(&self) -> &str {
+        "test-zoned"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.storage.lock().len() as u64 / 512
+    }
+
+    fn sector_size(&self) -> u32 {
+        512
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        None
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        512
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        false
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    async fn read_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+    ) -> Result<(), DiskError> {
+        let offset = sector as usize * 512;
+        let end = offset + buffers.len();
+        let storage = self.storage.lock();
+        if end > storage.len() {
+            return Err(DiskError::IllegalBlock);
+        }
+        buffers.writer().write(&storage[offset..end])?;
+        Ok(())
+    }
+
+    async fn write_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        _fua: bool,
+    ) -> Result<(), DiskError> {
+        self.write_at_write_pointer(buffers, sector)
+            .map_err(|err| match err {
+                ZoneError::Disk(err) => err,
+                _ => DiskError::InvalidInput,
+            })
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        Ok(())
+    }
+
+    async fn unmap(
+        &self,
+        _sector: u64,
+        _count: u64,
+        _block_level_only: bool,
+    ) -> Result<(), DiskError> {
+        Ok(())
+    }
+
+    fn unmap_behavior(&self) -> disk_backend::UnmapBehavior {
+        disk_backend::UnmapBehavior::Unspecified
+    }
+
+    fn zoned(&self) -> Option<&dyn ZonedDisk> {
+        Some(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl ZonedDisk for TestZonedDisk {
+    fn zone_geometry(&self) -> ZoneGeometry {
+        ZoneGeometry {
+            model: ZonedModel::HostManaged,
+            zone_sectors: self.zone_sectors,
+            max_open_zones: 0,
+            max_active_zones: 0,
+            max_append_sectors: self.zone_sectors as u32,
+        }
+    }
+
+    async fn report_zones(
+        &self,
+        sector: u64,
+        max_zones: usize,
+    ) -> Result<Vec<ZoneDescriptor>, ZoneError> {
+        let first = self.zone_index(sector)?;
+        let zones = self
+            .write_pointers
+            .lock()
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(max_zones)
+            .map(|(i, &write_pointer)| {
+                let start = i as u64 * self.zone_sectors;
+                let state = if write_pointer == start {
+                    ZoneState::Empty
+                } else if write_pointer == start + self.zone_sectors {
+                    ZoneState::Full
+                } else {
+                    ZoneState::ImplicitlyOpen
+                };
+                ZoneDescriptor {
+                    start,
+                    capacity: self.zone_sectors,
+                    write_pointer,
+                    zone_type: ZoneType::SequentialWriteRequired,
+                    state,
+                }
+            })
+            .collect();
+        Ok(zones)
+    }
+
+    async fn manage_zone(&self, action: ZoneAction, sector: u64) -> Result<(), ZoneError> {
+        let index = self.zone_index(sector)?;
+        let start = index as u64 * self.zone_sectors;
+        if sector != start {
+            return Err(ZoneError::InvalidCommand);
+        }
+        let mut write_pointers = self.write_pointers.lock();
+        match action {
+            ZoneAction::Open | ZoneAction::Close => {}
+            ZoneAction::Finish => write_pointers[index] = start + self.zone_sectors,
+            ZoneAction::Reset => write_pointers[index] = start,
+        }
+        Ok(())
+    }
+
+    async fn reset_all_zones(&self) -> Result<(), ZoneError> {
+        for (i, write_pointer) in self.write_pointers.lock().iter_mut().enumerate() {
+            *write_pointer = i as u64 * self.zone_sectors;
+        }
+        Ok(())
+    }
+
+    async fn zone_append(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        zone_sector: u64,
+    ) -> Result<u64, ZoneError> {
+        let index = self.zone_index(zone_sector)?;
+        let write_pointer = self.write_pointers.lock()[index];
+        self.write_at_write_pointer(buffers, write_pointer)?;
+        Ok(write_pointer)
+    }
+}
+
+// --- Zoned device tests ---
+
+/// Zone append lands at the write pointer, regular writes must hit the write
+/// pointer, and the zone report reflects both.
+#[async_test]
+async fn zoned_append_write_and_report(driver: DefaultDriver) {
+    // 4 zones of 16 sectors each.
+    let disk = Disk::new(TestZonedDisk::new(4, 16)).unwrap();
+    let mut harness = TestHarness::new(&driver, disk, false);
+
+    let features = harness
+        .device
+        .traits()
+        .device_features
+        .device_specific_low();
+    assert_ne!(features & VIRTIO_BLK_F_ZONED, 0);
+    // Discard is never offered for host-managed disks.
+    assert_eq!(features & VIRTIO_BLK_F_DISCARD, 0);
+    harness.enable().await;
+
+    // Append to zone 1 — the data lands at the start of the zone.
+    let data = [0x5A; 512];
+    let append_gpa = harness.post_zone_append_request(0, 16, &data);
+    let (_id, used_len) = harness.wait_for_used().await;
+    assert_eq!(used_len, 9); // le64 append sector + status byte
+    let append_sector: u64 = harness.mem.read_plain(append_gpa).unwrap();
+    assert_eq!(append_sector, 16);
+    assert_eq!(harness.read_status(append_gpa + 8), VIRTIO_BLK_S_OK);
+
+    // A write behind the write pointer fails.
+    let status_gpa = harness.post_write_request(3, 16, &data);
+    harness.wait_for_used().await;
+    assert_eq!(
+        harness.read_status(status_gpa),
+        VIRTIO_BLK_S_ZONE_UNALIGNED_WP
+    );
+
+    // A write at the write pointer succeeds.
+    let status_gpa = harness.post_write_request(6, 17, &data);
+    harness.wait_for_used().await;
+    assert_eq!(harness.read_status(status_gpa), VIRTIO_BLK_S_OK);
+
+    // Report from zone 1, with room for more zones than remain.
+    let report_len =
+        (size_of::<VirtioBlkZoneReport>() + 4 * size_of::<VirtioBlkZoneDescriptor>()) as u32;
+    let report_gpa = harness.post_zone_report_request(9, 16, report_len);
+    let (_id, used_len) = harness.wait_for_used().await;
+    let header_len = size_of::<VirtioBlkZoneReport>() as u64;
+    let desc_len = size_of::<VirtioBlkZoneDescriptor>() as u64;
+    assert_eq!(used_len as u64, header_len + 3 * desc_len + 1);
+    assert_eq!(
+        harness.read_status(report_gpa + report_len as u64),
+        VIRTIO_BLK_S_OK
+    );
+
+    let report: VirtioBlkZoneReport = harness.mem.read_plain(report_gpa).unwrap();
+    assert_eq!(report.nr_zones, 3);
+
+    let zone: VirtioBlkZoneDescriptor = harness.mem.read_plain(report_gpa + header_len).unwrap();
+    assert_eq!(zone.z_start, 16);
+    assert_eq!(zone.z_cap, 16);
+    assert_eq!(zone.z_wp, 18);
+    assert_eq!(zone.z_type, VIRTIO_BLK_ZT_SWR);
+    assert_eq!(zone.z_state, VIRTIO_BLK_ZS_IOPEN);
+
+    let zone: VirtioBlkZoneDescriptor = harness
+        .mem
+        .read_plain(report_gpa + header_len + desc_len)
+        .unwrap();
+    assert_eq!(zone.z_start, 32);
+    assert_eq!(zone.z_wp, 32);
+    assert_eq!(zone.z_state, VIRTIO_BLK_ZS_EMPTY);
+}
+
+/// Resetting a zone rewinds its write pointer.
+#[async_test]
+async fn zoned_reset_rewinds_write_pointer(driver: DefaultDriver) {
+    let disk = Disk::new(TestZonedDisk::new(4, 16)).unwrap();
+    let mut harness = TestHarness::new(&driver, disk, false);
+    harness.enable().await;
+
+    let data = [0xC3; 512];
+    let status_gpa = harness.post_write_request(0, 0, &data);
+    harness.wait_for_used().await;
+    assert_eq!(harness.read_status(status_gpa), VIRTIO_BLK_S_OK);
+
+    let status_gpa = harness.post_raw_request(3, VIRTIO_BLK_T_ZONE_RESET, 0);
+    harness.wait_for_used().await;
+    assert_eq!(harness.read_status(status_gpa), VIRTIO_BLK_S_OK);
+
+    // Sector 0 is the write pointer again.
+    let status_gpa = harness.post_write_request(5, 0, &data);
+    harness.wait_for_used().await;
+    assert_eq!(harness.read_status(status_gpa), VIRTIO_BLK_S_OK);
+}
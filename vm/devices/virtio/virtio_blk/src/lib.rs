@@ -12,6 +12,14 @@
 
 use anyhow::Context as _;
 use disk_backend::Disk;
+use disk_backend::DiskError;
+use disk_backend::zoned::ZoneAction;
+use disk_backend::zoned::ZoneDescriptor;
+use disk_backend::zoned::ZoneError;
+use disk_backend::zoned::ZoneState;
+use disk_backend::zoned::ZoneType;
+use disk_backend::zoned::ZonedDisk;
+use disk_backend::zoned::ZonedModel;
 use futures::StreamExt;
 use guestmem::GuestMemory;
 use guestmem::ranges::PagedRange;
@@ -58,16 +66,36 @@
 
 const MAX_IO_DEPTH: usize = 64;
 
+/// Maximum number of request queues.
+///
+/// The PCI transport has 64 MSI-X vectors; this leaves one for config
+/// change notifications and gives each request queue its own vector.
+pub const MAX_QUEUES: u16 = 63;
+
 /// The virtio-blk device.
-#[derive(InspectMut)]
 pub struct VirtioBlkDevice {
-    #[inspect(flatten, mut)]
-    worker: TaskControl<BlkWorker, BlkQueueState>,
-    #[inspect(skip)]
-    driver: VmTaskDriver,
+    /// One worker per request queue.
+    workers: Vec<TaskControl<BlkWorker, BlkQueueState>>,
+    /// The driver for each request queue's worker.
+    drivers: Vec<VmTaskDriver>,
+    disk: Disk,
     read_only: bool,
     supports_discard: bool,
+    zoned: bool,
     config: VirtioBlkConfig,
+    /// Completes when the disk's sector count changes.
+    resize: Pin<Box<dyn Future<Output = u64> + Send>>,
+}
+
+impl InspectMut for VirtioBlkDevice {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("read_only", self.read_only)
+            .field("supports_discard", self.supports_discard)
+            .field("zoned", self.zoned)
+            .field("config", &self.config)
+            .fields_mut("queues", self.workers.iter_mut().enumerate());
+    }
 }
 
 /// Persistent worker state. Survives across enable/disable cycles.
@@ -99,6 +127,7 @@ struct WorkerStats {
     write_ops: Counter,
     flush_ops: Counter,
     discard_ops: Counter,
+    zone_ops: Counter,
     bounce_ops: Counter,
     errors: Counter,
 }
@@ -124,6 +153,7 @@ enum IoStat {
     Write,
     Flush,
     Discard,
+    Zone,
     Error,
     None,
 }
@@ -137,6 +167,7 @@ fn finish_io(&mut self, queue: &mut VirtioQueue, completion: IoCompletion) {
             IoStat::Write => self.stats.write_ops.increment(),
             IoStat::Flush => self.stats.flush_ops.increment(),
             IoStat::Discard => self.stats.discard_ops.increment(),
+            IoStat::Zone => self.stats.zone_ops.increment(),
             IoStat::Error => self.stats.errors.increment(),
             IoStat::None => {}
         }
@@ -217,10 +248,20 @@ enum Event {
 }
 
 impl VirtioBlkDevice {
-    /// Creates a new virtio-blk device backed by the given disk.
-    pub fn new(driver_source: &VmTaskDriverSource, disk: Disk, read_only: bool) -> Self {
+    /// Creates a new virtio-blk device backed by the given disk, with
+    /// `num_queues` request queues.
+    ///
+    /// `num_queues` is clamped to `1..=MAX_QUEUES`.
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        disk: Disk,
+        read_only: bool,
+        num_queues: u16,
+    ) -> Self {
+        let num_queues = num_queues.clamp(1, MAX_QUEUES);
         let sector_count = disk.sector_count();
         let sector_size = disk.sector_size();
+        let sector_shift = disk.sector_shift() - 9;
         let physical_sector_size = disk.physical_sector_size();
 
         let physical_block_exp = if physical_sector_size > sector_size {
@@ -229,6 +270,18 @@ pub fn new(driver_source: &VmTaskDriverSource, disk: Disk, read_only: bool) -> S
             0
         };
 
+        let zoned_config = disk
+            .zoned()
+            .and_then(|zoned| zoned_characteristics(zoned, sector_size, sector_shift));
+
+        // Discard has no defined interaction with write pointers, so don't
+        // offer it for host-managed zoned disks.
+        let host_managed = zoned_config
+            .as_ref()
+            .is_some_and(|zoned| zoned.model == VIRTIO_BLK_Z_HM);
+        let supports_discard =
+            disk.unmap_behavior() != disk_backend::UnmapBehavior::Ignored && !host_managed;
+
         // Virtio block config space (spec §5.2.4).
         //
         // `capacity` is always present. Other fields are gated by feature bits
@@ -236,7 +289,7 @@ pub fn new(driver_source: &VmTaskDriverSource, disk: Disk, read_only: bool) -> S
         let config = VirtioBlkConfig {
             // Capacity in 512-byte sectors (spec §5.2.4). The protocol always
             // uses 512-byte units regardless of the disk's native sector size.
-            capacity: sector_count * (sector_size as u64 / 512),
+            capacity: sector_count << sector_shift,
             // Maximum bytes in a single segment (VIRTIO_BLK_F_SIZE_MAX). Not
             // specified.
             size_max: 0,
@@ -264,8 +317,9 @@ pub fn new(driver_source: &VmTaskDriverSource, disk: Disk, read_only: bool) -> S
             // writeback cache semantics (driver should use FLUSH).
             writeback: 1,
             unused0: 0,
-            // We don't advertise MQ; single queue.
-            num_queues: 1,
+            // Number of request queues (VIRTIO_BLK_F_MQ, advertised only when
+            // there is more than one).
+            num_queues,
             // Discard fields (VIRTIO_BLK_F_DISCARD, spec §5.2.4).
             // u32::MAX × 512 bytes ≈ 2 TiB per segment; no practical limit.
             max_discard_sectors: u32::MAX,
@@ -279,26 +333,84 @@ pub fn new(driver_source: &VmTaskDriverSource, disk: Disk, read_only: bool) -> S
             max_write_zeroes_seg: 0,
             write_zeroes_may_unmap: 0,
             unused1: [0; 3],
-            _padding: [0; 4],
+            // Secure erase fields (VIRTIO_BLK_F_SECURE_ERASE) — not advertised.
+            max_secure_erase_sectors: 0,
+            max_secure_erase_seg: 0,
+            secure_erase_sector_alignment: 0,
+            // Zoned characteristics (VIRTIO_BLK_F_ZONED), if the backend
+            // reports zones.
+            zoned: zoned_config
+                .clone()
+                .unwrap_or_else(VirtioBlkZonedCharacteristics::new_zeroed),
         };
 
-        let supports_discard = disk.unmap_behavior() != disk_backend::UnmapBehavior::Ignored;
+        let workers = (0..num_queues)
+            .map(|_| {
+                TaskControl::new(BlkWorker {
+                    disk: disk.clone(),
+                    read_only,
+                    stats: WorkerStats::default(),
+                    ios: FuturesUnordered::new(),
+                })
+            })
+            .collect();
+
+        // The target VP is only a hint that each queue wants its own thread;
+        // the guest chooses which CPUs submit to which queue.
+        let drivers = (0..num_queues)
+            .map(|i| {
+                driver_source
+                    .builder()
+                    .run_on_target(false)
+                    .target_vp(i.into())
+                    .build("virtio-blk")
+            })
+            .collect();
 
         Self {
-            worker: TaskControl::new(BlkWorker {
-                disk,
-                read_only,
-                stats: WorkerStats::default(),
-                ios: FuturesUnordered::new(),
-            }),
-            driver: driver_source.simple(),
+            workers,
+            drivers,
+            resize: wait_resize(disk.clone(), sector_count),
+            disk,
             read_only,
             supports_discard,
+            zoned: zoned_config.is_some(),
             config,
         }
     }
 }
 
+/// Returns a future that completes with the new sector count when the disk
+/// is resized.
+fn wait_resize(disk: Disk, sector_count: u64) -> Pin<Box<dyn Future<Output = u64> + Send>> {
+    Box::pin(async move { disk.wait_resize(sector_count).await })
+}
+
+/// Builds the zoned config space fields from the backend's zone geometry,
+/// or returns `None` if the geometry cannot be expressed in the config
+/// space.
+fn zoned_characteristics(
+    zoned: &dyn ZonedDisk,
+    sector_size: u32,
+    sector_shift: u32,
+) -> Option<VirtioBlkZonedCharacteristics> {
+    let geometry = zoned.zone_geometry();
+    let model = match geometry.model {
+        ZonedModel::HostManaged => VIRTIO_BLK_Z_HM,
+        ZonedModel::HostAware => VIRTIO_BLK_Z_HA,
+    };
+    Some(VirtioBlkZonedCharacteristics {
+        zone_sectors: (geometry.zone_sectors << sector_shift).try_into().ok()?,
+        max_open_zones: geometry.max_open_zones,
+        max_active_zones: geometry.max_active_zones,
+        max_append_sectors: ((geometry.max_append_sectors as u64) << sector_shift)
+            .min(u32::MAX.into()) as u32,
+        write_granularity: sector_size,
+        model,
+        unused2: [0; 3],
+    })
+}
+
 impl VirtioDevice for VirtioBlkDevice {
     fn traits(&self) -> DeviceTraits {
         let mut features = VIRTIO_BLK_F_SEG_MAX
@@ -315,6 +427,12 @@ fn traits(&self) -> DeviceTraits {
             // by adding an explicit write_zeroes operation to the DiskIo
             // backend trait, rather than emulating it with bounce-buffer writes.
         }
+        if self.workers.len() > 1 {
+            features |= VIRTIO_BLK_F_MQ;
+        }
+        if self.zoned {
+            features |= VIRTIO_BLK_F_ZONED;
+        }
 
         DeviceTraits {
             device_id: virtio::spec::VirtioDeviceType::BLK,
@@ -323,9 +441,8 @@ fn traits(&self) -> DeviceTraits {
                 .with_ring_event_idx(true)
                 .with_ring_indirect_desc(true)
                 .with_ring_packed(true),
-            max_queues: 1,
-            // Config space is 60 bytes (size_of minus 4 bytes of struct padding).
-            device_register_length: (size_of::<VirtioBlkConfig>() - 4) as u32,
+            max_queues: self.workers.len() as u16,
+            device_register_length: size_of::<VirtioBlkConfig>() as u32,
             shared_memory: DeviceTraitsSharedMemory::default(),
         }
     }
@@ -363,10 +480,9 @@ async fn start_queue(
         features: &VirtioDeviceFeatures,
         initial_state: Option<QueueState>,
     ) -> anyhow::Result<()> {
-        assert_eq!(idx, 0);
-
-        let queue_event = PolledWait::new(&self.driver, resources.event)
-            .context("failed to create queue event")?;
+        let driver = &self.drivers[idx as usize];
+        let queue_event =
+            PolledWait::new(driver, resources.event).context("failed to create queue event")?;
 
         let queue = VirtioQueue::new(
             *features,
@@ -378,36 +494,45 @@ async fn start_queue(
         )
         .context("failed to create virtio queue")?;
 
-        self.worker.insert(
-            self.driver.clone(),
+        let worker = &mut self.workers[idx as usize];
+        worker.insert(
+            driver.clone(),
             "virtio-blk-worker",
             BlkQueueState {
                 queue,
                 memory: resources.guest_memory,
             },
         );
-        self.worker.start();
+        worker.start();
         Ok(())
     }
 
     async fn stop_queue(&mut self, idx: u16) -> Option<QueueState> {
-        assert_eq!(idx, 0);
-        if !self.worker.has_state() {
+        let worker = &mut self.workers[idx as usize];
+        if !worker.has_state() {
             return None;
         }
         // Stop the worker task (cancels the run loop via until_stopped).
-        self.worker.stop().await;
+        worker.stop().await;
         // Drain in-flight IOs to completion. The FuturesUnordered lives in
         // BlkWorker and survives the stop — its pending disk IO futures are
         // polled here until all descriptors are completed in the used ring.
-        let (worker, queue_state) = self.worker.get_mut();
+        let (blk_worker, queue_state) = worker.get_mut();
         let queue = &mut queue_state.expect("state exists after stop").queue;
-        poll_fn(|cx| worker.poll_drain(queue, cx)).await;
+        poll_fn(|cx| blk_worker.poll_drain(queue, cx)).await;
         // Remove the queue state (drops VirtioQueue).
-        let state = self.worker.remove().queue.queue_state();
+        let state = worker.remove().queue.queue_state();
         Some(state)
     }
 
+    fn poll_config_change(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let sector_count = std::task::ready!(self.resize.as_mut().poll(cx));
+        tracing::info!(sector_count, "virtio-blk disk resized");
+        self.config.capacity = sector_count << (self.disk.sector_shift() - 9);
+        self.resize = wait_resize(self.disk.clone(), sector_count);
+        Poll::Ready(())
+    }
+
     fn supports_save_restore(&self) -> bool {
         true
     }
@@ -483,7 +608,7 @@ async fn process_request_inner(
     match request_type {
         VIRTIO_BLK_T_IN => {
             let disk_sector = virtio_to_disk_sector(header.sector, sector_shift, sector_mask)?;
-            let (bytes, bounced) = do_io(disk, mem, work, disk_sector, true).await?;
+            let (bytes, bounced, _) = do_io(disk, mem, work, disk_sector, DataOp::Read).await?;
             Ok((bytes, IoStat::Read, bounced))
         }
         VIRTIO_BLK_T_OUT => {
@@ -491,7 +616,7 @@ async fn process_request_inner(
                 return Err(VIRTIO_BLK_S_IOERR);
             }
             let disk_sector = virtio_to_disk_sector(header.sector, sector_shift, sector_mask)?;
-            let (bytes, bounced) = do_io(disk, mem, work, disk_sector, false).await?;
+            let (bytes, bounced, _) = do_io(disk, mem, work, disk_sector, DataOp::Write).await?;
             Ok((bytes, IoStat::Write, bounced))
         }
         VIRTIO_BLK_T_FLUSH => {
@@ -555,10 +680,135 @@ struct VirtioBlkDiscardReq {
                 .map_err(|_| VIRTIO_BLK_S_IOERR)?;
             Ok((0, IoStat::Discard, false))
         }
+        VIRTIO_BLK_T_ZONE_APPEND => {
+            let zoned = disk.zoned().ok_or(VIRTIO_BLK_S_UNSUPP)?;
+            if read_only {
+                return Err(VIRTIO_BLK_S_IOERR);
+            }
+            let zone_sector = virtio_to_disk_sector(header.sector, sector_shift, sector_mask)?;
+            let (_, bounced, append_sector) =
+                do_io(disk, mem, work, zone_sector, DataOp::ZoneAppend(zoned)).await?;
+            // The sector the data landed at precedes the status byte in the
+            // device-writable part of the request (spec §5.2.6).
+            work.write(mem, &(append_sector << sector_shift).to_le_bytes())
+                .map_err(|_| VIRTIO_BLK_S_IOERR)?;
+            Ok((size_of::<u64>() as u32, IoStat::Write, bounced))
+        }
+        VIRTIO_BLK_T_ZONE_REPORT => {
+            let zoned = disk.zoned().ok_or(VIRTIO_BLK_S_UNSUPP)?;
+            let sector = virtio_to_disk_sector(header.sector, sector_shift, sector_mask)?;
+            let bytes = report_zones(zoned, work, sector, sector_shift).await?;
+            work.write(mem, &bytes).map_err(|_| VIRTIO_BLK_S_IOERR)?;
+            Ok((bytes.len() as u32, IoStat::Zone, false))
+        }
+        VIRTIO_BLK_T_ZONE_OPEN
+        | VIRTIO_BLK_T_ZONE_CLOSE
+        | VIRTIO_BLK_T_ZONE_FINISH
+        | VIRTIO_BLK_T_ZONE_RESET => {
+            let zoned = disk.zoned().ok_or(VIRTIO_BLK_S_UNSUPP)?;
+            if read_only {
+                return Err(VIRTIO_BLK_S_IOERR);
+            }
+            let action = match request_type {
+                VIRTIO_BLK_T_ZONE_OPEN => ZoneAction::Open,
+                VIRTIO_BLK_T_ZONE_CLOSE => ZoneAction::Close,
+                VIRTIO_BLK_T_ZONE_FINISH => ZoneAction::Finish,
+                _ => ZoneAction::Reset,
+            };
+            let sector = virtio_to_disk_sector(header.sector, sector_shift, sector_mask)?;
+            zoned
+                .manage_zone(action, sector)
+                .await
+                .map_err(zone_error_status)?;
+            Ok((0, IoStat::Zone, false))
+        }
+        VIRTIO_BLK_T_ZONE_RESET_ALL => {
+            let zoned = disk.zoned().ok_or(VIRTIO_BLK_S_UNSUPP)?;
+            if read_only {
+                return Err(VIRTIO_BLK_S_IOERR);
+            }
+            zoned.reset_all_zones().await.map_err(zone_error_status)?;
+            Ok((0, IoStat::Zone, false))
+        }
         _ => Err(VIRTIO_BLK_S_UNSUPP),
     }
 }
 
+/// Build a `VIRTIO_BLK_T_ZONE_REPORT` response for zones starting at disk
+/// sector `sector`, sized to fit the request's data buffer.
+async fn report_zones(
+    zoned: &dyn ZonedDisk,
+    work: &VirtioQueueCallbackWork,
+    sector: u64,
+    sector_shift: u32,
+) -> Result<Vec<u8>, u8> {
+    // The last writable byte is the status byte.
+    let data_len = (work.get_payload_length(true) as usize).saturating_sub(1);
+    let header_len = size_of::<VirtioBlkZoneReport>();
+    if data_len < header_len {
+        return Err(VIRTIO_BLK_S_IOERR);
+    }
+    let max_zones = (data_len - header_len) / size_of::<VirtioBlkZoneDescriptor>();
+    let zones = zoned
+        .report_zones(sector, max_zones)
+        .await
+        .map_err(zone_error_status)?;
+
+    let mut report = VirtioBlkZoneReport::new_zeroed();
+    report.nr_zones = zones.len() as u64;
+    let mut bytes = report.as_bytes().to_vec();
+    for zone in zones.iter().take(max_zones) {
+        bytes.extend_from_slice(zone_descriptor(zone, sector_shift).as_bytes());
+    }
+    Ok(bytes)
+}
+
+/// Convert a backend zone descriptor to the virtio wire format.
+fn zone_descriptor(zone: &ZoneDescriptor, sector_shift: u32) -> VirtioBlkZoneDescriptor {
+    let mut desc = VirtioBlkZoneDescriptor::new_zeroed();
+    desc.z_cap = zone.capacity << sector_shift;
+    desc.z_start = zone.start << sector_shift;
+    desc.z_wp = zone.write_pointer << sector_shift;
+    desc.z_type = match zone.zone_type {
+        ZoneType::Conventional => VIRTIO_BLK_ZT_CONV,
+        ZoneType::SequentialWriteRequired => VIRTIO_BLK_ZT_SWR,
+        ZoneType::SequentialWritePreferred => VIRTIO_BLK_ZT_SWP,
+    };
+    desc.z_state = match zone.state {
+        ZoneState::NotWritePointer => VIRTIO_BLK_ZS_NOT_WP,
+        ZoneState::Empty => VIRTIO_BLK_ZS_EMPTY,
+        ZoneState::ImplicitlyOpen => VIRTIO_BLK_ZS_IOPEN,
+        ZoneState::ExplicitlyOpen => VIRTIO_BLK_ZS_EOPEN,
+        ZoneState::Closed => VIRTIO_BLK_ZS_CLOSED,
+        ZoneState::ReadOnly => VIRTIO_BLK_ZS_RDONLY,
+        ZoneState::Full => VIRTIO_BLK_ZS_FULL,
+        ZoneState::Offline => VIRTIO_BLK_ZS_OFFLINE,
+    };
+    desc
+}
+
+/// Map a zone operation failure to a virtio-blk status code.
+fn zone_error_status(err: ZoneError) -> u8 {
+    match err {
+        ZoneError::InvalidCommand => VIRTIO_BLK_S_ZONE_INVALID_CMD,
+        ZoneError::UnalignedWritePointer => VIRTIO_BLK_S_ZONE_UNALIGNED_WP,
+        ZoneError::TooManyOpenZones => VIRTIO_BLK_S_ZONE_OPEN_RESOURCE,
+        ZoneError::TooManyActiveZones => VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE,
+        ZoneError::Disk(_) => VIRTIO_BLK_S_IOERR,
+    }
+}
+
+/// Map a write failure to a virtio-blk status code.
+///
+/// Zoned backends fail writes that miss the zone's write pointer with
+/// [`DiskError::InvalidInput`].
+fn write_error_status(disk: &Disk, err: DiskError) -> u8 {
+    match err {
+        DiskError::InvalidInput if disk.zoned().is_some() => VIRTIO_BLK_S_ZONE_UNALIGNED_WP,
+        _ => VIRTIO_BLK_S_IOERR,
+    }
+}
+
 /// Convert a 512-byte virtio sector number to a backend disk sector,
 /// validating alignment for disks with larger native sectors.
 fn virtio_to_disk_sector(
@@ -615,23 +865,34 @@ fn copy_regions(
     Ok(())
 }
 
-/// Perform read or write I/O for a single request.
+/// The data transfer performed by [`do_io`].
+#[derive(Copy, Clone)]
+enum DataOp<'a> {
+    Read,
+    Write,
+    ZoneAppend(&'a dyn ZonedDisk),
+}
+
+/// Perform read, write, or zone append I/O for a single request.
 ///
-/// Issues a single `disk.read_vectored` / `disk.write_vectored` call
-/// for the entire data payload. If the descriptor chain's memory
+/// Issues a single `disk.read_vectored` / `disk.write_vectored` /
+/// `zoned.zone_append` call for the entire data payload. If the descriptor chain's memory
 /// layout is compatible with [`PagedRange`] (all interior boundaries
 /// page-aligned or GPA-contiguous), the IO targets guest memory
 /// directly. Otherwise, a bounce buffer is allocated and data is
 /// copied through it.
 ///
-/// Returns `Ok((data_bytes_written_to_guest, bounced))` on success.
+/// Returns `Ok((data_bytes_written_to_guest, bounced, disk_sector))` on
+/// success, where `disk_sector` is the sector the data was transferred at.
+/// This differs from `start_disk_sector` only for zone appends.
 async fn do_io(
     disk: &Disk,
     mem: &GuestMemory,
     work: &VirtioQueueCallbackWork,
     start_disk_sector: u64,
-    is_read: bool,
-) -> Result<(u32, bool), u8> {
+    op: DataOp<'_>,
+) -> Result<(u32, bool, u64), u8> {
+    let is_read = matches!(op, DataOp::Read);
     let writable = is_read;
     let total_payload = work.get_payload_length(writable);
     let skip_bytes: u64 = if !is_read {
@@ -646,7 +907,7 @@ async fn do_io(
     };
 
     if data_len == 0 {
-        return Ok((0, false));
+        return Ok((0, false, start_disk_sector));
     }
 
     // Cap request size to prevent u32 overflow when reporting bytes_written
@@ -699,22 +960,35 @@ async fn do_io(
         PagedRange::new(io_range.offset, io_range.len, &io_range.gpns).ok_or(VIRTIO_BLK_S_IOERR)?;
     let buffers = RequestBuffers::new(effective_mem, range, is_read);
 
-    if is_read {
-        disk.read_vectored(&buffers, start_disk_sector)
-            .await
-            .map_err(|_| VIRTIO_BLK_S_IOERR)?;
-    } else {
-        disk.write_vectored(&buffers, start_disk_sector, false)
+    let disk_sector = match op {
+        DataOp::Read => {
+            disk.read_vectored(&buffers, start_disk_sector)
+                .await
+                .map_err(|_| VIRTIO_BLK_S_IOERR)?;
+            start_disk_sector
+        }
+        DataOp::Write => {
+            disk.write_vectored(&buffers, start_disk_sector, false)
+                .await
+                .map_err(|err| write_error_status(disk, err))?;
+            start_disk_sector
+        }
+        DataOp::ZoneAppend(zoned) => zoned
+            .zone_append(&buffers, start_disk_sector)
             .await
-            .map_err(|_| VIRTIO_BLK_S_IOERR)?;
-    }
+            .map_err(zone_error_status)?,
+    };
 
     if bounced && is_read {
         let buf = io_mem.as_mut().unwrap().inner_buf_mut().unwrap();
         copy_regions(buf, mem, &regions, false)?;
     }
 
-    Ok((if is_read { io_range.len as u32 } else { 0 }, bounced))
+    Ok((
+        if is_read { io_range.len as u32 } else { 0 },
+        bounced,
+        disk_sector,
+    ))
 }
 
 /// Write the status byte to the last writable byte in the descriptor chain.
@@ -43,6 +43,12 @@ async fn resolve(
             )
             .await?;
 
-        Ok(VirtioBlkDevice::new(input.driver_source, disk.0, resource.read_only).into())
+        Ok(VirtioBlkDevice::new(
+            input.driver_source,
+            disk.0,
+            resource.read_only,
+            resource.num_queues.unwrap_or(1),
+        )
+        .into())
     }
 }
@@ -0,0 +1,862 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Processing of the virtio-gpu 2D control commands.
+
+use guestmem::GuestMemory;
+use guestmem::GuestMemoryError;
+use inspect::Inspect;
+use inspect_counters::Counter;
+use std::collections::HashMap;
+use thiserror::Error;
+use video_core::FramebufferControl;
+use video_core::FramebufferFormat;
+use virtio::spec::gpu::*;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+/// All supported formats use four bytes per pixel.
+const BYTES_PER_PIXEL: u64 = 4;
+
+/// The largest resource the guest may create, enough for a 4K display.
+const MAX_RESOURCE_BYTES: u64 = 64 << 20;
+
+/// The limit on the host memory used by all resources combined.
+const MAX_TOTAL_RESOURCE_BYTES: u64 = 256 << 20;
+
+/// The maximum number of backing entries per resource. Linux attaches one per
+/// page, which covers resources up to 256 MiB.
+pub const MAX_BACKING_ENTRIES: usize = 1 << 16;
+
+#[derive(Debug, Error)]
+enum CommandError {
+    #[error("unsupported command {0:#x}")]
+    Unsupported(u32),
+    #[error("request too short")]
+    Truncated,
+    #[error("invalid resource id {0}")]
+    InvalidResource(u32),
+    #[error("invalid scanout id {0}")]
+    InvalidScanout(u32),
+    #[error("invalid parameter: {0}")]
+    InvalidParameter(&'static str),
+    #[error("resource limit exceeded")]
+    OutOfMemory,
+    #[error("resource backing access failed")]
+    Backing(#[source] GuestMemoryError),
+    #[error("framebuffer access failed")]
+    Framebuffer(#[source] GuestMemoryError),
+}
+
+impl CommandError {
+    fn response_type(&self) -> u32 {
+        match self {
+            CommandError::Unsupported(_)
+            | CommandError::Truncated
+            | CommandError::Backing(_)
+            | CommandError::Framebuffer(_) => VIRTIO_GPU_RESP_ERR_UNSPEC,
+            CommandError::InvalidResource(_) => VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID,
+            CommandError::InvalidScanout(_) => VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID,
+            CommandError::InvalidParameter(_) => VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER,
+            CommandError::OutOfMemory => VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY,
+        }
+    }
+}
+
+enum Response {
+    NoData,
+    DisplayInfo([DisplayOne; VIRTIO_GPU_MAX_SCANOUTS]),
+}
+
+/// A rectangle in pixels, validated against a resource's bounds.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Inspect)]
+struct PixelRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl PixelRect {
+    fn new(rect: &Rect, resource: &Resource2d) -> Result<Self, CommandError> {
+        let r = Self {
+            x: rect.x.get(),
+            y: rect.y.get(),
+            width: rect.width.get(),
+            height: rect.height.get(),
+        };
+        if r.x.checked_add(r.width).is_none_or(|x| x > resource.width)
+            || r.y
+                .checked_add(r.height)
+                .is_none_or(|y| y > resource.height)
+        {
+            return Err(CommandError::InvalidParameter("rect out of bounds"));
+        }
+        Ok(r)
+    }
+
+    fn intersect(&self, other: &Self) -> Option<Self> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        (x < right && y < bottom).then_some(Self {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        })
+    }
+}
+
+/// A host-side 2D resource and the guest memory backing it.
+#[derive(Inspect)]
+struct Resource2d {
+    format: u32,
+    width: u32,
+    height: u32,
+    #[inspect(skip)]
+    data: Vec<u8>,
+    #[inspect(with = "Vec::len")]
+    backing: Vec<(u64, u32)>,
+}
+
+impl Resource2d {
+    fn stride(&self) -> u64 {
+        self.width as u64 * BYTES_PER_PIXEL
+    }
+
+    /// Reads `buf.len()` bytes at `offset` within the guest backing.
+    fn read_backing(
+        &self,
+        mem: &GuestMemory,
+        mut offset: u64,
+        mut buf: &mut [u8],
+    ) -> Result<(), CommandError> {
+        for &(addr, len) in &self.backing {
+            if buf.is_empty() {
+                break;
+            }
+            let len = len as u64;
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+            let n = ((len - offset) as usize).min(buf.len());
+            let (this, rest) = buf.split_at_mut(n);
+            mem.read_at(addr.wrapping_add(offset), this)
+                .map_err(CommandError::Backing)?;
+            buf = rest;
+            offset = 0;
+        }
+        if !buf.is_empty() {
+            return Err(CommandError::InvalidParameter("transfer beyond backing"));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the byte positions of the blue, green, and red channels within a
+/// pixel of `format`, or `None` if the format is not supported.
+fn bgr_offsets(format: u32) -> Option<[usize; 3]> {
+    let offsets = match format {
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM => [0, 1, 2],
+        VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM => [3, 2, 1],
+        VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM | VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM => [2, 1, 0],
+        VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM | VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM => [1, 2, 3],
+        _ => return None,
+    };
+    Some(offsets)
+}
+
+/// Converts a row of pixels to the framebuffer's BGRX layout in place.
+fn convert_row(format: u32, row: &mut [u8]) {
+    let [b, g, r] = bgr_offsets(format).unwrap();
+    if [b, g, r] == [0, 1, 2] {
+        return;
+    }
+    for pixel in row.chunks_exact_mut(BYTES_PER_PIXEL as usize) {
+        let bgr = [pixel[b], pixel[g], pixel[r]];
+        pixel[..3].copy_from_slice(&bgr);
+        pixel[3] = 0;
+    }
+}
+
+#[derive(Inspect)]
+struct Scanout {
+    resource_id: u32,
+    rect: PixelRect,
+}
+
+#[derive(Inspect, Default)]
+struct GpuStats {
+    commands: Counter,
+    errors: Counter,
+    transfers: Counter,
+    flushes: Counter,
+}
+
+/// The state of the GPU's 2D resources and its single scanout.
+#[derive(Inspect)]
+pub struct GpuState {
+    #[inspect(skip)]
+    control: Box<dyn FramebufferControl>,
+    #[inspect(skip)]
+    framebuffer: GuestMemory,
+    #[inspect(hex)]
+    framebuffer_len: u64,
+    preferred_width: u32,
+    preferred_height: u32,
+    #[inspect(iter_by_key)]
+    resources: HashMap<u32, Resource2d>,
+    resource_bytes: u64,
+    scanout: Option<Scanout>,
+    stats: GpuStats,
+}
+
+fn read_body<T: FromBytes + KnownLayout + Immutable>(body: &[u8]) -> Result<T, CommandError> {
+    T::read_from_prefix(body)
+        .map(|(t, _)| t)
+        .map_err(|_| CommandError::Truncated)
+}
+
+impl GpuState {
+    pub fn new(
+        control: Box<dyn FramebufferControl>,
+        framebuffer: GuestMemory,
+        framebuffer_len: u64,
+        preferred_width: u32,
+        preferred_height: u32,
+    ) -> Self {
+        Self {
+            control,
+            framebuffer,
+            framebuffer_len,
+            preferred_width,
+            preferred_height,
+            resources: HashMap::new(),
+            resource_bytes: 0,
+            scanout: None,
+            stats: Default::default(),
+        }
+    }
+
+    /// Releases all resources, as on device reset.
+    pub fn reset(&mut self) {
+        self.resources.clear();
+        self.resource_bytes = 0;
+        self.scanout = None;
+    }
+
+    /// Processes a control request, returning the response to write back to
+    /// the guest.
+    pub async fn process(&mut self, mem: &GuestMemory, request: &[u8]) -> Vec<u8> {
+        self.stats.commands.increment();
+        let Ok((hdr, body)) = CtrlHeader::read_from_prefix(request) else {
+            self.stats.errors.increment();
+            tracelimit::warn_ratelimited!("virtio-gpu request too short");
+            return response_header(&CtrlHeader::new_zeroed(), VIRTIO_GPU_RESP_ERR_UNSPEC)
+                .as_bytes()
+                .to_vec();
+        };
+
+        let cmd = hdr.cmd_type.get();
+        match self.command(mem, cmd, body).await {
+            Ok(Response::NoData) => response_header(&hdr, VIRTIO_GPU_RESP_OK_NODATA)
+                .as_bytes()
+                .to_vec(),
+            Ok(Response::DisplayInfo(pmodes)) => RespDisplayInfo {
+                hdr: response_header(&hdr, VIRTIO_GPU_RESP_OK_DISPLAY_INFO),
+                pmodes,
+            }
+            .as_bytes()
+            .to_vec(),
+            Err(err) => {
+                self.stats.errors.increment();
+                tracelimit::warn_ratelimited!(
+                    cmd,
+                    error = &err as &dyn std::error::Error,
+                    "virtio-gpu command failed"
+                );
+                response_header(&hdr, err.response_type())
+                    .as_bytes()
+                    .to_vec()
+            }
+        }
+    }
+
+    async fn command(
+        &mut self,
+        mem: &GuestMemory,
+        cmd: u32,
+        body: &[u8],
+    ) -> Result<Response, CommandError> {
+        match cmd {
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO => Ok(Response::DisplayInfo(self.display_info())),
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => self.resource_create_2d(read_body(body)?),
+            VIRTIO_GPU_CMD_RESOURCE_UNREF => {
+                self.resource_unref(read_body::<ResourceUnref>(body)?.resource_id.get())
+            }
+            VIRTIO_GPU_CMD_SET_SCANOUT => self.set_scanout(read_body(body)?).await,
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH => self.resource_flush(read_body(body)?),
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => self.transfer_to_host_2d(mem, read_body(body)?),
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => self.attach_backing(body),
+            VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => {
+                let req: ResourceDetachBacking = read_body(body)?;
+                self.resource_mut(req.resource_id.get())?.backing.clear();
+                Ok(Response::NoData)
+            }
+            cmd => Err(CommandError::Unsupported(cmd)),
+        }
+    }
+
+    fn resource(&self, id: u32) -> Result<&Resource2d, CommandError> {
+        self.resources
+            .get(&id)
+            .ok_or(CommandError::InvalidResource(id))
+    }
+
+    fn resource_mut(&mut self, id: u32) -> Result<&mut Resource2d, CommandError> {
+        self.resources
+            .get_mut(&id)
+            .ok_or(CommandError::InvalidResource(id))
+    }
+
+    fn display_info(&self) -> [DisplayOne; VIRTIO_GPU_MAX_SCANOUTS] {
+        let mut pmodes = [DisplayOne::new_zeroed(); VIRTIO_GPU_MAX_SCANOUTS];
+        pmodes[0] = DisplayOne {
+            r: Rect {
+                x: 0.into(),
+                y: 0.into(),
+                width: self.preferred_width.into(),
+                height: self.preferred_height.into(),
+            },
+            enabled: 1.into(),
+            flags: 0.into(),
+        };
+        pmodes
+    }
+
+    fn resource_create_2d(&mut self, req: ResourceCreate2d) -> Result<Response, CommandError> {
+        let id = req.resource_id.get();
+        let format = req.format.get();
+        let width = req.width.get();
+        let height = req.height.get();
+        if id == 0 || self.resources.contains_key(&id) {
+            return Err(CommandError::InvalidResource(id));
+        }
+        if bgr_offsets(format).is_none() {
+            return Err(CommandError::InvalidParameter("unsupported format"));
+        }
+        if width == 0 || height == 0 {
+            return Err(CommandError::InvalidParameter("empty resource"));
+        }
+        let len = width as u64 * height as u64 * BYTES_PER_PIXEL;
+        if len > MAX_RESOURCE_BYTES || self.resource_bytes + len > MAX_TOTAL_RESOURCE_BYTES {
+            return Err(CommandError::OutOfMemory);
+        }
+        self.resource_bytes += len;
+        self.resources.insert(
+            id,
+            Resource2d {
+                format,
+                width,
+                height,
+                data: vec![0; len as usize],
+                backing: Vec::new(),
+            },
+        );
+        Ok(Response::NoData)
+    }
+
+    fn resource_unref(&mut self, id: u32) -> Result<Response, CommandError> {
+        let resource = self
+            .resources
+            .remove(&id)
+            .ok_or(CommandError::InvalidResource(id))?;
+        self.resource_bytes -= resource.data.len() as u64;
+        if self.scanout.as_ref().is_some_and(|s| s.resource_id == id) {
+            self.scanout = None;
+        }
+        Ok(Response::NoData)
+    }
+
+    async fn set_scanout(&mut self, req: SetScanout) -> Result<Response, CommandError> {
+        let scanout_id = req.scanout_id.get();
+        if scanout_id != 0 {
+            return Err(CommandError::InvalidScanout(scanout_id));
+        }
+        let resource_id = req.resource_id.get();
+        if resource_id == 0 {
+            // The guest disabled the display.
+            self.scanout = None;
+            return Ok(Response::NoData);
+        }
+        let rect = PixelRect::new(&req.r, self.resource(resource_id)?)?;
+        if rect.width == 0 || rect.height == 0 {
+            return Err(CommandError::InvalidParameter("empty scanout"));
+        }
+        if rect.width as u64 * rect.height as u64 * BYTES_PER_PIXEL > self.framebuffer_len {
+            return Err(CommandError::InvalidParameter(
+                "scanout larger than the framebuffer",
+            ));
+        }
+        // Publishing the new size lets the VNC server send its clients a
+        // DesktopSize update.
+        let format = FramebufferFormat {
+            width: rect.width as usize,
+            height: rect.height as usize,
+            bytes_per_line: rect.width as usize * BYTES_PER_PIXEL as usize,
+            offset: 0,
+        };
+        if self.scanout.as_ref().is_none_or(|s| s.rect != rect) {
+            tracing::debug!(width = rect.width, height = rect.height, "scanout changed");
+        }
+        self.control.set_format(format).await;
+        self.scanout = Some(Scanout { resource_id, rect });
+        Ok(Response::NoData)
+    }
+
+    fn transfer_to_host_2d(
+        &mut self,
+        mem: &GuestMemory,
+        req: TransferToHost2d,
+    ) -> Result<Response, CommandError> {
+        self.stats.transfers.increment();
+        let resource = self.resource(req.resource_id.get())?;
+        let rect = PixelRect::new(&req.r, resource)?;
+        let stride = resource.stride();
+        let row_len = rect.width as u64 * BYTES_PER_PIXEL;
+        // Read the rows into a scratch buffer first, since the backing and
+        // the resource data cannot both be borrowed from `self`.
+        let mut rows = vec![0; (row_len * rect.height as u64) as usize];
+        for (h, row) in rows.chunks_exact_mut(row_len as usize).enumerate() {
+            let src = req.offset.get().wrapping_add(h as u64 * stride);
+            resource.read_backing(mem, src, row)?;
+        }
+        let resource = self.resource_mut(req.resource_id.get())?;
+        for (h, row) in rows.chunks_exact(row_len as usize).enumerate() {
+            let dst = (rect.y as u64 + h as u64) * stride + rect.x as u64 * BYTES_PER_PIXEL;
+            resource.data[dst as usize..][..row.len()].copy_from_slice(row);
+        }
+        Ok(Response::NoData)
+    }
+
+    fn resource_flush(&mut self, req: ResourceFlush) -> Result<Response, CommandError> {
+        self.stats.flushes.increment();
+        let resource_id = req.resource_id.get();
+        let resource = self.resource(resource_id)?;
+        let rect = PixelRect::new(&req.r, resource)?;
+        let Some(scanout) = self
+            .scanout
+            .as_ref()
+            .filter(|s| s.resource_id == resource_id)
+        else {
+            // The resource is not being displayed.
+            return Ok(Response::NoData);
+        };
+        let Some(dirty) = rect.intersect(&scanout.rect) else {
+            return Ok(Response::NoData);
+        };
+
+        let stride = resource.stride();
+        let row_len = (dirty.width as u64 * BYTES_PER_PIXEL) as usize;
+        let mut row = vec![0; row_len];
+        for y in dirty.y..dirty.y + dirty.height {
+            let src = y as u64 * stride + dirty.x as u64 * BYTES_PER_PIXEL;
+            row.copy_from_slice(&resource.data[src as usize..][..row_len]);
+            convert_row(resource.format, &mut row);
+            let dst = (y - scanout.rect.y) as u64 * scanout.rect.width as u64 * BYTES_PER_PIXEL
+                + (dirty.x - scanout.rect.x) as u64 * BYTES_PER_PIXEL;
+            self.framebuffer
+                .write_at(dst, &row)
+                .map_err(CommandError::Framebuffer)?;
+        }
+        Ok(Response::NoData)
+    }
+
+    fn attach_backing(&mut self, body: &[u8]) -> Result<Response, CommandError> {
+        let (req, entries) =
+            ResourceAttachBacking::read_from_prefix(body).map_err(|_| CommandError::Truncated)?;
+        let nr_entries = req.nr_entries.get() as usize;
+        if nr_entries > MAX_BACKING_ENTRIES {
+            return Err(CommandError::InvalidParameter("too many backing entries"));
+        }
+        let (entries, _) = <[MemEntry]>::ref_from_prefix_with_elems(entries, nr_entries)
+            .map_err(|_| CommandError::Truncated)?;
+        let resource = self.resource_mut(req.resource_id.get())?;
+        resource.backing = entries
+            .iter()
+            .map(|e| (e.addr.get(), e.length.get()))
+            .collect();
+        Ok(Response::NoData)
+    }
+}
+
+/// Builds a response header for request `hdr`, echoing its fence.
+fn response_header(hdr: &CtrlHeader, cmd_type: u32) -> CtrlHeader {
+    // Commands complete synchronously, so the fence has been reached by the
+    // time the response is written.
+    let flags = hdr.flags.get() & VIRTIO_GPU_FLAG_FENCE;
+    CtrlHeader {
+        cmd_type: cmd_type.into(),
+        flags: flags.into(),
+        fence_id: if flags != 0 { hdr.fence_id } else { 0.into() },
+        ctx_id: hdr.ctx_id,
+        ring_idx: hdr.ring_idx,
+        padding: [0; 3],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pal_async::async_test;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use test_with_tracing::test;
+
+    const WIDTH: u32 = 8;
+    const HEIGHT: u32 = 4;
+    const BACKING_ADDR: u64 = 0x1000;
+    const FRAMEBUFFER_LEN: usize = 0x1000;
+
+    struct TestFramebuffer(Arc<Mutex<Vec<FramebufferFormat>>>);
+
+    #[async_trait::async_trait]
+    impl FramebufferControl for TestFramebuffer {
+        async fn map(&mut self, _gpa: u64) {}
+        async fn unmap(&mut self) {}
+        async fn set_format(&mut self, format: FramebufferFormat) {
+            self.0.lock().push(format);
+        }
+    }
+
+    struct Harness {
+        gpu: GpuState,
+        mem: GuestMemory,
+        framebuffer: GuestMemory,
+        formats: Arc<Mutex<Vec<FramebufferFormat>>>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let formats = Arc::new(Mutex::new(Vec::new()));
+            let framebuffer = GuestMemory::allocate(FRAMEBUFFER_LEN);
+            Self {
+                gpu: GpuState::new(
+                    Box::new(TestFramebuffer(formats.clone())),
+                    framebuffer.clone(),
+                    FRAMEBUFFER_LEN as u64,
+                    1024,
+                    768,
+                ),
+                mem: GuestMemory::allocate(0x10000),
+                framebuffer,
+                formats,
+            }
+        }
+
+        /// Sends a command, returning the response.
+        async fn send(&mut self, cmd: u32, body: &[u8]) -> Vec<u8> {
+            let hdr = CtrlHeader {
+                cmd_type: cmd.into(),
+                ..CtrlHeader::new_zeroed()
+            };
+            let request = [hdr.as_bytes(), body].concat();
+            self.gpu.process(&self.mem, &request).await
+        }
+
+        /// Sends a command, returning the response type.
+        async fn command(&mut self, cmd: u32, body: &[u8]) -> u32 {
+            let response = self.send(cmd, body).await;
+            CtrlHeader::read_from_prefix(&response)
+                .unwrap()
+                .0
+                .cmd_type
+                .get()
+        }
+
+        /// Creates resource 1 with guest backing holding `pixels`, and
+        /// transfers it to the host.
+        async fn create_resource(&mut self, format: u32, pixels: &[u8]) {
+            let create = ResourceCreate2d {
+                resource_id: 1.into(),
+                format: format.into(),
+                width: WIDTH.into(),
+                height: HEIGHT.into(),
+            };
+            assert_eq!(
+                self.command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, create.as_bytes())
+                    .await,
+                VIRTIO_GPU_RESP_OK_NODATA
+            );
+
+            // Split the backing across two entries to exercise the walk.
+            self.mem.write_at(BACKING_ADDR, &pixels[..40]).unwrap();
+            self.mem
+                .write_at(BACKING_ADDR + 0x800, &pixels[40..])
+                .unwrap();
+            let attach = ResourceAttachBacking {
+                resource_id: 1.into(),
+                nr_entries: 2.into(),
+            };
+            let entries = [
+                MemEntry {
+                    addr: BACKING_ADDR.into(),
+                    length: 40.into(),
+                    padding: 0.into(),
+                },
+                MemEntry {
+                    addr: (BACKING_ADDR + 0x800).into(),
+                    length: (pixels.len() as u32 - 40).into(),
+                    padding: 0.into(),
+                },
+            ];
+            assert_eq!(
+                self.command(
+                    VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
+                    &[attach.as_bytes(), entries.as_bytes()].concat()
+                )
+                .await,
+                VIRTIO_GPU_RESP_OK_NODATA
+            );
+
+            let transfer = TransferToHost2d {
+                r: rect(0, 0, WIDTH, HEIGHT),
+                offset: 0.into(),
+                resource_id: 1.into(),
+                padding: 0.into(),
+            };
+            assert_eq!(
+                self.command(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D, transfer.as_bytes())
+                    .await,
+                VIRTIO_GPU_RESP_OK_NODATA
+            );
+        }
+
+        async fn set_scanout(&mut self, resource_id: u32, r: Rect) -> u32 {
+            let scanout = SetScanout {
+                r,
+                scanout_id: 0.into(),
+                resource_id: resource_id.into(),
+            };
+            self.command(VIRTIO_GPU_CMD_SET_SCANOUT, scanout.as_bytes())
+                .await
+        }
+
+        async fn flush(&mut self, r: Rect) -> u32 {
+            let flush = ResourceFlush {
+                r,
+                resource_id: 1.into(),
+                padding: 0.into(),
+            };
+            self.command(VIRTIO_GPU_CMD_RESOURCE_FLUSH, flush.as_bytes())
+                .await
+        }
+
+        fn framebuffer_pixel(&self, index: u64) -> [u8; 4] {
+            self.framebuffer
+                .read_plain(index * BYTES_PER_PIXEL)
+                .unwrap()
+        }
+    }
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            x: x.into(),
+            y: y.into(),
+            width: width.into(),
+            height: height.into(),
+        }
+    }
+
+    /// Returns pixels whose first three bytes are the pixel index and two
+    /// marker values, so that channel reordering can be checked.
+    fn test_pixels() -> Vec<u8> {
+        (0..WIDTH * HEIGHT)
+            .flat_map(|i| [i as u8, 0x80, 0xc0, 0xff])
+            .collect()
+    }
+
+    #[async_test]
+    async fn display_info() {
+        let mut h = Harness::new();
+        let response = h.send(VIRTIO_GPU_CMD_GET_DISPLAY_INFO, &[]).await;
+        let info = RespDisplayInfo::read_from_bytes(&response).unwrap();
+        assert_eq!(info.hdr.cmd_type.get(), VIRTIO_GPU_RESP_OK_DISPLAY_INFO);
+        assert_eq!(info.pmodes[0].enabled.get(), 1);
+        assert_eq!(info.pmodes[0].r, rect(0, 0, 1024, 768));
+        assert_eq!(info.pmodes[1].enabled.get(), 0);
+    }
+
+    #[async_test]
+    async fn scanout_and_flush() {
+        let mut h = Harness::new();
+        h.create_resource(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, &test_pixels())
+            .await;
+        assert_eq!(
+            h.set_scanout(1, rect(0, 0, WIDTH, HEIGHT)).await,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(
+            h.formats.lock().as_slice(),
+            &[FramebufferFormat {
+                width: WIDTH as usize,
+                height: HEIGHT as usize,
+                bytes_per_line: (WIDTH * 4) as usize,
+                offset: 0,
+            }]
+        );
+
+        // Only the flushed region reaches the framebuffer.
+        assert_eq!(h.flush(rect(1, 1, 2, 1)).await, VIRTIO_GPU_RESP_OK_NODATA);
+        assert_eq!(h.framebuffer_pixel(9), [9, 0x80, 0xc0, 0xff]);
+        assert_eq!(h.framebuffer_pixel(10), [10, 0x80, 0xc0, 0xff]);
+        assert_eq!(h.framebuffer_pixel(8), [0; 4]);
+        assert_eq!(h.framebuffer_pixel(11), [0; 4]);
+
+        assert_eq!(
+            h.flush(rect(0, 0, WIDTH, HEIGHT)).await,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(h.framebuffer_pixel(31), [31, 0x80, 0xc0, 0xff]);
+    }
+
+    #[async_test]
+    async fn scanout_offset_within_resource() {
+        let mut h = Harness::new();
+        h.create_resource(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, &test_pixels())
+            .await;
+        assert_eq!(
+            h.set_scanout(1, rect(2, 1, 4, 2)).await,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(
+            h.flush(rect(0, 0, WIDTH, HEIGHT)).await,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        // Scanout pixel (0, 0) is resource pixel (2, 1).
+        assert_eq!(h.framebuffer_pixel(0)[0], 10);
+        // Scanout pixel (3, 1) is resource pixel (5, 2).
+        assert_eq!(h.framebuffer_pixel(7)[0], 21);
+        assert_eq!(h.framebuffer_pixel(8), [0; 4]);
+    }
+
+    #[async_test]
+    async fn converts_rgbx() {
+        let mut h = Harness::new();
+        h.create_resource(VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM, &test_pixels())
+            .await;
+        h.set_scanout(1, rect(0, 0, WIDTH, HEIGHT)).await;
+        h.flush(rect(0, 0, WIDTH, HEIGHT)).await;
+        assert_eq!(h.framebuffer_pixel(5), [0xc0, 0x80, 5, 0]);
+    }
+
+    #[async_test]
+    async fn invalid_resource_echoes_fence() {
+        let mut h = Harness::new();
+        let hdr = CtrlHeader {
+            cmd_type: VIRTIO_GPU_CMD_RESOURCE_FLUSH.into(),
+            flags: VIRTIO_GPU_FLAG_FENCE.into(),
+            fence_id: 42.into(),
+            ..CtrlHeader::new_zeroed()
+        };
+        let flush = ResourceFlush {
+            r: rect(0, 0, 1, 1),
+            resource_id: 7.into(),
+            padding: 0.into(),
+        };
+        let response = h
+            .gpu
+            .process(&h.mem, &[hdr.as_bytes(), flush.as_bytes()].concat())
+            .await;
+        let response = CtrlHeader::read_from_bytes(&response).unwrap();
+        assert_eq!(
+            response.cmd_type.get(),
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+        assert_eq!(response.flags.get(), VIRTIO_GPU_FLAG_FENCE);
+        assert_eq!(response.fence_id.get(), 42);
+    }
+
+    #[async_test]
+    async fn rejects_invalid_requests() {
+        let mut h = Harness::new();
+        h.create_resource(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, &test_pixels())
+            .await;
+
+        // Out of bounds of the resource.
+        assert_eq!(
+            h.set_scanout(1, rect(1, 0, WIDTH, HEIGHT)).await,
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        // Duplicate ID.
+        let create = ResourceCreate2d {
+            resource_id: 1.into(),
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM.into(),
+            width: 1.into(),
+            height: 1.into(),
+        };
+        assert_eq!(
+            h.command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, create.as_bytes())
+                .await,
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+        // Larger than the framebuffer.
+        let create = ResourceCreate2d {
+            resource_id: 2.into(),
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM.into(),
+            width: 64.into(),
+            height: 64.into(),
+        };
+        assert_eq!(
+            h.command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, create.as_bytes())
+                .await,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(
+            h.set_scanout(2, rect(0, 0, 64, 64)).await,
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        // Too large to allocate.
+        let create = ResourceCreate2d {
+            resource_id: 3.into(),
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM.into(),
+            width: 65536.into(),
+            height: 65536.into(),
+        };
+        assert_eq!(
+            h.command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, create.as_bytes())
+                .await,
+            VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY
+        );
+        assert_eq!(
+            h.command(VIRTIO_GPU_CMD_GET_CAPSET_INFO, &[0; 8]).await,
+            VIRTIO_GPU_RESP_ERR_UNSPEC
+        );
+        assert!(h.formats.lock().is_empty());
+    }
+
+    #[async_test]
+    async fn unref_disables_scanout() {
+        let mut h = Harness::new();
+        h.create_resource(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, &test_pixels())
+            .await;
+        h.set_scanout(1, rect(0, 0, WIDTH, HEIGHT)).await;
+        let unref = ResourceUnref {
+            resource_id: 1.into(),
+            padding: 0.into(),
+        };
+        assert_eq!(
+            h.command(VIRTIO_GPU_CMD_RESOURCE_UNREF, unref.as_bytes())
+                .await,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert!(h.gpu.scanout.is_none());
+        assert_eq!(h.gpu.resource_bytes, 0);
+    }
+}
@@ -0,0 +1,379 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Virtio GPU device implementation.
+//!
+//! Implements the 2D subset of the virtio-gpu device (device ID 16) as
+//! specified in the VIRTIO 1.2 specification, §5.7 "GPU Device". The guest
+//! renders into host-side resources, and the resource attached to the single
+//! scanout is copied into the shared framebuffer on flush, so that the VNC
+//! server and screenshots see the guest's display.
+
+#![expect(missing_docs)]
+#![forbid(unsafe_code)]
+
+mod commands;
+pub mod resolver;
+
+use anyhow::Context as _;
+use commands::GpuState;
+use commands::MAX_BACKING_ENTRIES;
+use futures::StreamExt;
+use guestmem::GuestMemory;
+use inspect::Inspect;
+use inspect::InspectMut;
+use inspect_counters::Counter;
+use pal_async::wait::PolledWait;
+use task_control::AsyncRun;
+use task_control::Cancelled;
+use task_control::InspectTaskMut;
+use task_control::StopTask;
+use task_control::TaskControl;
+use video_core::FramebufferControl;
+use virtio::DeviceTraits;
+use virtio::DeviceTraitsSharedMemory;
+use virtio::QueueResources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use virtio::queue::QueueState;
+use virtio::spec::VirtioDeviceFeatures;
+use virtio::spec::gpu::*;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::FromBytes;
+use zerocopy::IntoBytes;
+
+/// The queue carrying 2D commands.
+const CONTROL_QUEUE: u16 = 0;
+/// The queue carrying cursor updates.
+const CURSOR_QUEUE: u16 = 1;
+
+/// The largest control request, an attach-backing command with the maximum
+/// number of entries.
+const MAX_REQUEST_SIZE: usize = size_of::<CtrlHeader>()
+    + size_of::<ResourceAttachBacking>()
+    + MAX_BACKING_ENTRIES * size_of::<MemEntry>();
+
+/// The virtio-gpu device.
+#[derive(InspectMut)]
+pub struct VirtioGpuDevice {
+    #[inspect(skip)]
+    driver: VmTaskDriver,
+    #[inspect(hex)]
+    events_read: u32,
+    #[inspect(mut)]
+    control_worker: TaskControl<ControlWorker, ControlQueue>,
+    #[inspect(mut)]
+    cursor_worker: TaskControl<CursorWorker, CursorQueue>,
+}
+
+impl VirtioGpuDevice {
+    /// Creates a new device that displays into the framebuffer `vram`, of
+    /// `vram_len` bytes, and reports its size changes through `control`.
+    ///
+    /// `width` and `height` are offered to the guest as the preferred
+    /// display mode.
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        control: Box<dyn FramebufferControl>,
+        vram: GuestMemory,
+        vram_len: u64,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        Self {
+            driver: driver_source.simple(),
+            events_read: 0,
+            control_worker: TaskControl::new(ControlWorker {
+                gpu: GpuState::new(control, vram, vram_len, width, height),
+            }),
+            cursor_worker: TaskControl::new(CursorWorker {
+                cursor: Default::default(),
+                stats: Default::default(),
+            }),
+        }
+    }
+
+    fn config(&self) -> Config {
+        Config {
+            events_read: self.events_read.into(),
+            events_clear: 0.into(),
+            num_scanouts: 1.into(),
+            num_capsets: 0.into(),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioGpuDevice {
+    fn traits(&self) -> DeviceTraits {
+        DeviceTraits {
+            device_id: virtio::spec::VirtioDeviceType::GPU,
+            device_features: VirtioDeviceFeatures::new()
+                .with_ring_event_idx(true)
+                .with_ring_indirect_desc(true)
+                .with_ring_packed(true),
+            max_queues: 2,
+            device_register_length: size_of::<Config>() as u32,
+            shared_memory: DeviceTraitsSharedMemory::default(),
+        }
+    }
+
+    async fn read_registers_u32(&mut self, offset: u16) -> u32 {
+        let config = self.config();
+        let offset = offset as usize;
+        config
+            .as_bytes()
+            .get(offset..offset + 4)
+            .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    async fn write_registers_u32(&mut self, offset: u16, val: u32) {
+        if offset as usize == std::mem::offset_of!(Config, events_clear) {
+            self.events_read &= !val;
+        }
+    }
+
+    async fn start_queue(
+        &mut self,
+        idx: u16,
+        resources: QueueResources,
+        features: &VirtioDeviceFeatures,
+        initial_state: Option<QueueState>,
+    ) -> anyhow::Result<()> {
+        let queue_event = PolledWait::new(&self.driver, resources.event)
+            .context("failed to create polled wait")?;
+        let queue = VirtioQueue::new(
+            *features,
+            resources.params,
+            resources.guest_memory.clone(),
+            resources.notify,
+            queue_event,
+            initial_state,
+        )
+        .context("failed to create virtio queue")?;
+
+        match idx {
+            CONTROL_QUEUE => {
+                self.control_worker.insert(
+                    self.driver.clone(),
+                    "virtio-gpu-control",
+                    ControlQueue {
+                        queue,
+                        mem: resources.guest_memory,
+                    },
+                );
+                self.control_worker.start();
+            }
+            CURSOR_QUEUE => {
+                self.cursor_worker.insert(
+                    self.driver.clone(),
+                    "virtio-gpu-cursor",
+                    CursorQueue {
+                        queue,
+                        mem: resources.guest_memory,
+                    },
+                );
+                self.cursor_worker.start();
+            }
+            _ => anyhow::bail!("invalid queue index {idx}"),
+        }
+        Ok(())
+    }
+
+    async fn stop_queue(&mut self, idx: u16) -> Option<QueueState> {
+        match idx {
+            CONTROL_QUEUE => {
+                if !self.control_worker.has_state() {
+                    return None;
+                }
+                self.control_worker.stop().await;
+                Some(self.control_worker.remove().queue.queue_state())
+            }
+            CURSOR_QUEUE => {
+                if !self.cursor_worker.has_state() {
+                    return None;
+                }
+                self.cursor_worker.stop().await;
+                Some(self.cursor_worker.remove().queue.queue_state())
+            }
+            _ => None,
+        }
+    }
+
+    async fn reset(&mut self) {
+        self.events_read = 0;
+        self.control_worker.task_mut().gpu.reset();
+        self.cursor_worker.task_mut().cursor = Default::default();
+    }
+}
+
+/// Control queue worker, which owns the GPU resources so that they survive
+/// queue restarts.
+#[derive(InspectMut)]
+struct ControlWorker {
+    gpu: GpuState,
+}
+
+/// Transient control queue state, created in `start_queue` and removed in
+/// `stop_queue`.
+#[derive(InspectMut)]
+struct ControlQueue {
+    queue: VirtioQueue,
+    mem: GuestMemory,
+}
+
+impl InspectTaskMut<ControlQueue> for ControlWorker {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>, state: Option<&mut ControlQueue>) {
+        req.respond().merge(self).merge(state);
+    }
+}
+
+impl AsyncRun<ControlQueue> for ControlWorker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut ControlQueue,
+    ) -> Result<(), Cancelled> {
+        loop {
+            let work = stop.until_stopped(state.queue.next()).await?;
+            let Some(work) = work else { break };
+            let work = match work {
+                Ok(work) => work,
+                Err(err) => {
+                    tracelimit::error_ratelimited!(
+                        err = &err as &dyn std::error::Error,
+                        "queue error"
+                    );
+                    break;
+                }
+            };
+            let len = (work.get_payload_length(false) as usize).min(MAX_REQUEST_SIZE);
+            let mut request = vec![0; len];
+            if let Err(err) = work.read(&state.mem, &mut request) {
+                tracelimit::error_ratelimited!(
+                    err = &err as &dyn std::error::Error,
+                    "failed to read gpu request"
+                );
+                state.queue.complete(work, 0);
+                continue;
+            }
+            let response = self.gpu.process(&state.mem, &request).await;
+            let len = match work.write(&state.mem, &response) {
+                Ok(()) => response.len() as u32,
+                Err(err) => {
+                    tracelimit::error_ratelimited!(
+                        err = &err as &dyn std::error::Error,
+                        "failed to write gpu response"
+                    );
+                    0
+                }
+            };
+            state.queue.complete(work, len);
+        }
+        Ok(())
+    }
+}
+
+/// The guest's hardware cursor. It is tracked for diagnostics only: VNC
+/// clients draw their own cursor, so it is not composed into the
+/// framebuffer.
+#[derive(Inspect, Default)]
+struct Cursor {
+    resource_id: u32,
+    x: u32,
+    y: u32,
+    hot_x: u32,
+    hot_y: u32,
+}
+
+/// Cursor queue worker.
+#[derive(InspectMut)]
+struct CursorWorker {
+    cursor: Cursor,
+    stats: CursorStats,
+}
+
+#[derive(Inspect, Default)]
+struct CursorStats {
+    updates: Counter,
+    moves: Counter,
+}
+
+#[derive(InspectMut)]
+struct CursorQueue {
+    queue: VirtioQueue,
+    mem: GuestMemory,
+}
+
+impl InspectTaskMut<CursorQueue> for CursorWorker {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>, state: Option<&mut CursorQueue>) {
+        req.respond().merge(self).merge(state);
+    }
+}
+
+impl CursorWorker {
+    fn update(&mut self, hdr: &CtrlHeader, cmd: &UpdateCursor) {
+        if cmd.pos.scanout_id.get() != 0 {
+            return;
+        }
+        self.cursor.x = cmd.pos.x.get();
+        self.cursor.y = cmd.pos.y.get();
+        match hdr.cmd_type.get() {
+            VIRTIO_GPU_CMD_UPDATE_CURSOR => {
+                self.stats.updates.increment();
+                self.cursor.resource_id = cmd.resource_id.get();
+                self.cursor.hot_x = cmd.hot_x.get();
+                self.cursor.hot_y = cmd.hot_y.get();
+            }
+            VIRTIO_GPU_CMD_MOVE_CURSOR => {
+                self.stats.moves.increment();
+            }
+            cmd => {
+                tracelimit::warn_ratelimited!(cmd, "unsupported cursor command");
+            }
+        }
+    }
+}
+
+impl AsyncRun<CursorQueue> for CursorWorker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut CursorQueue,
+    ) -> Result<(), Cancelled> {
+        loop {
+            let work = stop.until_stopped(state.queue.next()).await?;
+            let Some(work) = work else { break };
+            match work {
+                Ok(work) => {
+                    // Cursor commands get no response.
+                    let mut request = [0; size_of::<CtrlHeader>() + size_of::<UpdateCursor>()];
+                    match work.read(&state.mem, &mut request) {
+                        Ok(n) if n == request.len() => {
+                            let (hdr, cmd) = CtrlHeader::read_from_prefix(&request).unwrap();
+                            self.update(&hdr, &UpdateCursor::read_from_bytes(cmd).unwrap());
+                        }
+                        Ok(_) => {
+                            tracelimit::warn_ratelimited!("short cursor command");
+                        }
+                        Err(err) => {
+                            tracelimit::error_ratelimited!(
+                                err = &err as &dyn std::error::Error,
+                                "failed to read cursor command"
+                            );
+                        }
+                    }
+                    state.queue.complete(work, 0);
+                }
+                Err(err) => {
+                    tracelimit::error_ratelimited!(
+                        err = &err as &dyn std::error::Error,
+                        "queue error"
+                    );
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
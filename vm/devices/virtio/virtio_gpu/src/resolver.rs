@@ -0,0 +1,52 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Defines the resource resolver for virtio-gpu devices.
+
+use crate::VirtioGpuDevice;
+use anyhow::Context as _;
+use async_trait::async_trait;
+use virtio::resolve::ResolvedVirtioDevice;
+use virtio::resolve::VirtioResolveInput;
+use virtio_resources::gpu::VirtioGpuHandle;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::VirtioDeviceHandle;
+
+/// Resolver for virtio-gpu devices.
+pub struct VirtioGpuResolver;
+
+declare_static_async_resolver! {
+    VirtioGpuResolver,
+    (VirtioDeviceHandle, VirtioGpuHandle),
+}
+
+#[async_trait]
+impl AsyncResolveResource<VirtioDeviceHandle, VirtioGpuHandle> for VirtioGpuResolver {
+    type Output = ResolvedVirtioDevice;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        resource: VirtioGpuHandle,
+        input: VirtioResolveInput<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let control = resolver
+            .resolve(resource.framebuffer, ())
+            .await
+            .context("failed to resolve framebuffer")?
+            .0;
+        let (vram, vram_len) = control.vram().context("failed to map framebuffer memory")?;
+        Ok(VirtioGpuDevice::new(
+            input.driver_source,
+            control,
+            vram,
+            vram_len as u64,
+            resource.width,
+            resource.height,
+        )
+        .into())
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Translation of PC scancodes to Linux evdev key codes.
+
+/// Linux key codes for extended (`E0`-prefixed) scancodes.
+const EXTENDED_KEYS: &[(u8, u16)] = &[
+    (0x10, 165), // KEY_PREVIOUSSONG
+    (0x19, 163), // KEY_NEXTSONG
+    (0x1c, 96),  // KEY_KPENTER
+    (0x1d, 97),  // KEY_RIGHTCTRL
+    (0x20, 113), // KEY_MUTE
+    (0x22, 164), // KEY_PLAYPAUSE
+    (0x24, 166), // KEY_STOPCD
+    (0x2e, 114), // KEY_VOLUMEDOWN
+    (0x30, 115), // KEY_VOLUMEUP
+    (0x35, 98),  // KEY_KPSLASH
+    (0x37, 99),  // KEY_SYSRQ
+    (0x38, 100), // KEY_RIGHTALT
+    (0x46, 119), // KEY_PAUSE (Ctrl+Break)
+    (0x47, 102), // KEY_HOME
+    (0x48, 103), // KEY_UP
+    (0x49, 104), // KEY_PAGEUP
+    (0x4b, 105), // KEY_LEFT
+    (0x4d, 106), // KEY_RIGHT
+    (0x4f, 107), // KEY_END
+    (0x50, 108), // KEY_DOWN
+    (0x51, 109), // KEY_PAGEDOWN
+    (0x52, 110), // KEY_INSERT
+    (0x53, 111), // KEY_DELETE
+    (0x5b, 125), // KEY_LEFTMETA
+    (0x5c, 126), // KEY_RIGHTMETA
+    (0x5d, 127), // KEY_COMPOSE
+    (0x5e, 116), // KEY_POWER
+    (0x5f, 142), // KEY_SLEEP
+    (0x63, 143), // KEY_WAKEUP
+];
+
+/// Linux key codes for non-extended scancodes outside the range where the
+/// two coincide.
+const OTHER_KEYS: &[(u8, u16)] = &[
+    (0x54, 99),  // KEY_SYSRQ (Alt+Print Screen)
+    (0x56, 86),  // KEY_102ND
+    (0x57, 87),  // KEY_F11
+    (0x58, 88),  // KEY_F12
+    (0x70, 93),  // KEY_KATAKANAHIRAGANA
+    (0x73, 89),  // KEY_RO
+    (0x79, 92),  // KEY_HENKAN
+    (0x7b, 94),  // KEY_MUHENKAN
+    (0x7d, 124), // KEY_YEN
+];
+
+/// `KEY_PAUSE`, sent as the `E1 1D` sequence.
+const KEY_PAUSE: u16 = 119;
+
+/// Converts a scancode set 1 make code, with any `E0` or `E1` prefix in the
+/// high byte as in [`input_core::KeyboardData::code`], to a Linux key code.
+///
+/// Returns `None` for scancodes with no equivalent key.
+pub fn linux_key_code(scancode: u16) -> Option<u16> {
+    let code = (scancode & 0x7f) as u8;
+    let lookup = |table: &[(u8, u16)]| {
+        table
+            .iter()
+            .find_map(|&(sc, key)| (sc == code).then_some(key))
+    };
+    match scancode >> 8 {
+        // Through F10 and the keypad, the Linux key codes are the set 1
+        // scancodes.
+        0 if (0x01..=0x53).contains(&code) => Some(code.into()),
+        0 => lookup(OTHER_KEYS),
+        0xe0 => lookup(EXTENDED_KEYS),
+        0xe1 if code == 0x1d => Some(KEY_PAUSE),
+        _ => None,
+    }
+}
+
+/// Returns every Linux key code that [`linux_key_code`] can produce.
+pub fn supported_keys() -> impl Iterator<Item = u16> {
+    (0x01..=0x53)
+        .chain(OTHER_KEYS.iter().map(|&(_, key)| key))
+        .chain(EXTENDED_KEYS.iter().map(|&(_, key)| key))
+}
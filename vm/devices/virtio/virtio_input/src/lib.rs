@@ -0,0 +1,824 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Virtio input device implementation.
+//!
+//! Implements the virtio-input device (device ID 18) as specified in the
+//! VIRTIO 1.2 specification, §5.8 "Input Device". The device exposes either a
+//! keyboard or an absolute pointing device (tablet) to the guest, fed by an
+//! `input_core` input source, and reports input as Linux evdev events.
+
+#![expect(missing_docs)]
+#![forbid(unsafe_code)]
+
+mod keymap;
+pub mod resolver;
+
+use anyhow::Context as _;
+use futures::StreamExt;
+use guestmem::GuestMemory;
+use input_core::InputSource;
+use input_core::KeyboardData;
+use input_core::MouseData;
+use inspect::Inspect;
+use inspect::InspectMut;
+use inspect_counters::Counter;
+use pal_async::wait::PolledWait;
+use std::collections::VecDeque;
+use std::task::Context;
+use std::task::Poll;
+use task_control::AsyncRun;
+use task_control::Cancelled;
+use task_control::InspectTaskMut;
+use task_control::StopTask;
+use task_control::TaskControl;
+use virtio::DeviceTraits;
+use virtio::DeviceTraitsSharedMemory;
+use virtio::QueueResources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use virtio::VirtioQueueCallbackWork;
+use virtio::queue::QueueState;
+use virtio::spec::VirtioDeviceFeatures;
+use virtio::spec::input::*;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+/// The queue carrying input events to the guest.
+const EVENT_QUEUE: u16 = 0;
+/// The queue carrying status events, such as LED changes, from the guest.
+const STATUS_QUEUE: u16 = 1;
+
+/// The maximum absolute coordinate reported by the tablet, matching the
+/// range of [`MouseData`] positions.
+const TABLET_MAX_COORDINATE: u32 = 0x7fff;
+
+/// Maximum number of events to buffer while the guest has no event buffers
+/// available. Further input is dropped.
+const MAX_PENDING_EVENTS: usize = 256;
+
+/// The vendor ID reported in the device IDs (Microsoft).
+const VENDOR_ID: u16 = 0x1414;
+
+/// The size of the config space: the header followed by the data union.
+const CONFIG_SIZE: usize = size_of::<ConfigHeader>() + VIRTIO_INPUT_CFG_DATA_SIZE;
+
+/// The kind of input device exposed to the guest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Inspect)]
+pub enum InputDeviceKind {
+    Keyboard,
+    Tablet,
+}
+
+impl InputDeviceKind {
+    fn name(&self) -> &'static str {
+        match self {
+            InputDeviceKind::Keyboard => "OpenVMM Virtio Keyboard",
+            InputDeviceKind::Tablet => "OpenVMM Virtio Tablet",
+        }
+    }
+
+    fn product_id(&self) -> u16 {
+        match self {
+            InputDeviceKind::Keyboard => 1,
+            InputDeviceKind::Tablet => 2,
+        }
+    }
+
+    /// Returns the supported event codes for event type `ev`.
+    fn event_codes(&self, ev: u16) -> Vec<u16> {
+        match (self, ev) {
+            (InputDeviceKind::Keyboard, EV_KEY) => keymap::supported_keys().collect(),
+            (InputDeviceKind::Tablet, EV_KEY) => vec![BTN_LEFT, BTN_RIGHT, BTN_MIDDLE],
+            (InputDeviceKind::Tablet, EV_REL) => vec![REL_WHEEL],
+            (InputDeviceKind::Tablet, EV_ABS) => vec![ABS_X, ABS_Y],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns the contents of the config data union for the given selector.
+    fn config_data(&self, select: u8, subsel: u8) -> Vec<u8> {
+        match select {
+            VIRTIO_INPUT_CFG_ID_NAME if subsel == 0 => self.name().as_bytes().to_vec(),
+            VIRTIO_INPUT_CFG_ID_DEVIDS if subsel == 0 => DevIds {
+                bustype: BUS_VIRTUAL.into(),
+                vendor: VENDOR_ID.into(),
+                product: self.product_id().into(),
+                version: 1.into(),
+            }
+            .as_bytes()
+            .to_vec(),
+            VIRTIO_INPUT_CFG_EV_BITS => bitmap(self.event_codes(subsel.into())),
+            VIRTIO_INPUT_CFG_ABS_INFO
+                if *self == InputDeviceKind::Tablet
+                    && (u16::from(subsel) == ABS_X || u16::from(subsel) == ABS_Y) =>
+            {
+                AbsInfo {
+                    min: 0.into(),
+                    max: TABLET_MAX_COORDINATE.into(),
+                    fuzz: 0.into(),
+                    flat: 0.into(),
+                    res: 0.into(),
+                }
+                .as_bytes()
+                .to_vec()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Builds a little-endian bitmap with the given bits set, trimmed to the
+/// highest set bit.
+fn bitmap(bits: impl IntoIterator<Item = u16>) -> Vec<u8> {
+    let mut map = Vec::new();
+    for bit in bits {
+        let byte = bit as usize / 8;
+        if map.len() <= byte {
+            map.resize(byte + 1, 0);
+        }
+        map[byte] |= 1 << (bit % 8);
+    }
+    map
+}
+
+/// The input source feeding the device.
+enum Source {
+    Keyboard(Box<dyn InputSource<KeyboardData>>),
+    Tablet {
+        source: Box<dyn InputSource<MouseData>>,
+        /// The button mask of the last reported input.
+        buttons: u8,
+    },
+}
+
+impl Source {
+    async fn set_active(&mut self, active: bool) {
+        match self {
+            Source::Keyboard(source) => source.set_active(active).await,
+            Source::Tablet { source, .. } => source.set_active(active).await,
+        }
+    }
+
+    /// Polls for the next input, returning the events reporting it. The
+    /// returned list is empty if the input has no equivalent event.
+    fn poll_events(&mut self, cx: &mut Context<'_>) -> Poll<Vec<Event>> {
+        // A closed source never produces more input.
+        match self {
+            Source::Keyboard(source) => match source.poll_next_unpin(cx) {
+                Poll::Ready(Some(data)) => Poll::Ready(keyboard_events(data)),
+                Poll::Ready(None) | Poll::Pending => Poll::Pending,
+            },
+            Source::Tablet { source, buttons } => match source.poll_next_unpin(cx) {
+                Poll::Ready(Some(data)) => Poll::Ready(tablet_events(data, buttons)),
+                Poll::Ready(None) | Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+fn event(event_type: u16, code: u16, value: i32) -> Event {
+    Event {
+        event_type: event_type.into(),
+        code: code.into(),
+        value: (value as u32).into(),
+    }
+}
+
+fn syn_report() -> Event {
+    event(EV_SYN, SYN_REPORT, 0)
+}
+
+/// Translates a keystroke into key events.
+fn keyboard_events(data: KeyboardData) -> Vec<Event> {
+    let Some(key) = keymap::linux_key_code(data.code) else {
+        tracelimit::info_ratelimited!(code = data.code, "unmapped scancode");
+        return Vec::new();
+    };
+    vec![event(EV_KEY, key, data.make.into()), syn_report()]
+}
+
+/// Translates a mouse input into position, button, and wheel events.
+/// `buttons` holds the button mask of the previous input.
+fn tablet_events(data: MouseData, buttons: &mut u8) -> Vec<Event> {
+    // Bits 0-2 of the button mask are the left, middle, and right buttons;
+    // bits 3 and 4 are wheel up and down clicks.
+    const BUTTONS: [u16; 3] = [BTN_LEFT, BTN_MIDDLE, BTN_RIGHT];
+    const WHEEL_UP: u8 = 1 << 3;
+    const WHEEL_DOWN: u8 = 1 << 4;
+
+    let mut events = vec![
+        event(EV_ABS, ABS_X, data.x.into()),
+        event(EV_ABS, ABS_Y, data.y.into()),
+    ];
+    let changed = *buttons ^ data.button_mask;
+    let pressed = changed & data.button_mask;
+    for (i, &button) in BUTTONS.iter().enumerate() {
+        if changed & (1 << i) != 0 {
+            events.push(event(EV_KEY, button, ((data.button_mask >> i) & 1).into()));
+        }
+    }
+    if pressed & WHEEL_UP != 0 {
+        events.push(event(EV_REL, REL_WHEEL, 1));
+    }
+    if pressed & WHEEL_DOWN != 0 {
+        events.push(event(EV_REL, REL_WHEEL, -1));
+    }
+    events.push(syn_report());
+    *buttons = data.button_mask;
+    events
+}
+
+/// The virtio-input device.
+#[derive(InspectMut)]
+pub struct VirtioInputDevice {
+    #[inspect(skip)]
+    driver: VmTaskDriver,
+    kind: InputDeviceKind,
+    #[inspect(skip)]
+    config: [u8; CONFIG_SIZE],
+    #[inspect(mut)]
+    event_worker: TaskControl<EventWorker, EventQueue>,
+    #[inspect(mut)]
+    status_worker: TaskControl<StatusWorker, StatusQueue>,
+}
+
+impl VirtioInputDevice {
+    /// Creates a keyboard device fed by `source`.
+    pub fn keyboard(
+        driver_source: &VmTaskDriverSource,
+        source: Box<dyn InputSource<KeyboardData>>,
+    ) -> Self {
+        Self::new(
+            driver_source,
+            InputDeviceKind::Keyboard,
+            Source::Keyboard(source),
+        )
+    }
+
+    /// Creates a tablet device fed by `source`.
+    pub fn tablet(
+        driver_source: &VmTaskDriverSource,
+        source: Box<dyn InputSource<MouseData>>,
+    ) -> Self {
+        Self::new(
+            driver_source,
+            InputDeviceKind::Tablet,
+            Source::Tablet { source, buttons: 0 },
+        )
+    }
+
+    fn new(driver_source: &VmTaskDriverSource, kind: InputDeviceKind, source: Source) -> Self {
+        Self {
+            driver: driver_source.simple(),
+            kind,
+            config: [0; CONFIG_SIZE],
+            event_worker: TaskControl::new(EventWorker {
+                source,
+                stats: Default::default(),
+            }),
+            status_worker: TaskControl::new(StatusWorker {
+                stats: Default::default(),
+            }),
+        }
+    }
+
+    fn select(&mut self, select: u8, subsel: u8) {
+        let data = self.kind.config_data(select, subsel);
+        let header = ConfigHeader {
+            select,
+            subsel,
+            size: data.len() as u8,
+            reserved: [0; 5],
+        };
+        self.config.fill(0);
+        let (header_bytes, data_bytes) = self.config.split_at_mut(size_of::<ConfigHeader>());
+        header_bytes.copy_from_slice(header.as_bytes());
+        data_bytes[..data.len()].copy_from_slice(&data);
+    }
+}
+
+impl VirtioDevice for VirtioInputDevice {
+    fn traits(&self) -> DeviceTraits {
+        DeviceTraits {
+            device_id: virtio::spec::VirtioDeviceType::INPUT,
+            device_features: VirtioDeviceFeatures::new()
+                .with_ring_event_idx(true)
+                .with_ring_indirect_desc(true)
+                .with_ring_packed(true),
+            max_queues: 2,
+            device_register_length: CONFIG_SIZE as u32,
+            shared_memory: DeviceTraitsSharedMemory::default(),
+        }
+    }
+
+    async fn read_registers_u32(&mut self, offset: u16) -> u32 {
+        let offset = offset as usize;
+        self.config
+            .get(offset..offset + 4)
+            .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    async fn write_registers_u32(&mut self, offset: u16, val: u32) {
+        // Only `select` and `subsel` are writable by the driver.
+        if offset == 0 {
+            let [select, subsel, ..] = val.to_le_bytes();
+            self.select(select, subsel);
+        }
+    }
+
+    async fn start_queue(
+        &mut self,
+        idx: u16,
+        resources: QueueResources,
+        features: &VirtioDeviceFeatures,
+        initial_state: Option<QueueState>,
+    ) -> anyhow::Result<()> {
+        let queue_event = PolledWait::new(&self.driver, resources.event)
+            .context("failed to create polled wait")?;
+        let queue = VirtioQueue::new(
+            *features,
+            resources.params,
+            resources.guest_memory.clone(),
+            resources.notify,
+            queue_event,
+            initial_state,
+        )
+        .context("failed to create virtio queue")?;
+
+        match idx {
+            EVENT_QUEUE => {
+                self.event_worker.insert(
+                    self.driver.clone(),
+                    "virtio-input-event",
+                    EventQueue {
+                        queue,
+                        mem: resources.guest_memory,
+                        pending: VecDeque::new(),
+                    },
+                );
+                self.event_worker.start();
+            }
+            STATUS_QUEUE => {
+                self.status_worker.insert(
+                    self.driver.clone(),
+                    "virtio-input-status",
+                    StatusQueue {
+                        queue,
+                        mem: resources.guest_memory,
+                    },
+                );
+                self.status_worker.start();
+            }
+            _ => anyhow::bail!("invalid queue index {idx}"),
+        }
+        Ok(())
+    }
+
+    async fn stop_queue(&mut self, idx: u16) -> Option<QueueState> {
+        match idx {
+            EVENT_QUEUE => {
+                if !self.event_worker.has_state() {
+                    return None;
+                }
+                self.event_worker.stop().await;
+                // Let input go to other devices while the guest is not
+                // listening.
+                self.event_worker.task_mut().source.set_active(false).await;
+                Some(self.event_worker.remove().queue.queue_state())
+            }
+            STATUS_QUEUE => {
+                if !self.status_worker.has_state() {
+                    return None;
+                }
+                self.status_worker.stop().await;
+                Some(self.status_worker.remove().queue.queue_state())
+            }
+            _ => None,
+        }
+    }
+
+    async fn reset(&mut self) {
+        self.config.fill(0);
+        if let Source::Tablet { buttons, .. } = &mut self.event_worker.task_mut().source {
+            *buttons = 0;
+        }
+    }
+}
+
+/// Persistent event queue worker state, which survives across queue
+/// restarts.
+#[derive(InspectMut)]
+struct EventWorker {
+    #[inspect(skip)]
+    source: Source,
+    stats: EventStats,
+}
+
+#[derive(Inspect, Default)]
+struct EventStats {
+    events: Counter,
+    dropped_events: Counter,
+}
+
+/// Transient event queue state, created in `start_queue` and removed in
+/// `stop_queue`.
+#[derive(InspectMut)]
+struct EventQueue {
+    queue: VirtioQueue,
+    mem: GuestMemory,
+    /// Events waiting for a guest buffer.
+    #[inspect(with = "VecDeque::len")]
+    pending: VecDeque<Event>,
+}
+
+impl InspectTaskMut<EventQueue> for EventWorker {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>, state: Option<&mut EventQueue>) {
+        req.respond().merge(self).merge(state);
+    }
+}
+
+impl AsyncRun<EventQueue> for EventWorker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut EventQueue,
+    ) -> Result<(), Cancelled> {
+        enum Next {
+            Input(Vec<Event>),
+            Buffer(Result<VirtioQueueCallbackWork, std::io::Error>),
+        }
+
+        stop.until_stopped(self.source.set_active(true)).await?;
+        loop {
+            let next = stop
+                .until_stopped(std::future::poll_fn(|cx| {
+                    // Only take guest buffers when there is something to put
+                    // in them.
+                    if !state.pending.is_empty() {
+                        if let Poll::Ready(Some(work)) = state.queue.poll_next_unpin(cx) {
+                            return Poll::Ready(Next::Buffer(work));
+                        }
+                    }
+                    self.source.poll_events(cx).map(Next::Input)
+                }))
+                .await?;
+
+            match next {
+                Next::Input(events) => {
+                    if state.pending.len() + events.len() > MAX_PENDING_EVENTS {
+                        // Drop the whole report rather than delivering part
+                        // of it.
+                        self.stats.dropped_events.add(events.len() as u64);
+                        tracelimit::warn_ratelimited!("dropping input, no guest buffers available");
+                    } else {
+                        state.pending.extend(events);
+                    }
+                }
+                Next::Buffer(Ok(work)) => {
+                    let event = state.pending.pop_front().unwrap();
+                    let len = match work.write(&state.mem, event.as_bytes()) {
+                        Ok(()) => {
+                            self.stats.events.increment();
+                            size_of::<Event>() as u32
+                        }
+                        Err(err) => {
+                            tracelimit::error_ratelimited!(
+                                err = &err as &dyn std::error::Error,
+                                "failed to write input event"
+                            );
+                            0
+                        }
+                    };
+                    state.queue.complete(work, len);
+                }
+                Next::Buffer(Err(err)) => {
+                    tracelimit::error_ratelimited!(
+                        err = &err as &dyn std::error::Error,
+                        "queue error"
+                    );
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Status queue worker. The guest reports LED state changes here, which the
+/// device has no use for beyond tracing.
+#[derive(InspectMut)]
+struct StatusWorker {
+    stats: StatusStats,
+}
+
+#[derive(Inspect, Default)]
+struct StatusStats {
+    status_events: Counter,
+}
+
+#[derive(InspectMut)]
+struct StatusQueue {
+    queue: VirtioQueue,
+    mem: GuestMemory,
+}
+
+impl InspectTaskMut<StatusQueue> for StatusWorker {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>, state: Option<&mut StatusQueue>) {
+        req.respond().merge(self).merge(state);
+    }
+}
+
+impl AsyncRun<StatusQueue> for StatusWorker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut StatusQueue,
+    ) -> Result<(), Cancelled> {
+        loop {
+            let work = stop.until_stopped(state.queue.next()).await?;
+            let Some(work) = work else { break };
+            match work {
+                Ok(work) => {
+                    let mut event = Event::new_zeroed();
+                    match work.read(&state.mem, event.as_mut_bytes()) {
+                        Ok(n) if n == size_of::<Event>() => {
+                            self.stats.status_events.increment();
+                            tracing::debug!(
+                                event_type = event.event_type.get(),
+                                code = event.code.get(),
+                                value = event.value.get(),
+                                "input status event"
+                            );
+                        }
+                        Ok(_) => {
+                            tracelimit::warn_ratelimited!("short input status event");
+                        }
+                        Err(err) => {
+                            tracelimit::error_ratelimited!(
+                                err = &err as &dyn std::error::Error,
+                                "failed to read input status event"
+                            );
+                        }
+                    }
+                    state.queue.complete(work, 0);
+                }
+                Err(err) => {
+                    tracelimit::error_ratelimited!(
+                        err = &err as &dyn std::error::Error,
+                        "queue error"
+                    );
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use input_core::mesh_input::MeshInputSink;
+    use input_core::mesh_input::input_pair;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use pal_event::Event as PalEvent;
+    use test_with_tracing::test;
+    use virtio::queue::QueueParams;
+    use virtio::spec::queue::DescriptorFlags;
+    use virtio::test_helpers::init_avail_ring;
+    use virtio::test_helpers::init_used_ring;
+    use virtio::test_helpers::make_available;
+    use virtio::test_helpers::wait_for_used;
+    use virtio::test_helpers::write_descriptor;
+    use vmcore::interrupt::Interrupt;
+    use vmcore::vm_task::SingleDriverBackend;
+    use zerocopy::FromBytes;
+
+    const QUEUE_SIZE: u16 = 16;
+    const DESC_ADDR: u64 = 0x0000;
+    const AVAIL_ADDR: u64 = 0x1000;
+    const USED_ADDR: u64 = 0x2000;
+    const DATA_BASE: u64 = 0x10000;
+    const TOTAL_MEM_SIZE: usize = 0x20000;
+
+    fn keyboard(driver: &DefaultDriver) -> (VirtioInputDevice, MeshInputSink<KeyboardData>) {
+        let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
+        let (source, sink) = input_pair();
+        (
+            VirtioInputDevice::keyboard(&driver_source, Box::new(source)),
+            sink,
+        )
+    }
+
+    fn tablet(driver: &DefaultDriver) -> (VirtioInputDevice, MeshInputSink<MouseData>) {
+        let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
+        let (source, sink) = input_pair();
+        (
+            VirtioInputDevice::tablet(&driver_source, Box::new(source)),
+            sink,
+        )
+    }
+
+    /// Selects a config entry and returns its data.
+    async fn read_config(device: &mut VirtioInputDevice, select: u8, subsel: u8) -> Vec<u8> {
+        device
+            .write_registers_u32(0, u32::from_le_bytes([select, subsel, 0, 0]))
+            .await;
+        let mut config = Vec::new();
+        for offset in (0..CONFIG_SIZE as u16).step_by(4) {
+            config.extend(device.read_registers_u32(offset).await.to_le_bytes());
+        }
+        let size = config[2] as usize;
+        config[size_of::<ConfigHeader>()..][..size].to_vec()
+    }
+
+    fn has_bit(map: &[u8], bit: u16) -> bool {
+        map.get(bit as usize / 8)
+            .is_some_and(|b| b & (1 << (bit % 8)) != 0)
+    }
+
+    #[async_test]
+    async fn input_reports_correct_traits(driver: DefaultDriver) {
+        let (device, _sink) = keyboard(&driver);
+        let traits = device.traits();
+        assert_eq!(traits.device_id, virtio::spec::VirtioDeviceType::INPUT);
+        assert_eq!(traits.max_queues, 2);
+        assert_eq!(traits.device_register_length, CONFIG_SIZE as u32);
+    }
+
+    #[async_test]
+    async fn keyboard_config(driver: DefaultDriver) {
+        let (mut device, _sink) = keyboard(&driver);
+        assert_eq!(
+            read_config(&mut device, VIRTIO_INPUT_CFG_ID_NAME, 0).await,
+            b"OpenVMM Virtio Keyboard"
+        );
+        let keys = read_config(&mut device, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8).await;
+        // KEY_A, KEY_UP, and KEY_PAUSE.
+        assert!(has_bit(&keys, 30));
+        assert!(has_bit(&keys, 103));
+        assert!(has_bit(&keys, 119));
+        assert!(!has_bit(&keys, BTN_LEFT));
+        assert!(
+            read_config(&mut device, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8)
+                .await
+                .is_empty()
+        );
+        let ids = read_config(&mut device, VIRTIO_INPUT_CFG_ID_DEVIDS, 0).await;
+        let ids = DevIds::read_from_bytes(&ids).unwrap();
+        assert_eq!(ids.bustype.get(), BUS_VIRTUAL);
+    }
+
+    #[async_test]
+    async fn tablet_config(driver: DefaultDriver) {
+        let (mut device, _sink) = tablet(&driver);
+        let buttons = read_config(&mut device, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8).await;
+        assert!(has_bit(&buttons, BTN_LEFT));
+        assert!(has_bit(&buttons, BTN_RIGHT));
+        assert!(has_bit(&buttons, BTN_MIDDLE));
+        let rel = read_config(&mut device, VIRTIO_INPUT_CFG_EV_BITS, EV_REL as u8).await;
+        assert_eq!(rel, bitmap([REL_WHEEL]));
+        let abs = read_config(&mut device, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8).await;
+        assert_eq!(abs, bitmap([ABS_X, ABS_Y]));
+        let info = read_config(&mut device, VIRTIO_INPUT_CFG_ABS_INFO, ABS_Y as u8).await;
+        let info = AbsInfo::read_from_bytes(&info).unwrap();
+        assert_eq!(info.min.get(), 0);
+        assert_eq!(info.max.get(), TABLET_MAX_COORDINATE);
+        assert!(
+            read_config(&mut device, VIRTIO_INPUT_CFG_ABS_INFO, 5)
+                .await
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn scancode_translation() {
+        assert_eq!(keymap::linux_key_code(0x1e), Some(30)); // KEY_A
+        assert_eq!(keymap::linux_key_code(0x58), Some(88)); // KEY_F12
+        assert_eq!(keymap::linux_key_code(0xe01d), Some(97)); // KEY_RIGHTCTRL
+        assert_eq!(keymap::linux_key_code(0xe048), Some(103)); // KEY_UP
+        assert_eq!(keymap::linux_key_code(0xe11d), Some(119)); // KEY_PAUSE
+        assert_eq!(keymap::linux_key_code(0x55), None);
+        assert_eq!(keymap::linux_key_code(0xe001), None);
+    }
+
+    #[test]
+    fn tablet_translation() {
+        let mut buttons = 0;
+        let events = tablet_events(
+            MouseData {
+                button_mask: 0b101,
+                x: 0x100,
+                y: 0x200,
+            },
+            &mut buttons,
+        );
+        assert_eq!(
+            events,
+            [
+                event(EV_ABS, ABS_X, 0x100),
+                event(EV_ABS, ABS_Y, 0x200),
+                event(EV_KEY, BTN_LEFT, 1),
+                event(EV_KEY, BTN_RIGHT, 1),
+                syn_report(),
+            ]
+        );
+
+        // Releasing a button and clicking the wheel down.
+        let events = tablet_events(
+            MouseData {
+                button_mask: 0b10001,
+                x: 0x100,
+                y: 0x200,
+            },
+            &mut buttons,
+        );
+        assert_eq!(
+            events,
+            [
+                event(EV_ABS, ABS_X, 0x100),
+                event(EV_ABS, ABS_Y, 0x200),
+                event(EV_KEY, BTN_RIGHT, 0),
+                event(EV_REL, REL_WHEEL, -1),
+                syn_report(),
+            ]
+        );
+        assert_eq!(buttons, 0b10001);
+    }
+
+    #[async_test]
+    async fn keyboard_delivers_events(driver: DefaultDriver) {
+        let mem = GuestMemory::allocate(TOTAL_MEM_SIZE);
+        let (mut device, mut sink) = keyboard(&driver);
+        let queue_event = PalEvent::new();
+        let interrupt_event = PalEvent::new();
+        init_avail_ring(&mem, AVAIL_ADDR);
+        init_used_ring(&mem, USED_ADDR);
+        device
+            .start_queue(
+                EVENT_QUEUE,
+                QueueResources {
+                    params: QueueParams {
+                        size: QUEUE_SIZE,
+                        enable: true,
+                        desc_addr: DESC_ADDR,
+                        avail_addr: AVAIL_ADDR,
+                        used_addr: USED_ADDR,
+                    },
+                    notify: Interrupt::from_event(interrupt_event.clone()),
+                    event: queue_event.clone(),
+                    guest_memory: mem.clone(),
+                },
+                &VirtioDeviceFeatures::new(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let mut avail_idx = 0;
+        let mut used_idx = 0;
+        for i in 0..2 {
+            write_descriptor(
+                &mem,
+                DESC_ADDR,
+                i,
+                DATA_BASE + i as u64 * 0x100,
+                size_of::<Event>() as u32,
+                DescriptorFlags::new().with_write(true),
+                0,
+            );
+            make_available(&mem, AVAIL_ADDR, QUEUE_SIZE, i, &mut avail_idx);
+        }
+        queue_event.signal();
+
+        sink.send(KeyboardData {
+            code: 0xe048,
+            make: true,
+        });
+
+        let mut events = Vec::new();
+        for _ in 0..2 {
+            let (desc, len) = wait_for_used(
+                &driver,
+                &interrupt_event,
+                &mem,
+                USED_ADDR,
+                QUEUE_SIZE,
+                &mut used_idx,
+            )
+            .await;
+            assert_eq!(len, size_of::<Event>() as u32);
+            let mut event = Event::new_zeroed();
+            mem.read_at(DATA_BASE + desc as u64 * 0x100, event.as_mut_bytes())
+                .unwrap();
+            events.push(event);
+        }
+        assert_eq!(events, [event(EV_KEY, 103, 1), syn_report()]);
+
+        assert!(device.stop_queue(EVENT_QUEUE).await.is_some());
+    }
+}
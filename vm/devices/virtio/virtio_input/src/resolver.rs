@@ -0,0 +1,55 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Defines the resource resolver for virtio-input devices.
+
+use crate::VirtioInputDevice;
+use anyhow::Context as _;
+use async_trait::async_trait;
+use virtio::resolve::ResolvedVirtioDevice;
+use virtio::resolve::VirtioResolveInput;
+use virtio_resources::input::VirtioInputDevice as InputDeviceConfig;
+use virtio_resources::input::VirtioInputHandle;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::VirtioDeviceHandle;
+
+/// Resolver for virtio-input devices.
+pub struct VirtioInputResolver;
+
+declare_static_async_resolver! {
+    VirtioInputResolver,
+    (VirtioDeviceHandle, VirtioInputHandle),
+}
+
+#[async_trait]
+impl AsyncResolveResource<VirtioDeviceHandle, VirtioInputHandle> for VirtioInputResolver {
+    type Output = ResolvedVirtioDevice;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        resource: VirtioInputHandle,
+        input: VirtioResolveInput<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let device = match resource.device {
+            InputDeviceConfig::Keyboard(source) => {
+                let source = resolver
+                    .resolve(source, "virtio-keyboard")
+                    .await
+                    .context("failed to resolve keyboard input source")?;
+                VirtioInputDevice::keyboard(input.driver_source, source.0)
+            }
+            InputDeviceConfig::Tablet(source) => {
+                let source = resolver
+                    .resolve(source, "virtio-tablet")
+                    .await
+                    .context("failed to resolve mouse input source")?;
+                VirtioInputDevice::tablet(input.driver_source, source.0)
+            }
+        };
+        Ok(device.into())
+    }
+}
@@ -5,6 +5,7 @@
 use crate::VirtioNetHeaderFlags;
 use crate::header_size;
 use guestmem::GuestMemory;
+use guestmem::GuestMemoryError;
 use inspect::Inspect;
 use net_backend::BufferAccess;
 use net_backend::RxBufferSegment;
@@ -15,11 +16,22 @@
 use zerocopy::IntoBytes;
 
 struct RxPacket {
-    work: VirtioQueueCallbackWork,
+    /// The descriptor chains backing the buffer, in order. There is more than
+    /// one only when mergeable receive buffers are in use.
+    works: Vec<VirtioQueueCallbackWork>,
     len: u32,
     cap: u32,
 }
 
+/// Returns the writable guest ranges of `works`, in order.
+fn writable_ranges(works: &[VirtioQueueCallbackWork]) -> impl Iterator<Item = (u64, u32)> + '_ {
+    works
+        .iter()
+        .flat_map(|work| work.payload.iter())
+        .filter(|p| p.writeable)
+        .map(|p| (p.address, p.length))
+}
+
 /// Holds virtio buffers available for a network backend to send data to the client.
 #[derive(Inspect)]
 #[inspect(extra = "Self::inspect_extra")]
@@ -27,6 +39,15 @@ pub struct VirtioWorkPool {
     mem: GuestMemory,
     #[inspect(skip)]
     rx_packets: Vec<Option<RxPacket>>,
+    /// The minimum capacity of a buffer assembled from multiple descriptor
+    /// chains, or 0 if VIRTIO_NET_F_MRG_RXBUF was not negotiated.
+    merge_capacity: u32,
+    /// Descriptor chains collected toward the next mergeable buffer.
+    #[inspect(with = "Vec::len")]
+    partial: Vec<VirtioQueueCallbackWork>,
+    /// Descriptor indexes owned by the pool, for detecting duplicates.
+    #[inspect(skip)]
+    in_use: Vec<bool>,
 }
 
 impl VirtioWorkPool {
@@ -38,10 +59,17 @@ fn inspect_extra(&self, resp: &mut inspect::Response<'_>) {
     }
 
     /// Create a new instance.
-    pub fn new(mem: GuestMemory, queue_size: u16) -> Self {
+    ///
+    /// If `merge_capacity` is non-zero, descriptor chains are combined until
+    /// each buffer offered to the backend can hold at least that many bytes,
+    /// as allowed by VIRTIO_NET_F_MRG_RXBUF.
+    pub fn new(mem: GuestMemory, queue_size: u16, merge_capacity: u32) -> Self {
         Self {
             mem,
             rx_packets: (0..queue_size).map(|_| None).collect(),
+            merge_capacity,
+            partial: Vec::new(),
+            in_use: vec![false; queue_size as usize],
         }
     }
 
@@ -71,46 +99,169 @@ pub fn fill_ready(&self, buf: &mut [RxId]) -> usize {
 
     /// Add a virtio work instance to the buffers available for use.
     ///
+    /// Returns the ID of a newly available buffer, or `None` if the work was
+    /// held back to be merged with later descriptor chains.
+    ///
     /// Returns `Err` with the work item if the descriptor index is already in
     /// use (duplicate submission by the guest).
     pub fn queue_work(
         &mut self,
         work: VirtioQueueCallbackWork,
-    ) -> Result<RxId, VirtioQueueCallbackWork> {
-        let idx = work.descriptor_index();
-        let packet = &mut self.rx_packets[idx as usize];
-        if packet.is_some() {
+    ) -> Result<Option<RxId>, VirtioQueueCallbackWork> {
+        let idx = work.descriptor_index() as usize;
+        if self.in_use[idx] {
             tracelimit::warn_ratelimited!("dropping RX buffer: descriptor index already in use");
             return Err(work);
         }
         let payload_length = work.get_payload_length(true) as u32;
-        let Some(cap) = payload_length.checked_sub(header_size() as u32) else {
+        if payload_length < header_size() as u32 {
             tracelimit::warn_ratelimited!(
                 len = payload_length,
                 "dropping RX buffer: payload length smaller than virtio-net header size"
             );
             return Err(work);
-        };
-        *packet = Some(RxPacket { len: 0, cap, work });
-        Ok(RxId(idx.into()))
+        }
+        self.in_use[idx] = true;
+        if self.merge_capacity == 0 {
+            Ok(Some(self.insert_packet(vec![work])))
+        } else {
+            self.partial.push(work);
+            Ok(self.try_merge())
+        }
+    }
+
+    /// Forms a buffer from the pending partial chains if they are large
+    /// enough.
+    fn try_merge(&mut self) -> Option<RxId> {
+        let cap = writable_ranges(&self.partial)
+            .map(|(_, len)| len)
+            .sum::<u32>()
+            - header_size() as u32;
+        (cap >= self.merge_capacity).then(|| {
+            let works = std::mem::take(&mut self.partial);
+            self.insert_packet(works)
+        })
     }
 
-    /// Take the RX work item for the given packet, returning it with the
-    /// computed payload length. The caller is responsible for completing
-    /// the descriptor via the queue.
-    #[must_use = "caller must complete the returned work via VirtioQueue::complete"]
-    pub fn take_rx_work(&mut self, rx_id: RxId) -> (VirtioQueueCallbackWork, u32) {
+    fn insert_packet(&mut self, works: Vec<VirtioQueueCallbackWork>) -> RxId {
+        let idx = works[0].descriptor_index();
+        let cap = writable_ranges(&works).map(|(_, len)| len).sum::<u32>() - header_size() as u32;
+        self.rx_packets[idx as usize] = Some(RxPacket { works, len: 0, cap });
+        RxId(idx.into())
+    }
+
+    /// Take the RX work items for the given packet, appending each descriptor
+    /// chain to `completed` with the number of bytes written to it. The caller
+    /// is responsible for completing the descriptors via the queue.
+    ///
+    /// Chains of a mergeable buffer that the packet did not reach are kept by
+    /// the pool. If they form a new buffer, its ID is returned and the caller
+    /// must offer it to the backend.
+    #[must_use]
+    pub fn take_rx_work(
+        &mut self,
+        rx_id: RxId,
+        completed: &mut Vec<(VirtioQueueCallbackWork, u32)>,
+    ) -> Option<RxId> {
         let packet = self.rx_packets[rx_id.0 as usize]
             .take()
             .expect("valid packet index");
-        let payload_len = if packet.len == 0 {
+        let mut remaining = if packet.len == 0 {
             // Header was not written, so treat as empty packet.
             tracelimit::warn_ratelimited!("dropping RX buffer: header not written");
             0
         } else {
             packet.len + header_size() as u32
         };
-        (packet.work, payload_len)
+        let mut works = packet.works.into_iter();
+        // The first chain is always completed, even for an empty packet.
+        let mut unused = Vec::new();
+        for (i, work) in works.by_ref().enumerate() {
+            if i > 0 && remaining == 0 {
+                unused.push(work);
+                break;
+            }
+            let len = remaining.min(work.get_payload_length(true) as u32);
+            remaining -= len;
+            self.in_use[work.descriptor_index() as usize] = false;
+            completed.push((work, len));
+        }
+        unused.extend(works);
+        if unused.is_empty() {
+            return None;
+        }
+        // Put the unused chains ahead of any later ones so that they are
+        // consumed first.
+        self.partial.splice(0..0, unused);
+        self.try_merge()
+    }
+
+    /// Returns a buffer to the available state without completing it, after
+    /// its contents were discarded.
+    pub fn recycle(&mut self, rx_id: RxId) {
+        self.rx_packets[rx_id.0 as usize]
+            .as_mut()
+            .expect("invalid buffer index")
+            .len = 0;
+    }
+
+    /// Reads the start of the frame written to the given buffer into `buf`,
+    /// returning the number of bytes read.
+    pub fn read_frame_prefix(
+        &self,
+        rx_id: RxId,
+        buf: &mut [u8],
+    ) -> Result<usize, GuestMemoryError> {
+        let packet = self.rx_packets[rx_id.0 as usize]
+            .as_ref()
+            .expect("invalid buffer index");
+        let len = buf.len().min(packet.len as usize);
+        let buf = &mut buf[..len];
+        let mut skip = header_size() as u64;
+        let mut done = 0;
+        for (address, length) in writable_ranges(&packet.works) {
+            if done == len {
+                break;
+            }
+            let length = length as u64;
+            if skip >= length {
+                skip -= length;
+                continue;
+            }
+            let n = ((length - skip) as usize).min(len - done);
+            self.mem
+                .read_at(address.saturating_add(skip), &mut buf[done..done + n])?;
+            done += n;
+            skip = 0;
+        }
+        Ok(done)
+    }
+
+    /// Writes `data` at `offset` within the writable portion of a buffer,
+    /// spanning descriptor chains as needed.
+    fn write_at(&self, rx_id: RxId, offset: u64, data: &[u8]) -> Result<(), GuestMemoryError> {
+        let packet = self.rx_packets[rx_id.0 as usize]
+            .as_ref()
+            .expect("invalid buffer index");
+        let mut skip = offset;
+        let mut remaining = data;
+        for (address, length) in writable_ranges(&packet.works) {
+            if remaining.is_empty() {
+                break;
+            }
+            let length = length as u64;
+            if skip >= length {
+                skip -= length;
+                continue;
+            }
+            let n = ((length - skip) as usize).min(remaining.len());
+            let (current, next) = remaining.split_at(n);
+            self.mem.write_at(address.saturating_add(skip), current)?;
+            remaining = next;
+            skip = 0;
+        }
+        assert!(remaining.is_empty(), "write beyond buffer capacity");
+        Ok(())
     }
 }
 
@@ -120,13 +271,7 @@ fn guest_memory(&self) -> &GuestMemory {
     }
 
     fn write_data(&mut self, id: RxId, data: &[u8]) {
-        let packet = self.rx_packets[id.0 as usize]
-            .as_mut()
-            .expect("invalid buffer index");
-        if let Err(err) = packet
-            .work
-            .write_at_offset(header_size() as u64, &self.mem, data)
-        {
+        if let Err(err) = self.write_at(id, header_size() as u64, data) {
             tracelimit::warn_ratelimited!(
                 len = data.len(),
                 error = &err as &dyn std::error::Error,
@@ -139,17 +284,7 @@ fn push_guest_addresses(&self, id: RxId, buf: &mut Vec<RxBufferSegment>) {
         let packet = self.rx_packets[id.0 as usize]
             .as_ref()
             .expect("invalid buffer index");
-        buf.extend(
-            packet
-                .work
-                .payload
-                .iter()
-                .filter(|x| x.writeable)
-                .map(|p| RxBufferSegment {
-                    gpa: p.address,
-                    len: p.length,
-                }),
-        );
+        buf.extend(writable_ranges(&packet.works).map(|(gpa, len)| RxBufferSegment { gpa, len }));
     }
 
     fn capacity(&self, id: RxId) -> u32 {
@@ -163,6 +298,27 @@ fn write_header(&mut self, id: RxId, metadata: &RxMetadata) {
         assert_eq!(metadata.offset, 0);
         assert!(metadata.len > 0);
 
+        let packet = self.rx_packets[id.0 as usize]
+            .as_ref()
+            .expect("invalid buffer index");
+        assert!(
+            metadata.len <= packet.cap as usize,
+            "packet len {} exceeds buffer capacity {}",
+            metadata.len,
+            packet.cap
+        );
+
+        // Count the descriptor chains the header and packet span.
+        let mut remaining = (metadata.len + header_size()) as u64;
+        let mut num_buffers = 0;
+        for work in &packet.works {
+            if remaining == 0 {
+                break;
+            }
+            remaining = remaining.saturating_sub(work.get_payload_length(true));
+            num_buffers += 1;
+        }
+
         // Map RxMetadata checksum state to virtio-net header flags.
         // Set VIRTIO_NET_HDR_F_DATA_VALID when both IP and L4 checksums have
         // been validated (Good or ValidatedButWrong, e.g. after RSC/LRO),
@@ -172,28 +328,16 @@ fn write_header(&mut self, id: RxId, metadata: &RxMetadata) {
 
         let virtio_net_header = VirtioNetHeader {
             flags: flags.into(),
-            num_buffers: 1,
+            num_buffers,
             ..FromZeros::new_zeroed()
         };
-        let packet = self.rx_packets[id.0 as usize]
-            .as_mut()
-            .expect("invalid buffer index");
-        if let Err(err) = packet
-            .work
-            .write(&self.mem, &virtio_net_header.as_bytes()[..header_size()])
-        {
+        if let Err(err) = self.write_at(id, 0, &virtio_net_header.as_bytes()[..header_size()]) {
             tracelimit::warn_ratelimited!(
                 error = &err as &dyn std::error::Error,
                 "failure writing header"
             );
             return;
         }
-        assert!(
-            metadata.len <= packet.cap as usize,
-            "packet len {} exceeds buffer capacity {}",
-            metadata.len,
-            packet.cap
-        );
-        packet.len = metadata.len as u32;
+        self.rx_packets[id.0 as usize].as_mut().unwrap().len = metadata.len as u32;
     }
 }
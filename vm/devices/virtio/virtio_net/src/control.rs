@@ -0,0 +1,354 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Control virtqueue command parsing and receive filtering (virtio spec
+//! §5.1.6.5).
+
+use inspect::Inspect;
+use net_backend_resources::mac_address::MacAddress;
+use thiserror::Error;
+
+// Command classes and commands.
+const VIRTIO_NET_CTRL_RX: u8 = 0;
+const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+const VIRTIO_NET_CTRL_RX_ALLUNI: u8 = 2;
+const VIRTIO_NET_CTRL_RX_NOMULTI: u8 = 3;
+const VIRTIO_NET_CTRL_RX_NOUNI: u8 = 4;
+const VIRTIO_NET_CTRL_RX_NOBCAST: u8 = 5;
+
+const VIRTIO_NET_CTRL_MAC: u8 = 1;
+const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+const VIRTIO_NET_CTRL_MAC_ADDR_SET: u8 = 1;
+
+const VIRTIO_NET_CTRL_VLAN: u8 = 2;
+const VIRTIO_NET_CTRL_VLAN_ADD: u8 = 0;
+const VIRTIO_NET_CTRL_VLAN_DEL: u8 = 1;
+
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+const VIRTIO_NET_CTRL_MQ_RSS_CONFIG: u8 = 1;
+
+// Command acknowledgements.
+pub const VIRTIO_NET_OK: u8 = 0;
+pub const VIRTIO_NET_ERR: u8 = 1;
+
+// Hash types for VIRTIO_NET_F_RSS.
+pub const VIRTIO_NET_RSS_HASH_TYPE_IPV4: u32 = 1 << 0;
+pub const VIRTIO_NET_RSS_HASH_TYPE_TCPV4: u32 = 1 << 1;
+pub const VIRTIO_NET_RSS_HASH_TYPE_UDPV4: u32 = 1 << 2;
+pub const VIRTIO_NET_RSS_HASH_TYPE_IPV6: u32 = 1 << 3;
+pub const VIRTIO_NET_RSS_HASH_TYPE_TCPV6: u32 = 1 << 4;
+pub const VIRTIO_NET_RSS_HASH_TYPE_UDPV6: u32 = 1 << 5;
+
+/// The Toeplitz key size used by RSS-capable backends.
+pub const RSS_MAX_KEY_SIZE: u8 = 40;
+
+/// The largest control command accepted from the guest. A full MAC table
+/// plus headers fits well within this.
+pub const MAX_COMMAND_SIZE: u64 = 0x1000;
+
+/// The number of unicast or multicast MAC filters tracked before falling
+/// back to accepting all addresses of that kind.
+const MAX_MAC_FILTERS: usize = 64;
+
+const VLAN_ID_COUNT: usize = 4096;
+
+/// A receive mode toggled by a `VIRTIO_NET_CTRL_RX` command.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RxMode {
+    Promiscuous,
+    AllMulticast,
+    AllUnicast,
+    NoMulticast,
+    NoUnicast,
+    NoBroadcast,
+}
+
+impl RxMode {
+    /// Returns true if this mode requires `VIRTIO_NET_F_CTRL_RX_EXTRA`
+    /// rather than just `VIRTIO_NET_F_CTRL_RX`.
+    pub fn is_extra(self) -> bool {
+        !matches!(self, Self::Promiscuous | Self::AllMulticast)
+    }
+}
+
+/// A `VIRTIO_NET_CTRL_MQ_RSS_CONFIG` request.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RssRequest {
+    pub hash_types: u32,
+    pub indirection_table: Vec<u16>,
+    pub unclassified_queue: u16,
+    pub max_tx_vq: u16,
+    pub key: Vec<u8>,
+}
+
+/// A parsed control virtqueue command.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    SetRxMode(RxMode, bool),
+    SetMacTable {
+        unicast: Vec<[u8; 6]>,
+        multicast: Vec<[u8; 6]>,
+    },
+    SetMacAddress([u8; 6]),
+    AddVlan(u16),
+    RemoveVlan(u16),
+    SetQueuePairs(u16),
+    SetRss(RssRequest),
+}
+
+#[derive(Debug, Error)]
+pub enum ControlError {
+    #[error("unsupported command class {0} command {1}")]
+    Unsupported(u8, u8),
+    #[error("command feature not negotiated")]
+    NotNegotiated,
+    #[error("command too large: {0:#x} bytes")]
+    TooLarge(u64),
+    #[error("command truncated")]
+    Truncated,
+    #[error("invalid VLAN id {0}")]
+    InvalidVlan(u16),
+    #[error("invalid queue pair count {0}")]
+    InvalidQueuePairs(u32),
+    #[error("invalid RSS indirection table length {0}")]
+    InvalidIndirectionTable(usize),
+    #[error("invalid RSS key length {0}")]
+    InvalidKeyLength(usize),
+}
+
+/// A cursor over the command-specific data of a control command.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], ControlError> {
+        if self.0.len() < n {
+            return Err(ControlError::Truncated);
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, ControlError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ControlError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ControlError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn mac(&mut self) -> Result<[u8; 6], ControlError> {
+        Ok(self.bytes(6)?.try_into().unwrap())
+    }
+
+    fn mac_table(&mut self) -> Result<Vec<[u8; 6]>, ControlError> {
+        let entries = self.u32()? as usize;
+        if entries > self.0.len() / 6 {
+            return Err(ControlError::Truncated);
+        }
+        (0..entries).map(|_| self.mac()).collect()
+    }
+}
+
+/// Parses the readable portion of a control command: the class and
+/// command bytes followed by command-specific data.
+pub fn parse_command(buf: &[u8]) -> Result<ControlCommand, ControlError> {
+    let mut reader = Reader(buf);
+    let class = reader.u8()?;
+    let command = reader.u8()?;
+    let cmd = match (class, command) {
+        (VIRTIO_NET_CTRL_RX, _) => {
+            let mode = match command {
+                VIRTIO_NET_CTRL_RX_PROMISC => RxMode::Promiscuous,
+                VIRTIO_NET_CTRL_RX_ALLMULTI => RxMode::AllMulticast,
+                VIRTIO_NET_CTRL_RX_ALLUNI => RxMode::AllUnicast,
+                VIRTIO_NET_CTRL_RX_NOMULTI => RxMode::NoMulticast,
+                VIRTIO_NET_CTRL_RX_NOUNI => RxMode::NoUnicast,
+                VIRTIO_NET_CTRL_RX_NOBCAST => RxMode::NoBroadcast,
+                _ => return Err(ControlError::Unsupported(class, command)),
+            };
+            ControlCommand::SetRxMode(mode, reader.u8()? != 0)
+        }
+        (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET) => ControlCommand::SetMacTable {
+            unicast: reader.mac_table()?,
+            multicast: reader.mac_table()?,
+        },
+        (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET) => {
+            ControlCommand::SetMacAddress(reader.mac()?)
+        }
+        (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_ADD | VIRTIO_NET_CTRL_VLAN_DEL) => {
+            let vid = reader.u16()?;
+            if vid as usize >= VLAN_ID_COUNT {
+                return Err(ControlError::InvalidVlan(vid));
+            }
+            if command == VIRTIO_NET_CTRL_VLAN_ADD {
+                ControlCommand::AddVlan(vid)
+            } else {
+                ControlCommand::RemoveVlan(vid)
+            }
+        }
+        (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) => {
+            ControlCommand::SetQueuePairs(reader.u16()?)
+        }
+        (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_RSS_CONFIG) => {
+            let hash_types = reader.u32()?;
+            let table_len = reader.u16()? as usize + 1;
+            if !table_len.is_power_of_two() {
+                return Err(ControlError::InvalidIndirectionTable(table_len));
+            }
+            let unclassified_queue = reader.u16()?;
+            let indirection_table = (0..table_len)
+                .map(|_| reader.u16())
+                .collect::<Result<_, _>>()?;
+            let max_tx_vq = reader.u16()?;
+            let key_len = reader.u8()? as usize;
+            let key = reader.bytes(key_len)?.to_vec();
+            ControlCommand::SetRss(RssRequest {
+                hash_types,
+                indirection_table,
+                unclassified_queue,
+                max_tx_vq,
+                key,
+            })
+        }
+        _ => return Err(ControlError::Unsupported(class, command)),
+    };
+    Ok(cmd)
+}
+
+/// The receive filter programmed by the guest via the control queue.
+///
+/// Workers hold a copy and consult it for each received frame; the
+/// coordinator pushes updates to them after each change.
+#[derive(Debug, Clone, Inspect)]
+pub struct RxFilter {
+    pub promiscuous: bool,
+    all_multicast: bool,
+    all_unicast: bool,
+    no_multicast: bool,
+    no_unicast: bool,
+    no_broadcast: bool,
+    /// The unicast MAC table was too large to track.
+    unicast_overflow: bool,
+    /// The multicast MAC table was too large to track.
+    multicast_overflow: bool,
+    mac_address: MacAddress,
+    #[inspect(with = "Vec::len")]
+    unicast: Vec<[u8; 6]>,
+    #[inspect(with = "Vec::len")]
+    multicast: Vec<[u8; 6]>,
+    #[inspect(with = "|x| x.iter().map(|w| w.count_ones()).sum::<u32>()")]
+    vlans: Box<[u64; VLAN_ID_COUNT / 64]>,
+}
+
+impl RxFilter {
+    /// Returns the filter in effect after device reset.
+    ///
+    /// The device starts out promiscuous so that drivers that never program
+    /// a filter still receive all traffic. Tagged frames are only filtered
+    /// if the driver negotiated VLAN filtering.
+    pub fn new(mac_address: MacAddress, vlan_filtering: bool) -> Self {
+        let vlans = if vlan_filtering { 0 } else { !0 };
+        Self {
+            promiscuous: true,
+            all_multicast: false,
+            all_unicast: false,
+            no_multicast: false,
+            no_unicast: false,
+            no_broadcast: false,
+            unicast_overflow: false,
+            multicast_overflow: false,
+            mac_address,
+            unicast: Vec::new(),
+            multicast: Vec::new(),
+            vlans: Box::new([vlans; VLAN_ID_COUNT / 64]),
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: RxMode, on: bool) {
+        let field = match mode {
+            RxMode::Promiscuous => &mut self.promiscuous,
+            RxMode::AllMulticast => &mut self.all_multicast,
+            RxMode::AllUnicast => &mut self.all_unicast,
+            RxMode::NoMulticast => &mut self.no_multicast,
+            RxMode::NoUnicast => &mut self.no_unicast,
+            RxMode::NoBroadcast => &mut self.no_broadcast,
+        };
+        *field = on;
+    }
+
+    pub fn set_mac_address(&mut self, mac_address: [u8; 6]) {
+        self.mac_address = mac_address.into();
+    }
+
+    /// Replaces the MAC filter tables. Tables too large to track switch the
+    /// filter to accepting all addresses of that kind, independently of the
+    /// ALLUNI and ALLMULTI modes set by the guest.
+    pub fn set_mac_table(&mut self, unicast: Vec<[u8; 6]>, multicast: Vec<[u8; 6]>) {
+        self.unicast_overflow = unicast.len() > MAX_MAC_FILTERS;
+        self.multicast_overflow = multicast.len() > MAX_MAC_FILTERS;
+        self.unicast = if self.unicast_overflow {
+            Vec::new()
+        } else {
+            unicast
+        };
+        self.multicast = if self.multicast_overflow {
+            Vec::new()
+        } else {
+            multicast
+        };
+    }
+
+    pub fn set_vlan(&mut self, vid: u16, allowed: bool) {
+        let (word, bit) = (vid as usize / 64, vid % 64);
+        if allowed {
+            self.vlans[word] |= 1 << bit;
+        } else {
+            self.vlans[word] &= !(1 << bit);
+        }
+    }
+
+    /// Returns whether a frame should be delivered to the guest, given at
+    /// least the first [`FRAME_PREFIX_LEN`] bytes of it.
+    pub fn accepts(&self, frame: &[u8]) -> bool {
+        const ETHERTYPE_VLAN: u16 = 0x8100;
+
+        if self.promiscuous {
+            return true;
+        }
+        let Some(dst) = frame.first_chunk::<6>() else {
+            // Leave runt frames for the guest to drop.
+            return true;
+        };
+        if frame.len() >= FRAME_PREFIX_LEN
+            && u16::from_be_bytes([frame[12], frame[13]]) == ETHERTYPE_VLAN
+        {
+            let vid = u16::from_be_bytes([frame[14], frame[15]]) & 0xfff;
+            if self.vlans[vid as usize / 64] & (1 << (vid % 64)) == 0 {
+                return false;
+            }
+        }
+        if dst == &[0xff; 6] {
+            !self.no_broadcast
+        } else if dst[0] & 1 != 0 {
+            !self.no_multicast
+                && (self.all_multicast || self.multicast_overflow || self.multicast.contains(dst))
+        } else {
+            !self.no_unicast
+                && (self.all_unicast
+                    || self.unicast_overflow
+                    || *dst == self.mac_address.to_bytes()
+                    || self.unicast.contains(dst))
+        }
+    }
+}
+
+/// The number of leading frame bytes [`RxFilter::accepts`] inspects: the
+/// Ethernet header plus an 802.1Q tag control field.
+pub const FRAME_PREFIX_LEN: usize = 16;
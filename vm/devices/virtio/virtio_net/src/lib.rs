@@ -4,20 +4,25 @@
 //! Virtio network device implementation.
 //!
 //! This crate implements a virtio-net device that connects a guest's virtual
-//! NIC to a pluggable [`net_backend::Endpoint`]. It currently operates with a
-//! single queue pair (one RX, one TX) and supports synchronous and asynchronous
-//! TX completion modes depending on the backend.
+//! NIC to a pluggable [`net_backend::Endpoint`]. It offers one queue pair per
+//! endpoint queue with RSS steering, a control queue for receive filtering and
+//! queue configuration, mergeable receive buffers, and supports synchronous and
+//! asynchronous TX completion modes depending on the backend.
 
 #![expect(missing_docs)]
 #![forbid(unsafe_code)]
 
 mod buffers;
+mod control;
 pub mod resolver;
 
 #[cfg(test)]
 mod tests;
 
 use crate::buffers::VirtioWorkPool;
+use crate::control::ControlCommand;
+use crate::control::ControlError;
+use crate::control::RxFilter;
 use anyhow::Context as _;
 use bitfield_struct::bitfield;
 use guestmem::GuestMemory;
@@ -28,6 +33,7 @@
 use net_backend::Endpoint;
 use net_backend::EndpointAction;
 use net_backend::QueueConfig;
+use net_backend::RssConfig;
 use net_backend::RxId;
 use net_backend::TxFlags;
 use net_backend::TxId;
@@ -39,6 +45,7 @@
 use pal_async::wait::PolledWait;
 use std::future::pending;
 use std::mem::offset_of;
+use std::pin::pin;
 use std::sync::Arc;
 use std::task::Poll;
 use task_control::AsyncRun;
@@ -85,7 +92,7 @@ struct NetworkFeaturesBank0 {
     pub ctrl_vq: bool,
     pub ctrl_rx: bool,
     pub ctrl_vlan: bool,
-    _reserved3: bool,
+    pub ctrl_rx_extra: bool,
     pub guest_announce: bool,
     pub mq: bool,
     pub ctrl_mac_addr: bool,
@@ -136,9 +143,12 @@ struct NetStatus {
     _reserved: u16,
 }
 
-const DEFAULT_MTU: u16 = 1514;
+/// The MTU reported when none is configured.
+const DEFAULT_MTU: u16 = 1500;
+
+/// The minimum MTU allowed by the spec for VIRTIO_NET_F_MTU.
+const MIN_MTU: u16 = 68;
 
-#[expect(dead_code)]
 const VIRTIO_NET_MAX_QUEUES: u16 = 0x8000;
 
 #[repr(C)]
@@ -223,6 +233,22 @@ struct Adapter {
     tx_fast_completions: bool,
     mac_address: MacAddress,
     tx_offload_support: TxOffloadSupport,
+    /// The MTU offered via VIRTIO_NET_F_MTU, if configured.
+    mtu: Option<u16>,
+    /// The endpoint's RSS indirection table size, or 0 if RSS is not
+    /// supported.
+    indirection_table_size: u16,
+}
+
+impl Adapter {
+    /// The index of the control queue, which follows the last queue pair.
+    fn control_queue_index(&self) -> u16 {
+        2 * self.max_queue_pairs
+    }
+
+    fn rss_supported(&self) -> bool {
+        self.max_queue_pairs > 1 && self.indirection_table_size > 0
+    }
 }
 
 pub struct Device {
@@ -270,9 +296,19 @@ fn traits(&self) -> DeviceTraits {
             .with_csum(csum)
             .with_guest_csum(true)
             .with_host_tso4(host_tso)
-            .with_host_tso6(host_tso);
-
-        let features_bank1 = NetworkFeaturesBank1::new().with_host_uso(host_uso);
+            .with_host_tso6(host_tso)
+            .with_mtu(self.adapter.mtu.is_some())
+            .with_mrg_rxbuf(true)
+            .with_ctrl_vq(true)
+            .with_ctrl_rx(true)
+            .with_ctrl_rx_extra(true)
+            .with_ctrl_vlan(true)
+            .with_ctrl_mac_addr(true)
+            .with_mq(self.adapter.max_queue_pairs > 1);
+
+        let features_bank1 = NetworkFeaturesBank1::new()
+            .with_host_uso(host_uso)
+            .with_rss(self.adapter.rss_supported());
 
         DeviceTraits {
             device_id: virtio::spec::VirtioDeviceType::NET,
@@ -282,7 +318,7 @@ fn traits(&self) -> DeviceTraits {
                 .with_ring_event_idx(true)
                 .with_ring_indirect_desc(true)
                 .with_ring_packed(true),
-            max_queues: 2 * self.registers.max_virtqueue_pairs,
+            max_queues: self.adapter.control_queue_index() + 1,
             device_register_length: size_of::<NetConfig>() as u32,
             shared_memory: DeviceTraitsSharedMemory { id: 0, size: 0 },
         }
@@ -330,6 +366,19 @@ async fn start_queue(
         )
         .context("failed creating virtio net queue")?;
 
+        if idx == self.adapter.control_queue_index() {
+            let running = self.coordinator.stop().await;
+            self.coordinator.task_mut().control = Some(ControlQueue {
+                queue,
+                mem: guest_memory,
+                pending: Vec::new(),
+            });
+            if running {
+                self.coordinator.start();
+            }
+            return Ok(());
+        }
+
         let negotiated_features = NetworkFeaturesBank0::from(features.bank(0));
         let negotiated_features_bank1 = NetworkFeaturesBank1::from(features.bank(1));
         let pair_idx = (idx / 2) as usize;
@@ -377,9 +426,20 @@ async fn start_queue(
                     (queue, queue_size, pending_queue, pending_queue_size)
                 };
 
-                if first_pair {
-                    self.insert_coordinator(self.pairs.len() as u16);
-                }
+                // Stop the coordinator to add the worker, and have it
+                // reallocate the endpoint queues to include the new pair.
+                let running = if first_pair {
+                    self.insert_coordinator(
+                        self.pairs.len() as u16,
+                        negotiated_features,
+                        negotiated_features_bank1,
+                    );
+                    true
+                } else {
+                    let running = self.coordinator.stop().await;
+                    self.coordinator.state_mut().unwrap().restart = true;
+                    running
+                };
 
                 let virtio_state = VirtioState {
                     rx_queue,
@@ -395,7 +455,7 @@ async fn start_queue(
                     negotiated_features_bank1,
                 );
 
-                if first_pair {
+                if running {
                     self.coordinator.start();
                 }
             }
@@ -407,6 +467,15 @@ async fn start_queue(
     }
 
     async fn stop_queue(&mut self, idx: u16) -> Option<QueueState> {
+        if idx == self.adapter.control_queue_index() {
+            let running = self.coordinator.stop().await;
+            self.coordinator.task_mut().control = None;
+            if running {
+                self.coordinator.start();
+            }
+            return None;
+        }
+
         let pair_idx = (idx / 2) as usize;
 
         if pair_idx < self.pairs.len() {
@@ -422,13 +491,24 @@ async fn stop_queue(&mut self, idx: u16) -> Option<QueueState> {
             } else if matches!(self.pairs[pair_idx], QueuePairState::Active) {
                 // Stop the coordinator (which stops all workers).
                 self.coordinator.stop().await;
-                if let Some(coordinator) = self.coordinator.state_mut() {
-                    for worker in &mut coordinator.workers {
-                        worker.stop().await;
-                    }
+                let coordinator = self.coordinator.state_mut().unwrap();
+                for worker in &mut coordinator.workers {
+                    worker.stop().await;
                 }
-                let _ = self.coordinator.remove();
                 self.pairs[pair_idx] = QueuePairState::Empty;
+                if self
+                    .pairs
+                    .iter()
+                    .any(|p| matches!(p, QueuePairState::Active))
+                {
+                    // Drop this pair's worker and redistribute the endpoint
+                    // queues across the remaining pairs.
+                    coordinator.workers[pair_idx].remove();
+                    coordinator.restart = true;
+                    self.coordinator.start();
+                } else {
+                    let _ = self.coordinator.remove();
+                }
             }
         }
 
@@ -438,6 +518,12 @@ async fn stop_queue(&mut self, idx: u16) -> Option<QueueState> {
 
     async fn reset(&mut self) {
         self.pairs.fill_with(|| QueuePairState::Empty);
+        self.coordinator.stop().await;
+        if let Some(coordinator) = self.coordinator.state_mut() {
+            coordinator.stop_workers().await;
+            self.coordinator.remove();
+        }
+        self.coordinator.task_mut().control = None;
     }
 
     fn supports_save_restore(&self) -> bool {
@@ -492,6 +578,7 @@ struct QueueStats {
     tx_packets: Counter,
     tx_dropped: Counter,
     rx_dropped: Counter,
+    rx_filtered: Counter,
     tx_packets_per_wake: Histogram<10>,
     rx_packets_per_wake: Histogram<10>,
 }
@@ -506,10 +593,10 @@ struct ActiveState {
 }
 
 impl ActiveState {
-    fn new(mem: GuestMemory, rx_queue_size: u16, tx_queue_size: u16) -> Self {
+    fn new(mem: GuestMemory, rx_queue_size: u16, tx_queue_size: u16, merge_capacity: u32) -> Self {
         Self {
             pending_tx_packets: (0..tx_queue_size).map(|_| None).collect(),
-            pending_rx_packets: VirtioWorkPool::new(mem, rx_queue_size),
+            pending_rx_packets: VirtioWorkPool::new(mem, rx_queue_size, merge_capacity),
             data: ProcessingData::new(rx_queue_size, tx_queue_size),
             stats: Default::default(),
         }
@@ -523,14 +610,23 @@ struct PendingTxPacket {
 
 pub struct NicBuilder {
     max_queue_pairs: u16,
+    mtu: Option<u16>,
 }
 
 impl NicBuilder {
+    /// Limits the number of queue pairs. The device offers no more queue
+    /// pairs than the endpoint has queues.
     pub fn max_queues(mut self, max_queue_pairs: u16) -> Self {
         self.max_queue_pairs = max_queue_pairs;
         self
     }
 
+    /// Reports `mtu` to the guest via VIRTIO_NET_F_MTU.
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.mtu = Some(mtu.max(MIN_MTU));
+        self
+    }
+
     /// Creates a new NIC.
     pub fn build(
         self,
@@ -538,10 +634,11 @@ pub fn build(
         endpoint: Box<dyn Endpoint>,
         mac_address: MacAddress,
     ) -> Device {
-        // TODO: Implement VIRTIO_NET_F_MQ and VIRTIO_NET_F_RSS logic based on mulitqueue support.
-        // let multiqueue = endpoint.multiqueue_support();
-        // let max_queue_pairs = self.max_queue_pairs.clamp(1, multiqueue.max_queues.min(VIRTIO_NET_MAX_QUEUES));
-        let max_queue_pairs = 1;
+        let multiqueue = endpoint.multiqueue_support();
+        let max_queue_pairs = self
+            .max_queue_pairs
+            .min(multiqueue.max_queues)
+            .clamp(1, VIRTIO_NET_MAX_QUEUES);
 
         let driver = driver_source.simple();
         let tx_offload_support = endpoint.tx_offload_support();
@@ -551,23 +648,42 @@ pub fn build(
             tx_fast_completions: endpoint.tx_fast_completions(),
             mac_address,
             tx_offload_support,
+            mtu: self.mtu,
+            indirection_table_size: multiqueue.indirection_table_size,
         });
 
         let coordinator = TaskControl::new(CoordinatorState {
             endpoint,
             adapter: adapter.clone(),
+            control: None,
         });
 
+        let (rss_max_key_size, rss_max_indirection_table_length, supported_hash_types) =
+            if adapter.rss_supported() {
+                (
+                    control::RSS_MAX_KEY_SIZE,
+                    adapter.indirection_table_size,
+                    control::VIRTIO_NET_RSS_HASH_TYPE_IPV4
+                        | control::VIRTIO_NET_RSS_HASH_TYPE_TCPV4
+                        | control::VIRTIO_NET_RSS_HASH_TYPE_UDPV4
+                        | control::VIRTIO_NET_RSS_HASH_TYPE_IPV6
+                        | control::VIRTIO_NET_RSS_HASH_TYPE_TCPV6
+                        | control::VIRTIO_NET_RSS_HASH_TYPE_UDPV6,
+                )
+            } else {
+                (0, 0, 0)
+            };
+
         let registers = NetConfig {
             mac: mac_address.to_bytes(),
             status: NetStatus::new().with_link_up(true).into(),
             max_virtqueue_pairs: max_queue_pairs,
-            mtu: DEFAULT_MTU,
+            mtu: self.mtu.unwrap_or(DEFAULT_MTU),
             speed: 0xffffffff,
             duplex: 0xff,
-            rss_max_key_size: 0,
-            rss_max_indirection_table_length: 0,
-            supported_hash_types: 0,
+            rss_max_key_size,
+            rss_max_indirection_table_length,
+            supported_hash_types,
         };
 
         Device {
@@ -586,6 +702,7 @@ impl Device {
     pub fn builder() -> NicBuilder {
         NicBuilder {
             max_queue_pairs: !0,
+            mtu: None,
         }
     }
 }
@@ -597,7 +714,12 @@ fn inspect_mut(&mut self, req: inspect::Request<'_>) {
 }
 
 impl Device {
-    fn insert_coordinator(&mut self, num_queues: u16) {
+    fn insert_coordinator(
+        &mut self,
+        num_queues: u16,
+        features: NetworkFeaturesBank0,
+        features_bank1: NetworkFeaturesBank1,
+    ) {
         self.coordinator.insert(
             &self.adapter.driver,
             "virtio-net-coordinator".to_string(),
@@ -607,6 +729,12 @@ fn insert_coordinator(&mut self, num_queues: u16) {
                     .collect(),
                 num_queues,
                 restart: true,
+                active_pairs: 1,
+                rss: None,
+                rx_filter: RxFilter::new(self.adapter.mac_address, features.ctrl_vlan()),
+                rx_filter_changed: false,
+                features,
+                features_bank1,
             },
         );
     }
@@ -623,8 +751,7 @@ fn insert_worker(
         negotiated_features_bank1: NetworkFeaturesBank1,
     ) {
         let mut builder = self.driver_source.builder();
-        // TODO: set this correctly
-        builder.target_vp(0);
+        builder.target_vp(idx as u32);
         // If tx completions arrive quickly, then just do tx processing
         // on whatever processor the guest happens to signal from.
         // Subsequent transmits will be pulled from the completion
@@ -632,18 +759,28 @@ fn insert_worker(
         builder.run_on_target(!self.adapter.tx_fast_completions);
         let driver = builder.build("virtio-net");
 
+        // With mergeable buffers, each buffer offered to the endpoint must
+        // hold a full frame at the MTU.
+        let merge_capacity = if negotiated_features.mrg_rxbuf() {
+            self.registers.mtu as u32 + net_backend::ETHERNET_VLAN_HEADER_LEN
+        } else {
+            0
+        };
         let active_state = ActiveState::new(
             guest_memory.clone(),
             virtio_state.rx_queue_size,
             virtio_state.tx_queue_size,
+            merge_capacity,
         );
+        let coordinator = self.coordinator.state_mut().unwrap();
         let worker = Worker {
             virtio_state,
             active_state,
             negotiated_features,
             negotiated_features_bank1,
+            rx_filter: coordinator.rx_filter.clone(),
+            rx_completions: Vec::new(),
         };
-        let coordinator = self.coordinator.state_mut().unwrap();
         let worker_task = &mut coordinator.workers[idx];
         worker_task.insert(&driver, "virtio-net".to_string(), worker);
         worker_task.start();
@@ -654,11 +791,56 @@ struct Coordinator {
     workers: Vec<TaskControl<NetQueue, Worker>>,
     num_queues: u16,
     restart: bool,
+    /// The number of queue pairs the guest has enabled via
+    /// VIRTIO_NET_CTRL_MQ.
+    active_pairs: u16,
+    rss: Option<RssState>,
+    rx_filter: RxFilter,
+    rx_filter_changed: bool,
+    features: NetworkFeaturesBank0,
+    features_bank1: NetworkFeaturesBank1,
+}
+
+/// The RSS configuration programmed by the guest.
+struct RssState {
+    key: Vec<u8>,
+    /// Queue pair indexes, each less than the coordinator's `active_pairs`.
+    indirection_table: Vec<u16>,
+}
+
+/// The control virtqueue.
+struct ControlQueue {
+    queue: VirtioQueue,
+    mem: GuestMemory,
+    /// Processed commands and their acks, completed once the workers have
+    /// picked up the resulting configuration.
+    pending: Vec<(VirtioQueueCallbackWork, u8)>,
+}
+
+impl ControlQueue {
+    fn complete_pending(&mut self) {
+        for (work, ack) in self.pending.drain(..) {
+            let len = match work.write(&self.mem, &[ack]) {
+                Ok(()) => 1,
+                Err(err) => {
+                    tracelimit::warn_ratelimited!(
+                        error = &err as &dyn std::error::Error,
+                        "failed to write control command ack"
+                    );
+                    0
+                }
+            };
+            self.queue.complete(work, len);
+        }
+    }
 }
 
 struct CoordinatorState {
     endpoint: Box<dyn Endpoint>,
     adapter: Arc<Adapter>,
+    /// Kept here rather than in [`Coordinator`] so that the control queue
+    /// outlives the queue pairs across coordinator removal.
+    control: Option<ControlQueue>,
 }
 
 impl InspectTaskMut<Coordinator> for CoordinatorState {
@@ -667,7 +849,9 @@ fn inspect_mut(&mut self, req: inspect::Request<'_>, coordinator: Option<&mut Co
 
         let adapter = self.adapter.as_ref();
         resp.field("mac_address", adapter.mac_address)
-            .field("max_queue_pairs", adapter.max_queue_pairs);
+            .field("max_queue_pairs", adapter.max_queue_pairs)
+            .field("mtu", adapter.mtu)
+            .field("control_queue", self.control.as_ref().map(|c| &c.queue));
 
         resp.field("endpoint_type", self.endpoint.endpoint_type())
             .field(
@@ -677,6 +861,9 @@ fn inspect_mut(&mut self, req: inspect::Request<'_>, coordinator: Option<&mut Co
             .field_mut("endpoint", self.endpoint.as_mut());
 
         if let Some(coordinator) = coordinator {
+            resp.field("active_pairs", coordinator.active_pairs)
+                .field("rss", coordinator.rss.is_some())
+                .field("rx_filter", &coordinator.rx_filter);
             resp.fields_mut(
                 "queues",
                 coordinator.workers[..coordinator.num_queues as usize]
@@ -716,17 +903,170 @@ async fn process(
                 }
                 self.restart = false;
             }
+            if self.rx_filter_changed {
+                // Push the new receive filter to the workers.
+                stop.until_stopped(self.stop_workers()).await?;
+                for worker in &mut self.workers {
+                    if let Some(worker) = worker.state_mut() {
+                        worker.rx_filter = self.rx_filter.clone();
+                    }
+                }
+                self.rx_filter_changed = false;
+            }
+            if let Some(control) = &mut state.control {
+                control.complete_pending();
+            }
             self.start_workers();
-            match stop
-                .until_stopped(state.endpoint.wait_for_endpoint_action())
+            let action = {
+                let mut action = pin!(state.endpoint.wait_for_endpoint_action());
+                let control = &mut state.control;
+                stop.until_stopped(std::future::poll_fn(|cx| {
+                    if let Some(control) = control.as_mut()
+                        && control.queue.poll_kick(cx).is_ready()
+                    {
+                        return Poll::Ready(None);
+                    }
+                    action.as_mut().poll(cx).map(Some)
+                }))
                 .await?
-            {
-                EndpointAction::RestartRequired => self.restart = true,
-                EndpointAction::LinkStatusNotify(_) => {
+            };
+            match action {
+                Some(EndpointAction::RestartRequired) => self.restart = true,
+                Some(EndpointAction::LinkStatusNotify(_)) => {
                     tracing::error!("unexpected link status notification")
                 }
+                None => {
+                    let control = state.control.as_mut().unwrap();
+                    self.process_control_queue(control, &state.adapter);
+                }
+            }
+        }
+    }
+
+    /// Processes all available control commands, leaving them pending
+    /// completion.
+    fn process_control_queue(&mut self, control: &mut ControlQueue, adapter: &Adapter) {
+        loop {
+            let work = match control.queue.try_next() {
+                Ok(Some(work)) => work,
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::error!(
+                        error = &err as &dyn std::error::Error,
+                        "failed to read control queue"
+                    );
+                    break;
+                }
+            };
+            let ack = match self.handle_control_command(&control.mem, &work, adapter) {
+                Ok(changed) => {
+                    self.rx_filter_changed |= changed;
+                    control::VIRTIO_NET_OK
+                }
+                Err(err) => {
+                    tracelimit::warn_ratelimited!(
+                        error = &err as &dyn std::error::Error,
+                        "control command failed"
+                    );
+                    control::VIRTIO_NET_ERR
+                }
+            };
+            control.pending.push((work, ack));
+        }
+    }
+
+    /// Reads and applies a control command, returning true if the receive
+    /// filter changed.
+    fn handle_control_command(
+        &mut self,
+        mem: &GuestMemory,
+        work: &VirtioQueueCallbackWork,
+        adapter: &Adapter,
+    ) -> Result<bool, ControlError> {
+        let len = work.get_payload_length(false);
+        if len > control::MAX_COMMAND_SIZE {
+            return Err(ControlError::TooLarge(len));
+        }
+        let mut buf = vec![0; len as usize];
+        work.read(mem, &mut buf)
+            .map_err(|_| ControlError::Truncated)?;
+        let command = control::parse_command(&buf)?;
+        tracing::debug!(?command, "virtio-net control command");
+
+        let negotiated = match &command {
+            ControlCommand::SetRxMode(mode, _) => {
+                if mode.is_extra() {
+                    self.features.ctrl_rx_extra()
+                } else {
+                    self.features.ctrl_rx()
+                }
+            }
+            ControlCommand::SetMacTable { .. } => self.features.ctrl_rx(),
+            ControlCommand::SetMacAddress(_) => self.features.ctrl_mac_addr(),
+            ControlCommand::AddVlan(_) | ControlCommand::RemoveVlan(_) => self.features.ctrl_vlan(),
+            ControlCommand::SetQueuePairs(_) => self.features.mq(),
+            ControlCommand::SetRss(_) => self.features_bank1.rss(),
+        };
+        if !negotiated {
+            return Err(ControlError::NotNegotiated);
+        }
+
+        match command {
+            ControlCommand::SetRxMode(mode, on) => self.rx_filter.set_mode(mode, on),
+            ControlCommand::SetMacTable { unicast, multicast } => {
+                self.rx_filter.set_mac_table(unicast, multicast)
+            }
+            ControlCommand::SetMacAddress(mac) => self.rx_filter.set_mac_address(mac),
+            ControlCommand::AddVlan(vid) => self.rx_filter.set_vlan(vid, true),
+            ControlCommand::RemoveVlan(vid) => self.rx_filter.set_vlan(vid, false),
+            ControlCommand::SetQueuePairs(pairs) => {
+                if pairs == 0 || pairs as usize > self.workers.len() {
+                    return Err(ControlError::InvalidQueuePairs(pairs.into()));
+                }
+                self.active_pairs = pairs;
+                self.rss = None;
+                self.restart = true;
+                return Ok(false);
+            }
+            ControlCommand::SetRss(rss) => {
+                let max_pairs = self.workers.len();
+                if rss.key.len() > control::RSS_MAX_KEY_SIZE as usize {
+                    return Err(ControlError::InvalidKeyLength(rss.key.len()));
+                }
+                let indirection_table = if rss.hash_types == 0 {
+                    // Hashing disabled: steer everything to the unclassified
+                    // queue.
+                    vec![rss.unclassified_queue]
+                } else {
+                    if rss.indirection_table.len() > adapter.indirection_table_size as usize {
+                        return Err(ControlError::InvalidIndirectionTable(
+                            rss.indirection_table.len(),
+                        ));
+                    }
+                    rss.indirection_table
+                };
+                // Enable enough queue pairs for every receive queue the table
+                // refers to and for the requested transmit queues.
+                let pairs = indirection_table
+                    .iter()
+                    .map(|&q| q as u32 + 1)
+                    .chain([rss.unclassified_queue as u32 + 1, rss.max_tx_vq as u32])
+                    .max()
+                    .unwrap()
+                    .max(1);
+                if pairs as usize > max_pairs {
+                    return Err(ControlError::InvalidQueuePairs(pairs));
+                }
+                self.active_pairs = pairs as u16;
+                self.rss = Some(RssState {
+                    key: rss.key,
+                    indirection_table,
+                });
+                self.restart = true;
+                return Ok(false);
             }
         }
+        Ok(true)
     }
 
     async fn stop_workers(&mut self) {
@@ -741,20 +1081,49 @@ async fn restart_queues(&mut self, c_state: &mut CoordinatorState) -> Result<(),
             worker.task_mut().state = None;
         }
 
-        let queue_config = (0..self.workers.len())
+        // Allocate endpoint queues for the enabled pairs that have been
+        // started by the guest.
+        let num_queues = self
+            .workers
+            .iter()
+            .take(self.active_pairs as usize)
+            .take_while(|worker| worker.has_state())
+            .count();
+        if num_queues == 0 {
+            return Ok(());
+        }
+
+        let queue_config = (0..num_queues)
             .map(|_| QueueConfig {
                 driver: Box::new(c_state.adapter.driver.clone()),
             })
             .collect::<Vec<_>>();
 
+        // Fold the guest's table onto the queues that actually exist.
+        let indirection_table;
+        let rss = if let Some(rss) = &self.rss {
+            indirection_table = rss
+                .indirection_table
+                .iter()
+                .map(|&q| q % num_queues as u16)
+                .collect::<Vec<_>>();
+            Some(RssConfig {
+                key: &rss.key,
+                indirection_table: &indirection_table,
+                flags: 0,
+            })
+        } else {
+            None
+        };
+
         let mut queues = Vec::new();
         c_state
             .endpoint
-            .get_queues(queue_config, None, &mut queues)
+            .get_queues(queue_config, rss.as_ref(), &mut queues)
             .await
             .map_err(WorkerError::Endpoint)?;
 
-        assert_eq!(queues.len(), self.workers.len());
+        assert_eq!(queues.len(), num_queues);
 
         for (worker, mut queue) in self.workers.iter_mut().zip(queues) {
             let state = &mut worker.state_mut().unwrap().active_state;
@@ -836,6 +1205,11 @@ struct Worker {
     negotiated_features: NetworkFeaturesBank0,
     #[inspect(skip)]
     negotiated_features_bank1: NetworkFeaturesBank1,
+    #[inspect(skip)]
+    rx_filter: RxFilter,
+    /// Scratch space for descriptor chains to complete on receive.
+    #[inspect(skip)]
+    rx_completions: Vec<(VirtioQueueCallbackWork, u32)>,
 }
 
 impl Worker {
@@ -1246,7 +1620,7 @@ fn process_virtio_rx(
         {
             tracing::trace!("rx packet");
             match self.active_state.pending_rx_packets.queue_work(work) {
-                Ok(rx_id) => rx_ids.push(rx_id),
+                Ok(rx_id) => rx_ids.extend(rx_id),
                 Err(work) => {
                     // Reason has been traced by the callee.
                     self.active_state.stats.rx_dropped.increment();
@@ -1274,10 +1648,37 @@ fn process_endpoint_rx(
             return Ok(false);
         }
 
-        for ready_id in state.data.rx_ready[..n].iter() {
+        let mut rx_ids = Vec::new();
+        for &ready_id in &state.data.rx_ready[..n] {
+            if !self.rx_filter.promiscuous {
+                let mut prefix = [0; control::FRAME_PREFIX_LEN];
+                let accepted = match state
+                    .pending_rx_packets
+                    .read_frame_prefix(ready_id, &mut prefix)
+                {
+                    Ok(len) => self.rx_filter.accepts(&prefix[..len]),
+                    Err(_) => true,
+                };
+                if !accepted {
+                    // Give the buffer back to the endpoint.
+                    state.stats.rx_filtered.increment();
+                    state.pending_rx_packets.recycle(ready_id);
+                    rx_ids.push(ready_id);
+                    continue;
+                }
+            }
             state.stats.rx_packets.increment();
-            let (work, bytes) = state.pending_rx_packets.take_rx_work(*ready_id);
-            self.virtio_state.rx_queue.complete(work, bytes);
+            rx_ids.extend(
+                state
+                    .pending_rx_packets
+                    .take_rx_work(ready_id, &mut self.rx_completions),
+            );
+            for (work, bytes) in self.rx_completions.drain(..) {
+                self.virtio_state.rx_queue.complete(work, bytes);
+            }
+        }
+        if !rx_ids.is_empty() {
+            epqueue.rx_avail(&mut state.pending_rx_packets, &rx_ids);
         }
 
         state.stats.rx_packets_per_wake.add_sample(n as u64);
@@ -37,6 +37,9 @@ async fn resolve(
         if let Some(max_queues) = resource.max_queues {
             builder = builder.max_queues(max_queues);
         }
+        if let Some(mtu) = resource.mtu {
+            builder = builder.mtu(mtu);
+        }
 
         let endpoint = resolver
             .resolve(
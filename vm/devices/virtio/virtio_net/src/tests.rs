@@ -71,6 +71,11 @@
 const TX_USED_ADDR: u64 = 0x12000;
 
 // Data area for TX packet headers and payloads
+// Control queue and second queue pair layout
+const CTRL_RINGS_BASE: u64 = 0x3000;
+const RX1_RINGS_BASE: u64 = 0x6000;
+const TX1_RINGS_BASE: u64 = 0x9000;
+
 const DATA_BASE: u64 = 0x20000;
 const TOTAL_MEM_SIZE: usize = 0x30000;
 
@@ -336,6 +341,10 @@ fn new_mock_queue() -> (MockQueue, MockQueueHandle) {
 
 struct MockEndpoint {
     queue_tx: mesh::Sender<MockQueueHandle>,
+    multiqueue: MultiQueueSupport,
+    /// Reports the queue count and RSS indirection table of each
+    /// `get_queues` call.
+    get_queues_tx: mesh::Sender<(usize, Option<Vec<u16>>)>,
 }
 
 impl InspectMut for MockEndpoint {
@@ -352,13 +361,17 @@ fn endpoint_type(&self) -> &'static str {
 
     async fn get_queues(
         &mut self,
-        _config: Vec<QueueConfig>,
-        _rss: Option<&RssConfig<'_>>,
+        config: Vec<QueueConfig>,
+        rss: Option<&RssConfig<'_>>,
         queues: &mut Vec<Box<dyn net_backend::Queue>>,
     ) -> anyhow::Result<()> {
-        let (queue, handle) = new_mock_queue();
-        self.queue_tx.send(handle);
-        queues.push(Box::new(queue));
+        self.get_queues_tx
+            .send((config.len(), rss.map(|rss| rss.indirection_table.to_vec())));
+        for _ in config {
+            let (queue, handle) = new_mock_queue();
+            self.queue_tx.send(handle);
+            queues.push(Box::new(queue));
+        }
         Ok(())
     }
 
@@ -373,10 +386,7 @@ fn tx_offload_support(&self) -> TxOffloadSupport {
     }
 
     fn multiqueue_support(&self) -> MultiQueueSupport {
-        MultiQueueSupport {
-            max_queues: 1,
-            indirection_table_size: 0,
-        }
+        self.multiqueue
     }
 
     fn tx_fast_completions(&self) -> bool {
@@ -432,6 +442,7 @@ struct TestHarness {
     mem: GuestMemory,
     driver: DefaultDriver,
     queue_handle_rx: mesh::Receiver<MockQueueHandle>,
+    get_queues_rx: mesh::Receiver<(usize, Option<Vec<u16>>)>,
     rx_event: Event,
     rx_interrupt_event: Event,
     tx_event: Event,
@@ -440,11 +451,25 @@ struct TestHarness {
     rx_used_idx: u16,
     tx_avail_idx: u16,
     tx_used_idx: u16,
+    ctrl_event: Event,
+    ctrl_interrupt_event: Event,
+    ctrl_avail_idx: u16,
+    ctrl_used_idx: u16,
     next_data_offset: u64,
 }
 
 impl TestHarness {
     fn new(driver: &DefaultDriver) -> Self {
+        Self::with_multiqueue(
+            driver,
+            MultiQueueSupport {
+                max_queues: 1,
+                indirection_table_size: 0,
+            },
+        )
+    }
+
+    fn with_multiqueue(driver: &DefaultDriver, multiqueue: MultiQueueSupport) -> Self {
         let mem = GuestMemory::allocate(TOTAL_MEM_SIZE);
 
         // Initialize RX queue rings
@@ -457,7 +482,12 @@ fn new(driver: &DefaultDriver) -> Self {
 
         // Create mock endpoint with channel
         let (queue_tx, queue_handle_rx) = mesh::channel();
-        let endpoint = MockEndpoint { queue_tx };
+        let (get_queues_tx, get_queues_rx) = mesh::channel();
+        let endpoint = MockEndpoint {
+            queue_tx,
+            multiqueue,
+            get_queues_tx,
+        };
 
         let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
         let mac = MacAddress::new([0x00, 0x15, 0x5d, 0xaa, 0xbb, 0xcc]);
@@ -473,6 +503,7 @@ fn new(driver: &DefaultDriver) -> Self {
             mem,
             driver: driver.clone(),
             queue_handle_rx,
+            get_queues_rx,
             rx_event,
             rx_interrupt_event,
             tx_event,
@@ -481,10 +512,119 @@ fn new(driver: &DefaultDriver) -> Self {
             rx_used_idx: 0,
             tx_avail_idx: 0,
             tx_used_idx: 0,
+            ctrl_event: Event::new(),
+            ctrl_interrupt_event: Event::new(),
+            ctrl_avail_idx: 0,
+            ctrl_used_idx: 0,
             next_data_offset: DATA_BASE,
         }
     }
 
+    /// Start the queue at `idx` with its rings laid out from `base`.
+    async fn start_queue_at(
+        &mut self,
+        idx: u16,
+        base: u64,
+        event: Event,
+        interrupt_event: Event,
+        features: &VirtioDeviceFeatures,
+    ) {
+        init_avail_ring(&self.mem, base + 0x1000);
+        init_used_ring(&self.mem, base + 0x2000);
+        self.device
+            .start_queue(
+                idx,
+                QueueResources {
+                    params: QueueParams {
+                        size: QUEUE_SIZE,
+                        enable: true,
+                        desc_addr: base,
+                        avail_addr: base + 0x1000,
+                        used_addr: base + 0x2000,
+                    },
+                    notify: Interrupt::from_event(interrupt_event),
+                    event,
+                    guest_memory: self.mem.clone(),
+                },
+                features,
+                None,
+            )
+            .await
+            .unwrap();
+    }
+
+    /// Start the control queue, which follows the last queue pair.
+    async fn start_control_queue(&mut self, features: &VirtioDeviceFeatures) {
+        let idx = self.device.traits().max_queues - 1;
+        self.start_queue_at(
+            idx,
+            CTRL_RINGS_BASE,
+            self.ctrl_event.clone(),
+            self.ctrl_interrupt_event.clone(),
+            features,
+        )
+        .await;
+    }
+
+    /// Send a control command and wait for the device's ack.
+    async fn send_control_command(&mut self, desc_index: u16, command: &[u8]) -> u8 {
+        let command_gpa = self.alloc_data(command.len() as u32);
+        let ack_gpa = self.alloc_data(1);
+        self.mem.write_at(command_gpa, command).unwrap();
+        self.mem.write_at(ack_gpa, &[0xff]).unwrap();
+        write_descriptor(
+            &self.mem,
+            CTRL_RINGS_BASE,
+            desc_index,
+            command_gpa,
+            command.len() as u32,
+            DescriptorFlags::new().with_next(true),
+            desc_index + 1,
+        );
+        write_descriptor(
+            &self.mem,
+            CTRL_RINGS_BASE,
+            desc_index + 1,
+            ack_gpa,
+            1,
+            DescriptorFlags::new().with_write(true),
+            0,
+        );
+        make_available(
+            &self.mem,
+            CTRL_RINGS_BASE + 0x1000,
+            QUEUE_SIZE,
+            desc_index,
+            &mut self.ctrl_avail_idx,
+        );
+        self.ctrl_event.signal();
+
+        let (used_id, used_len) = wait_for_used(
+            &self.driver,
+            &self.ctrl_interrupt_event,
+            &self.mem,
+            CTRL_RINGS_BASE + 0x2000,
+            QUEUE_SIZE,
+            &mut self.ctrl_used_idx,
+        )
+        .await;
+        assert_eq!(used_id, desc_index);
+        assert_eq!(used_len, 1);
+        let mut ack = [0];
+        self.mem.read_at(ack_gpa, &mut ack).unwrap();
+        ack[0]
+    }
+
+    /// Wait for the next `get_queues` call on the endpoint.
+    async fn wait_for_get_queues(&mut self) -> (usize, Option<Vec<u16>>) {
+        mesh::CancelContext::new()
+            .with_timeout(Duration::from_secs(5))
+            .until_cancelled(self.get_queues_rx.next())
+            .await
+            .expect("timed out waiting for get_queues")
+            .expect("channel closed")
+    }
+
     /// Enable the device and retrieve the MockQueueHandle.
     async fn enable_and_get_handle(&mut self) -> MockQueueHandle {
         self.enable_and_get_handle_with_features(VirtioDeviceFeatures::new())
@@ -1059,6 +1199,339 @@ async fn disable_and_reenable(driver: DefaultDriver) {
     drop(handle);
 }
 
+// --- Control Queue, Multiqueue, and Mergeable Buffer Tests ---
+
+/// Build an Ethernet frame with the given destination MAC.
+fn make_frame(dst: [u8; 6], len: usize) -> Vec<u8> {
+    let mut frame = vec![0x5a; len];
+    frame[..6].copy_from_slice(&dst);
+    frame[6..12].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x99]);
+    frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    frame
+}
+
+/// Verify that the device offers the control queue, multiqueue, RSS,
+/// mergeable buffers, and MTU when the endpoint and configuration support
+/// them.
+#[async_test]
+async fn feature_negotiation_multiqueue(driver: DefaultDriver) {
+    let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver));
+    let mac = MacAddress::new([0x00, 0x15, 0x5d, 0x01, 0x02, 0x03]);
+    let (queue_tx, _queue_rx) = mesh::channel();
+    let (get_queues_tx, _get_queues_rx) = mesh::channel();
+    let endpoint = MockEndpoint {
+        queue_tx,
+        multiqueue: MultiQueueSupport {
+            max_queues: 4,
+            indirection_table_size: 128,
+        },
+        get_queues_tx,
+    };
+
+    let mut device =
+        Device::builder()
+            .max_queues(8)
+            .mtu(9000)
+            .build(&driver_source, Box::new(endpoint), mac);
+    let traits = device.traits();
+    // Four queue pairs (clamped to the endpoint) plus the control queue.
+    assert_eq!(traits.max_queues, 9);
+
+    let bank0 = NetworkFeaturesBank0::from(traits.device_features.bank(0));
+    assert!(bank0.ctrl_vq());
+    assert!(bank0.ctrl_rx());
+    assert!(bank0.ctrl_vlan());
+    assert!(bank0.mq());
+    assert!(bank0.mrg_rxbuf());
+    assert!(bank0.mtu());
+    let bank1 = NetworkFeaturesBank1::from(traits.device_features.bank(1));
+    assert!(bank1.rss());
+
+    // max_virtqueue_pairs and mtu
+    assert_eq!(device.read_registers_u32(8).await, 4 | (9000 << 16));
+    // rss_max_key_size and rss_max_indirection_table_length
+    assert_eq!(device.read_registers_u32(16).await >> 8, 40 | (128 << 8));
+}
+
+/// A single-queue endpoint gets neither MQ nor RSS, and no MTU is offered
+/// unless configured.
+#[async_test]
+async fn feature_negotiation_single_queue(driver: DefaultDriver) {
+    let mut harness = TestHarness::new(&driver);
+    let traits = harness.device.traits();
+    assert_eq!(traits.max_queues, 3);
+
+    let bank0 = NetworkFeaturesBank0::from(traits.device_features.bank(0));
+    assert!(bank0.ctrl_vq());
+    assert!(!bank0.mq());
+    assert!(!bank0.mtu());
+    let bank1 = NetworkFeaturesBank1::from(traits.device_features.bank(1));
+    assert!(!bank1.rss());
+    assert_eq!(harness.device.read_registers_u32(8).await, 1 | (1500 << 16));
+}
+
+/// Turn off promiscuous mode through the control queue and verify that
+/// frames for other unicast addresses are returned to the endpoint instead
+/// of the guest.
+#[async_test]
+async fn ctrl_rx_filter_drops_other_unicast(driver: DefaultDriver) {
+    let mut harness = TestHarness::new(&driver);
+    let features = VirtioDeviceFeatures::new().with_bank(
+        0,
+        NetworkFeaturesBank0::new()
+            .with_ctrl_vq(true)
+            .with_ctrl_rx(true)
+            .into_bits(),
+    );
+    let mut handle = harness.enable_and_get_handle_with_features(features).await;
+    harness.start_control_queue(&features).await;
+
+    // VIRTIO_NET_CTRL_RX_PROMISC off
+    let ack = harness.send_control_command(0, &[0, 0, 0]).await;
+    assert_eq!(ack, 0);
+    // VIRTIO_NET_CTRL_VLAN_ADD was not negotiated
+    let ack = harness.send_control_command(2, &[2, 0, 5, 0]).await;
+    assert_eq!(ack, 1);
+
+    harness.post_rx_buffer_and_signal(0, 512);
+    harness.post_rx_buffer_and_signal(1, 512);
+    handle.wait_for_rx_pending().await;
+    handle.wait_for_rx_pending().await;
+
+    // Not our address: the buffer goes back to the endpoint.
+    handle.inject_rx_packet(&make_frame([0x02, 0, 0, 0, 0, 0x01], 64));
+    handle.wait_for_rx_pending().await;
+
+    // Our address: delivered.
+    let frame = make_frame([0x00, 0x15, 0x5d, 0xaa, 0xbb, 0xcc], 64);
+    handle.inject_rx_packet(&frame);
+    let (used_id, used_len) = harness.wait_for_rx_used().await;
+    assert_eq!(used_id, 1);
+    assert_eq!(used_len, NET_HEADER_SIZE + frame.len() as u32);
+}
+
+/// Enable ALLMULTI, then program a small MAC table, and verify that the
+/// table does not turn ALLMULTI back off.
+#[async_test]
+async fn ctrl_rx_allmulti_survives_mac_table_set(driver: DefaultDriver) {
+    let mut harness = TestHarness::new(&driver);
+    let features = VirtioDeviceFeatures::new().with_bank(
+        0,
+        NetworkFeaturesBank0::new()
+            .with_ctrl_vq(true)
+            .with_ctrl_rx(true)
+            .into_bits(),
+    );
+    let mut handle = harness.enable_and_get_handle_with_features(features).await;
+    harness.start_control_queue(&features).await;
+
+    // VIRTIO_NET_CTRL_RX_PROMISC off
+    let ack = harness.send_control_command(0, &[0, 0, 0]).await;
+    assert_eq!(ack, 0);
+    // VIRTIO_NET_CTRL_RX_ALLMULTI on
+    let ack = harness.send_control_command(2, &[0, 1, 1]).await;
+    assert_eq!(ack, 0);
+    // VIRTIO_NET_CTRL_MAC_TABLE_SET with one unicast and no multicast
+    // entries.
+    let mut table_set = vec![1, 0];
+    table_set.extend_from_slice(&1u32.to_le_bytes());
+    table_set.extend_from_slice(&[0x02, 0, 0, 0, 0, 1]);
+    table_set.extend_from_slice(&0u32.to_le_bytes());
+    let ack = harness.send_control_command(4, &table_set).await;
+    assert_eq!(ack, 0);
+
+    harness.post_rx_buffer_and_signal(0, 512);
+    handle.wait_for_rx_pending().await;
+
+    // Not in the (empty) multicast table, but ALLMULTI is still on.
+    let frame = make_frame([0x01, 0x00, 0x5e, 0, 0, 1], 64);
+    handle.inject_rx_packet(&frame);
+    let (used_id, used_len) = harness.wait_for_rx_used().await;
+    assert_eq!(used_id, 0);
+    assert_eq!(used_len, NET_HEADER_SIZE + frame.len() as u32);
+}
+
+/// Enable a second queue pair, then use VQ_PAIRS_SET and RSS_CONFIG to
+/// reconfigure the endpoint queues.
+#[async_test]
+async fn ctrl_mq_and_rss_reconfigure_endpoint(driver: DefaultDriver) {
+    let mut harness = TestHarness::with_multiqueue(
+        &driver,
+        MultiQueueSupport {
+            max_queues: 2,
+            indirection_table_size: 4,
+        },
+    );
+    let features = VirtioDeviceFeatures::new()
+        .with_bank(
+            0,
+            NetworkFeaturesBank0::new()
+                .with_ctrl_vq(true)
+                .with_mq(true)
+                .into_bits(),
+        )
+        .with_bank(1, NetworkFeaturesBank1::new().with_rss(true).into_bits());
+    let _handle = harness.enable_and_get_handle_with_features(features).await;
+    assert_eq!(harness.wait_for_get_queues().await, (1, None));
+
+    // Starting the second pair restarts the queues, but only one pair is
+    // enabled until the guest asks for more.
+    for (idx, base) in [(2, RX1_RINGS_BASE), (3, TX1_RINGS_BASE)] {
+        harness
+            .start_queue_at(idx, base, Event::new(), Event::new(), &features)
+            .await;
+    }
+    assert_eq!(harness.wait_for_get_queues().await, (1, None));
+    harness.start_control_queue(&features).await;
+
+    // VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET 2
+    let ack = harness.send_control_command(0, &[4, 0, 2, 0]).await;
+    assert_eq!(ack, 0);
+    assert_eq!(harness.wait_for_get_queues().await, (2, None));
+
+    // Too many pairs.
+    let ack = harness.send_control_command(2, &[4, 0, 3, 0]).await;
+    assert_eq!(ack, 1);
+
+    // VIRTIO_NET_CTRL_MQ_RSS_CONFIG with a four-entry table.
+    let mut command = vec![4, 1];
+    command.extend_from_slice(&0x3fu32.to_le_bytes()); // hash_types
+    command.extend_from_slice(&3u16.to_le_bytes()); // indirection_table_mask
+    command.extend_from_slice(&0u16.to_le_bytes()); // unclassified_queue
+    for q in [1u16, 0, 1, 1] {
+        command.extend_from_slice(&q.to_le_bytes());
+    }
+    command.extend_from_slice(&2u16.to_le_bytes()); // max_tx_vq
+    command.push(40);
+    command.extend_from_slice(&[0x6d; 40]);
+    let ack = harness.send_control_command(4, &command).await;
+    assert_eq!(ack, 0);
+    assert_eq!(
+        harness.wait_for_get_queues().await,
+        (2, Some(vec![1, 0, 1, 1]))
+    );
+}
+
+/// With VIRTIO_NET_F_MRG_RXBUF, small buffers are merged so that a frame
+/// can span several descriptor chains, and the header reports how many were
+/// used.
+#[async_test]
+async fn rx_mergeable_buffers(driver: DefaultDriver) {
+    let mut harness = TestHarness::new(&driver);
+    let features = VirtioDeviceFeatures::new().with_bank(
+        0,
+        NetworkFeaturesBank0::new().with_mrg_rxbuf(true).into_bits(),
+    );
+    let mut handle = harness.enable_and_get_handle_with_features(features).await;
+
+    // Two 800-byte chains are needed to hold a 1518-byte frame.
+    let gpa0 = harness.post_rx_buffer_and_signal(0, 800);
+    let gpa1 = harness.post_rx_buffer_and_signal(1, 800);
+    harness.post_rx_buffer_and_signal(2, 800);
+    handle.wait_for_rx_pending().await;
+
+    let payload: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    handle.inject_rx_packet(&payload);
+
+    assert_eq!(harness.wait_for_rx_used().await, (0, 800));
+    assert_eq!(
+        harness.wait_for_rx_used().await,
+        (1, NET_HEADER_SIZE + 1000 - 800)
+    );
+
+    let mut header = VirtioNetHeader::new_zeroed();
+    harness
+        .mem
+        .read_at(gpa0, &mut header.as_mut_bytes()[..header_size()])
+        .unwrap();
+    assert_eq!(header.num_buffers, 2);
+
+    let first = 800 - NET_HEADER_SIZE as usize;
+    let mut readback = vec![0; payload.len()];
+    harness
+        .mem
+        .read_at(gpa0 + NET_HEADER_SIZE as u64, &mut readback[..first])
+        .unwrap();
+    harness.mem.read_at(gpa1, &mut readback[first..]).unwrap();
+    assert_eq!(readback, payload);
+}
+
+#[test]
+fn ctrl_parse_commands() {
+    use crate::control::ControlCommand;
+    use crate::control::RxMode;
+    use crate::control::parse_command;
+
+    assert_eq!(
+        parse_command(&[0, 0, 1]).unwrap(),
+        ControlCommand::SetRxMode(RxMode::Promiscuous, true)
+    );
+
+    let mut table_set = vec![1, 0];
+    table_set.extend_from_slice(&1u32.to_le_bytes());
+    table_set.extend_from_slice(&[0x02, 0, 0, 0, 0, 1]);
+    table_set.extend_from_slice(&0u32.to_le_bytes());
+    assert_eq!(
+        parse_command(&table_set).unwrap(),
+        ControlCommand::SetMacTable {
+            unicast: vec![[0x02, 0, 0, 0, 0, 1]],
+            multicast: Vec::new(),
+        }
+    );
+
+    // Truncated MAC table, out-of-range VLAN, non-power-of-two RSS table,
+    // and unknown class.
+    assert!(parse_command(&table_set[..8]).is_err());
+    assert!(parse_command(&[2, 0, 0x00, 0x10]).is_err());
+    assert!(parse_command(&[4, 1, 0x3f, 0, 0, 0, 2, 0]).is_err());
+    assert!(parse_command(&[9, 0]).is_err());
+}
+
+#[test]
+fn rx_filter_accepts() {
+    use crate::control::RxFilter;
+    use crate::control::RxMode;
+
+    let mac = [0x00, 0x15, 0x5d, 0xaa, 0xbb, 0xcc];
+    let other = [0x02, 0, 0, 0, 0, 1];
+    let multicast = [0x01, 0x00, 0x5e, 0, 0, 1];
+    let broadcast = [0xff; 6];
+
+    let mut filter = RxFilter::new(mac.into(), true);
+    assert!(filter.accepts(&make_frame(other, 64)));
+
+    filter.set_mode(RxMode::Promiscuous, false);
+    assert!(filter.accepts(&make_frame(mac, 64)));
+    assert!(!filter.accepts(&make_frame(other, 64)));
+    assert!(!filter.accepts(&make_frame(multicast, 64)));
+    assert!(filter.accepts(&make_frame(broadcast, 64)));
+
+    filter.set_mac_table(vec![other], vec![multicast]);
+    assert!(filter.accepts(&make_frame(other, 64)));
+    assert!(filter.accepts(&make_frame(multicast, 64)));
+
+    // A table too large to track accepts all multicast addresses until the
+    // next table replaces it, without affecting ALLMULTI.
+    filter.set_mac_table(Vec::new(), vec![multicast; 1000]);
+    assert!(filter.accepts(&make_frame([0x01, 0, 0, 0, 0, 2], 64)));
+    filter.set_mode(RxMode::AllMulticast, true);
+    filter.set_mac_table(Vec::new(), Vec::new());
+    assert!(filter.accepts(&make_frame([0x01, 0, 0, 0, 0, 2], 64)));
+    filter.set_mode(RxMode::AllMulticast, false);
+    assert!(!filter.accepts(&make_frame([0x01, 0, 0, 0, 0, 2], 64)));
+    filter.set_mac_table(vec![other], vec![multicast]);
+
+    filter.set_mode(RxMode::NoBroadcast, true);
+    assert!(!filter.accepts(&make_frame(broadcast, 64)));
+
+    // VLAN filtering was negotiated, so tagged frames need their VLAN added.
+    let mut tagged = make_frame(mac, 64);
+    tagged[12..16].copy_from_slice(&[0x81, 0x00, 0x00, 0x05]);
+    assert!(!filter.accepts(&tagged));
+    filter.set_vlan(5, true);
+    assert!(filter.accepts(&tagged));
+}
+
 // --- TX Offload Parsing Tests ---
 
 use net_backend::TxFlags;
@@ -112,6 +112,61 @@ impl ResourceId<VirtioDeviceHandle> for VirtioRngHandle {
     }
 }
 
+pub mod balloon {
+    use memory_range::MemoryRange;
+    use mesh::MeshPayload;
+    use mesh::rpc::Rpc;
+    use std::sync::Arc;
+    use vm_resource::CanResolveTo;
+    use vm_resource::Resource;
+    use vm_resource::ResourceId;
+    use vm_resource::ResourceKind;
+    use vm_resource::kind::VirtioDeviceHandle;
+
+    /// A trait to release the host memory backing guest RAM.
+    pub trait DiscardRam: Send + Sync {
+        /// Releases the host memory backing `range`. The next access to the
+        /// range sees zeroes.
+        fn discard_ram(&self, range: MemoryRange) -> std::io::Result<()>;
+    }
+
+    /// Resolved platform-specific [`DiscardRam`] implementation.
+    pub struct ResolvedDiscardRam(pub Arc<dyn DiscardRam>);
+
+    /// Resource kind for platform-specific [`DiscardRam`] implementations.
+    pub enum DiscardRamHandleKind {}
+
+    impl ResourceKind for DiscardRamHandleKind {
+        const NAME: &'static str = "discard_ram";
+    }
+
+    impl CanResolveTo<ResolvedDiscardRam> for DiscardRamHandleKind {
+        type Input<'a> = ();
+    }
+
+    #[derive(MeshPayload)]
+    pub struct VirtioBalloonHandle {
+        /// Platform-specific implementation of RAM discard.
+        pub discard_ram: Resource<DiscardRamHandleKind>,
+        /// Let the guest deflate the balloon when it runs out of memory.
+        pub deflate_on_oom: bool,
+        /// Runtime requests for the device.
+        pub recv: mesh::Receiver<BalloonRequest>,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VirtioBalloonHandle {
+        const ID: &'static str = "virtio-balloon";
+    }
+
+    /// A runtime request for a virtio-balloon device.
+    #[derive(MeshPayload)]
+    pub enum BalloonRequest {
+        /// Sets the amount of memory, in bytes, that the guest is asked to
+        /// give back to the host.
+        SetTarget(Rpc<u64, ()>),
+    }
+}
+
 pub mod blk {
     use mesh::MeshPayload;
     use vm_resource::Resource;
@@ -123,6 +178,8 @@ pub mod blk {
     pub struct VirtioBlkHandle {
         pub disk: Resource<DiskHandleKind>,
         pub read_only: bool,
+        /// The number of request queues. Defaults to one.
+        pub num_queues: Option<u16>,
     }
 
     impl ResourceId<VirtioDeviceHandle> for VirtioBlkHandle {
@@ -130,6 +187,55 @@ impl ResourceId<VirtioDeviceHandle> for VirtioBlkHandle {
     }
 }
 
+pub mod gpu {
+    use mesh::MeshPayload;
+    use vm_resource::Resource;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::FramebufferHandleKind;
+    use vm_resource::kind::VirtioDeviceHandle;
+
+    #[derive(MeshPayload)]
+    pub struct VirtioGpuHandle {
+        /// The framebuffer that the scanout is published to.
+        pub framebuffer: Resource<FramebufferHandleKind>,
+        /// The display resolution offered to the guest as its preferred mode.
+        pub width: u32,
+        pub height: u32,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VirtioGpuHandle {
+        const ID: &'static str = "virtio-gpu";
+    }
+}
+
+pub mod input {
+    use mesh::MeshPayload;
+    use vm_resource::Resource;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::KeyboardInputHandleKind;
+    use vm_resource::kind::MouseInputHandleKind;
+    use vm_resource::kind::VirtioDeviceHandle;
+
+    #[derive(MeshPayload)]
+    pub struct VirtioInputHandle {
+        pub device: VirtioInputDevice,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VirtioInputHandle {
+        const ID: &'static str = "virtio-input";
+    }
+
+    /// The kind of input device to expose to the guest.
+    #[derive(MeshPayload)]
+    pub enum VirtioInputDevice {
+        /// A keyboard reporting key events.
+        Keyboard(Resource<KeyboardInputHandleKind>),
+        /// A pointing device reporting absolute positions, buttons, and
+        /// wheel movement.
+        Tablet(Resource<MouseInputHandleKind>),
+    }
+}
+
 pub mod net {
     use mesh::MeshPayload;
     use net_backend_resources::mac_address::MacAddress;
@@ -141,6 +247,8 @@ pub mod net {
     #[derive(MeshPayload)]
     pub struct VirtioNetHandle {
         pub max_queues: Option<u16>,
+        /// The MTU to report to the guest. If `None`, no MTU is offered.
+        pub mtu: Option<u16>,
         pub mac_address: MacAddress,
         pub endpoint: Resource<NetEndpointHandleKind>,
     }
@@ -170,6 +278,7 @@ impl ResourceId<VirtioDeviceHandle> for VirtioConsoleHandle {
 #[cfg(unix)]
 pub mod vhost_user {
     use mesh::MeshPayload;
+    use net_backend_resources::mac_address::MacAddress;
     use std::os::fd::OwnedFd;
     use vm_resource::ResourceId;
     use vm_resource::kind::VirtioDeviceHandle;
@@ -179,7 +288,7 @@ pub mod vhost_user {
     /// The socket must already be connected. The CLI layer connects
     /// to the backend and passes the connected fd here.
     ///
-    /// For device types with specific handles (FS, BLK), use those
+    /// For device types with specific handles (FS, BLK, NET), use those
     /// instead. This handle is for devices identified only by their
     /// numeric virtio device ID.
     #[derive(MeshPayload)]
@@ -233,6 +342,28 @@ pub struct VhostUserBlkHandle {
     impl ResourceId<VirtioDeviceHandle> for VhostUserBlkHandle {
         const ID: &'static str = "vhost-user-blk";
     }
+
+    /// Handle for a vhost-user virtio-net device.
+    ///
+    /// The backend only processes the receive and transmit queues. The
+    /// frontend owns the config space and the control queue, as QEMU does,
+    /// so backends do not need to implement either.
+    #[derive(MeshPayload)]
+    pub struct VhostUserNetHandle {
+        /// Connected Unix socket fd to the vhost-user backend.
+        pub socket: OwnedFd,
+        /// The MAC address exposed to the guest.
+        pub mac_address: MacAddress,
+        /// Number of receive/transmit queue pairs (default 1 in resolver).
+        pub queue_pairs: Option<u16>,
+        /// Queue size for the receive and transmit queues (default 256 in
+        /// resolver).
+        pub queue_size: Option<u16>,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VhostUserNetHandle {
+        const ID: &'static str = "vhost-user-net";
+    }
 }
 
 pub mod vsock {
@@ -0,0 +1,60 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Virtio memory balloon device specification constants and types.
+//!
+//! Based on OASIS VIRTIO v1.2, Section 5.5.
+//! <https://docs.oasis-open.org/virtio/virtio/v1.2/cs01/virtio-v1.2-cs01.html>
+
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+// Feature bits (spec §5.5.3). These are device-specific bits in bank 0 (bits 0..23).
+/// Host has to be told before pages from the balloon are used.
+pub const VIRTIO_BALLOON_F_MUST_TELL_HOST: u32 = 1 << 0;
+/// A virtqueue for reporting guest memory statistics is present.
+pub const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1 << 1;
+/// Deflate balloon on guest out of memory condition.
+pub const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 1 << 2;
+/// Device has support for free page hinting.
+pub const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u32 = 1 << 3;
+/// Device has support for free page reporting.
+pub const VIRTIO_BALLOON_F_REPORTING: u32 = 1 << 5;
+
+/// The size of the pages described by `num_pages`, `actual`, and the inflate
+/// and deflate queues, independent of the guest's page size.
+pub const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
+
+// Memory statistics tags (spec §5.5.6.3).
+pub const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+pub const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+pub const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+pub const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+pub const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+pub const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+pub const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+pub const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+pub const VIRTIO_BALLOON_S_HTLB_PGALLOC: u16 = 8;
+pub const VIRTIO_BALLOON_S_HTLB_PGFAIL: u16 = 9;
+
+/// Config space layout for virtio-balloon (spec §5.5.4).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Config {
+    /// The number of pages the device wants the balloon to hold.
+    pub num_pages: crate::u32_le,
+    /// The number of pages the driver currently holds in the balloon.
+    pub actual: crate::u32_le,
+    pub free_page_hint_cmd_id: crate::u32_le,
+    pub poison_val: crate::u32_le,
+}
+
+/// A single memory statistic reported on the stats queue (spec §5.5.6.3).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Stat {
+    pub tag: crate::u16_le,
+    pub val: crate::u64_le,
+}
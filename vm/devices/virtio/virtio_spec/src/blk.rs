@@ -25,10 +25,14 @@
 pub const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
 /// Device exports information on optimal I/O alignment.
 pub const VIRTIO_BLK_F_TOPOLOGY: u32 = 1 << 10;
+/// Device supports multiple request queues; the count is in `num_queues`.
+pub const VIRTIO_BLK_F_MQ: u32 = 1 << 12;
 /// Device can support discard command.
 pub const VIRTIO_BLK_F_DISCARD: u32 = 1 << 13;
 /// Device can support write zeroes command (not currently advertised).
 pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 1 << 14;
+/// Device is a zoned block device; zone characteristics are in `zoned`.
+pub const VIRTIO_BLK_F_ZONED: u32 = 1 << 17;
 
 // Request types (spec §5.2.6).
 pub const VIRTIO_BLK_T_IN: u32 = 0;
@@ -37,11 +41,42 @@
 pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
 pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
 pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+pub const VIRTIO_BLK_T_ZONE_APPEND: u32 = 15;
+pub const VIRTIO_BLK_T_ZONE_REPORT: u32 = 16;
+pub const VIRTIO_BLK_T_ZONE_OPEN: u32 = 18;
+pub const VIRTIO_BLK_T_ZONE_CLOSE: u32 = 20;
+pub const VIRTIO_BLK_T_ZONE_FINISH: u32 = 22;
+pub const VIRTIO_BLK_T_ZONE_RESET: u32 = 24;
+pub const VIRTIO_BLK_T_ZONE_RESET_ALL: u32 = 26;
 
 // Status codes (spec §5.2.6).
 pub const VIRTIO_BLK_S_OK: u8 = 0;
 pub const VIRTIO_BLK_S_IOERR: u8 = 1;
 pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+pub const VIRTIO_BLK_S_ZONE_INVALID_CMD: u8 = 3;
+pub const VIRTIO_BLK_S_ZONE_UNALIGNED_WP: u8 = 4;
+pub const VIRTIO_BLK_S_ZONE_OPEN_RESOURCE: u8 = 5;
+pub const VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE: u8 = 6;
+
+// Zoned models (spec §5.2.4, `zoned.model`).
+pub const VIRTIO_BLK_Z_NONE: u8 = 0;
+pub const VIRTIO_BLK_Z_HM: u8 = 1;
+pub const VIRTIO_BLK_Z_HA: u8 = 2;
+
+// Zone types (spec §5.2.6.1).
+pub const VIRTIO_BLK_ZT_CONV: u8 = 1;
+pub const VIRTIO_BLK_ZT_SWR: u8 = 2;
+pub const VIRTIO_BLK_ZT_SWP: u8 = 3;
+
+// Zone states (spec §5.2.6.1).
+pub const VIRTIO_BLK_ZS_NOT_WP: u8 = 0;
+pub const VIRTIO_BLK_ZS_EMPTY: u8 = 1;
+pub const VIRTIO_BLK_ZS_IOPEN: u8 = 2;
+pub const VIRTIO_BLK_ZS_EOPEN: u8 = 3;
+pub const VIRTIO_BLK_ZS_CLOSED: u8 = 4;
+pub const VIRTIO_BLK_ZS_RDONLY: u8 = 13;
+pub const VIRTIO_BLK_ZS_FULL: u8 = 14;
+pub const VIRTIO_BLK_ZS_OFFLINE: u8 = 15;
 
 /// Maximum length of the device ID string (spec §5.2.6).
 pub const VIRTIO_BLK_ID_BYTES: usize = 20;
@@ -79,8 +114,7 @@ pub struct VirtioBlkConfig {
     /// (valid if VIRTIO_BLK_F_CONFIG_WCE, which we don't negotiate).
     pub writeback: u8,
     pub unused0: u8,
-    /// Number of request queues (valid if VIRTIO_BLK_F_MQ,
-    /// which we don't negotiate). Set to 1.
+    /// Number of request queues (valid if VIRTIO_BLK_F_MQ).
     pub num_queues: u16,
     /// Maximum number of 512-byte sectors in a single discard segment
     /// (valid if VIRTIO_BLK_F_DISCARD).
@@ -105,11 +139,37 @@ pub struct VirtioBlkConfig {
     pub write_zeroes_may_unmap: u8,
     #[inspect(skip)]
     pub unused1: [u8; 3],
-    // Explicit padding to satisfy zerocopy IntoBytes alignment requirements
-    // for the u64 `capacity` field. Not part of the virtio config space;
-    // `device_register_length` excludes these bytes.
+    /// Maximum number of 512-byte sectors in a single secure erase segment
+    /// (valid if VIRTIO_BLK_F_SECURE_ERASE, which we don't negotiate).
+    pub max_secure_erase_sectors: u32,
+    /// Maximum number of secure erase segments per request
+    /// (valid if VIRTIO_BLK_F_SECURE_ERASE).
+    pub max_secure_erase_seg: u32,
+    /// Required alignment for secure erase ranges, in 512-byte sectors
+    /// (valid if VIRTIO_BLK_F_SECURE_ERASE).
+    pub secure_erase_sector_alignment: u32,
+    /// Zoned device characteristics (valid if VIRTIO_BLK_F_ZONED).
+    pub zoned: VirtioBlkZonedCharacteristics,
+}
+
+/// Zoned device characteristics (spec §5.2.4).
+#[repr(C)]
+#[derive(Debug, Clone, IntoBytes, Immutable, KnownLayout, FromBytes, Inspect)]
+pub struct VirtioBlkZonedCharacteristics {
+    /// Size of each zone, in 512-byte sectors.
+    pub zone_sectors: u32,
+    /// Maximum number of open zones, or 0 for no limit.
+    pub max_open_zones: u32,
+    /// Maximum number of active zones, or 0 for no limit.
+    pub max_active_zones: u32,
+    /// Maximum size of a zone append request, in 512-byte sectors.
+    pub max_append_sectors: u32,
+    /// Alignment of writes in sequential zones, in bytes.
+    pub write_granularity: u32,
+    /// One of the `VIRTIO_BLK_Z_*` models.
+    pub model: u8,
     #[inspect(skip)]
-    pub _padding: [u8; 4],
+    pub unused2: [u8; 3],
 }
 
 #[repr(C)]
@@ -150,3 +210,27 @@ pub struct VirtioBlkDiscardWriteZeroes {
     pub num_sectors: u32,
     pub flags: u32,
 }
+
+/// Header of a `VIRTIO_BLK_T_ZONE_REPORT` response (spec §5.2.6.1).
+#[repr(C)]
+#[derive(Debug, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct VirtioBlkZoneReport {
+    /// Number of descriptors that follow.
+    pub nr_zones: u64,
+    pub reserved: [u8; 56],
+}
+
+/// A zone descriptor in a `VIRTIO_BLK_T_ZONE_REPORT` response
+/// (spec §5.2.6.1). All sector values are in 512-byte units.
+#[repr(C)]
+#[derive(Debug, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct VirtioBlkZoneDescriptor {
+    pub z_cap: u64,
+    pub z_start: u64,
+    pub z_wp: u64,
+    /// One of the `VIRTIO_BLK_ZT_*` types.
+    pub z_type: u8,
+    /// One of the `VIRTIO_BLK_ZS_*` states.
+    pub z_state: u8,
+    pub reserved: [u8; 38],
+}
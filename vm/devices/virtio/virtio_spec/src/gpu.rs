@@ -0,0 +1,199 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Virtio GPU device specification constants and types.
+//!
+//! Based on OASIS VIRTIO v1.2, Section 5.7. Only the 2D command set is
+//! defined here.
+//! <https://docs.oasis-open.org/virtio/virtio/v1.2/cs01/virtio-v1.2-cs01.html>
+
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+// Feature bits (spec §5.7.3).
+pub const VIRTIO_GPU_F_VIRGL: u32 = 1 << 0;
+pub const VIRTIO_GPU_F_EDID: u32 = 1 << 1;
+
+/// The display configuration changed (spec §5.7.4).
+pub const VIRTIO_GPU_EVENT_DISPLAY: u32 = 1 << 0;
+
+/// The maximum number of scanouts.
+pub const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+// 2D commands (spec §5.7.6.7).
+pub const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+pub const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+pub const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x0102;
+pub const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+pub const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+pub const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+pub const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+pub const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+pub const VIRTIO_GPU_CMD_GET_CAPSET_INFO: u32 = 0x0108;
+pub const VIRTIO_GPU_CMD_GET_CAPSET: u32 = 0x0109;
+pub const VIRTIO_GPU_CMD_GET_EDID: u32 = 0x010a;
+
+// Cursor commands.
+pub const VIRTIO_GPU_CMD_UPDATE_CURSOR: u32 = 0x0300;
+pub const VIRTIO_GPU_CMD_MOVE_CURSOR: u32 = 0x0301;
+
+// Success responses.
+pub const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+pub const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+// Error responses.
+pub const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
+pub const VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+pub const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+pub const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+pub const VIRTIO_GPU_RESP_ERR_INVALID_CONTEXT_ID: u32 = 0x1204;
+pub const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+/// The driver wants the response to signal completion of `fence_id`.
+pub const VIRTIO_GPU_FLAG_FENCE: u32 = 1 << 0;
+
+// Resource formats (spec §5.7.6.8). Names give the byte order in memory.
+pub const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
+pub const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+pub const VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM: u32 = 3;
+pub const VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM: u32 = 4;
+pub const VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM: u32 = 67;
+pub const VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM: u32 = 68;
+pub const VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM: u32 = 121;
+pub const VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM: u32 = 134;
+
+/// Config space layout for virtio-gpu (spec §5.7.4).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Config {
+    /// Pending events. Read-only.
+    pub events_read: crate::u32_le,
+    /// Writing a bit clears it from `events_read`.
+    pub events_clear: crate::u32_le,
+    pub num_scanouts: crate::u32_le,
+    pub num_capsets: crate::u32_le,
+}
+
+/// The header of every request and response.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct CtrlHeader {
+    pub cmd_type: crate::u32_le,
+    pub flags: crate::u32_le,
+    pub fence_id: crate::u64_le,
+    pub ctx_id: crate::u32_le,
+    pub ring_idx: u8,
+    pub padding: [u8; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Rect {
+    pub x: crate::u32_le,
+    pub y: crate::u32_le,
+    pub width: crate::u32_le,
+    pub height: crate::u32_le,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct DisplayOne {
+    pub r: Rect,
+    pub enabled: crate::u32_le,
+    pub flags: crate::u32_le,
+}
+
+/// The response to [`VIRTIO_GPU_CMD_GET_DISPLAY_INFO`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct RespDisplayInfo {
+    pub hdr: CtrlHeader,
+    pub pmodes: [DisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ResourceCreate2d {
+    pub resource_id: crate::u32_le,
+    pub format: crate::u32_le,
+    pub width: crate::u32_le,
+    pub height: crate::u32_le,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ResourceUnref {
+    pub resource_id: crate::u32_le,
+    pub padding: crate::u32_le,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct SetScanout {
+    pub r: Rect,
+    pub scanout_id: crate::u32_le,
+    pub resource_id: crate::u32_le,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ResourceFlush {
+    pub r: Rect,
+    pub resource_id: crate::u32_le,
+    pub padding: crate::u32_le,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct TransferToHost2d {
+    pub r: Rect,
+    pub offset: crate::u64_le,
+    pub resource_id: crate::u32_le,
+    pub padding: crate::u32_le,
+}
+
+/// Followed by `nr_entries` [`MemEntry`] structures.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ResourceAttachBacking {
+    pub resource_id: crate::u32_le,
+    pub nr_entries: crate::u32_le,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct MemEntry {
+    pub addr: crate::u64_le,
+    pub length: crate::u32_le,
+    pub padding: crate::u32_le,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ResourceDetachBacking {
+    pub resource_id: crate::u32_le,
+    pub padding: crate::u32_le,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct CursorPos {
+    pub scanout_id: crate::u32_le,
+    pub x: crate::u32_le,
+    pub y: crate::u32_le,
+    pub padding: crate::u32_le,
+}
+
+/// The body of [`VIRTIO_GPU_CMD_UPDATE_CURSOR`] and
+/// [`VIRTIO_GPU_CMD_MOVE_CURSOR`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct UpdateCursor {
+    pub pos: CursorPos,
+    pub resource_id: crate::u32_le,
+    pub hot_x: crate::u32_le,
+    pub hot_y: crate::u32_le,
+    pub padding: crate::u32_le,
+}
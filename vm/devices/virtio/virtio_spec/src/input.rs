@@ -0,0 +1,100 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Virtio input device specification constants and types.
+//!
+//! Based on OASIS VIRTIO v1.2, Section 5.8. Event types and codes are those
+//! of the Linux evdev interface (`linux/input-event-codes.h`).
+//! <https://docs.oasis-open.org/virtio/virtio/v1.2/cs01/virtio-v1.2-cs01.html>
+
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+// Config space selectors (spec §5.8.4).
+pub const VIRTIO_INPUT_CFG_UNSET: u8 = 0x00;
+pub const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+pub const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+pub const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+pub const VIRTIO_INPUT_CFG_PROP_BITS: u8 = 0x10;
+pub const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+pub const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+/// The size of the config space union.
+pub const VIRTIO_INPUT_CFG_DATA_SIZE: usize = 128;
+
+// Event types.
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const EV_MSC: u16 = 0x04;
+pub const EV_LED: u16 = 0x11;
+pub const EV_REP: u16 = 0x14;
+
+// EV_SYN codes.
+pub const SYN_REPORT: u16 = 0;
+
+// EV_REL codes.
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+
+// EV_ABS codes.
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+
+// EV_KEY button codes.
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+/// The highest EV_KEY code.
+pub const KEY_MAX: u16 = 0x2ff;
+
+/// `BUS_VIRTUAL` from `linux/input.h`, for [`DevIds::bustype`].
+pub const BUS_VIRTUAL: u16 = 0x06;
+
+/// The header of the config space (spec §5.8.4). It is followed by
+/// [`VIRTIO_INPUT_CFG_DATA_SIZE`] bytes whose contents depend on `select`
+/// and `subsel`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct ConfigHeader {
+    pub select: u8,
+    pub subsel: u8,
+    /// The number of valid bytes in the data union.
+    pub size: u8,
+    pub reserved: [u8; 5],
+}
+
+/// Axis information returned for [`VIRTIO_INPUT_CFG_ABS_INFO`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct AbsInfo {
+    pub min: crate::u32_le,
+    pub max: crate::u32_le,
+    pub fuzz: crate::u32_le,
+    pub flat: crate::u32_le,
+    pub res: crate::u32_le,
+}
+
+/// Device IDs returned for [`VIRTIO_INPUT_CFG_ID_DEVIDS`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct DevIds {
+    pub bustype: crate::u16_le,
+    pub vendor: crate::u16_le,
+    pub product: crate::u16_le,
+    pub version: crate::u16_le,
+}
+
+/// An event on the event or status queue (spec §5.8.6).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Event {
+    pub event_type: crate::u16_le,
+    pub code: crate::u16_le,
+    pub value: crate::u32_le,
+}
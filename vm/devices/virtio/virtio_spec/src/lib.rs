@@ -9,8 +9,11 @@
 
 #![expect(missing_docs)]
 
+pub mod balloon;
 pub mod blk;
 pub mod fs;
+pub mod gpu;
+pub mod input;
 
 use bitfield_struct::bitfield;
 use inspect::Inspect;
@@ -115,7 +118,10 @@ pub enum VirtioDeviceType: u16 {
         BLK = 2,
         CONSOLE = 3,
         RNG = 4,
+        BALLOON = 5,
         P9 = 9,
+        GPU = 16,
+        INPUT = 18,
         VSOCK = 19,
         FS = 26,
         PMEM = 27,
@@ -53,6 +53,7 @@ mod ioctl {
         0x46,
         kvm_userspace_memory_region
     );
+    ioctl_write_ptr!(kvm_get_dirty_log, KVMIO, 0x42, kvm_dirty_log);
     ioctl_write_ptr!(
         kvm_set_user_memory_region2,
         KVMIO,
@@ -247,6 +248,8 @@ pub enum Error {
     SignalMsi(#[source] nix::Error),
     #[error("SetMemoryRegion")]
     SetMemoryRegion(#[source] nix::Error),
+    #[error("GetDirtyLog")]
+    GetDirtyLog(#[source] nix::Error),
     #[error("SetMemoryAttributes")]
     SetMemoryAttributes(#[source] nix::Error),
     #[error("CreateGuestMemfd")]
@@ -897,10 +900,16 @@ pub unsafe fn set_user_memory_region(
         size: usize,
         addr: u64,
         readonly: bool,
+        log_dirty_pages: bool,
     ) -> Result<()> {
         let region = kvm_userspace_memory_region {
             slot,
-            flags: if readonly { KVM_MEM_READONLY } else { 0 },
+            flags: if readonly { KVM_MEM_READONLY } else { 0 }
+                | if log_dirty_pages {
+                    KVM_MEM_LOG_DIRTY_PAGES
+                } else {
+                    0
+                },
             guest_phys_addr: addr,
             memory_size: size as u64,
             userspace_addr: data as usize as u64,
@@ -956,6 +965,26 @@ pub unsafe fn set_user_memory_region2(
         Ok(())
     }
 
+    /// Retrieves and clears the dirty page bitmap for a memory slot that was
+    /// registered with dirty page logging enabled.
+    ///
+    /// `bitmap` must have at least one bit for each page in the slot.
+    pub fn get_dirty_log(&self, slot: u32, bitmap: &mut [u64]) -> Result<()> {
+        let log = kvm_dirty_log {
+            slot,
+            padding1: 0,
+            __bindgen_anon_1: kvm_dirty_log__bindgen_ty_1 {
+                dirty_bitmap: bitmap.as_mut_ptr().cast(),
+            },
+        };
+        // SAFETY: `bitmap` is valid for writes, and the caller guarantees it
+        // is large enough for the slot.
+        unsafe {
+            ioctl::kvm_get_dirty_log(self.vm.as_raw_fd(), &log).map_err(Error::GetDirtyLog)?;
+        }
+        Ok(())
+    }
+
     pub fn create_guest_memfd(&self, size: u64) -> Result<File> {
         let mut guest_memfd = kvm_create_guest_memfd {
             size,
@@ -2,8 +2,6 @@
 // Licensed under the MIT License.
 
 //! VHD1 file format definitions.
-//!
-//! Currently incomplete (missing defs for non-fixed disks).
 
 #![expect(missing_docs)]
 #![forbid(unsafe_code)]
@@ -24,7 +22,7 @@ mod packed_nums {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
 pub struct VhdFooter {
     pub cookie: u64_be,
     pub features: u32_be,
@@ -54,6 +52,11 @@ impl VhdFooter {
     pub const FIXED_DATA_OFFSET: u64 = !0;
     pub const CREATOR_VERSION_MAGIC: u32 = 0x000a0000;
     pub const DISK_TYPE_FIXED: u32 = 2;
+    pub const DISK_TYPE_DYNAMIC: u32 = 3;
+    pub const DISK_TYPE_DIFFERENCING: u32 = 4;
+    /// The creator application, "ovmm".
+    pub const CREATOR_APPLICATION: u32 = u32::from_be_bytes(*b"ovmm");
+    pub const CREATOR_HOST_OS_WINDOWS: u32 = u32::from_be_bytes(*b"Wi2k");
 
     pub fn new_fixed(size: u64, guid: Guid) -> Self {
         let mut footer = Self {
@@ -73,6 +76,29 @@ pub fn new_fixed(size: u64, guid: Guid) -> Self {
         footer
     }
 
+    /// Returns a footer for a dynamic or differencing disk of `size` bytes
+    /// whose dynamic header is at `data_offset`.
+    pub fn new_dynamic(size: u64, guid: Guid, disk_type: u32, data_offset: u64) -> Self {
+        let mut footer = Self {
+            cookie: Self::COOKIE_MAGIC,
+            features: Self::FEATURE_MASK.into(),
+            file_format_version: Self::FILE_FORMAT_VERSION_MAGIC.into(),
+            data_offset: data_offset.into(),
+            creator_application: Self::CREATOR_APPLICATION.into(),
+            creator_version: Self::CREATOR_VERSION_MAGIC.into(),
+            creator_host_os: Self::CREATOR_HOST_OS_WINDOWS.into(),
+            original_size: size.into(),
+            current_size: size.into(),
+            disk_geometry: chs_geometry(size).into(),
+            disk_type: disk_type.into(),
+            ..FromZeros::new_zeroed()
+        };
+
+        footer.unique_id = guid;
+        footer.checksum = footer.compute_checksum().into();
+        footer
+    }
+
     pub fn compute_checksum(&self) -> u32 {
         !(self.as_bytes().iter().map(|b| *b as u32).sum::<u32>()
             - self
@@ -83,3 +109,99 @@ pub fn compute_checksum(&self) -> u32 {
                 .sum::<u32>())
     }
 }
+
+/// Computes the CHS geometry field for a disk of `size` bytes, using the
+/// algorithm from the VHD specification.
+pub fn chs_geometry(size: u64) -> u32 {
+    let mut total_sectors = (size / 512).min(65535 * 16 * 255);
+    let (sectors_per_track, heads);
+    if total_sectors >= 65535 * 16 * 63 {
+        sectors_per_track = 255;
+        heads = 16;
+    } else {
+        let mut spt = 17;
+        let mut cylinder_times_heads = total_sectors / spt;
+        let mut h = cylinder_times_heads.div_ceil(1024).max(4);
+        if cylinder_times_heads >= h * 1024 || h > 16 {
+            spt = 31;
+            h = 16;
+            cylinder_times_heads = total_sectors / spt;
+        }
+        if cylinder_times_heads >= h * 1024 {
+            spt = 63;
+            h = 16;
+        }
+        sectors_per_track = spt;
+        heads = h;
+    }
+    total_sectors -= total_sectors % (sectors_per_track * heads);
+    let cylinders = total_sectors / sectors_per_track / heads;
+    ((cylinders as u32) << 16) | ((heads as u32) << 8) | sectors_per_track as u32
+}
+
+/// The header of a dynamic or differencing disk, pointed to by
+/// [`VhdFooter::data_offset`].
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct VhdDynamicHeader {
+    pub cookie: u64_be,
+    pub data_offset: u64_be,
+    pub table_offset: u64_be,
+    pub header_version: u32_be,
+    pub max_table_entries: u32_be,
+    pub block_size: u32_be,
+    pub checksum: u32_be,
+    pub parent_unique_id: Guid,
+    pub parent_time_stamp: u32_be,
+    pub reserved: u32_be,
+    /// The parent's file name, in UTF-16 big endian.
+    pub parent_unicode_name: [u8; 512],
+    pub parent_locators: [VhdParentLocator; 8],
+    pub reserved2: [u8; 256],
+}
+
+impl VhdDynamicHeader {
+    pub const LEN: u64 = 1024;
+
+    pub const COOKIE_MAGIC: u64_be = u64_be::from_bytes(*b"cxsparse");
+    pub const DATA_OFFSET: u64 = !0;
+    pub const HEADER_VERSION: u32 = 0x00010000;
+    pub const DEFAULT_BLOCK_SIZE: u32 = 0x200000;
+    /// The BAT entry for an unallocated block.
+    pub const BAT_ENTRY_UNUSED: u32 = !0;
+
+    pub fn compute_checksum(&self) -> u32 {
+        !(self.as_bytes().iter().map(|b| *b as u32).sum::<u32>()
+            - self
+                .checksum
+                .as_bytes()
+                .iter()
+                .map(|b| *b as u32)
+                .sum::<u32>())
+    }
+}
+
+const _: () = assert!(size_of::<VhdDynamicHeader>() == VhdDynamicHeader::LEN as usize);
+
+/// A parent locator entry in a [`VhdDynamicHeader`].
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct VhdParentLocator {
+    pub platform_code: u32_be,
+    /// The space reserved for the locator data. The specification gives this
+    /// in sectors, but Windows writes it in bytes, so values below 512 should
+    /// be treated as sectors.
+    pub platform_data_space: u32_be,
+    /// The length of the locator data, in bytes.
+    pub platform_data_length: u32_be,
+    pub reserved: u32_be,
+    pub platform_data_offset: u64_be,
+}
+
+impl VhdParentLocator {
+    pub const PLATFORM_CODE_NONE: u32 = 0;
+    /// An absolute Windows path, in UTF-16 little endian.
+    pub const PLATFORM_CODE_W2KU: u32 = u32::from_be_bytes(*b"W2ku");
+    /// A relative Windows path, in UTF-16 little endian.
+    pub const PLATFORM_CODE_W2RU: u32 = u32::from_be_bytes(*b"W2ru");
+}
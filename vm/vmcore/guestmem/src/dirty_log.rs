@@ -0,0 +1,140 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Logging of the pages written through [`GuestMemory`](crate::GuestMemory).
+
+use crate::PAGE_SIZE64;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::btree_map::Entry;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+/// The log shared by a guest memory object, its clones, and its subranges.
+#[derive(Debug, Default)]
+struct DirtyLog {
+    enabled: AtomicBool,
+    state: Mutex<DirtyLogState>,
+}
+
+#[derive(Debug, Default)]
+struct DirtyLogState {
+    /// The pages written since the last query.
+    dirty: BTreeSet<u64>,
+    /// The number of outstanding [`LockedPages`](crate::LockedPages) for each
+    /// page. These are tracked even while logging is disabled, since locked
+    /// pages can be written at any time until they are unlocked.
+    locked: BTreeMap<u64, usize>,
+}
+
+/// A view of a [`DirtyLog`] for memory that starts `offset` bytes into the
+/// logged memory.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DirtyLogRef {
+    log: Arc<DirtyLog>,
+    offset: u64,
+}
+
+impl DirtyLogRef {
+    /// Returns a view for a subrange starting at `offset`.
+    pub fn subrange(&self, offset: u64) -> Self {
+        Self {
+            log: self.log.clone(),
+            offset: self.offset + offset,
+        }
+    }
+
+    /// Returns the logged pages spanned by `gpa..gpa + len`.
+    fn pages(&self, gpa: u64, len: u64) -> Range<u64> {
+        let start = self.offset.saturating_add(gpa);
+        start / PAGE_SIZE64..start.saturating_add(len).div_ceil(PAGE_SIZE64)
+    }
+
+    pub fn set_enabled(&self, enable: bool) {
+        let mut state = self.log.state.lock();
+        state.dirty.clear();
+        self.log.enabled.store(enable, Ordering::SeqCst);
+    }
+
+    /// Logs a write to `gpa..gpa + len`.
+    ///
+    /// This must be called after the write, so that a concurrent query either
+    /// sees the page as dirty or reads its new contents.
+    pub fn mark(&self, gpa: u64, len: u64) {
+        if len == 0 || !self.log.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+        let pages = self.pages(gpa, len);
+        self.log.state.lock().dirty.extend(pages);
+    }
+
+    /// Logs writes to the pages `gpns`.
+    pub fn mark_gpns(&self, gpns: &[u64]) {
+        if !self.log.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut state = self.log.state.lock();
+        for &gpn in gpns {
+            state
+                .dirty
+                .extend(self.pages(gpn * PAGE_SIZE64, PAGE_SIZE64));
+        }
+    }
+
+    /// Records that the pages `gpns` have been locked.
+    pub fn lock_gpns(&self, gpns: &[u64]) {
+        let mut state = self.log.state.lock();
+        for &gpn in gpns {
+            for page in self.pages(gpn * PAGE_SIZE64, PAGE_SIZE64) {
+                *state.locked.entry(page).or_default() += 1;
+            }
+        }
+    }
+
+    /// Records that the pages `gpns` have been unlocked, logging them as
+    /// written.
+    pub fn unlock_gpns(&self, gpns: &[u64]) {
+        let enabled = self.log.enabled.load(Ordering::SeqCst);
+        let mut state = self.log.state.lock();
+        for &gpn in gpns {
+            for page in self.pages(gpn * PAGE_SIZE64, PAGE_SIZE64) {
+                let Entry::Occupied(mut entry) = state.locked.entry(page) else {
+                    unreachable!("page {page:#x} is not locked");
+                };
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+                if enabled {
+                    state.dirty.insert(page);
+                }
+            }
+        }
+    }
+
+    /// Sets the bits in `bitmap` for the pages in `addr..addr + size` that were
+    /// written since the last query or that are locked, and resets the written
+    /// state of those pages.
+    pub fn query(&self, addr: u64, size: u64, bitmap: &mut [u64]) {
+        if !self.log.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+        let pages = self.pages(addr, size);
+        let mut set = |page: u64| {
+            let bit = page - pages.start;
+            bitmap[(bit / 64) as usize] |= 1 << (bit % 64);
+        };
+        let mut state = self.log.state.lock();
+        let mut written = state.dirty.split_off(&pages.start);
+        let mut rest = written.split_off(&pages.end);
+        state.dirty.append(&mut rest);
+        written.into_iter().for_each(&mut set);
+        state
+            .locked
+            .range(pages.clone())
+            .for_each(|(&page, _)| set(page));
+    }
+}
@@ -7,8 +7,10 @@
 #![expect(unsafe_code)]
 #![expect(missing_docs)]
 
+mod dirty_log;
 pub mod ranges;
 
+use self::dirty_log::DirtyLogRef;
 use self::ranges::PagedRange;
 use inspect::Inspect;
 use pal_event::Event;
@@ -1054,7 +1056,8 @@ fn create_memory_subrange(
     _allow_preemptive_locking: bool,
 ) -> Result<GuestMemory, GuestMemoryBackingError> {
     let (_, _, region) = base.region(offset, len)?;
-    Ok(GuestMemory::new(
+    let dirty_log = base.dirty_log.subrange(offset);
+    Ok(GuestMemory::new_inner(
         base.debug_name.clone(),
         GuestMemoryAccessRange {
             base,
@@ -1062,6 +1065,8 @@ fn create_memory_subrange(
             len,
             region,
         },
+        false,
+        dirty_log,
     ))
 }
 
@@ -1199,6 +1204,8 @@ struct GuestMemoryInner<T: ?Sized = dyn DynGuestMemoryAccess> {
     /// Cached result of [`GuestMemoryAccess::supports_locking`], since it is
     /// queried on hot zero-copy paths and never changes for a given backing.
     supports_locking: bool,
+    /// The log of pages written through this memory, shared with subranges.
+    dirty_log: DirtyLogRef,
     imp: T,
 }
 
@@ -1387,10 +1394,15 @@ pub fn new(debug_name: impl Into<Arc<str>>, imp: impl GuestMemoryAccess) -> Self
         if imp.mapping().is_some() && !cfg!(miri) {
             trycopy::initialize_try_copy();
         }
-        Self::new_inner(debug_name.into(), imp, false)
+        Self::new_inner(debug_name.into(), imp, false, DirtyLogRef::default())
     }
 
-    fn new_inner(debug_name: Arc<str>, imp: impl GuestMemoryAccess, allocated: bool) -> Self {
+    fn new_inner(
+        debug_name: Arc<str>,
+        imp: impl GuestMemoryAccess,
+        allocated: bool,
+        dirty_log: DirtyLogRef,
+    ) -> Self {
         let regions = vec![MemoryRegion::new(&imp)];
         let supports_locking = imp.supports_locking();
         Self {
@@ -1405,6 +1417,7 @@ fn new_inner(debug_name: Arc<str>, imp: impl GuestMemoryAccess, allocated: bool)
                 regions,
                 allocated,
                 supports_locking,
+                dirty_log,
             }),
         }
     }
@@ -1489,6 +1502,7 @@ pub fn new_multi_region(
             imp,
             allocated: false,
             supports_locking,
+            dirty_log: DirtyLogRef::default(),
         };
 
         Ok(Self {
@@ -1506,7 +1520,12 @@ pub fn new_multi_region(
     /// different debug name, manually use `GuestMemory::new` with
     /// [`AlignedHeapMemory`].
     pub fn allocate(size: usize) -> Self {
-        Self::new_inner("heap".into(), AlignedHeapMemory::new(size), true)
+        Self::new_inner(
+            "heap".into(),
+            AlignedHeapMemory::new(size),
+            true,
+            DirtyLogRef::default(),
+        )
     }
 
     /// If this memory is unaliased and was created via
@@ -1745,9 +1764,14 @@ fn run_on_mapping<T, P>(
         // critical section. This will allow callers to flush concurrent
         // accesses after bitmap updates.
         #[cfg(feature = "bitmap")]
-        return rcu().run(op);
+        let result = rcu().run(op);
         #[cfg(not(feature = "bitmap"))]
-        op()
+        let result = op();
+        if access_type == AccessType::Write {
+            // Even a failed write may have written part of the range.
+            self.inner.dirty_log.mark(gpa, len as u64);
+        }
+        result
     }
 
     /// # Safety
@@ -2060,10 +2084,12 @@ pub fn lock_gpns(
                 let page = self.probe_page_for_lock(with_kernel_access, gpa)?;
                 pages.push(PagePtr(page));
             }
-            let store_gpns = self.inner.imp.lock_gpns(gpns)?;
+            let backing_locked = self.inner.imp.lock_gpns(gpns)?;
+            self.inner.dirty_log.lock_gpns(gpns);
             Ok(LockedPages {
                 pages: pages.into_boxed_slice(),
-                gpns: store_gpns.then(|| gpns.to_vec().into_boxed_slice()),
+                gpns: gpns.into(),
+                backing_locked,
                 mem: self.inner.clone(),
             })
         })
@@ -2218,6 +2244,36 @@ pub fn read_range_to_atomic(
         })
     }
 
+    /// Enables or disables logging of the pages written through this guest
+    /// memory object, its clones, and its subranges.
+    ///
+    /// This complements the hypervisor's dirty page tracking, which only sees
+    /// writes by the guest's processors, with the writes made by device
+    /// emulators and other host code. Pages locked with
+    /// [`lock_gpns`](Self::lock_gpns) are logged for as long as they are
+    /// locked, and pages locked with [`lock_range`](Self::lock_range) when
+    /// they are unlocked. Writes through other raw pointers, such as the one
+    /// returned by [`full_mapping`](Self::full_mapping), are not logged.
+    ///
+    /// Enabling or disabling the log discards the pages logged so far.
+    pub fn set_dirty_log(&self, enable: bool) {
+        self.inner.dirty_log.set_enabled(enable);
+    }
+
+    /// Sets a bit in `bitmap` for each 4KiB page in the given range that was
+    /// written through this memory since the last query or since logging was
+    /// enabled, or that is locked, and resets the written state of those
+    /// pages.
+    ///
+    /// Bit `n` of `bitmap` (in little-endian `u64` order) corresponds to the
+    /// page at `addr + n * 4096`, and `addr` must be page aligned. Bits for
+    /// clean pages are left unchanged. Nothing is reported if logging is
+    /// disabled.
+    pub fn query_dirty_log(&self, addr: u64, size: u64, bitmap: &mut [u64]) {
+        assert!(addr.is_multiple_of(PAGE_SIZE64));
+        self.inner.dirty_log.query(addr, size, bitmap);
+    }
+
     /// Locks the guest pages spanned by the specified `PagedRange`.
     ///
     /// # Arguments
@@ -2245,10 +2301,11 @@ pub fn lock_range<'a, T: LockedRange<'a>>(
                     self.dangerous_access_pre_locked_memory(range.start, range.len() as usize),
                 );
             }
-            let store_gpns = self.inner.imp.lock_gpns(paged_range.gpns())?;
+            let backing_locked = self.inner.imp.lock_gpns(paged_range.gpns())?;
             Ok(LockedRangeImpl {
                 mem: &self.inner,
-                gpns: store_gpns.then(|| paged_range.gpns().to_vec().into_boxed_slice()),
+                gpns: paged_range.gpns().into(),
+                backing_locked,
                 inner: locked_range,
             })
         })
@@ -2313,16 +2370,19 @@ fn region(
 
 pub struct LockedPages {
     pages: Box<[PagePtr]>,
-    gpns: Option<Box<[u64]>>,
+    gpns: Box<[u64]>,
+    /// Whether the backing must be told when the pages are unlocked.
+    backing_locked: bool,
     // maintain a reference to the backing memory
     mem: Arc<GuestMemoryInner>,
 }
 
 impl Drop for LockedPages {
     fn drop(&mut self) {
-        if let Some(gpns) = &self.gpns {
-            self.mem.imp.unlock_gpns(gpns);
+        if self.backing_locked {
+            self.mem.imp.unlock_gpns(&self.gpns);
         }
+        self.mem.dirty_log.unlock_gpns(&self.gpns);
     }
 }
 
@@ -2373,7 +2433,9 @@ pub trait LockedRange<'a> {
 
 pub struct LockedRangeImpl<'a, T: LockedRange<'a>> {
     mem: &'a GuestMemoryInner,
-    gpns: Option<Box<[u64]>>,
+    gpns: Box<[u64]>,
+    /// Whether the backing must be told when the pages are unlocked.
+    backing_locked: bool,
     inner: T,
 }
 
@@ -2389,9 +2451,11 @@ pub fn get_mut(&mut self) -> &mut T {
 
 impl<'a, T: LockedRange<'a>> Drop for LockedRangeImpl<'a, T> {
     fn drop(&mut self) {
-        if let Some(gpns) = &self.gpns {
-            self.mem.imp.unlock_gpns(gpns);
+        if self.backing_locked {
+            self.mem.imp.unlock_gpns(&self.gpns);
         }
+        // The range may have been written through its mapping while locked.
+        self.mem.dirty_log.mark_gpns(&self.gpns);
     }
 }
 
@@ -2865,6 +2929,45 @@ fn test_fault() {
         gm.write_plain::<u8>(PAGE_SIZE64 * 3 - 1, &0).unwrap_err();
     }
 
+    #[test]
+    fn test_dirty_log() {
+        let gm = GuestMemory::allocate(SIZE_1MB);
+        let query = || {
+            let mut bitmap = [0; 4];
+            gm.query_dirty_log(0, SIZE_1MB as u64, &mut bitmap);
+            bitmap[0]
+        };
+
+        // Writes before logging is enabled are not logged.
+        gm.write_at(0, &[1]).unwrap();
+        gm.set_dirty_log(true);
+        assert_eq!(query(), 0);
+
+        // Writes are logged for every page they span, reads are not.
+        gm.write_at(0x1fff, &[1, 2]).unwrap();
+        gm.fill_at(0x5000, 1, 1).unwrap();
+        gm.read_plain::<u8>(0x6000).unwrap();
+        assert_eq!(query(), 0b100110);
+        assert_eq!(query(), 0);
+
+        // Subranges share the log.
+        let sub = gm.subrange(0x8000, 0x2000, false).unwrap();
+        sub.write_plain(0x1000, &1u32).unwrap();
+        assert_eq!(query(), 1 << 9);
+
+        // Locked pages are dirty until unlocked.
+        let locked = sub.lock_gpns(false, &[0]).unwrap();
+        assert_eq!(query(), 1 << 8);
+        assert_eq!(query(), 1 << 8);
+        drop(locked);
+        assert_eq!(query(), 1 << 8);
+        assert_eq!(query(), 0);
+
+        gm.set_dirty_log(false);
+        gm.write_at(0, &[1]).unwrap();
+        assert_eq!(query(), 0);
+    }
+
     #[cfg(feature = "bitmap")]
     #[test]
     fn test_zero_length_access_at_offset_zero() {
@@ -231,6 +231,14 @@ pub fn WHvMapGpaRange2(
         Flags: WHV_MAP_GPA_RANGE_FLAGS,
     ) -> HRESULT;
 
+    pub fn WHvQueryGpaRangeDirtyBitmap(
+        Partition: WHV_PARTITION_HANDLE,
+        GuestAddress: u64,
+        RangeSizeInBytes: u64,
+        Bitmap: *mut u64,
+        BitmapSizeInBytes: u32,
+    ) -> HRESULT;
+
     pub fn WHvStartPartitionMigration(
         Partition: WHV_PARTITION_HANDLE,
         MigrationHandle: *mut HANDLE,
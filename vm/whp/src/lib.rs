@@ -592,6 +592,27 @@ pub fn unmap_range(&self, addr: u64, size: u64) -> Result<()> {
         unsafe { check_hresult(api::WHvUnmapGpaRange(self.handle, addr, size)) }
     }
 
+    /// Queries and resets the dirty state of the pages in a range mapped with
+    /// [`abi::WHvMapGpaRangeFlagTrackDirtyPages`]. If `bitmap` is `None`, the
+    /// dirty state is reset without being reported.
+    pub fn query_gpa_range_dirty_bitmap(
+        &self,
+        addr: u64,
+        size: u64,
+        bitmap: Option<&mut [u64]>,
+    ) -> Result<()> {
+        let (ptr, len) = bitmap.map_or((null_mut(), 0), |b| (b.as_mut_ptr(), size_of_val(b)));
+        unsafe {
+            check_hresult(api::WHvQueryGpaRangeDirtyBitmap(
+                self.handle,
+                addr,
+                size,
+                ptr,
+                len.try_into().unwrap(),
+            ))
+        }
+    }
+
     pub fn populate_ranges(
         &self,
         ranges: &[abi::WHV_MEMORY_RANGE_ENTRY],
@@ -43,6 +43,27 @@ fn pin_range(&self, _addr: u64, _size: u64) -> anyhow::Result<()> {
         Ok(())
     }
 
+    /// Enables or disables tracking of guest writes to writable mapped
+    /// ranges, including ranges mapped after this call.
+    ///
+    /// Only writes performed by the guest's processors are tracked. Writes
+    /// through the host mapping are not; see `GuestMemory`'s dirty log. On
+    /// failure, tracking is left as it was.
+    fn set_dirty_tracking(&self, _enable: bool) -> anyhow::Result<()> {
+        anyhow::bail!("dirty page tracking is not supported")
+    }
+
+    /// Sets a bit in `bitmap` for each 4KiB page in the given range that the
+    /// guest has written since the last query or since tracking was enabled,
+    /// and resets the dirty state of those pages.
+    ///
+    /// Bit `n` of `bitmap` (in little-endian `u64` order) corresponds to the
+    /// page at `addr + n * 4096`. Bits for clean pages are left unchanged.
+    /// The range must fully contain any mapped range that it overlaps.
+    fn query_dirty_pages(&self, _addr: u64, _size: u64, _bitmap: &mut [u64]) -> anyhow::Result<()> {
+        anyhow::bail!("dirty page tracking is not supported")
+    }
+
     /// Maps a range residing in a remote process.
     ///
     /// This may fail if the range overlaps any other mapped range.
@@ -4,6 +4,7 @@
 use crate::KvmError;
 use crate::KvmPartition;
 use crate::KvmPartitionInner;
+use hvdef::HV_PAGE_SIZE;
 use inspect::Inspect;
 use memory_range::MemoryRange;
 use std::fs::File;
@@ -13,6 +14,7 @@
 pub(crate) struct KvmMemoryRange {
     host_addr: *mut u8,
     range: MemoryRange,
+    readonly: bool,
     guest_memfd_offset: Option<u64>,
     private_attributes_set: bool,
 }
@@ -24,6 +26,8 @@ unsafe impl Send for KvmMemoryRange {}
 pub(crate) struct KvmMemoryRangeState {
     #[inspect(flatten, iter_by_index)]
     pub(crate) ranges: Vec<Option<KvmMemoryRange>>,
+    #[inspect(skip)]
+    pub(crate) log_dirty_pages: bool,
 }
 
 #[derive(Debug, Inspect)]
@@ -150,6 +154,7 @@ unsafe fn map_region(
                         size,
                         addr,
                         readonly,
+                        state.log_dirty_pages && !readonly,
                     )?
                 };
                 (None, false)
@@ -192,6 +197,7 @@ unsafe fn map_region(
         state.ranges[slot_to_use] = Some(KvmMemoryRange {
             host_addr: data,
             range,
+            readonly,
             guest_memfd_offset,
             private_attributes_set,
         });
@@ -233,8 +239,14 @@ unsafe fn clear_slot(&self, slot: usize, guest_memfd_backed: bool) -> Result<(),
         } else {
             // SAFETY: the caller ensures clearing this slot is valid.
             unsafe {
-                self.kvm
-                    .set_user_memory_region(slot as u32, std::ptr::null_mut(), 0, 0, false)
+                self.kvm.set_user_memory_region(
+                    slot as u32,
+                    std::ptr::null_mut(),
+                    0,
+                    0,
+                    false,
+                    false,
+                )
             }
         }
     }
@@ -352,6 +364,97 @@ fn unmap_range(&self, addr: u64, size: u64) -> anyhow::Result<()> {
         }
         Ok(())
     }
+
+    fn set_dirty_tracking(&self, enable: bool) -> anyhow::Result<()> {
+        let mut state = self.memory.lock();
+        if state.log_dirty_pages == enable {
+            return Ok(());
+        }
+        // Check before changing any slot, so that a failure leaves tracking
+        // off everywhere.
+        if enable
+            && state
+                .ranges
+                .iter()
+                .flatten()
+                .any(|kvm_range| kvm_range.guest_memfd_offset.is_some())
+        {
+            anyhow::bail!("dirty page tracking is not supported for guest_memfd memory");
+        }
+        let set_logging = |slot: usize, kvm_range: &KvmMemoryRange, enable: bool| {
+            // SAFETY: the slot is re-registered with the same host mapping,
+            // which the caller of `map_range` keeps valid until it is
+            // unmapped. Only the logging flag changes.
+            unsafe {
+                self.kvm.set_user_memory_region(
+                    slot as u32,
+                    kvm_range.host_addr,
+                    kvm_range.range.len() as usize,
+                    kvm_range.range.start(),
+                    kvm_range.readonly,
+                    enable && !kvm_range.readonly,
+                )
+            }
+        };
+        for (slot, entry) in state.ranges.iter().enumerate() {
+            let Some(kvm_range) = entry else { continue };
+            if kvm_range.guest_memfd_offset.is_some() {
+                // Only possible when disabling: the slot was mapped while
+                // tracking was on, without logging.
+                continue;
+            }
+            if let Err(err) = set_logging(slot, kvm_range, enable) {
+                // Restore the slots that were already changed, so that they
+                // match `log_dirty_pages` again.
+                for (slot, entry) in state.ranges[..slot].iter().enumerate() {
+                    let Some(kvm_range) = entry else { continue };
+                    if kvm_range.guest_memfd_offset.is_none()
+                        && let Err(err) = set_logging(slot, kvm_range, !enable)
+                    {
+                        tracing::warn!(
+                            slot,
+                            error = &err as &dyn std::error::Error,
+                            "failed to restore dirty page logging"
+                        );
+                    }
+                }
+                return Err(err.into());
+            }
+        }
+        state.log_dirty_pages = enable;
+        Ok(())
+    }
+
+    fn query_dirty_pages(&self, addr: u64, size: u64, bitmap: &mut [u64]) -> anyhow::Result<()> {
+        let range = MemoryRange::new(addr..addr + size);
+        let state = self.memory.lock();
+        anyhow::ensure!(state.log_dirty_pages, "dirty page tracking is not enabled");
+        let mut slot_bitmap = Vec::new();
+        for (slot, entry) in state.ranges.iter().enumerate() {
+            let Some(kvm_range) = entry else { continue };
+            if kvm_range.readonly || !range.overlaps(&kvm_range.range) {
+                continue;
+            }
+            anyhow::ensure!(
+                range.contains(&kvm_range.range),
+                "dirty page query must cover entire mapped ranges"
+            );
+            let page_count = kvm_range.range.page_count_4k() as usize;
+            slot_bitmap.clear();
+            slot_bitmap.resize(page_count.div_ceil(64), 0);
+            self.kvm.get_dirty_log(slot as u32, &mut slot_bitmap)?;
+            let first_page = ((kvm_range.range.start() - addr) / HV_PAGE_SIZE) as usize;
+            for (i, &word) in slot_bitmap.iter().enumerate() {
+                let mut word = word;
+                while word != 0 {
+                    let page = first_page + i * 64 + word.trailing_zeros() as usize;
+                    bitmap[page / 64] |= 1 << (page % 64);
+                    word &= word - 1;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -462,6 +565,7 @@ fn private_memory_range_resolves_hva_offset() {
         let slots = [Some(KvmMemoryRange {
             host_addr,
             range: range(0x1000, 0x5000),
+            readonly: false,
             guest_memfd_offset: Some(0),
             private_attributes_set: true,
         })];
@@ -479,6 +583,7 @@ fn private_memory_range_rejects_non_private_or_non_guest_memfd_slots() {
         let userspace_slots = [Some(KvmMemoryRange {
             host_addr,
             range: range(0x1000, 0x5000),
+            readonly: false,
             guest_memfd_offset: None,
             private_attributes_set: true,
         })];
@@ -490,6 +595,7 @@ fn private_memory_range_rejects_non_private_or_non_guest_memfd_slots() {
         let shared_slots = [Some(KvmMemoryRange {
             host_addr,
             range: range(0x1000, 0x5000),
+            readonly: false,
             guest_memfd_offset: Some(0),
             private_attributes_set: false,
         })];
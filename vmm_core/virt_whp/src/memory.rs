@@ -54,6 +54,12 @@ unsafe fn map_range(
         let mut flags = whp::abi::WHvMapGpaRangeFlagRead;
         if writable {
             flags |= whp::abi::WHvMapGpaRangeFlagWrite;
+            // Track dirty pages whenever possible, since tracking cannot be
+            // enabled on an existing mapping. The hypervisor does no extra
+            // work until the dirty state is first queried.
+            if dirty_page_tracking_supported() {
+                flags |= whp::abi::WHvMapGpaRangeFlagTrackDirtyPages;
+            }
         }
         if exec {
             flags |= whp::abi::WHvMapGpaRangeFlagExecute;
@@ -72,6 +78,14 @@ fn unmap_range(&self, addr: u64, size: u64) -> anyhow::Result<()> {
     }
 }
 
+fn dirty_page_tracking_supported() -> bool {
+    static SUPPORTED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        whp::capabilities::features()
+            .is_ok_and(|f| f.is_set(whp::abi::WHV_CAPABILITY_FEATURES::DirtyPageTracking))
+    })
+}
+
 /// Trait for different partition memory mapper implementations.
 pub(crate) trait MemoryMapper: Inspect + Send + Sync {
     /// Map a range into the partition.
@@ -519,6 +533,58 @@ fn pin_range(&self, addr: u64, size: u64) -> anyhow::Result<()> {
             }])?;
         Ok(())
     }
+
+    fn set_dirty_tracking(&self, enable: bool) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            dirty_page_tracking_supported(),
+            "dirty page tracking is not supported by this version of WHP"
+        );
+        if enable {
+            // Writable ranges are always mapped with tracking enabled, so
+            // just discard anything dirtied so far.
+            let vtlp = self.vtlp();
+            for range in vtlp.ranges.read().iter().filter(|r| r.writable) {
+                vtlp.whp
+                    .query_gpa_range_dirty_bitmap(range.range.start(), range.range.len(), None)
+                    .context("failed to reset dirty bitmap")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn query_dirty_pages(&self, addr: u64, size: u64, bitmap: &mut [u64]) -> anyhow::Result<()> {
+        let query = MemoryRange::new(addr..addr + size);
+        let vtlp = self.vtlp();
+        let mut range_bitmap = Vec::new();
+        for range in vtlp.ranges.read().iter() {
+            if !range.writable || !query.overlaps(&range.range) {
+                continue;
+            }
+            anyhow::ensure!(
+                query.contains(&range.range),
+                "dirty page query must cover entire mapped ranges"
+            );
+            range_bitmap.clear();
+            range_bitmap.resize((range.range.page_count_4k() as usize).div_ceil(64), 0);
+            vtlp.whp
+                .query_gpa_range_dirty_bitmap(
+                    range.range.start(),
+                    range.range.len(),
+                    Some(&mut range_bitmap),
+                )
+                .context("failed to query dirty bitmap")?;
+            let first_page = ((range.range.start() - addr) / HV_PAGE_SIZE) as usize;
+            for (i, &word) in range_bitmap.iter().enumerate() {
+                let mut word = word;
+                while word != 0 {
+                    let page = first_page + i * 64 + word.trailing_zeros() as usize;
+                    bitmap[page / 64] |= 1 << (page % 64);
+                    word &= word - 1;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Inspect)]
@@ -34,6 +34,8 @@
 mod large_pages;
 // Memory Validation tests.
 mod memstat;
+/// Live migration tests.
+mod migration;
 /// NUMA topology tests.
 mod numa;
 /// Servicing tests.
@@ -135,3 +135,23 @@ async fn timesync_ic(config: PetriVmBuilder<OpenVmmPetriBackend>) -> anyhow::Res
     vm.wait_for_clean_teardown().await?;
     Ok(())
 }
+
+/// Test the heartbeat IC.
+#[openvmm_test(
+    uefi_x64(vhd(windows_datacenter_core_2022_x64)),
+    uefi_x64(vhd(ubuntu_2504_server_x64)),
+    uefi_aarch64(vhd(ubuntu_2404_server_aarch64))
+)]
+async fn heartbeat_ic(config: PetriVmBuilder<OpenVmmPetriBackend>) -> anyhow::Result<()> {
+    let (mut vm, agent) = config.run().await?;
+
+    mesh::CancelContext::new()
+        .with_timeout(Duration::from_secs(60))
+        .until_cancelled(vm.backend().wait_for_heartbeat())
+        .await
+        .context("guest never reported a healthy heartbeat")??;
+
+    agent.power_off().await?;
+    vm.wait_for_clean_teardown().await?;
+    Ok(())
+}
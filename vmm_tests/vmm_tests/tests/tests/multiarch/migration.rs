@@ -0,0 +1,97 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Live migration between two OpenVMM workers on the same host.
+
+use petri::Firmware;
+use petri::PetriTestParams;
+use petri::PetriVmArtifacts;
+use petri::PetriVmBuilder;
+use petri::openvmm::OpenVmmPetriBackend;
+use petri_artifacts_common::tags::MachineArch;
+
+petri::test!(migrate_same_host, |resolver| {
+    let arch = MachineArch::host();
+    let artifacts = || {
+        PetriVmArtifacts::<OpenVmmPetriBackend>::new(
+            resolver,
+            Firmware::linux_direct(resolver, arch),
+            arch,
+            true,
+        )
+    };
+    Some((artifacts()?, artifacts()?))
+});
+
+/// Migrates a Linux direct VM to an identically configured VM and checks that
+/// the guest keeps running on the destination with its memory intact.
+fn migrate_same_host(
+    params: PetriTestParams<'_>,
+    (source, dest): (
+        PetriVmArtifacts<OpenVmmPetriBackend>,
+        PetriVmArtifacts<OpenVmmPetriBackend>,
+    ),
+) -> anyhow::Result<()> {
+    const TEST_FILE: &str = "migrated.txt";
+    const TEST_CONTENT: &[u8] = b"written before migration";
+
+    let PetriTestParams {
+        test_name,
+        logger,
+        post_test_hooks,
+    } = params;
+
+    pal_async::DefaultPool::run_with(async |driver| {
+        let source = PetriVmBuilder::new(
+            PetriTestParams {
+                test_name,
+                logger,
+                post_test_hooks: &mut *post_test_hooks,
+            },
+            source,
+            &driver,
+        )?;
+        let dest = PetriVmBuilder::new(
+            PetriTestParams {
+                test_name,
+                logger,
+                post_test_hooks: &mut *post_test_hooks,
+            },
+            dest,
+            &driver,
+        )?;
+
+        let (mut vm, agent) = source.run().await?;
+        // The guest's root file system is in RAM, so the file only survives
+        // if guest memory is migrated.
+        agent.write_file(TEST_FILE, TEST_CONTENT).await?;
+        drop(agent);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let source_socket = std::net::TcpStream::connect(listener.local_addr()?)?;
+        let (dest_socket, _) = listener.accept()?;
+
+        let dest = dest
+            .modify_backend(move |b| b.with_incoming_migration(dest_socket.into()))
+            .run_without_agent();
+        let (stats, dest) = futures::join!(
+            vm.backend().migrate_out(source_socket.into(), 100, 10),
+            dest
+        );
+        let stats = stats?;
+        let mut dest = dest?;
+        tracing::info!(?stats, "migration complete");
+        assert!(stats.pages_sent > 0);
+
+        // The source is left stopped.
+        vm.teardown().await?;
+
+        // Pipette's connection to the source is gone, so it reconnects.
+        let agent = dest.wait_for_agent().await?;
+        assert_eq!(agent.read_file(TEST_FILE).await?, TEST_CONTENT);
+
+        agent.power_off().await?;
+        dest.wait_for_clean_teardown().await?;
+        Ok(())
+    })
+}
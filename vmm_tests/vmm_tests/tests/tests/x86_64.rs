@@ -196,6 +196,7 @@ async fn vpci_filter(config: PetriVmBuilder<OpenVmmPetriBackend>) -> anyhow::Res
                         resource: VirtioPciDeviceHandle(
                             VirtioNetHandle {
                                 max_queues: None,
+                                mtu: None,
                                 mac_address: MacAddress::new([0x00, 0x15, 0x5D, 0x12, 0x12, 0x12]),
                                 endpoint: NullHandle.into_resource(),
                             }
@@ -306,6 +307,7 @@ async fn virtio_blk_device(config: PetriVmBuilder<OpenVmmPetriBackend>) -> anyho
                     VirtioBlkHandle {
                         disk: disk_resource,
                         read_only: false,
+                        num_queues: None,
                     }
                     .into_resource(),
                 ));
@@ -554,6 +556,13 @@ async fn snapshot_save_to_disk(
         vp_count: 2,
         page_size: 4096,
         architecture: "x86_64".to_string(),
+        compressed_memory: false,
+        vm_args: Vec::new(),
+        name: String::new(),
+        description: String::new(),
+        parent: String::new(),
+        disks: Vec::new(),
+        configured_disks: Vec::new(),
     };
     openvmm_helpers::snapshot::write_snapshot(&snap_dir, &manifest, &saved_state_bytes, &mem_path)?;
 